@@ -0,0 +1,81 @@
+// Lowering and body encoding
+// Times `encode.rs` on a body of a million instructions, one
+// `Function::instruction` at a time against the presized buffer and the
+// parallel encoding, and the direct backend lowering and emitting a function
+// of 10 000 x86-64 instructions. `lower/10k_instructions` lowers into the
+// single instruction buffer of `LoweredFunction`; `lower/10k_per_instruction`
+// also copies every lowering into a vector of its own, the layout the buffer
+// replaced, so the two compare the allocations it saves.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::hint::black_box;
+use wasm_encoder::{Encode, Function, Instruction as WasmInstr, ValType};
+use x64_to_wasm_server::arch::Arch;
+use x64_to_wasm_server::backend::{FunctionIR, TranspilerBackend};
+use x64_to_wasm_server::encode;
+use x64_to_wasm_server::transpiler_real::{DirectBackend, TranspileOptions};
+
+// Roughly what the lowering of a long run of arithmetic looks like
+fn large_body(len: usize) -> Vec<WasmInstr<'static>> {
+    (0..len)
+        .map(|idx| match idx % 4 {
+            0 => WasmInstr::LocalGet(idx as u32 % 64),
+            1 => WasmInstr::I64Const(idx as i64 * 0x1234_5678),
+            2 => WasmInstr::I64Add,
+            _ => WasmInstr::LocalSet(idx as u32 % 64),
+        })
+        .collect()
+}
+
+fn encoding(c: &mut Criterion) {
+    let body = large_body(1 << 20);
+    let mut group = c.benchmark_group("encode");
+    group.sample_size(10);
+    group.bench_function("per_instruction", |b| {
+        b.iter(|| {
+            let mut func = Function::new(vec![(64, ValType::I64)]);
+            for instr in &body {
+                func.instruction(instr);
+            }
+            func.instruction(&WasmInstr::End);
+            func.into_raw_body()
+        })
+    });
+    group.bench_function("presized", |b| {
+        b.iter(|| {
+            let mut bytes = Function::new(vec![(64, ValType::I64)]).into_raw_body();
+            encode::encode(black_box(&body), 1, &mut bytes);
+            WasmInstr::End.encode(&mut bytes);
+            bytes
+        })
+    });
+    group.bench_function("parallel", |b| b.iter(|| encode::function(vec![(64, ValType::I64)], black_box(&body))));
+    group.finish();
+}
+
+fn lowering(c: &mut Criterion) {
+    // add rax, rdi; imul rax, rsi; mov [rdi+8], rax; xor rax, rdx, then ret
+    let mut code = Vec::new();
+    for _ in 0..2500 {
+        code.extend([0x48, 0x01, 0xf8, 0x48, 0x0f, 0xaf, 0xc6, 0x48, 0x89, 0x47, 0x08, 0x48, 0x31, 0xd0]);
+    }
+    code.push(0xc3);
+    let backend = DirectBackend::new(TranspileOptions::default());
+    let decoded = FunctionIR::decode("bench", Arch::X86_64, &code, 0x1000);
+    assert_eq!(decoded.instructions.len(), 10_001);
+
+    let mut group = c.benchmark_group("lower");
+    group.sample_size(10);
+    group.bench_function("10k_instructions", |b| b.iter(|| backend.emit(backend.lower(black_box(&decoded)).unwrap())));
+    group.bench_function("10k_per_instruction", |b| {
+        b.iter(|| {
+            let lowered = backend.lower(black_box(&decoded)).unwrap();
+            let owned: Vec<Vec<WasmInstr<'static>>> = lowered.lowerings.iter().map(|lowering| lowered.wasm(lowering).to_vec()).collect();
+            (backend.emit(lowered), owned)
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, encoding, lowering);
+criterion_main!(benches);
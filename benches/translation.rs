@@ -0,0 +1,39 @@
+// Native vs translated execution
+// The criterion counterpart of GET /admin/bench (`src/bench.rs`): times every
+// demo callback natively and its WASM lowering, compiled by Cranelift as in
+// differential verification and instantiated once, on each sample state.
+// Callbacks verification skips, or whose lowering traps, are left out.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::hint::black_box;
+use x64_to_wasm_server::config::TranspilerConfig;
+use x64_to_wasm_server::transpiler::Transpiler;
+use x64_to_wasm_server::verify::{self, SAMPLE_COUNTERS};
+use x64_to_wasm_server::{register_callbacks, State};
+
+fn native_vs_wasm(c: &mut Criterion) {
+    let registry = register_callbacks();
+    let transpiler = Transpiler::new(registry.iter(), &TranspilerConfig::default());
+    for callback in registry.iter() {
+        let Ok(compiled) = verify::comparable(callback, &transpiler) else { continue };
+        let Ok(mut runner) = compiled.instantiate(&transpiler.verify_limits()) else { continue };
+        if SAMPLE_COUNTERS.iter().any(|&counter| runner.run(&State { counter }).0.trap.is_some()) {
+            continue;
+        }
+
+        let mut group = c.benchmark_group(&callback.name);
+        for counter in SAMPLE_COUNTERS {
+            let input = State { counter };
+            group.bench_with_input(BenchmarkId::new("native", counter), &input, |b, input| {
+                b.iter(|| callback.call(black_box(&mut input.clone()), &[]))
+            });
+            group.bench_with_input(BenchmarkId::new("wasm", counter), &input, |b, input| {
+                b.iter(|| runner.run(black_box(input)))
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, native_vs_wasm);
+criterion_main!(benches);
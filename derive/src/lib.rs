@@ -0,0 +1,153 @@
+// State layout and rendering derives
+// `#[derive(SelfServeState)]` for the repr(C) structs the server shares with
+// translated code: the application state and the structs callbacks return.
+// It implements `layout::SelfServeState` with the offset, size and type of
+// every field, taken from the compiler through `offset_of!` and `size_of`,
+// so the manifest cannot drift from the struct. Field types implement
+// `layout::FieldLayout`; nested structs derive it too.
+// `#[derive(RenderDom)]` implements `render::RenderDom`, rendering a struct
+// as a definition list of its fields, see `render.rs`.
+// The expansions name `crate::layout`, `crate::render` and `crate::dom`, so
+// the derives are for types of the server crate.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields};
+
+#[proc_macro_derive(SelfServeState)]
+pub fn derive_self_serve_state(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+fn expand(input: &DeriveInput) -> Result<proc_macro2::TokenStream, Error> {
+    let name = &input.ident;
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(&input.generics, "SelfServeState cannot be derived for generic structs"));
+    }
+    if !is_repr_c(input)? {
+        return Err(Error::new(
+            Span::call_site(),
+            "SelfServeState needs #[repr(C)], the field order of other representations is unspecified",
+        ));
+    }
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(Error::new_spanned(name, "SelfServeState needs named fields")),
+        },
+        _ => return Err(Error::new_spanned(name, "SelfServeState can only be derived for structs")),
+    };
+
+    let fields = fields.iter().map(|field| {
+        let ident = field.ident.as_ref().expect("named field");
+        let ty = &field.ty;
+        let field_name = ident.to_string();
+        quote! {
+            crate::layout::Field {
+                name: #field_name,
+                offset: ::std::mem::offset_of!(Self, #ident),
+                size: ::std::mem::size_of::<#ty>(),
+                ty: <#ty as crate::layout::FieldLayout>::field_type(),
+            }
+        }
+    });
+    let type_name = name.to_string();
+    Ok(quote! {
+        impl crate::layout::SelfServeState for #name {
+            fn layout() -> crate::layout::StructLayout {
+                crate::layout::StructLayout {
+                    name: #type_name,
+                    size: ::std::mem::size_of::<Self>(),
+                    align: ::std::mem::align_of::<Self>(),
+                    fields: vec![#(#fields),*],
+                }
+            }
+        }
+
+        impl crate::layout::FieldLayout for #name {
+            fn field_type() -> crate::layout::FieldType {
+                crate::layout::FieldType::Struct(<Self as crate::layout::SelfServeState>::layout())
+            }
+        }
+    })
+}
+
+#[proc_macro_derive(RenderDom, attributes(render))]
+pub fn derive_render_dom(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_render(&input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+fn expand_render(input: &DeriveInput) -> Result<proc_macro2::TokenStream, Error> {
+    let name = &input.ident;
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => return Err(Error::new_spanned(name, "RenderDom can only be derived for structs")),
+    };
+
+    let mut entries = Vec::new();
+    for (idx, field) in fields.iter().enumerate() {
+        if is_skipped(field)? {
+            continue;
+        }
+        // Tuple struct fields are named by their index
+        let (member, label) = match &field.ident {
+            Some(ident) => (quote!(#ident), ident.to_string()),
+            None => {
+                let index = syn::Index::from(idx);
+                (quote!(#index), idx.to_string())
+            }
+        };
+        entries.push(quote! {
+            crate::dom::DomNode::element("dt", vec![], vec![crate::dom::DomNode::text(#label)]),
+            crate::dom::DomNode::element("dd", vec![], vec![crate::render::RenderDom::render_dom(&self.#member)]),
+        });
+    }
+
+    // Type parameters are rendered too
+    let mut generics = input.generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(syn::parse_quote!(crate::render::RenderDom));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics crate::render::RenderDom for #name #ty_generics #where_clause {
+            fn render_dom(&self) -> crate::dom::DomNode {
+                crate::dom::DomNode::element("dl", vec![], vec![#(#entries)*])
+            }
+        }
+    })
+}
+
+fn is_skipped(field: &syn::Field) -> Result<bool, Error> {
+    let mut skip = false;
+    for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("render")) {
+        attr.parse_nested_meta(|meta| match meta.path.is_ident("skip") {
+            true => {
+                skip = true;
+                Ok(())
+            }
+            false => Err(meta.error("expected `skip`")),
+        })?;
+    }
+    Ok(skip)
+}
+
+fn is_repr_c(input: &DeriveInput) -> Result<bool, Error> {
+    let mut repr_c = false;
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("repr")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("C") {
+                repr_c = true;
+            }
+            // align(N) and packed(N) carry a value
+            if meta.input.peek(syn::token::Paren) {
+                let _ = meta.input.parse::<proc_macro2::Group>()?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(repr_c)
+}
@@ -0,0 +1,10 @@
+title = x64-zu-WASM-Zähler
+counter = Zähler: { $count }
+increment = Erhöhen
+decrement = Verringern
+reset = Zurücksetzen
+parity = Parität
+squared = Quadrat
+stats = Statistik
+counter-input-label = Neuer Zählerwert
+set = Setzen
@@ -0,0 +1,11 @@
+# Texts of the counter page, in the subset of Fluent read by src/i18n.rs
+title = x64 to WASM Counter
+counter = Counter: { $count }
+increment = Increment
+decrement = Decrement
+reset = Reset
+parity = Parity
+squared = Squared
+stats = Stats
+counter-input-label = New counter value
+set = Set
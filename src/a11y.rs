@@ -0,0 +1,211 @@
+// Accessibility
+// Typed ARIA roles and attributes for the DOM builder, IDs that tie labels
+// to their form inputs, and a checker for rendered pages. In debug builds
+// (or with `SELF_SERVE_A11Y_CHECK=1`) every rendered page is checked for
+// buttons, inputs, selects, text areas and images without an accessible
+// name, and each problem is logged once as a warning.
+
+use crate::dom::{Dom, DomNode};
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Mutex;
+
+// The builder's vocabulary, the roles and attributes pages use
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    /// A live region new entries are appended to
+    Log,
+    /// A live region with advisory updates, e.g. progress
+    Status,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Log => "log",
+            Role::Status => "status",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Aria {
+    Label(String),
+}
+
+impl Aria {
+    /// The attribute name and value
+    pub fn attr(&self) -> (&'static str, String) {
+        match self {
+            Aria::Label(label) => ("aria-label", label.clone()),
+        }
+    }
+}
+
+/// Hands out IDs unique within one render, `<prefix>-<kind>-<n>`. They only
+/// depend on the order of the calls, so the same render gets the same IDs.
+pub struct Ids {
+    prefix: String,
+    next: usize,
+}
+
+impl Ids {
+    pub fn new(prefix: &str) -> Self {
+        Self { prefix: prefix.to_string(), next: 0 }
+    }
+
+    pub fn next(&mut self, kind: &str) -> String {
+        self.next += 1;
+        format!("{}-{}-{}", self.prefix, kind, self.next)
+    }
+}
+
+/// A label and the form control it names, e.g. an `input`. The control
+/// keeps an `id` it already has, otherwise it gets one from `ids`, and the
+/// label's `for` points at it.
+pub fn labelled(ids: &mut Ids, label: Vec<DomNode>, control: DomNode) -> [DomNode; 2] {
+    let (control, id) = match control.attr("id").map(str::to_string) {
+        Some(id) => (control, id),
+        None => {
+            let id = ids.next(control.tag().unwrap_or("control"));
+            (control.with_attr("id", &id), id)
+        }
+    };
+    [DomNode::element("label", vec![("for", &id)], label), control]
+}
+
+/// Enabled by default in debug builds, `SELF_SERVE_A11Y_CHECK=0|1` overrides
+pub fn check_enabled() -> bool {
+    match std::env::var("SELF_SERVE_A11Y_CHECK") {
+        Ok(value) => value != "0",
+        Err(_) => cfg!(debug_assertions),
+    }
+}
+
+/// Problems in `dom`, one message per element lacking an accessible name
+pub fn check(dom: &Dom) -> Vec<String> {
+    let mut labelled_ids = HashSet::new();
+    collect_label_targets(&dom.nodes, &mut labelled_ids);
+    let mut problems = Vec::new();
+    for node in &dom.nodes {
+        check_node(node, false, &labelled_ids, &mut problems);
+    }
+    problems
+}
+
+fn collect_label_targets<'a>(nodes: &'a [DomNode], ids: &mut HashSet<&'a str>) {
+    for node in nodes {
+        if let DomNode::Element { tag, children, .. } = node {
+            if let (true, Some(id)) = (tag == "label", node.attr("for")) {
+                ids.insert(id);
+            }
+            collect_label_targets(children, ids);
+        }
+    }
+}
+
+fn check_node(node: &DomNode, in_label: bool, labelled_ids: &HashSet<&str>, problems: &mut Vec<String>) {
+    let DomNode::Element { tag, attrs, children } = node else { return };
+    let named_by_attr = ["aria-label", "aria-labelledby", "title"]
+        .iter()
+        .any(|name| node.attr(name).is_some_and(|value| !value.trim().is_empty()));
+    let describe = || {
+        let attrs: String = attrs.iter().map(|(name, value)| format!(" {}=\"{}\"", name, value)).collect();
+        format!("<{}{}>", tag, attrs)
+    };
+    let labelled = in_label || node.attr("id").is_some_and(|id| labelled_ids.contains(id));
+    match tag.as_str() {
+        "button" if !named_by_attr && !has_text(children) => {
+            problems.push(format!("{} has no text or aria-label", describe()));
+        }
+        "input" => {
+            let kind = node.attr("type").unwrap_or("text");
+            let named = match kind {
+                "hidden" => true,
+                "submit" | "reset" | "button" => node.attr("value").is_some() || named_by_attr,
+                _ => labelled || named_by_attr,
+            };
+            if !named {
+                problems.push(format!("{} has no label", describe()));
+            }
+        }
+        "select" | "textarea" if !labelled && !named_by_attr => {
+            problems.push(format!("{} has no label", describe()));
+        }
+        "img" if node.attr("alt").is_none() && !named_by_attr => {
+            problems.push(format!("{} has no alt text", describe()));
+        }
+        _ => {}
+    }
+    for child in children {
+        check_node(child, in_label || tag == "label", labelled_ids, problems);
+    }
+}
+
+fn has_text(nodes: &[DomNode]) -> bool {
+    nodes.iter().any(|node| match node {
+        DomNode::Text(text) => !text.trim().is_empty(),
+        DomNode::Tr { .. } => true,
+        DomNode::Element { tag, children, .. } => match tag.as_str() {
+            "img" => node.attr("alt").is_some_and(|alt| !alt.trim().is_empty()),
+            _ => has_text(children),
+        },
+    })
+}
+
+/// Logs the problems of rendered pages, each only the first time it is seen
+pub struct Checker {
+    enabled: bool,
+    reported: Mutex<BTreeSet<String>>,
+}
+
+impl Checker {
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled, reported: Mutex::new(BTreeSet::new()) }
+    }
+
+    pub fn check_rendered(&self, page: &str, dom: &Dom) {
+        if !self.enabled {
+            return;
+        }
+        let mut reported = self.reported.lock().unwrap();
+        for problem in check(dom) {
+            if reported.insert(format!("{} {}", page, problem)) {
+                tracing::warn!(page, %problem, "Accessibility problem");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wires_labels_and_finds_unnamed_controls() {
+        let mut ids = Ids::new("form");
+        let [label, input] = labelled(&mut ids, vec![DomNode::text("Name")], DomNode::element("input", vec![], vec![]));
+        assert_eq!(label.attr("for"), Some("form-input-1"));
+        assert_eq!(input.attr("id"), Some("form-input-1"));
+        let [_, kept] = labelled(&mut ids, vec![DomNode::text("Age")], DomNode::element("input", vec![("id", "age")], vec![]));
+        assert_eq!(kept.attr("id"), Some("age"));
+
+        let icon = DomNode::element("button", vec![], vec![DomNode::element("img", vec![("src", "x.svg")], vec![])]);
+        let dom = Dom {
+            nodes: vec![
+                label,
+                input,
+                DomNode::element("label", vec![], vec![DomNode::text("Wrapped"), DomNode::element("input", vec![], vec![])]),
+                DomNode::element("button", vec![], vec![DomNode::tr("save", vec![])]),
+                DomNode::element("button", vec![], vec![]).with_aria(Aria::Label("Close".to_string())),
+                DomNode::element("input", vec![("type", "hidden")], vec![]),
+                DomNode::element("input", vec![("id", "orphan")], vec![]).with_role(Role::Status),
+                icon,
+            ],
+        };
+        let problems = check(&dom);
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[0].starts_with(r#"<input id="orphan" role="status">"#));
+        assert!(problems[1].starts_with("<button>"));
+        assert!(problems[2].contains("alt text"));
+    }
+}
@@ -0,0 +1,229 @@
+// AArch64 front-end
+// Lifts decoded ARM64 instructions to the shared IR. W registers share the
+// virtual register of the X register they are part of, the zero register
+// reads as the constant 0 and writes to it are dropped.
+
+use crate::ir::{BinOp, Cond, Lifter, Op, RegKey, TrapKind, VReg, Value};
+use yaxpeax_arm::armv8::a64::{Instruction, Opcode, Operand, ShiftStyle, SizeCode};
+
+const ZR: u16 = 31;
+
+impl Lifter {
+    fn aarch64_reg(&mut self, number: u16) -> Value {
+        Value::Reg(self.key(RegKey::AArch64(number)))
+    }
+
+    // Source operand: registers, immediates and unshifted register operands
+    fn aarch64_operand(&mut self, operand: &Operand) -> Option<Value> {
+        match *operand {
+            Operand::Register(_, ZR) => Some(Value::Const(0)),
+            Operand::Register(_, n) | Operand::RegisterOrSP(_, n) => Some(self.aarch64_reg(n)),
+            Operand::RegShift(ShiftStyle::LSL, 0, _, ZR) => Some(Value::Const(0)),
+            Operand::RegShift(ShiftStyle::LSL, 0, _, n) => Some(self.aarch64_reg(n)),
+            Operand::Immediate(imm) => Some(Value::Const(imm as i64)),
+            Operand::Imm16(imm) => Some(Value::Const(imm as i64)),
+            Operand::Imm64(imm) => Some(Value::Const(imm as i64)),
+            Operand::ImmShift(imm, shift) => Some(Value::Const((imm as i64) << shift)),
+            _ => None,
+        }
+    }
+
+    // Destination register, None for the zero register
+    fn aarch64_dst(&mut self, operand: &Operand) -> Option<Option<VReg>> {
+        match *operand {
+            Operand::Register(_, ZR) => Some(None),
+            Operand::Register(_, n) | Operand::RegisterOrSP(_, n) => Some(Some(self.key(RegKey::AArch64(n)))),
+            _ => None,
+        }
+    }
+
+    // Base and offset of a memory operand, plus the base update for
+    // pre/post-indexed addressing
+    fn aarch64_address(&mut self, operand: &Operand, addr: u64) -> Option<(Value, i64, Option<Op>)> {
+        let writeback = |lifter: &mut Self, n: u16, offset: i32| {
+            let base = lifter.key(RegKey::AArch64(n));
+            Op::Binary { op: BinOp::Add, dst: base, lhs: Value::Reg(base), rhs: Value::Const(offset as i64) }
+        };
+        match *operand {
+            Operand::RegPreIndex(n, offset, false) => Some((self.aarch64_reg(n), offset as i64, None)),
+            Operand::RegPreIndex(n, offset, true) => {
+                Some((self.aarch64_reg(n), offset as i64, Some(writeback(self, n, offset))))
+            }
+            Operand::RegPostIndex(n, offset) => Some((self.aarch64_reg(n), 0, Some(writeback(self, n, offset)))),
+            // Literal loads are PC-relative
+            Operand::PCOffset(offset) => Some((Value::Const(0), addr.wrapping_add(offset as u64) as i64, None)),
+            _ => None,
+        }
+    }
+
+    pub fn lift_aarch64(&mut self, instr: &Instruction, addr: u64) -> Option<Vec<Op>> {
+        let operands = &instr.operands;
+        let target = || {
+            operands.iter().find_map(|operand| match operand {
+                Operand::PCOffset(offset) => Some(addr.wrapping_add(*offset as u64)),
+                _ => None,
+            })
+        };
+
+        let ops = match instr.opcode {
+            Opcode::MOVZ | Opcode::MOVN => {
+                let Value::Const(imm) = self.aarch64_operand(&operands[1])? else { return None };
+                let value = match (instr.opcode, operands[0]) {
+                    (Opcode::MOVZ, _) => imm,
+                    (_, Operand::Register(SizeCode::W, _)) => !imm & 0xffff_ffff,
+                    _ => !imm,
+                };
+                match self.aarch64_dst(&operands[0])? {
+                    Some(dst) => vec![Op::Copy { dst, src: Value::Const(value) }],
+                    None => vec![],
+                }
+            }
+
+            Opcode::ADR | Opcode::ADRP => {
+                let page = if instr.opcode == Opcode::ADRP { addr & !0xfff } else { addr };
+                let Operand::PCOffset(offset) = operands[1] else { return None };
+                match self.aarch64_dst(&operands[0])? {
+                    Some(dst) => vec![Op::Copy { dst, src: Value::Const(page.wrapping_add(offset as u64) as i64) }],
+                    None => vec![],
+                }
+            }
+
+            Opcode::ADD | Opcode::SUB | Opcode::AND | Opcode::ORR
+            | Opcode::ADDS | Opcode::SUBS | Opcode::ANDS | Opcode::MADD => {
+                let lhs = self.aarch64_operand(&operands[1])?;
+                let rhs = self.aarch64_operand(&operands[2])?;
+                let op = match instr.opcode {
+                    Opcode::ADD | Opcode::ADDS => BinOp::Add,
+                    Opcode::SUB | Opcode::SUBS => BinOp::Sub,
+                    Opcode::AND | Opcode::ANDS => BinOp::And,
+                    // madd is only a plain multiply when it accumulates onto zr
+                    Opcode::MADD if self.aarch64_operand(&operands[3])? == Value::Const(0) => BinOp::Mul,
+                    // orr is only a plain move (`mov x0, x1`) when one side is zr
+                    Opcode::ORR if lhs == Value::Const(0) => {
+                        return Some(match self.aarch64_dst(&operands[0])? {
+                            Some(dst) => vec![Op::Copy { dst, src: rhs }],
+                            None => vec![],
+                        });
+                    }
+                    _ => return None,
+                };
+
+                let dst = self.aarch64_dst(&operands[0])?;
+                let mut ops = Vec::new();
+                if let Some(dst) = dst {
+                    ops.push(Op::Binary { op, dst, lhs, rhs });
+                }
+                if matches!(instr.opcode, Opcode::ADDS | Opcode::SUBS | Opcode::ANDS) {
+                    let flags = self.flags();
+                    ops.push(match dst {
+                        Some(dst) => Op::Copy { dst: flags, src: Value::Reg(dst) },
+                        // cmp/cmn/tst
+                        None => Op::Binary { op, dst: flags, lhs, rhs },
+                    });
+                }
+                ops
+            }
+
+            Opcode::LDR | Opcode::LDUR => {
+                let (base, offset, update) = self.aarch64_address(&operands[1], addr)?;
+                let mut ops = Vec::new();
+                if let Some(dst) = self.aarch64_dst(&operands[0])? {
+                    ops.push(Op::Load { dst, base, offset });
+                }
+                ops.extend(update);
+                ops
+            }
+
+            Opcode::STR | Opcode::STUR => {
+                let value = self.aarch64_operand(&operands[0])?;
+                let (base, offset, update) = self.aarch64_address(&operands[1], addr)?;
+                let mut ops = vec![Op::Store { base, offset, value }];
+                ops.extend(update);
+                ops
+            }
+
+            Opcode::B => vec![Op::Jump { target: target()? }],
+
+            Opcode::Bcc(cc) => {
+                let cond = match cc {
+                    0 => Cond::Eq,
+                    1 => Cond::Ne,
+                    2 => Cond::AboveEq,
+                    3 => Cond::Below,
+                    8 => Cond::Above,
+                    9 => Cond::BelowEq,
+                    10 => Cond::Ge,
+                    11 => Cond::Lt,
+                    12 => Cond::Gt,
+                    13 => Cond::Le,
+                    14 | 15 => return Some(vec![Op::Jump { target: target()? }]),
+                    // Sign and overflow conditions have no IR equivalent
+                    _ => return None,
+                };
+                vec![Op::Branch { cond, flags: Value::Reg(self.flags()), target: target()? }]
+            }
+
+            // Compare-and-branch tests the register itself
+            Opcode::CBZ | Opcode::CBNZ => vec![Op::Branch {
+                cond: if instr.opcode == Opcode::CBZ { Cond::Eq } else { Cond::Ne },
+                flags: self.aarch64_operand(&operands[0])?,
+                target: target()?,
+            }],
+
+            // Return value is in x0/w0
+            Opcode::RET => vec![Op::Return { value: self.aarch64_reg(0) }],
+
+            Opcode::BR => vec![Op::IndirectJump],
+
+            Opcode::UDF => vec![Op::Trap { kind: TrapKind::Invalid }],
+            Opcode::BRK => vec![Op::Trap { kind: TrapKind::Breakpoint }],
+
+            // Number in x8, arguments in x0-x5, result in x0
+            Opcode::SVC => {
+                let args = [0, 1, 2, 3, 4, 5].map(|n| self.aarch64_reg(n));
+                let number = self.aarch64_reg(8);
+                vec![Op::Syscall { dst: self.key(RegKey::AArch64(0)), number, args }]
+            }
+
+            Opcode::BL | Opcode::BLR | Opcode::STP | Opcode::LDP => {
+                vec![Op::Opaque { mnemonic: instr.opcode.to_string() }]
+            }
+
+            // nop and the pointer authentication hints
+            Opcode::HINT => vec![],
+
+            _ => return None,
+        };
+        Some(ops)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arch::Arch;
+    use crate::backend::FunctionIR;
+    use crate::ir::{self, BinOp, Op, Value, VReg};
+    use crate::transpiler_real::FidelityReport;
+
+    #[test]
+    fn lifts_load_add_store() {
+        // ldr w8, [x0]; add w8, w8, #1; str w8, [x0]; mov w0, w8; ret
+        let code: Vec<u8> = [0xb9400008u32, 0x11000508, 0xb9000008, 0x2a0803e0, 0xd65f03c0]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        let decoded = FunctionIR::decode("test", Arch::AArch64, &code, 0x1000);
+        let mut report = FidelityReport::default();
+        let function = ir::lift(&decoded, &mut report);
+        let (x0, x8) = (VReg(0), VReg(1));
+
+        assert!(report.unsupported.is_empty(), "{:?}", report.unsupported);
+        assert_eq!(function.insts().map(|inst| inst.op.clone()).collect::<Vec<_>>(), vec![
+            Op::Load { dst: x8, base: Value::Reg(x0), offset: 0 },
+            Op::Binary { op: BinOp::Add, dst: x8, lhs: Value::Reg(x8), rhs: Value::Const(1) },
+            Op::Store { base: Value::Reg(x0), offset: 0, value: Value::Reg(x8) },
+            Op::Copy { dst: x0, src: Value::Reg(x8) },
+            Op::Return { value: Value::Reg(x0) },
+        ]);
+    }
+}
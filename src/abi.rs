@@ -0,0 +1,164 @@
+// Callback ABI manifest
+// Besides the provenance of `meta.rs`, every served module carries a
+// "selfserve.abi" custom section with what a host needs to call it: the
+// exported function, the signature of the callback (the registry's
+// `Signature` with its return descriptor, or the declared signature of a
+// `[binaries]` symbol), where the state pointer points and the layout of the
+// state there, how state is accessed, which allocator functions the
+// module exports and the heap base it needs in the shared runtime it imports
+// them from, see `runtime.rs`. A module is self-describing this way, so hosts other
+// than the page glue can instantiate and call it without the descriptors
+// of the page or /api/state/layout.
+
+use crate::callbacks::Signature;
+use crate::layout::{SelfServeState, StructLayout};
+use crate::signatures::{DeclaredSignature, ParamType};
+use crate::transpiler_real::{StateAccess, TranspileOptions};
+use serde::Serialize;
+use wasm_encoder::{CustomSection, Section};
+use wasmparser::{ExternalKind, Parser, Payload};
+
+pub const SECTION: &str = "selfserve.abi";
+
+/// How the exported function is called
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum CallSignature {
+    /// A callback of the registry
+    Registered(Signature),
+    /// A symbol with a declared signature, see `signatures.rs`
+    Declared(DeclaredSignature),
+}
+
+impl CallSignature {
+    fn takes_state(&self) -> bool {
+        match self {
+            CallSignature::Registered(signature) => signature.takes_state(),
+            CallSignature::Declared(signature) => signature.params.contains(&ParamType::State),
+        }
+    }
+}
+
+/// The bytes the state pointer addresses
+#[derive(Debug, Clone, Serialize)]
+pub struct StateSlice {
+    pub address: u64,
+    pub layout: StructLayout,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AbiManifest {
+    /// The exported function to call, none if the module exports none
+    pub export: Option<String>,
+    /// None for symbols without a registered or declared signature, whose
+    /// parameters are the ones the lifter recovered
+    pub signature: Option<CallSignature>,
+    /// Scalar out-parameters are returned as extra results after the
+    /// status, see `multivalue.rs`
+    pub multi_value: bool,
+    /// None if the function takes no state pointer
+    pub state: Option<StateSlice>,
+    pub state_access: StateAccess,
+    /// Exported linear memory, none if the module has none
+    pub memory: Option<String>,
+    /// Exported functions of `alloc.rs`
+    pub allocator: Vec<String>,
+    /// Heap base to `reserve` in the shared runtime before instantiating,
+    /// none if the module does not import it
+    pub runtime: Option<u32>,
+}
+
+impl AbiManifest {
+    pub fn new(signature: Option<CallSignature>, options: &TranspileOptions, wasm: &[u8]) -> Self {
+        let mut export = None;
+        let mut memory = None;
+        let mut allocator = Vec::new();
+        for payload in Parser::new(0).parse_all(wasm) {
+            let Ok(Payload::ExportSection(reader)) = payload else { continue };
+            for item in reader.into_iter().flatten() {
+                match item.kind {
+                    // The callback is exported first, the allocator after it
+                    ExternalKind::Func if export.is_none() => export = Some(item.name.to_string()),
+                    ExternalKind::Func if crate::alloc::EXPORTS.contains(&item.name) => allocator.push(item.name.to_string()),
+                    ExternalKind::Memory => memory = Some(item.name.to_string()),
+                    _ => {}
+                }
+            }
+        }
+        let state = signature
+            .as_ref()
+            .filter(|signature| signature.takes_state())
+            .map(|_| StateSlice { address: 0, layout: crate::State::layout() });
+        Self {
+            export,
+            multi_value: options.multi_value && !options.out_results.is_empty() && memory.is_some(),
+            signature,
+            state,
+            state_access: options.state_access,
+            memory,
+            allocator,
+            runtime: crate::runtime::heap_base(wasm),
+        }
+    }
+
+    /// Appends the manifest to `wasm`
+    pub fn append_to(&self, wasm: &mut Vec<u8>) {
+        let data = serde_json::to_vec(self).expect("ABI manifests serialize to JSON");
+        CustomSection { name: SECTION.into(), data: data.into() }.append_to(wasm);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::Arch;
+    use crate::auth::Permission;
+    use crate::backend::{FunctionIR, TranspilerBackend};
+    use crate::callbacks::{CallbackRegistry, ElementType};
+    use crate::transpiler_real::DirectBackend;
+
+    fn read(wasm: &[u8]) -> Option<serde_json::Value> {
+        Parser::new(0).parse_all(wasm).find_map(|payload| match payload.ok()? {
+            Payload::CustomSection(reader) if reader.name() == SECTION => serde_json::from_slice(reader.data()).ok(),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn describes_the_exports_signature_and_state() {
+        let mut callbacks = CallbackRegistry::new();
+        callbacks.register_string("counter_parity", crate::counter_parity, Permission::User);
+        let signature = callbacks.get("counter_parity").unwrap().signature.clone();
+
+        // mov qword [rsi], 0; mov qword [rdx], 0; mov eax, [rdi]; ret
+        let code = [
+            0x48, 0xc7, 0x06, 0x00, 0x00, 0x00, 0x00, 0x48, 0xc7, 0x02, 0x00, 0x00, 0x00, 0x00, 0x8b, 0x07, 0xc3,
+        ];
+        let function = FunctionIR::decode("counter_parity", Arch::X86_64, &code, 0x1000);
+        let options = TranspileOptions {
+            allocator: true,
+            multi_value: true,
+            out_results: vec![ElementType::U64, ElementType::U64],
+            ..Default::default()
+        };
+        let mut wasm = DirectBackend::new(options.clone()).transpile(&function).unwrap();
+        AbiManifest::new(Some(CallSignature::Registered(signature)), &options, &wasm.clone()).append_to(&mut wasm);
+        wasmparser::Validator::new_with_features(wasmparser::WasmFeatures::all()).validate_all(&wasm).unwrap();
+
+        let abi = read(&wasm).unwrap();
+        assert_eq!(abi["export"], "callback");
+        assert_eq!(abi["memory"], "memory");
+        assert_eq!(abi["allocator"], serde_json::json!(["alloc", "free", "reset"]));
+        assert_eq!(abi["multi_value"], true);
+        assert_eq!(abi["signature"]["params"], serde_json::json!(["state_ptr", { "out": "u64" }, { "out": "u64" }]));
+        assert_eq!(abi["signature"]["returns"]["kind"], "string");
+        assert_eq!(abi["state"]["address"], 0);
+        assert_eq!(abi["state"]["layout"]["fields"][0]["name"], "counter");
+        assert_eq!(abi["state_access"], "memory");
+        assert_eq!(abi["runtime"], serde_json::Value::Null);
+
+        // A symbol without a signature has no state pointer to describe
+        let abi = AbiManifest::new(None, &TranspileOptions::default(), &wasm);
+        assert!(abi.state.is_none() && !abi.multi_value);
+    }
+}
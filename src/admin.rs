@@ -0,0 +1,600 @@
+// Admin dashboard
+// Lists every registered callback with its transpile status, module size,
+// fidelity of the x86-64 translation, the instructions it needs that the
+// front-end does not support and last execution time, followed by the
+// latest errors browsers reported, see `telemetry.rs`, a window at a time,
+// the state, rendered with `render.rs`, and the reports of the debug
+// channel, see `debug.rs`. A progress bar follows warm-ups and reloads, see
+// `progress.rs`.
+// `/admin/audit` pages through the log of executions, see `audit.rs`.
+
+use crate::a11y::{Aria, Role};
+use crate::audit::{AuditEntry, AuditFilter};
+use crate::auth::Permission;
+use crate::dom::{Dom, DomNode};
+use crate::error::ApiError;
+use crate::pagination::{PageQuery, PaginatedList};
+use crate::render::RenderDom;
+use crate::telemetry::StoredReport;
+use crate::transpiler::{ReloadDelta, TranspileStatus};
+use crate::ServerContext;
+use actix_web::{web, HttpRequest, HttpResponse, Responder, ResponseError};
+use serde::Deserialize;
+use std::time::SystemTime;
+
+pub async fn dashboard(req: HttpRequest, query: web::Query<PageQuery>, ctx: web::Data<ServerContext>) -> impl Responder {
+    if let Err(e) = ctx.auth.authorize(&req, Permission::Admin) {
+        return e.into_response(&ctx.auth);
+    }
+
+    page(&req, &ctx, "/admin", "Admin", render_dashboard(&ctx, &query))
+}
+
+/// `dom` as an admin page at `path`
+fn page(req: &HttpRequest, ctx: &ServerContext, path: &str, title: &str, mut dom: Dom) -> HttpResponse {
+    let session = ctx.csrf.session(req);
+    ctx.a11y.check_rendered(path, &dom);
+    let mut policy = crate::csp::Policy::new();
+    policy.apply(&mut dom);
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+    <meta charset="utf-8">
+    <meta name="csrf-token" content="{}">
+    <title>x64 to WASM Server - {}</title>
+    <style nonce="{}">
+        body {{ font-family: Arial, sans-serif; max-width: 1100px; margin: 30px auto; }}
+        table {{ border-collapse: collapse; width: 100%; }}
+        th, td {{ border-bottom: 1px solid #ddd; padding: 8px; text-align: left; vertical-align: top; }}
+        .ok {{ color: #2a7d2a; }}
+        .failed {{ color: #b22222; }}
+        .partial {{ color: #b8860b; }}
+        .detail {{ color: #666; font-size: 12px; }}
+        button, a.button {{ margin-right: 5px; padding: 4px 10px; font-size: 13px; cursor: pointer; }}
+    </style>
+    <script nonce="{}">
+        async function retranspile(fnName) {{
+            const csrfToken = document.querySelector('meta[name="csrf-token"]').content;
+            const response = await fetch(`/admin/retranspile/${{fnName}}`, {{
+                method: 'POST',
+                headers: {{ '{}': csrfToken }},
+            }});
+            if (!response.ok) {{
+                alert((await response.json()).message);
+            }}
+            window.location.reload();
+        }}
+{}{}{}
+    </script>
+</head>
+<body>
+{}
+</body>
+</html>"#,
+        ctx.csrf.token_for(&session.id),
+        title,
+        policy.nonce(),
+        policy.nonce(),
+        crate::csrf::CSRF_HEADER,
+        crate::pagination::SCRIPT,
+        crate::debug::SCRIPT,
+        crate::progress::SCRIPT,
+        dom.to_html()
+    );
+
+    let mut response = HttpResponse::Ok();
+    response.content_type("text/html; charset=utf-8");
+    if let Some(csp) = ctx.csp.header(&policy) {
+        response.insert_header(csp);
+    }
+    if session.is_new {
+        response.cookie(ctx.csrf.session_cookie(&session));
+    }
+    response.body(html)
+}
+
+pub async fn retranspile(
+    req: HttpRequest,
+    path: web::Path<String>,
+    ctx: web::Data<ServerContext>,
+) -> impl Responder {
+    if let Err(e) = ctx.csrf.verify(&req) {
+        return e.error_response();
+    }
+    if let Err(e) = ctx.auth.authorize(&req, Permission::Admin) {
+        return e.into_response(&ctx.auth);
+    }
+
+    let fn_name = path.into_inner();
+    if ctx.callbacks.get(&fn_name).is_none() {
+        return ApiError::not_found("Unknown callback").error_response();
+    }
+
+    match web::block(move || ctx.transpiler.retranspile(&fn_name)).await {
+        Ok(Ok(())) => HttpResponse::Ok().body("OK"),
+        Ok(Err(e)) => ApiError::internal(e).error_response(),
+        Err(e) => ApiError::internal(e.to_string()).error_response(),
+    }
+}
+
+/// Server-sent events of the warm-ups and reloads, see `progress.rs`
+pub async fn transpile_progress(req: HttpRequest, ctx: web::Data<ServerContext>) -> impl Responder {
+    if let Err(e) = ctx.auth.authorize(&req, Permission::Admin) {
+        return e.into_response(&ctx.auth);
+    }
+
+    ctx.progress.subscribe()
+}
+
+/// Runs the differential verification of every callback, see `verify.rs`
+pub async fn verify(req: HttpRequest, ctx: web::Data<ServerContext>) -> impl Responder {
+    if let Err(e) = ctx.auth.authorize(&req, Permission::Admin) {
+        return e.into_response(&ctx.auth);
+    }
+
+    match web::block(move || crate::verify::verify_all(ctx.callbacks.iter(), &ctx.transpiler)).await {
+        Ok(verifications) => HttpResponse::Ok().json(verifications),
+        Err(e) => ApiError::internal(e.to_string()).error_response(),
+    }
+}
+
+/// Downloads the static bundle as a tar archive, see `bundle.rs`
+pub async fn export(req: HttpRequest, ctx: web::Data<ServerContext>) -> impl Responder {
+    if let Err(e) = ctx.auth.authorize(&req, Permission::Admin) {
+        return e.into_response(&ctx.auth);
+    }
+
+    match web::block(move || crate::bundle::build(&ctx.callbacks, &ctx.transpiler, &ctx.binaries).to_tar()).await {
+        Ok(Ok(tar)) => HttpResponse::Ok()
+            .content_type("application/x-tar")
+            .insert_header(("Content-Disposition", r#"attachment; filename="self-serve-bundle.tar""#))
+            .body(tar),
+        Ok(Err(e)) => ApiError::internal(e.to_string()).error_response(),
+        Err(e) => ApiError::internal(e.to_string()).error_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BenchQuery {
+    iterations: Option<u32>,
+}
+
+/// Times every callback natively and translated, see `bench.rs`
+pub async fn bench(req: HttpRequest, query: web::Query<BenchQuery>, ctx: web::Data<ServerContext>) -> impl Responder {
+    if let Err(e) = ctx.auth.authorize(&req, Permission::Admin) {
+        return e.into_response(&ctx.auth);
+    }
+
+    let iterations = query.iterations.unwrap_or(1000).clamp(1, 100_000);
+    match web::block(move || crate::bench::bench_all(ctx.callbacks.iter(), &ctx.transpiler, iterations)).await {
+        Ok(benchmarks) => HttpResponse::Ok().json(benchmarks),
+        Err(e) => ApiError::internal(e.to_string()).error_response(),
+    }
+}
+
+/// Reports the instructions any symbol of the binary needs, so it can be
+/// vetted before it is registered as a callback, see `coverage.rs`
+pub async fn probe(
+    req: HttpRequest,
+    path: web::Path<String>,
+    ctx: web::Data<ServerContext>,
+) -> impl Responder {
+    if let Err(e) = ctx.auth.authorize(&req, Permission::Admin) {
+        return e.into_response(&ctx.auth);
+    }
+
+    match web::block(move || ctx.transpiler.probe(&path.into_inner())).await {
+        Ok(Ok(probe)) => HttpResponse::Ok().json(probe),
+        Ok(Err(e)) => ApiError::not_found(e).error_response(),
+        Err(e) => ApiError::internal(e.to_string()).error_response(),
+    }
+}
+
+/// Mnemonic frequencies and unsupported forms over every function of the
+/// binary, see `coverage::profile`
+pub async fn instructions(req: HttpRequest, ctx: web::Data<ServerContext>) -> impl Responder {
+    if let Err(e) = ctx.auth.authorize(&req, Permission::Admin) {
+        return e.into_response(&ctx.auth);
+    }
+
+    match web::block(move || ctx.transpiler.instruction_profile()).await {
+        Ok(Ok(profile)) => HttpResponse::Ok().json(profile),
+        Ok(Err(e)) => ApiError::internal(e).error_response(),
+        Err(e) => ApiError::internal(e.to_string()).error_response(),
+    }
+}
+
+fn render_coverage(ctx: &ServerContext, fn_name: &str) -> DomNode {
+    let probe = match ctx.transpiler.probe(fn_name) {
+        Ok(probe) => probe,
+        Err(e) => return DomNode::element("td", vec![("class", "failed")], vec![
+            DomNode::text(&format!("probe failed: {}", e)),
+        ]),
+    };
+
+    let unsupported: Vec<String> = probe.unsupported()
+        .map(|r| format!("{:#x}: {} (\u{d7}{})", r.addr, format!("{} {}", r.mnemonic, r.form).trim_end(), r.count))
+        .collect();
+    let class = if probe.is_supported() { "ok" } else { "failed" };
+    let mut cells = vec![DomNode::text(&format!(
+        "{} forms, {} unsupported",
+        probe.required.len(),
+        unsupported.len(),
+    ))];
+    if !probe.features.is_empty() {
+        cells.push(DomNode::element("div", vec![("class", "detail")], vec![
+            DomNode::text(&format!("needs {}", probe.features.join(", "))),
+        ]));
+    }
+    if !unsupported.is_empty() {
+        cells.push(DomNode::element("div", vec![("class", "detail")], vec![
+            DomNode::text(&unsupported.join(", ")),
+        ]));
+    }
+    DomNode::element("td", vec![("class", class)], cells)
+}
+
+#[derive(Deserialize)]
+pub struct FormatQuery {
+    format: Option<String>,
+}
+
+/// The audit log of executions, filtered by `filter`, as a page with a
+/// paginated list, or its window as JSON with `?format=json`
+pub async fn audit(
+    req: HttpRequest,
+    filter: web::Query<AuditFilter>,
+    query: web::Query<PageQuery>,
+    format: web::Query<FormatQuery>,
+    ctx: web::Data<ServerContext>,
+) -> impl Responder {
+    if let Err(e) = ctx.auth.authorize(&req, Permission::Admin) {
+        return e.into_response(&ctx.auth);
+    }
+
+    let entries = ctx.audit.query(&filter);
+    let list = PaginatedList::new(AUDIT_LOG, "/admin/audit", query.page()).with_query(req.query_string());
+    if format.format.as_deref() == Some("json") {
+        let page = query.page();
+        let window = entries.iter().skip(page.offset).take(page.limit).collect::<Vec<_>>();
+        return HttpResponse::Ok().json(serde_json::json!({
+            "total": entries.len(),
+            "offset": page.offset,
+            "limit": page.limit,
+            "entries": window,
+        }));
+    }
+    let wants_json = req
+        .headers()
+        .get(actix_web::http::header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("application/json"));
+    if wants_json {
+        return list.window_response(entries.len(), render_audit(list.window(&entries)));
+    }
+
+    let dom = Dom {
+        nodes: vec![
+            DomNode::element("h1", vec![], vec![DomNode::text("Audit log")]),
+            DomNode::element("p", vec![], vec![
+                DomNode::element("a", vec![("class", "button"), ("href", "/admin")], vec![DomNode::text("Dashboard")]),
+            ]),
+            render_audit_filter(&filter),
+            list.render(entries.len(), render_audit(list.window(&entries))),
+        ],
+    };
+    page(&req, &ctx, "/admin/audit", "Audit log", dom)
+}
+
+const AUDIT_LOG: &str = "audit-log";
+
+fn render_audit_filter(filter: &AuditFilter) -> DomNode {
+    let field = |label: &str, name: &str, value: Option<String>| {
+        DomNode::element("label", vec![], vec![
+            DomNode::text(label),
+            DomNode::element("input", vec![("name", name), ("value", &value.unwrap_or_default())], vec![]),
+        ])
+    };
+    let trigger = filter.trigger.map(|trigger| format!("{:?}", trigger).to_lowercase()).unwrap_or_default();
+    let options = ["", "execute", "batch", "sync", "schedule"]
+        .iter()
+        .map(|value| {
+            let mut attrs = vec![("value", *value)];
+            if *value == trigger {
+                attrs.push(("selected", "selected"));
+            }
+            DomNode::element("option", attrs, vec![DomNode::text(if value.is_empty() { "any" } else { value })])
+        })
+        .collect();
+    let mut failed = vec![("type", "checkbox"), ("name", "failed"), ("value", "true")];
+    if filter.failed {
+        failed.push(("checked", "checked"));
+    }
+    DomNode::element("form", vec![("method", "get"), ("action", "/admin/audit")], vec![
+        field("Callback ", "callback", filter.callback.clone()),
+        field(" Session ", "session", filter.session.clone()),
+        DomNode::element("label", vec![], vec![
+            DomNode::text(" Trigger "),
+            DomNode::element("select", vec![("name", "trigger")], options),
+        ]),
+        field(" Versions from ", "since", filter.since.map(|since| since.to_string())),
+        field(" to ", "until", filter.until.map(|until| until.to_string())),
+        DomNode::element("label", vec![], vec![DomNode::element("input", failed, vec![]), DomNode::text(" failed only ")]),
+        DomNode::element("button", vec![("type", "submit")], vec![DomNode::text("Filter")]),
+    ])
+}
+
+fn render_audit(entries: &[AuditEntry]) -> DomNode {
+    if entries.is_empty() {
+        return DomNode::element("p", vec![("class", "detail")], vec![DomNode::text("No executions recorded")]);
+    }
+
+    let header = DomNode::element("tr", vec![], ["#", "Executed", "Callback", "Trigger", "Session", "Arguments", "Outcome", "Version"]
+        .iter()
+        .map(|title| DomNode::element("th", vec![], vec![DomNode::text(title)]))
+        .collect());
+    let mut rows = vec![header];
+    for entry in entries {
+        let outcome = match (&entry.result, &entry.error) {
+            (_, Some(error)) => DomNode::element("td", vec![("class", "failed")], vec![DomNode::text(error)]),
+            (Some(result), None) => DomNode::element("td", vec![("class", "ok")], vec![DomNode::text(&result.to_string())]),
+            (None, None) => DomNode::element("td", vec![], vec![DomNode::text("-")]),
+        };
+        let executed = SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(entry.at);
+        rows.push(DomNode::element("tr", vec![], vec![
+            DomNode::element("td", vec![], vec![DomNode::text(&entry.seq.to_string())]),
+            DomNode::element("td", vec![], vec![DomNode::text(&format_age(executed))]),
+            DomNode::element("td", vec![], vec![DomNode::text(&entry.callback)]),
+            DomNode::element("td", vec![], vec![DomNode::text(&format!("{:?}", entry.trigger).to_lowercase())]),
+            DomNode::element("td", vec![("class", "detail")], vec![DomNode::text(entry.session.as_deref().unwrap_or("-"))]),
+            DomNode::element("td", vec![("class", "detail")], vec![DomNode::text(&entry.args_sha256[..12])]),
+            outcome,
+            DomNode::element("td", vec![], vec![
+                DomNode::text(&entry.version.map_or("-".to_string(), |version| version.to_string())),
+            ]),
+        ]));
+    }
+    DomNode::element("table", vec![], rows).with_aria(Aria::Label("Executions".to_string()))
+}
+
+/// A window of the client errors for the dashboard's paginated list
+pub async fn errors(req: HttpRequest, query: web::Query<PageQuery>, ctx: web::Data<ServerContext>) -> impl Responder {
+    if let Err(e) = ctx.auth.authorize(&req, Permission::Admin) {
+        return e.into_response(&ctx.auth);
+    }
+    let reports = ctx.telemetry.recent();
+    let list = PaginatedList::new(CLIENT_ERRORS, "/admin/errors", query.page());
+    list.window_response(reports.len(), render_client_errors(list.window(&reports)))
+}
+
+fn render_dashboard(ctx: &ServerContext, query: &PageQuery) -> Dom {
+    let header = DomNode::element("tr", vec![], ["Callback", "Permission", "Status", "Module size", "Fidelity", "Coverage", "Last executed", ""]
+        .iter()
+        .map(|title| DomNode::element("th", vec![], vec![DomNode::text(title)]))
+        .collect());
+
+    let mut rows = vec![header];
+    for callback in ctx.callbacks.iter() {
+        let last_executed = match *callback.last_executed.lock().unwrap() {
+            Some(time) => format_age(time),
+            None => "never".to_string(),
+        };
+
+        let (status, size, fidelity) = match ctx.transpiler.status(&callback.name) {
+            TranspileStatus::Ready(module) => {
+                let fidelity = match &module.fidelity {
+                    Ok(report) => {
+                        let unsupported = report.unsupported
+                            .iter()
+                            .map(|(addr, mnemonic)| format!("{:#x}: {}", addr, mnemonic))
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        let class = if report.is_exact() { "ok" } else { "partial" };
+                        DomNode::element("td", vec![("class", class)], vec![
+                            DomNode::text(&format!(
+                                "{}/{} exact, {} approximated, {} unsupported",
+                                report.translated(),
+                                report.instructions,
+                                report.approximated.len(),
+                                report.unsupported.len(),
+                            )),
+                            DomNode::element("div", vec![("class", "detail")], vec![
+                                DomNode::text(&format!(
+                                    "optimized: {} \u{2192} {} bytes, {} \u{2192} {} instructions",
+                                    report.unoptimized.bytes,
+                                    report.optimized.bytes,
+                                    report.unoptimized.wasm_instructions,
+                                    report.optimized.wasm_instructions,
+                                )),
+                            ]),
+                            DomNode::element("div", vec![("class", "detail")], vec![
+                                DomNode::text(&unsupported),
+                            ]),
+                        ])
+                    }
+                    Err(e) => DomNode::element("td", vec![("class", "failed")], vec![
+                        DomNode::text(&format!("analysis failed: {}", e)),
+                    ]),
+                };
+
+                (
+                    DomNode::element("td", vec![("class", "ok")], vec![
+                        DomNode::text(&format!("transpiled {}", format_age(module.transpiled_at))),
+                    ]),
+                    format!("{} bytes", module.wasm.len()),
+                    fidelity,
+                )
+            }
+            TranspileStatus::Failed(e) => (
+                DomNode::element("td", vec![("class", "failed")], vec![
+                    DomNode::text(&format!("failed: {}", e)),
+                ]),
+                "-".to_string(),
+                DomNode::element("td", vec![], vec![DomNode::text("-")]),
+            ),
+            TranspileStatus::Missing => (
+                DomNode::element("td", vec![("class", "failed")], vec![
+                    DomNode::text("not transpiled"),
+                ]),
+                "-".to_string(),
+                DomNode::element("td", vec![], vec![DomNode::text("-")]),
+            ),
+        };
+
+        let retranspile = format!("retranspile('{}')", callback.name);
+        let wat_link = format!("/wat/{}", callback.name);
+        let disasm_link = format!("/disasm/{}", callback.name);
+
+        rows.push(DomNode::element("tr", vec![], vec![
+            DomNode::element("td", vec![], vec![DomNode::text(&callback.name)]),
+            DomNode::element("td", vec![], vec![DomNode::text(&format!("{:?}", callback.permission))]),
+            status,
+            DomNode::element("td", vec![], vec![DomNode::text(&size)]),
+            fidelity,
+            render_coverage(ctx, &callback.name),
+            DomNode::element("td", vec![], vec![DomNode::text(&last_executed)]),
+            DomNode::element("td", vec![], vec![
+                DomNode::element("button", vec![("onclick", &retranspile)], vec![
+                    DomNode::text("Re-transpile"),
+                ]),
+                DomNode::element("a", vec![("class", "button"), ("href", &wat_link)], vec![
+                    DomNode::text("WAT"),
+                ]),
+                DomNode::element("a", vec![("class", "button"), ("href", &disasm_link)], vec![
+                    DomNode::text("Disassembly"),
+                ]),
+            ]),
+        ]));
+    }
+
+    let reports = ctx.telemetry.recent();
+    let list = PaginatedList::new(CLIENT_ERRORS, "/admin/errors", query.page());
+    let client_errors = match reports.is_empty() {
+        true => render_client_errors(&reports),
+        false => list.render(reports.len(), render_client_errors(list.window(&reports))),
+    };
+
+    Dom {
+        nodes: vec![
+            DomNode::element("h1", vec![], vec![DomNode::text("Admin")]),
+            DomNode::element("p", vec![], vec![
+                DomNode::element("a", vec![("class", "button"), ("href", "/admin/export")], vec![DomNode::text("Export bundle")]),
+                DomNode::element("a", vec![("class", "button"), ("href", "/admin/audit")], vec![DomNode::text("Audit log")]),
+            ]),
+            DomNode::element("h2", vec![], vec![DomNode::text("Callbacks")]),
+            render_progress(ctx),
+            render_reload(ctx.transpiler.last_reload()),
+            DomNode::element("table", vec![], rows).with_aria(Aria::Label("Callbacks".to_string())),
+            DomNode::element("h2", vec![], vec![DomNode::text("Client errors")]),
+            client_errors,
+            DomNode::element("h2", vec![], vec![DomNode::text("State")]),
+            render_state(ctx),
+            DomNode::element("h2", vec![], vec![DomNode::text("Debug channel")]),
+            render_debug_channel(ctx),
+        ],
+    }
+}
+
+fn render_state(ctx: &ServerContext) -> DomNode {
+    let state = ctx.state.read();
+    DomNode::element("div", vec![], vec![
+        DomNode::element("p", vec![("class", "detail")], vec![
+            DomNode::text(&format!("Version {}", ctx.state.version())),
+        ]),
+        state.render_dom(),
+    ])
+}
+
+/// Reports of executions in the browser, filled in live, see `debug.rs`
+fn render_debug_channel(ctx: &ServerContext) -> DomNode {
+    match ctx.debug.enabled() {
+        true => DomNode::element("ol", vec![("id", "debug-channel"), ("data-src", "/debug/ws?watch")], vec![]).with_role(Role::Log),
+        false => DomNode::element("p", vec![("class", "detail")], vec![
+            DomNode::text("Disabled, set [debug] enabled = true to see the executions of the page's modules"),
+        ]),
+    }
+}
+
+/// Progress bar of the current warm-up or reload, hidden between runs
+fn render_progress(ctx: &ServerContext) -> DomNode {
+    let run = ctx.progress.run();
+    let mut attrs = vec![("id", "transpile-progress"), ("data-src", "/admin/transpile-progress")];
+    if run.running == 0 {
+        attrs.push(("hidden", "hidden"));
+    }
+    let (total, finished) = (run.total.max(1).to_string(), run.finished.to_string());
+    DomNode::element("p", attrs, vec![
+        DomNode::element("progress", vec![("max", &total), ("value", &finished)], vec![])
+            .with_aria(Aria::Label("Transpilation progress".to_string())),
+        DomNode::element("span", vec![("class", "detail")], vec![
+            DomNode::text(&format!(" {}/{}", run.finished, run.total)),
+        ]),
+    ])
+    .with_role(Role::Status)
+}
+
+/// What the last hot reload of the binary re-transpiled
+fn render_reload(delta: Option<ReloadDelta>) -> DomNode {
+    let text = match delta {
+        None => "Not reloaded since start".to_string(),
+        Some(delta) => {
+            let retranspiled = match delta.retranspiled.is_empty() {
+                true => "nothing".to_string(),
+                false => delta.retranspiled.join(", "),
+            };
+            format!("Last reload {}: re-transpiled {}, {} unchanged", format_age(delta.at), retranspiled, delta.unchanged.len())
+        }
+    };
+    DomNode::element("p", vec![("class", "detail")], vec![DomNode::text(&text)])
+}
+
+const CLIENT_ERRORS: &str = "client-errors";
+
+fn render_client_errors(reports: &[StoredReport]) -> DomNode {
+    if reports.is_empty() {
+        return DomNode::element("p", vec![("class", "detail")], vec![DomNode::text("No errors reported")]);
+    }
+
+    let header = DomNode::element("tr", vec![], ["Received", "Callback", "Module", "Error", "Request", "Browser"]
+        .iter()
+        .map(|title| DomNode::element("th", vec![], vec![DomNode::text(title)]))
+        .collect());
+    let mut rows = vec![header];
+    for stored in reports {
+        let report = &stored.report;
+        let error = match (&report.trap, &report.message) {
+            (Some(trap), _) => format!("trapped at {}: {} (code {})", trap.address, trap.reason, trap.code),
+            (None, Some(message)) => message.clone(),
+            (None, None) => "-".to_string(),
+        };
+        rows.push(DomNode::element("tr", vec![], vec![
+            DomNode::element("td", vec![], vec![DomNode::text(&format_age(stored.received_at))]),
+            DomNode::element("td", vec![], vec![DomNode::text(&report.callback)]),
+            DomNode::element("td", vec![], vec![DomNode::text(report.module.as_deref().unwrap_or("-"))]),
+            DomNode::element("td", vec![("class", "failed")], vec![DomNode::text(&error)]),
+            DomNode::element("td", vec![("class", "detail")], vec![
+                DomNode::text(report.request_id.as_deref().unwrap_or("-")),
+            ]),
+            DomNode::element("td", vec![("class", "detail")], vec![
+                DomNode::text(report.user_agent.as_deref().unwrap_or("-")),
+            ]),
+        ]));
+    }
+    DomNode::element("table", vec![], rows).with_aria(Aria::Label("Client errors".to_string()))
+}
+
+fn format_age(time: SystemTime) -> String {
+    let secs = SystemTime::now()
+        .duration_since(time)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
@@ -0,0 +1,193 @@
+// Module allocator
+// With `allocator = true`, modules with a linear memory get a bump allocator
+// for the page glue to pass strings and structs in: `alloc(size, align)`
+// returns a pointer or 0 when memory cannot grow, `free(ptr, size)` gives
+// back the latest allocation, and `reset()` frees everything. The heap
+// starts after the state image at address 0 and the data segments, so
+// allocations never overwrite either, and grows the memory as needed. Pointers and sizes are i32 in
+// memory64 modules too, as segments and browser memories stay below 4 GiB.
+
+use crate::transpiler_real::LinearMemory;
+use wasm_encoder::{BlockType, ConstExpr, Function, GlobalType, Instruction, ValType};
+
+/// Exported functions, in order of their function indices after the callback
+pub const EXPORTS: [&str; 3] = ["alloc", "free", "reset"];
+
+// Alignment of the heap base, enough for any scalar and v128
+const HEAP_ALIGN: u64 = 16;
+
+/// First address after the state image and the data segments, never 0
+pub fn heap_base(memory: &LinearMemory) -> u32 {
+    let state = std::mem::size_of::<crate::State>() as u64;
+    let end = memory.segments.iter().map(|(addr, bytes)| addr + bytes.len() as u64).fold(state, u64::max);
+    end.max(1).next_multiple_of(HEAP_ALIGN) as u32
+}
+
+/// The mutable i32 global holding the next free address
+pub fn global(memory: &LinearMemory) -> (GlobalType, ConstExpr) {
+    let ty = GlobalType { val_type: ValType::I32, mutable: true, shared: false };
+    (ty, ConstExpr::i32_const(heap_base(memory) as i32))
+}
+
+/// Parameter and result types of `EXPORTS`
+pub fn types() -> [(Vec<ValType>, Vec<ValType>); 3] {
+    [
+        (vec![ValType::I32, ValType::I32], vec![ValType::I32]),
+        (vec![ValType::I32, ValType::I32], vec![]),
+        (vec![], vec![]),
+    ]
+}
+
+/// Bodies of `EXPORTS`, with the heap pointer in global `top`
+pub fn functions(memory: &LinearMemory, top: u32) -> [Function; 3] {
+    [alloc(memory.memory64, top), free(top), reset(heap_base(memory), top)]
+}
+
+// alloc(size, align): rounds the heap pointer up to align, a power of two,
+// and bumps it past size bytes
+pub fn alloc(memory64: bool, top: u32) -> Function {
+    let (size, align, ptr, end) = (0, 1, 2, 3);
+    // Memory size in bytes and growing it by pages, in i32
+    let memory_size: &[Instruction] = match memory64 {
+        true => &[Instruction::MemorySize(0), Instruction::I32WrapI64],
+        false => &[Instruction::MemorySize(0)],
+    };
+    let mut function = Function::new([(2, ValType::I32)]);
+    let mut emit = |instrs: &[Instruction]| instrs.iter().for_each(|instr| {
+        function.instruction(instr);
+    });
+    // ptr = (top + align - 1) & -align
+    emit(&[
+        Instruction::GlobalGet(top),
+        Instruction::LocalGet(align),
+        Instruction::I32Add,
+        Instruction::I32Const(1),
+        Instruction::I32Sub,
+        Instruction::I32Const(0),
+        Instruction::LocalGet(align),
+        Instruction::I32Sub,
+        Instruction::I32And,
+        Instruction::LocalSet(ptr),
+    ]);
+    // end = ptr + size, failing if it wraps around
+    emit(&[
+        Instruction::LocalGet(ptr),
+        Instruction::LocalGet(size),
+        Instruction::I32Add,
+        Instruction::LocalTee(end),
+        Instruction::LocalGet(ptr),
+        Instruction::I32LtU,
+        Instruction::If(BlockType::Empty),
+        Instruction::I32Const(0),
+        Instruction::Return,
+        Instruction::End,
+    ]);
+    // Grow by the missing pages if end is past the memory
+    emit(&[Instruction::LocalGet(end)]);
+    emit(memory_size);
+    emit(&[
+        Instruction::I32Const(16),
+        Instruction::I32Shl,
+        Instruction::I32GtU,
+        Instruction::If(BlockType::Empty),
+        Instruction::LocalGet(end),
+        Instruction::I32Const(0xffff),
+        Instruction::I32Add,
+        Instruction::I32Const(16),
+        Instruction::I32ShrU,
+    ]);
+    emit(memory_size);
+    emit(&[Instruction::I32Sub]);
+    match memory64 {
+        true => emit(&[
+            Instruction::I64ExtendI32U,
+            Instruction::MemoryGrow(0),
+            Instruction::I64Const(-1),
+            Instruction::I64Eq,
+        ]),
+        false => emit(&[Instruction::MemoryGrow(0), Instruction::I32Const(-1), Instruction::I32Eq]),
+    }
+    emit(&[
+        Instruction::If(BlockType::Empty),
+        Instruction::I32Const(0),
+        Instruction::Return,
+        Instruction::End,
+        Instruction::End,
+        Instruction::LocalGet(end),
+        Instruction::GlobalSet(top),
+        Instruction::LocalGet(ptr),
+        Instruction::End,
+    ]);
+    function
+}
+
+// free(ptr, size): moves the heap pointer back if it is the latest
+// allocation, other frees wait for reset
+pub fn free(top: u32) -> Function {
+    let mut function = Function::new([]);
+    for instr in [
+        Instruction::LocalGet(0),
+        Instruction::LocalGet(1),
+        Instruction::I32Add,
+        Instruction::GlobalGet(top),
+        Instruction::I32Eq,
+        Instruction::If(BlockType::Empty),
+        Instruction::LocalGet(0),
+        Instruction::GlobalSet(top),
+        Instruction::End,
+        Instruction::End,
+    ] {
+        function.instruction(&instr);
+    }
+    function
+}
+
+fn reset(heap_base: u32, top: u32) -> Function {
+    let mut function = Function::new([]);
+    for instr in [Instruction::I32Const(heap_base as i32), Instruction::GlobalSet(top), Instruction::End] {
+        function.instruction(&instr);
+    }
+    function
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::Arch;
+    use crate::backend::{FunctionIR, TranspilerBackend};
+    use crate::transpiler_real::{DirectBackend, TranspileOptions};
+    use wasmparser::{ExternalKind, Parser, Payload, Validator, WasmFeatures};
+
+    #[test]
+    fn exports_an_allocator_after_the_data_segments() {
+        let memory = LinearMemory { memory64: true, segments: vec![(0x1000, vec![0; 5])], allocator: true };
+        assert_eq!(heap_base(&memory), 0x1010);
+        assert_eq!(heap_base(&LinearMemory { segments: Vec::new(), ..memory.clone() }), 16);
+
+        // mov eax, 1; ret, which has no memory of its own
+        let code = [0xb8, 0x01, 0x00, 0x00, 0x00, 0xc3];
+        let function = FunctionIR::decode("test", Arch::X86_64, &code, 0x1000);
+        let backend = DirectBackend::new(TranspileOptions { allocator: true, ..Default::default() });
+        let wasm = backend.transpile(&function).unwrap();
+        Validator::new_with_features(WasmFeatures::all()).validate_all(&wasm).unwrap();
+        let exports: Vec<(String, ExternalKind, u32)> = Parser::new(0)
+            .parse_all(&wasm)
+            .filter_map(|payload| match payload.unwrap() {
+                Payload::ExportSection(reader) => Some(reader.into_iter().map(|export| {
+                    let export = export.unwrap();
+                    (export.name.to_string(), export.kind, export.index)
+                }).collect::<Vec<_>>()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        assert_eq!(exports, vec![
+            ("callback".to_string(), ExternalKind::Func, 0),
+            ("memory".to_string(), ExternalKind::Memory, 0),
+            ("alloc".to_string(), ExternalKind::Func, 1),
+            ("free".to_string(), ExternalKind::Func, 2),
+            ("reset".to_string(), ExternalKind::Func, 3),
+        ]);
+        assert!(DirectBackend::default().transpile(&function).unwrap().len() < wasm.len());
+    }
+}
@@ -0,0 +1,91 @@
+// Analysis cache
+// Reading the symbol tables of a large binary into the index of `symbols.rs`
+// and hashing the code of its functions takes a while on every boot. With
+// `[transpiler] analysis_cache = "<directory>"`, the index and the address,
+// size and code hash of every function body are written to
+// `<directory>/<binary SHA-256>.json` after the first analysis, and read
+// back instead as long as the binary is unchanged. A rebuilt binary has
+// another hash, so a file is never used for the wrong binary; one that
+// cannot be read is rebuilt and overwritten. Files of earlier builds are
+// left for the operator to remove.
+
+use crate::symbols::SymbolIndex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A function body of the binary
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FunctionEntry {
+    pub addr: u64,
+    pub size: u64,
+    /// See `X64ToWasmTranspiler::code_hash`
+    pub code_hash: String,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AnalysisIndex {
+    pub symbols: SymbolIndex,
+    /// By the name of `SymbolIndex::functions`
+    pub functions: BTreeMap<String, FunctionEntry>,
+}
+
+fn path(directory: &Path, binary_sha256: &str) -> PathBuf {
+    directory.join(format!("{}.json", binary_sha256))
+}
+
+/// The index of the binary with hash `binary_sha256`, none if it was not
+/// stored or cannot be read
+pub fn load(directory: &Path, binary_sha256: &str) -> Option<AnalysisIndex> {
+    let path = path(directory, binary_sha256);
+    let contents = std::fs::read(&path).ok()?;
+    match serde_json::from_slice(&contents) {
+        Ok(index) => Some(index),
+        Err(e) => {
+            tracing::warn!(error = %e, path = %path.display(), "Ignoring unreadable analysis cache");
+            None
+        }
+    }
+}
+
+/// Stores `index` for the binary with hash `binary_sha256`. Written to a
+/// temporary file first, so concurrent boots never read half a file.
+pub fn store(directory: &Path, binary_sha256: &str, index: &AnalysisIndex) -> io::Result<()> {
+    std::fs::create_dir_all(directory)?;
+    let path = path(directory, binary_sha256);
+    let partial = path.with_extension(format!("json.{}", std::process::id()));
+    std::fs::write(&partial, serde_json::to_vec(index).expect("analysis index serializes to JSON"))?;
+    std::fs::rename(&partial, &path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transpiler_real::X64ToWasmTranspiler;
+
+    #[test]
+    fn reloads_the_index_of_an_unchanged_binary() {
+        let directory = std::env::temp_dir().join(format!("self-serve-analysis-{}", std::process::id()));
+        let exe = std::env::current_exe().unwrap();
+        let exe = exe.to_str().unwrap();
+        let uncached = X64ToWasmTranspiler::new(exe).unwrap();
+
+        let first = X64ToWasmTranspiler::open(exe, Some(&directory)).unwrap();
+        let stored = super::load(&directory, first.binary_sha256()).unwrap();
+        let entry = &stored.functions["verify_ratio"];
+        assert_eq!(entry.code_hash, uncached.code_hash("verify_ratio").unwrap());
+        assert!(entry.size > 0);
+
+        // The second boot takes the index from the file
+        let second = X64ToWasmTranspiler::open(exe, Some(&directory)).unwrap();
+        assert_eq!(second.code_hash("verify_ratio").unwrap(), entry.code_hash);
+        assert_eq!(second.aliases("verify_ratio"), uncached.aliases("verify_ratio"));
+        second.decode_function("verify_ratio").unwrap();
+
+        // A broken file is rebuilt
+        std::fs::write(super::path(&directory, first.binary_sha256()), b"{").unwrap();
+        X64ToWasmTranspiler::open(exe, Some(&directory)).unwrap();
+        assert_eq!(super::load(&directory, first.binary_sha256()).unwrap(), stored);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
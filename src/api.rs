@@ -0,0 +1,105 @@
+// JSON API for non-browser clients
+// Exposes the current state, the callbacks that can be invoked on it and the
+// rendered DOM.
+
+use crate::auth::Permission;
+use crate::callbacks::Signature;
+use crate::{ServerContext, State};
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize)]
+pub struct StateSnapshot<'a> {
+    pub version: u64,
+    pub state: &'a State,
+    pub callbacks: Vec<CallbackDescription<'a>>,
+}
+
+#[derive(Serialize)]
+pub struct CallbackDescription<'a> {
+    pub name: &'a str,
+    pub permission: Permission,
+    pub signature: &'a Signature,
+    pub read_only: bool,
+    pub wasm: String,
+    pub execute: String,
+}
+
+/// True if the client asked for JSON rather than HTML.
+pub fn accepts_json(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json") && !accept.contains("text/html"))
+}
+
+pub fn state_response(ctx: &ServerContext) -> HttpResponse {
+    let state = ctx.state.read();
+
+    let snapshot = StateSnapshot {
+        version: ctx.state.version(),
+        state: &state,
+        callbacks: ctx.callbacks
+            .iter()
+            .map(|callback| CallbackDescription {
+                name: &callback.name,
+                permission: callback.permission,
+                signature: &callback.signature,
+                read_only: callback.read_only,
+                wasm: format!("/wasm/{}", callback.name),
+                execute: format!("/execute/{}", callback.name),
+            })
+            .collect(),
+    };
+
+    HttpResponse::Ok()
+        .insert_header((header::VARY, "Accept"))
+        .json(snapshot)
+}
+
+pub async fn get_state(ctx: web::Data<ServerContext>) -> impl Responder {
+    state_response(&ctx)
+}
+
+/// The page's DOM as JSON, for native clients such as a TUI that render it
+/// themselves. Text is translated into the session's locale, as on the page.
+pub async fn get_dom(req: HttpRequest, ctx: web::Data<ServerContext>) -> impl Responder {
+    let state = ctx.state.read();
+    let locale = ctx.i18n.locale_for(&req);
+    let locales: Vec<&str> = ctx.i18n.locales().collect();
+    let dom = crate::render_app(&state, &locales, &ctx.templates)
+        .translated(&|key, args| ctx.i18n.translate(locale, key, args));
+
+    HttpResponse::Ok()
+        .insert_header((header::VARY, "Accept-Language, Cookie"))
+        .json(serde_json::json!({
+            "version": ctx.state.version(),
+            "locale": locale,
+            "dom": dom,
+        }))
+}
+
+#[derive(Deserialize)]
+pub struct ByteRange {
+    offset: Option<usize>,
+    len: Option<usize>,
+}
+
+/// Raw bytes of the state (or `?offset=&len=` of it), backing
+/// `env.read_i64` in the client glue. Ranges are clamped to the state size.
+pub async fn get_state_bytes(
+    range: web::Query<ByteRange>,
+    ctx: web::Data<ServerContext>,
+) -> impl Responder {
+    let state = ctx.state.read();
+    let bytes = state.as_bytes();
+
+    let start = range.offset.unwrap_or(0).min(bytes.len());
+    let end = start.saturating_add(range.len.unwrap_or(bytes.len())).min(bytes.len());
+
+    HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .insert_header(("X-State-Version", ctx.state.version().to_string()))
+        .body(bytes[start..end].to_vec())
+}
@@ -0,0 +1,360 @@
+// Source architectures
+// The machine type of the object file selects the decoder. Both front-ends
+// produce MachineInstr values, which the CFG builder and the IR lifter
+// understand without caring where they came from.
+
+use crate::ir::{RegKey, Ty};
+use iced_x86::{Code, Decoder, DecoderOptions, Formatter, FlowControl, IntelFormatter, MemorySize, Mnemonic, Register};
+use std::collections::{HashMap, HashSet};
+use yaxpeax_arch::{Decoder as _, U8Reader};
+use yaxpeax_arm::armv8::a64::{self, InstDecoder, Opcode, Operand};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    X86_64,
+    /// 32-bit x86 (i386), for legacy plugin binaries
+    X86,
+    AArch64,
+    #[cfg(feature = "riscv")]
+    RiscV64,
+}
+
+impl Arch {
+    pub fn from_object(architecture: object::Architecture) -> Result<Self, String> {
+        match architecture {
+            object::Architecture::X86_64 => Ok(Arch::X86_64),
+            object::Architecture::I386 => Ok(Arch::X86),
+            object::Architecture::Aarch64 => Ok(Arch::AArch64),
+            #[cfg(feature = "riscv")]
+            object::Architecture::Riscv64 => Ok(Arch::RiscV64),
+            other => Err(format!("Unsupported architecture: {:?}", other)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Arch::X86_64 => "x86-64",
+            Arch::X86 => "x86",
+            Arch::AArch64 => "AArch64",
+            #[cfg(feature = "riscv")]
+            Arch::RiscV64 => "RV64",
+        }
+    }
+
+    /// Width of the general purpose registers
+    pub fn word(self) -> Ty {
+        match self {
+            Arch::X86 => Ty::I32,
+            _ => Ty::I64,
+        }
+    }
+
+    /// The stack pointer, whose value on entry the function's frame is
+    /// addressed from
+    pub fn stack_pointer(self) -> RegKey {
+        match self {
+            Arch::X86_64 | Arch::X86 => RegKey::X86(Register::RSP),
+            Arch::AArch64 => RegKey::AArch64(31),
+            // sp is x2
+            #[cfg(feature = "riscv")]
+            Arch::RiscV64 => RegKey::RiscV(2),
+        }
+    }
+
+    /// Integer argument registers, in calling convention order. 32-bit x86
+    /// passes arguments on the stack.
+    pub fn arg_registers(self) -> Vec<RegKey> {
+        match self {
+            Arch::X86_64 => [Register::RDI, Register::RSI, Register::RDX, Register::RCX, Register::R8, Register::R9]
+                .into_iter()
+                .map(RegKey::X86)
+                .collect(),
+            Arch::X86 => Vec::new(),
+            Arch::AArch64 => (0..8).map(RegKey::AArch64).collect(),
+            // a0-a7
+            #[cfg(feature = "riscv")]
+            Arch::RiscV64 => (10..18).map(RegKey::RiscV).collect(),
+        }
+    }
+
+    /// Float argument registers, in calling convention order; the first one
+    /// also holds a float result. Only SSE registers on x86-64 are lifted.
+    pub fn float_arg_registers(self) -> Vec<RegKey> {
+        match self {
+            Arch::X86_64 => [
+                Register::XMM0, Register::XMM1, Register::XMM2, Register::XMM3,
+                Register::XMM4, Register::XMM5, Register::XMM6, Register::XMM7,
+            ]
+                .into_iter()
+                .map(|register| RegKey::X86(register.full_register()))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn decode(self, code: &[u8], entry: u64) -> Vec<MachineInstr> {
+        match self {
+            Arch::X86_64 | Arch::X86 => Decoder::with_ip(self.bitness(), code, entry, DecoderOptions::NONE)
+                .iter()
+                .map(MachineInstr::X86)
+                .collect(),
+            Arch::AArch64 => {
+                let decoder = InstDecoder::default();
+                // Instructions are fixed-width; undecodable words are kept
+                // as invalid instructions so addresses stay aligned
+                code.chunks_exact(4)
+                    .enumerate()
+                    .map(|(idx, word)| {
+                        let instr = decoder
+                            .decode(&mut U8Reader::new(word))
+                            .unwrap_or(a64::Instruction {
+                                opcode: Opcode::Invalid,
+                                operands: [Operand::Nothing; 4],
+                            });
+                        MachineInstr::AArch64 { addr: entry + idx as u64 * 4, instr }
+                    })
+                    .collect()
+            }
+            #[cfg(feature = "riscv")]
+            Arch::RiscV64 => crate::riscv::decode(code, entry),
+        }
+    }
+
+    /// Length of the code reachable from `entry`, following branches that
+    /// stay inside `code` until every path returns, traps or jumps out
+    pub fn reachable_len(self, code: &[u8], entry: u64) -> usize {
+        let instructions = self.decode(code, entry);
+        let index: HashMap<u64, usize> = instructions.iter().enumerate().map(|(idx, instr)| (instr.addr(), idx)).collect();
+        let mut visited = HashSet::new();
+        let mut pending = vec![entry];
+        let mut len = 0;
+        while let Some(addr) = pending.pop() {
+            let Some(&idx) = index.get(&addr) else { continue };
+            let instr = &instructions[idx];
+            if !visited.insert(idx) || instr.is_invalid() {
+                continue;
+            }
+            let next = addr + instr.len() as u64;
+            len = len.max((next - entry) as usize);
+            pending.extend(instr.branch_target());
+            if instr.falls_through() {
+                pending.push(next);
+            }
+        }
+        len
+    }
+
+    fn bitness(self) -> u32 {
+        match self {
+            Arch::X86 => 32,
+            _ => 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum MachineInstr {
+    X86(iced_x86::Instruction),
+    AArch64 { addr: u64, instr: a64::Instruction },
+    #[cfg(feature = "riscv")]
+    RiscV {
+        addr: u64,
+        len: u8,
+        instr: Result<riscv_decode::Instruction, riscv_decode::DecodingError>,
+    },
+}
+
+impl MachineInstr {
+    pub fn addr(&self) -> u64 {
+        match self {
+            MachineInstr::X86(instr) => instr.ip(),
+            MachineInstr::AArch64 { addr, .. } => *addr,
+            #[cfg(feature = "riscv")]
+            MachineInstr::RiscV { addr, .. } => *addr,
+        }
+    }
+
+    /// Length of the encoding in bytes, never 0
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        match self {
+            MachineInstr::X86(instr) => instr.len(),
+            MachineInstr::AArch64 { .. } => 4,
+            #[cfg(feature = "riscv")]
+            MachineInstr::RiscV { len, .. } => *len as usize,
+        }
+    }
+
+    pub fn text(&self) -> String {
+        match self {
+            MachineInstr::X86(instr) => {
+                let mut formatter = IntelFormatter::new();
+                let mut text = String::new();
+                formatter.format(instr, &mut text);
+                text
+            }
+            MachineInstr::AArch64 { instr, .. } => instr.to_string(),
+            #[cfg(feature = "riscv")]
+            MachineInstr::RiscV { addr, instr, .. } => crate::riscv::text(*addr, instr),
+        }
+    }
+
+    pub fn mnemonic(&self) -> String {
+        match self {
+            MachineInstr::X86(instr) => format!("{:?}", instr.mnemonic()).to_lowercase(),
+            MachineInstr::AArch64 { instr, .. } => {
+                let text = instr.to_string();
+                text.split_whitespace().next().unwrap_or_default().to_string()
+            }
+            #[cfg(feature = "riscv")]
+            MachineInstr::RiscV { instr, .. } => crate::riscv::mnemonic(instr),
+        }
+    }
+
+    /// Bytes the decoder did not recognize as an instruction: data in
+    /// `.text`, or code decoded from the wrong offset
+    pub fn is_invalid(&self) -> bool {
+        match self {
+            MachineInstr::X86(instr) => instr.code() == Code::INVALID,
+            MachineInstr::AArch64 { instr, .. } => instr.opcode == Opcode::Invalid,
+            #[cfg(feature = "riscv")]
+            MachineInstr::RiscV { instr, .. } => instr.is_err(),
+        }
+    }
+
+    /// Accesses thread-local storage through an `fs:`/`gs:` segment
+    /// override. These address the thread control block, not the flat
+    /// address space, and have no WASM equivalent.
+    pub fn is_thread_local(&self) -> bool {
+        match self {
+            MachineInstr::X86(instr) => {
+                (0..instr.op_count()).any(|operand| instr.op_kind(operand) == iced_x86::OpKind::Memory)
+                    && matches!(instr.memory_segment(), Register::FS | Register::GS)
+            }
+            _ => false,
+        }
+    }
+
+    /// x87 instructions whose result depends on 80-bit extended precision:
+    /// tbyte loads and stores, and control word loads, which set the
+    /// precision and rounding mode. The lifter models the x87 stack with
+    /// doubles.
+    pub fn is_extended_precision(&self) -> bool {
+        match self {
+            MachineInstr::X86(instr) => {
+                instr.memory_size() == MemorySize::Float80 || matches!(instr.mnemonic(), Mnemonic::Fldcw | Mnemonic::Fldenv | Mnemonic::Frstor)
+            }
+            _ => false,
+        }
+    }
+
+    /// x87 arithmetic, which is computed in double precision
+    pub fn is_x87_arithmetic(&self) -> bool {
+        match self {
+            MachineInstr::X86(instr) => matches!(
+                instr.mnemonic(),
+                Mnemonic::Fadd | Mnemonic::Faddp | Mnemonic::Fsub | Mnemonic::Fsubp | Mnemonic::Fsubr | Mnemonic::Fsubrp |
+                Mnemonic::Fmul | Mnemonic::Fmulp | Mnemonic::Fdiv | Mnemonic::Fdivp | Mnemonic::Fdivr | Mnemonic::Fdivrp
+            ),
+            _ => false,
+        }
+    }
+
+    /// Target of a direct jump or conditional branch
+    pub fn branch_target(&self) -> Option<u64> {
+        match self {
+            MachineInstr::X86(instr) => {
+                (instr.is_jmp_short_or_near() || instr.is_jcc_short_or_near()).then(|| instr.near_branch_target())
+            }
+            MachineInstr::AArch64 { addr, instr } => match instr.opcode {
+                Opcode::B | Opcode::Bcc(_) | Opcode::CBZ | Opcode::CBNZ | Opcode::TBZ | Opcode::TBNZ => {
+                    instr.operands.iter().find_map(|operand| match operand {
+                        Operand::PCOffset(offset) => Some(addr.wrapping_add(*offset as u64)),
+                        _ => None,
+                    })
+                }
+                _ => None,
+            },
+            #[cfg(feature = "riscv")]
+            MachineInstr::RiscV { addr, instr, .. } => {
+                instr.as_ref().ok().and_then(|instr| crate::riscv::branch_target(*addr, instr))
+            }
+        }
+    }
+
+    /// Target of a direct call
+    pub fn call_target(&self) -> Option<u64> {
+        match self {
+            MachineInstr::X86(instr) => instr.is_call_near().then(|| instr.near_branch_target()),
+            MachineInstr::AArch64 { addr, instr } => match instr.opcode {
+                Opcode::BL => instr.operands.iter().find_map(|operand| match operand {
+                    Operand::PCOffset(offset) => Some(addr.wrapping_add(*offset as u64)),
+                    _ => None,
+                }),
+                _ => None,
+            },
+            #[cfg(feature = "riscv")]
+            MachineInstr::RiscV { addr, instr, .. } => {
+                instr.as_ref().ok().and_then(|instr| crate::riscv::call_target(*addr, instr))
+            }
+        }
+    }
+
+    /// Execution can continue with the next instruction: everything but
+    /// returns, unconditional jumps and traps
+    pub fn falls_through(&self) -> bool {
+        match self {
+            MachineInstr::X86(instr) => {
+                matches!(
+                    instr.flow_control(),
+                    FlowControl::Next | FlowControl::ConditionalBranch | FlowControl::Call | FlowControl::IndirectCall | FlowControl::Interrupt
+                ) && !matches!(instr.mnemonic(), Mnemonic::Int3 | Mnemonic::Int)
+            }
+            MachineInstr::AArch64 { instr, .. } => {
+                !matches!(instr.opcode, Opcode::B | Opcode::BR | Opcode::RET | Opcode::UDF | Opcode::BRK)
+            }
+            #[cfg(feature = "riscv")]
+            MachineInstr::RiscV { instr, .. } => instr.as_ref().is_ok_and(crate::riscv::falls_through),
+        }
+    }
+
+    /// Jumps, branches, calls and returns end a basic block
+    pub fn ends_block(&self) -> bool {
+        match self {
+            // Traps (ud2, int3, int n) are lowered to `unreachable`
+            MachineInstr::X86(instr) => {
+                !matches!(instr.flow_control(), FlowControl::Next | FlowControl::Interrupt | FlowControl::Exception)
+                    || matches!(instr.mnemonic(), Mnemonic::Ud2 | Mnemonic::Int3 | Mnemonic::Int)
+            }
+            MachineInstr::AArch64 { instr, .. } => matches!(
+                instr.opcode,
+                Opcode::B | Opcode::Bcc(_) | Opcode::CBZ | Opcode::CBNZ | Opcode::TBZ | Opcode::TBNZ
+                    | Opcode::BL | Opcode::BLR | Opcode::BR | Opcode::RET | Opcode::UDF | Opcode::BRK
+            ),
+            #[cfg(feature = "riscv")]
+            MachineInstr::RiscV { instr, .. } => instr.as_ref().is_ok_and(crate::riscv::ends_block),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn infers_the_length_of_reachable_code() {
+        // test edi, edi; je +6; mov eax, 1; ret; xor eax, eax; ret; int3 padding
+        let code = [
+            0x85, 0xff,
+            0x74, 0x06,
+            0xb8, 0x01, 0x00, 0x00, 0x00,
+            0xc3,
+            0x31, 0xc0,
+            0xc3,
+            0xcc, 0xcc, 0xcc,
+        ];
+        assert_eq!(Arch::X86_64.reachable_len(&code, 0x1000), 13);
+        // A tail jump out of the code ends the path too: jmp +0x100
+        assert_eq!(Arch::X86_64.reachable_len(&[0xe9, 0x00, 0x01, 0x00, 0x00, 0xcc], 0x1000), 5);
+    }
+}
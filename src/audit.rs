@@ -0,0 +1,272 @@
+// Execution audit log
+// Every callback execution, from /execute, batches, /sync and schedules, is
+// recorded with its time, the session that ran it, the callback, a SHA-256
+// of its arguments, its result or error and the state version it committed,
+// so drift between a client's replica and the server can be traced to the
+// executions behind it. Entries are inserted into the `executions` table of
+// the SQLite database at `[audit] path`, which outlives restarts; without a
+// path the database is in memory. Entries older than `retention_days` and
+// beyond the newest `max_entries` are deleted as new ones are recorded.
+// `GET /admin/audit` queries the log, newest first.
+
+use crate::config::AuditConfig;
+use crate::hooks::Trigger;
+use actix_web::HttpRequest;
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection, Row};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    /// Unix time in milliseconds
+    pub at: u64,
+    /// `session_digest` of the session, none for schedules and requests
+    /// without a session cookie
+    pub session: Option<String>,
+    pub callback: String,
+    pub trigger: Trigger,
+    pub args_sha256: String,
+    pub result: Option<serde_json::Value>,
+    pub error: Option<String>,
+    /// The version committed, none if the execution changed nothing
+    pub version: Option<u64>,
+}
+
+/// An execution to record, before it gets its sequence number and time
+pub struct Execution<'a> {
+    pub session: Option<String>,
+    pub callback: &'a str,
+    pub trigger: Trigger,
+    pub args: &'a [serde_json::Value],
+    pub outcome: Result<serde_json::Value, String>,
+    pub version: Option<u64>,
+}
+
+/// What `GET /admin/audit` filters by. Its form sends the fields left
+/// empty as `name=`, which filter nothing.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditFilter {
+    #[serde(default, deserialize_with = "non_empty")]
+    pub callback: Option<String>,
+    #[serde(default, deserialize_with = "non_empty")]
+    pub session: Option<String>,
+    #[serde(default, deserialize_with = "non_empty")]
+    pub trigger: Option<Trigger>,
+    /// Committed versions from `since` to `until`, both included
+    #[serde(default, deserialize_with = "non_empty")]
+    pub since: Option<u64>,
+    #[serde(default, deserialize_with = "non_empty")]
+    pub until: Option<u64>,
+    /// Only failed executions
+    #[serde(default)]
+    pub failed: bool,
+}
+
+fn non_empty<'de, D: Deserializer<'de>, T: FromStr<Err: fmt::Display>>(deserializer: D) -> Result<Option<T>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .filter(|value| !value.is_empty())
+        .map(|value| value.parse().map_err(de::Error::custom))
+        .transpose()
+}
+
+impl AuditFilter {
+    // The WHERE clause selecting the entries the filter matches, and its
+    // parameters
+    fn condition(&self) -> (String, Vec<Value>) {
+        let mut conditions = vec!["1".to_string()];
+        let mut params = Vec::new();
+        let mut add = |condition: &str, value: Value| {
+            conditions.push(condition.to_string());
+            params.push(value);
+        };
+        if let Some(callback) = &self.callback {
+            add("callback = ?", Value::Text(callback.clone()));
+        }
+        if let Some(session) = &self.session {
+            add("session = ?", Value::Text(session.clone()));
+        }
+        if let Some(trigger) = self.trigger {
+            add("trigger = ?", Value::Text(trigger.to_string()));
+        }
+        if let Some(since) = self.since {
+            add("version >= ?", Value::Integer(since as i64));
+        }
+        if let Some(until) = self.until {
+            add("version <= ?", Value::Integer(until as i64));
+        }
+        if self.failed {
+            conditions.push("error IS NOT NULL".to_string());
+        }
+        (conditions.join(" AND "), params)
+    }
+}
+
+pub struct AuditLog {
+    config: AuditConfig,
+    db: Mutex<Connection>,
+}
+
+/// Identifies a session in the log without revealing its id, which the
+/// CSRF token is derived from
+pub fn session_digest(req: &HttpRequest) -> Option<String> {
+    let cookie = req.cookie(crate::csrf::SESSION_COOKIE)?;
+    let mut digest = hex::encode(Sha256::digest(cookie.value()));
+    digest.truncate(16);
+    Some(digest)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+// An entry from a row of `executions`. Times and versions fit SQLite's
+// signed integers.
+fn entry(row: &Row) -> rusqlite::Result<AuditEntry> {
+    let json = |idx: usize| -> rusqlite::Result<Option<serde_json::Value>> {
+        row.get::<_, Option<String>>(idx).map(|text| text.and_then(|text| serde_json::from_str(&text).ok()))
+    };
+    let trigger: String = row.get(4)?;
+    Ok(AuditEntry {
+        seq: row.get::<_, i64>(0)? as u64,
+        at: row.get::<_, i64>(1)? as u64,
+        session: row.get(2)?,
+        callback: row.get(3)?,
+        trigger: trigger.parse().map_err(|e: String| rusqlite::Error::FromSqlConversionFailure(4, rusqlite::types::Type::Text, e.into()))?,
+        args_sha256: row.get(5)?,
+        result: json(6)?,
+        error: row.get(7)?,
+        version: row.get::<_, Option<i64>>(8)?.map(|version| version as u64),
+    })
+}
+
+impl AuditLog {
+    /// Opens the database at `config.path`, creating the table if missing,
+    /// and drops the entries no longer retained
+    pub fn open(config: &AuditConfig) -> Result<Self, String> {
+        let db = match config.path.as_deref() {
+            Some(path) => Connection::open(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?,
+            None => Connection::open_in_memory().map_err(|e| e.to_string())?,
+        };
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS executions (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                at INTEGER NOT NULL,
+                session TEXT,
+                callback TEXT NOT NULL,
+                trigger TEXT NOT NULL,
+                args_sha256 TEXT NOT NULL,
+                result TEXT,
+                error TEXT,
+                version INTEGER
+            );
+            CREATE INDEX IF NOT EXISTS executions_at ON executions (at);
+            CREATE INDEX IF NOT EXISTS executions_session ON executions (session);
+            CREATE INDEX IF NOT EXISTS executions_version ON executions (version);",
+        )
+        .map_err(|e| format!("Could not create the audit log: {}", e))?;
+        let audit = Self { config: config.clone(), db: Mutex::new(db) };
+        audit.prune(&audit.db.lock().unwrap(), now_ms()).map_err(|e| format!("Could not prune the audit log: {}", e))?;
+        Ok(audit)
+    }
+
+    fn prune(&self, db: &Connection, now: u64) -> rusqlite::Result<usize> {
+        let cutoff = now.saturating_sub(self.config.retention_days.saturating_mul(DAY_MS));
+        db.execute(
+            "DELETE FROM executions WHERE at < ?1 OR seq <= (SELECT MAX(seq) FROM executions) - ?2",
+            (cutoff.min(i64::MAX as u64) as i64, self.config.max_entries.min(i64::MAX as usize) as i64),
+        )
+    }
+
+    pub fn record(&self, execution: Execution) {
+        let (result, error) = match execution.outcome {
+            Ok(result) => (Some(result.to_string()), None),
+            Err(error) => (None, Some(error)),
+        };
+        let args = serde_json::to_vec(execution.args).expect("arguments serialize to JSON");
+        let now = now_ms();
+        let db = self.db.lock().unwrap();
+        let inserted = db.execute(
+            "INSERT INTO executions (at, session, callback, trigger, args_sha256, result, error, version) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            (
+                now as i64,
+                execution.session,
+                execution.callback,
+                execution.trigger.to_string(),
+                hex::encode(Sha256::digest(args)),
+                result,
+                error,
+                execution.version.map(|version| version as i64),
+            ),
+        );
+        if let Err(e) = inserted.and_then(|_| self.prune(&db, now)) {
+            tracing::warn!(error = %e, "Could not record an execution in the audit log");
+        }
+    }
+
+    /// The entries `filter` matches, newest first
+    pub fn query(&self, filter: &AuditFilter) -> Vec<AuditEntry> {
+        let (condition, params) = filter.condition();
+        let db = self.db.lock().unwrap();
+        let sql = format!(
+            "SELECT seq, at, session, callback, trigger, args_sha256, result, error, version FROM executions WHERE {} ORDER BY seq DESC",
+            condition
+        );
+        let entries = db.prepare(&sql).and_then(|mut statement| statement.query_map(params_from_iter(params), entry)?.collect());
+        entries.unwrap_or_else(|e| {
+            tracing::warn!(error = %e, "Could not query the audit log");
+            Vec::new()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn execution(callback: &str, version: Option<u64>, outcome: Result<serde_json::Value, String>) -> Execution<'_> {
+        Execution { session: Some("abc".to_string()), callback, trigger: Trigger::Execute, args: &[], outcome, version }
+    }
+
+    #[test]
+    fn persists_queries_and_retains_executions() {
+        let path = std::env::temp_dir().join(format!("self-serve-audit-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = AuditConfig { path: Some(path.clone()), retention_days: 30, max_entries: 3 };
+
+        let audit = AuditLog::open(&config).unwrap();
+        audit.record(execution("increment_counter", Some(1), Ok(json!(1))));
+        audit.record(execution("set_counter", None, Err("rejected".to_string())));
+        audit.record(Execution { args: &[json!("5")], ..execution("set_counter", Some(2), Ok(json!(5))) });
+        let failed = audit.query(&AuditFilter { failed: true, ..Default::default() });
+        assert_eq!(failed.iter().map(|entry| entry.seq).collect::<Vec<_>>(), [2]);
+        let set = audit.query(&AuditFilter { callback: Some("set_counter".to_string()), since: Some(2), ..Default::default() });
+        assert_eq!(set.len(), 1);
+        assert_eq!((set[0].trigger, &set[0].result), (Trigger::Execute, &Some(json!(5))));
+        // echo -n '["5"]' | sha256sum
+        assert_eq!(set[0].args_sha256, "5e3322c682b4e46a737ec3a18be48fc20ba87309ae7f942cef08eb51f2a6537e");
+
+        // The fourth entry drops the oldest, and the log outlives the process
+        audit.record(execution("decrement_counter", Some(3), Ok(json!(0))));
+        drop(audit);
+        let reopened = AuditLog::open(&config).unwrap();
+        let seqs: Vec<u64> = reopened.query(&AuditFilter::default()).iter().map(|entry| entry.seq).collect();
+        assert_eq!(seqs, [4, 3, 2]);
+        reopened.record(execution("increment_counter", Some(4), Ok(json!(1))));
+        assert_eq!(reopened.query(&AuditFilter::default())[0].seq, 5);
+
+        // Retention drops everything recorded before the cutoff
+        reopened.prune(&reopened.db.lock().unwrap(), now_ms() + 31 * DAY_MS).unwrap();
+        assert!(reopened.query(&AuditFilter::default()).is_empty());
+        drop(reopened);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
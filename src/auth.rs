@@ -0,0 +1,246 @@
+// Authentication and authorization for callback execution
+// Every mutating entry point asks `Auth::authorize` whether the caller may
+// invoke a callback with the permission level declared at registration.
+
+use crate::config::AuthConfig;
+use crate::error::ApiError;
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use base64::Engine;
+use serde::Serialize;
+use std::fmt;
+use std::sync::Arc;
+
+/// Permission levels, ordered from least to most privileged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Public,
+    User,
+    Admin,
+}
+
+pub type Validator = Arc<dyn Fn(&HttpRequest) -> Option<Permission> + Send + Sync>;
+
+pub enum AuthProvider {
+    /// No credentials are checked, every caller is treated as an admin.
+    Disabled,
+    /// `Authorization: Bearer <token>` grants admin access.
+    BearerToken(String),
+    /// `Authorization: Basic <base64(user:pass)>` grants admin access.
+    Basic { username: String, password: String },
+    /// User-provided closure that maps a request to the caller's permission
+    /// level, or `None` if the credentials are invalid.
+    Validator(Validator),
+}
+
+pub struct Auth {
+    provider: AuthProvider,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// Missing or invalid credentials (401)
+    Unauthenticated,
+    /// Valid credentials, but not enough privileges for this callback (403)
+    Forbidden { required: Permission },
+}
+
+impl Auth {
+    pub fn new(provider: AuthProvider) -> Self {
+        Self { provider }
+    }
+
+    /// `validator` with `[auth] validator = true`, the provider of the
+    /// environment otherwise
+    pub fn load(config: &AuthConfig, validator: Validator) -> Self {
+        match config.validator {
+            true => Self::new(AuthProvider::Validator(validator)),
+            false => Self::from_env(),
+        }
+    }
+
+    /// Reads the provider from the environment:
+    /// `SELF_SERVE_AUTH_TOKEN=<token>` or `SELF_SERVE_BASIC_AUTH=<user>:<password>`.
+    /// Without either variable, authentication is disabled.
+    pub fn from_env() -> Self {
+        if let Ok(token) = std::env::var("SELF_SERVE_AUTH_TOKEN") {
+            return Self::new(AuthProvider::BearerToken(token));
+        }
+
+        if let Ok(credentials) = std::env::var("SELF_SERVE_BASIC_AUTH") {
+            if let Some((username, password)) = credentials.split_once(':') {
+                return Self::new(AuthProvider::Basic {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                });
+            }
+            tracing::warn!("SELF_SERVE_BASIC_AUTH must be <user>:<password>, ignoring");
+        }
+
+        Self::new(AuthProvider::Disabled)
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self.provider {
+            AuthProvider::Disabled => "disabled",
+            AuthProvider::BearerToken(_) => "bearer token",
+            AuthProvider::Basic { .. } => "basic auth",
+            AuthProvider::Validator(_) => "custom validator",
+        }
+    }
+
+    /// Determines the permission level of the caller. Requests without
+    /// credentials are `Public`, requests with invalid credentials are rejected.
+    pub fn permission(&self, req: &HttpRequest) -> Result<Permission, AuthError> {
+        let authorization = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+
+        match &self.provider {
+            AuthProvider::Disabled => Ok(Permission::Admin),
+            AuthProvider::BearerToken(token) => match authorization {
+                None => Ok(Permission::Public),
+                Some(value) => match value.strip_prefix("Bearer ") {
+                    Some(given) if constant_time_eq(given.as_bytes(), token.as_bytes()) => {
+                        Ok(Permission::Admin)
+                    }
+                    _ => Err(AuthError::Unauthenticated),
+                },
+            },
+            AuthProvider::Basic { username, password } => match authorization {
+                None => Ok(Permission::Public),
+                Some(value) => {
+                    let decoded = value
+                        .strip_prefix("Basic ")
+                        .and_then(|encoded| {
+                            base64::engine::general_purpose::STANDARD.decode(encoded).ok()
+                        })
+                        .and_then(|bytes| String::from_utf8(bytes).ok());
+
+                    let expected = format!("{}:{}", username, password);
+                    match decoded {
+                        Some(given) if constant_time_eq(given.as_bytes(), expected.as_bytes()) => {
+                            Ok(Permission::Admin)
+                        }
+                        _ => Err(AuthError::Unauthenticated),
+                    }
+                }
+            },
+            AuthProvider::Validator(validator) => {
+                validator(req).ok_or(AuthError::Unauthenticated)
+            }
+        }
+    }
+
+    pub fn authorize(&self, req: &HttpRequest, required: Permission) -> Result<Permission, AuthError> {
+        let granted = self.permission(req)?;
+
+        if granted >= required {
+            Ok(granted)
+        } else if granted == Permission::Public {
+            // Anonymous callers get a chance to retry with credentials
+            Err(AuthError::Unauthenticated)
+        } else {
+            Err(AuthError::Forbidden { required })
+        }
+    }
+
+    fn challenge(&self) -> Option<&'static str> {
+        match self.provider {
+            AuthProvider::BearerToken(_) => Some("Bearer"),
+            AuthProvider::Basic { .. } => Some("Basic realm=\"self-serve\""),
+            _ => None,
+        }
+    }
+}
+
+impl AuthError {
+    /// Builds the error response, including the `WWW-Authenticate` challenge
+    /// for the configured provider.
+    pub fn into_response(self, auth: &Auth) -> HttpResponse {
+        let mut response = self.error_response();
+        if let (AuthError::Unauthenticated, Some(challenge)) = (&self, auth.challenge()) {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                header::HeaderValue::from_static(challenge),
+            );
+        }
+        response
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Unauthenticated => write!(f, "Authentication required"),
+            AuthError::Forbidden { required } => {
+                write!(f, "Insufficient permissions (requires {:?})", required)
+            }
+        }
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Unauthenticated => StatusCode::UNAUTHORIZED,
+            AuthError::Forbidden { .. } => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let code = match self {
+            AuthError::Unauthenticated => "unauthenticated",
+            AuthError::Forbidden { .. } => "forbidden",
+        };
+        ApiError::new(self.status_code(), code, self.to_string()).error_response()
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_bearer_token_levels() {
+        let auth = Auth::new(AuthProvider::BearerToken("secret".to_string()));
+
+        let anonymous = TestRequest::default().to_http_request();
+        assert_eq!(auth.permission(&anonymous).unwrap(), Permission::Public);
+        assert!(auth.authorize(&anonymous, Permission::User).is_err());
+
+        let valid = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_http_request();
+        assert_eq!(auth.authorize(&valid, Permission::Admin).unwrap(), Permission::Admin);
+
+        let invalid = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Bearer wrong"))
+            .to_http_request();
+        assert!(matches!(auth.permission(&invalid), Err(AuthError::Unauthenticated)));
+    }
+
+    #[test]
+    fn test_validator_forbidden() {
+        let auth = Auth::load(&AuthConfig { validator: true }, Arc::new(|_| Some(Permission::User)));
+        assert_eq!(auth.describe(), "custom validator");
+        let req = TestRequest::default().to_http_request();
+
+        assert!(auth.authorize(&req, Permission::User).is_ok());
+        assert!(matches!(
+            auth.authorize(&req, Permission::Admin),
+            Err(AuthError::Forbidden { required: Permission::Admin })
+        ));
+    }
+}
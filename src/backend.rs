@@ -0,0 +1,203 @@
+// Transpilation backends
+// The binary is decoded into a backend-independent FunctionIR (instructions
+// and basic blocks); a TranspilerBackend turns that into a WASM module. New
+// lowering strategies implement the trait and get a BackendKind variant.
+
+use crate::arch::Arch;
+use crate::transpiler_real::{ControlFlowGraph, DirectBackend, InstructionInfo, LoweredFunction, TranspileOptions};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+/// A decoded function, the input of every backend
+pub struct FunctionIR {
+    pub name: String,
+    pub arch: Arch,
+    pub entry: u64,
+    pub code: Vec<u8>,
+    pub instructions: Vec<InstructionInfo>,
+    pub cfg: ControlFlowGraph,
+    /// Address the binary is loaded at, added to RIP-relative addresses.
+    /// Shared libraries and position-independent executables are linked
+    /// at 0.
+    pub load_bias: u64,
+    /// Pointers the dynamic loader stores (GOT slots), by the link-time
+    /// address of the slot, with the load bias applied
+    pub relocations: HashMap<u64, u64>,
+    /// Initialized data sections (.data, .rodata), at their addresses with
+    /// the load bias applied, see `embed.rs`
+    pub data: Vec<DataBlock>,
+    /// Ranges of `data` a specialization fixed, whose loads are folded to
+    /// constants, see `specialize.rs`
+    pub fixed: Vec<Range<u64>>,
+    /// Functions it calls directly that are small enough to inline, by
+    /// entry address, see `inline.rs`. Only looked up in linked binaries.
+    pub callees: HashMap<u64, FunctionIR>,
+}
+
+/// Contents of a data section of the binary
+#[derive(Debug, Clone)]
+pub struct DataBlock {
+    pub addr: u64,
+    pub bytes: Vec<u8>,
+    pub big_endian: bool,
+}
+
+impl DataBlock {
+    /// The `len` bytes at `addr`, if they lie in this section
+    pub fn read(&self, addr: u64, len: usize) -> Option<&[u8]> {
+        let start = addr.checked_sub(self.addr)? as usize;
+        self.bytes.get(start..start.checked_add(len)?)
+    }
+}
+
+impl FunctionIR {
+    /// Disassembles `code` starting at `entry` and splits it into basic blocks.
+    pub fn decode(name: &str, arch: Arch, code: &[u8], entry: u64) -> Self {
+        let instructions: Vec<InstructionInfo> = arch
+            .decode(code, entry)
+            .into_iter()
+            .map(|instr| InstructionInfo { addr: instr.addr(), instr })
+            .collect();
+        let cfg = ControlFlowGraph::from_instructions(&instructions, entry);
+
+        Self {
+            name: name.to_string(),
+            arch,
+            entry,
+            code: code.to_vec(),
+            instructions,
+            cfg,
+            load_bias: 0,
+            relocations: HashMap::new(),
+            data: Vec::new(),
+            fixed: Vec::new(),
+            callees: HashMap::new(),
+        }
+    }
+
+    /// Sets the `size` byte global at `addr` to `value` and marks it fixed.
+    /// A global outside the data sections (.bss) gets a block of its own.
+    pub fn fix(&mut self, addr: u64, size: u64, value: i64) -> Result<(), String> {
+        if !matches!(size, 1 | 2 | 4 | 8) {
+            return Err(format!("Only globals of 1, 2, 4 or 8 bytes can be fixed, not {}", size));
+        }
+        let bits = size as u32 * 8;
+        if bits < 64 && !(-(1i64 << (bits - 1))..(1i64 << bits)).contains(&value) {
+            return Err(format!("{} does not fit in {} bytes", value, size));
+        }
+        let size = size as usize;
+        let big_endian = self.data.first().is_some_and(|block| block.big_endian);
+        let bytes = match big_endian {
+            true => value.to_be_bytes()[8 - size..].to_vec(),
+            false => value.to_le_bytes()[..size].to_vec(),
+        };
+        match self.data.iter_mut().find(|block| block.read(addr, size).is_some()) {
+            Some(block) => {
+                let start = (addr - block.addr) as usize;
+                block.bytes[start..start + size].copy_from_slice(&bytes);
+            }
+            None => self.data.push(DataBlock { addr, bytes, big_endian }),
+        }
+        self.fixed.push(addr..addr + size as u64);
+        Ok(())
+    }
+
+    /// Fails at the first undecodable instruction. Decoding past it would
+    /// only produce junk, usually because the symbol size is wrong or data
+    /// is interleaved with the code.
+    pub fn validate(&self) -> Result<(), DecodeError> {
+        let Some((decoded, invalid)) = self.instructions.iter().enumerate().find(|(_, info)| info.instr.is_invalid()) else {
+            return Ok(());
+        };
+        let offset = (invalid.addr - self.entry) as usize;
+        let len = invalid.instr.len().max(1);
+        // Up to 8 bytes on either side, the offending ones in brackets
+        let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ");
+        let end = (offset + len).min(self.code.len());
+        let context = [
+            hex(&self.code[offset.saturating_sub(8)..offset]),
+            format!("[{}]", hex(&self.code[offset..end])),
+            hex(&self.code[end..(end + 8).min(self.code.len())]),
+        ];
+        Err(DecodeError {
+            function: self.name.clone(),
+            offset,
+            addr: invalid.addr,
+            decoded,
+            context: context.into_iter().filter(|part| !part.is_empty()).collect::<Vec<_>>().join(" "),
+        })
+    }
+}
+
+/// Undecodable bytes inside a function
+#[derive(Debug)]
+pub struct DecodeError {
+    pub function: String,
+    /// Offset from the start of the function
+    pub offset: usize,
+    pub addr: u64,
+    /// Instructions decoded before the invalid one
+    pub decoded: usize,
+    /// Hex dump around the offending bytes
+    pub context: String,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid instruction in {} at offset {:#x} ({:#x}) after {} instructions; the symbol size may be wrong or data is mixed into the code: {}",
+            self.function, self.offset, self.addr, self.decoded, self.context
+        )
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub trait TranspilerBackend: Send + Sync {
+    /// Named in the `transpile` span of every translation
+    fn name(&self) -> &'static str;
+
+    /// Translates every instruction, keeping the per-instruction mapping for
+    /// the debugging views and the fidelity report
+    fn lower(&self, function: &FunctionIR) -> Result<LoweredFunction, Box<dyn std::error::Error>>;
+
+    /// Builds the final module from a lowered function
+    fn emit(&self, function: LoweredFunction) -> Vec<u8>;
+
+    fn transpile(&self, function: &FunctionIR) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        Ok(self.emit(self.lower(function)?))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+    /// Instruction-by-instruction translation
+    #[default]
+    Direct,
+}
+
+pub fn create(kind: BackendKind, options: TranspileOptions) -> Box<dyn TranspilerBackend> {
+    match kind {
+        BackendKind::Direct => Box::new(DirectBackend::new(options)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_at_invalid_instructions() {
+        // mov eax, [rdi]; ret; then data: 0x06 (push es) is invalid in 64-bit mode
+        let code = [0x8b, 0x07, 0xc3, 0x06, 0x01, 0x02];
+        let error = FunctionIR::decode("tail", Arch::X86_64, &code, 0x1000).validate().unwrap_err();
+        assert_eq!((error.offset, error.addr, error.decoded), (3, 0x1003, 2));
+        assert_eq!(error.context, "8b 07 c3 [06 01] 02");
+
+        assert!(FunctionIR::decode("ok", Arch::X86_64, &code[..3], 0x1000).validate().is_ok());
+    }
+}
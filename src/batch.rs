@@ -0,0 +1,161 @@
+// Batch execution
+// POST /execute-batch applies an ordered list of callback invocations under a
+// single state lock. If any entry fails, the state is restored to the
+// snapshot taken before the first entry (all-or-nothing). Hooks run around
+// every entry as on /execute, so the after hooks of entries that are later
+// rolled back have run too; their changes to the state are undone with the
+// entry.
+
+use crate::audit::Execution;
+use crate::auth::Permission;
+use crate::callbacks::CallbackValue;
+use crate::error::ApiError;
+use crate::hooks::{Invocation, Trigger};
+use crate::ServerContext;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Responder, ResponseError};
+use serde::{Deserialize, Serialize};
+
+const MAX_BATCH_SIZE: usize = 1024;
+
+#[derive(Deserialize)]
+pub struct BatchEntry {
+    pub callback: String,
+    #[serde(default)]
+    pub args: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum EntryResult {
+    Ok { callback: String, result: CallbackValue },
+    /// Executed, but undone because a later entry failed
+    RolledBack { callback: String, result: CallbackValue },
+    Error { callback: String, error: String },
+    /// Not attempted because an earlier entry failed
+    Skipped { callback: String },
+}
+
+#[derive(Serialize)]
+pub struct BatchResponse {
+    pub committed: bool,
+    pub version: u64,
+    pub results: Vec<EntryResult>,
+}
+
+pub async fn execute_batch(
+    req: HttpRequest,
+    entries: web::Json<Vec<BatchEntry>>,
+    ctx: web::Data<ServerContext>,
+) -> impl Responder {
+    if let Err(e) = ctx.csrf.verify(&req) {
+        return e.error_response();
+    }
+
+    let granted = match ctx.auth.permission(&req) {
+        Ok(granted) => granted,
+        Err(e) => return e.into_response(&ctx.auth),
+    };
+
+    let mut entries = entries.into_inner();
+    if entries.len() > MAX_BATCH_SIZE {
+        return ApiError::payload_too_large(format!("At most {} entries per batch", MAX_BATCH_SIZE)).error_response();
+    }
+
+    // Upload references resolve as on /execute, an unknown one fails its
+    // entry. Resolved before locking, as it looks at the files.
+    let uploads: Vec<Result<(), String>> = entries.iter_mut().map(|entry| ctx.uploads.resolve(&mut entry.args)).collect();
+    let args: Vec<Vec<serde_json::Value>> = entries.iter().map(|entry| entry.args.clone()).collect();
+    let mut state = ctx.state.lock();
+    let snapshot = state.clone();
+
+    let mut results = Vec::with_capacity(entries.len());
+    let mut failed = false;
+
+    for (entry, upload) in entries.into_iter().zip(uploads) {
+        if failed {
+            results.push(EntryResult::Skipped { callback: entry.callback });
+            continue;
+        }
+
+        let outcome = upload.and_then(|()| check_entry(&ctx, &entry, granted)).and_then(|()| {
+            let callback = ctx.callbacks.get(&entry.callback).expect("checked above");
+            let invocation = Invocation { callback: &entry.callback, args: &entry.args, trigger: Trigger::Batch };
+            ctx.hooks.run(&invocation, &mut state, |state| callback.invoke(state, &entry.args)).map_err(|e| e.to_string())
+        });
+
+        match outcome {
+            Ok(result) => {
+                results.push(EntryResult::Ok { callback: entry.callback, result });
+            }
+            Err(error) => {
+                failed = true;
+                results.push(EntryResult::Error { callback: entry.callback, error });
+            }
+        }
+    }
+
+    if failed {
+        *state = snapshot;
+        let results: Vec<EntryResult> = results
+            .into_iter()
+            .map(|result| match result {
+                EntryResult::Ok { callback, result } => EntryResult::RolledBack { callback, result },
+                other => other,
+            })
+            .collect();
+
+        tracing::warn!("batch rolled back");
+        audit(&ctx, &req, &results, &args, None);
+        let response = BatchResponse {
+            committed: false,
+            version: ctx.state.version(),
+            results,
+        };
+        return ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "batch_failed", "An entry failed, the batch was rolled back")
+            .with_detail(response)
+            .error_response();
+    }
+
+    let version = ctx.state.commit(&state);
+    tracing::info!(entries = results.len(), version, "executed batch");
+    audit(&ctx, &req, &results, &args, Some(version));
+
+    HttpResponse::Ok().json(BatchResponse {
+        committed: true,
+        version,
+        results,
+    })
+}
+
+// Records the attempted entries, with the version the batch committed
+fn audit(ctx: &ServerContext, req: &HttpRequest, results: &[EntryResult], args: &[Vec<serde_json::Value>], version: Option<u64>) {
+    let session = crate::audit::session_digest(req);
+    for (result, args) in results.iter().zip(args) {
+        let (callback, outcome) = match result {
+            EntryResult::Ok { callback, result } => (callback, Ok(serde_json::to_value(result).expect("results serialize to JSON"))),
+            EntryResult::RolledBack { callback, .. } => (callback, Err("Rolled back".to_string())),
+            EntryResult::Error { callback, error } => (callback, Err(error.clone())),
+            EntryResult::Skipped { .. } => continue,
+        };
+        let version = version.filter(|_| outcome.is_ok());
+        ctx.audit.record(Execution { session: session.clone(), callback, trigger: Trigger::Batch, args, outcome, version });
+    }
+}
+
+fn check_entry(ctx: &ServerContext, entry: &BatchEntry, granted: Permission) -> Result<(), String> {
+    let callback = ctx.callbacks
+        .get(&entry.callback)
+        .ok_or_else(|| "Unknown callback".to_string())?;
+
+    if granted < callback.permission {
+        return Err(format!("Insufficient permissions (requires {:?})", callback.permission));
+    }
+
+    let expected = callback.signature.client_params().count();
+    if entry.args.len() != expected {
+        return Err(format!("Expected {} arguments, got {}", expected, entry.args.len()));
+    }
+
+    Ok(())
+}
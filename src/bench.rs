@@ -0,0 +1,141 @@
+// Native vs translated execution
+// Times every callback natively and its WASM lowering, compiled by Cranelift
+// as in differential verification (`verify.rs`), on the same sample states,
+// and counts the WASM instructions the lowering executes per call. The count
+// does not depend on the machine, so it tracks the generated code as
+// optimizations land. `benches/translation.rs` measures the same under
+// criterion.
+
+use crate::callbacks::CallbackEntry;
+use crate::transpiler::Transpiler;
+use crate::verify::{self, SAMPLE_COUNTERS};
+use crate::State;
+use serde::Serialize;
+use std::hint::black_box;
+use std::time::Instant;
+
+#[derive(Debug, Serialize)]
+pub struct Benchmark {
+    pub callback: String,
+    /// Why the callback was not measured, if it was skipped
+    pub skipped: Option<String>,
+    /// Calls per sample state
+    pub iterations: u32,
+    /// Mean duration of a call, over all sample states
+    pub native_ns: f64,
+    pub wasm_ns: f64,
+    /// `wasm_ns / native_ns`
+    pub slowdown: f64,
+    /// WASM instructions executed per call, averaged over the sample states
+    pub wasm_instructions: f64,
+}
+
+pub fn bench_all<'a>(
+    callbacks: impl IntoIterator<Item = &'a CallbackEntry>,
+    transpiler: &Transpiler,
+    iterations: u32,
+) -> Vec<Benchmark> {
+    callbacks
+        .into_iter()
+        .map(|callback| bench(callback, transpiler, iterations))
+        .collect()
+}
+
+pub fn bench(callback: &CallbackEntry, transpiler: &Transpiler, iterations: u32) -> Benchmark {
+    let mut benchmark = Benchmark {
+        callback: callback.name.clone(),
+        skipped: None,
+        iterations,
+        native_ns: 0.0,
+        wasm_ns: 0.0,
+        slowdown: 0.0,
+        wasm_instructions: 0.0,
+    };
+    let compiled = match verify::comparable(callback, transpiler) {
+        Ok(compiled) => compiled,
+        Err(reason) => {
+            benchmark.skipped = Some(reason);
+            return benchmark;
+        }
+    };
+    // Instantiated once, so the timings leave out compilation and
+    // instantiation, as a page calling a module repeatedly would
+    let mut runner = match compiled.instantiate(&transpiler.verify_limits()) {
+        Ok(runner) => runner,
+        Err(reason) => {
+            benchmark.skipped = Some(reason);
+            return benchmark;
+        }
+    };
+
+    let (mut native, mut wasm, mut executed) = (0, 0, 0);
+    for counter in SAMPLE_COUNTERS {
+        let input = State { counter };
+        let (outcome, instructions) = runner.run(&input);
+        if let Some(trap) = outcome.trap {
+            benchmark.skipped = Some(format!("the lowering trapped on counter {}: {}", counter, trap));
+            return benchmark;
+        }
+        executed += instructions;
+
+        let start = Instant::now();
+        for _ in 0..iterations {
+            let mut state = input.clone();
+            let _ = black_box(callback.call(black_box(&mut state), &[]));
+        }
+        native += start.elapsed().as_nanos();
+
+        let start = Instant::now();
+        for _ in 0..iterations {
+            black_box(runner.run(black_box(&input)));
+        }
+        wasm += start.elapsed().as_nanos();
+    }
+
+    let calls = (iterations.max(1) as usize * SAMPLE_COUNTERS.len()) as f64;
+    benchmark.native_ns = native as f64 / calls;
+    benchmark.wasm_ns = wasm as f64 / calls;
+    benchmark.slowdown = benchmark.wasm_ns / benchmark.native_ns.max(1.0);
+    benchmark.wasm_instructions = executed as f64 / SAMPLE_COUNTERS.len() as f64;
+    benchmark
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::auth::Permission;
+    use crate::callbacks::{CallbackRegistry, NativeCallback};
+    use crate::config::TranspilerConfig;
+
+    // Translated from this test binary. Written in assembly, as a debug
+    // build of `(*state).counter ^ 0x55` checks the alignment of the pointer
+    // with a call, which the lowering drops.
+    std::arch::global_asm!(
+        ".text",
+        ".globl bench_flip",
+        ".type bench_flip, @function",
+        "bench_flip:",
+        "mov eax, [rdi]",
+        "xor eax, 0x55",
+        "ret",
+        ".size bench_flip, . - bench_flip",
+    );
+    extern "C" {
+        fn bench_flip(state: *mut State) -> i32;
+    }
+
+    #[test]
+    fn measures_native_and_translated_calls() {
+        let mut registry = CallbackRegistry::new();
+        let native: unsafe extern "C" fn(*mut State) -> i32 = bench_flip;
+        // SAFETY: bench_flip only reads the counter of a valid state
+        let native = unsafe { std::mem::transmute::<unsafe extern "C" fn(*mut State) -> i32, NativeCallback>(native) };
+        registry.register("bench_flip", native, Permission::Public);
+        let transpiler = Transpiler::new(registry.iter(), &TranspilerConfig::default());
+
+        let benchmark = bench(registry.get("bench_flip").unwrap(), &transpiler, 10);
+        assert_eq!(benchmark.skipped, None);
+        assert!(benchmark.native_ns > 0.0 && benchmark.wasm_ns > 0.0, "{:?}", benchmark);
+        assert!(benchmark.wasm_instructions > 0.0, "{:?}", benchmark);
+    }
+}
@@ -0,0 +1,82 @@
+// Binary registry
+// Besides the binary the callbacks live in, the config can declare further
+// binaries under `[binaries.<namespace>]`, such as plugin libraries. Each one
+// gets its own Transpiler, with its own module cache and hot reload watcher,
+// and its callbacks are served at `/wasm/<namespace>/<symbol>`. These are
+// only translated, never executed natively.
+
+use crate::config::Config;
+use crate::exposure::Exposure;
+use crate::signatures::Signatures;
+use crate::transpiler::Transpiler;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[derive(Default)]
+pub struct BinaryRegistry {
+    binaries: BTreeMap<String, Arc<Transpiler>>,
+}
+
+impl BinaryRegistry {
+    /// Loads every configured binary with its exposed symbols, which
+    /// `Transpiler::warm_up` translates; none with `[exposure] mode = "auto"`. `callbacks` are the symbols of
+    /// the callback binary, for the check of `[transpiler.functions]`. Fails
+    /// if a signatures file cannot be read.
+    pub fn new(config: &Config, callbacks: &[String], exposure: &Exposure) -> Result<Self, String> {
+        let binaries: BTreeMap<String, Arc<Transpiler>> = config.binaries
+            .iter()
+            .map(|(namespace, binary)| (namespace, exposure.filter_binary(namespace, binary)))
+            .filter(|(_, binary)| !binary.callbacks.is_empty())
+            .map(|(namespace, binary)| {
+                let signatures = match &binary.signatures {
+                    Some(path) => Signatures::load(path)?,
+                    None => Signatures::default(),
+                };
+                tracing::info!(%namespace, path = %binary.path.display(), "Loading binary");
+                Ok((namespace.clone(), Arc::new(Transpiler::for_namespace(namespace, &binary, &signatures, &config.transpiler))))
+            })
+            .collect::<Result<_, String>>()?;
+
+        let known = |fn_name: &String| {
+            callbacks.contains(fn_name) || binaries.values().any(|binary| binary.symbols().contains(fn_name))
+        };
+        for fn_name in config.transpiler.functions.keys().filter(|fn_name| !known(fn_name)) {
+            tracing::warn!(symbol = %fn_name, "Transpile options given for a symbol that is not a registered callback");
+        }
+        Ok(Self { binaries })
+    }
+
+    pub fn get(&self, namespace: &str) -> Option<&Arc<Transpiler>> {
+        self.binaries.get(namespace)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Arc<Transpiler>)> {
+        self.binaries.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BinaryConfig;
+
+    #[test]
+    fn serves_each_binary_from_its_own_cache() {
+        let binary = BinaryConfig {
+            path: std::env::current_exe().unwrap(),
+            // A function of the test binary, see `verify.rs`
+            callbacks: vec!["verify_ratio".to_string()],
+            load_bias: None,
+            signatures: None,
+        };
+        let config = Config { binaries: BTreeMap::from([("self".to_string(), binary)]), ..Default::default() };
+        let registry = BinaryRegistry::new(&config, &[], &Exposure::new(&Default::default())).unwrap();
+
+        let transpiler = registry.get("self").unwrap();
+        transpiler.warm_up();
+        let wasm = transpiler.get_wasm_for_function("verify_ratio").unwrap();
+        assert!(wasmprinter::print_bytes(&wasm).unwrap().contains(r#"(export "callback""#));
+        assert!(transpiler.get_wasm_for_function("verify_lerp").is_none());
+        assert!(registry.get("other").is_none());
+    }
+}
@@ -0,0 +1,183 @@
+// Module size budget
+// Splits a module's size into code, data segments, the name section and the
+// rest (types, imports, exports, section headers), and checks it against
+// `max_module_bytes` with suggestions on how to get under it. Functions with
+// more than `max_instructions` instructions are rejected before they are
+// lifted, as their IR and module would be too large to be of use anyway.
+
+use crate::backend::FunctionIR;
+use crate::transpiler_real::{ModuleSize, OptLevel, TranspileOptions};
+use serde::Serialize;
+use wasmparser::{Parser, Payload};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct SizeBreakdown {
+    pub total: usize,
+    pub code: usize,
+    pub data: usize,
+    pub names: usize,
+    pub other: usize,
+}
+
+impl SizeBreakdown {
+    pub fn of(wasm: &[u8]) -> Result<Self, String> {
+        let mut size = SizeBreakdown { total: wasm.len(), ..Default::default() };
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload.map_err(|e| format!("Invalid module: {}", e))? {
+                Payload::CodeSectionStart { range, .. } => size.code += range.len(),
+                Payload::DataSection(reader) => size.data += reader.range().len(),
+                Payload::CustomSection(reader) if reader.name() == "name" => size.names += reader.range().len(),
+                _ => {}
+            }
+        }
+        size.other = size.total - size.code - size.data - size.names;
+        Ok(size)
+    }
+}
+
+/// Fails with the size breakdown and suggestions if `wasm` is larger than
+/// the `max_module_bytes` of `options`
+pub fn check(wasm: &[u8], options: &TranspileOptions) -> Result<SizeBreakdown, String> {
+    let size = SizeBreakdown::of(wasm)?;
+    let Some(max) = options.max_module_bytes.filter(|max| size.total > *max) else {
+        return Ok(size);
+    };
+
+    let mut suggestions = Vec::new();
+    if size.names > 0 {
+        suggestions.push(format!("strip the name section ({} bytes)", size.names));
+    }
+    if options.optimization == OptLevel::None {
+        suggestions.push("set optimization = \"full\"".to_string());
+    }
+    suggestions.push("raise max_module_bytes".to_string());
+
+    Err(format!(
+        "Module is {} bytes, over the budget of {} (code {}, data {}, names {}, other {}); try: {}",
+        size.total,
+        max,
+        size.code,
+        size.data,
+        size.names,
+        size.other,
+        suggestions.join(", "),
+    ))
+}
+
+/// `max_instructions` if the config sets none
+pub const DEFAULT_MAX_INSTRUCTIONS: usize = 20_000;
+
+/// Fails if `function` has more instructions than the `max_instructions` of
+/// `options`
+pub fn check_instructions(function: &FunctionIR, options: &TranspileOptions) -> Result<(), String> {
+    let count = function.instructions.len();
+    match options.max_instructions.filter(|max| count > *max) {
+        Some(max) => Err(format!(
+            "Function {} is too large: {} instructions ({} bytes of code), over the limit of {}; \
+             consider excluding it with [exposure] deny, or raise max_instructions in [transpiler.functions.{}]",
+            function.name,
+            count,
+            function.code.len(),
+            max,
+            function.name,
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Sizes shown at `/report/{fn_name}`
+#[derive(Debug, Serialize)]
+pub struct SizeReport {
+    pub callback: String,
+    pub max_module_bytes: Option<usize>,
+    /// The served module
+    pub module: Measured<SizeBreakdown>,
+    /// The translation of the real machine code
+    pub lowering: Measured<LoweringSize>,
+}
+
+/// A size, or why it could not be measured
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Measured<T> {
+    Ok(T),
+    Failed { error: String },
+}
+
+impl<T> From<Result<T, String>> for Measured<T> {
+    fn from(result: Result<T, String>) -> Self {
+        match result {
+            Ok(size) => Measured::Ok(size),
+            Err(error) => Measured::Failed { error },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoweringSize {
+    pub module: SizeBreakdown,
+    pub unoptimized: ModuleSize,
+    pub optimized: ModuleSize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_encoder::{CodeSection, DataSection, Function, FunctionSection, Instruction, Module, NameMap, NameSection, TypeSection};
+
+    #[test]
+    fn breaks_down_and_enforces_the_budget() {
+        let mut module = Module::new();
+        let mut types = TypeSection::new();
+        types.ty().function(vec![], vec![]);
+        module.section(&types);
+        let mut functions = FunctionSection::new();
+        functions.function(0);
+        module.section(&functions);
+        let mut code = CodeSection::new();
+        let mut func = Function::new(vec![]);
+        func.instruction(&Instruction::End);
+        code.function(&func);
+        module.section(&code);
+        let mut data = DataSection::new();
+        data.passive([0u8; 16]);
+        module.section(&data);
+        let mut names = NameSection::new();
+        let mut functions = NameMap::new();
+        functions.append(0, "callback");
+        names.functions(&functions);
+        module.section(&names);
+        let wasm = module.finish();
+
+        let size = SizeBreakdown::of(&wasm).unwrap();
+        assert_eq!((size.code, size.data), (4, 19));
+        assert!(size.names > 0 && size.other > 0);
+        assert_eq!(size.code + size.data + size.names + size.other, wasm.len());
+
+        let options = TranspileOptions { max_module_bytes: Some(wasm.len()), ..Default::default() };
+        assert_eq!(check(&wasm, &options), Ok(size));
+        let options = TranspileOptions { max_module_bytes: Some(32), optimization: OptLevel::None, ..Default::default() };
+        let error = check(&wasm, &options).unwrap_err();
+        assert!(error.contains("strip the name section"), "{}", error);
+        assert!(error.contains("optimization = \"full\""), "{}", error);
+    }
+
+    #[test]
+    fn rejects_functions_over_the_instruction_limit() {
+        use crate::arch::Arch;
+        use crate::backend::TranspilerBackend;
+        use crate::transpiler_real::DirectBackend;
+
+        // add rax, 1 a hundred times, then ret
+        let code: Vec<u8> = std::iter::repeat_n([0x48, 0x83, 0xc0, 0x01], 100).flatten().chain([0xc3]).collect();
+        let function = FunctionIR::decode("unrolled", Arch::X86_64, &code, 0x1000);
+        let options = TranspileOptions { max_instructions: Some(100), ..Default::default() };
+        let error = DirectBackend::new(options.clone()).transpile(&function).unwrap_err().to_string();
+        assert!(error.starts_with("Function unrolled is too large: 101 instructions (401 bytes of code)"), "{}", error);
+        assert!(error.contains("[exposure] deny"), "{}", error);
+        assert!(crate::jsgen::generate(&function, options).is_err());
+
+        let options = TranspileOptions { max_instructions: Some(101), ..Default::default() };
+        assert!(DirectBackend::new(options).transpile(&function).is_ok());
+    }
+}
@@ -0,0 +1,241 @@
+// Static bundle export
+// Everything the page loads that does not change with the state, gathered
+// for a CDN: the release modules of the callbacks and `[binaries]` symbols,
+// also under the hashed URLs of `manifest.rs` with the manifest, the
+// callbacks' proposal requirements, JavaScript fallbacks and WIT worlds, the
+// shared runtimes of `runtime.rs` if enabled, the glue as app.js, the page style as app.css and the state layout. index.json
+// lists each file with the URL the glue requests it at, its content type and
+// SHA-256, so an upload script can key and label the objects, and the routes
+// that stay on the server (/execute, /sync, ...). Written as a directory by
+// `x64_to_wasm_server export <dir>`, or as a tar archive when the path ends
+// in `.tar` and from `GET /admin/export`. Archives are reproducible: entries
+// are sorted and carry no timestamps.
+
+use crate::binaries::BinaryRegistry;
+use crate::callbacks::CallbackRegistry;
+use crate::layout::SelfServeState;
+use crate::profile::Profile;
+use crate::transpiler::Transpiler;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+/// Routes the bundle cannot replace, as the glue calls them
+const SERVER_ROUTES: [&str; 5] = ["/execute/{fn_name}", "/execute-batch", "/sync", "/telemetry", "/api/state/bytes"];
+
+pub struct BundleFile {
+    /// The path the glue requests, none for the files a page includes itself
+    pub url: Option<String>,
+    pub content_type: &'static str,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct IndexEntry<'a> {
+    file: &'a str,
+    url: Option<&'a str>,
+    content_type: &'static str,
+    bytes: usize,
+    sha256: String,
+}
+
+#[derive(Serialize)]
+struct Skipped {
+    file: String,
+    error: String,
+}
+
+pub struct Bundle {
+    // By path within the bundle
+    files: BTreeMap<String, BundleFile>,
+    skipped: Vec<Skipped>,
+}
+
+impl Bundle {
+    fn add(&mut self, file: String, url: Option<String>, content_type: &'static str, bytes: Result<Vec<u8>, String>) {
+        match bytes {
+            Ok(bytes) => {
+                self.files.insert(file, BundleFile { url, content_type, bytes });
+            }
+            Err(error) => {
+                tracing::warn!(%file, %error, "Left out of the bundle");
+                self.skipped.push(Skipped { file, error });
+            }
+        }
+    }
+
+    /// The files with index.json
+    fn entries(&self) -> Vec<(String, Vec<u8>)> {
+        let files: Vec<IndexEntry> = self.files
+            .iter()
+            .map(|(file, entry)| IndexEntry {
+                file,
+                url: entry.url.as_deref(),
+                content_type: entry.content_type,
+                bytes: entry.bytes.len(),
+                sha256: hex::encode(Sha256::digest(&entry.bytes)),
+            })
+            .collect();
+        let index = serde_json::json!({
+            "transpiler_version": env!("CARGO_PKG_VERSION"),
+            "files": files,
+            "skipped": self.skipped,
+            "server_routes": SERVER_ROUTES,
+        });
+        let index = serde_json::to_vec_pretty(&index).expect("index serializes to JSON");
+        let mut entries: Vec<(String, Vec<u8>)> = self.files
+            .iter()
+            .map(|(file, entry)| (file.clone(), entry.bytes.clone()))
+            .collect();
+        entries.push(("index.json".to_string(), index));
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    pub fn write_dir(&self, dir: &Path) -> io::Result<()> {
+        for (file, bytes) in self.entries() {
+            let path = dir.join(&file);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, bytes)?;
+        }
+        Ok(())
+    }
+
+    /// The bundle as a ustar archive
+    pub fn to_tar(&self) -> io::Result<Vec<u8>> {
+        let mut tar = Vec::new();
+        for (file, bytes) in self.entries() {
+            tar.extend_from_slice(&tar_header(&file, bytes.len())?);
+            tar.extend_from_slice(&bytes);
+            tar.resize(tar.len().next_multiple_of(512), 0);
+        }
+        // End of archive
+        tar.resize(tar.len() + 1024, 0);
+        Ok(tar)
+    }
+
+    /// Writes a tar archive if `path` ends in `.tar`, a directory otherwise
+    pub fn write_to(&self, path: &Path) -> io::Result<()> {
+        match path.extension().is_some_and(|extension| extension == "tar") {
+            true => std::fs::write(path, self.to_tar()?),
+            false => self.write_dir(path),
+        }
+    }
+}
+
+fn tar_header(path: &str, size: usize) -> io::Result<[u8; 512]> {
+    // Names over 100 bytes are split at a slash into the 155 byte prefix
+    let (prefix, name) = match path.len() <= 100 {
+        true => ("", path),
+        false => path
+            .char_indices()
+            .filter(|(_, c)| *c == '/')
+            .map(|(idx, _)| (&path[..idx], &path[idx + 1..]))
+            .find(|(prefix, name)| prefix.len() <= 155 && name.len() <= 100)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Path too long for tar: {}", path)))?,
+    };
+    let mut header = [0u8; 512];
+    let mut put = |offset: usize, value: &[u8]| header[offset..offset + value.len()].copy_from_slice(value);
+    put(0, name.as_bytes());
+    put(100, b"0000644\0");
+    put(108, b"0000000\0");
+    put(116, b"0000000\0");
+    put(124, format!("{:011o}\0", size).as_bytes());
+    put(136, b"00000000000\0");
+    put(148, b"        ");
+    put(156, b"0");
+    put(257, b"ustar\0");
+    put(263, b"00");
+    put(345, prefix.as_bytes());
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    Ok(header)
+}
+
+pub fn build(callbacks: &CallbackRegistry, transpiler: &Transpiler, binaries: &BinaryRegistry) -> Bundle {
+    let mut bundle = Bundle { files: BTreeMap::new(), skipped: Vec::new() };
+    let mut manifest = BTreeMap::new();
+
+    for callback in callbacks.iter() {
+        let name = &callback.name;
+        let wasm = transpiler.get_wasm_with_profile(name, Profile::Release);
+        let Some(wasm) = wasm else {
+            bundle.skipped.push(Skipped { file: format!("wasm/{}.wasm", name), error: "No module".to_string() });
+            continue;
+        };
+        let requirements = crate::features::requirements(&wasm)
+            .map(|features| serde_json::to_vec(&crate::features::Requirements { features }).expect("requirements serialize to JSON"));
+        bundle.add(format!("wasm/{}/requirements.json", name), Some(format!("/wasm/{}/requirements", name)), "application/json", requirements);
+        let wit = crate::component::wit(name, &callback.signature, Some(&wasm)).map(String::into_bytes);
+        bundle.add(format!("wit/{}.wit", name), Some(format!("/wit/{}", name)), "text/plain; charset=utf-8", wit);
+        let js = transpiler.javascript(name).map(|js| crate::javascript_module(&js).into_bytes());
+        bundle.add(format!("js/{}.js", name), Some(format!("/js/{}?module=true", name)), "text/javascript; charset=utf-8", js);
+        let hashed = crate::manifest::hashed_url(name, &wasm);
+        manifest.insert(name.clone(), hashed.clone());
+        bundle.add(hashed.trim_start_matches('/').to_string(), Some(hashed), "application/wasm", Ok(wasm.clone()));
+        bundle.add(format!("wasm/{}.wasm", name), Some(format!("/wasm/{}", name)), "application/wasm", Ok(wasm));
+    }
+    let manifest = crate::manifest::Manifest { profile: Profile::Release, modules: manifest };
+    let manifest = serde_json::to_vec(&manifest).expect("manifest serializes to JSON");
+    bundle.add("wasm/manifest.json".to_string(), Some("/wasm/manifest.json".to_string()), "application/json", Ok(manifest));
+    if let Some(runtime) = transpiler.runtime() {
+        bundle.add("wasm/runtime.wasm".to_string(), Some("/wasm/runtime.wasm".to_string()), "application/wasm", runtime);
+    }
+
+    for (namespace, binary) in binaries.iter() {
+        for symbol in binary.symbols() {
+            let file = format!("wasm/{}/{}.wasm", namespace, symbol);
+            let wasm = binary.get_wasm_with_profile(symbol, Profile::Release).ok_or_else(|| "No module".to_string());
+            bundle.add(file, Some(format!("/wasm/{}/{}", namespace, symbol)), "application/wasm", wasm);
+        }
+        if let Some(runtime) = binary.runtime() {
+            let url = format!("/wasm/{}/runtime.wasm", namespace);
+            bundle.add(url.trim_start_matches('/').to_string(), Some(url), "application/wasm", runtime);
+        }
+    }
+
+    bundle.add("app.js".to_string(), None, "text/javascript; charset=utf-8", Ok(crate::glue::script(callbacks, crate::csrf::CSRF_HEADER).into_bytes()));
+    bundle.add("app.css".to_string(), None, "text/css; charset=utf-8", Ok(crate::APP_CSS.as_bytes().to_vec()));
+    let layout = serde_json::to_vec(&crate::State::layout()).expect("layout serializes to JSON");
+    bundle.add("layout.json".to_string(), Some("/api/state/layout".to_string()), "application/json", Ok(layout));
+    bundle
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TranspilerConfig;
+
+    #[test]
+    fn exports_modules_with_an_index() {
+        let callbacks = crate::register_callbacks();
+        let transpiler = Transpiler::new(callbacks.iter(), &TranspilerConfig::default());
+        transpiler.warm_up();
+        let bundle = build(&callbacks, &transpiler, &BinaryRegistry::default());
+
+        let entries = bundle.entries();
+        let (_, index) = entries.iter().find(|(file, _)| file == "index.json").unwrap();
+        let index: serde_json::Value = serde_json::from_slice(index).unwrap();
+        let wasm = transpiler.get_wasm_for_function("increment_counter").unwrap();
+        let entry = index["files"].as_array().unwrap().iter().find(|entry| entry["file"] == "wasm/increment_counter.wasm").unwrap();
+        assert_eq!(entry["url"], "/wasm/increment_counter");
+        assert_eq!(entry["sha256"], hex::encode(Sha256::digest(&wasm)));
+        assert!(index["server_routes"].as_array().unwrap().contains(&"/execute/{fn_name}".into()));
+
+        let tar = bundle.to_tar().unwrap();
+        assert_eq!(tar.len() % 512, 0);
+        let (first, bytes) = &entries[0];
+        assert_eq!(&tar[..first.len()], first.as_bytes());
+        assert_eq!(&tar[124..136], format!("{:011o}\0", bytes.len()).as_bytes());
+        assert_eq!(&tar[512..512 + bytes.len()], &bytes[..]);
+        let long = format!("wasm/{}/{}.wasm", "n".repeat(60), "s".repeat(60));
+        let header = tar_header(&long, 1).unwrap();
+        let (prefix, name) = long.rsplit_once('/').unwrap();
+        assert_eq!(&header[345..345 + prefix.len() + 1], format!("{}\0", prefix).as_bytes());
+        assert_eq!(&header[..name.len() + 1], format!("{}\0", name).as_bytes());
+    }
+}
@@ -0,0 +1,488 @@
+// Callback registry
+// Maps callback names to their native implementation, the permission level
+// required to invoke them, a signature describing how their results are
+// marshalled and the validation rules of their client arguments. Callbacks
+// marked read-only run on a copy of the state under the shared lock, so
+// several run at once, and can also be executed with GET /execute.
+// By convention callbacks take `*mut State` first. Every signature declares
+// it, so /execute passes the locked state, the glue the base of linear
+// memory, and neither the client API nor the OpenAPI spec has the parameter.
+
+use crate::auth::Permission;
+use crate::layout::{SelfServeState, StructLayout};
+use crate::validation::{Rule, Violation};
+use crate::State;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+pub type NativeCallback = extern "C" fn(*mut State) -> i32;
+
+/// Returns a double in xmm0, lowered to a WASM f64 result
+pub type FloatCallback = extern "C" fn(*mut State) -> f64;
+
+/// Returns a UTF-8 string through `(out_ptr, out_len)`. The bytes must stay
+/// valid while the state is locked, i.e. point into the state or static data.
+pub type StringCallback = extern "C" fn(*mut State, *mut *const u8, *mut usize) -> i32;
+
+/// Fills a caller-allocated struct through an out-pointer.
+pub type StructCallback<T> = extern "C" fn(*mut State, *mut T) -> i32;
+
+/// Takes a UTF-8 string argument as `(ptr, len)`, like `add_todo`
+pub type StrArgCallback = extern "C" fn(*mut State, *const u8, usize) -> i32;
+
+/// Takes an array argument as `(ptr, len)`, like `sum(int* data, size_t n)`
+pub type SliceArgCallback<T> = extern "C" fn(*mut State, *const T, usize) -> i32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueType {
+    /// Pointer to the application state, supplied by the server
+    StatePtr,
+    /// Pointer to storage for the result, supplied by the caller
+    OutPtr,
+    /// Pointer to a scalar the callback writes, supplied by the caller. With
+    /// `multi_value` the module returns it as an extra result instead.
+    Out(ElementType),
+    /// UTF-8 string supplied by the client, passed as a `(ptr, len)` pair
+    Str,
+    /// Array supplied by the client, passed as a `(ptr, len)` pair with the
+    /// number of elements
+    Slice(ElementType),
+}
+
+/// Element type of a slice parameter or scalar out-parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ElementType {
+    U8,
+    I32,
+    U32,
+    I64,
+    U64,
+    F32,
+    F64,
+}
+
+impl ElementType {
+    pub fn name(self) -> &'static str {
+        match self {
+            ElementType::U8 => "u8",
+            ElementType::I32 => "i32",
+            ElementType::U32 => "u32",
+            ElementType::I64 => "i64",
+            ElementType::U64 => "u64",
+            ElementType::F32 => "f32",
+            ElementType::F64 => "f64",
+        }
+    }
+}
+
+/// Rust types of slice elements, converted from the JSON numbers of a
+/// client argument
+pub trait SliceElement: Copy + 'static {
+    const TYPE: ElementType;
+
+    /// None if the number does not fit
+    fn from_json(value: &serde_json::Value) -> Option<Self>;
+}
+
+macro_rules! integer_element {
+    ($($ty:ty => $element:ident),*) => {
+        $(impl SliceElement for $ty {
+            const TYPE: ElementType = ElementType::$element;
+
+            fn from_json(value: &serde_json::Value) -> Option<Self> {
+                value.as_i64().and_then(|value| Self::try_from(value).ok())
+            }
+        })*
+    };
+}
+
+integer_element!(u8 => U8, i32 => I32, u32 => U32, i64 => I64, u64 => U64);
+
+impl SliceElement for f32 {
+    const TYPE: ElementType = ElementType::F32;
+
+    fn from_json(value: &serde_json::Value) -> Option<Self> {
+        value.as_f64().map(|value| value as f32)
+    }
+}
+
+impl SliceElement for f64 {
+    const TYPE: ElementType = ElementType::F64;
+
+    fn from_json(value: &serde_json::Value) -> Option<Self> {
+        value.as_f64()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ReturnType {
+    I32,
+    F64,
+    /// Pointer + length written through out-pointers, the return value is a status
+    String,
+    /// Struct written through an out-pointer, the return value is a status
+    Struct {
+        type_name: String,
+        schema: serde_json::Value,
+        /// Where the fields are in the written bytes
+        layout: StructLayout,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Signature {
+    pub params: Vec<ValueType>,
+    pub returns: ReturnType,
+}
+
+impl Signature {
+    /// A signature of the registry's convention: callbacks take the state
+    /// pointer first, which the server supplies natively and the glue as
+    /// the base of linear memory, then `params`
+    fn with_state(params: impl IntoIterator<Item = ValueType>, returns: ReturnType) -> Self {
+        Self { params: std::iter::once(ValueType::StatePtr).chain(params).collect(), returns }
+    }
+
+    /// Whether the first parameter is the state pointer
+    pub fn takes_state(&self) -> bool {
+        self.params.first() == Some(&ValueType::StatePtr)
+    }
+
+    /// Parameters the client has to supply: everything except the state and
+    /// out-pointers, which are provided by the server or the glue
+    pub fn client_params(&self) -> impl Iterator<Item = &ValueType> {
+        self.params
+            .iter()
+            .filter(|param| !matches!(param, ValueType::StatePtr | ValueType::OutPtr | ValueType::Out(_)))
+    }
+
+    /// Types of the scalar out-parameters, in order
+    pub fn outs(&self) -> Vec<ElementType> {
+        self.params
+            .iter()
+            .filter_map(|param| match param {
+                ValueType::Out(ty) => Some(*ty),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// A marshalled callback result, serialized as a plain JSON value
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum CallbackValue {
+    I32(i32),
+    F64(f64),
+    String(String),
+    Struct(serde_json::Value),
+}
+
+#[derive(Debug)]
+pub enum CallbackError {
+    /// String and struct callbacks signal failure with a nonzero status
+    Status(i32),
+    Marshal(String),
+    /// The client arguments do not match the signature
+    Arguments(String),
+    /// Vetoed by a before hook, see `hooks.rs`
+    Rejected(String),
+    /// The client arguments break validation rules, see `validation.rs`
+    Invalid(Vec<Violation>),
+}
+
+impl fmt::Display for CallbackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallbackError::Status(status) => write!(f, "Callback failed with status {}", status),
+            CallbackError::Marshal(e) => write!(f, "Could not marshal result: {}", e),
+            CallbackError::Arguments(e) => write!(f, "Invalid arguments: {}", e),
+            CallbackError::Rejected(e) => write!(f, "Rejected: {}", e),
+            CallbackError::Invalid(violations) => {
+                let messages: Vec<String> = violations
+                    .iter()
+                    .map(|violation| format!("argument {} {}", violation.param, violation.message))
+                    .collect();
+                write!(f, "Invalid arguments: {}", messages.join("; "))
+            }
+        }
+    }
+}
+
+type Invoker = Box<dyn Fn(&mut State, &[serde_json::Value]) -> Result<CallbackValue, CallbackError> + Send + Sync>;
+
+pub struct CallbackEntry {
+    pub name: String,
+    pub permission: Permission,
+    pub signature: Signature,
+    pub last_executed: Mutex<Option<SystemTime>>,
+    /// Validation rules of each client parameter
+    pub rules: Vec<Vec<Rule>>,
+    /// Only reads the state, see `CallbackRegistry::read_only`
+    pub read_only: bool,
+    invoker: Invoker,
+}
+
+impl CallbackEntry {
+    /// Runs the callback with the client parameters of its signature
+    pub fn invoke(&self, state: &mut State, args: &[serde_json::Value]) -> Result<CallbackValue, CallbackError> {
+        let result = self.call(state, args);
+        self.record_execution();
+        result
+    }
+
+    /// Runs the callback without recording it as executed
+    pub fn call(&self, state: &mut State, args: &[serde_json::Value]) -> Result<CallbackValue, CallbackError> {
+        let expected = self.signature.client_params().count();
+        if args.len() != expected {
+            return Err(CallbackError::Arguments(format!("expected {}, got {}", expected, args.len())));
+        }
+        let violations = crate::validation::check(&self.rules, args);
+        if !violations.is_empty() {
+            return Err(CallbackError::Invalid(violations));
+        }
+        (self.invoker)(state, args)
+    }
+
+    pub fn record_execution(&self) {
+        *self.last_executed.lock().unwrap() = Some(SystemTime::now());
+    }
+}
+
+#[derive(Default)]
+pub struct CallbackRegistry {
+    entries: BTreeMap<String, CallbackEntry>,
+}
+
+impl CallbackRegistry {
+    pub fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, name: &str, native: NativeCallback, permission: Permission) -> &mut Self {
+        let signature = Signature::with_state([], ReturnType::I32);
+        self.insert(name, permission, signature, Box::new(move |state, _| {
+            Ok(CallbackValue::I32(native(state)))
+        }))
+    }
+
+    pub fn register_f64(&mut self, name: &str, native: FloatCallback, permission: Permission) -> &mut Self {
+        let signature = Signature::with_state([], ReturnType::F64);
+        self.insert(name, permission, signature, Box::new(move |state, _| {
+            Ok(CallbackValue::F64(native(state)))
+        }))
+    }
+
+    pub fn register_string(&mut self, name: &str, native: StringCallback, permission: Permission) -> &mut Self {
+        let signature = Signature::with_state([ValueType::Out(ElementType::U64), ValueType::Out(ElementType::U64)], ReturnType::String);
+        self.insert(name, permission, signature, Box::new(move |state, _| {
+            let mut ptr: *const u8 = std::ptr::null();
+            let mut len: usize = 0;
+            let status = native(state, &mut ptr, &mut len);
+            if status != 0 {
+                return Err(CallbackError::Status(status));
+            }
+            if ptr.is_null() {
+                return Ok(CallbackValue::String(String::new()));
+            }
+            // SAFETY: the callback guarantees ptr/len describe valid bytes for
+            // as long as the state is borrowed
+            let bytes = unsafe { std::slice::from_raw_parts(ptr, len) };
+            String::from_utf8(bytes.to_vec())
+                .map(CallbackValue::String)
+                .map_err(|e| CallbackError::Marshal(e.to_string()))
+        }))
+    }
+
+    pub fn register_struct<T>(&mut self, name: &str, native: StructCallback<T>, permission: Permission) -> &mut Self
+    where
+        T: Serialize + Default + JsonSchema + SelfServeState + 'static,
+    {
+        let schema = schemars::schema_for!(T);
+        let returns = ReturnType::Struct {
+            type_name: T::schema_name(),
+            schema: serde_json::to_value(&schema.schema).unwrap_or_default(),
+            layout: T::layout(),
+        };
+        let signature = Signature::with_state([ValueType::OutPtr], returns);
+        self.insert(name, permission, signature, Box::new(move |state, _| {
+            let mut out = T::default();
+            let status = native(state, &mut out);
+            if status != 0 {
+                return Err(CallbackError::Status(status));
+            }
+            serde_json::to_value(&out)
+                .map(CallbackValue::Struct)
+                .map_err(|e| CallbackError::Marshal(e.to_string()))
+        }))
+    }
+
+    pub fn register_str_arg(&mut self, name: &str, native: StrArgCallback, permission: Permission) -> &mut Self {
+        let signature = Signature::with_state([ValueType::Str], ReturnType::I32);
+        self.insert(name, permission, signature, Box::new(move |state, args| {
+            let text = args[0]
+                .as_str()
+                .ok_or_else(|| CallbackError::Arguments("expected a string".to_string()))?;
+            Ok(CallbackValue::I32(native(state, text.as_ptr(), text.len())))
+        }))
+    }
+
+    pub fn register_slice_arg<T: SliceElement>(
+        &mut self,
+        name: &str,
+        native: SliceArgCallback<T>,
+        permission: Permission,
+    ) -> &mut Self {
+        let signature = Signature::with_state([ValueType::Slice(T::TYPE)], ReturnType::I32);
+        self.insert(name, permission, signature, Box::new(move |state, args| {
+            let values: Vec<T> = args[0]
+                .as_array()
+                .and_then(|values| values.iter().map(T::from_json).collect())
+                .ok_or_else(|| CallbackError::Arguments(format!("expected an array of {}", T::TYPE.name())))?;
+            Ok(CallbackValue::I32(native(state, values.as_ptr(), values.len())))
+        }))
+    }
+
+    /// Adds rules for the client parameter at `param` of a registered
+    /// callback. Panics if there is no such parameter.
+    pub fn validate(&mut self, name: &str, param: usize, rules: Vec<Rule>) -> &mut Self {
+        let entry = self.entries.get_mut(name).expect("validated callback is registered");
+        entry.rules.get_mut(param).expect("validated parameter exists").extend(rules);
+        self
+    }
+
+    /// Marks a registered callback as only reading the state. Writes it
+    /// makes anyway are discarded. Panics if there is no such callback.
+    pub fn read_only(&mut self, name: &str) -> &mut Self {
+        self.entries.get_mut(name).expect("read-only callback is registered").read_only = true;
+        self
+    }
+
+    fn insert(&mut self, name: &str, permission: Permission, signature: Signature, invoker: Invoker) -> &mut Self {
+        let rules = vec![Vec::new(); signature.client_params().count()];
+        self.entries.insert(name.to_string(), CallbackEntry {
+            name: name.to_string(),
+            permission,
+            signature,
+            last_executed: Mutex::new(None),
+            rules,
+            read_only: false,
+            invoker,
+        });
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&CallbackEntry> {
+        self.entries.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &CallbackEntry> {
+        self.entries.values()
+    }
+
+    /// Keeps the callbacks whose name `keep` accepts
+    pub fn retain(&mut self, mut keep: impl FnMut(&str) -> bool) {
+        self.entries.retain(|name, _| keep(name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" fn greeting(_: *mut State, out_ptr: *mut *const u8, out_len: *mut usize) -> i32 {
+        let text = "hello";
+        unsafe {
+            *out_ptr = text.as_ptr();
+            *out_len = text.len();
+        }
+        0
+    }
+
+    extern "C" fn failing(_: *mut State, _: *mut *const u8, _: *mut usize) -> i32 {
+        3
+    }
+
+    #[test]
+    fn marshals_string_results_and_status() {
+        let mut registry = CallbackRegistry::new();
+        registry
+            .register_string("greeting", greeting, Permission::Public)
+            .register_string("failing", failing, Permission::Public);
+
+        let mut state = State { counter: 0 };
+        let value = registry.get("greeting").unwrap().invoke(&mut state, &[]).unwrap();
+        assert!(matches!(value, CallbackValue::String(s) if s == "hello"));
+
+        let error = registry.get("failing").unwrap().invoke(&mut state, &[]).unwrap_err();
+        assert!(matches!(error, CallbackError::Status(3)));
+    }
+
+    #[test]
+    fn passes_string_arguments() {
+        let mut registry = CallbackRegistry::new();
+        registry.register_str_arg("set_counter", crate::set_counter, Permission::Public);
+        let callback = registry.get("set_counter").unwrap();
+        assert_eq!(callback.signature.client_params().collect::<Vec<_>>(), vec![&ValueType::Str]);
+
+        let mut state = State { counter: 0 };
+        let value = callback.invoke(&mut state, &[serde_json::json!("-17")]).unwrap();
+        assert_eq!(value, CallbackValue::I32(-17));
+        assert!(matches!(callback.invoke(&mut state, &[]), Err(CallbackError::Arguments(_))));
+        assert!(matches!(callback.invoke(&mut state, &[serde_json::json!(3)]), Err(CallbackError::Arguments(_))));
+        assert_eq!(state.counter, -17);
+    }
+
+    #[test]
+    fn round_trips_slice_arguments() {
+        let mut registry = CallbackRegistry::new();
+        registry.register_slice_arg("add_values", crate::add_values, Permission::Public);
+        let callback = registry.get("add_values").unwrap();
+        assert_eq!(
+            serde_json::to_value(&callback.signature.params).unwrap(),
+            serde_json::json!(["state_ptr", { "slice": "i32" }])
+        );
+
+        let mut state = State { counter: 1 };
+        let value = callback.invoke(&mut state, &[serde_json::json!([2, -4, 10])]).unwrap();
+        assert_eq!(value, CallbackValue::I32(9));
+        let value = callback.invoke(&mut state, &[serde_json::json!([])]).unwrap();
+        assert_eq!(value, CallbackValue::I32(9));
+        for wrong in [serde_json::json!([1, 2.5]), serde_json::json!([1u64 << 40]), serde_json::json!("1, 2")] {
+            let error = callback.invoke(&mut state, &[wrong]).unwrap_err();
+            assert_eq!(error.to_string(), "Invalid arguments: expected an array of i32");
+        }
+        assert_eq!(state.counter, 9);
+    }
+
+    #[test]
+    fn marks_read_only_callbacks() {
+        let registry = crate::register_callbacks();
+        let read_only: Vec<&str> = registry.iter().filter(|callback| callback.read_only).map(|callback| callback.name.as_str()).collect();
+        assert_eq!(read_only, ["counter_parity", "counter_squared", "counter_stats"]);
+        let script = crate::glue::script(&registry, crate::csrf::CSRF_HEADER);
+        assert!(script.contains(r#"const READ_ONLY = new Set(["counter_parity","counter_squared","counter_stats"]);"#));
+    }
+
+    #[test]
+    fn declares_the_state_pointer_first() {
+        let registry = crate::register_callbacks();
+        let stats = registry.get("counter_stats").unwrap();
+        assert_eq!(stats.signature.params, vec![ValueType::StatePtr, ValueType::OutPtr]);
+        assert!(stats.signature.takes_state() && stats.signature.client_params().next().is_none());
+        assert!(registry.iter().all(|callback| callback.signature.takes_state()));
+
+        let names: Vec<&str> = registry.iter().map(|callback| callback.name.as_str()).collect();
+        let script = crate::glue::script(&registry, crate::csrf::CSRF_HEADER);
+        assert!(script.contains(&format!("const STATE_PARAM = new Set({});", serde_json::to_string(&names).unwrap())));
+    }
+}
@@ -0,0 +1,251 @@
+// Cluster relay
+// Instances behind a load balancer each push events to their own browsers.
+// With `[cluster] pubsub = "redis"` or `"nats"`, every event an instance
+// broadcasts is also published on a shared channel, and every instance
+// re-broadcasts the events of the others to its browsers. `state` events
+// carry the state image along with the version, so an instance takes over
+// newer states of the others even without a shared state backend. Lost
+// connections are reopened with exponential backoff; events published
+// meanwhile are dropped after one retry.
+
+use crate::events::Broadcaster;
+use crate::store::{Snapshot, StateStore};
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Duration;
+
+// How often an idle publisher answers the server's PINGs
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+// First and longest wait before reconnecting
+const BACKOFF: (Duration, Duration) = (Duration::from_millis(500), Duration::from_secs(60));
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PubSub {
+    #[default]
+    None,
+    Redis,
+    Nats,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    /// Id of the publishing instance, in hex, which ignores its own
+    /// messages
+    pub origin: String,
+    pub event: String,
+    pub data: String,
+    /// State image of `state` events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<Vec<u8>>,
+}
+
+/// The publishing side, owned by the `Broadcaster`
+pub struct Relay {
+    origin: String,
+    // Drained by a thread, so broadcasting never waits for the network
+    outgoing: mpsc::Sender<Vec<u8>>,
+}
+
+impl Relay {
+    pub fn publish(&self, event: &str, data: &str, state: Option<&[u8]>) {
+        let message = Message {
+            origin: self.origin.clone(),
+            event: event.to_string(),
+            data: data.to_string(),
+            state: state.map(<[u8]>::to_vec),
+        };
+        if let Ok(payload) = serde_json::to_vec(&message) {
+            let _ = self.outgoing.send(payload);
+        }
+    }
+}
+
+// A connection to the pub/sub server. One publishes, a second one is
+// subscribed, as subscribed Redis connections take no other commands.
+enum Connection {
+    Redis(crate::redis::Connection),
+    Nats(crate::nats::Connection),
+}
+
+impl Connection {
+    fn open(pubsub: PubSub, url: &str) -> Result<Self, String> {
+        match pubsub {
+            PubSub::Redis => crate::redis::Connection::open(url).map(Connection::Redis),
+            PubSub::Nats => crate::nats::Connection::open(url).map(Connection::Nats),
+            PubSub::None => Err("No pubsub server configured".to_string()),
+        }
+    }
+
+    fn publish(&mut self, channel: &str, payload: &[u8]) -> Result<(), String> {
+        match self {
+            Connection::Redis(connection) => connection.command(&[b"PUBLISH", channel.as_bytes(), payload]).map(|_| ()),
+            Connection::Nats(connection) => connection.publish(channel, payload),
+        }
+    }
+
+    /// Answers what the server sent a connection that only publishes
+    fn keep_alive(&mut self) -> Result<(), String> {
+        match self {
+            // Redis does not ping its clients
+            Connection::Redis(_) => Ok(()),
+            Connection::Nats(connection) => connection.keep_alive(),
+        }
+    }
+
+    fn subscribe(&mut self, channel: &str) -> Result<(), String> {
+        match self {
+            Connection::Redis(connection) => connection.command(&[b"SUBSCRIBE", channel.as_bytes()]).map(|_| ()),
+            Connection::Nats(connection) => connection.subscribe(channel),
+        }
+    }
+
+    /// Payload of the next message on the subscribed channel
+    fn next_message(&mut self) -> Result<Vec<u8>, String> {
+        use crate::redis::Reply;
+        match self {
+            Connection::Redis(connection) => loop {
+                if let Reply::Array(mut reply) = connection.read_reply()? {
+                    if reply.len() == 3 && reply[0] == Reply::Bulk(Some(b"message".to_vec())) {
+                        if let Some(Reply::Bulk(Some(payload))) = reply.pop() {
+                            return Ok(payload);
+                        }
+                    }
+                }
+            },
+            Connection::Nats(connection) => connection.next_message(),
+        }
+    }
+}
+
+/// Connects to the pub/sub server and attaches the relay to `events`
+pub fn start(config: &crate::config::ClusterConfig, events: Arc<Broadcaster>, store: Arc<StateStore>) -> Result<(), String> {
+    if config.pubsub == PubSub::None {
+        return Ok(());
+    }
+    if config.url.is_empty() {
+        return Err("cluster.url is required with a pubsub channel".to_string());
+    }
+    let channel = config.channel.clone();
+    let mut publisher = Connection::open(config.pubsub, &config.url)?;
+    let mut subscriber = Connection::open(config.pubsub, &config.url)?;
+    subscriber.subscribe(&channel)?;
+
+    let origin = format!("{:x}", store.instance());
+    let (outgoing, queue) = mpsc::channel::<Vec<u8>>();
+    events.relay_to(Relay { origin: origin.clone(), outgoing });
+
+    let (pubsub, url, publish_channel) = (config.pubsub, config.url.clone(), channel.clone());
+    std::thread::spawn(move || loop {
+        let payload = match queue.recv_timeout(KEEP_ALIVE) {
+            Ok(payload) => Some(payload),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        let published = publisher.keep_alive().and_then(|_| match &payload {
+            Some(payload) => publisher.publish(&publish_channel, payload),
+            None => Ok(()),
+        });
+        if let Err(e) = published {
+            tracing::warn!(error = %e, "Lost the connection publishing to the cluster");
+            publisher = reconnect(pubsub, &url, None);
+            if let Some(Err(e)) = payload.map(|payload| publisher.publish(&publish_channel, &payload)) {
+                tracing::warn!(error = %e, "Could not publish an event to the cluster");
+            }
+        }
+    });
+    let (url, subscribed) = (config.url.clone(), channel.clone());
+    std::thread::spawn(move || loop {
+        let payload = match subscriber.next_message() {
+            Ok(payload) => payload,
+            Err(e) => {
+                tracing::warn!(error = %e, "Lost the cluster subscription");
+                subscriber = reconnect(pubsub, &url, Some(&subscribed));
+                continue;
+            }
+        };
+        match serde_json::from_slice::<Message>(&payload) {
+            Ok(message) if message.origin == origin => {}
+            Ok(message) => receive(&message, &events, &store),
+            Err(e) => tracing::warn!(error = %e, "Ignoring a malformed cluster message"),
+        }
+    });
+    tracing::info!(pubsub = ?config.pubsub, %channel, "Relaying events across instances");
+    Ok(())
+}
+
+// A new connection, subscribed to `channel` if given, retried with
+// exponential backoff until the server is back
+fn reconnect(pubsub: PubSub, url: &str, channel: Option<&str>) -> Connection {
+    let mut delay = BACKOFF.0;
+    loop {
+        std::thread::sleep(delay);
+        let connection = Connection::open(pubsub, url).and_then(|mut connection| {
+            if let Some(channel) = channel {
+                connection.subscribe(channel)?;
+            }
+            Ok(connection)
+        });
+        match connection {
+            Ok(connection) => {
+                tracing::info!(?pubsub, "Reconnected to the cluster");
+                return connection;
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, retry_in = ?delay, "Could not reconnect to the cluster");
+                delay = (delay * 2).min(BACKOFF.1);
+            }
+        }
+    }
+}
+
+// Another instance's event, for the browsers connected to this one
+fn receive(message: &Message, events: &Broadcaster, store: &StateStore) {
+    let instance = u64::from_str_radix(&message.origin, 16);
+    match (message.event.as_str(), &message.state, message.data.parse::<u64>(), instance) {
+        // Announced by take_over if later, an earlier state is not announced
+        ("state", Some(image), Ok(version), Ok(instance)) => store.take_over(&Snapshot { version, instance, image: image.clone() }),
+        _ => events.broadcast_local(&message.event, &message.data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_messages_carry_the_image() {
+        let (outgoing, queue) = mpsc::channel();
+        let relay = Relay { origin: "a1".to_string(), outgoing };
+        relay.publish("state", "3", Some(&[1, 2]));
+        relay.publish("reload", "4", None);
+
+        let messages: Vec<Message> = queue.try_iter().map(|payload| serde_json::from_slice(&payload).unwrap()).collect();
+        assert_eq!(messages[0].state, Some(vec![1, 2]));
+        assert_eq!(messages[0].origin, "a1");
+        assert!(!String::from_utf8(serde_json::to_vec(&messages[1]).unwrap()).unwrap().contains("state"));
+    }
+
+    #[test]
+    fn resubscribes_once_the_server_is_back() {
+        use std::io::{BufRead, BufReader, Write};
+        // A port nothing listens on until the server comes back
+        let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            std::thread::sleep(BACKOFF.0 + Duration::from_millis(200));
+            let listener = std::net::TcpListener::bind(address).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            reader.get_mut().write_all(b"INFO {}\r\n").unwrap();
+            let mut lines = String::new();
+            while !lines.contains("SUB") {
+                reader.read_line(&mut lines).unwrap();
+            }
+            lines
+        });
+
+        reconnect(PubSub::Nats, &format!("nats://{}", address), Some("events"));
+        assert!(server.join().unwrap().ends_with("SUB events 1\r\n"));
+    }
+}
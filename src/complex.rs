@@ -97,11 +97,13 @@ pub extern "C" fn clear_completed(state_ptr: *mut AppState) -> u32 {
     }
 }
 
-// Complex rendering logic
-fn render_app_extended(state: &AppState) -> Dom {
+// Complex rendering logic. Only the requested window of the todos is
+// rendered, see `pagination.rs`.
+fn render_app_extended(state: &AppState, page: Page) -> Dom {
+    let list = PaginatedList::new("todos", "/todos", page);
     let mut todos_nodes = Vec::new();
     
-    for todo in &state.todos {
+    for todo in list.window(&state.todos) {
         let checkbox_attrs = if todo.completed {
             vec![
                 ("type", "checkbox"),
@@ -158,7 +160,7 @@ fn render_app_extended(state: &AppState) -> Dom {
                     ]),
                 ]),
                 
-                DomNode::element("ul", vec![("class", "todo-list")], todos_nodes),
+                list.render(state.todos.len(), DomNode::element("ul", vec![("class", "todo-list")], todos_nodes)),
                 
                 DomNode::element("div", vec![("class", "footer")], vec![
                     DomNode::element("span", vec![], vec![
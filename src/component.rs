@@ -0,0 +1,406 @@
+// WASM Component Model output
+// Wraps the core module of a callback in a component so it can be consumed by
+// jco and other component tooling, and describes it with a WIT world derived
+// from the registered signature. The state pointer stays a plain s32 address
+// in linear memory, out-pointers disappear into the result type: strings
+// become `result<string, s32>` and structs a record translated from their JSON
+// schema (arrays become lists), with the status code as the error.
+//
+// Only i32 and f64 callbacks have a core module that can be lifted as is,
+// strings and records would need a `cabi_realloc` export. Host functions the
+// core module imports (`env.read_i64`/`env.write_i64` in imports mode) become
+// component imports.
+
+use crate::callbacks::{ElementType, ReturnType, Signature, ValueType};
+use serde_json::Value as Json;
+use std::fmt::Write;
+use wasm_encoder::{
+    ComponentBuilder, ComponentExportKind, ComponentTypeRef, ExportKind, ModuleArg, PrimitiveValType,
+};
+use wasmparser::{Parser, Payload, TypeRef, ValType};
+
+// WIT keywords that need a `%` escape when used as identifiers
+const KEYWORDS: &[&str] = &[
+    "bool", "char", "enum", "export", "flags", "func", "import", "interface", "list", "option",
+    "package", "record", "resource", "result", "s8", "s16", "s32", "s64", "string", "tuple", "type",
+    "u8", "u16", "u32", "u64", "f32", "f64", "use", "variant", "world",
+];
+
+/// `increment_counter` / `CounterStats` -> `increment-counter` / `counter-stats`
+fn kebab(name: &str) -> String {
+    let mut out = String::new();
+    let mut prev_lower = false;
+    for c in name.chars() {
+        if c == '_' || c == '-' {
+            if !out.ends_with('-') && !out.is_empty() {
+                out.push('-');
+            }
+            prev_lower = false;
+            continue;
+        }
+        if c.is_ascii_uppercase() && prev_lower {
+            out.push('-');
+        }
+        prev_lower = c.is_ascii_lowercase() || c.is_ascii_digit();
+        out.push(c.to_ascii_lowercase());
+    }
+    out.trim_end_matches('-').to_string()
+}
+
+fn ident(name: &str) -> String {
+    let name = kebab(name);
+    match KEYWORDS.contains(&name.as_str()) {
+        true => format!("%{}", name),
+        false => name,
+    }
+}
+
+// A host function imported by the core module
+struct HostImport {
+    module: String,
+    field: String,
+    params: Vec<PrimitiveValType>,
+    result: Option<PrimitiveValType>,
+}
+
+// Exported function and host imports of the core module
+struct CoreModule {
+    export: String,
+    params: Vec<ValType>,
+    results: Vec<ValType>,
+    imports: Vec<HostImport>,
+}
+
+fn primitive(ty: ValType) -> Result<PrimitiveValType, String> {
+    match ty {
+        ValType::I32 => Ok(PrimitiveValType::S32),
+        ValType::I64 => Ok(PrimitiveValType::S64),
+        ValType::F32 => Ok(PrimitiveValType::F32),
+        ValType::F64 => Ok(PrimitiveValType::F64),
+        other => Err(format!("{} has no component equivalent", other)),
+    }
+}
+
+fn primitive_name(ty: PrimitiveValType) -> &'static str {
+    match ty {
+        PrimitiveValType::S32 => "s32",
+        PrimitiveValType::S64 => "s64",
+        PrimitiveValType::F32 => "f32",
+        PrimitiveValType::F64 => "f64",
+        _ => unreachable!("core types only map to numbers"),
+    }
+}
+
+fn element_type(element: ElementType) -> &'static str {
+    match element {
+        ElementType::U8 => "u8",
+        ElementType::I32 => "s32",
+        ElementType::U32 => "u32",
+        ElementType::I64 => "s64",
+        ElementType::U64 => "u64",
+        ElementType::F32 => "f32",
+        ElementType::F64 => "f64",
+    }
+}
+
+fn parse(core: &[u8]) -> Result<CoreModule, String> {
+    let mut types = Vec::new();
+    let mut imported = Vec::new();
+    let mut functions = Vec::new();
+    let mut export = None;
+
+    for payload in Parser::new(0).parse_all(core) {
+        match payload.map_err(|e| e.to_string())? {
+            Payload::TypeSection(reader) => {
+                for ty in reader.into_iter_err_on_gc_types() {
+                    types.push(ty.map_err(|e| e.to_string())?);
+                }
+            }
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import.map_err(|e| e.to_string())?;
+                    let TypeRef::Func(ty) = import.ty else {
+                        return Err(format!("{}.{} is not a function import", import.module, import.name));
+                    };
+                    imported.push((import.module.to_string(), import.name.to_string(), ty));
+                }
+            }
+            Payload::FunctionSection(reader) => {
+                for ty in reader {
+                    functions.push(ty.map_err(|e| e.to_string())?);
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for item in reader {
+                    let item = item.map_err(|e| e.to_string())?;
+                    if item.kind == wasmparser::ExternalKind::Func && export.is_none() {
+                        export = Some((item.name.to_string(), item.index));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let (export, index) = export.ok_or("The module exports no function")?;
+    // Imported functions come first in the function index space
+    let ty = match (index as usize).checked_sub(imported.len()) {
+        Some(local) => functions.get(local).copied(),
+        None => None,
+    };
+    let ty = ty.and_then(|ty| types.get(ty as usize)).ok_or("The export is not a defined function")?;
+
+    let imports = imported
+        .into_iter()
+        .map(|(module, name, ty)| {
+            let ty = types.get(ty as usize).ok_or("Import of an unknown type")?;
+            let params = ty.params().iter().map(|&ty| primitive(ty)).collect::<Result<_, _>>()?;
+            let result = match ty.results() {
+                [] => None,
+                [ty] => Some(primitive(*ty)?),
+                _ => return Err(format!("{}.{} returns multiple values", module, name)),
+            };
+            Ok(HostImport { module, field: name, params, result })
+        })
+        .collect::<Result<_, String>>()?;
+
+    Ok(CoreModule {
+        export,
+        params: ty.params().to_vec(),
+        results: ty.results().to_vec(),
+        imports,
+    })
+}
+
+/// WIT world of a callback: the signature as an export, plus the host
+/// functions its core module imports, if it has one.
+pub fn wit(fn_name: &str, signature: &Signature, core: Option<&[u8]>) -> Result<String, String> {
+    let imports = match core {
+        Some(core) => parse(core)?.imports,
+        None => Vec::new(),
+    };
+    let mut records = Vec::new();
+    let result = match &signature.returns {
+        ReturnType::I32 => "s32".to_string(),
+        ReturnType::F64 => "f64".to_string(),
+        ReturnType::String => "result<string, s32>".to_string(),
+        ReturnType::Struct { type_name, schema, .. } => {
+            format!("result<{}, s32>", record(type_name, schema, &mut records)?)
+        }
+    };
+    let params: Vec<String> = signature
+        .params
+        .iter()
+        .filter_map(|param| match param {
+            ValueType::StatePtr => Some("state: s32".to_string()),
+            ValueType::OutPtr | ValueType::Out(_) => None,
+            ValueType::Str => Some("text: string".to_string()),
+            ValueType::Slice(element) => Some(format!("values: list<{}>", element_type(*element))),
+        })
+        .collect();
+
+    let mut wit = String::new();
+    let _ = writeln!(wit, "package self-serve:callbacks;");
+    let _ = writeln!(wit);
+    let _ = writeln!(wit, "world {} {{", ident(fn_name));
+    for import in &imports {
+        let params: Vec<String> = import
+            .params
+            .iter()
+            .enumerate()
+            .map(|(idx, ty)| format!("p{}: {}", idx, primitive_name(*ty)))
+            .collect();
+        let result = import.result.map(|ty| format!(" -> {}", primitive_name(ty))).unwrap_or_default();
+        let _ = writeln!(wit, "    import {}: func({}){};", ident(&import.field), params.join(", "), result);
+    }
+    for record in records {
+        let _ = writeln!(wit, "{}", record);
+    }
+    let _ = writeln!(wit, "    export {}: func({}) -> {};", ident(fn_name), params.join(", "), result);
+    let _ = writeln!(wit, "}}");
+    Ok(wit)
+}
+
+// Declares a record for an object schema and returns its name
+fn record(type_name: &str, schema: &Json, records: &mut Vec<String>) -> Result<String, String> {
+    let name = ident(type_name);
+    let properties = schema["properties"].as_object().ok_or(format!("{} is not an object", type_name))?;
+    let mut fields = Vec::new();
+    for (field, schema) in properties {
+        fields.push(format!("        {}: {},", ident(field), schema_type(field, schema, records)?));
+    }
+    records.push(format!("    record {} {{\n{}\n    }}", name, fields.join("\n")));
+    Ok(name)
+}
+
+fn schema_type(field: &str, schema: &Json, records: &mut Vec<String>) -> Result<String, String> {
+    // Option<T> is `"type": ["...", "null"]`
+    if let Some(types) = schema["type"].as_array() {
+        let inner: Vec<&Json> = types.iter().filter(|ty| ty.as_str() != Some("null")).collect();
+        if let [ty] = inner[..] {
+            let mut schema = schema.clone();
+            schema["type"] = ty.clone();
+            return Ok(format!("option<{}>", schema_type(field, &schema, records)?));
+        }
+    }
+
+    let ty = match (schema["type"].as_str(), schema["format"].as_str()) {
+        (Some("boolean"), _) => "bool",
+        (Some("string"), _) => "string",
+        (Some("integer"), Some("uint8")) => "u8",
+        (Some("integer"), Some("int8")) => "s8",
+        (Some("integer"), Some("uint16")) => "u16",
+        (Some("integer"), Some("int16")) => "s16",
+        (Some("integer"), Some("uint32" | "uint")) => "u32",
+        (Some("integer"), Some("int32" | "int")) => "s32",
+        (Some("integer"), Some("uint64")) => "u64",
+        (Some("integer"), _) => "s64",
+        (Some("number"), Some("float")) => "f32",
+        (Some("number"), _) => "f64",
+        (Some("array"), _) => return Ok(format!("list<{}>", schema_type(field, &schema["items"], records)?)),
+        (Some("object"), _) => return record(field, schema, records),
+        _ => return Err(format!("Field {} has no WIT equivalent: {}", field, schema)),
+    };
+    Ok(ty.to_string())
+}
+
+/// Wraps the core module of a callback in a component exporting it under the
+/// kebab-case callback name, with the type from `wit`.
+pub fn wrap(fn_name: &str, signature: &Signature, core: &[u8]) -> Result<Vec<u8>, String> {
+    let (core_result, result) = match signature.returns {
+        ReturnType::I32 => (ValType::I32, PrimitiveValType::S32),
+        ReturnType::F64 => (ValType::F64, PrimitiveValType::F64),
+        _ => return Err("Only scalar callbacks can be lifted without a cabi_realloc export".to_string()),
+    };
+    if signature.client_params().next().is_some() {
+        return Err("String and list arguments cannot be lowered without a cabi_realloc export".to_string());
+    }
+    let module = parse(core)?;
+    let state_params = signature.params.iter().filter(|param| **param == ValueType::StatePtr).count();
+    if module.params != vec![ValType::I32; state_params] || module.results != [core_result] {
+        return Err(format!(
+            "Core signature {:?} -> {:?} does not match the callback signature",
+            module.params, module.results
+        ));
+    }
+
+    let mut component = ComponentBuilder::default();
+    let core_module = component.core_module_raw(core);
+
+    // Host imports, lowered and grouped into one core instance per module
+    let mut lowered = Vec::new();
+    for import in &module.imports {
+        let params: Vec<(String, PrimitiveValType)> =
+            import.params.iter().enumerate().map(|(idx, ty)| (format!("p{}", idx), *ty)).collect();
+        let (ty, mut func) = component.type_function();
+        func.params(params.iter().map(|(name, ty)| (name.as_str(), *ty)));
+        if let Some(result) = import.result {
+            func.result(result);
+        }
+        let func = component.import(&kebab(&import.field), ComponentTypeRef::Func(ty));
+        lowered.push((import, component.lower_func(func, [])));
+    }
+    let mut modules: Vec<&str> = module.imports.iter().map(|import| import.module.as_str()).collect();
+    modules.sort();
+    modules.dedup();
+    let mut args = Vec::new();
+    for name in modules {
+        let exports: Vec<(&str, ExportKind, u32)> = lowered
+            .iter()
+            .filter(|(import, _)| import.module == name)
+            .map(|(import, func)| (import.field.as_str(), ExportKind::Func, *func))
+            .collect();
+        args.push((name, ModuleArg::Instance(component.core_instantiate_exports(exports))));
+    }
+
+    let instance = component.core_instantiate(core_module, args);
+    let core_func = component.core_alias_export(instance, &module.export, ExportKind::Func);
+    let (ty, mut func) = component.type_function();
+    func.params((0..state_params).map(|_| ("state", PrimitiveValType::S32)));
+    func.result(result);
+    let func = component.lift_func(core_func, ty, []);
+    component.export(&kebab(fn_name), ComponentExportKind::Func, func, None);
+    Ok(component.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::StructLayout;
+    use wasm_encoder::{
+        CodeSection, EntityType, ExportSection, Function, FunctionSection, ImportSection, Instruction, Module,
+        TypeSection, ValType as CoreType,
+    };
+    use wasmparser::{Validator, WasmFeatures};
+
+    fn i32_signature() -> Signature {
+        Signature {
+            params: vec![ValueType::StatePtr],
+            returns: ReturnType::I32,
+        }
+    }
+
+    // (state) -> state + env.read_i64(0)
+    fn core_module() -> Vec<u8> {
+        let mut module = Module::new();
+        let mut types = TypeSection::new();
+        types.ty().function(vec![CoreType::I32], vec![CoreType::I32]);
+        types.ty().function(vec![CoreType::I64], vec![CoreType::I64]);
+        module.section(&types);
+        let mut imports = ImportSection::new();
+        imports.import("env", "read_i64", EntityType::Function(1));
+        module.section(&imports);
+        let mut functions = FunctionSection::new();
+        functions.function(0);
+        module.section(&functions);
+        let mut exports = ExportSection::new();
+        exports.export("callback", wasm_encoder::ExportKind::Func, 1);
+        module.section(&exports);
+        let mut codes = CodeSection::new();
+        let mut func = Function::new(vec![]);
+        func.instruction(&Instruction::LocalGet(0));
+        func.instruction(&Instruction::I64Const(0));
+        func.instruction(&Instruction::Call(0));
+        func.instruction(&Instruction::I32WrapI64);
+        func.instruction(&Instruction::I32Add);
+        func.instruction(&Instruction::End);
+        codes.function(&func);
+        module.section(&codes);
+        module.finish()
+    }
+
+    #[test]
+    fn wraps_core_module_with_host_imports() {
+        let component = wrap("increment_counter", &i32_signature(), &core_module()).unwrap();
+        Validator::new_with_features(WasmFeatures::all()).validate_all(&component).unwrap();
+
+        let wit = wit("increment_counter", &i32_signature(), Some(&core_module())).unwrap();
+        assert!(wit.contains("world increment-counter {"), "{}", wit);
+        assert!(wit.contains("import read-i64: func(p0: s64) -> s64;"), "{}", wit);
+        assert!(wit.contains("export increment-counter: func(state: s32) -> s32;"), "{}", wit);
+    }
+
+    #[test]
+    fn translates_struct_schemas_to_records() {
+        let signature = Signature {
+            params: vec![ValueType::StatePtr, ValueType::OutPtr],
+            returns: ReturnType::Struct {
+                type_name: "CounterHistory".to_string(),
+                schema: serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "values": { "type": "array", "items": { "type": "integer", "format": "int32" } },
+                        "last_reset": { "type": ["integer", "null"], "format": "uint64" },
+                    }
+                }),
+                // Vec and Option have no fixed layout
+                layout: StructLayout { name: "CounterHistory", size: 0, align: 1, fields: Vec::new() },
+            },
+        };
+        let wit = wit("counter_history", &signature, None).unwrap();
+        assert!(wit.contains("last-reset: option<u64>,"), "{}", wit);
+        assert!(wit.contains("values: list<s32>,"), "{}", wit);
+        assert!(wit.contains("func(state: s32) -> result<counter-history, s32>;"), "{}", wit);
+        assert!(wrap("counter_history", &signature, &core_module()).is_err());
+    }
+}
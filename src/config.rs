@@ -0,0 +1,544 @@
+// Server configuration
+// Loaded from the TOML file named by SELF_SERVE_CONFIG, or ./self-serve.toml
+// if present. Every section is optional and falls back to its defaults.
+
+use crate::backend::BackendKind;
+use crate::cluster::PubSub;
+use crate::cpuid::CpuidLeaf;
+use crate::exposure::ExposureMode;
+use crate::profile::Profile;
+use crate::schedule::ScheduleConfig;
+use crate::store::StateBackendKind;
+use crate::sync::SyncPolicy;
+use crate::syscalls::SyscallMode;
+use crate::transpiler_real::{OptLevel, StateAccess, TranspileOptions, TrapStrategy};
+use crate::verify::VerifyLimits;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub cors: CorsConfig,
+    pub auth: AuthConfig,
+    pub transpiler: TranspilerConfig,
+    pub sync: SyncConfig,
+    pub state: StateConfig,
+    pub cluster: ClusterConfig,
+    /// Callbacks run on a schedule, as `[[schedule]]` tables, see
+    /// `schedule.rs`
+    pub schedule: Vec<ScheduleConfig>,
+    pub i18n: I18nConfig,
+    pub templates: TemplatesConfig,
+    pub csp: CspConfig,
+    pub limits: LimitsConfig,
+    pub audit: AuditConfig,
+    pub debug: DebugConfig,
+    pub exposure: ExposureConfig,
+    /// Further binaries whose symbols are served under their namespace, as
+    /// `[binaries.<namespace>]` tables, see `binaries.rs`
+    pub binaries: BTreeMap<String, BinaryConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BinaryConfig {
+    /// Executable, shared library, archive or object file
+    pub path: PathBuf,
+    /// Symbols translated and served at `/wasm/<namespace>/<symbol>`
+    pub callbacks: Vec<String>,
+    /// Overrides `transpiler.load_bias` for this binary
+    #[serde(default)]
+    pub load_bias: Option<u64>,
+    /// Parameter and return types of the symbols, for binaries without
+    /// debug info, see `signatures.rs`
+    #[serde(default)]
+    pub signatures: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Admit callers with the application's validator closure,
+    /// `validate_caller` in lib.rs, instead of the credentials of
+    /// SELF_SERVE_AUTH_TOKEN or SELF_SERVE_BASIC_AUTH, see `auth.rs`
+    pub validator: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TranspilerConfig {
+    /// Lowering strategy, see `backend::BackendKind`
+    pub backend: BackendKind,
+    /// `"memory"` translates state accesses to linear memory loads/stores,
+    /// `"imports"` to calls to `env.read_i64`/`env.write_i64` so the state
+    /// never has to be shipped to the browser as a whole, `"handles"` passes
+    /// pointer parameters as externref handles with generated field accessors
+    pub state_access: StateAccess,
+    /// `"full"` runs the IR optimization passes, `"none"` lowers the lifted
+    /// IR as is
+    pub optimization: OptLevel,
+    /// What becomes of instructions that cannot be lifted: `"skip"` drops
+    /// them, `"unreachable"` traps when one is reached, `"reject"` fails the
+    /// translation
+    pub traps: TrapStrategy,
+    /// Fail the translation if a module grows larger than this
+    pub max_module_bytes: Option<usize>,
+    /// Fail the translation of functions with more instructions than this,
+    /// `budget::DEFAULT_MAX_INSTRUCTIONS` if unset, no limit if 0
+    pub max_instructions: Option<usize>,
+    /// Report why a trap instruction (ud2, int3) was reached through an
+    /// imported `env.trap_reason(code)` before trapping
+    pub trap_reason: bool,
+    /// Report traps with the address of the source instruction through an
+    /// imported `env.on_trap(code, addr_low, addr_high)` instead, also for
+    /// the instructions `traps = "unreachable"` replaces
+    pub on_trap: bool,
+    /// Host functions syscall instructions call: `"browser"` imports
+    /// `env.syscall_<name>` implemented by the page glue, `"wasi"` the
+    /// `wasi_snapshot_preview1` equivalents
+    pub syscalls: SyscallMode,
+    /// Timestamp counter frequency in MHz that rdtsc ticks are scaled to,
+    /// one tick per nanosecond if unset
+    pub tsc_mhz: Option<u32>,
+    /// Constant cpuid results as `[[transpiler.cpuid]]` tables, over the
+    /// built-in ones, see `cpuid.rs`. Applies to every symbol.
+    pub cpuid: Vec<CpuidLeaf>,
+    /// Address the binary is loaded at, added to the addresses its code
+    /// computes from its own position (RIP-relative operands, GOT entries).
+    /// Shared libraries and position-independent executables are linked at
+    /// 0, so their globals only line up with the state image once this is
+    /// set. Applies to every symbol.
+    pub load_bias: u64,
+    /// Fail the translation when a constant embedded from .data/.rodata
+    /// would read back differently in WASM, see `embed.rs`
+    pub audit_data: bool,
+    /// Give modules with a linear memory a bump allocator exporting
+    /// `alloc`, `free` and `reset`, see `alloc.rs`
+    pub allocator: bool,
+    /// Return the scalar out-parameters of a callback as extra WASM results
+    /// instead of writing them through pointers, see `multivalue.rs`
+    pub multi_value: bool,
+    /// Keep registers that only 32-bit instructions write in i32 locals,
+    /// widened where they meet 64-bit values, see `ir::infer_widths`
+    pub infer_widths: bool,
+    /// Inline direct calls to functions of the binary that lift to at most
+    /// this many IR ops, 0 to inline none, see `inline.rs`
+    pub inline_threshold: usize,
+    /// Import the memory and allocator of modules from one runtime module
+    /// the glue instantiates once, see `runtime.rs`
+    pub shared_runtime: bool,
+    /// Cross-check every callback's WASM lowering against native execution
+    /// at startup, see `verify.rs`
+    pub verify: bool,
+    /// Fuel, memory and time budgets of each verification run, as a
+    /// `[transpiler.verify_limits]` table
+    pub verify_limits: VerifyLimits,
+    /// Module variant `/wasm` serves without a `?profile=`: `"debug"` with
+    /// name and mapping sections, `"release"` without. Defaults to `"debug"`
+    /// in debug builds.
+    pub profile: Option<Profile>,
+    /// Modules specialized for the globals of a session that are kept, the
+    /// least recently used are dropped first (default 256)
+    pub max_specialized_modules: Option<usize>,
+    /// Directory the symbol index and code hashes of each binary are kept
+    /// in between boots, none to analyze every binary anew, see
+    /// `analysis_cache.rs`
+    pub analysis_cache: Option<PathBuf>,
+    /// Per-symbol overrides of the options above, as
+    /// `[transpiler.functions.<symbol>]` tables
+    pub functions: BTreeMap<String, TranspileOverrides>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TranspileOverrides {
+    pub state_access: Option<StateAccess>,
+    pub optimization: Option<OptLevel>,
+    pub traps: Option<TrapStrategy>,
+    pub max_module_bytes: Option<usize>,
+    pub max_instructions: Option<usize>,
+    pub trap_reason: Option<bool>,
+    pub on_trap: Option<bool>,
+    pub syscalls: Option<SyscallMode>,
+    pub tsc_mhz: Option<u32>,
+    pub audit_data: Option<bool>,
+    pub allocator: Option<bool>,
+    pub multi_value: Option<bool>,
+    pub infer_widths: Option<bool>,
+    pub inline_threshold: Option<usize>,
+    pub shared_runtime: Option<bool>,
+    /// Globals of the symbol a session can fix with POST /session/globals,
+    /// see `specialize.rs`
+    pub session_globals: Vec<String>,
+}
+
+// A `max_instructions` setting, where 0 is no limit
+fn limit(max: usize) -> Option<usize> {
+    (max > 0).then_some(max)
+}
+
+impl TranspilerConfig {
+    /// The global options, used for symbols without overrides
+    pub fn defaults(&self) -> TranspileOptions {
+        TranspileOptions {
+            state_access: self.state_access,
+            optimization: self.optimization,
+            traps: self.traps,
+            max_module_bytes: self.max_module_bytes,
+            max_instructions: limit(self.max_instructions.unwrap_or(crate::budget::DEFAULT_MAX_INSTRUCTIONS)),
+            float_result: false,
+            signature: None,
+            trap_reason: self.trap_reason,
+            on_trap: self.on_trap,
+            syscalls: self.syscalls,
+            tsc_mhz: self.tsc_mhz,
+            cpuid: self.cpuid.clone(),
+            audit_data: self.audit_data,
+            allocator: self.allocator,
+            multi_value: self.multi_value,
+            out_results: Vec::new(),
+            infer_widths: self.infer_widths,
+            inline_threshold: self.inline_threshold,
+            shared_runtime: self.shared_runtime,
+        }
+    }
+
+    /// The options of `fn_name`: its overrides merged over the defaults
+    pub fn options(&self, fn_name: &str) -> TranspileOptions {
+        let defaults = self.defaults();
+        match self.functions.get(fn_name) {
+            Some(overrides) => TranspileOptions {
+                state_access: overrides.state_access.unwrap_or(defaults.state_access),
+                optimization: overrides.optimization.unwrap_or(defaults.optimization),
+                traps: overrides.traps.unwrap_or(defaults.traps),
+                max_module_bytes: overrides.max_module_bytes.or(defaults.max_module_bytes),
+                max_instructions: overrides.max_instructions.map_or(defaults.max_instructions, limit),
+                float_result: defaults.float_result,
+                signature: defaults.signature,
+                trap_reason: overrides.trap_reason.unwrap_or(defaults.trap_reason),
+                on_trap: overrides.on_trap.unwrap_or(defaults.on_trap),
+                syscalls: overrides.syscalls.unwrap_or(defaults.syscalls),
+                tsc_mhz: overrides.tsc_mhz.or(defaults.tsc_mhz),
+                cpuid: defaults.cpuid,
+                audit_data: overrides.audit_data.unwrap_or(defaults.audit_data),
+                allocator: overrides.allocator.unwrap_or(defaults.allocator),
+                multi_value: overrides.multi_value.unwrap_or(defaults.multi_value),
+                out_results: defaults.out_results,
+                infer_widths: overrides.infer_widths.unwrap_or(defaults.infer_widths),
+                inline_threshold: overrides.inline_threshold.unwrap_or(defaults.inline_threshold),
+                shared_runtime: overrides.shared_runtime.unwrap_or(defaults.shared_runtime),
+            },
+            None => defaults,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncConfig {
+    /// `"verify"` re-executes the native callback before accepting a patch
+    /// posted to /sync, `"trust"` applies it as is, `"paranoid"` verifies
+    /// and also compares the client's memory image
+    pub policy: SyncPolicy,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StateConfig {
+    /// Where the state is persisted: `"memory"` nowhere, `"file"` at
+    /// `path`, `"sqlite"` in the database at `path`, `"redis"` under `key`
+    /// of the server at `url`
+    pub backend: StateBackendKind,
+    pub path: PathBuf,
+    pub url: String,
+    pub key: String,
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        Self {
+            backend: StateBackendKind::Memory,
+            path: PathBuf::from("state.bin"),
+            url: "redis://127.0.0.1:6379".to_string(),
+            key: "self-serve:state".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// `"redis"` or `"nats"` relays events between the instances subscribed
+    /// to `channel` on the server at `url`, `"none"` keeps them local
+    pub pubsub: PubSub,
+    pub url: String,
+    pub channel: String,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self { pubsub: PubSub::None, url: String::new(), channel: "self-serve.events".to_string() }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct I18nConfig {
+    /// Holds a `<locale>.ftl` translation catalog per locale, see `i18n.rs`
+    pub directory: PathBuf,
+    /// Rendered when neither the session nor Accept-Language names a
+    /// locale with a catalog
+    pub default_locale: String,
+}
+
+impl Default for I18nConfig {
+    fn default() -> Self {
+        Self { directory: PathBuf::from("locales"), default_locale: "en".to_string() }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TemplatesConfig {
+    /// Holds a `<name>.html` partial per template, see `templates.rs`
+    pub directory: PathBuf,
+}
+
+impl Default for TemplatesConfig {
+    fn default() -> Self {
+        Self { directory: PathBuf::from("templates") }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// SQLite database the executions are recorded in, none keeps them in
+    /// memory only, see `audit.rs`
+    pub path: Option<PathBuf>,
+    /// Entries older than this are dropped
+    pub retention_days: u64,
+    /// The newest entries kept
+    pub max_entries: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self { path: None, retention_days: 30, max_entries: 100_000 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DebugConfig {
+    /// Opens the debug channel, see `debug.rs`. For development only: every
+    /// page then reports the arguments and memory of its local executions.
+    pub enabled: bool,
+    /// The newest reports a dashboard gets when it connects
+    pub history: usize,
+}
+
+impl Default for DebugConfig {
+    fn default() -> Self {
+        Self { enabled: false, history: 100 }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExposureConfig {
+    /// `"listed"` serves the registered callbacks and the `[binaries]`
+    /// symbols, `"auto"` only the registered callbacks, see `exposure.rs`
+    pub mode: ExposureMode,
+    /// Glob patterns a symbol has to match one of; all symbols if empty
+    pub allow: Vec<String>,
+    /// Glob patterns of symbols never served, even if allowed
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Largest body of POST /execute, the JSON array of arguments
+    pub execute_bytes: usize,
+    pub batch_bytes: usize,
+    pub sync_bytes: usize,
+    pub telemetry_bytes: usize,
+    /// Largest body of POST /upload, streamed to files, see `uploads.rs`
+    pub upload_bytes: usize,
+    pub upload_dir: PathBuf,
+    /// Uploads are removed this long after they were received
+    pub upload_ttl_secs: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            execute_bytes: 64 * 1024,
+            batch_bytes: 256 * 1024,
+            sync_bytes: 1024 * 1024,
+            telemetry_bytes: 16 * 1024,
+            upload_bytes: 64 * 1024 * 1024,
+            upload_dir: std::env::temp_dir().join("self-serve-uploads"),
+            upload_ttl_secs: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CspConfig {
+    /// Send a nonce-based Content-Security-Policy with every HTML page, see
+    /// `csp.rs`
+    pub enabled: bool,
+    /// Where browsers report violations
+    pub report_uri: Option<String>,
+}
+
+impl Default for CspConfig {
+    fn default() -> Self {
+        Self { enabled: true, report_uri: None }
+    }
+}
+
+impl CspConfig {
+    /// The header for a page with `policy`, if enabled
+    pub fn header(&self, policy: &crate::csp::Policy) -> Option<(&'static str, String)> {
+        self.enabled.then(|| ("Content-Security-Policy", policy.header_value(self.report_uri.as_deref())))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins allowed to make cross-origin requests, `"*"` allows any.
+    /// Empty (the default) disables CORS entirely.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    /// Seconds browsers may cache a preflight response
+    pub max_age: Option<usize>,
+    /// Send Cross-Origin-Opener-Policy / Cross-Origin-Embedder-Policy, which
+    /// browsers require before allowing SharedArrayBuffer (shared-memory WASM)
+    pub cross_origin_isolation: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers: vec![
+                "Authorization".to_string(),
+                "Content-Type".to_string(),
+                crate::csrf::CSRF_HEADER.to_string(),
+                crate::logging::REQUEST_ID_HEADER.to_string(),
+            ],
+            max_age: Some(3600),
+            cross_origin_isolation: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Self, String> {
+        match Self::path() {
+            Some(path) => Self::load_from(&path),
+            None => Ok(Config::default()),
+        }
+    }
+
+    /// The config file: `SELF_SERVE_CONFIG`, or `self-serve.toml` if it
+    /// exists. None runs on the defaults.
+    pub fn path() -> Option<PathBuf> {
+        match std::env::var_os("SELF_SERVE_CONFIG") {
+            Some(path) => Some(PathBuf::from(path)),
+            None => Some(PathBuf::from("self-serve.toml")).filter(|default| default.exists()),
+        }
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        toml::from_str(&contents).map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_function_overrides_over_defaults() {
+        let config: Config = toml::from_str(r#"
+            [transpiler]
+            state_access = "imports"
+            traps = "unreachable"
+            max_module_bytes = 4096
+            tsc_mhz = 3000
+
+            [[transpiler.cpuid]]
+            leaf = 7
+            subleaf = 0
+            ebx = 0x20
+
+            [transpiler.functions.counter_stats]
+            optimization = "none"
+            traps = "reject"
+            max_module_bytes = 16384
+            max_instructions = 0
+            audit_data = true
+            allocator = true
+            multi_value = true
+            infer_widths = true
+            inline_threshold = 4
+            shared_runtime = true
+        "#).unwrap();
+
+        let defaults = config.transpiler.options("increment_counter");
+        assert_eq!(defaults, TranspileOptions {
+            state_access: StateAccess::Imports,
+            optimization: OptLevel::Full,
+            traps: TrapStrategy::Unreachable,
+            max_module_bytes: Some(4096),
+            max_instructions: Some(crate::budget::DEFAULT_MAX_INSTRUCTIONS),
+            float_result: false,
+            signature: None,
+            trap_reason: false,
+            on_trap: false,
+            syscalls: SyscallMode::Browser,
+            tsc_mhz: Some(3000),
+            cpuid: vec![CpuidLeaf { leaf: 7, subleaf: Some(0), eax: 0, ebx: 0x20, ecx: 0, edx: 0 }],
+            audit_data: false,
+            allocator: false,
+            multi_value: false,
+            out_results: Vec::new(),
+            infer_widths: false,
+            inline_threshold: 0,
+            shared_runtime: false,
+        });
+        assert_eq!(config.transpiler.options("counter_stats"), TranspileOptions {
+            state_access: StateAccess::Imports,
+            optimization: OptLevel::None,
+            traps: TrapStrategy::Reject,
+            max_module_bytes: Some(16384),
+            max_instructions: None,
+            float_result: false,
+            signature: None,
+            trap_reason: false,
+            on_trap: false,
+            syscalls: SyscallMode::Browser,
+            tsc_mhz: Some(3000),
+            cpuid: vec![CpuidLeaf { leaf: 7, subleaf: Some(0), eax: 0, ebx: 0x20, ecx: 0, edx: 0 }],
+            audit_data: true,
+            allocator: true,
+            multi_value: true,
+            out_results: Vec::new(),
+            infer_widths: true,
+            inline_threshold: 4,
+            shared_runtime: true,
+        });
+    }
+}
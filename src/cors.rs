@@ -0,0 +1,33 @@
+// CORS and cross-origin isolation headers
+// Lets a frontend hosted on another origin fetch /wasm/* and call /execute.
+
+use crate::config::CorsConfig;
+use actix_cors::Cors;
+use actix_web::middleware::DefaultHeaders;
+
+pub fn middleware(config: &CorsConfig) -> Cors {
+    let mut cors = Cors::default();
+
+    if config.allowed_origins.iter().any(|origin| origin == "*") {
+        cors = cors.allow_any_origin();
+    } else {
+        for origin in &config.allowed_origins {
+            cors = cors.allowed_origin(origin);
+        }
+    }
+
+    cors = cors
+        .allowed_methods(config.allowed_methods.iter().map(String::as_str))
+        .allowed_headers(config.allowed_headers.iter().map(String::as_str))
+        .expose_headers([crate::logging::REQUEST_ID_HEADER]);
+    cors.max_age(config.max_age)
+}
+
+/// Headers that opt the page into cross-origin isolation. Resources are marked
+/// as loadable cross-origin so isolated pages elsewhere can still use /wasm/*.
+pub fn isolation_headers() -> DefaultHeaders {
+    DefaultHeaders::new()
+        .add(("Cross-Origin-Opener-Policy", "same-origin"))
+        .add(("Cross-Origin-Embedder-Policy", "require-corp"))
+        .add(("Cross-Origin-Resource-Policy", "cross-origin"))
+}
@@ -0,0 +1,544 @@
+// Instruction coverage database
+// Static table of the mnemonics and operand forms each front-end lifts, and a
+// probe that checks a decoded function against it without lifting, so a
+// symbol can be vetted before it is exposed as a callback.
+//
+// Operand forms are comma separated: `r` register, `v` vector register, `imm`
+// immediate, `m` memory operand without index register, `m[i]` indexed
+// memory operand, `m[tls]` fs/gs-relative (thread-local) memory operand,
+// `sr` shifted register, `rel` branch target and `?` anything else. The form
+// `*` accepts any operands. RISC-V mnemonics have a single operand format
+// each, so their form is left empty.
+//
+// `profile` runs the probe over every function of a binary and counts the
+// mnemonics, and for each unsupported form the functions it keeps from being
+// translated, to show which front-end work pays off most for that binary.
+
+use crate::arch::{Arch, MachineInstr};
+use crate::backend::FunctionIR;
+use iced_x86::{MemorySize, OpKind, Register};
+use serde::Serialize;
+use yaxpeax_arm::armv8::a64::{Operand, ShiftStyle};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Support {
+    /// Lifted with its full semantics
+    Exact,
+    /// Lifted, but dropped or simplified in the WASM lowering (branches,
+    /// calls, stack operations)
+    Approximated,
+    Unsupported,
+}
+
+pub struct Capability {
+    pub mnemonic: &'static str,
+    pub forms: &'static [&'static str],
+    pub support: Support,
+}
+
+const fn cap(mnemonic: &'static str, forms: &'static [&'static str], support: Support) -> Capability {
+    Capability { mnemonic, forms, support }
+}
+
+// Memory operands of arithmetic are only lifted when they address a stack
+// argument of a 32-bit function
+const ALU: &[&str] = &["r, r", "r, imm", "r, m"];
+const BIT_TEST: &[&str] = &["r, r", "r, imm", "m, r", "m, imm"];
+const BRANCH: &[&str] = &["rel"];
+const ANY: &[&str] = &["*"];
+
+/// x86-64 and 32-bit x86, see `Lifter::lift_x86`
+pub const X86: &[Capability] = &[
+    cap("mov", &["r, r", "r, imm", "r, m", "m, r", "m, imm"], Support::Exact),
+    cap("add", ALU, Support::Exact),
+    cap("sub", ALU, Support::Exact),
+    cap("imul", &["r, r", "r, m", "r, r, imm"], Support::Exact),
+    cap("cmp", ALU, Support::Exact),
+    cap("test", ALU, Support::Exact),
+    cap("movzx", &["r, r", "r, m"], Support::Exact),
+    cap("movsx", &["r, r", "r, m"], Support::Exact),
+    cap("movsxd", &["r, r", "r, m"], Support::Exact),
+    cap("bt", BIT_TEST, Support::Exact),
+    cap("bts", BIT_TEST, Support::Exact),
+    cap("btr", BIT_TEST, Support::Exact),
+    cap("btc", BIT_TEST, Support::Exact),
+    cap("ret", &["", "imm"], Support::Exact),
+    cap("je", BRANCH, Support::Approximated),
+    cap("jne", BRANCH, Support::Approximated),
+    cap("jg", BRANCH, Support::Approximated),
+    cap("jl", BRANCH, Support::Approximated),
+    cap("jge", BRANCH, Support::Approximated),
+    cap("jle", BRANCH, Support::Approximated),
+    cap("ja", BRANCH, Support::Approximated),
+    cap("jb", BRANCH, Support::Approximated),
+    cap("jae", BRANCH, Support::Approximated),
+    cap("jbe", BRANCH, Support::Approximated),
+    cap("jmp", &["rel", "r", "m"], Support::Approximated),
+    cap("call", ANY, Support::Approximated),
+    cap("push", ANY, Support::Approximated),
+    cap("pop", ANY, Support::Approximated),
+    cap("ud2", &[""], Support::Exact),
+    cap("int3", &[""], Support::Exact),
+    cap("int", &["imm"], Support::Exact),
+    // Host functions stand in for the kernel, see `syscalls.rs`
+    cap("syscall", &[""], Support::Approximated),
+    // Timestamps from env.now_ns, constant cpuid results, see `cpuid.rs`
+    cap("rdtsc", &[""], Support::Approximated),
+    cap("rdtscp", &[""], Support::Approximated),
+    cap("cpuid", &[""], Support::Approximated),
+    // Scalar doubles; the packed moves only as register copies and the xors
+    // only as the zeroing idiom
+    cap("movsd", &["v, v", "v, m", "m, v"], Support::Exact),
+    cap("movapd", &["v, v"], Support::Exact),
+    cap("movaps", &["v, v"], Support::Exact),
+    cap("addsd", &["v, v", "v, m"], Support::Exact),
+    cap("subsd", &["v, v", "v, m"], Support::Exact),
+    cap("mulsd", &["v, v", "v, m"], Support::Exact),
+    cap("divsd", &["v, v", "v, m"], Support::Exact),
+    cap("cvtsi2sd", &["v, r", "v, m"], Support::Exact),
+    cap("xorpd", &["v, v"], Support::Exact),
+    cap("xorps", &["v, v"], Support::Exact),
+    // The x87 stack holds doubles: memory operands are 64-bit floats or
+    // (fild) integers, arithmetic is rounded to double precision
+    cap("fld", &["m", "st"], Support::Exact),
+    cap("fild", &["m"], Support::Exact),
+    cap("fldz", &[""], Support::Exact),
+    cap("fld1", &[""], Support::Exact),
+    cap("fst", &["m", "st"], Support::Exact),
+    cap("fstp", &["m", "st"], Support::Exact),
+    cap("fadd", &["m", "st, st"], Support::Approximated),
+    cap("faddp", &["st, st"], Support::Approximated),
+    cap("fsub", &["m", "st, st"], Support::Approximated),
+    cap("fsubp", &["st, st"], Support::Approximated),
+    cap("fsubr", &["m", "st, st"], Support::Approximated),
+    cap("fsubrp", &["st, st"], Support::Approximated),
+    cap("fmul", &["m", "st, st"], Support::Approximated),
+    cap("fmulp", &["st, st"], Support::Approximated),
+    cap("fdiv", &["m", "st, st"], Support::Approximated),
+    cap("fdivp", &["st, st"], Support::Approximated),
+    cap("fdivr", &["m", "st, st"], Support::Approximated),
+    cap("fdivrp", &["st, st"], Support::Approximated),
+    cap("fchs", &[""], Support::Exact),
+    cap("fxch", ANY, Support::Exact),
+];
+
+/// AArch64 by opcode (not by alias: `mov` is listed as `orr`/`movz`), see
+/// `Lifter::lift_aarch64`
+pub const AARCH64: &[Capability] = &[
+    cap("movz", &["r, imm"], Support::Exact),
+    cap("movn", &["r, imm"], Support::Exact),
+    cap("adr", &["r, rel"], Support::Exact),
+    cap("adrp", &["r, rel"], Support::Exact),
+    cap("add", &["r, r, r", "r, r, imm"], Support::Exact),
+    cap("adds", &["r, r, r", "r, r, imm"], Support::Exact),
+    cap("sub", &["r, r, r", "r, r, imm"], Support::Exact),
+    cap("subs", &["r, r, r", "r, r, imm"], Support::Exact),
+    cap("and", &["r, r, r", "r, r, imm"], Support::Exact),
+    cap("ands", &["r, r, r", "r, r, imm"], Support::Exact),
+    // Only as `mov` (orr with the zero register) and `mul` (madd onto it)
+    cap("orr", &["r, r, r", "r, r, imm"], Support::Exact),
+    cap("madd", &["r, r, r, r"], Support::Exact),
+    cap("ldr", &["r, m", "r, rel"], Support::Exact),
+    cap("ldur", &["r, m"], Support::Exact),
+    cap("str", &["r, m"], Support::Exact),
+    cap("stur", &["r, m"], Support::Exact),
+    cap("ret", ANY, Support::Exact),
+    cap("hint", ANY, Support::Exact),
+    cap("udf", ANY, Support::Exact),
+    cap("brk", ANY, Support::Exact),
+    cap("svc", ANY, Support::Approximated),
+    cap("b", BRANCH, Support::Approximated),
+    cap("b.eq", BRANCH, Support::Approximated),
+    cap("b.ne", BRANCH, Support::Approximated),
+    cap("b.hs", BRANCH, Support::Approximated),
+    cap("b.lo", BRANCH, Support::Approximated),
+    cap("b.hi", BRANCH, Support::Approximated),
+    cap("b.ls", BRANCH, Support::Approximated),
+    cap("b.ge", BRANCH, Support::Approximated),
+    cap("b.lt", BRANCH, Support::Approximated),
+    cap("b.gt", BRANCH, Support::Approximated),
+    cap("b.le", BRANCH, Support::Approximated),
+    cap("b.al", BRANCH, Support::Approximated),
+    cap("b.nv", BRANCH, Support::Approximated),
+    cap("cbz", &["r, rel"], Support::Approximated),
+    cap("cbnz", &["r, rel"], Support::Approximated),
+    cap("br", &["r"], Support::Approximated),
+    cap("bl", BRANCH, Support::Approximated),
+    cap("blr", &["r"], Support::Approximated),
+    cap("stp", ANY, Support::Approximated),
+    cap("ldp", ANY, Support::Approximated),
+];
+
+/// RV64IMC, see `Lifter::lift_riscv`
+#[cfg(feature = "riscv")]
+pub const RISCV: &[Capability] = &[
+    cap("lui", ANY, Support::Exact),
+    cap("auipc", ANY, Support::Exact),
+    cap("addi", ANY, Support::Exact),
+    cap("addiw", ANY, Support::Exact),
+    cap("andi", ANY, Support::Exact),
+    cap("add", ANY, Support::Exact),
+    cap("addw", ANY, Support::Exact),
+    cap("sub", ANY, Support::Exact),
+    cap("subw", ANY, Support::Exact),
+    cap("and", ANY, Support::Exact),
+    cap("mul", ANY, Support::Exact),
+    cap("mulw", ANY, Support::Exact),
+    cap("lw", ANY, Support::Exact),
+    cap("lwu", ANY, Support::Exact),
+    cap("ld", ANY, Support::Exact),
+    cap("sw", ANY, Support::Exact),
+    cap("sd", ANY, Support::Exact),
+    cap("fence", ANY, Support::Exact),
+    cap("fencei", ANY, Support::Exact),
+    cap("beq", ANY, Support::Approximated),
+    cap("bne", ANY, Support::Approximated),
+    cap("blt", ANY, Support::Approximated),
+    cap("bge", ANY, Support::Approximated),
+    cap("bltu", ANY, Support::Approximated),
+    cap("bgeu", ANY, Support::Approximated),
+    cap("jal", ANY, Support::Approximated),
+    cap("jalr", ANY, Support::Approximated),
+    cap("ecall", ANY, Support::Approximated),
+    cap("ebreak", ANY, Support::Exact),
+];
+
+pub fn table(arch: Arch) -> &'static [Capability] {
+    match arch {
+        Arch::X86_64 | Arch::X86 => X86,
+        Arch::AArch64 => AARCH64,
+        #[cfg(feature = "riscv")]
+        Arch::RiscV64 => RISCV,
+    }
+}
+
+pub fn lookup(arch: Arch, mnemonic: &str, form: &str) -> Support {
+    table(arch)
+        .iter()
+        .find(|capability| {
+            capability.mnemonic == mnemonic && capability.forms.iter().any(|f| *f == "*" || *f == form)
+        })
+        .map_or(Support::Unsupported, |capability| capability.support)
+}
+
+/// Mnemonic (the opcode on AArch64) and operand form of an instruction
+pub fn signature(machine_instr: &MachineInstr) -> (String, String) {
+    match machine_instr {
+        MachineInstr::X86(instr) => {
+            let form: Vec<&str> = (0..instr.op_count())
+                .map(|operand| match instr.op_kind(operand) {
+                    OpKind::Register if instr.op_register(operand).is_vector_register() => "v",
+                    OpKind::Register if instr.op_register(operand).is_st() => "st",
+                    OpKind::Register => "r",
+                    OpKind::Immediate8
+                    | OpKind::Immediate8_2nd
+                    | OpKind::Immediate16
+                    | OpKind::Immediate32
+                    | OpKind::Immediate64
+                    | OpKind::Immediate8to16
+                    | OpKind::Immediate8to32
+                    | OpKind::Immediate8to64
+                    | OpKind::Immediate32to64 => "imm",
+                    OpKind::Memory if matches!(instr.memory_segment(), Register::FS | Register::GS) => "m[tls]",
+                    OpKind::Memory if instr.memory_index() != Register::None => "m[i]",
+                    OpKind::Memory if instr.memory_size() == MemorySize::Float80 => "m80",
+                    OpKind::Memory => "m",
+                    OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64 => "rel",
+                    _ => "?",
+                })
+                .collect();
+            (machine_instr.mnemonic(), form.join(", "))
+        }
+        MachineInstr::AArch64 { instr, .. } => {
+            let form: Vec<&str> = instr.operands
+                .iter()
+                .filter(|operand| **operand != Operand::Nothing)
+                .map(|operand| match operand {
+                    Operand::Register(..) | Operand::RegisterOrSP(..) => "r",
+                    Operand::RegShift(ShiftStyle::LSL, 0, ..) => "r",
+                    Operand::RegShift(..) => "sr",
+                    Operand::SIMDRegister(..)
+                    | Operand::SIMDRegisterElements(..)
+                    | Operand::SIMDRegisterElementsLane(..) => "v",
+                    Operand::Immediate(_) | Operand::Imm16(_) | Operand::Imm64(_) | Operand::ImmShift(..) => "imm",
+                    Operand::RegPreIndex(..) | Operand::RegPostIndex(..) => "m",
+                    Operand::RegRegOffset(..) => "m[i]",
+                    Operand::PCOffset(_) => "rel",
+                    _ => "?",
+                })
+                .collect();
+            (instr.opcode.to_string(), form.join(", "))
+        }
+        #[cfg(feature = "riscv")]
+        MachineInstr::RiscV { .. } => (machine_instr.mnemonic(), String::new()),
+    }
+}
+
+/// One mnemonic and operand form a function uses
+#[derive(Debug, Clone, Serialize)]
+pub struct Requirement {
+    pub mnemonic: String,
+    pub form: String,
+    pub support: Support,
+    pub count: usize,
+    /// Address of the first occurrence
+    pub addr: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Probe {
+    pub arch: &'static str,
+    pub instructions: usize,
+    /// Every distinct mnemonic and operand form, in order of first use
+    pub required: Vec<Requirement>,
+    /// Features outside the supported subset the function needs, e.g.
+    /// vector registers or indexed addressing
+    pub features: Vec<&'static str>,
+}
+
+impl Probe {
+    pub fn unsupported(&self) -> impl Iterator<Item = &Requirement> {
+        self.required.iter().filter(|requirement| requirement.support == Support::Unsupported)
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.unsupported().next().is_none()
+    }
+}
+
+/// Checks every decoded instruction of `function` against the table
+pub fn probe(function: &FunctionIR) -> Probe {
+    let mut required: Vec<Requirement> = Vec::new();
+    let mut features = Vec::new();
+
+    for info in &function.instructions {
+        let (mnemonic, form) = signature(&info.instr);
+        for (token, feature) in [
+            ("v", "vector registers"),
+            ("m[i]", "indexed addressing"),
+            ("m[tls]", "thread-local storage"),
+            ("m80", "80-bit extended precision"),
+            ("sr", "shifted operands"),
+        ] {
+            if form.split(", ").any(|operand| operand == token) && !features.contains(&feature) {
+                features.push(feature);
+            }
+        }
+
+        match required.iter_mut().find(|r| r.mnemonic == mnemonic && r.form == form) {
+            Some(requirement) => requirement.count += 1,
+            None => required.push(Requirement {
+                support: lookup(function.arch, &mnemonic, &form),
+                mnemonic,
+                form,
+                count: 1,
+                addr: info.addr,
+            }),
+        }
+    }
+
+    Probe {
+        arch: function.arch.name(),
+        instructions: function.instructions.len(),
+        required,
+        features,
+    }
+}
+
+/// How often a mnemonic occurs across a binary
+#[derive(Debug, Clone, Serialize)]
+pub struct MnemonicCount {
+    pub mnemonic: String,
+    pub count: usize,
+    /// Functions using it at least once
+    pub functions: usize,
+    /// Occurrences in operand forms the front-end does not lift
+    pub unsupported: usize,
+}
+
+/// An unsupported mnemonic and operand form, by the functions it blocks
+#[derive(Debug, Clone, Serialize)]
+pub struct Blocker {
+    pub mnemonic: String,
+    pub form: String,
+    pub functions: usize,
+    /// Functions it is the only unsupported form of, which supporting it
+    /// would make translatable
+    pub sole_blocker: usize,
+    /// The first few functions it blocks
+    pub examples: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DecodeFailure {
+    pub function: String,
+    pub error: String,
+}
+
+/// Instruction frequencies over the functions of a binary
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct InstructionProfile {
+    pub functions: usize,
+    /// Functions without unsupported forms
+    pub supported: usize,
+    pub instructions: usize,
+    /// Most frequent first
+    pub mnemonics: Vec<MnemonicCount>,
+    /// Most blocking first
+    pub blockers: Vec<Blocker>,
+    /// Functions that could not be decoded
+    pub failures: Vec<DecodeFailure>,
+}
+
+const EXAMPLES: usize = 5;
+
+/// Probes each decoded function and totals the results
+pub fn profile(functions: impl IntoIterator<Item = (String, Result<FunctionIR, String>)>) -> InstructionProfile {
+    let mut profile = InstructionProfile::default();
+    for (name, function) in functions {
+        let function = match function {
+            Ok(function) => function,
+            Err(error) => {
+                profile.failures.push(DecodeFailure { function: name, error });
+                continue;
+            }
+        };
+        let probe = probe(&function);
+        profile.functions += 1;
+        profile.instructions += probe.instructions;
+
+        let mut mnemonics: Vec<&str> = Vec::new();
+        for requirement in &probe.required {
+            let count = match profile.mnemonics.iter_mut().find(|count| count.mnemonic == requirement.mnemonic) {
+                Some(count) => count,
+                None => {
+                    profile.mnemonics.push(MnemonicCount { mnemonic: requirement.mnemonic.clone(), count: 0, functions: 0, unsupported: 0 });
+                    profile.mnemonics.last_mut().unwrap()
+                }
+            };
+            count.count += requirement.count;
+            if requirement.support == Support::Unsupported {
+                count.unsupported += requirement.count;
+            }
+            if !mnemonics.contains(&requirement.mnemonic.as_str()) {
+                mnemonics.push(&requirement.mnemonic);
+                count.functions += 1;
+            }
+        }
+
+        let unsupported: Vec<&Requirement> = probe.unsupported().collect();
+        if unsupported.is_empty() {
+            profile.supported += 1;
+        }
+        for requirement in &unsupported {
+            let blocker = match profile.blockers.iter_mut().find(|b| b.mnemonic == requirement.mnemonic && b.form == requirement.form) {
+                Some(blocker) => blocker,
+                None => {
+                    profile.blockers.push(Blocker {
+                        mnemonic: requirement.mnemonic.clone(),
+                        form: requirement.form.clone(),
+                        functions: 0,
+                        sole_blocker: 0,
+                        examples: Vec::new(),
+                    });
+                    profile.blockers.last_mut().unwrap()
+                }
+            };
+            blocker.functions += 1;
+            if unsupported.len() == 1 {
+                blocker.sole_blocker += 1;
+            }
+            if blocker.examples.len() < EXAMPLES {
+                blocker.examples.push(name.clone());
+            }
+        }
+    }
+
+    profile.mnemonics.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.mnemonic.cmp(&b.mnemonic)));
+    profile.blockers.sort_by(|a, b| {
+        (b.functions, b.sole_blocker).cmp(&(a.functions, a.sole_blocker)).then_with(|| (&a.mnemonic, &a.form).cmp(&(&b.mnemonic, &b.form)))
+    });
+    profile
+}
+
+impl std::fmt::Display for InstructionProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{} functions, {} instructions, {} translatable", self.functions, self.instructions, self.supported)?;
+        writeln!(f, "\nMnemonics:")?;
+        for count in &self.mnemonics {
+            write!(f, "{:>8}  {:<12} in {} functions", count.count, count.mnemonic, count.functions)?;
+            match count.unsupported {
+                0 => writeln!(f)?,
+                unsupported => writeln!(f, ", {} unsupported", unsupported)?,
+            }
+        }
+        writeln!(f, "\nUnsupported forms by functions blocked:")?;
+        for blocker in &self.blockers {
+            writeln!(
+                f,
+                "{:>8}  {:<24} only blocker of {}, e.g. {}",
+                blocker.functions,
+                format!("{} {}", blocker.mnemonic, blocker.form).trim_end(),
+                blocker.sole_blocker,
+                blocker.examples.join(", ")
+            )?;
+        }
+        if !self.failures.is_empty() {
+            writeln!(f, "\nNot decoded:")?;
+            for failure in &self.failures {
+                writeln!(f, "          {}: {}", failure.function, failure.error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_unsupported_forms_and_features() {
+        // mov eax, [rdi]; add eax, [rdi+rsi*4]; movd xmm0, eax; ret
+        let function = FunctionIR::decode("test", Arch::X86_64, &[
+            0x8b, 0x07,
+            0x03, 0x04, 0xb7,
+            0x66, 0x0f, 0x6e, 0xc0,
+            0xc3,
+        ], 0x1000);
+        let probe = probe(&function);
+
+        let unsupported: Vec<(&str, &str)> = probe.unsupported()
+            .map(|r| (r.mnemonic.as_str(), r.form.as_str()))
+            .collect();
+        assert_eq!(unsupported, vec![("add", "r, m[i]"), ("movd", "v, r")]);
+        assert_eq!(probe.features, vec!["indexed addressing", "vector registers"]);
+        assert_eq!(probe.required[0].support, Support::Exact);
+    }
+
+    #[test]
+    fn profiles_blockers_across_functions() {
+        let decode = |name: &str, code: &[u8]| (name.to_string(), Ok(FunctionIR::decode(name, Arch::X86_64, code, 0x1000)));
+        let profile = profile([
+            // mov eax, [rdi]; ret
+            decode("load", &[0x8b, 0x07, 0xc3]),
+            // movd xmm0, eax; ret
+            decode("to_vector", &[0x66, 0x0f, 0x6e, 0xc0, 0xc3]),
+            // movd xmm0, eax; add eax, [rdi+rsi*4]; ret
+            decode("both", &[0x66, 0x0f, 0x6e, 0xc0, 0x03, 0x04, 0xb7, 0xc3]),
+            ("missing".to_string(), Err("Function not found".to_string())),
+        ]);
+
+        assert_eq!((profile.functions, profile.supported, profile.instructions), (3, 1, 7));
+        assert_eq!(profile.mnemonics[0].mnemonic, "ret");
+        assert_eq!(profile.mnemonics[0].count, 3);
+        let blockers: Vec<(&str, usize, usize)> = profile.blockers
+            .iter()
+            .map(|blocker| (blocker.mnemonic.as_str(), blocker.functions, blocker.sole_blocker))
+            .collect();
+        assert_eq!(blockers, vec![("movd", 2, 1), ("add", 1, 0)]);
+        assert_eq!(profile.blockers[0].examples, vec!["to_vector", "both"]);
+        assert_eq!(profile.failures[0].function, "missing");
+    }
+}
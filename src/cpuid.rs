@@ -0,0 +1,114 @@
+// CPUID results
+// Feature detection code runs cpuid and branches on what it returns. The
+// translation answers with constants: the `[[transpiler.cpuid]]` tables of the
+// config, over a built-in table that only advertises what the front-end lifts
+// (scalar SSE2 doubles and rdtsc). Leaves in neither read as zeros.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CpuidLeaf {
+    /// eax input
+    pub leaf: u32,
+    /// ecx input of leaves with subleaves, any if absent
+    #[serde(default)]
+    pub subleaf: Option<u32>,
+    #[serde(default)]
+    pub eax: u32,
+    #[serde(default)]
+    pub ebx: u32,
+    #[serde(default)]
+    pub ecx: u32,
+    #[serde(default)]
+    pub edx: u32,
+}
+
+impl CpuidLeaf {
+    /// Output register by index: eax, ebx, ecx, edx
+    pub fn output(&self, register: u8) -> u32 {
+        [self.eax, self.ebx, self.ecx, self.edx][register as usize]
+    }
+
+    fn matches(&self, leaf: u32, subleaf: Option<u32>) -> bool {
+        self.leaf == leaf && (self.subleaf.is_none() || self.subleaf == subleaf)
+    }
+}
+
+const fn builtin(leaf: u32, eax: u32, ebx: u32, ecx: u32, edx: u32) -> CpuidLeaf {
+    CpuidLeaf { leaf, subleaf: None, eax, ebx, ecx, edx }
+}
+
+const BUILTIN: &[CpuidLeaf] = &[
+    // Highest basic leaf 1, vendor "GenuineIntel" in ebx, edx, ecx
+    builtin(0, 1, 0x756e_6547, 0x6c65_746e, 0x4965_6e69),
+    // edx: TSC (bit 4), SSE (25), SSE2 (26)
+    builtin(1, 0, 0, 0, 1 << 4 | 1 << 25 | 1 << 26),
+];
+
+/// Built-in leaves the config does not replace, followed by the configured
+/// ones. Later entries take precedence.
+pub fn entries(configured: &[CpuidLeaf]) -> Vec<CpuidLeaf> {
+    BUILTIN
+        .iter()
+        .filter(|builtin| !configured.iter().any(|leaf| leaf.leaf == builtin.leaf))
+        .chain(configured)
+        .copied()
+        .collect()
+}
+
+/// Output registers for `leaf`, None if they depend on a subleaf that is
+/// only known at runtime
+pub fn resolve(configured: &[CpuidLeaf], leaf: u32, subleaf: Option<u32>) -> Option<[u32; 4]> {
+    let entries = entries(configured);
+    if subleaf.is_none() && entries.iter().any(|entry| entry.leaf == leaf && entry.subleaf.is_some()) {
+        return None;
+    }
+    let result = match entries.iter().rev().find(|entry| entry.matches(leaf, subleaf)) {
+        Some(entry) => [entry.eax, entry.ebx, entry.ecx, entry.edx],
+        None => [0; 4],
+    };
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::Arch;
+    use crate::backend::{FunctionIR, TranspilerBackend};
+    use crate::transpiler_real::{DirectBackend, TranspileOptions};
+
+    fn wat(code: &[u8], options: TranspileOptions) -> String {
+        let wasm = DirectBackend::new(options).transpile(&FunctionIR::decode("test", Arch::X86_64, code, 0x1000)).unwrap();
+        wasmparser::Validator::new().validate_all(&wasm).unwrap();
+        wasmprinter::print_bytes(&wasm).unwrap()
+    }
+
+    #[test]
+    fn lowers_rdtsc_and_cpuid() {
+        // rdtsc; ret
+        let wat_text = wat(&[0x0f, 0x31, 0xc3], TranspileOptions { tsc_mhz: Some(3000), ..Default::default() });
+        assert!(wat_text.contains(r#"(import "env" "now_ns" (func (;0;) (type 1)))"#), "{}", wat_text);
+        assert!(wat_text.contains("i64.const 3000"), "{}", wat_text);
+        // eax gets the low half, edx is never read
+        assert!(wat_text.contains("i64.and") && !wat_text.contains("i64.shr_u"), "{}", wat_text);
+
+        // mov eax, 0; cpuid; mov eax, ebx; ret: the first vendor word
+        let code = [0xb8, 0x00, 0x00, 0x00, 0x00, 0x0f, 0xa2, 0x89, 0xd8, 0xc3];
+        let wat_text = wat(&code, TranspileOptions::default());
+        assert!(wat_text.contains(&format!("i64.const {}", u32::from_le_bytes(*b"Genu"))), "{}", wat_text);
+        assert!(!wat_text.contains("select"), "{}", wat_text);
+
+        // mov eax, 7; cpuid; mov eax, ebx; ret, with a configured leaf whose
+        // subleaf (ecx) is only known at runtime
+        let code = [0xb8, 0x07, 0x00, 0x00, 0x00, 0x0f, 0xa2, 0x89, 0xd8, 0xc3];
+        let leaf = CpuidLeaf { leaf: 7, subleaf: Some(0), eax: 0, ebx: 1 << 5, ecx: 0, edx: 0 };
+        let wat_text = wat(&code, TranspileOptions { cpuid: vec![leaf], ..Default::default() });
+        assert!(wat_text.contains("select") && wat_text.contains("i64.const 32"), "{}", wat_text);
+
+        assert_eq!(resolve(&[leaf], 7, Some(0)), Some([0, 1 << 5, 0, 0]));
+        assert_eq!(resolve(&[leaf], 7, Some(1)), Some([0; 4]));
+        assert_eq!(resolve(&[leaf], 7, None), None);
+        assert_eq!(resolve(&[], 1, None).map(|result| result[3] & 1 << 26), Some(1 << 26));
+    }
+}
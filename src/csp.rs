@@ -0,0 +1,107 @@
+// Content-Security-Policy
+// Every HTML page is sent with a policy that runs no script the server did
+// not emit, without 'unsafe-inline'. Each response gets a fresh nonce,
+// which the page's inline `<script>` and `<style>` elements carry, including
+// those inside the rendered DOM. Inline event handlers such as `onclick`
+// cannot carry a nonce, so the policy lists the hash of each one the page
+// uses. 'wasm-unsafe-eval' lets the glue compile the callback modules.
+
+use crate::dom::{Dom, DomNode};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+
+pub struct Policy {
+    nonce: String,
+    // Base64 SHA-256 of the inline event handlers
+    handlers: BTreeSet<String>,
+}
+
+impl Policy {
+    pub fn new() -> Self {
+        Self {
+            nonce: base64::engine::general_purpose::STANDARD.encode(rand::random::<[u8; 16]>()),
+            handlers: BTreeSet::new(),
+        }
+    }
+
+    /// For the `nonce` attribute of the page's own `<script>` and `<style>`
+    pub fn nonce(&self) -> &str {
+        &self.nonce
+    }
+
+    /// Adds the nonce to the inline scripts and styles of `dom` and allows
+    /// its event handlers
+    pub fn apply(&mut self, dom: &mut Dom) {
+        for node in &mut dom.nodes {
+            self.apply_node(node);
+        }
+    }
+
+    fn apply_node(&mut self, node: &mut DomNode) {
+        let DomNode::Element { tag, attrs, children } = node else { return };
+        for (name, value) in attrs.iter() {
+            if name.starts_with("on") {
+                self.handlers.insert(base64::engine::general_purpose::STANDARD.encode(Sha256::digest(value.as_bytes())));
+            }
+        }
+        // Scripts with a src need none, 'self' covers them
+        let inline = match tag.as_str() {
+            "script" => !attrs.iter().any(|(name, _)| name == "src"),
+            "style" => true,
+            _ => false,
+        };
+        if inline {
+            attrs.retain(|(name, _)| name != "nonce");
+            attrs.push(("nonce".to_string(), self.nonce.clone()));
+        }
+        for child in children {
+            self.apply_node(child);
+        }
+    }
+
+    pub fn header_value(&self, report_uri: Option<&str>) -> String {
+        let mut script_src = format!("'self' 'nonce-{}' 'wasm-unsafe-eval'", self.nonce);
+        if !self.handlers.is_empty() {
+            script_src.push_str(" 'unsafe-hashes'");
+            for hash in &self.handlers {
+                script_src.push_str(&format!(" 'sha256-{}'", hash));
+            }
+        }
+        let mut header = format!(
+            "default-src 'self'; script-src {}; style-src 'self' 'nonce-{}'; object-src 'none'; base-uri 'none'; frame-ancestors 'self'",
+            script_src, self.nonce
+        );
+        if let Some(uri) = report_uri {
+            header.push_str(&format!("; report-uri {}", uri));
+        }
+        header
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_only_emitted_scripts() {
+        let mut dom = Dom {
+            nodes: vec![
+                DomNode::element("button", vec![("onclick", "alert(1)")], vec![DomNode::text("Go")]),
+                DomNode::element("style", vec![], vec![DomNode::text("p {}")]),
+                DomNode::element("script", vec![("src", "/app.js")], vec![]),
+            ],
+        };
+        let mut policy = Policy::new();
+        policy.apply(&mut dom);
+
+        assert_eq!(dom.nodes[1].attr("nonce"), Some(policy.nonce()));
+        assert_eq!(dom.nodes[2].attr("nonce"), None);
+        let header = policy.header_value(None);
+        // echo -n 'alert(1)' | openssl dgst -sha256 -binary | base64
+        assert!(header.contains("'unsafe-hashes' 'sha256-bhHHL3z2vDgxUt0W3dWQOrprscmda2Y5pLsLg4GF+pI='"), "{}", header);
+        assert!(header.contains(&format!("script-src 'self' 'nonce-{}'", policy.nonce())));
+        assert!(!header.contains("unsafe-inline"));
+        assert_ne!(Policy::new().nonce(), policy.nonce());
+    }
+}
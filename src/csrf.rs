@@ -0,0 +1,233 @@
+// CSRF protection for state-mutating endpoints
+// Each browser session gets a random id in a SameSite=Strict cookie. The CSRF
+// token is an HMAC of that id, embedded into the rendered page and sent back
+// by the JS glue in the `X-CSRF-Token` header. Forms posted without
+// JavaScript carry it in a hidden `_csrf` field instead, see `embed_token`.
+// The HMAC key is `SELF_SERVE_CSRF_SECRET`, or random per process.
+
+use crate::cluster::PubSub;
+use crate::config::ClusterConfig;
+use crate::dom::{Dom, DomNode};
+use crate::error::ApiError;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+
+pub const SESSION_COOKIE: &str = "self_serve_session";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// The form field holding the token
+pub const FORM_FIELD: &str = "_csrf";
+
+pub struct Csrf {
+    secret: Vec<u8>,
+}
+
+pub struct Session {
+    pub id: String,
+    /// The session was created by this request, so the cookie still has to be set
+    pub is_new: bool,
+}
+
+#[derive(Debug)]
+pub enum CsrfError {
+    MissingSession,
+    MissingToken,
+    InvalidToken,
+}
+
+impl Csrf {
+    /// Tokens keyed with a random secret, so they are only valid on this
+    /// instance
+    pub fn new() -> Self {
+        Self::with_secret(rand::random::<[u8; 32]>().to_vec())
+    }
+
+    pub fn with_secret(secret: Vec<u8>) -> Self {
+        Self { secret }
+    }
+
+    /// Keys tokens with `SELF_SERVE_CSRF_SECRET`, which the instances of a
+    /// cluster share so a token is valid on all of them. Without it the
+    /// secret is random, with a warning if `cluster` relays to others.
+    pub fn load(cluster: &ClusterConfig) -> Self {
+        match std::env::var("SELF_SERVE_CSRF_SECRET") {
+            Ok(secret) if !secret.is_empty() => Self::with_secret(secret.into_bytes()),
+            _ => {
+                if cluster.pubsub != PubSub::None {
+                    tracing::warn!("SELF_SERVE_CSRF_SECRET is not set, so other instances reject this one's CSRF tokens");
+                }
+                Self::new()
+            }
+        }
+    }
+
+    /// Returns the session of the request, or starts a new one.
+    pub fn session(&self, req: &HttpRequest) -> Session {
+        match req.cookie(SESSION_COOKIE) {
+            Some(cookie) if !cookie.value().is_empty() => Session {
+                id: cookie.value().to_string(),
+                is_new: false,
+            },
+            _ => Session {
+                id: hex::encode(rand::random::<[u8; 16]>()),
+                is_new: true,
+            },
+        }
+    }
+
+    pub fn session_cookie(&self, session: &Session) -> Cookie<'static> {
+        Cookie::build(SESSION_COOKIE, session.id.clone())
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .finish()
+    }
+
+    pub fn token_for(&self, session_id: &str) -> String {
+        hex::encode(self.mac(session_id).finalize().into_bytes())
+    }
+
+    /// Checks the `X-CSRF-Token` header against the session cookie.
+    /// Requests carrying a bearer token are exempt: browsers never attach
+    /// those automatically, so they cannot be forged cross-site.
+    pub fn verify(&self, req: &HttpRequest) -> Result<(), CsrfError> {
+        let token = req.headers().get(CSRF_HEADER).and_then(|value| value.to_str().ok());
+        self.verify_token(req, token)
+    }
+
+    /// Checks the `_csrf` field of a submitted form, like `verify`
+    pub fn verify_form(&self, req: &HttpRequest, token: Option<&str>) -> Result<(), CsrfError> {
+        self.verify_token(req, token)
+    }
+
+    fn verify_token(&self, req: &HttpRequest, token: Option<&str>) -> Result<(), CsrfError> {
+        let uses_bearer = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("Bearer "));
+        if uses_bearer {
+            return Ok(());
+        }
+
+        let session = req.cookie(SESSION_COOKIE).ok_or(CsrfError::MissingSession)?;
+        let token = token.ok_or(CsrfError::MissingToken)?;
+
+        let token = hex::decode(token).map_err(|_| CsrfError::InvalidToken)?;
+        self.mac(session.value())
+            .verify_slice(&token)
+            .map_err(|_| CsrfError::InvalidToken)
+    }
+
+    fn mac(&self, session_id: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(session_id.as_bytes());
+        mac
+    }
+}
+
+impl fmt::Display for CsrfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CsrfError::MissingSession => write!(f, "CSRF check failed: no session cookie"),
+            CsrfError::MissingToken => write!(f, "CSRF check failed: missing {} header or {} field", CSRF_HEADER, FORM_FIELD),
+            CsrfError::InvalidToken => write!(f, "CSRF check failed: invalid token"),
+        }
+    }
+}
+
+impl ResponseError for CsrfError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::FORBIDDEN
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        ApiError::new(self.status_code(), "csrf_failed", self.to_string()).error_response()
+    }
+}
+
+/// Adds `token` as a hidden `_csrf` field to every form of `dom` that posts
+pub fn embed_token(dom: &mut Dom, token: &str) {
+    fn embed(node: &mut DomNode, token: &str) {
+        let posts = node.tag() == Some("form") && node.attr("method").is_some_and(|method| method.eq_ignore_ascii_case("post"));
+        let DomNode::Element { children, .. } = node else { return };
+        for child in children.iter_mut() {
+            embed(child, token);
+        }
+        if posts {
+            children.insert(0, DomNode::element("input", vec![("type", "hidden"), ("name", FORM_FIELD), ("value", token)], vec![]));
+        }
+    }
+    for node in &mut dom.nodes {
+        embed(node, token);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_token_roundtrip() {
+        let csrf = Csrf::new();
+        let token = csrf.token_for("session-a");
+
+        let valid = TestRequest::post()
+            .cookie(Cookie::new(SESSION_COOKIE, "session-a"))
+            .insert_header((CSRF_HEADER, token.as_str()))
+            .to_http_request();
+        assert!(csrf.verify(&valid).is_ok());
+
+        // Token of another session must not be accepted
+        let stolen = TestRequest::post()
+            .cookie(Cookie::new(SESSION_COOKIE, "session-b"))
+            .insert_header((CSRF_HEADER, token.as_str()))
+            .to_http_request();
+        assert!(matches!(csrf.verify(&stolen), Err(CsrfError::InvalidToken)));
+
+        let missing = TestRequest::post()
+            .cookie(Cookie::new(SESSION_COOKIE, "session-a"))
+            .to_http_request();
+        assert!(matches!(csrf.verify(&missing), Err(CsrfError::MissingToken)));
+    }
+
+    #[test]
+    fn instances_sharing_a_secret_accept_each_others_tokens() {
+        let issuer = Csrf::with_secret(b"shared".to_vec());
+        let req = TestRequest::post()
+            .cookie(Cookie::new(SESSION_COOKIE, "session-a"))
+            .insert_header((CSRF_HEADER, issuer.token_for("session-a")))
+            .to_http_request();
+        assert!(Csrf::with_secret(b"shared".to_vec()).verify(&req).is_ok());
+        assert!(matches!(Csrf::new().verify(&req), Err(CsrfError::InvalidToken)));
+    }
+
+    #[test]
+    fn embeds_tokens_into_posting_forms() {
+        let csrf = Csrf::new();
+        let token = csrf.token_for("session-a");
+        let mut dom = Dom {
+            nodes: vec![DomNode::element("div", vec![], vec![
+                DomNode::element("form", vec![("method", "post")], vec![DomNode::element("button", vec![], vec![])]),
+                DomNode::element("form", vec![("method", "get")], vec![]),
+            ])],
+        };
+        embed_token(&mut dom, &token);
+        assert_eq!(
+            dom.to_html(),
+            format!(r#"<div><form method="post"><input type="hidden" name="_csrf" value="{}"><button></button></form><form method="get"></form></div>"#, token)
+        );
+
+        let req = TestRequest::post().cookie(Cookie::new(SESSION_COOKIE, "session-a")).to_http_request();
+        assert!(csrf.verify_form(&req, Some(&token)).is_ok());
+        assert!(matches!(csrf.verify_form(&req, None), Err(CsrfError::MissingToken)));
+        assert!(matches!(csrf.verify_form(&req, Some("00")), Err(CsrfError::InvalidToken)));
+    }
+}
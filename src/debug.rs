@@ -0,0 +1,288 @@
+// Remote debugging
+// With `[debug] enabled = true` the page carries a debug-channel meta tag
+// and the glue opens a WebSocket to /debug/ws. For every callback it runs
+// in the browser it sends a report: the arguments, the result or error, the
+// state image before and after and the ranges of linear memory the call
+// changed. The server runs the native callback on the same state and marks
+// the report diverged if the result or the state differs, then forwards it
+// to the admin dashboards connected with `?watch`, which show the reports
+// live. A dashboard first gets the newest `[debug] history` reports.
+
+use crate::auth::Permission;
+use crate::config::DebugConfig;
+use crate::error::ApiError;
+use crate::ServerContext;
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{self, Frame, Message};
+use actix_web::body::{BodyStream, BoxBody};
+use actix_web::http::header;
+use actix_web::web::BytesMut;
+use actix_web::{web, HttpRequest, HttpResponse, Responder, ResponseError};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+/// Fills the dashboard's debug channel list with the reports as they come
+pub const SCRIPT: &str = r#"
+        document.addEventListener('DOMContentLoaded', () => {
+            const list = document.getElementById('debug-channel');
+            if (!list) {
+                return;
+            }
+            const scheme = location.protocol === 'https:' ? 'wss' : 'ws';
+            const socket = new WebSocket(`${scheme}://${location.host}${list.dataset.src}`);
+            socket.addEventListener('message', (message) => {
+                const entry = JSON.parse(message.data);
+                const item = document.createElement('li');
+                item.className = entry.diverged ? 'failed' : 'ok';
+                const outcome = entry.error !== undefined && entry.error !== null ? `error: ${entry.error}` : JSON.stringify(entry.result);
+                item.textContent = `#${entry.seq} ${entry.callback}(${entry.args.map((arg) => JSON.stringify(arg)).join(', ')}) = ${outcome}`;
+                const detail = document.createElement('div');
+                detail.className = 'detail';
+                const native = entry.native
+                    ? `native: ${entry.native.error || JSON.stringify(entry.native.result)}, ${entry.native.state_differences.length} state bytes differ`
+                    : 'native: not comparable';
+                const memory = entry.memory.map((change) => `${change.offset}: ${change.before} → ${change.after}`).join(', ');
+                detail.textContent = `${native}; memory: ${memory || 'unchanged'}`;
+                item.appendChild(detail);
+                list.prepend(item);
+            });
+        });
+"#;
+
+/// A callback run in the browser, as the glue reports it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Report {
+    pub callback: String,
+    #[serde(default)]
+    pub args: Vec<Value>,
+    #[serde(default)]
+    pub result: Option<Value>,
+    #[serde(default)]
+    pub error: Option<String>,
+    /// The state image at address 0 of linear memory, before and after
+    pub before: Vec<u8>,
+    pub after: Vec<u8>,
+    /// Changed ranges of the whole linear memory
+    #[serde(default)]
+    pub memory: Vec<MemoryChange>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryChange {
+    pub offset: u64,
+    /// Hex bytes
+    pub before: String,
+    pub after: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Native {
+    pub result: Option<Value>,
+    pub error: Option<String>,
+    /// State bytes that differ from the browser's, as `(offset, native)`
+    pub state_differences: Vec<(usize, u8)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub report: Report,
+    /// The native run on the same state, none if the state image does not
+    /// fit the server's state or the callback is unknown
+    pub native: Option<Native>,
+    pub diverged: bool,
+}
+
+struct Channel {
+    next_seq: u64,
+    history: VecDeque<String>,
+    watchers: Vec<mpsc::UnboundedSender<Message>>,
+}
+
+pub struct DebugChannel {
+    config: DebugConfig,
+    channel: Mutex<Channel>,
+}
+
+impl DebugChannel {
+    pub fn new(config: &DebugConfig) -> Self {
+        let channel = Channel { next_seq: 1, history: VecDeque::new(), watchers: Vec::new() };
+        Self { config: config.clone(), channel: Mutex::new(channel) }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Compares `report` with the native callback and forwards it
+    fn publish(&self, ctx: &ServerContext, report: Report) {
+        let native = native_run(ctx, &report);
+        let diverged = native.as_ref().is_some_and(|native| {
+            !same_value(native.result.as_ref(), report.result.as_ref())
+                || native.error.is_some() != report.error.is_some()
+                || !native.state_differences.is_empty()
+        });
+        let mut channel = self.channel.lock().unwrap();
+        let entry = Entry { seq: channel.next_seq, report, native, diverged };
+        channel.next_seq += 1;
+        if entry.diverged {
+            tracing::warn!(callback = %entry.report.callback, seq = entry.seq, "Browser execution diverged from the native callback");
+        }
+        let json = serde_json::to_string(&entry).expect("debug entries serialize to JSON");
+        channel.history.push_back(json.clone());
+        while channel.history.len() > self.config.history {
+            channel.history.pop_front();
+        }
+        channel.watchers.retain(|watcher| watcher.send(Message::Text(json.clone().into())).is_ok());
+    }
+
+    fn watch(&self, watcher: mpsc::UnboundedSender<Message>) {
+        let mut channel = self.channel.lock().unwrap();
+        for json in &channel.history {
+            let _ = watcher.send(Message::Text(json.clone().into()));
+        }
+        channel.watchers.push(watcher);
+    }
+}
+
+/// Numbers compare by value, as the browser reports 4.0 as 4
+fn same_value(native: Option<&Value>, browser: Option<&Value>) -> bool {
+    match (native, browser) {
+        (Some(Value::Number(a)), Some(Value::Number(b))) => a.as_f64() == b.as_f64(),
+        (Some(Value::Object(a)), Some(Value::Object(b))) => {
+            a.len() == b.len() && a.iter().all(|(key, value)| same_value(Some(value), b.get(key)))
+        }
+        (Some(Value::Array(a)), Some(Value::Array(b))) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same_value(Some(a), Some(b)))
+        }
+        (a, b) => a == b,
+    }
+}
+
+/// Runs the reported callback natively on the state the browser started from
+fn native_run(ctx: &ServerContext, report: &Report) -> Option<Native> {
+    let callback = ctx.callbacks.get(&report.callback)?;
+    let template = ctx.state.read().clone();
+    let mut state = crate::store::from_image(&template, &report.before).ok()?;
+    let (result, error) = match callback.call(&mut state, &report.args) {
+        Ok(result) => (Some(serde_json::to_value(result).expect("results serialize to JSON")), None),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    let state_differences = state
+        .as_bytes()
+        .iter()
+        .zip(&report.after)
+        .enumerate()
+        .filter(|(_, (native, browser))| native != browser)
+        .map(|(offset, (native, _))| (offset, *native))
+        .collect();
+    Some(Native { result, error, state_differences })
+}
+
+#[derive(Deserialize)]
+pub struct SocketQuery {
+    /// Receive the reports instead of sending them, for the dashboard
+    #[serde(default)]
+    watch: Option<String>,
+}
+
+/// GET /debug/ws, the WebSocket reports are sent and watched on
+pub async fn socket(
+    req: HttpRequest,
+    query: web::Query<SocketQuery>,
+    mut payload: web::Payload,
+    ctx: web::Data<ServerContext>,
+) -> impl Responder {
+    if !ctx.debug.enabled() {
+        return ApiError::not_found("The debug channel is disabled").error_response();
+    }
+    // Browsers attach cookies to WebSockets of any origin
+    let origin = req.headers().get(header::ORIGIN).and_then(|origin| origin.to_str().ok());
+    let host = req.connection_info().host().to_string();
+    if origin.is_some_and(|origin| origin.split_once("://").map(|(_, origin)| origin) != Some(host.as_str())) {
+        return ApiError::new(actix_web::http::StatusCode::FORBIDDEN, "cross_origin", "The debug channel only accepts the server's own pages")
+            .error_response();
+    }
+    let watch = query.watch.is_some();
+    if watch {
+        if let Err(e) = ctx.auth.authorize(&req, Permission::Admin) {
+            return e.into_response(&ctx.auth);
+        }
+    }
+    let mut response = match ws::handshake(req.head()) {
+        Ok(response) => response,
+        Err(e) => return e.error_response(),
+    };
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+    if watch {
+        ctx.debug.watch(tx.clone());
+    }
+    // Frames from the browser: reports, pings and the close
+    let reader = ctx.clone();
+    actix_rt::spawn(async move {
+        let mut codec = ws::Codec::new();
+        let mut buffer = BytesMut::new();
+        while let Some(chunk) = payload.next().await {
+            let Ok(chunk) = chunk else { break };
+            buffer.extend_from_slice(&chunk);
+            loop {
+                match codec.decode(&mut buffer) {
+                    Ok(Some(Frame::Text(text))) if !watch => match serde_json::from_slice::<Report>(&text) {
+                        Ok(report) => reader.debug.publish(&reader, report),
+                        Err(e) => tracing::warn!(error = %e, "Ignored a malformed debug report"),
+                    },
+                    Ok(Some(Frame::Ping(bytes))) => {
+                        let _ = tx.send(Message::Pong(bytes));
+                    }
+                    Ok(Some(Frame::Close(reason))) => {
+                        let _ = tx.send(Message::Close(reason));
+                        return;
+                    }
+                    Ok(Some(_)) => {}
+                    Ok(None) => break,
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Closed the debug channel on an invalid frame");
+                        return;
+                    }
+                }
+            }
+        }
+    });
+
+    // Frames to the browser, until the reader and the dashboard let go
+    let mut codec = ws::Codec::new();
+    let frames = futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx)).map(move |message| {
+        let mut bytes = BytesMut::new();
+        codec.encode(message, &mut bytes).map(|()| bytes.freeze()).map_err(actix_web::error::ErrorInternalServerError)
+    });
+    match response.message_body(BoxBody::new(BodyStream::new(frames))) {
+        Ok(response) => HttpResponse::from(response),
+        Err(e) => ApiError::internal(e.to_string()).error_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn compares_results_by_value() {
+        assert!(same_value(Some(&json!(25.0)), Some(&json!(25))));
+        assert!(!same_value(Some(&json!(25.0)), Some(&json!(26))));
+        let native = json!({ "value": -3, "magnitude": 3, "is_negative": true });
+        assert!(same_value(Some(&native), Some(&json!({ "is_negative": true, "magnitude": 3.0, "value": -3 }))));
+        assert!(!same_value(Some(&native), Some(&json!({ "value": -3, "magnitude": 3 }))));
+        assert!(!same_value(Some(&json!("even")), None));
+
+        let report: Report = serde_json::from_value(json!({ "callback": "increment_counter", "before": [5, 0, 0, 0], "after": [6, 0, 0, 0] })).unwrap();
+        assert!(report.memory.is_empty() && report.result.is_none());
+    }
+}
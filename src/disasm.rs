@@ -0,0 +1,134 @@
+// Disassembly viewer
+// Shows the machine instructions of a callback side by side with the WASM
+// instructions each of them was lowered to. Serves HTML by default and JSON
+// for `?format=json` or `Accept: application/json`.
+
+use crate::dom::{Dom, DomNode};
+use crate::error::ApiError;
+use crate::transpiler_real::LoweredFunction;
+use crate::ServerContext;
+use actix_web::{web, HttpRequest, HttpResponse, Responder, ResponseError};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct DisasmQuery {
+    format: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct DisasmLine {
+    pub addr: u64,
+    pub bytes: String,
+    pub asm: String,
+    pub wasm: Vec<String>,
+}
+
+pub async fn disasm(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<DisasmQuery>,
+    ctx: web::Data<ServerContext>,
+) -> impl Responder {
+    let fn_name = path.into_inner();
+    if ctx.callbacks.get(&fn_name).is_none() {
+        return ApiError::not_found("Function not found").error_response();
+    }
+
+    let lowered = match ctx.transpiler.lower(&fn_name) {
+        Ok(lowered) => lowered,
+        Err(e) => return ApiError::internal(e).error_response(),
+    };
+    let lines = disasm_lines(&lowered);
+
+    let wants_json = match query.format.as_deref() {
+        Some(format) => format == "json",
+        None => crate::api::accepts_json(&req),
+    };
+
+    if wants_json {
+        return HttpResponse::Ok().json(lines);
+    }
+
+    let mut dom = render_disasm(&fn_name, &lowered, &lines);
+    let mut policy = crate::csp::Policy::new();
+    policy.apply(&mut dom);
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+    <meta charset="utf-8">
+    <title>{} - Disassembly</title>
+    <style nonce="{}">
+        body {{ font-family: Arial, sans-serif; max-width: 1100px; margin: 30px auto; }}
+        table {{ border-collapse: collapse; width: 100%; font-family: monospace; font-size: 13px; }}
+        td {{ border-bottom: 1px solid #eee; padding: 4px 8px; vertical-align: top; }}
+        .addr, .bytes {{ color: #888; }}
+        .dropped {{ color: #b22222; }}
+    </style>
+</head>
+<body>
+{}
+</body>
+</html>"#,
+        crate::dom::escape_html(&fn_name),
+        policy.nonce(),
+        dom.to_html()
+    );
+
+    let mut response = HttpResponse::Ok();
+    response.content_type("text/html; charset=utf-8");
+    if let Some(csp) = ctx.csp.header(&policy) {
+        response.insert_header(csp);
+    }
+    response.body(html)
+}
+
+pub fn disasm_lines(lowered: &LoweredFunction) -> Vec<DisasmLine> {
+    lowered
+        .lowerings
+        .iter()
+        .map(|lowering| DisasmLine {
+            addr: lowering.instr.addr(),
+            bytes: lowered.bytes(lowering).iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" "),
+            asm: lowering.instr.text(),
+            wasm: lowered.wasm(lowering).iter().map(|instr| lowered.wat_text(instr)).collect(),
+        })
+        .collect()
+}
+
+fn render_disasm(fn_name: &str, lowered: &LoweredFunction, lines: &[DisasmLine]) -> Dom {
+    let rows = lines
+        .iter()
+        .map(|line| {
+            let wasm = if line.wasm.is_empty() {
+                DomNode::element("td", vec![("class", "dropped")], vec![DomNode::text("(no wasm)")])
+            } else {
+                DomNode::element("td", vec![], line.wasm
+                    .iter()
+                    .map(|op| DomNode::element("div", vec![], vec![DomNode::text(op)]))
+                    .collect())
+            };
+
+            DomNode::element("tr", vec![], vec![
+                DomNode::element("td", vec![("class", "addr")], vec![
+                    DomNode::text(&format!("{:#x}", line.addr)),
+                ]),
+                DomNode::element("td", vec![("class", "bytes")], vec![DomNode::text(&line.bytes)]),
+                DomNode::element("td", vec![], vec![DomNode::text(&line.asm)]),
+                wasm,
+            ])
+        })
+        .collect();
+
+    Dom {
+        nodes: vec![
+            DomNode::element("h1", vec![], vec![DomNode::text(&format!(
+                "{} @ {:#x} ({})",
+                fn_name,
+                lowered.entry,
+                lowered.arch.name(),
+            ))]),
+            DomNode::element("table", vec![], rows),
+        ],
+    }
+}
@@ -1,7 +1,14 @@
+use crate::a11y::{Aria, Role};
+use serde::{Deserialize, Serialize};
+
+/// Serializes to JSON for clients that render it themselves, see `/api/dom`
+#[derive(Serialize, Deserialize)]
 pub struct Dom {
     pub nodes: Vec<DomNode>,
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum DomNode {
     Element {
         tag: String,
@@ -9,8 +16,16 @@ pub enum DomNode {
         children: Vec<DomNode>,
     },
     Text(String),
+    /// Text from the translation catalog of the page's locale, see `i18n.rs`
+    Tr {
+        key: String,
+        args: Vec<(String, String)>,
+    },
 }
 
+/// Resolves `DomNode::Tr` nodes when rendering
+pub type Translate<'a> = &'a dyn Fn(&str, &[(String, String)]) -> String;
+
 impl DomNode {
     pub fn element(tag: &str, attrs: Vec<(&str, &str)>, children: Vec<DomNode>) -> Self {
         DomNode::Element {
@@ -23,13 +38,54 @@ impl DomNode {
     pub fn text(content: &str) -> Self {
         DomNode::Text(content.to_string())
     }
+
+    pub fn tr(key: &str, args: Vec<(&str, &str)>) -> Self {
+        DomNode::Tr {
+            key: key.to_string(),
+            args: args.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        }
+    }
     
-    fn to_html(&self) -> String {
+    pub fn tag(&self) -> Option<&str> {
+        match self {
+            DomNode::Element { tag, .. } => Some(tag),
+            _ => None,
+        }
+    }
+
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        match self {
+            DomNode::Element { attrs, .. } => attrs.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Sets an attribute of an element, replacing its previous value
+    pub fn with_attr(mut self, name: &str, value: &str) -> Self {
+        if let DomNode::Element { attrs, .. } = &mut self {
+            match attrs.iter_mut().find(|(key, _)| key == name) {
+                Some((_, previous)) => *previous = value.to_string(),
+                None => attrs.push((name.to_string(), value.to_string())),
+            }
+        }
+        self
+    }
+
+    pub fn with_role(self, role: Role) -> Self {
+        self.with_attr("role", role.as_str())
+    }
+
+    pub fn with_aria(self, aria: Aria) -> Self {
+        let (name, value) = aria.attr();
+        self.with_attr(name, &value)
+    }
+    
+    fn to_html(&self, translate: Translate) -> String {
         match self {
             DomNode::Element { tag, attrs, children } => {
                 let attrs_str = attrs
                     .iter()
-                    .map(|(k, v)| format!(r#"{}="{}""#, k, v))
+                    .map(|(k, v)| format!(r#"{}="{}""#, k, escape_html(v)))
                     .collect::<Vec<_>>()
                     .join(" ");
                 
@@ -39,23 +95,341 @@ impl DomNode {
                     format!(" {}", attrs_str)
                 };
                 
+                // Void elements have no end tag
+                if is_void(tag) {
+                    return format!("<{}{}>", tag, attrs_part);
+                }
+
                 let children_html = children
                     .iter()
-                    .map(|child| child.to_html())
+                    .map(|child| child.to_html(translate))
                     .collect::<String>();
                 
                 format!("<{}{}>{}</{}>", tag, attrs_part, children_html, tag)
             }
-            DomNode::Text(content) => content.clone(),
+            DomNode::Text(content) => escape_html(content),
+            DomNode::Tr { key, args } => escape_html(&translate(key, args)),
         }
     }
 }
 
 impl Dom {
+    /// Renders `DomNode::Tr` nodes as their key
     pub fn to_html(&self) -> String {
+        self.to_html_localized(&|key, _| key.to_string())
+    }
+
+    pub fn to_html_localized(&self, translate: Translate) -> String {
         self.nodes
             .iter()
-            .map(|node| node.to_html())
+            .map(|node| node.to_html(translate))
             .collect::<String>()
     }
+
+    /// The same tree with `DomNode::Tr` nodes replaced by their text
+    pub fn translated(&self, translate: Translate) -> Dom {
+        fn translate_node(node: &DomNode, translate: Translate) -> DomNode {
+            match node {
+                DomNode::Element { tag, attrs, children } => DomNode::Element {
+                    tag: tag.clone(),
+                    attrs: attrs.clone(),
+                    children: children.iter().map(|child| translate_node(child, translate)).collect(),
+                },
+                DomNode::Text(text) => DomNode::Text(text.clone()),
+                DomNode::Tr { key, args } => DomNode::Text(translate(key, args)),
+            }
+        }
+        Dom { nodes: self.nodes.iter().map(|node| translate_node(node, translate)).collect() }
+    }
+
+    /// The changes that turn this tree into `after`, applied in order.
+    /// Nodes are addressed by their child index at each level.
+    pub fn diff(&self, after: &Dom) -> Vec<DomChange> {
+        let mut changes = Vec::new();
+        diff_children(&mut Vec::new(), &self.nodes, &after.nodes, &mut changes);
+        changes
+    }
+}
+
+/// One step of `Dom::diff`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum DomChange {
+    /// The node at `path` became `node`
+    Replace { path: Vec<usize>, node: DomNode },
+    /// The element at `path` kept its tag and children, its attributes
+    /// became `attrs`
+    Attrs { path: Vec<usize>, attrs: Vec<(String, String)> },
+    Insert { path: Vec<usize>, node: DomNode },
+    Remove { path: Vec<usize> },
+}
+
+fn diff_children(path: &mut Vec<usize>, before: &[DomNode], after: &[DomNode], changes: &mut Vec<DomChange>) {
+    for (idx, (old, new)) in before.iter().zip(after).enumerate() {
+        path.push(idx);
+        diff_node(path, old, new, changes);
+        path.pop();
+    }
+    let child = |path: &Vec<usize>, idx: usize| path.iter().copied().chain([idx]).collect();
+    for (idx, node) in after.iter().enumerate().skip(before.len()) {
+        changes.push(DomChange::Insert { path: child(path, idx), node: node.clone() });
+    }
+    // From the last, so the indices of the others stay valid
+    for idx in (after.len()..before.len()).rev() {
+        changes.push(DomChange::Remove { path: child(path, idx) });
+    }
+}
+
+fn diff_node(path: &mut Vec<usize>, before: &DomNode, after: &DomNode, changes: &mut Vec<DomChange>) {
+    match (before, after) {
+        _ if before == after => {}
+        (
+            DomNode::Element { tag, attrs, children },
+            DomNode::Element { tag: new_tag, attrs: new_attrs, children: new_children },
+        ) if tag == new_tag => {
+            if attrs != new_attrs {
+                changes.push(DomChange::Attrs { path: path.clone(), attrs: new_attrs.clone() });
+            }
+            diff_children(path, children, new_children, changes);
+        }
+        _ => changes.push(DomChange::Replace { path: path.clone(), node: after.clone() }),
+    }
+}
+
+fn is_void(tag: &str) -> bool {
+    matches!(tag, "br" | "hr" | "img" | "input")
+}
+
+/// Parses an HTML fragment, e.g. a template partial. Tag and attribute names
+/// are lowercased, comments are dropped, and so is whitespace-only text that
+/// spans lines, i.e. indentation. An element with a `data-tr` attribute gets
+/// that catalog message as its content. Script and style contents are not
+/// special-cased, so they must not contain `<`.
+pub fn parse(html: &str) -> Result<Vec<DomNode>, String> {
+    Parser { input: html, pos: 0 }.nodes(None)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    // Children up to the end tag of `parent`, or to the end of the input
+    fn nodes(&mut self, parent: Option<&str>) -> Result<Vec<DomNode>, String> {
+        let mut nodes = Vec::new();
+        loop {
+            let rest = self.rest();
+            if rest.is_empty() {
+                return match parent {
+                    Some(tag) => Err(format!("<{}> is not closed", tag)),
+                    None => Ok(nodes),
+                };
+            }
+            if let Some(comment) = rest.strip_prefix("<!--") {
+                let end = comment.find("-->").ok_or_else(|| format!("Unterminated comment at byte {}", self.pos))?;
+                self.pos += "<!--".len() + end + "-->".len();
+            } else if let Some(end_tag) = rest.strip_prefix("</") {
+                let end = end_tag.find('>').ok_or_else(|| format!("Unterminated end tag at byte {}", self.pos))?;
+                let tag = end_tag[..end].trim().to_ascii_lowercase();
+                self.pos += "</".len() + end + 1;
+                return match parent {
+                    Some(parent) if parent == tag => Ok(nodes),
+                    Some(parent) => Err(format!("</{}> closes <{}>", tag, parent)),
+                    None => Err(format!("Unexpected </{}>", tag)),
+                };
+            } else if rest.starts_with('<') {
+                nodes.push(self.element()?);
+            } else {
+                let end = rest.find('<').unwrap_or(rest.len());
+                let text = &rest[..end];
+                self.pos += end;
+                if !(text.trim().is_empty() && text.contains('\n')) {
+                    nodes.push(DomNode::Text(unescape_html(text)));
+                }
+            }
+        }
+    }
+
+    fn element(&mut self) -> Result<DomNode, String> {
+        self.pos += 1;
+        let tag = self.name().to_ascii_lowercase();
+        if tag.is_empty() {
+            return Err(format!("Expected a tag name at byte {}", self.pos));
+        }
+        let mut attrs = Vec::new();
+        let self_closing = loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.starts_with("/>") {
+                self.pos += 2;
+                break true;
+            }
+            if rest.starts_with('>') {
+                self.pos += 1;
+                break false;
+            }
+            let name = self.name().to_ascii_lowercase();
+            if name.is_empty() {
+                return Err(format!("Malformed <{}> tag at byte {}", tag, self.pos));
+            }
+            self.skip_whitespace();
+            let value = match self.rest().starts_with('=') {
+                true => {
+                    self.pos += 1;
+                    self.skip_whitespace();
+                    self.value()?
+                }
+                // Boolean attribute
+                false => String::new(),
+            };
+            attrs.push((name, value));
+        };
+        let mut children = match self_closing || is_void(&tag) {
+            true => Vec::new(),
+            false => self.nodes(Some(&tag))?,
+        };
+        if let Some(idx) = attrs.iter().position(|(name, _)| name == "data-tr") {
+            let (_, key) = attrs.remove(idx);
+            children = vec![DomNode::Tr { key, args: Vec::new() }];
+        }
+        Ok(DomNode::Element { tag, attrs, children })
+    }
+
+    fn name(&mut self) -> &'a str {
+        let rest = self.rest();
+        let end = rest
+            .find(|c: char| c.is_whitespace() || matches!(c, '>' | '/' | '='))
+            .unwrap_or(rest.len());
+        self.pos += end;
+        &rest[..end]
+    }
+
+    fn value(&mut self) -> Result<String, String> {
+        let rest = self.rest();
+        match rest.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let end = rest[1..].find(quote).ok_or_else(|| format!("Unterminated attribute value at byte {}", self.pos))?;
+                self.pos += end + 2;
+                Ok(unescape_html(&rest[1..=end]))
+            }
+            _ => {
+                let end = rest.find(|c: char| c.is_whitespace() || c == '>').unwrap_or(rest.len());
+                self.pos += end;
+                Ok(unescape_html(&rest[..end]))
+            }
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+}
+
+/// The named and numeric character references `escape_html` produces, plus
+/// the common `&#39;`, `&apos;` and `&nbsp;`
+fn unescape_html(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(idx) = rest.find('&') {
+        unescaped.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+        let reference = rest.find(';').map(|end| &rest[1..end]);
+        let c = match reference {
+            Some("amp") => Some('&'),
+            Some("lt") => Some('<'),
+            Some("gt") => Some('>'),
+            Some("quot") => Some('"'),
+            Some("apos") => Some('\''),
+            Some("nbsp") => Some('\u{a0}'),
+            Some(number) => number
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| number.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+            None => None,
+        };
+        match (c, reference) {
+            (Some(c), Some(reference)) => {
+                unescaped.push(c);
+                rest = &rest[reference.len() + 2..];
+            }
+            // A lone ampersand
+            _ => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_json() {
+        let dom = Dom {
+            nodes: vec![DomNode::element("p", vec![("class", "counter")], vec![
+                DomNode::tr("counter", vec![("count", "3")]),
+                DomNode::text(" & more"),
+            ])],
+        };
+        let translated = dom.translated(&|key, args| format!("{}={}", key, args[0].1));
+        let json = serde_json::to_value(&translated).unwrap();
+        assert_eq!(json, serde_json::json!({ "nodes": [{ "element": {
+            "tag": "p",
+            "attrs": [["class", "counter"]],
+            "children": [{ "text": "counter=3" }, { "text": " & more" }],
+        } }] }));
+        let parsed: Dom = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.nodes, translated.nodes);
+        assert_eq!(parsed.to_html(), "<p class=\"counter\">counter=3 &amp; more</p>");
+    }
+
+    #[test]
+    fn diffs_trees_by_child_index() {
+        let item = |text: &str| DomNode::element("li", vec![], vec![DomNode::text(text)]);
+        let before = Dom {
+            nodes: vec![
+                DomNode::element("ul", vec![("class", "todos")], vec![item("a"), item("b"), item("c")]),
+                DomNode::element("p", vec![], vec![]),
+            ],
+        };
+        let after = Dom {
+            nodes: vec![DomNode::element("ul", vec![("class", "todos done")], vec![item("a"), item("x")]), DomNode::text("empty")],
+        };
+        let changes = before.diff(&after);
+        assert_eq!(changes, vec![
+            DomChange::Attrs { path: vec![0], attrs: vec![("class".to_string(), "todos done".to_string())] },
+            DomChange::Replace { path: vec![0, 1, 0], node: DomNode::text("x") },
+            DomChange::Remove { path: vec![0, 2] },
+            DomChange::Replace { path: vec![1], node: DomNode::text("empty") },
+        ]);
+        assert!(before.diff(&before).is_empty());
+        let json = serde_json::to_value(&changes[2]).unwrap();
+        assert_eq!(json, serde_json::json!({ "op": "remove", "path": [0, 2] }));
+    }
 }
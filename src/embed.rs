@@ -0,0 +1,189 @@
+// Data embedding
+// Constants a function reads from .data/.rodata at fixed addresses are copied
+// into active data segments of its module, at the same addresses in linear
+// memory, so the translated loads find them. WASM loads are little-endian:
+// the fields of big-endian binaries are byte-swapped per access, and `audit`
+// checks that every access reads back the value the machine code would.
+
+use crate::arch::MachineInstr;
+use crate::backend::{DataBlock, FunctionIR};
+use crate::ir::{self, Op, Ty, Value};
+use std::collections::HashMap;
+
+// Linear memory browsers can address, even with memory64
+const ADDRESSABLE: u64 = 1 << 32;
+
+/// A load from a constant address inside a data section
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Access {
+    pub addr: u64,
+    /// Size of the field the instruction reads
+    pub width: usize,
+    /// Bytes the WASM load reads, at least the field
+    pub span: usize,
+}
+
+/// Constant-address loads of the lifted function that hit a data section
+pub fn accesses(lifted: &ir::Function, function: &FunctionIR) -> Vec<Access> {
+    let instrs: HashMap<u64, &MachineInstr> = function.instructions.iter().map(|info| (info.addr, &info.instr)).collect();
+    let mut accesses: Vec<Access> = lifted
+        .insts()
+        .filter_map(|inst| {
+            let Op::Load { dst, base: Value::Const(base), offset } = inst.op else { return None };
+            let span = if lifted.vregs[dst.0 as usize] == Ty::I32 { 4 } else { 8 };
+            // Only x86 operands carry their size; the other front-ends load
+            // whole registers
+            let width = match instrs.get(&inst.addr) {
+                Some(MachineInstr::X86(instr)) => instr.memory_size().size(),
+                _ => span,
+            };
+            let width = if (1..=span).contains(&width) { width } else { span };
+            let addr = base.wrapping_add(offset) as u64;
+            function.data.iter().any(|block| block.read(addr, width).is_some()).then_some(Access { addr, width, span })
+        })
+        .collect();
+    accesses.sort();
+    accesses.dedup();
+    accesses
+}
+
+/// Replaces the loads of fields that lie in a fixed range of `function` by
+/// the value they read, for constant propagation to fold further. Nothing is
+/// folded if the function itself stores to a fixed range.
+pub fn fold_fixed(lifted: &mut ir::Function, function: &FunctionIR) {
+    let fixed = |addr: u64, width: usize| function.fixed.iter().any(|range| range.start < addr + width as u64 && addr < range.end);
+    let stores_fixed = lifted.insts().any(|inst| match inst.op {
+        Op::Store { base: Value::Const(base), offset, .. } => fixed(base.wrapping_add(offset) as u64, 8),
+        _ => false,
+    });
+    if function.fixed.is_empty() || stores_fixed {
+        return;
+    }
+    let accesses = accesses(lifted, function);
+    let segments = segments(&accesses, &function.data);
+    let vregs = lifted.vregs.clone();
+    for inst in lifted.blocks.iter_mut().flat_map(|block| block.insts.iter_mut()) {
+        let Op::Load { dst, base: Value::Const(base), offset } = inst.op else { continue };
+        let addr = base.wrapping_add(offset) as u64;
+        let span = if vregs[dst.0 as usize] == Ty::I32 { 4 } else { 8 };
+        let Some(access) = accesses.iter().find(|access| access.addr == addr && access.span == span) else { continue };
+        let contained = function.fixed.iter().any(|range| range.start <= addr && addr + access.width as u64 <= range.end);
+        let bytes = segments
+            .iter()
+            .find_map(|(start, bytes)| bytes.get(addr.checked_sub(*start)? as usize..)?.get(..access.span));
+        if let (true, Some(bytes)) = (contained, bytes) {
+            let value = match span {
+                4 => Ty::I32.wrap(lifted.word, little_endian(bytes) as i64),
+                _ => little_endian(bytes) as i64,
+            };
+            inst.op = Op::Copy { dst, src: Value::Const(value) };
+        }
+    }
+}
+
+/// Data segments covering every access, as (address, bytes). Bytes outside
+/// the data sections (.bss, padding) are zero.
+pub fn segments(accesses: &[Access], data: &[DataBlock]) -> Vec<(u64, Vec<u8>)> {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for access in accesses {
+        let end = access.addr.saturating_add(access.span as u64);
+        match ranges.last_mut() {
+            Some((_, last)) if access.addr <= *last => *last = (*last).max(end),
+            _ => ranges.push((access.addr, end)),
+        }
+    }
+    ranges
+        .into_iter()
+        .map(|(start, end)| {
+            let mut bytes: Vec<u8> = (start..end).map(|addr| byte(data, addr).unwrap_or(0)).collect();
+            for access in accesses.iter().filter(|access| (start..end).contains(&access.addr)) {
+                if block(data, access).is_some_and(|block| block.big_endian) {
+                    let at = (access.addr - start) as usize;
+                    bytes[at..at + access.width].reverse();
+                }
+            }
+            (start, bytes)
+        })
+        .collect()
+}
+
+/// Accesses whose little-endian read of the segments differs from the value
+/// the machine code reads from the binary, and segments browsers cannot
+/// address. A big-endian field read at two different widths can only be
+/// swapped for one of them.
+pub fn audit(accesses: &[Access], data: &[DataBlock], segments: &[(u64, Vec<u8>)]) -> Vec<String> {
+    let mut problems = Vec::new();
+    for access in accesses {
+        let Some(block) = block(data, access) else { continue };
+        let mut source = block.read(access.addr, access.width).unwrap_or_default().to_vec();
+        if block.big_endian {
+            source.reverse();
+        }
+        let embedded = segments
+            .iter()
+            .find_map(|(start, bytes)| bytes.get(access.addr.checked_sub(*start)? as usize..).map(|rest| &rest[..access.width.min(rest.len())]))
+            .unwrap_or_default();
+        if embedded != source.as_slice() {
+            problems.push(format!(
+                "{:#x}: {}-byte field reads {:#x} from the data segment, {:#x} in the binary",
+                access.addr,
+                access.width,
+                little_endian(embedded),
+                little_endian(&source),
+            ));
+        }
+    }
+    for (start, bytes) in segments {
+        if start.saturating_add(bytes.len() as u64) > ADDRESSABLE {
+            problems.push(format!("{:#x}: data segment lies beyond the 4 GiB browsers can address", start));
+        }
+    }
+    problems
+}
+
+fn block<'a>(data: &'a [DataBlock], access: &Access) -> Option<&'a DataBlock> {
+    data.iter().find(|block| block.read(access.addr, access.width).is_some())
+}
+
+fn byte(data: &[DataBlock], addr: u64) -> Option<u8> {
+    data.iter().find_map(|block| block.read(addr, 1)).map(|bytes| bytes[0])
+}
+
+fn little_endian(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |value, byte| value << 8 | *byte as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::Arch;
+
+    #[test]
+    fn swaps_big_endian_fields_per_access() {
+        // movsx rax, word [rip+0xff8] (0x2000); mov rax, [rip+0xff9] (0x2008); ret
+        let code = [0x48, 0x0f, 0xbf, 0x05, 0xf8, 0x0f, 0x00, 0x00, 0x48, 0x8b, 0x05, 0xf9, 0x0f, 0x00, 0x00, 0xc3];
+        let mut function = FunctionIR::decode("test", Arch::X86_64, &code, 0x1000);
+        let mut bytes = vec![0x12, 0x34, 0, 0, 0, 0, 0, 0];
+        bytes.extend(0x0102_0304_0506_0708u64.to_be_bytes());
+        function.data = vec![DataBlock { addr: 0x2000, bytes, big_endian: true }];
+        // Unoptimized, so the first load is not dropped as dead
+        let lifted = ir::lift(&function, &mut Default::default());
+
+        let accesses = accesses(&lifted, &function);
+        assert_eq!(accesses, vec![
+            Access { addr: 0x2000, width: 2, span: 8 },
+            Access { addr: 0x2008, width: 8, span: 8 },
+        ]);
+        let segments = segments(&accesses, &function.data);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(&segments[0].1[..2], &0x1234u16.to_le_bytes());
+        assert_eq!(&segments[0].1[8..], &0x0102_0304_0506_0708u64.to_le_bytes());
+        assert!(audit(&accesses, &function.data, &segments).is_empty());
+
+        // The same field also read as 8 bytes cannot be swapped both ways
+        let overlapping = [accesses[0], Access { addr: 0x2000, width: 8, span: 8 }];
+        let problems = audit(&overlapping, &function.data, &super::segments(&overlapping, &function.data));
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].starts_with("0x2000: 2-byte field"), "{:?}", problems);
+    }
+}
@@ -0,0 +1,95 @@
+// Function body encoding
+// Encoding the body one `Function::instruction` at a time grows its buffer
+// over and over, which dominates module generation for big functions. The
+// body is encoded into a buffer sized up front instead. Instructions encode
+// independently of each other, so bodies of at least `PARALLEL_THRESHOLD`
+// instructions are split into chunks encoded on separate threads and joined
+// in order, which gives the same bytes as encoding them one by one. On a
+// single core the chunks are encoded in turn.
+
+use std::thread;
+use wasm_encoder::{Encode, Function, Instruction as WasmInstr, ValType};
+
+pub const PARALLEL_THRESHOLD: usize = 8192;
+// Most instructions take one opcode byte and a LEB128 immediate or two
+const BYTES_PER_INSTRUCTION: usize = 3;
+
+/// The raw body of the function with `locals` and `body`, ended with
+/// `end`, for `CodeSection::raw`
+pub fn function(locals: Vec<(u32, ValType)>, body: &[WasmInstr]) -> Vec<u8> {
+    let threads = match body.len() < PARALLEL_THRESHOLD {
+        true => 1,
+        false => thread::available_parallelism().map_or(1, usize::from),
+    };
+    let mut bytes = Function::new(locals).into_raw_body();
+    encode(body, threads, &mut bytes);
+    WasmInstr::End.encode(&mut bytes);
+    bytes
+}
+
+/// Appends the encoded instructions of `body` to `bytes`, split across
+/// `threads`
+pub fn encode(body: &[WasmInstr], threads: usize, bytes: &mut Vec<u8>) {
+    if threads <= 1 {
+        bytes.reserve(body.len() * BYTES_PER_INSTRUCTION);
+        for instr in body {
+            instr.encode(bytes);
+        }
+        return;
+    }
+    let chunk_len = body.len().div_ceil(threads);
+    let chunks: Vec<Vec<u8>> = thread::scope(|scope| {
+        let handles: Vec<_> = body
+            .chunks(chunk_len)
+            .map(|chunk| scope.spawn(|| {
+                let mut bytes = Vec::new();
+                encode(chunk, 1, &mut bytes);
+                bytes
+            }))
+            .collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    });
+    bytes.reserve(chunks.iter().map(Vec::len).sum());
+    for chunk in chunks {
+        bytes.extend_from_slice(&chunk);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Roughly what the lowering of a long run of arithmetic looks like
+    fn large_body(len: usize) -> Vec<WasmInstr<'static>> {
+        (0..len)
+            .map(|idx| match idx % 4 {
+                0 => WasmInstr::LocalGet(idx as u32 % 64),
+                1 => WasmInstr::I64Const(idx as i64 * 0x1234_5678),
+                2 => WasmInstr::I64Add,
+                _ => WasmInstr::LocalSet(idx as u32 % 64),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parallel_encoding_matches_sequential() {
+        let body = large_body(PARALLEL_THRESHOLD + 3);
+        let mut expected = Function::new(vec![(64, ValType::I64)]);
+        for instr in &body {
+            expected.instruction(instr);
+        }
+        expected.instruction(&WasmInstr::End);
+
+        assert_eq!(function(vec![(64, ValType::I64)], &body), expected.into_raw_body());
+        let sequential = {
+            let mut bytes = Vec::new();
+            encode(&body, 1, &mut bytes);
+            bytes
+        };
+        for threads in [2, 3, 7, body.len() + 1] {
+            let mut bytes = vec![0xff];
+            encode(&body, threads, &mut bytes);
+            assert_eq!(bytes[1..], sequential[..]);
+        }
+    }
+}
@@ -0,0 +1,150 @@
+// API errors
+// Every route answers a failure with a JSON body of the same shape,
+// `{"code", "message", "detail", "request_id"}`. `code` is a stable
+// snake_case identifier for clients to branch on and `message` is meant for
+// people. `detail` holds structured data where there is any, such as the
+// violations of invalid arguments, and is null otherwise. `request_id` is
+// the request's `X-Request-Id`, so a failure can be found in the logs.
+
+use crate::callbacks::CallbackError;
+use actix_web::error::{JsonPayloadError, QueryPayloadError};
+use actix_web::http::StatusCode;
+use actix_web::web::JsonConfig;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use serde::Serialize;
+use std::fmt;
+
+tokio::task_local! {
+    /// The ID of the request being handled, set by `logging::trace_request`
+    pub static REQUEST_ID: Option<String>;
+}
+
+/// The ID of the request this task is handling, if it has one
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok().flatten()
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    status: StatusCode,
+    pub code: &'static str,
+    pub message: String,
+    pub detail: Option<serde_json::Value>,
+    pub request_id: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self { status, code, message: message.into(), detail: None, request_id: request_id() }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    pub fn payload_too_large(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", message)
+    }
+
+    pub fn with_detail(mut self, detail: impl Serialize) -> Self {
+        self.detail = serde_json::to_value(detail).ok();
+        self
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(self)
+    }
+}
+
+impl From<CallbackError> for ApiError {
+    fn from(e: CallbackError) -> Self {
+        let code = match &e {
+            CallbackError::Status(_) => "callback_failed",
+            CallbackError::Marshal(_) => "marshal_failed",
+            CallbackError::Arguments(_) | CallbackError::Invalid(_) => "invalid_arguments",
+            CallbackError::Rejected(_) => "rejected",
+        };
+        let error = Self::new(StatusCode::UNPROCESSABLE_ENTITY, code, e.to_string());
+        match e {
+            CallbackError::Invalid(violations) => error.with_detail(serde_json::json!({ "violations": violations })),
+            _ => error,
+        }
+    }
+}
+
+/// Error handler of the JSON extractors, so a malformed or oversized body
+/// gets the same shape as any other error
+pub fn json_error(e: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    match e {
+        JsonPayloadError::Overflow { limit } | JsonPayloadError::OverflowKnownLength { limit, .. } => {
+            ApiError::payload_too_large(format!("The body is limited to {} bytes", limit))
+        }
+        JsonPayloadError::ContentType => {
+            ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", "Expected application/json")
+        }
+        e => ApiError::bad_request(format!("Invalid JSON body: {}", e)),
+    }
+    .into()
+}
+
+/// `config` answering its errors with `json_error`
+pub fn json_config(config: JsonConfig) -> JsonConfig {
+    config.error_handler(json_error)
+}
+
+pub fn query_error(e: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::bad_request(format!("Invalid query: {}", e)).into()
+}
+
+/// For the bodies extracted as raw bytes, whose size limit has no handler
+pub fn payload_error(e: actix_web::Error) -> ApiError {
+    match e.as_response_error().status_code() {
+        StatusCode::PAYLOAD_TOO_LARGE => ApiError::payload_too_large(e.to_string()),
+        _ => ApiError::bad_request(e.to_string()),
+    }
+}
+
+/// Answers the paths no route matches
+pub async fn no_route() -> HttpResponse {
+    ApiError::not_found("No such route").error_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::Violation;
+
+    #[actix_web::test]
+    async fn renders_the_same_shape_for_every_error() {
+        let error = ApiError::from(CallbackError::Invalid(vec![Violation { param: 0, rule: "range", message: "2000 is not within [-1000, 1000]".to_string() }]));
+        assert_eq!(error.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        let json = serde_json::to_value(&error).unwrap();
+        assert_eq!(json["code"], "invalid_arguments");
+        assert_eq!(json["detail"]["violations"][0]["rule"], "range");
+        assert_eq!(json["request_id"], serde_json::Value::Null);
+
+        let json = REQUEST_ID.scope(Some("abc".to_string()), async { serde_json::to_value(ApiError::not_found("Function not found")).unwrap() }).await;
+        assert_eq!(json, serde_json::json!({ "code": "not_found", "message": "Function not found", "detail": null, "request_id": "abc" }));
+    }
+}
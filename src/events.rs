@@ -0,0 +1,78 @@
+// Server-sent events
+// Connected browsers subscribe to /events and receive push notifications,
+// e.g. a `reload` event after the binary was re-transpiled. With a cluster
+// relay, events are also published to the other instances, see `cluster.rs`.
+
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use actix_web::{web, HttpResponse, Responder};
+use std::sync::{Mutex, OnceLock};
+use tokio::sync::mpsc;
+
+use crate::cluster::Relay;
+use crate::ServerContext;
+
+pub struct Broadcaster {
+    clients: Mutex<Vec<mpsc::UnboundedSender<Bytes>>>,
+    relay: OnceLock<Relay>,
+}
+
+impl Broadcaster {
+    pub fn new() -> Self {
+        Self {
+            clients: Mutex::new(Vec::new()),
+            relay: OnceLock::new(),
+        }
+    }
+
+    pub fn subscribe(&self) -> HttpResponse {
+        let (tx, rx) = mpsc::unbounded_channel();
+        // Comment line so the client sees the connection as open right away
+        let _ = tx.send(Bytes::from_static(b": connected\n\n"));
+        self.clients.lock().unwrap().push(tx);
+
+        let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|bytes| (Ok::<_, actix_web::Error>(bytes), rx))
+        });
+
+        HttpResponse::Ok()
+            .insert_header((header::CACHE_CONTROL, "no-cache"))
+            .content_type("text/event-stream")
+            .streaming(stream)
+    }
+
+    /// Publishes every later broadcast to the other instances too
+    pub fn relay_to(&self, relay: Relay) {
+        let _ = self.relay.set(relay);
+    }
+
+    /// Sends an event to every connected client and the other instances.
+    pub fn broadcast(&self, event: &str, data: &str) {
+        self.broadcast_local(event, data);
+        if let Some(relay) = self.relay.get() {
+            relay.publish(event, data, None);
+        }
+    }
+
+    /// Sends a `state` event with the new version, and the image for the
+    /// other instances to take over.
+    pub fn broadcast_state(&self, version: u64, image: &[u8]) {
+        self.broadcast_local("state", &version.to_string());
+        if let Some(relay) = self.relay.get() {
+            relay.publish("state", &version.to_string(), Some(image));
+        }
+    }
+
+    /// Sends an event to every connected client, dropping disconnected ones.
+    pub fn broadcast_local(&self, event: &str, data: &str) {
+        let message = Bytes::from(format!("event: {}\ndata: {}\n\n", event, data));
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|client| client.send(message.clone()).is_ok());
+    }
+}
+
+pub async fn subscribe(ctx: web::Data<ServerContext>) -> impl Responder {
+    ctx.events.subscribe()
+}
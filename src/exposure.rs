@@ -0,0 +1,144 @@
+// Symbol exposure
+// Which functions the server translates and routes. `[exposure] mode =
+// "listed"` serves the callbacks registered in code and the `callbacks` of
+// each `[binaries]` table; `"auto"` serves only the ones registered in code,
+// so listing a symbol in the config is not enough to put it on the network.
+// `allow` and `deny` are glob patterns (`*` any run of characters, `?` one
+// character) over the symbol name, and for `[binaries]` symbols also over
+// `<namespace>/<symbol>`: a symbol has to match an allow pattern if there
+// are any, and must not match a deny pattern. Hidden callbacks are removed
+// from the registry before anything is translated, and the namespaced
+// routes check again, so a hidden symbol is neither translated nor served.
+
+use crate::callbacks::CallbackRegistry;
+use crate::config::{BinaryConfig, ExposureConfig};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExposureMode {
+    /// Registered callbacks and the symbols listed under `[binaries]`
+    #[default]
+    Listed,
+    /// Registered callbacks only
+    Auto,
+}
+
+pub struct Exposure {
+    mode: ExposureMode,
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+/// `*` matches any run of characters, `?` any one
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and the name position it was tried at
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the `*` take one more character
+                Some((star, at)) => {
+                    backtrack = Some((star, at + 1));
+                    p = star + 1;
+                    n = at + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+impl Exposure {
+    pub fn new(config: &ExposureConfig) -> Self {
+        Self { mode: config.mode, allow: config.allow.clone(), deny: config.deny.clone() }
+    }
+
+    fn permits(&self, names: &[&str]) -> bool {
+        let matches = |patterns: &[String]| patterns.iter().any(|pattern| names.iter().any(|name| glob_match(pattern, name)));
+        (self.allow.is_empty() || matches(&self.allow)) && !matches(&self.deny)
+    }
+
+    /// A callback of the server's own binary
+    pub fn exposes(&self, fn_name: &str) -> bool {
+        self.permits(&[fn_name])
+    }
+
+    /// A symbol of the `[binaries]` table `namespace`
+    pub fn exposes_in(&self, namespace: &str, symbol: &str) -> bool {
+        self.mode == ExposureMode::Listed && self.permits(&[symbol, &format!("{}/{}", namespace, symbol)])
+    }
+
+    /// Removes the callbacks that are not exposed
+    pub fn apply(&self, callbacks: &mut CallbackRegistry) {
+        callbacks.retain(|name| {
+            let exposed = self.exposes(name);
+            if !exposed {
+                tracing::info!(callback = %name, "Callback hidden by [exposure]");
+            }
+            exposed
+        });
+    }
+
+    /// `binary` with only the exposed symbols of its `callbacks`
+    pub fn filter_binary(&self, namespace: &str, binary: &BinaryConfig) -> BinaryConfig {
+        let mut binary = binary.clone();
+        binary.callbacks.retain(|symbol| {
+            let exposed = self.exposes_in(namespace, symbol);
+            if !exposed {
+                tracing::info!(%namespace, %symbol, "Symbol hidden by [exposure]");
+            }
+            exposed
+        });
+        binary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Permission;
+
+    #[test]
+    fn filters_symbols_by_mode_and_patterns() {
+        assert!(glob_match("counter_*", "counter_stats"));
+        assert!(glob_match("*_counter", "reset_counter"));
+        assert!(glob_match("a*b?d", "axxbcd"));
+        assert!(!glob_match("a*b?d", "axxbd"));
+        assert!(glob_match("*", ""));
+
+        let exposure = Exposure::new(&ExposureConfig {
+            mode: ExposureMode::Listed,
+            allow: vec!["*_counter".to_string(), "plugin/*".to_string()],
+            deny: vec!["reset_*".to_string(), "*/internal_*".to_string()],
+        });
+        assert!(exposure.exposes("increment_counter"));
+        assert!(!exposure.exposes("reset_counter"));
+        assert!(!exposure.exposes("counter_stats"));
+        assert!(exposure.exposes_in("plugin", "blend"));
+        assert!(!exposure.exposes_in("plugin", "internal_blend"));
+        assert!(!exposure.exposes_in("other", "blend"));
+
+        let mut callbacks = CallbackRegistry::new();
+        callbacks
+            .register("increment_counter", crate::increment_counter, Permission::User)
+            .register("reset_counter", crate::reset_counter, Permission::Admin);
+        exposure.apply(&mut callbacks);
+        assert_eq!(callbacks.iter().map(|callback| callback.name.as_str()).collect::<Vec<_>>(), vec!["increment_counter"]);
+
+        let auto = Exposure::new(&ExposureConfig { mode: ExposureMode::Auto, ..Default::default() });
+        assert!(auto.exposes("reset_counter"));
+        assert!(!auto.exposes_in("plugin", "blend"));
+    }
+}
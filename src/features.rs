@@ -0,0 +1,187 @@
+// WASM feature requirements
+// The post-MVP proposals a module depends on, found by validating it with
+// each one switched off in turn. `/wasm/{fn_name}/requirements` lists them,
+// and the page glue checks each against a tiny probe module that uses only
+// that proposal, the way wasm-feature-detect does. A browser missing any of
+// them gets the JavaScript fallback without downloading the module.
+
+use crate::error::ApiError;
+use crate::ServerContext;
+use actix_web::{web, HttpResponse, Responder, ResponseError};
+use serde::Serialize;
+use wasm_encoder::{
+    CodeSection, ExportKind, ExportSection, Function, FunctionSection, Instruction, MemArg, MemorySection, MemoryType, Module,
+    TypeSection, ValType,
+};
+use wasmparser::{Validator, WasmFeatures};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    SaturatingFloatToInt,
+    SignExtension,
+    MultiValue,
+    ReferenceTypes,
+    BulkMemory,
+    Simd,
+    Threads,
+    TailCall,
+    Memory64,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 9] = [
+        Feature::SaturatingFloatToInt,
+        Feature::SignExtension,
+        Feature::MultiValue,
+        Feature::ReferenceTypes,
+        Feature::BulkMemory,
+        Feature::Simd,
+        Feature::Threads,
+        Feature::TailCall,
+        Feature::Memory64,
+    ];
+
+    // The proposal, and the ones building on it that would accept the
+    // module without it
+    fn flags(self) -> WasmFeatures {
+        match self {
+            Feature::SaturatingFloatToInt => WasmFeatures::SATURATING_FLOAT_TO_INT,
+            Feature::SignExtension => WasmFeatures::SIGN_EXTENSION,
+            Feature::MultiValue => WasmFeatures::MULTI_VALUE,
+            Feature::ReferenceTypes => WasmFeatures::REFERENCE_TYPES | WasmFeatures::FUNCTION_REFERENCES | WasmFeatures::GC,
+            Feature::BulkMemory => WasmFeatures::BULK_MEMORY,
+            Feature::Simd => WasmFeatures::SIMD | WasmFeatures::RELAXED_SIMD,
+            Feature::Threads => WasmFeatures::THREADS | WasmFeatures::SHARED_EVERYTHING_THREADS,
+            Feature::TailCall => WasmFeatures::TAIL_CALL,
+            Feature::Memory64 => WasmFeatures::MEMORY64,
+        }
+    }
+
+    /// Smallest module that only validates with the proposal
+    pub fn probe(self) -> Vec<u8> {
+        let mut module = Module::new();
+        let mut types = TypeSection::new();
+        let (params, results) = match self {
+            Feature::MultiValue => (vec![], vec![ValType::I32, ValType::I32]),
+            Feature::ReferenceTypes => (vec![ValType::EXTERNREF], vec![]),
+            Feature::Simd => (vec![], vec![ValType::V128]),
+            _ => (vec![], vec![]),
+        };
+        types.ty().function(params, results);
+        module.section(&types);
+        let mut functions = FunctionSection::new();
+        functions.function(0);
+        module.section(&functions);
+        if matches!(self, Feature::BulkMemory | Feature::Threads | Feature::Memory64) {
+            let mut memories = MemorySection::new();
+            memories.memory(MemoryType {
+                minimum: 1,
+                maximum: Some(1),
+                memory64: self == Feature::Memory64,
+                shared: self == Feature::Threads,
+                page_size_log2: None,
+            });
+            module.section(&memories);
+        }
+        // Exported, so browsers cannot skip validating the body
+        let mut exports = ExportSection::new();
+        exports.export("probe", ExportKind::Func, 0);
+        module.section(&exports);
+
+        let mut body = Function::new([]);
+        let instructions = match self {
+            Feature::SaturatingFloatToInt => vec![Instruction::F32Const(0.0), Instruction::I32TruncSatF32S, Instruction::Drop],
+            Feature::SignExtension => vec![Instruction::I32Const(0), Instruction::I32Extend8S, Instruction::Drop],
+            Feature::MultiValue => vec![Instruction::I32Const(0), Instruction::I32Const(0)],
+            Feature::ReferenceTypes => vec![Instruction::LocalGet(0), Instruction::Drop],
+            Feature::BulkMemory => vec![
+                Instruction::I32Const(0),
+                Instruction::I32Const(0),
+                Instruction::I32Const(0),
+                Instruction::MemoryFill(0),
+            ],
+            Feature::Simd => vec![Instruction::V128Const(0)],
+            Feature::Threads => vec![Instruction::AtomicFence],
+            Feature::TailCall => vec![Instruction::ReturnCall(0)],
+            Feature::Memory64 => vec![
+                Instruction::I64Const(0),
+                Instruction::I64Load(MemArg { offset: 0, align: 3, memory_index: 0 }),
+                Instruction::Drop,
+            ],
+        };
+        for instruction in &instructions {
+            body.instruction(instruction);
+        }
+        body.instruction(&Instruction::End);
+        let mut code = CodeSection::new();
+        code.function(&body);
+        module.section(&code);
+        module.finish()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Requirements {
+    pub features: Vec<Feature>,
+}
+
+/// The proposals `wasm` does not validate without
+pub fn requirements(wasm: &[u8]) -> Result<Vec<Feature>, String> {
+    Validator::new()
+        .validate_all(wasm)
+        .map_err(|e| format!("Invalid module: {}", e))?;
+    Ok(Feature::ALL
+        .into_iter()
+        .filter(|feature| {
+            let features = WasmFeatures::default().difference(feature.flags());
+            Validator::new_with_features(features).validate_all(wasm).is_err()
+        })
+        .collect())
+}
+
+/// Probe module of every feature, as byte arrays for the page glue
+pub fn probes_json() -> String {
+    let probes: serde_json::Map<String, serde_json::Value> = Feature::ALL
+        .into_iter()
+        .map(|feature| {
+            let name = serde_json::to_value(feature).unwrap().as_str().unwrap_or_default().to_string();
+            (name, serde_json::to_value(feature.probe()).unwrap())
+        })
+        .collect();
+    serde_json::Value::Object(probes).to_string()
+}
+
+pub async fn get_requirements(path: web::Path<String>, ctx: web::Data<ServerContext>) -> impl Responder {
+    let fn_name = path.into_inner();
+    let (Some(wasm), Some(_)) = (ctx.transpiler.get_wasm_for_function(&fn_name), ctx.callbacks.get(&fn_name)) else {
+        return ApiError::not_found("Function not found").error_response();
+    };
+    match requirements(&wasm) {
+        Ok(features) => HttpResponse::Ok().json(Requirements { features }),
+        Err(e) => ApiError::internal(e).error_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::Arch;
+    use crate::backend::{FunctionIR, TranspilerBackend};
+    use crate::transpiler_real::{DirectBackend, StateAccess, TranspileOptions};
+
+    #[test]
+    fn detects_the_proposals_a_module_needs() {
+        for feature in Feature::ALL {
+            assert_eq!(requirements(&feature.probe()), Ok(vec![feature]), "{:?}", feature);
+        }
+
+        // mov rax, [rdi]; add rax, 1; mov [rdi], rax; ret
+        let code = [0x48, 0x8b, 0x07, 0x48, 0x83, 0xc0, 0x01, 0x48, 0x89, 0x07, 0xc3];
+        let function = FunctionIR::decode("test", Arch::X86_64, &code, 0x1000);
+        let memory = DirectBackend::default().transpile(&function).unwrap();
+        assert_eq!(requirements(&memory), Ok(vec![Feature::Memory64]));
+        let handles = DirectBackend::new(TranspileOptions { state_access: StateAccess::Handles, ..Default::default() });
+        assert_eq!(requirements(&handles.transpile(&function).unwrap()), Ok(vec![Feature::ReferenceTypes]));
+    }
+}
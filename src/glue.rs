@@ -0,0 +1,1033 @@
+// Client glue
+// JavaScript embedded into the rendered page. It loads the module of a
+// callback, or its JavaScript translation, calls it with the client
+// arguments and decodes the result, runs callbacks on the server or on a
+// local replica of the state that is synced back, and reports errors to
+// /telemetry. The tables it works from, return descriptors, validation
+// rules and the state layout, are generated from the callback registry.
+
+use crate::callbacks::CallbackRegistry;
+use crate::layout::SelfServeState;
+use serde_json::{Map, Value};
+
+pub fn script(callbacks: &CallbackRegistry, csrf_header: &str) -> String {
+    let descriptors: Map<String, Value> = callbacks
+        .iter()
+        .map(|callback| {
+            let returns = serde_json::to_value(&callback.signature.returns).unwrap_or_default();
+            (callback.name.clone(), returns)
+        })
+        .collect();
+
+    let rules: Map<String, Value> = callbacks
+        .iter()
+        .filter(|callback| callback.rules.iter().any(|rules| !rules.is_empty()))
+        .map(|callback| (callback.name.clone(), serde_json::to_value(&callback.rules).unwrap_or_default()))
+        .collect();
+
+    let read_only: Vec<&str> = callbacks
+        .iter()
+        .filter(|callback| callback.read_only)
+        .map(|callback| callback.name.as_str())
+        .collect();
+
+    let state_param: Vec<&str> = callbacks
+        .iter()
+        .filter(|callback| callback.signature.takes_state())
+        .map(|callback| callback.name.as_str())
+        .collect();
+
+    // Escape "</" so a descriptor can never close the surrounding script tag
+    let descriptors = Value::Object(descriptors).to_string().replace("</", "<\\/");
+    let rules = Value::Object(rules).to_string().replace("</", "<\\/");
+    let read_only = serde_json::to_string(&read_only).unwrap_or_default().replace("</", "<\\/");
+    let state_param = serde_json::to_string(&state_param).unwrap_or_default().replace("</", "<\\/");
+    let probes = crate::features::probes_json();
+    let state_layout = serde_json::to_string(&crate::State::layout()).unwrap_or_default().replace("</", "<\\/");
+
+    format!(
+        r#"
+        // Return descriptor of every callback, struct results with their
+        // layout manifest, see `layout.rs`
+        const RETURN_TYPES = {descriptors};
+
+        // Validation rules of the client parameters, per callback, see
+        // `validation.rs`
+        const VALIDATION_RULES = {rules};
+
+        // Callbacks that only read the state, executed with GET
+        const READ_ONLY = new Set({read_only});
+
+        // Callbacks taking the state pointer first, which the glue supplies;
+        // the client arguments never include it
+        const STATE_PARAM = new Set({state_param});
+
+        // Layout manifest of the state, as served at /api/state/layout
+        const STATE_LAYOUT = {state_layout};
+
+        // A module per WASM proposal that only validates where it is supported
+        const FEATURE_PROBES = {probes};
+        const featureSupport = {{}};
+
+        // State words read by translated code, keyed by byte offset. Writes only
+        // touch the cache; the server state changes through /execute.
+        const stateCache = new Map();
+
+        function fetchStateWord(offset) {{
+            // WASM imports are synchronous, so this cannot use fetch()
+            const xhr = new XMLHttpRequest();
+            xhr.open('GET', `/api/state/bytes?offset=${{offset}}&len=8`, false);
+            xhr.overrideMimeType('text/plain; charset=x-user-defined');
+            xhr.send();
+            let value = 0n;
+            for (let i = xhr.responseText.length - 1; i >= 0; i--) {{
+                value = (value << 8n) | BigInt(xhr.responseText.charCodeAt(i) & 0xff);
+            }}
+            return BigInt.asIntN(64, value);
+        }}
+
+        // Imports of modules transpiled with `state_access = "imports"`,
+        // backed by the word cache
+        const stateImports = {{
+            env: {{
+                read_i64(offset) {{
+                    const key = Number(offset);
+                    if (!stateCache.has(key)) {{
+                        stateCache.set(key, fetchStateWord(key));
+                    }}
+                    return stateCache.get(key);
+                }},
+                write_i64(offset, value) {{
+                    stateCache.set(Number(offset), value);
+                }},
+                // Called before a trap instruction with its x86 exception
+                // vector, or 256 + the number of an unsupported syscall
+                trap_reason(code) {{
+                    console.error(`Callback trapped: ${{trapReason(code)}}`);
+                }},
+                // Linux syscalls, with BigInt arguments and results; errors
+                // are returned as -errno. Pointers are state offsets.
+                syscall_getpid() {{
+                    return 1n;
+                }},
+                syscall_gettid() {{
+                    return 1n;
+                }},
+                syscall_sched_yield() {{
+                    return 0n;
+                }},
+                syscall_clock_gettime(clock, tp) {{
+                    // CLOCK_REALTIME from the wall clock, the others from the page's monotonic clock
+                    const ns = clock === 0n
+                        ? BigInt(Date.now()) * 1000000n
+                        : BigInt(Math.round(performance.now() * 1e6));
+                    stateImports.env.write_i64(tp, ns / 1000000000n);
+                    stateImports.env.write_i64(tp + 8n, ns % 1000000000n);
+                    return 0n;
+                }},
+                syscall_getrandom(buf, len, flags) {{
+                    // Whole words, so the last one may run past len
+                    const words = crypto.getRandomValues(new BigInt64Array(Number((len + 7n) / 8n)));
+                    words.forEach((word, idx) => stateImports.env.write_i64(buf + BigInt(idx * 8), word));
+                    return len;
+                }},
+                syscall_exit(status) {{
+                    throw new Error(`Callback exited with status ${{status}}`);
+                }},
+                syscall_exit_group(status) {{
+                    throw new Error(`Callback exited with status ${{status}}`);
+                }},
+                // Read by rdtsc: nanoseconds since the page loaded, as a BigInt
+                now_ns() {{
+                    return BigInt(Math.round(performance.now() * 1e6));
+                }},
+            }},
+        }};
+
+        // Objects handed to modules transpiled with `state_access = "handles"`
+        // as externref handles, for their pointer parameters. A handle is a small
+        // object holding its id; the table maps ids to the object's accessors,
+        // so dropping the entry revokes the handle.
+        const handleTable = new Map([
+            [0, {{
+                read: (offset) => stateImports.env.read_i64(BigInt(offset)),
+                write: (offset, value) => stateImports.env.write_i64(BigInt(offset), value),
+            }}],
+        ]);
+        const STATE_HANDLE = Object.freeze({{ id: 0 }});
+
+        function fieldAccessor(kind, ty, offset) {{
+            const object = (handle) => {{
+                const entry = handleTable.get(handle && handle.id);
+                if (!entry) {{
+                    throw new Error('Stale or invalid handle');
+                }}
+                return entry;
+            }};
+            // Words are BigInts; doubles are reinterpreted, not converted
+            const toDouble = (word) => new Float64Array(new BigInt64Array([word]).buffer)[0];
+            const fromDouble = (value) => new BigInt64Array(new Float64Array([value]).buffer)[0];
+            if (kind === 'get') {{
+                if (ty === 'f64') {{
+                    return (handle) => toDouble(object(handle).read(offset));
+                }}
+                return ty === 'i32'
+                    ? (handle) => Number(BigInt.asIntN(32, object(handle).read(offset)))
+                    : (handle) => object(handle).read(offset);
+            }}
+            if (ty === 'f64') {{
+                return (handle, value) => object(handle).write(offset, fromDouble(value));
+            }}
+            return ty === 'i32'
+                ? (handle, value) => object(handle).write(offset, BigInt(value))
+                : (handle, value) => object(handle).write(offset, value);
+        }}
+
+        function trapReason(code) {{
+            if (code >= 256) {{
+                return `unsupported syscall ${{code - 256}}`;
+            }}
+            return {{ 3: 'breakpoint', 6: 'invalid instruction (panic, failed assertion or untranslated instruction)' }}[code]
+                || `interrupt ${{code}}`;
+        }}
+
+        // Posts an error report to the server's telemetry, which the admin
+        // dashboard lists
+        function reportError(report) {{
+            const csrfToken = document.querySelector('meta[name="csrf-token"]').content;
+            fetch('/telemetry', {{
+                method: 'POST',
+                headers: {{ 'Content-Type': 'application/json', '{csrf_header}': csrfToken }},
+                body: JSON.stringify({{ ...report, user_agent: navigator.userAgent }}),
+                keepalive: true,
+            }}).catch(() => {{}});
+        }}
+
+        // env.on_trap of the code loaded from moduleUrl: logs the trap with
+        // the address of the machine instruction it was translated from, in
+        // two i32 halves, and reports it
+        function onTrap(fnName, moduleUrl) {{
+            return (code, low, high) => {{
+                const address = `0x${{((BigInt(high >>> 0) << 32n) | BigInt(low >>> 0)).toString(16)}}`;
+                const reason = trapReason(code);
+                console.error(`Callback ${{fnName}} trapped at ${{address}}: ${{reason}}`);
+                reportError({{ callback: fnName, module: moduleUrl, trap: {{ code, address, reason }} }});
+            }};
+        }}
+
+        // State imports plus the env.get_<ty>_<offset> / env.set_<ty>_<offset>
+        // field accessors a handles-mode module imports
+        function importsFor(module, fnName) {{
+            const env = {{ ...stateImports.env, on_trap: onTrap(fnName, `/wasm/${{fnName}}`) }};
+            for (const {{ module: namespace, name, kind }} of WebAssembly.Module.imports(module)) {{
+                const accessor = /^(get|set)_(i32|i64|f64)_(-?\d+)$/.exec(name);
+                if (namespace === 'env' && kind === 'function' && accessor) {{
+                    env[name] = fieldAccessor(accessor[1], accessor[2], Number(accessor[3]));
+                }}
+            }}
+            return {{ env }};
+        }}
+
+        // Shared runtime instances by URL, see `runtime.rs`
+        const runtimes = new Map();
+
+        // Instantiates module, wiring it to the shared runtime at runtimeUrl
+        // if its selfserve.abi section names a heap base to reserve there
+        async function instantiate(module, fnName, runtimeUrl) {{
+            const imports = importsFor(module, fnName);
+            const [abi] = WebAssembly.Module.customSections(module, 'selfserve.abi');
+            const heapBase = abi && JSON.parse(new TextDecoder().decode(abi)).runtime;
+            if (heapBase != null) {{
+                if (!runtimes.has(runtimeUrl)) {{
+                    runtimes.set(runtimeUrl, fetch(runtimeUrl).then(async (response) => {{
+                        if (!response.ok) {{
+                            throw new Error(`Could not load ${{runtimeUrl}}: ${{response.status}}`);
+                        }}
+                        return (await WebAssembly.instantiate(await response.arrayBuffer())).instance;
+                    }}));
+                }}
+                const runtime = await runtimes.get(runtimeUrl);
+                if (runtime.exports.reserve(heapBase) === 0) {{
+                    throw new Error(`Could not reserve ${{heapBase}} bytes of ${{runtimeUrl}}`);
+                }}
+                imports.runtime = runtime.exports;
+            }}
+            return await WebAssembly.instantiate(module, imports);
+        }}
+
+        // Lowest allocated byte of the scratch space per instance, for
+        // modules without an allocator. It grows down from the end of linear
+        // memory and is released after every call.
+        const scratchTops = new WeakMap();
+
+        // size bytes of the module's linear memory, from its exported
+        // alloc(size, align) if it has one
+        function allocate(instance, size, align) {{
+            const {{ alloc, memory }} = instance.exports;
+            if (alloc) {{
+                const ptr = Number(alloc(size, align));
+                if (ptr === 0) {{
+                    throw new Error(`Could not allocate ${{size}} bytes`);
+                }}
+                return ptr;
+            }}
+            const top = scratchTops.get(instance) ?? memory.buffer.byteLength;
+            const ptr = (top - size) & ~(align - 1);
+            if (ptr < 0) {{
+                throw new Error(`Could not allocate ${{size}} bytes`);
+            }}
+            scratchTops.set(instance, ptr);
+            return ptr;
+        }}
+
+        function releaseAll(instance) {{
+            scratchTops.delete(instance);
+            if (instance.exports.reset) {{
+                instance.exports.reset();
+            }}
+        }}
+
+        // Encodes text as UTF-8 into linear memory, for (ptr, len) parameters
+        function writeString(instance, text) {{
+            const bytes = new TextEncoder().encode(text);
+            const ptr = allocate(instance, Math.max(bytes.length, 1), 1);
+            new Uint8Array(instance.exports.memory.buffer, ptr, bytes.length).set(bytes);
+            return [ptr, bytes.length];
+        }}
+
+        // Copies a TypedArray into linear memory, for (ptr, len) parameters
+        // counting elements
+        function writeArray(instance, values) {{
+            const ptr = allocate(instance, Math.max(values.byteLength, 1), values.BYTES_PER_ELEMENT);
+            new Uint8Array(instance.exports.memory.buffer, ptr, values.byteLength)
+                .set(new Uint8Array(values.buffer, values.byteOffset, values.byteLength));
+            return [ptr, values.length];
+        }}
+
+        function decodeString(memory, ptr, len) {{
+            return new TextDecoder().decode(new Uint8Array(memory.buffer, ptr, len));
+        }}
+
+        // Reads a UTF-8 string written as (ptr, len) through two 4-byte out-pointers
+        function readString(memory, ptrSlot, lenSlot) {{
+            const view = new DataView(memory.buffer);
+            return decodeString(memory, view.getUint32(ptrSlot, true), view.getUint32(lenSlot, true));
+        }}
+
+        // Reads a value of a layout manifest field type (see `layout.rs`) at
+        // offset. 64-bit integers are BigInts.
+        function readValue(view, offset, type) {{
+            switch (type.kind) {{
+                case 'i8': return view.getInt8(offset);
+                case 'u8': return view.getUint8(offset);
+                case 'i16': return view.getInt16(offset, true);
+                case 'u16': return view.getUint16(offset, true);
+                case 'i32': return view.getInt32(offset, true);
+                case 'u32': return view.getUint32(offset, true);
+                case 'i64': return view.getBigInt64(offset, true);
+                case 'u64': return view.getBigUint64(offset, true);
+                case 'f32': return view.getFloat32(offset, true);
+                case 'f64': return view.getFloat64(offset, true);
+                case 'bool': return view.getUint8(offset) !== 0;
+                case 'array':
+                    return Array.from({{ length: type.len }}, (_, idx) => readValue(view, offset + idx * type.stride, type.element));
+                case 'struct': return readStruct(view, offset, type);
+                default: throw new Error(`Unknown field type ${{type.kind}}`);
+            }}
+        }}
+
+        function readStruct(view, offset, layout) {{
+            const value = {{}};
+            for (const field of layout.fields) {{
+                value[field.name] = readValue(view, offset + field.offset, field.type);
+            }}
+            return value;
+        }}
+
+        // Accessors reading and writing the fields of a value of the given
+        // layout manifest type at offset of the view: numbers and booleans
+        // for scalar fields, nested accessors for arrays and structs, so
+        // `state.todos[1].completed` is a bool read straight from the buffer.
+        // Views on WASM memory detach when it grows; take new accessors then.
+        function accessor(view, offset, type) {{
+            if (type.kind === 'array') {{
+                const elements = [];
+                for (let idx = 0; idx < type.len; idx++) {{
+                    field(elements, idx, view, offset + idx * type.stride, type.element);
+                }}
+                return Object.freeze(elements);
+            }}
+            const fields = {{}};
+            for (const {{ name, offset: fieldOffset, type: fieldType }} of type.fields) {{
+                field(fields, name, view, offset + fieldOffset, fieldType);
+            }}
+            return Object.freeze(fields);
+        }}
+
+        function field(object, key, view, offset, type) {{
+            if (type.kind === 'array' || type.kind === 'struct') {{
+                const nested = accessor(view, offset, type);
+                Object.defineProperty(object, key, {{ get: () => nested, enumerable: true }});
+                return;
+            }}
+            Object.defineProperty(object, key, {{
+                get: () => readValue(view, offset, type),
+                set: (value) => writeValue(view, offset, type, value),
+                enumerable: true,
+            }});
+        }}
+
+        function writeValue(view, offset, type, value) {{
+            switch (type.kind) {{
+                case 'i8': return view.setInt8(offset, value);
+                case 'u8': return view.setUint8(offset, value);
+                case 'i16': return view.setInt16(offset, value, true);
+                case 'u16': return view.setUint16(offset, value, true);
+                case 'i32': return view.setInt32(offset, value, true);
+                case 'u32': return view.setUint32(offset, value, true);
+                case 'i64': return view.setBigInt64(offset, BigInt(value), true);
+                case 'u64': return view.setBigUint64(offset, BigInt(value), true);
+                case 'f32': return view.setFloat32(offset, value, true);
+                case 'f64': return view.setFloat64(offset, value, true);
+                case 'bool': return view.setUint8(offset, value ? 1 : 0);
+                default: throw new Error(`Cannot assign a ${{type.kind}} field`);
+            }}
+        }}
+
+        // Accessors for the state image at offset of buffer, e.g. the linear
+        // memory of a module the state pointer was passed into
+        function stateAccessor(buffer, offset = 0) {{
+            return accessor(new DataView(buffer), offset, STATE_LAYOUT);
+        }}
+
+        // Accessors for a copy of the server's current state
+        async function fetchState() {{
+            const response = await fetch('/api/state/bytes');
+            if (!response.ok) {{
+                throw new Error(`Could not fetch the state: ${{response.status}}`);
+            }}
+            return stateAccessor(await response.arrayBuffer());
+        }}
+
+        // Calls an export with the client arguments, strings and TypedArrays
+        // passed as (ptr, len) pairs in linear memory, and out-pointer slots
+        // at the end of linear memory, and decodes its result according to
+        // the callback's descriptor. statePtr goes first, unless it is null.
+        function callWasm(instance, exportName, descriptor, statePtr, ...args) {{
+            try {{
+                return callWasmWith(instance, exportName, descriptor, statePtr, args);
+            }} finally {{
+                releaseAll(instance);
+            }}
+        }}
+
+        function callWasmWith(instance, exportName, descriptor, statePtr, args) {{
+            const memory = instance.exports.memory;
+            const inMemory = (arg) => typeof arg === 'string' || ArrayBuffer.isView(arg);
+            if (!memory && args.some(inMemory)) {{
+                throw new Error(`${{exportName}} takes a string or array but has no memory to pass it in`);
+            }}
+            const params = args.flatMap((arg) => {{
+                if (typeof arg === 'string') {{
+                    return writeString(instance, arg);
+                }}
+                return ArrayBuffer.isView(arg) ? writeArray(instance, arg) : [arg];
+            }});
+            const state = statePtr === null ? [] : [statePtr];
+            const fn = (...outPtrs) => instance.exports[exportName](...state, ...params, ...outPtrs);
+            if (descriptor.kind === 'i32' || descriptor.kind === 'f64' || !memory) {{
+                return fn();
+            }}
+            if (descriptor.kind === 'struct') {{
+                const {{ layout }} = descriptor;
+                const out = allocate(instance, layout.size, layout.align);
+                const status = fn(out);
+                if (status !== 0) {{
+                    throw new Error(`Callback failed with status ${{status}}`);
+                }}
+                return readStruct(new DataView(memory.buffer), out, layout);
+            }}
+            const scratch = allocate(instance, 8, 4);
+            const returned = fn(scratch, scratch + 4);
+            // Modules with multi-value results return the out-parameters
+            // after the status instead of writing them, see `multivalue.rs`
+            if (Array.isArray(returned)) {{
+                const [status, ptr, len] = returned;
+                if (Number(status) !== 0) {{
+                    throw new Error(`Callback failed with status ${{status}}`);
+                }}
+                return decodeString(memory, Number(ptr), Number(len));
+            }}
+            if (returned !== 0) {{
+                throw new Error(`Callback failed with status ${{returned}}`);
+            }}
+            return readString(memory, scratch, scratch + 4);
+        }}
+
+        // Results of /execute, or of a module, whose lowering returns rax
+        // as an i64, i.e. a BigInt
+        function decodeResult(descriptor, result) {{
+            switch (descriptor.kind) {{
+                case 'i32': return typeof result === 'bigint' ? Number(BigInt.asIntN(32, result)) : result | 0;
+                case 'f64': return Number(result);
+                case 'string': return String(result);
+                default: return result;
+            }}
+        }}
+
+        // WebAssembly.validate rejects modules using features the browser
+        // lacks, which makes it the feature detection
+        function wasmSupported(bytes) {{
+            return typeof WebAssembly === 'object' && WebAssembly.validate(bytes);
+        }}
+
+        function featureSupported(feature) {{
+            if (!(feature in featureSupport)) {{
+                const probe = FEATURE_PROBES[feature];
+                let supported = probe !== undefined && wasmSupported(new Uint8Array(probe));
+                if (feature === 'threads') {{
+                    // Shared memory also needs a cross-origin isolated page
+                    supported = supported && typeof SharedArrayBuffer === 'function';
+                }}
+                featureSupport[feature] = supported;
+            }}
+            return featureSupport[feature];
+        }}
+
+        // Whether the browser has every proposal the module of fnName needs
+        async function requirementsMet(fnName) {{
+            const response = await fetch(`/wasm/${{fnName}}/requirements`);
+            if (!response.ok) {{
+                return false;
+            }}
+            const {{ features }} = await response.json();
+            return features.every(featureSupported);
+        }}
+
+        // Content-hashed module URLs, which the browser caches for good, see
+        // `manifest.rs`. The manifest is revalidated with its ETag once per
+        // page load.
+        let moduleUrls = null;
+        async function moduleUrl(fnName) {{
+            if (!moduleUrls) {{
+                const response = await fetch('/wasm/manifest.json', {{ cache: 'no-cache' }});
+                moduleUrls = response.ok ? (await response.json()).modules : {{}};
+            }}
+            return moduleUrls[fnName] || `/wasm/${{fnName}}`;
+        }}
+
+        // Fixes the session_globals of the session, so the modules loaded
+        // from now on have their values baked in, see `specialize.rs`. An
+        // empty object clears them.
+        async function setSessionGlobals(globals) {{
+            const csrfToken = document.querySelector('meta[name="csrf-token"]').content;
+            const response = await fetch('/session/globals', {{
+                method: 'POST',
+                headers: {{ 'Content-Type': 'application/json', '{csrf_header}': csrfToken }},
+                body: JSON.stringify(globals),
+            }});
+            if (!response.ok) {{
+                throw new Error(`Could not set the session globals: ${{response.status}}`);
+            }}
+            moduleUrls = null;
+            localInstances.clear();
+        }}
+
+        // Instantiates the WASM module, or the JavaScript fallback behind the
+        // same `exports.callback` interface. Browsers missing a proposal that
+        // /wasm/{{fn_name}}/requirements lists (SIMD, threads, ...), see
+        // `features.rs`, or failing to validate the module get the fallback.
+        async function loadCallback(fnName) {{
+            const wasmResponse = (await requirementsMet(fnName)) ? await fetch(await moduleUrl(fnName)) : null;
+            if (wasmResponse && wasmResponse.ok) {{
+                const bytes = await wasmResponse.arrayBuffer();
+                if (wasmSupported(bytes)) {{
+                    const module = await WebAssembly.compile(bytes);
+                    return await instantiate(module, fnName, '/wasm/runtime.wasm');
+                }}
+            }}
+            // Imported as a module, as the page's policy allows no eval
+            let factory;
+            try {{
+                factory = (await import(`/js/${{fnName}}?module=true`)).default;
+            }} catch (e) {{
+                return null;
+            }}
+            const heap = new DataView(new ArrayBuffer(65536));
+            const env = {{ ...stateImports.env, on_trap: onTrap(fnName, `/js/${{fnName}}`) }};
+            return {{ exports: {{ callback: factory(env, heap) }} }};
+        }}
+
+        // Calls symbol of the [binaries.<namespace>] binary. With a signature
+        // declared in its signatures file (see `signatures.rs`), which the
+        // module's selfserve.meta section carries, the arguments are converted
+        // to the declared types and the result to a Number, BigInt or undefined
+        // for "void"; without one the raw WASM values are passed. The client
+        // supplies neither state pointers, the state image is copied to
+        // address 0 instead, nor output buffers, which are allocated and
+        // returned as {{ result, out: [Uint8Array, ...] }}.
+        async function callSymbol(namespace, symbol, ...args) {{
+            const path = `${{namespace}}/${{symbol}}`;
+            const response = await fetch(`/wasm/${{path}}`);
+            if (!response.ok) {{
+                throw new Error(`Could not load ${{path}}: ${{response.status}}`);
+            }}
+            const module = await WebAssembly.compile(await response.arrayBuffer());
+            const instance = await instantiate(module, path, `/wasm/${{namespace}}/runtime.wasm`);
+            const [meta] = WebAssembly.Module.customSections(module, 'selfserve.meta');
+            const signature = meta && JSON.parse(new TextDecoder().decode(meta)).options.signature;
+            if (!signature) {{
+                return instance.exports.callback(...args);
+            }}
+            const isOut = (param) => typeof param === 'object';
+            const supplied = signature.params.filter((param) => param !== 'state' && !isOut(param));
+            if (args.length !== supplied.length) {{
+                throw new Error(`${{path}} takes ${{supplied.length}} arguments, got ${{args.length}}`);
+            }}
+            const memory = instance.exports.memory;
+            if (!memory && signature.params.some((param) => param === 'state' || param === 'in' || isOut(param))) {{
+                throw new Error(`${{path}} takes a buffer but has no memory to pass it in`);
+            }}
+            if (signature.params.includes('state')) {{
+                const state = await fetch('/api/state/bytes');
+                if (!state.ok) {{
+                    throw new Error(`Could not fetch the state: ${{state.status}}`);
+                }}
+                const image = new Uint8Array(await state.arrayBuffer());
+                new Uint8Array(memory.buffer, 0, image.length).set(image);
+            }}
+            try {{
+                // [kind, value] of every WASM parameter
+                const outs = [];
+                let next = 0;
+                const params = signature.params.flatMap((param) => {{
+                    if (param === 'state') {{
+                        return [];
+                    }}
+                    if (isOut(param)) {{
+                        const ptr = allocate(instance, Math.max(param.out, 1), 8);
+                        outs.push([ptr, param.out]);
+                        return [['int', ptr]];
+                    }}
+                    const arg = args[next++];
+                    if (param === 'in') {{
+                        const [ptr, len] = typeof arg === 'string' ? writeString(instance, arg) : writeArray(instance, arg);
+                        return [['int', ptr], ['int', len]];
+                    }}
+                    return [[param === 'f64' ? 'f64' : 'int', arg]];
+                }});
+                // Integers are as wide as the registers of the binary, which
+                // the JS API does not tell; a value of the wrong width is
+                // rejected with a TypeError before the function runs
+                const call = (wide) => instance.exports.callback(
+                    ...params.map(([kind, value]) => (kind === 'int' && wide ? BigInt(value) : Number(value))),
+                );
+                let result;
+                try {{
+                    result = call(true);
+                }} catch (e) {{
+                    if (!(e instanceof TypeError)) {{
+                        throw e;
+                    }}
+                    result = call(false);
+                }}
+                switch (signature.returns) {{
+                    case 'void': result = undefined; break;
+                    case 'f64': break;
+                    case 'i32': result = Number(BigInt.asIntN(32, BigInt(result))); break;
+                    default: result = BigInt(result);
+                }}
+                if (outs.length === 0) {{
+                    return result;
+                }}
+                return {{ result, out: outs.map(([ptr, size]) => new Uint8Array(memory.buffer, ptr, size).slice()) }};
+            }} finally {{
+                if (memory) {{
+                    releaseAll(instance);
+                }}
+            }}
+        }}
+
+        // Violations of fnName's validation rules by args, as the server
+        // would report them. Arguments with violations are not sent.
+        function validateArgs(fnName, args) {{
+            const violations = [];
+            (VALIDATION_RULES[fnName] || []).forEach((rules, param) => {{
+                const arg = jsonArgument(args[param]);
+                for (const rule of rules) {{
+                    const message = ruleViolation(rule, arg);
+                    if (message) {{
+                        violations.push({{ param, rule: rule.rule, message }});
+                    }}
+                }}
+            }});
+            return violations;
+        }}
+
+        function ruleViolation(rule, value) {{
+            if (rule.rule === 'range' && Array.isArray(value)) {{
+                return value.map((element) => ruleViolation(rule, element)).find(Boolean);
+            }}
+            if (rule.rule === 'range' && typeof value === 'number') {{
+                const outside = (rule.min !== null && value < rule.min) || (rule.max !== null && value > rule.max);
+                return outside ? `${{value}} is not within [${{rule.min ?? '...'}}, ${{rule.max ?? '...'}}]` : null;
+            }}
+            if (rule.rule === 'max_length' && typeof value === 'string') {{
+                const length = [...value].length;
+                return length > rule.max ? `${{length}} characters, at most ${{rule.max}} are allowed` : null;
+            }}
+            if (rule.rule === 'max_length' && Array.isArray(value)) {{
+                return value.length > rule.max ? `${{value.length}} elements, at most ${{rule.max}} are allowed` : null;
+            }}
+            if (rule.rule === 'pattern' && typeof value === 'string') {{
+                return new RegExp(rule.pattern, 'u').test(value) ? null : `does not match ${{rule.pattern}}`;
+            }}
+            return null;
+        }}
+
+        function jsonArgument(arg) {{
+            return ArrayBuffer.isView(arg) ? Array.from(arg, Number) : arg;
+        }}
+
+        // Client arguments, such as the text of a string parameter, are sent
+        // to /execute as a JSON array, TypedArrays as arrays of numbers.
+        // Read-only callbacks are executed with GET, without a CSRF token.
+        async function executeCallback(fnName, ...args) {{
+            const descriptor = RETURN_TYPES[fnName];
+            const violations = validateArgs(fnName, args);
+            if (violations.length) {{
+                // Not sent, the server would reject them too
+                const messages = violations.map(({{ param, message }}) => `argument ${{param}} ${{message}}`);
+                document.getElementById('result').textContent = `Invalid arguments: ${{messages.join('; ')}}`;
+                return;
+            }}
+            try {{
+                // Built-in callbacks such as set_locale have no module
+                if (descriptor) {{
+                    await loadCallback(fnName);
+                }}
+
+                // The state lives on the server, so the callback is executed there
+                const csrfToken = document.querySelector('meta[name="csrf-token"]').content;
+                const readOnly = READ_ONLY.has(fnName);
+                const query = readOnly && args.length ? `?args=${{encodeURIComponent(JSON.stringify(args.map(jsonArgument)))}}` : '';
+                const response = await fetch(`/execute/${{fnName}}${{query}}`, readOnly ? undefined : {{
+                    method: 'POST',
+                    headers: {{ 'Content-Type': 'application/json', '{csrf_header}': csrfToken }},
+                    body: args.length ? JSON.stringify(args.map(jsonArgument)) : undefined,
+                }});
+                const body = await response.json();
+                if (!readOnly) {{
+                    stateCache.clear();
+                }}
+                if (!response.ok) {{
+                    const error = new Error(body.message);
+                    error.requestId = body.request_id;
+                    throw error;
+                }}
+                const result = descriptor ? decodeResult(descriptor, body.result) : body.result;
+
+                if (!descriptor || descriptor.kind === 'i32') {{
+                    // Reload the page to show updated state
+                    window.location.reload();
+                }} else {{
+                    document.getElementById('result').textContent = JSON.stringify(result, null, 2);
+                }}
+            }} catch (e) {{
+                console.error('Error executing callback:', e);
+                reportError({{ callback: fnName, message: String(e), request_id: e.requestId }});
+            }}
+        }}
+
+        // The page's callback forms post to /execute without JavaScript; here
+        // they run through executeCallback with their "arg" fields instead,
+        // see `callback_form` in lib.rs
+        document.addEventListener('submit', (event) => {{
+            const form = event.target.closest('form[data-callback]');
+            if (!form) {{
+                return;
+            }}
+            event.preventDefault();
+            executeCallback(form.dataset.callback, ...new FormData(form).getAll('arg'));
+        }});
+
+        // What executing fnName would change, without committing it, see
+        // `preview.rs`:
+        // {{ version, result, state: [{{ path, before, after }}], dom: [changes] }}.
+        // For confirmation dialogs, e.g.
+        // if ((await previewCallback('reset_counter')).state.length && confirm(...))
+        async function previewCallback(fnName, ...args) {{
+            const csrfToken = document.querySelector('meta[name="csrf-token"]').content;
+            const response = await fetch(`/preview/${{fnName}}`, {{
+                method: 'POST',
+                headers: {{ 'Content-Type': 'application/json', '{csrf_header}': csrfToken }},
+                body: args.length ? JSON.stringify(args.map(jsonArgument)) : undefined,
+            }});
+            const body = await response.json();
+            if (!response.ok) {{
+                throw new Error(body.message);
+            }}
+            return body;
+        }}
+
+        // Byte ranges that differ between two copies of the state image
+        function diffState(before, after) {{
+            const patch = [];
+            for (let idx = 0; idx < after.length; idx++) {{
+                if (before[idx] === after[idx]) {{
+                    continue;
+                }}
+                const last = patch[patch.length - 1];
+                if (last && last.offset + last.bytes.length === idx) {{
+                    last.bytes.push(after[idx]);
+                }} else {{
+                    patch.push({{ offset: idx, bytes: [after[idx]] }});
+                }}
+            }}
+            return patch;
+        }}
+
+        // Reports a callback executed in the browser, on the state image of
+        // the given version, so the server applies its changes (see
+        // `sync.rs`). Rejects with the server's conflict body, its current
+        // version and state, if the patch is stale or does not match native
+        // execution. Under the paranoid policy the whole linear memory after
+        // the call goes along, for the server to compare byte by byte.
+        const PARANOID = document.querySelector('meta[name="sync-policy"]') !== null;
+
+        function base64Bytes(bytes) {{
+            let binary = '';
+            for (let i = 0; i < bytes.length; i += 0x8000) {{
+                binary += String.fromCharCode(...bytes.subarray(i, i + 0x8000));
+            }}
+            return btoa(binary);
+        }}
+
+        async function syncState(fnName, args, version, before, after, image) {{
+            const csrfToken = document.querySelector('meta[name="csrf-token"]').content;
+            const response = await fetch('/sync', {{
+                method: 'POST',
+                headers: {{ 'Content-Type': 'application/json', '{csrf_header}': csrfToken }},
+                body: JSON.stringify({{
+                    callback: fnName,
+                    args: args.map(jsonArgument),
+                    version,
+                    state_patch: diffState(before, after),
+                    memory_image: image ? base64Bytes(image) : undefined,
+                }}),
+            }});
+            if (!response.ok) {{
+                const error = new Error(`Sync of ${{fnName}} rejected: ${{response.status}}`);
+                error.status = response.status;
+                error.body = await response.json().catch(() => null);
+                throw error;
+            }}
+            return await response.json();
+        }}
+
+        // Local replica for optimistic execution: the last state image the
+        // server confirmed, its version, and the invocations not synced yet,
+        // each with the state it ran on and the state it produced
+        const replica = {{ version: null, base: null, pending: [], syncing: null }};
+        const localInstances = new Map();
+        let mergeHook = null;
+
+        // Sets the merge hook called for every pending invocation when a sync
+        // is rejected, with {{ callback, args, base, local, server }}: the
+        // state the invocation ran on, the state it produced and the state it
+        // now applies to. Returning bytes uses them as its result, returning
+        // null drops the invocation, returning undefined re-executes it.
+        function onSyncConflict(hook) {{
+            mergeHook = hook;
+        }}
+
+        async function resetReplica() {{
+            const response = await fetch('/api/state/bytes');
+            if (!response.ok) {{
+                throw new Error(`Could not fetch the state: ${{response.status}}`);
+            }}
+            replica.version = Number(response.headers.get('X-State-Version'));
+            replica.base = new Uint8Array(await response.arrayBuffer());
+            replica.pending = [];
+        }}
+
+        // The replica's state with all pending invocations applied
+        function replicaState() {{
+            const last = replica.pending[replica.pending.length - 1];
+            return last ? last.after : replica.base;
+        }}
+
+        // Remote debugging, see `debug.rs`: on pages with a debug-channel meta
+        // tag every callback run in the browser is reported with the linear
+        // memory it changed
+        const debugMeta = document.querySelector('meta[name="debug-channel"]');
+        const debugChannel = debugMeta
+            ? new WebSocket(`${{location.protocol === 'https:' ? 'wss' : 'ws'}}://${{location.host}}${{debugMeta.content}}`)
+            : null;
+
+        // Byte ranges where two memories differ, joining ranges less than
+        // 8 bytes apart, as hex
+        function memoryChanges(before, after) {{
+            const hex = (bytes) => Array.from(bytes, (byte) => byte.toString(16).padStart(2, '0')).join('');
+            const length = Math.max(before.length, after.length);
+            const changes = [];
+            let start = -1;
+            let last = -1;
+            for (let i = 0; i <= length; i++) {{
+                const differs = i < length && (before[i] || 0) !== (after[i] || 0);
+                if (differs && start < 0) {{
+                    start = i;
+                }}
+                if (differs) {{
+                    last = i;
+                }}
+                if (start >= 0 && (i === length || i - last >= 8)) {{
+                    const end = last + 1;
+                    const padded = (bytes) => {{
+                        const range = new Uint8Array(end - start);
+                        range.set(bytes.subarray(start, Math.min(end, bytes.length)));
+                        return range;
+                    }};
+                    changes.push({{ offset: start, before: hex(padded(before)), after: hex(padded(after)) }});
+                    start = -1;
+                }}
+            }}
+            return changes;
+        }}
+
+        function reportInvocation(fnName, args, outcome, snapshot, memory, stateLength) {{
+            if (debugChannel.readyState !== WebSocket.OPEN) {{
+                return;
+            }}
+            const after = new Uint8Array(memory.buffer);
+            debugChannel.send(JSON.stringify({{
+                callback: fnName,
+                args: args.map(jsonArgument),
+                ...outcome,
+                before: Array.from(snapshot.subarray(0, stateLength)),
+                after: Array.from(after.subarray(0, stateLength)),
+                memory: memoryChanges(snapshot, after),
+            }}));
+        }}
+
+        // Runs fnName's module on a copy of state, the image at address 0 of
+        // its linear memory, and returns the result and the new image, with
+        // the whole memory under the paranoid policy
+        async function runLocally(fnName, state, args) {{
+            if (!localInstances.has(fnName)) {{
+                localInstances.set(fnName, await loadCallback(fnName));
+            }}
+            const instance = localInstances.get(fnName);
+            const memory = instance && instance.exports.memory;
+            if (!memory) {{
+                throw new Error(`${{fnName}} has no linear memory to run on a state replica`);
+            }}
+            new Uint8Array(memory.buffer, 0, state.length).set(state);
+            const snapshot = debugChannel && new Uint8Array(memory.buffer).slice();
+            const report = (outcome) => snapshot && reportInvocation(fnName, args, outcome, snapshot, memory, state.length);
+            const descriptor = RETURN_TYPES[fnName];
+            let result;
+            try {{
+                // The state image is at the base of linear memory
+                result = decodeResult(descriptor, callWasm(instance, 'callback', descriptor, STATE_PARAM.has(fnName) ? 0 : null, ...args));
+            }} catch (e) {{
+                report({{ error: String(e) }});
+                throw e;
+            }}
+            report({{ result }});
+            return {{
+                result,
+                state: new Uint8Array(memory.buffer, 0, state.length).slice(),
+                image: PARANOID ? new Uint8Array(memory.buffer).slice() : null,
+            }};
+        }}
+
+        // Executes fnName in the browser and resolves to its result right
+        // away, with a `synced` promise that settles once the server accepted
+        // every queued invocation
+        async function executeOptimistic(fnName, ...args) {{
+            if (replica.base === null) {{
+                await resetReplica();
+            }}
+            const before = replicaState();
+            const {{ result, state, image }} = await runLocally(fnName, before, args);
+            replica.pending.push({{ fnName, args, before, after: state, image }});
+            window.dispatchEvent(new CustomEvent('selfserve:state', {{ detail: {{ version: replica.version, local: true }} }}));
+            return {{ result, synced: flushPending() }};
+        }}
+
+        function flushPending() {{
+            if (!replica.syncing) {{
+                replica.syncing = syncPending().finally(() => {{
+                    replica.syncing = null;
+                }});
+            }}
+            return replica.syncing;
+        }}
+
+        async function syncPending() {{
+            while (replica.pending.length) {{
+                const entry = replica.pending[0];
+                try {{
+                    const {{ version }} = await syncState(entry.fnName, entry.args, replica.version, entry.before, entry.after, entry.image);
+                    replica.version = version;
+                    replica.base = entry.after;
+                    replica.pending.shift();
+                }} catch (e) {{
+                    if (e.status !== 409 || !e.body) {{
+                        // The server did not take the invocation, start over from its state
+                        await resetReplica();
+                        throw e;
+                    }}
+                    if (e.body.detail.reason === 'mismatch') {{
+                        // Native execution disagrees, so the server's result wins
+                        console.warn(`Dropping ${{entry.fnName}}: ${{e.body.message}}`);
+                        replica.pending.shift();
+                    }}
+                    await rebase(e.body.detail);
+                }}
+            }}
+        }}
+
+        // Re-applies the pending invocations on top of the state of a 409
+        async function rebase(conflict) {{
+            const server = new Uint8Array(conflict.state);
+            const pending = [];
+            let state = server;
+            for (const entry of replica.pending) {{
+                let after = mergeHook
+                    ? await mergeHook({{ callback: entry.fnName, args: entry.args, base: entry.before, local: entry.after, server: state }})
+                    : undefined;
+                if (after === null) {{
+                    continue;
+                }}
+                // A merged state stands in for the memory it did not run in
+                let image = PARANOID && after !== undefined ? new Uint8Array(after) : null;
+                if (after === undefined) {{
+                    ({{ state: after, image }} = await runLocally(entry.fnName, state, entry.args));
+                }}
+                pending.push({{ ...entry, before: state, after: new Uint8Array(after), image }});
+                state = pending[pending.length - 1].after;
+            }}
+            replica.version = conflict.version;
+            replica.base = server;
+            replica.pending = pending;
+            window.dispatchEvent(new CustomEvent('selfserve:state', {{ detail: {{ version: replica.version, local: true }} }}));
+        }}
+
+        const events = new EventSource('/events');
+        // The server pushes a reload event after re-transpiling changed callbacks
+        events.addEventListener('reload', () => window.location.reload());
+        // And a state event with the new version when a browser synced its
+        // changes; custom frontend code listens for selfserve:state
+        events.addEventListener('state', (event) => {{
+            stateCache.clear();
+            if (!replica.pending.length && replica.version !== Number(event.data)) {{
+                // Refetched by the next optimistic execution
+                replica.base = null;
+            }}
+            window.dispatchEvent(new CustomEvent('selfserve:state', {{ detail: {{ version: Number(event.data) }} }}));
+        }});
+"#
+    )
+}
@@ -0,0 +1,123 @@
+// Callback hooks
+// Closures registered to run around every callback execution: on /execute,
+// in batches, on /sync, on schedule and in previews. Before hooks see the
+// invocation and the current state and can veto it with a message, which is
+// returned to the client as a 422 without the callback running. After hooks
+// get the state from before the call and the new state, which they may
+// update, e.g. to recompute derived fields, before it is committed.
+
+use crate::callbacks::{CallbackError, CallbackValue};
+use crate::State;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    Execute,
+    Batch,
+    Sync,
+    Schedule,
+    /// A dry run of POST /preview, whose state is thrown away
+    Preview,
+}
+
+impl std::str::FromStr for Trigger {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        serde_json::from_value(serde_json::Value::String(s.to_string())).map_err(|_| format!("Unknown trigger: {}", s))
+    }
+}
+
+impl std::fmt::Display for Trigger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Ok(serde_json::Value::String(name)) = serde_json::to_value(self) else { unreachable!("triggers serialize to names") };
+        f.write_str(&name)
+    }
+}
+
+pub struct Invocation<'a> {
+    pub callback: &'a str,
+    pub args: &'a [serde_json::Value],
+    pub trigger: Trigger,
+}
+
+type BeforeHook = Box<dyn Fn(&Invocation, &State) -> Result<(), String> + Send + Sync>;
+type AfterHook = Box<dyn Fn(&Invocation, &State, &mut State) + Send + Sync>;
+
+pub struct Hooks {
+    before: Vec<BeforeHook>,
+    after: Vec<AfterHook>,
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Self { before: Vec::new(), after: Vec::new() }
+    }
+
+    pub fn before(&mut self, hook: impl Fn(&Invocation, &State) -> Result<(), String> + Send + Sync + 'static) -> &mut Self {
+        self.before.push(Box::new(hook));
+        self
+    }
+
+    pub fn after(&mut self, hook: impl Fn(&Invocation, &State, &mut State) + Send + Sync + 'static) -> &mut Self {
+        self.after.push(Box::new(hook));
+        self
+    }
+
+    /// Runs the before hooks in registration order, stopping at the first veto
+    pub fn check(&self, invocation: &Invocation, state: &State) -> Result<(), CallbackError> {
+        self.before.iter().try_for_each(|hook| hook(invocation, state).map_err(CallbackError::Rejected))
+    }
+
+    pub fn finish(&self, invocation: &Invocation, old: &State, new: &mut State) {
+        self.after.iter().for_each(|hook| hook(invocation, old, new));
+    }
+
+    /// `call` on the state between the before and the after hooks
+    pub fn run(
+        &self,
+        invocation: &Invocation,
+        state: &mut State,
+        call: impl FnOnce(&mut State) -> Result<CallbackValue, CallbackError>,
+    ) -> Result<CallbackValue, CallbackError> {
+        self.check(invocation, state)?;
+        if self.after.is_empty() {
+            return call(state);
+        }
+        let old = state.clone();
+        let result = call(state)?;
+        self.finish(invocation, &old, state);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vetoes_and_updates_around_the_call() {
+        let mut hooks = Hooks::new();
+        hooks
+            .before(|invocation, state| match invocation.trigger == Trigger::Batch && state.counter >= 10 {
+                true => Err("counter is at its limit".to_string()),
+                false => Ok(()),
+            })
+            .after(|_, old, new| new.counter = new.counter.max(old.counter * 2));
+        let invocation = Invocation { callback: "increment_counter", args: &[], trigger: Trigger::Batch };
+
+        let mut state = State { counter: 3 };
+        let result = hooks.run(&invocation, &mut state, |state| {
+            state.counter += 1;
+            Ok(CallbackValue::I32(state.counter))
+        });
+        assert!(matches!(result, Ok(CallbackValue::I32(4))));
+        assert_eq!(state.counter, 6);
+
+        let mut state = State { counter: 10 };
+        let result = hooks.run(&invocation, &mut state, |_| unreachable!());
+        assert_eq!(result.unwrap_err().to_string(), "Rejected: counter is at its limit");
+        assert_eq!(state.counter, 10);
+    }
+}
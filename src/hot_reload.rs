@@ -0,0 +1,161 @@
+// Hot reload
+// Watches the callback binary and re-transpiles when it changes. The parent
+// directory is watched instead of the file itself, because build tools
+// usually replace the binary (new inode) instead of writing it in place.
+// The template partials are watched too, so markup edits show up without a
+// restart. So is the config file, whose `[transpiler]` section is applied to
+// every binary; the other sections are read at startup only.
+
+use crate::config::Config;
+use crate::events::Broadcaster;
+use crate::store::StateStore;
+use crate::templates::Templates;
+use crate::transpiler::Transpiler;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+// Linkers write the output in several steps, wait until it settles
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Enabled by default in debug builds, `SELF_SERVE_HOT_RELOAD=0|1` overrides.
+pub fn enabled() -> bool {
+    match std::env::var("SELF_SERVE_HOT_RELOAD") {
+        Ok(value) => value != "0",
+        Err(_) => cfg!(debug_assertions),
+    }
+}
+
+/// Starts watching the transpiler's binary. The returned watcher must be
+/// kept alive for as long as changes should be picked up.
+pub fn watch(
+    transpiler: Arc<Transpiler>,
+    events: Arc<Broadcaster>,
+    state: Arc<StateStore>,
+) -> notify::Result<RecommendedWatcher> {
+    let binary_path = transpiler.binary_path().to_path_buf();
+    let watched_file: PathBuf = binary_path.file_name().map(PathBuf::from).unwrap_or_default();
+    let directory = binary_path
+        .parent()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."));
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |result: notify::Result<notify::Event>| {
+        if let Ok(event) = result {
+            let affects_binary = event
+                .paths
+                .iter()
+                .any(|path| path.file_name() == Some(watched_file.as_os_str()));
+            if affects_binary && (event.kind.is_create() || event.kind.is_modify()) {
+                let _ = tx.send(());
+            }
+        }
+    })?;
+    watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+
+    std::thread::spawn(move || {
+        while rx.recv().is_ok() {
+            // Swallow the burst of events belonging to the same rebuild
+            while rx.recv_timeout(DEBOUNCE).is_ok() {}
+
+            // The state did not change, browsers reload at the same version
+            transpiler.reload_binary();
+            events.broadcast("reload", &state.version().to_string());
+        }
+    });
+
+    tracing::info!(path = %binary_path.display(), "Hot reload enabled");
+    Ok(watcher)
+}
+
+/// Starts watching the template directory, re-reading every partial after a
+/// change. Browsers of this instance reload; other instances watch their own
+/// directories.
+pub fn watch_templates(
+    templates: Arc<Templates>,
+    events: Arc<Broadcaster>,
+    state: Arc<StateStore>,
+) -> notify::Result<RecommendedWatcher> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |result: notify::Result<notify::Event>| {
+        if let Ok(event) = result {
+            if !event.kind.is_access() {
+                let _ = tx.send(());
+            }
+        }
+    })?;
+    watcher.watch(templates.directory(), RecursiveMode::NonRecursive)?;
+
+    let directory = templates.directory().to_path_buf();
+    std::thread::spawn(move || {
+        while rx.recv().is_ok() {
+            // Editors save in several steps too
+            while rx.recv_timeout(DEBOUNCE).is_ok() {}
+
+            match templates.reload() {
+                Ok(()) => events.broadcast_local("reload", &state.version().to_string()),
+                Err(e) => tracing::warn!(error = %e, "Keeping the previous template partials"),
+            }
+        }
+    });
+
+    tracing::info!(path = %directory.display(), "Template hot reload enabled");
+    Ok(watcher)
+}
+
+/// Starts watching the config file at `path`, applying its `[transpiler]`
+/// section to `transpilers` after a change. An invalid file keeps the
+/// previous config.
+pub fn watch_config(
+    path: &Path,
+    transpilers: Vec<Arc<Transpiler>>,
+    events: Arc<Broadcaster>,
+    state: Arc<StateStore>,
+) -> notify::Result<RecommendedWatcher> {
+    let path = path.to_path_buf();
+    let watched_file: PathBuf = path.file_name().map(PathBuf::from).unwrap_or_default();
+    // Editors replace the file too, see `watch`
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |result: notify::Result<notify::Event>| {
+        if let Ok(event) = result {
+            let affects_config = event
+                .paths
+                .iter()
+                .any(|path| path.file_name() == Some(watched_file.as_os_str()));
+            if affects_config && (event.kind.is_create() || event.kind.is_modify()) {
+                let _ = tx.send(());
+            }
+        }
+    })?;
+    watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+
+    let config_path = path.clone();
+    std::thread::spawn(move || {
+        while rx.recv().is_ok() {
+            while rx.recv_timeout(DEBOUNCE).is_ok() {}
+
+            let config = match Config::load_from(&config_path) {
+                Ok(config) => config,
+                Err(e) => {
+                    tracing::warn!(error = %e, "Keeping the previous config");
+                    continue;
+                }
+            };
+            for transpiler in &transpilers {
+                transpiler.reconfigure(&config.transpiler);
+            }
+            tracing::info!(path = %config_path.display(), "Applied [transpiler], other sections take effect on restart");
+            events.broadcast("reload", &state.version().to_string());
+        }
+    });
+
+    tracing::info!(path = %path.display(), "Config hot reload enabled");
+    Ok(watcher)
+}
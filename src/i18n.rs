@@ -0,0 +1,197 @@
+// Internationalization
+// Translation catalogs, one `<locale>.ftl` file per locale in the
+// `[i18n] directory`, loaded at startup. They use a subset of Fluent:
+// `key = text` messages, indented continuation lines, `#` comments and
+// `{ $name }` placeables filled from the arguments of a `DomNode::tr` node.
+// Without the directory, the built-in English catalog is used. The locale of
+// a session comes from its `locale` cookie, set by the built-in `set_locale`
+// callback, then from Accept-Language, then the default locale.
+
+use crate::callbacks::CallbackError;
+use crate::error::ApiError;
+use crate::ServerContext;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Name of the built-in callback switching the session's locale
+pub const SET_LOCALE: &str = "set_locale";
+
+const LOCALE_COOKIE: &str = "locale";
+
+const BUILTIN_LOCALE: (&str, &str) = ("en", include_str!("../locales/en.ftl"));
+
+pub struct Catalog {
+    messages: BTreeMap<String, String>,
+}
+
+impl Catalog {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut messages = BTreeMap::new();
+        let mut last: Option<String> = None;
+        for (idx, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
+            if line.starts_with(char::is_whitespace) {
+                let Some(message) = last.as_ref().and_then(|key| messages.get_mut(key)) else {
+                    return Err(format!("Line {}: continuation without a message", idx + 1));
+                };
+                let message: &mut String = message;
+                message.push('\n');
+                message.push_str(line.trim());
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(format!("Line {}: expected `key = text`", idx + 1));
+            };
+            let key = key.trim().to_string();
+            messages.insert(key.clone(), value.trim().to_string());
+            last = Some(key);
+        }
+        Ok(Self { messages })
+    }
+
+    /// The message with its placeables filled, none if the key is missing
+    pub fn format(&self, key: &str, args: &[(String, String)]) -> Option<String> {
+        let mut text = self.messages.get(key)?.clone();
+        for (name, value) in args {
+            for placeable in [format!("{{ ${} }}", name), format!("{{${}}}", name)] {
+                text = text.replace(&placeable, value);
+            }
+        }
+        Some(text)
+    }
+}
+
+pub struct Catalogs {
+    default_locale: String,
+    catalogs: BTreeMap<String, Catalog>,
+}
+
+impl Catalogs {
+    /// Every `<locale>.ftl` in `directory`, the built-in catalog if it has none
+    pub fn load(config: &crate::config::I18nConfig) -> Result<Self, String> {
+        let mut catalogs = BTreeMap::new();
+        if let Ok(entries) = std::fs::read_dir(&config.directory) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let Some(locale) = locale_of(&path) else { continue };
+                let text = std::fs::read_to_string(&path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+                let catalog = Catalog::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+                catalogs.insert(locale, catalog);
+            }
+        }
+        if catalogs.is_empty() {
+            let (locale, text) = BUILTIN_LOCALE;
+            catalogs.insert(locale.to_string(), Catalog::parse(text)?);
+        }
+        Ok(Self { default_locale: config.default_locale.to_lowercase(), catalogs })
+    }
+
+    pub fn locales(&self) -> impl Iterator<Item = &str> {
+        self.catalogs.keys().map(String::as_str)
+    }
+
+    /// The locale to render in: the cookie's if there is a catalog for it,
+    /// else the first Accept-Language range with one, by quality
+    pub fn negotiate<'a>(&'a self, cookie: Option<&str>, accept_language: Option<&str>) -> &'a str {
+        let mut ranges: Vec<(&str, f32)> = accept_language
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next()?.trim();
+                let quality = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+                Some((tag, quality))
+            })
+            .collect();
+        // Stable, so equal qualities keep their order
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        cookie
+            .into_iter()
+            .chain(ranges.into_iter().filter(|(_, quality)| *quality > 0.0).map(|(tag, _)| tag))
+            .find_map(|tag| self.matching(tag))
+            .or_else(|| self.matching(&self.default_locale))
+            .or_else(|| self.catalogs.keys().next().map(String::as_str))
+            .unwrap_or_default()
+    }
+
+    pub fn locale_for(&self, req: &HttpRequest) -> &str {
+        let cookie = req.cookie(LOCALE_COOKIE);
+        let accept_language = req.headers().get(header::ACCEPT_LANGUAGE).and_then(|value| value.to_str().ok());
+        self.negotiate(cookie.as_ref().map(Cookie::value), accept_language)
+    }
+
+    /// The catalog key matching a language tag exactly or by its primary
+    /// subtag, so `de-AT` gets `de`
+    fn matching(&self, tag: &str) -> Option<&str> {
+        let tag = tag.to_lowercase();
+        let primary = tag.split('-').next().unwrap_or_default();
+        let (locale, _) = self.catalogs.get_key_value(tag.as_str()).or_else(|| self.catalogs.get_key_value(primary))?;
+        Some(locale.as_str())
+    }
+
+    /// The message in `locale`, else in the default locale, else the key
+    pub fn translate(&self, locale: &str, key: &str, args: &[(String, String)]) -> String {
+        [locale, &self.default_locale]
+            .into_iter()
+            .find_map(|locale| self.catalogs.get(locale)?.format(key, args))
+            .unwrap_or_else(|| key.to_string())
+    }
+}
+
+fn locale_of(path: &Path) -> Option<String> {
+    match path.extension()?.to_str()? {
+        "ftl" => Some(path.file_stem()?.to_str()?.to_lowercase()),
+        _ => None,
+    }
+}
+
+/// The built-in `set_locale` callback, taking the locale as its argument.
+/// It changes the session, not the state, so it only sets the cookie.
+pub fn set_locale(ctx: &ServerContext, args: &[serde_json::Value]) -> HttpResponse {
+    let locale = match args {
+        [serde_json::Value::String(locale)] => locale.to_lowercase(),
+        _ => return ApiError::from(CallbackError::Arguments("expected a locale".to_string())).error_response(),
+    };
+    if !ctx.i18n.locales().any(|known| known == locale) {
+        return ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "unknown_locale", format!("No catalog for locale {}", locale)).error_response();
+    }
+    let cookie = Cookie::build(LOCALE_COOKIE, locale.clone())
+        .path("/")
+        .same_site(SameSite::Lax)
+        .permanent()
+        .finish();
+    HttpResponse::Ok()
+        .cookie(cookie)
+        .json(serde_json::json!({ "version": ctx.state.version(), "result": locale }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_and_formats_messages() {
+        let mut catalogs = BTreeMap::new();
+        catalogs.insert("en".to_string(), Catalog::parse("# comment\ncounter = Counter: { $count }\nbye = Bye").unwrap());
+        catalogs.insert("de".to_string(), Catalog::parse("counter = Zähler: {$count}\nmulti = eins\n  zwei").unwrap());
+        let catalogs = Catalogs { default_locale: "en".to_string(), catalogs };
+
+        assert_eq!(catalogs.negotiate(None, Some("fr-CH, de-AT;q=0.8, en;q=0.5")), "de");
+        assert_eq!(catalogs.negotiate(Some("en"), Some("de")), "en");
+        assert_eq!(catalogs.negotiate(Some("xx"), Some("de;q=0")), "en");
+
+        let args = [("count".to_string(), "3".to_string())];
+        assert_eq!(catalogs.translate("de", "counter", &args), "Zähler: 3");
+        assert_eq!(catalogs.translate("de", "bye", &[]), "Bye");
+        assert_eq!(catalogs.translate("de", "multi", &[]), "eins\nzwei");
+        assert_eq!(catalogs.translate("de", "missing", &[]), "missing");
+        assert!(Catalog::parse("no equals sign").is_err());
+    }
+}
@@ -0,0 +1,119 @@
+// Inlining of tiny callees
+// A module holds a single function, so direct calls are dropped while
+// lifting. Callees too small to be worth a module of their own, such as
+// getters and the 3-instruction helpers compilers leave behind, are spliced
+// into the caller instead: with `inline_threshold = n`, a call is replaced by
+// the ops of its callee if that lifts to at most n ops in one block ending
+// in a return, without calls, branches, syscalls or stack accesses of its
+// own. The callee works on the caller's registers, so it reads the
+// arguments the caller left in the argument registers and leaves its result
+// in the return register, as the call did. Callees are looked up among the
+// symbols of linked binaries, see `FunctionIR::callees`.
+
+use crate::backend::FunctionIR;
+use crate::ir::{self, Inst, Op, VReg};
+use crate::transpiler_real::FidelityReport;
+use std::collections::HashMap;
+
+/// Callees larger than this are not decoded at all
+pub const MAX_CALLEE_BYTES: usize = 64;
+
+// The lifted callee, if it qualifies for inlining at `threshold`
+fn body(callee: &FunctionIR, threshold: usize) -> Option<ir::Function> {
+    let mut report = FidelityReport::default();
+    let lifted = ir::lift(callee, &mut report);
+    let [block] = &lifted.blocks[..] else { return None };
+    let inlinable = report.unsupported.is_empty()
+        && report.approximated.is_empty()
+        && lifted.params.is_empty()
+        && lifted.stack_pointer.is_none()
+        && lifted.x87_result.is_none()
+        && matches!(block.insts.last(), Some(Inst { op: Op::Return { .. }, .. }))
+        && block.insts.len() <= threshold + 1;
+    inlinable.then_some(lifted)
+}
+
+// The ops of `callee` up to its return, on the registers of `caller`.
+// Machine registers map to the caller's, flags and temporaries to new ones.
+fn rename(caller: &mut ir::Function, callee: &ir::Function) -> Vec<Op> {
+    let mut registers: HashMap<VReg, VReg> = callee.registers.iter().map(|(key, reg)| (*reg, caller.register(*key))).collect();
+    let ops: Vec<Op> = callee.insts().map(|inst| inst.op.clone()).filter(|op| !matches!(op, Op::Return { .. })).collect();
+    ops.into_iter()
+        .map(|mut op| {
+            for value in op.values_mut() {
+                if let ir::Value::Reg(reg) = value {
+                    *reg = *registers.entry(*reg).or_insert_with(|| caller.new_vreg(callee.vregs[reg.0 as usize]));
+                }
+            }
+            if let Some(dst) = op.def_mut() {
+                *dst = *registers.entry(*dst).or_insert_with(|| caller.new_vreg(callee.vregs[dst.0 as usize]));
+                // A register the caller only writes 32 bits of may now be
+                // written whole
+                caller.narrow.retain(|reg| *reg != *dst);
+            }
+            op
+        })
+        .collect()
+}
+
+/// Replaces the direct calls of `lifted` to callees of at most `threshold`
+/// ops by the callees' ops. Returns the addresses of the inlined calls,
+/// which are no longer approximated.
+pub fn inline_calls(lifted: &mut ir::Function, function: &FunctionIR, threshold: usize) -> Vec<u64> {
+    let targets: HashMap<u64, u64> = function.instructions.iter().filter_map(|info| Some((info.addr, info.instr.call_target()?))).collect();
+    let mut bodies: HashMap<u64, Option<ir::Function>> = HashMap::new();
+    let mut inlined = Vec::new();
+    for block in 0..lifted.blocks.len() {
+        let mut idx = 0;
+        while idx < lifted.blocks[block].insts.len() {
+            let Inst { addr, op } = &lifted.blocks[block].insts[idx];
+            let addr = *addr;
+            let target = targets.get(&addr).filter(|_| matches!(op, Op::Opaque { .. }));
+            let body = target.and_then(|target| {
+                bodies
+                    .entry(*target)
+                    .or_insert_with(|| function.callees.get(target).and_then(|callee| body(callee, threshold)))
+                    .as_ref()
+            });
+            let Some(body) = body else {
+                idx += 1;
+                continue;
+            };
+            let ops = rename(lifted, body);
+            let len = ops.len();
+            lifted.blocks[block].insts.splice(idx..idx + 1, ops.into_iter().map(|op| Inst { addr, op }));
+            inlined.push(addr);
+            idx += len;
+        }
+    }
+    inlined
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::Arch;
+    use crate::ir::{BinOp, Value};
+
+    #[test]
+    fn splices_tiny_callees_into_the_caller() {
+        // call 0x2000; add rax, 1; ret
+        let mut caller = FunctionIR::decode("caller", Arch::X86_64, &[0xe8, 0xfb, 0x0f, 0x00, 0x00, 0x48, 0x83, 0xc0, 0x01, 0xc3], 0x1000);
+        // lea rax, [rdi+rsi]; ret is not lifted, so: mov rax, rdi; add rax, rsi; ret
+        let callee = FunctionIR::decode("sum", Arch::X86_64, &[0x48, 0x89, 0xf8, 0x48, 0x01, 0xf0, 0xc3], 0x2000);
+        caller.callees.insert(0x2000, callee);
+
+        let mut report = FidelityReport::default();
+        let mut lifted = ir::lift(&caller, &mut report);
+        assert!(inline_calls(&mut lifted.clone(), &caller, 1).is_empty());
+        assert_eq!(inline_calls(&mut lifted, &caller, 2), vec![0x1000]);
+        let (rdi, rsi) = (lifted.int_args[0].unwrap(), lifted.int_args[1].unwrap());
+        ir::optimize(&mut lifted);
+        let ops: Vec<&Op> = lifted.insts().map(|inst| &inst.op).collect();
+        assert!(matches!(ops[..], [
+            Op::Binary { op: BinOp::Add, dst: sum, lhs: Value::Reg(lhs), rhs: Value::Reg(rhs) },
+            Op::Binary { op: BinOp::Add, dst, lhs: Value::Reg(total), rhs: Value::Const(1) },
+            Op::Return { value: Value::Reg(ret) },
+        ] if *lhs == rdi && *rhs == rsi && total == sum && ret == dst), "{:?}", ops);
+    }
+}
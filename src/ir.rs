@@ -0,0 +1,2135 @@
+// Intermediate representation between machine code decoding and WASM emission
+//
+// `lift` turns decoded instructions into typed ops on virtual registers,
+// grouped into the basic blocks found by the CFG. `optimize` runs
+// target-independent passes on that, and backends lower the result. Every op
+// remembers the address of the machine instruction it came from so the
+// debugging views can still show the mapping. The x86 front-end lives here,
+// the AArch64 and RISC-V ones in `aarch64.rs` and `riscv.rs`.
+
+use crate::arch::{Arch, MachineInstr};
+use crate::backend::FunctionIR;
+use crate::signatures::{DeclaredSignature, ParamType};
+use crate::transpiler_real::FidelityReport;
+use iced_x86::{Instruction, MemorySize, Mnemonic, OpKind, Register};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VReg(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ty {
+    I64,
+    /// Registers of 32-bit architectures
+    I32,
+    /// Result of a comparison, consumed by branches. As wide as the
+    /// function's registers.
+    Flags,
+    /// Pointer argument passed as an opaque host reference, only ever used
+    /// as the base of loads and stores, see `mark_handles`
+    Handle,
+    /// Scalar double in an SSE register. Constants hold the bit pattern.
+    F64,
+}
+
+impl Ty {
+    /// Truncates a folded constant to the width of the type, so 32-bit
+    /// arithmetic wraps the way the original instructions did. 32-bit
+    /// registers of 64-bit code are zero-extended, as writing one clears the
+    /// upper half of the full register.
+    pub fn wrap(self, word: Ty, value: i64) -> i64 {
+        match (self, word) {
+            (Ty::I32, Ty::I64) => value as u32 as i64,
+            (Ty::I32, _) | (Ty::Flags, Ty::I32) => value as i32 as i64,
+            _ => value,
+        }
+    }
+}
+
+/// Why a trap instruction stops execution
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapKind {
+    /// ud2 / udf / c.unimp; Rust panics and failed assertions end in one
+    Invalid,
+    /// int3 / brk / ebreak
+    Breakpoint,
+    /// int imm
+    Interrupt(u8),
+}
+
+impl TrapKind {
+    /// Code passed to `env.trap_reason`: the x86 exception vector
+    pub fn code(self) -> i32 {
+        match self {
+            TrapKind::Invalid => 6,
+            TrapKind::Breakpoint => 3,
+            TrapKind::Interrupt(vector) => vector as i32,
+        }
+    }
+}
+
+impl std::fmt::Display for TrapKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrapKind::Invalid => write!(f, "invalid instruction"),
+            TrapKind::Breakpoint => write!(f, "breakpoint"),
+            TrapKind::Interrupt(vector) => write!(f, "interrupt {:#x}", vector),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    Reg(VReg),
+    Const(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    And,
+    /// Only lifted for doubles
+    Div,
+    /// Logical shift right
+    Shr,
+    /// Arithmetic shift right
+    Sar,
+    Shl,
+    Or,
+    Xor,
+}
+
+impl BinOp {
+    /// None if folding would change behavior (a division trapping at runtime)
+    fn fold(self, ty: Ty, lhs: i64, rhs: i64) -> Option<i64> {
+        if ty == Ty::F64 {
+            let (lhs, rhs) = (f64::from_bits(lhs as u64), f64::from_bits(rhs as u64));
+            let value = match self {
+                BinOp::Add => lhs + rhs,
+                BinOp::Sub => lhs - rhs,
+                BinOp::Mul => lhs * rhs,
+                BinOp::Div => lhs / rhs,
+                BinOp::And | BinOp::Shr | BinOp::Sar | BinOp::Shl | BinOp::Or | BinOp::Xor => return None,
+            };
+            return Some(value.to_bits() as i64);
+        }
+        match self {
+            BinOp::Add => Some(lhs.wrapping_add(rhs)),
+            BinOp::Sub => Some(lhs.wrapping_sub(rhs)),
+            BinOp::Mul => Some(lhs.wrapping_mul(rhs)),
+            BinOp::And => Some(lhs & rhs),
+            BinOp::Div => lhs.checked_div(rhs),
+            BinOp::Shr if ty == Ty::I32 => Some(((lhs as u32) >> (rhs & 31)) as i64),
+            BinOp::Shr => Some(((lhs as u64) >> (rhs & 63)) as i64),
+            BinOp::Sar if ty == Ty::I32 => Some(((lhs as i32) >> (rhs & 31)) as i64),
+            BinOp::Sar => Some(lhs >> (rhs & 63)),
+            BinOp::Shl if ty == Ty::I32 => Some(((lhs as i32) << (rhs & 31)) as i64),
+            BinOp::Shl => Some(lhs << (rhs & 63)),
+            BinOp::Or => Some(lhs | rhs),
+            BinOp::Xor => Some(lhs ^ rhs),
+        }
+    }
+}
+
+/// Branch condition, evaluated on the flags value (the result of the
+/// comparison's subtraction or and)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    Eq,
+    Ne,
+    Gt,
+    Lt,
+    Ge,
+    Le,
+    Above,
+    Below,
+    AboveEq,
+    BelowEq,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Copy { dst: VReg, src: Value },
+    Binary { op: BinOp, dst: VReg, lhs: Value, rhs: Value },
+    Load { dst: VReg, base: Value, offset: i64 },
+    Store { base: Value, offset: i64, value: Value },
+    /// Signed integer to double, reading the low `from` bits of `src`. An
+    /// i32 `dst` gets the low half of an i64 `src`.
+    Convert { dst: VReg, src: Value, from: Ty },
+    /// Conditional branch on the flags register, not yet structured
+    Branch { cond: Cond, flags: Value, target: u64 },
+    Jump { target: u64 },
+    /// Jump through a register, ends a block without known successors
+    IndirectJump,
+    Return { value: Value },
+    /// Stops execution, lowered to `unreachable`
+    Trap { kind: TrapKind },
+    /// System call with the number and arguments in the registers of the
+    /// Linux convention, see `syscalls.rs`. Writes the result to `dst`.
+    Syscall { dst: VReg, number: Value, args: [Value; 6] },
+    /// Timestamp counter ticks derived from the imported `env.now_ns`,
+    /// always an i64
+    Timestamp { dst: VReg },
+    /// One output register (0-3: eax, ebx, ecx, edx) of cpuid, a constant
+    /// looked up in the configured table, see `cpuid.rs`
+    Cpuid { dst: VReg, leaf: Value, subleaf: Value, output: u8 },
+    /// An instruction without an IR equivalent (calls, stack ops), dropped
+    Opaque { mnemonic: String },
+    /// SSA join, one argument per predecessor of the block. Only exists
+    /// between `build_ssa` and `destroy_ssa`.
+    Phi { dst: VReg, args: Vec<Value> },
+}
+
+impl Op {
+    fn def(&self) -> Option<VReg> {
+        match self {
+            Op::Copy { dst, .. }
+            | Op::Binary { dst, .. }
+            | Op::Load { dst, .. }
+            | Op::Convert { dst, .. }
+            | Op::Syscall { dst, .. }
+            | Op::Timestamp { dst }
+            | Op::Cpuid { dst, .. }
+            | Op::Phi { dst, .. } => Some(*dst),
+            _ => None,
+        }
+    }
+
+    pub fn def_mut(&mut self) -> Option<&mut VReg> {
+        match self {
+            Op::Copy { dst, .. }
+            | Op::Binary { dst, .. }
+            | Op::Load { dst, .. }
+            | Op::Convert { dst, .. }
+            | Op::Syscall { dst, .. }
+            | Op::Timestamp { dst }
+            | Op::Cpuid { dst, .. }
+            | Op::Phi { dst, .. } => Some(dst),
+            _ => None,
+        }
+    }
+
+    fn values(&self) -> Vec<Value> {
+        match self {
+            Op::Copy { src, .. } | Op::Convert { src, .. } => vec![*src],
+            Op::Binary { lhs, rhs, .. } => vec![*lhs, *rhs],
+            Op::Load { base, .. } => vec![*base],
+            Op::Store { base, value, .. } => vec![*base, *value],
+            Op::Branch { flags, .. } => vec![*flags],
+            Op::Return { value } => vec![*value],
+            Op::Syscall { number, args, .. } => std::iter::once(*number).chain(*args).collect(),
+            Op::Cpuid { leaf, subleaf, .. } => vec![*leaf, *subleaf],
+            Op::Phi { args, .. } => args.clone(),
+            Op::Jump { .. } | Op::IndirectJump | Op::Trap { .. } | Op::Timestamp { .. } | Op::Opaque { .. } => vec![],
+        }
+    }
+
+    fn uses(&self) -> Vec<VReg> {
+        self.values()
+            .into_iter()
+            .filter_map(|value| match value {
+                Value::Reg(reg) => Some(reg),
+                Value::Const(_) => None,
+            })
+            .collect()
+    }
+
+    pub fn values_mut(&mut self) -> Vec<&mut Value> {
+        match self {
+            Op::Copy { src, .. } | Op::Convert { src, .. } => vec![src],
+            Op::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+            Op::Load { base, .. } => vec![base],
+            Op::Store { base, value, .. } => vec![base, value],
+            Op::Branch { flags, .. } => vec![flags],
+            Op::Return { value } => vec![value],
+            Op::Syscall { number, args, .. } => std::iter::once(number).chain(args.iter_mut()).collect(),
+            Op::Cpuid { leaf, subleaf, .. } => vec![leaf, subleaf],
+            Op::Phi { args, .. } => args.iter_mut().collect(),
+            Op::Jump { .. } | Op::IndirectJump | Op::Trap { .. } | Op::Timestamp { .. } | Op::Opaque { .. } => vec![],
+        }
+    }
+
+    /// Ops that stay even if nothing reads their result
+    fn has_side_effects(&self) -> bool {
+        matches!(self, Op::Syscall { .. })
+    }
+
+    fn is_terminator(&self) -> bool {
+        matches!(self, Op::Branch { .. } | Op::Jump { .. } | Op::IndirectJump | Op::Return { .. } | Op::Trap { .. })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Inst {
+    /// Address of the machine instruction this op was lifted from
+    pub addr: u64,
+    pub op: Op,
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    /// Address of the first machine instruction, the target of jumps
+    pub start: u64,
+    pub insts: Vec<Inst>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    /// Source architecture, which numbers the syscalls
+    pub arch: Arch,
+    pub blocks: Vec<Block>,
+    /// Type of every virtual register, indexed by `VReg`
+    pub vregs: Vec<Ty>,
+    /// Register width of the source architecture
+    pub word: Ty,
+    /// Registers holding the recovered parameters, in order. Only stack
+    /// arguments of 32-bit x86 functions are recovered.
+    pub params: Vec<VReg>,
+    /// Argument registers the function touches, in calling convention
+    /// order. Reads of their entry values keep these registers through SSA
+    /// construction.
+    pub args: Vec<VReg>,
+    /// Integer argument registers in calling convention order, None if the
+    /// function does not touch them
+    pub int_args: Vec<Option<VReg>>,
+    /// Float argument registers in calling convention order, None if the
+    /// function does not touch them
+    pub float_args: Vec<Option<VReg>>,
+    /// Type of the WASM result, the register width unless the function
+    /// returns a double, see `apply_float_abi`
+    pub result: Ty,
+    /// Register st(0) is copied to at returns of 32-bit x86 code that left
+    /// a value on the x87 stack, where cdecl returns doubles
+    pub x87_result: Option<VReg>,
+    /// Addresses of the instructions that could not be lifted, which
+    /// `traps = "unreachable"` replaces by a trap
+    pub unsupported: Vec<u64>,
+    /// Registers of 64-bit code that only 32-bit moves and arithmetic
+    /// write, see `infer_widths`
+    pub narrow: Vec<VReg>,
+    /// The stack pointer, None if the function does not touch it
+    pub stack_pointer: Option<VReg>,
+    /// Virtual register of each machine register the function touches
+    pub registers: HashMap<RegKey, VReg>,
+}
+
+impl Function {
+    pub fn insts(&self) -> impl Iterator<Item = &Inst> {
+        self.blocks.iter().flat_map(|block| block.insts.iter())
+    }
+
+    pub fn new_vreg(&mut self, ty: Ty) -> VReg {
+        self.vregs.push(ty);
+        VReg(self.vregs.len() as u32 - 1)
+    }
+
+    /// Virtual register of a machine register. One the function did not
+    /// touch yet gets a new register, which also becomes its argument
+    /// register if it is one.
+    pub fn register(&mut self, key: RegKey) -> VReg {
+        if let Some(vreg) = self.registers.get(&key) {
+            return *vreg;
+        }
+        let vreg = self.new_vreg(key.ty(self.word));
+        self.registers.insert(key, vreg);
+        if let Some(idx) = self.arch.arg_registers().iter().position(|arg| *arg == key) {
+            self.int_args[idx] = Some(vreg);
+            self.args = self.int_args.iter().flatten().copied().collect();
+        }
+        if let Some(idx) = self.arch.float_arg_registers().iter().position(|arg| *arg == key) {
+            self.float_args[idx] = Some(vreg);
+        }
+        vreg
+    }
+
+    /// Successors of every block, the inverse of `predecessors`
+    pub fn successors(&self) -> Vec<Vec<usize>> {
+        let mut succs = vec![Vec::new(); self.blocks.len()];
+        for (block, preds) in self.predecessors().into_iter().enumerate() {
+            for pred in preds {
+                succs[pred].push(block);
+            }
+        }
+        succs
+    }
+
+    /// Predecessors of every block, derived from jump/branch targets and
+    /// fall-through. Indirect jumps end a block without successors.
+    pub fn predecessors(&self) -> Vec<Vec<usize>> {
+        let block_at: HashMap<u64, usize> = self.blocks
+            .iter()
+            .enumerate()
+            .map(|(idx, block)| (block.start, idx))
+            .collect();
+
+        let mut preds = vec![Vec::new(); self.blocks.len()];
+        for (idx, block) in self.blocks.iter().enumerate() {
+            let fallthrough = (idx + 1 < self.blocks.len()).then_some(idx + 1);
+            let successors = match block.insts.last().map(|inst| &inst.op) {
+                Some(Op::Jump { target }) => vec![block_at.get(target).copied()],
+                Some(Op::Branch { target, .. }) => vec![block_at.get(target).copied(), fallthrough],
+                Some(Op::Return { .. } | Op::IndirectJump | Op::Trap { .. }) => vec![],
+                _ => vec![fallthrough],
+            };
+            for successor in successors.into_iter().flatten() {
+                if !preds[successor].contains(&idx) {
+                    preds[successor].push(idx);
+                }
+            }
+        }
+        preds
+    }
+}
+
+/// A machine register. Partial registers (eax, w0) share the key of the
+/// full register they are part of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RegKey {
+    X86(Register),
+    /// x0-x30, 31 is the stack pointer
+    AArch64(u16),
+    /// x1-x31, x0 is the constant zero
+    #[cfg(feature = "riscv")]
+    RiscV(u32),
+    /// Physical x87 register; st(i) is slot (top + i) % 8, see
+    /// `Lifter::lift_x87`
+    X87(u8),
+}
+
+impl RegKey {
+    // Type of the virtual register of a machine register
+    fn ty(self, word: Ty) -> Ty {
+        match self {
+            RegKey::X86(register) if register.is_vector_register() => Ty::F64,
+            RegKey::X87(_) => Ty::F64,
+            _ => word,
+        }
+    }
+}
+
+// Position of the stack pointer relative to its value on entry, used to
+// recognize stack arguments. cdecl and stdcall both pass them on the stack,
+// the first one at [esp+4] on entry; stdcall functions pop them with `ret n`.
+#[derive(Default)]
+struct StackFrame {
+    sp: i64,
+    // Stack pointer at the time it was copied to ebp
+    bp: Option<i64>,
+    callee_pops: u64,
+}
+
+// Maps machine registers to virtual registers
+pub struct Lifter {
+    registers: HashMap<RegKey, VReg>,
+    flags: Option<VReg>,
+    vregs: Vec<Ty>,
+    word: Ty,
+    // Only tracked for 32-bit x86
+    stack: Option<StackFrame>,
+    params: Vec<VReg>,
+    // Physical slot of the x87 st(0), tracked while lifting like the stack
+    // pointer; pushes decrement it
+    x87_top: u8,
+    x87_result: Option<VReg>,
+    load_bias: u64,
+    relocations: HashMap<u64, u64>,
+    // Registers written by 32-bit moves and arithmetic of 64-bit code, and
+    // the ones written or stored whole, see `track_widths`
+    narrow: HashSet<VReg>,
+    wide: HashSet<VReg>,
+}
+
+impl Lifter {
+    fn new_vreg(&mut self, ty: Ty) -> VReg {
+        self.vregs.push(ty);
+        VReg(self.vregs.len() as u32 - 1)
+    }
+
+    pub fn key(&mut self, key: RegKey) -> VReg {
+        if let Some(vreg) = self.registers.get(&key) {
+            return *vreg;
+        }
+        let vreg = self.new_vreg(key.ty(self.word));
+        self.registers.insert(key, vreg);
+        vreg
+    }
+
+    fn reg(&mut self, register: Register) -> VReg {
+        self.key(RegKey::X86(register.full_register()))
+    }
+
+    pub fn flags(&mut self) -> VReg {
+        match self.flags {
+            Some(vreg) => vreg,
+            None => {
+                let vreg = self.new_vreg(Ty::Flags);
+                self.flags = Some(vreg);
+                vreg
+            }
+        }
+    }
+
+    // Sorts the registers the ops of `instr` write into narrow ones, the
+    // destination of a 32-bit mov, add, sub or imul of 64-bit code, and wide
+    // ones. A 64-bit store reads the whole register, so it makes it wide too.
+    fn track_widths(&mut self, instr: &MachineInstr, ops: &[Op]) {
+        let MachineInstr::X86(instr) = instr else {
+            self.wide.extend(ops.iter().filter_map(Op::def));
+            return;
+        };
+        let narrow = (self.word == Ty::I64
+            && matches!(instr.mnemonic(), Mnemonic::Mov | Mnemonic::Add | Mnemonic::Sub | Mnemonic::Imul)
+            && instr.op0_kind() == OpKind::Register
+            && instr.op0_register().is_gpr32())
+        .then(|| self.reg(instr.op0_register()));
+        for dst in ops.iter().filter_map(Op::def) {
+            match Some(dst) == narrow {
+                true => self.narrow.insert(dst),
+                false => self.wide.insert(dst),
+            };
+        }
+        for op in ops {
+            if let Op::Store { value: Value::Reg(value), .. } = op {
+                if instr.memory_size().size() == 8 {
+                    self.wide.insert(*value);
+                }
+            }
+        }
+    }
+
+    fn operand(&mut self, instr: &Instruction, operand: u32) -> Option<Value> {
+        match instr.op_kind(operand) {
+            OpKind::Register => Some(Value::Reg(self.reg(instr.op_register(operand)))),
+            OpKind::Immediate8
+            | OpKind::Immediate8to32
+            | OpKind::Immediate8to64
+            | OpKind::Immediate16
+            | OpKind::Immediate32
+            | OpKind::Immediate32to64
+            | OpKind::Immediate64 => Some(Value::Const(instr.immediate(operand) as i64)),
+            OpKind::Memory => self.param_slot(instr).map(Value::Reg),
+            _ => None,
+        }
+    }
+
+    fn param(&mut self, idx: usize) -> VReg {
+        while self.params.len() <= idx {
+            let vreg = self.new_vreg(self.word);
+            self.params.push(vreg);
+        }
+        self.params[idx]
+    }
+
+    // The parameter a memory operand refers to, if it addresses the
+    // argument area of the caller's frame
+    fn param_slot(&mut self, instr: &Instruction) -> Option<VReg> {
+        let stack = self.stack.as_ref()?;
+        if instr.memory_index() != Register::None {
+            return None;
+        }
+        let base = match instr.memory_base() {
+            Register::ESP => stack.sp,
+            Register::EBP => stack.bp?,
+            _ => return None,
+        };
+        // [esp] on entry holds the return address
+        let offset = base + instr.memory_displacement32() as i32 as i64;
+        if offset < 4 || offset % 4 != 0 {
+            return None;
+        }
+        Some(self.param(offset as usize / 4 - 1))
+    }
+
+    fn track_stack(&mut self, instr: &Instruction) {
+        let Some(stack) = self.stack.as_mut() else { return };
+        let adjusts_sp = instr.op_count() == 2
+            && instr.op0_kind() == OpKind::Register
+            && instr.op0_register() == Register::ESP;
+        match instr.mnemonic() {
+            Mnemonic::Push => stack.sp -= 4,
+            Mnemonic::Pop => stack.sp += 4,
+            Mnemonic::Add | Mnemonic::Sub if adjusts_sp && instr.op1_kind() != OpKind::Register => {
+                let imm = instr.immediate(1) as i32 as i64;
+                stack.sp += if instr.mnemonic() == Mnemonic::Add { imm } else { -imm };
+            }
+            Mnemonic::Mov if instr.op0_register() == Register::EBP && instr.op1_register() == Register::ESP => {
+                stack.bp = Some(stack.sp);
+            }
+            Mnemonic::Ret if instr.op_count() == 1 => stack.callee_pops = instr.immediate16() as u64,
+            _ => {}
+        }
+    }
+
+    // Base and displacement of a memory operand; indexed addressing and
+    // fs/gs-relative (thread-local) operands are not supported
+    fn address(&mut self, instr: &Instruction) -> Option<(Value, i64)> {
+        if instr.memory_index() != Register::None || matches!(instr.memory_segment(), Register::FS | Register::GS) {
+            return None;
+        }
+        let (base, bias) = match instr.memory_base() {
+            // The displacement of RIP-relative operands is already absolute,
+            // but at the link-time address
+            Register::RIP => (Value::Const(0), self.load_bias),
+            Register::None => (Value::Const(0), 0),
+            base => (Value::Reg(self.reg(base)), 0),
+        };
+        Some((base, instr.memory_displacement64().wrapping_add(bias) as i64))
+    }
+
+    // Address a RIP-relative load reads from a GOT slot, which the loader
+    // fills in with the address of a global
+    fn relocated_slot(&self, instr: &Instruction) -> Option<u64> {
+        if instr.memory_base() != Register::RIP {
+            return None;
+        }
+        self.relocations.get(&instr.memory_displacement64()).copied()
+    }
+
+    fn lift_x86(&mut self, instr: &Instruction) -> Option<Vec<Op>> {
+        self.track_stack(instr);
+        let op = match instr.mnemonic() {
+            Mnemonic::Mov => match (instr.op0_kind(), instr.op1_kind()) {
+                (OpKind::Register, OpKind::Memory) if self.param_slot(instr).is_some() => {
+                    let src = self.operand(instr, 1)?;
+                    Op::Copy { dst: self.reg(instr.op0_register()), src }
+                }
+                (OpKind::Memory, _) if self.param_slot(instr).is_some() => {
+                    let dst = self.param_slot(instr)?;
+                    Op::Copy { dst, src: self.operand(instr, 1)? }
+                }
+                (OpKind::Register, OpKind::Memory) if self.relocated_slot(instr).is_some() => {
+                    let address = self.relocated_slot(instr)?;
+                    Op::Copy { dst: self.reg(instr.op0_register()), src: Value::Const(address as i64) }
+                }
+                (OpKind::Register, OpKind::Memory) => {
+                    let (base, offset) = self.address(instr)?;
+                    Op::Load { dst: self.reg(instr.op0_register()), base, offset }
+                }
+                (OpKind::Memory, _) => {
+                    let (base, offset) = self.address(instr)?;
+                    let value = self.operand(instr, 1)?;
+                    Op::Store { base, offset, value }
+                }
+                (OpKind::Register, _) => {
+                    let src = self.operand(instr, 1)?;
+                    Op::Copy { dst: self.reg(instr.op0_register()), src }
+                }
+                _ => return None,
+            },
+
+            Mnemonic::Add | Mnemonic::Sub | Mnemonic::Imul if instr.op0_kind() == OpKind::Register => {
+                let op = match instr.mnemonic() {
+                    Mnemonic::Add => BinOp::Add,
+                    Mnemonic::Sub => BinOp::Sub,
+                    _ => BinOp::Mul,
+                };
+                // Three-operand imul: dst = op1 * imm
+                let (lhs, rhs) = if instr.op_count() == 3 {
+                    (self.operand(instr, 1)?, self.operand(instr, 2)?)
+                } else {
+                    (self.operand(instr, 0)?, self.operand(instr, 1)?)
+                };
+                Op::Binary { op, dst: self.reg(instr.op0_register()), lhs, rhs }
+            }
+
+            // Comparisons only set the flags register
+            Mnemonic::Cmp | Mnemonic::Test => {
+                let op = if instr.mnemonic() == Mnemonic::Cmp { BinOp::Sub } else { BinOp::And };
+                let lhs = self.operand(instr, 0)?;
+                let rhs = self.operand(instr, 1)?;
+                Op::Binary { op, dst: self.flags(), lhs, rhs }
+            }
+
+            Mnemonic::Je | Mnemonic::Jne | Mnemonic::Jg | Mnemonic::Jl |
+            Mnemonic::Jge | Mnemonic::Jle | Mnemonic::Ja | Mnemonic::Jb |
+            Mnemonic::Jae | Mnemonic::Jbe => Op::Branch {
+                cond: match instr.mnemonic() {
+                    Mnemonic::Je => Cond::Eq,
+                    Mnemonic::Jne => Cond::Ne,
+                    Mnemonic::Jg => Cond::Gt,
+                    Mnemonic::Jl => Cond::Lt,
+                    Mnemonic::Jge => Cond::Ge,
+                    Mnemonic::Jle => Cond::Le,
+                    Mnemonic::Ja => Cond::Above,
+                    Mnemonic::Jb => Cond::Below,
+                    Mnemonic::Jae => Cond::AboveEq,
+                    _ => Cond::BelowEq,
+                },
+                flags: Value::Reg(self.flags()),
+                target: instr.near_branch_target(),
+            },
+
+            Mnemonic::Jmp if instr.is_jmp_short_or_near() => Op::Jump { target: instr.near_branch_target() },
+
+            // Return value is in RAX/EAX, or st(0) for doubles in 32-bit code
+            Mnemonic::Ret if self.word == Ty::I32 && self.x87_top != 0 => {
+                let result = match self.x87_result {
+                    Some(result) => result,
+                    None => {
+                        let result = self.new_vreg(Ty::F64);
+                        self.x87_result = Some(result);
+                        result
+                    }
+                };
+                return Some(vec![
+                    Op::Copy { dst: result, src: Value::Reg(self.st(0)) },
+                    Op::Return { value: Value::Reg(self.reg(Register::RAX)) },
+                ]);
+            }
+            Mnemonic::Ret => Op::Return { value: Value::Reg(self.reg(Register::RAX)) },
+
+            Mnemonic::Jmp => Op::IndirectJump,
+
+            Mnemonic::Ud2 => Op::Trap { kind: TrapKind::Invalid },
+            Mnemonic::Int3 => Op::Trap { kind: TrapKind::Breakpoint },
+            Mnemonic::Int => Op::Trap { kind: TrapKind::Interrupt(instr.immediate8()) },
+
+            // Number in rax, arguments in rdi, rsi, rdx, r10, r8, r9
+            Mnemonic::Syscall => {
+                let args = [Register::RDI, Register::RSI, Register::RDX, Register::R10, Register::R8, Register::R9]
+                    .map(|register| Value::Reg(self.reg(register)));
+                let rax = self.reg(Register::RAX);
+                Op::Syscall { dst: rax, number: Value::Reg(rax), args }
+            }
+
+            // Timestamp counter in edx:eax
+            Mnemonic::Rdtsc | Mnemonic::Rdtscp => {
+                let ticks = self.new_vreg(Ty::I64);
+                let (rax, rdx) = (self.reg(Register::RAX), self.reg(Register::RDX));
+                let mut ops = vec![Op::Timestamp { dst: ticks }];
+                match self.word {
+                    Ty::I32 => {
+                        let high = self.new_vreg(Ty::I64);
+                        ops.extend([
+                            Op::Convert { dst: rax, src: Value::Reg(ticks), from: Ty::I64 },
+                            Op::Binary { op: BinOp::Shr, dst: high, lhs: Value::Reg(ticks), rhs: Value::Const(32) },
+                            Op::Convert { dst: rdx, src: Value::Reg(high), from: Ty::I64 },
+                        ]);
+                    }
+                    _ => ops.extend([
+                        Op::Binary { op: BinOp::And, dst: rax, lhs: Value::Reg(ticks), rhs: Value::Const(0xffff_ffff) },
+                        Op::Binary { op: BinOp::Shr, dst: rdx, lhs: Value::Reg(ticks), rhs: Value::Const(32) },
+                    ]),
+                }
+                // rdtscp also returns the processor id (IA32_TSC_AUX) in ecx
+                if instr.mnemonic() == Mnemonic::Rdtscp {
+                    ops.push(Op::Copy { dst: self.reg(Register::RCX), src: Value::Const(0) });
+                }
+                return Some(ops);
+            }
+
+            // Leaf in eax and subleaf in ecx, read before the outputs
+            // overwrite them
+            Mnemonic::Cpuid => {
+                let (leaf, subleaf) = (self.new_vreg(self.word), self.new_vreg(self.word));
+                let mut ops = vec![
+                    Op::Copy { dst: leaf, src: Value::Reg(self.reg(Register::RAX)) },
+                    Op::Copy { dst: subleaf, src: Value::Reg(self.reg(Register::RCX)) },
+                ];
+                for (output, register) in [Register::RAX, Register::RBX, Register::RCX, Register::RDX].into_iter().enumerate() {
+                    let dst = self.reg(register);
+                    ops.push(Op::Cpuid { dst, leaf: Value::Reg(leaf), subleaf: Value::Reg(subleaf), output: output as u8 });
+                }
+                return Some(ops);
+            }
+
+            Mnemonic::Bt | Mnemonic::Bts | Mnemonic::Btr | Mnemonic::Btc => return self.lift_bit_test(instr),
+
+            Mnemonic::Movzx | Mnemonic::Movsx | Mnemonic::Movsxd => return self.lift_extend(instr),
+
+            Mnemonic::Call | Mnemonic::Push | Mnemonic::Pop => {
+                Op::Opaque { mnemonic: format!("{:?}", instr.mnemonic()).to_lowercase() }
+            }
+
+            // Scalar double SSE: an xmm register holds a single f64. Only
+            // movsd moves 64 bits to and from memory, the packed moves are
+            // only register copies here.
+            Mnemonic::Movsd | Mnemonic::Movapd | Mnemonic::Movaps => match (instr.op0_kind(), instr.op1_kind()) {
+                (OpKind::Register, OpKind::Register) => Op::Copy {
+                    dst: self.reg(instr.op0_register()),
+                    src: Value::Reg(self.reg(instr.op1_register())),
+                },
+                (OpKind::Register, OpKind::Memory) if instr.mnemonic() == Mnemonic::Movsd => {
+                    let (base, offset) = self.address(instr)?;
+                    Op::Load { dst: self.reg(instr.op0_register()), base, offset }
+                }
+                (OpKind::Memory, OpKind::Register) if instr.mnemonic() == Mnemonic::Movsd => {
+                    let (base, offset) = self.address(instr)?;
+                    Op::Store { base, offset, value: Value::Reg(self.reg(instr.op1_register())) }
+                }
+                _ => return None,
+            },
+
+            Mnemonic::Addsd | Mnemonic::Subsd | Mnemonic::Mulsd | Mnemonic::Divsd => {
+                let op = match instr.mnemonic() {
+                    Mnemonic::Addsd => BinOp::Add,
+                    Mnemonic::Subsd => BinOp::Sub,
+                    Mnemonic::Mulsd => BinOp::Mul,
+                    _ => BinOp::Div,
+                };
+                let dst = self.reg(instr.op0_register());
+                let mut ops = Vec::new();
+                let rhs = match instr.op1_kind() {
+                    OpKind::Register => Value::Reg(self.reg(instr.op1_register())),
+                    OpKind::Memory => {
+                        let (base, offset) = self.address(instr)?;
+                        let loaded = self.new_vreg(Ty::F64);
+                        ops.push(Op::Load { dst: loaded, base, offset });
+                        Value::Reg(loaded)
+                    }
+                    _ => return None,
+                };
+                ops.push(Op::Binary { op, dst, lhs: Value::Reg(dst), rhs });
+                return Some(ops);
+            }
+
+            Mnemonic::Cvtsi2sd => {
+                let dst = self.reg(instr.op0_register());
+                match instr.op1_kind() {
+                    OpKind::Register => {
+                        let from = if instr.op1_register().size() == 8 { Ty::I64 } else { Ty::I32 };
+                        Op::Convert { dst, src: Value::Reg(self.reg(instr.op1_register())), from }
+                    }
+                    OpKind::Memory => {
+                        let (base, offset) = self.address(instr)?;
+                        let from = if instr.memory_size().size() == 8 { Ty::I64 } else { Ty::I32 };
+                        let loaded = self.new_vreg(from);
+                        return Some(vec![
+                            Op::Load { dst: loaded, base, offset },
+                            Op::Convert { dst, src: Value::Reg(loaded), from },
+                        ]);
+                    }
+                    _ => return None,
+                }
+            }
+
+            // xorpd xmm0, xmm0 zeroes the register
+            Mnemonic::Xorpd | Mnemonic::Xorps
+                if instr.op1_kind() == OpKind::Register && instr.op0_register() == instr.op1_register() =>
+            {
+                Op::Copy { dst: self.reg(instr.op0_register()), src: Value::Const(0) }
+            }
+
+            _ => return self.lift_x87(instr),
+        };
+        Some(vec![op])
+    }
+
+    // The tested bit goes to the carry flag, kept as the flags value
+    // 0 - bit so that jb/jc see a negative difference. The bit index is
+    // taken modulo the operand width, except for a register index into
+    // memory: that addresses a bit string, the high bits selecting the word.
+    // Zero or sign extension of a narrower register or memory field. Loads
+    // read a whole word, the field is its low bytes.
+    fn lift_extend(&mut self, instr: &Instruction) -> Option<Vec<Op>> {
+        let dst = self.reg(instr.op0_register());
+        let mut ops = Vec::new();
+        let (src, bits) = match instr.op1_kind() {
+            OpKind::Register => (Value::Reg(self.reg(instr.op1_register())), instr.op1_register().size() as i64 * 8),
+            OpKind::Memory => {
+                let (base, offset) = self.address(instr)?;
+                let loaded = self.new_vreg(self.word);
+                ops.push(Op::Load { dst: loaded, base, offset });
+                (Value::Reg(loaded), instr.memory_size().size() as i64 * 8)
+            }
+            _ => return None,
+        };
+        let word_bits = if self.word == Ty::I32 { 32 } else { 64 };
+        if bits >= word_bits {
+            ops.push(Op::Copy { dst, src });
+        } else if instr.mnemonic() == Mnemonic::Movzx {
+            ops.push(Op::Binary { op: BinOp::And, dst, lhs: src, rhs: Value::Const((1 << bits) - 1) });
+        } else {
+            let shift = Value::Const(word_bits - bits);
+            ops.extend([
+                Op::Binary { op: BinOp::Shl, dst, lhs: src, rhs: shift },
+                Op::Binary { op: BinOp::Sar, dst, lhs: Value::Reg(dst), rhs: shift },
+            ]);
+        }
+        Some(ops)
+    }
+
+    fn lift_bit_test(&mut self, instr: &Instruction) -> Option<Vec<Op>> {
+        let bits = match instr.op0_kind() {
+            OpKind::Register => instr.op0_register().size() as i64 * 8,
+            _ => instr.memory_size().size() as i64 * 8,
+        };
+        let mut ops = Vec::new();
+        let index = self.operand(instr, 1)?;
+        let bit = match index {
+            Value::Const(index) => Value::Const(index & (bits - 1)),
+            Value::Reg(_) => {
+                let bit = self.new_vreg(self.word);
+                ops.push(Op::Binary { op: BinOp::And, dst: bit, lhs: index, rhs: Value::Const(bits - 1) });
+                Value::Reg(bit)
+            }
+        };
+        let (value, memory) = match instr.op0_kind() {
+            OpKind::Register => (self.reg(instr.op0_register()), None),
+            OpKind::Memory => {
+                let (mut base, offset) = self.address(instr)?;
+                if let Value::Reg(_) = index {
+                    // (index sar log2(bits)) words of bits / 8 bytes
+                    let (words, address) = (self.new_vreg(self.word), self.new_vreg(self.word));
+                    ops.extend([
+                        Op::Binary { op: BinOp::Sar, dst: words, lhs: index, rhs: Value::Const(bits.trailing_zeros() as i64) },
+                        Op::Binary { op: BinOp::Shl, dst: words, lhs: Value::Reg(words), rhs: Value::Const((bits / 8).trailing_zeros() as i64) },
+                        Op::Binary { op: BinOp::Add, dst: address, lhs: base, rhs: Value::Reg(words) },
+                    ]);
+                    base = Value::Reg(address);
+                }
+                let loaded = self.new_vreg(self.word);
+                ops.push(Op::Load { dst: loaded, base, offset });
+                (loaded, Some((base, offset)))
+            }
+            _ => return None,
+        };
+
+        let (shifted, carry) = (self.new_vreg(self.word), self.new_vreg(self.word));
+        ops.extend([
+            Op::Binary { op: BinOp::Shr, dst: shifted, lhs: Value::Reg(value), rhs: bit },
+            Op::Binary { op: BinOp::And, dst: carry, lhs: Value::Reg(shifted), rhs: Value::Const(1) },
+            Op::Binary { op: BinOp::Sub, dst: self.flags(), lhs: Value::Const(0), rhs: Value::Reg(carry) },
+        ]);
+        if instr.mnemonic() == Mnemonic::Bt {
+            return Some(ops);
+        }
+        let mask = self.new_vreg(self.word);
+        ops.push(Op::Binary { op: BinOp::Shl, dst: mask, lhs: Value::Const(1), rhs: bit });
+        let (op, rhs) = match instr.mnemonic() {
+            Mnemonic::Bts => (BinOp::Or, Value::Reg(mask)),
+            Mnemonic::Btc => (BinOp::Xor, Value::Reg(mask)),
+            _ => {
+                let inverted = self.new_vreg(self.word);
+                ops.push(Op::Binary { op: BinOp::Xor, dst: inverted, lhs: Value::Reg(mask), rhs: Value::Const(-1) });
+                (BinOp::And, Value::Reg(inverted))
+            }
+        };
+        ops.push(Op::Binary { op, dst: value, lhs: Value::Reg(value), rhs });
+        if let Some((base, offset)) = memory {
+            ops.push(Op::Store { base, offset, value: Value::Reg(value) });
+        }
+        Some(ops)
+    }
+
+    fn st(&mut self, idx: u32) -> VReg {
+        self.key(RegKey::X87((self.x87_top + idx as u8) % 8))
+    }
+
+    fn st_operand(&mut self, instr: &Instruction, operand: u32) -> VReg {
+        self.st(instr.op_register(operand).number() as u32)
+    }
+
+    // New st(0), after moving the top down
+    fn x87_push(&mut self) -> VReg {
+        self.x87_top = (self.x87_top + 7) % 8;
+        self.st(0)
+    }
+
+    fn x87_pop(&mut self) {
+        self.x87_top = (self.x87_top + 1) % 8;
+    }
+
+    // The x87 register stack, modeled as eight f64 registers and a top of
+    // stack index known at translation time. Values are doubles rather
+    // than 80-bit extended precision: tbyte loads and stores and precision
+    // control changes are not lifted, see `MachineInstr::is_extended_precision`.
+    fn lift_x87(&mut self, instr: &Instruction) -> Option<Vec<Op>> {
+        let memory = instr.op_count() > 0 && instr.op0_kind() == OpKind::Memory;
+        let mut ops = Vec::new();
+        match instr.mnemonic() {
+            Mnemonic::Fld if memory && instr.memory_size() == MemorySize::Float64 => {
+                let (base, offset) = self.address(instr)?;
+                ops.push(Op::Load { dst: self.x87_push(), base, offset });
+            }
+            Mnemonic::Fld if !memory => {
+                let src = self.st_operand(instr, 0);
+                ops.push(Op::Copy { dst: self.x87_push(), src: Value::Reg(src) });
+            }
+            Mnemonic::Fild if matches!(instr.memory_size(), MemorySize::Int32 | MemorySize::Int64) => {
+                let (base, offset) = self.address(instr)?;
+                let from = if instr.memory_size() == MemorySize::Int64 { Ty::I64 } else { Ty::I32 };
+                let loaded = self.new_vreg(from);
+                ops.push(Op::Load { dst: loaded, base, offset });
+                ops.push(Op::Convert { dst: self.x87_push(), src: Value::Reg(loaded), from });
+            }
+            Mnemonic::Fldz | Mnemonic::Fld1 => {
+                let value = if instr.mnemonic() == Mnemonic::Fld1 { 1.0f64 } else { 0.0 };
+                ops.push(Op::Copy { dst: self.x87_push(), src: Value::Const(value.to_bits() as i64) });
+            }
+
+            Mnemonic::Fst | Mnemonic::Fstp if memory && instr.memory_size() == MemorySize::Float64 => {
+                let (base, offset) = self.address(instr)?;
+                ops.push(Op::Store { base, offset, value: Value::Reg(self.st(0)) });
+            }
+            Mnemonic::Fst | Mnemonic::Fstp if !memory => {
+                let (dst, src) = (self.st_operand(instr, 0), self.st(0));
+                if dst != src {
+                    ops.push(Op::Copy { dst, src: Value::Reg(src) });
+                }
+            }
+
+            // st(0) op m64, or dst op src on registers; the r forms swap
+            // the operands, the p forms pop
+            Mnemonic::Fadd | Mnemonic::Faddp | Mnemonic::Fsub | Mnemonic::Fsubp | Mnemonic::Fsubr | Mnemonic::Fsubrp |
+            Mnemonic::Fmul | Mnemonic::Fmulp | Mnemonic::Fdiv | Mnemonic::Fdivp | Mnemonic::Fdivr | Mnemonic::Fdivrp => {
+                let op = match instr.mnemonic() {
+                    Mnemonic::Fadd | Mnemonic::Faddp => BinOp::Add,
+                    Mnemonic::Fsub | Mnemonic::Fsubp | Mnemonic::Fsubr | Mnemonic::Fsubrp => BinOp::Sub,
+                    Mnemonic::Fmul | Mnemonic::Fmulp => BinOp::Mul,
+                    _ => BinOp::Div,
+                };
+                let (dst, src) = if memory {
+                    if instr.memory_size() != MemorySize::Float64 {
+                        return None;
+                    }
+                    let (base, offset) = self.address(instr)?;
+                    let loaded = self.new_vreg(Ty::F64);
+                    ops.push(Op::Load { dst: loaded, base, offset });
+                    (self.st(0), loaded)
+                } else {
+                    (self.st_operand(instr, 0), self.st_operand(instr, 1))
+                };
+                let reversed = matches!(instr.mnemonic(), Mnemonic::Fsubr | Mnemonic::Fsubrp | Mnemonic::Fdivr | Mnemonic::Fdivrp);
+                let (lhs, rhs) = if reversed { (src, dst) } else { (dst, src) };
+                ops.push(Op::Binary { op, dst, lhs: Value::Reg(lhs), rhs: Value::Reg(rhs) });
+            }
+
+            // Multiplying by -1 flips the sign of zeros too
+            Mnemonic::Fchs => {
+                let st0 = self.st(0);
+                ops.push(Op::Binary { op: BinOp::Mul, dst: st0, lhs: Value::Reg(st0), rhs: Value::Const((-1.0f64).to_bits() as i64) });
+            }
+
+            Mnemonic::Fxch => {
+                let (st0, other) = (self.st(0), self.st_operand(instr, instr.op_count() - 1));
+                let saved = self.new_vreg(Ty::F64);
+                ops.extend([
+                    Op::Copy { dst: saved, src: Value::Reg(st0) },
+                    Op::Copy { dst: st0, src: Value::Reg(other) },
+                    Op::Copy { dst: other, src: Value::Reg(saved) },
+                ]);
+            }
+
+            _ => return None,
+        }
+        if matches!(instr.mnemonic(), Mnemonic::Fstp | Mnemonic::Faddp | Mnemonic::Fsubp | Mnemonic::Fsubrp | Mnemonic::Fmulp | Mnemonic::Fdivp | Mnemonic::Fdivrp) {
+            self.x87_pop();
+        }
+        Some(ops)
+    }
+}
+
+/// Lifts every instruction of `function`, recording instructions that could
+/// only be approximated or not lifted at all in `report`.
+pub fn lift(function: &FunctionIR, report: &mut FidelityReport) -> Function {
+    let mut lifter = Lifter {
+        registers: HashMap::new(),
+        flags: None,
+        vregs: Vec::new(),
+        word: function.arch.word(),
+        stack: (function.arch == Arch::X86).then(StackFrame::default),
+        params: Vec::new(),
+        x87_top: 0,
+        x87_result: None,
+        load_bias: function.load_bias,
+        relocations: function.relocations.clone(),
+        narrow: HashSet::new(),
+        wide: HashSet::new(),
+    };
+
+    let mut blocks = Vec::new();
+    let mut unsupported = Vec::new();
+    for block in function.cfg.structure_control_flow() {
+        // Most instructions lift to one or two ops
+        let mut insts = Vec::with_capacity(block.instruction_indices.len() * 2);
+        for &idx in &block.instruction_indices {
+            let instr = &function.instructions[idx].instr;
+            let lifted = match instr {
+                MachineInstr::X86(instr) => lifter.lift_x86(instr),
+                MachineInstr::AArch64 { addr, instr } => lifter.lift_aarch64(instr, *addr),
+                #[cfg(feature = "riscv")]
+                MachineInstr::RiscV { addr, instr, .. } => lifter.lift_riscv(instr, *addr),
+            };
+            match lifted {
+                Some(ops) => {
+                    // Control flow is not structured yet and calls/stack
+                    // operations are dropped
+                    let approximated = |op: &Op| matches!(
+                        op,
+                        Op::Branch { .. } | Op::Jump { .. } | Op::IndirectJump | Op::Syscall { .. } | Op::Timestamp { .. } | Op::Cpuid { .. } | Op::Opaque { .. }
+                    );
+                    if ops.iter().any(approximated) || instr.is_x87_arithmetic() {
+                        report.approximated.push((instr.addr(), instr.mnemonic()));
+                    }
+                    lifter.track_widths(instr, &ops);
+                    insts.extend(ops.into_iter().map(|op| Inst { addr: instr.addr(), op }));
+                }
+                None => {
+                    tracing::debug!("Unsupported instruction: {}", instr.text());
+                    let mnemonic = if instr.is_thread_local() {
+                        format!("{} (thread-local fs/gs access)", instr.mnemonic())
+                    } else if instr.is_extended_precision() {
+                        format!("{} (80-bit extended precision)", instr.mnemonic())
+                    } else {
+                        instr.mnemonic()
+                    };
+                    report.unsupported.push((instr.addr(), mnemonic));
+                    unsupported.push(instr.addr());
+                }
+            }
+        }
+        blocks.push(Block { start: block.start_addr, insts });
+    }
+
+    // stdcall callees pop their arguments, which also covers the ones
+    // that are never read
+    if let Some(stack) = &lifter.stack {
+        if stack.callee_pops >= 4 {
+            lifter.param(stack.callee_pops as usize / 4 - 1);
+        }
+    }
+
+    let int_args: Vec<Option<VReg>> = function.arch
+        .arg_registers()
+        .iter()
+        .map(|key| lifter.registers.get(key).copied())
+        .collect();
+    let float_args = function.arch
+        .float_arg_registers()
+        .iter()
+        .map(|key| lifter.registers.get(key).copied())
+        .collect();
+    let mut narrow: Vec<VReg> = lifter.narrow.difference(&lifter.wide).copied().collect();
+    narrow.sort();
+
+    Function {
+        arch: function.arch,
+        blocks,
+        vregs: lifter.vregs,
+        word: lifter.word,
+        params: lifter.params,
+        args: int_args.iter().flatten().copied().collect(),
+        int_args,
+        float_args,
+        result: lifter.word,
+        x87_result: lifter.x87_result,
+        unsupported,
+        narrow,
+        stack_pointer: lifter.registers.get(&function.arch.stack_pointer()).copied(),
+        registers: lifter.registers,
+    }
+}
+
+/// Keeps the registers that only 32-bit moves and arithmetic of 64-bit code
+/// write in i32 locals instead of the register width: their upper half is
+/// always zero, so 32-bit operations compute them exactly. Where one meets
+/// a 64-bit operand, a return or an address, the lowering zero-extends it.
+/// Runs before `optimize`, so the registers SSA construction splits them
+/// into are i32 as well.
+pub fn infer_widths(function: &mut Function) {
+    // Argument registers read before they are written hold an entry value
+    // of any width
+    let entry = live_in(function, &function.successors()).into_iter().next().unwrap_or_default();
+    for reg in function.narrow.iter().filter(|reg| !entry.contains(reg)) {
+        function.vregs[reg.0 as usize] = Ty::I32;
+    }
+}
+
+/// Maps the float side of the calling convention: float argument registers
+/// read before being written become f64 parameters, in order, and with
+/// `returns_float` returns yield the first float argument register (xmm0)
+/// instead of the integer return register. Runs before `optimize`, so SSA
+/// construction sees the return read the register's last write.
+pub fn apply_float_abi(function: &mut Function, returns_float: bool) {
+    let entry = live_in(function, &function.successors()).into_iter().next().unwrap_or_default();
+    if let Some(last) = function.float_args.iter().rposition(|reg| reg.is_some_and(|reg| entry.contains(&reg))) {
+        for idx in 0..=last {
+            // Unused registers before the last one still take a position
+            let reg = match function.float_args[idx] {
+                Some(reg) => reg,
+                None => function.new_vreg(Ty::F64),
+            };
+            function.params.push(reg);
+        }
+    }
+
+    if returns_float {
+        let value = match (function.x87_result, function.float_args.first().copied()) {
+            (Some(reg), _) | (None, Some(Some(reg))) => Value::Reg(reg),
+            _ => Value::Const(0),
+        };
+        for inst in function.blocks.iter_mut().flat_map(|block| block.insts.iter_mut()) {
+            if let Op::Return { value: returned } = &mut inst.op {
+                *returned = value;
+            }
+        }
+        function.result = Ty::F64;
+    }
+}
+
+/// Replaces the recovered parameters by the declared ones, see
+/// `signatures.rs`. Integers and pointers take the next integer argument
+/// register, or stack slot on 32-bit x86, input buffers the next two for
+/// their pointer and length, doubles the next float argument register;
+/// registers the code never touches get a fresh one. State pointers take
+/// their position but are left out of the parameters, so they keep the
+/// address 0 of the zero-initialized local. The declared parameters are
+/// final: `mark_handles` only retypes them.
+pub fn apply_signature(function: &mut Function, signature: &DeclaredSignature) -> Result<(), String> {
+    // The next integer argument, a stack slot of 32-bit x86 code
+    fn int_arg(function: &mut Function, idx: usize) -> Option<VReg> {
+        if function.arch == Arch::X86 {
+            // Stack slots are recovered as parameters in order
+            while function.params.len() <= idx {
+                let reg = function.new_vreg(function.word);
+                function.params.push(reg);
+            }
+            return Some(function.params[idx]);
+        }
+        // A register `infer_widths` narrowed is written before it is read,
+        // the parameter is passed at the register width all the same
+        let reg = match *function.int_args.get(idx)? {
+            Some(reg) if function.vregs[reg.0 as usize] == function.word => reg,
+            _ => function.new_vreg(function.word),
+        };
+        Some(reg)
+    }
+
+    let (mut ints, mut floats) = (0, 0);
+    let mut params = Vec::new();
+    for (idx, param) in signature.params.iter().enumerate() {
+        let regs = match param {
+            ParamType::F64 => {
+                let reg = function.float_args.get(floats).copied().ok_or_else(|| format!("No float argument register for parameter {}", idx))?;
+                floats += 1;
+                vec![reg.unwrap_or_else(|| function.new_vreg(Ty::F64))]
+            }
+            _ => {
+                let mut regs = Vec::new();
+                for _ in 0..param.int_args() {
+                    regs.push(int_arg(function, ints).ok_or_else(|| format!("No integer argument register for parameter {}", idx))?);
+                    ints += 1;
+                }
+                regs
+            }
+        };
+        if *param != ParamType::State {
+            params.extend(regs);
+        }
+    }
+    function.params = params;
+    function.args.clear();
+    Ok(())
+}
+
+/// Retypes pointer arguments that are only ever used as the base of loads
+/// and stores to `Ty::Handle`. Argument registers up to the last handle
+/// become parameters, so callers still pass them by position.
+pub fn mark_handles(function: &mut Function) {
+    let is_handle = |reg: VReg| {
+        let mut accessed = false;
+        for inst in function.insts() {
+            match inst.op {
+                _ if inst.op.def() == Some(reg) => return false,
+                Op::Load { base, .. } if base == Value::Reg(reg) => accessed = true,
+                Op::Store { base, value, .. } if base == Value::Reg(reg) && value != base => accessed = true,
+                ref op if op.uses().contains(&reg) => return false,
+                _ => {}
+            }
+        }
+        accessed
+    };
+
+    let handles: Vec<VReg> = function.params.iter().chain(&function.args).copied().filter(|reg| is_handle(*reg)).collect();
+    if let Some(last) = function.args.iter().rposition(|reg| handles.contains(reg)) {
+        // Before the float parameters, see `apply_float_abi`
+        let args = function.args[..=last].to_vec();
+        function.params.splice(0..0, args);
+    }
+    for handle in handles {
+        function.vregs[handle.0 as usize] = Ty::Handle;
+    }
+}
+
+/// Runs all optimization passes: SSA construction, copy and constant
+/// propagation, stack slot promotion, dead code elimination, conversion back
+/// to plain copies and dead store elimination.
+pub fn optimize(function: &mut Function) {
+    // Promoted slots are plain registers, a second round renames them and
+    // propagates the spilled values to the reloads
+    for _ in 0..2 {
+        if !build_ssa(function) {
+            break;
+        }
+        propagate_copies(function);
+        let promoted = promote_stack_slots(function);
+        eliminate_dead_code(function);
+        destroy_ssa(function);
+        if !promoted {
+            break;
+        }
+    }
+    eliminate_dead_stores(function);
+}
+
+/// Turns stack slots into registers, so spills and reloads like `mov
+/// [rbp-8], rax` ... `mov rax, [rbp-8]` become copies instead of memory
+/// traffic. Frame addresses are the entry value of the stack pointer and
+/// the registers copies and constant adds derive from it, such as the frame
+/// pointer. A slot is promoted if every access overlapping it has its
+/// offset and type. Nothing is promoted if a frame address is used any
+/// other way, e.g. stored, compared or passed to a syscall, or if the
+/// function has calls or other ops dropped while lifting besides push and
+/// pop, since those could reach the frame. Requires SSA form and leaves
+/// the new registers with several definitions; returns whether it promoted
+/// any slot.
+pub fn promote_stack_slots(function: &mut Function) -> bool {
+    let Some(sp) = function.stack_pointer else { return false };
+    if function.insts().any(|inst| matches!(&inst.op, Op::Opaque { mnemonic } if mnemonic != "push" && mnemonic != "pop")) {
+        return false;
+    }
+
+    // Offset of each frame address from the entry stack pointer
+    let mut frame = HashMap::from([(sp, 0i64)]);
+    loop {
+        let mut derived = Vec::new();
+        for inst in function.insts() {
+            let (dst, src, offset) = match inst.op {
+                Op::Copy { dst, src: Value::Reg(src) } if function.vregs[dst.0 as usize] == function.vregs[src.0 as usize] => (dst, src, 0),
+                Op::Binary { op: BinOp::Add, dst, lhs: Value::Reg(src), rhs: Value::Const(offset) }
+                | Op::Binary { op: BinOp::Add, dst, lhs: Value::Const(offset), rhs: Value::Reg(src) } => (dst, src, offset),
+                Op::Binary { op: BinOp::Sub, dst, lhs: Value::Reg(src), rhs: Value::Const(offset) } => (dst, src, offset.wrapping_neg()),
+                _ => continue,
+            };
+            if let Some(base) = frame.get(&src).filter(|_| !frame.contains_key(&dst)) {
+                derived.push((dst, base.wrapping_add(offset)));
+            }
+        }
+        if derived.is_empty() {
+            break;
+        }
+        frame.extend(derived);
+    }
+
+    let escapes = function.insts().any(|inst| match &inst.op {
+        Op::Load { .. } => false,
+        Op::Store { value, .. } => matches!(value, Value::Reg(value) if frame.contains_key(value)),
+        op if op.def().is_some_and(|dst| frame.contains_key(&dst)) => false,
+        op => op.uses().iter().any(|reg| frame.contains_key(reg)),
+    });
+    if escapes {
+        return false;
+    }
+
+    // Block, index, frame offset and type of every access to the frame
+    let mut accesses = Vec::new();
+    for (block, insts) in function.blocks.iter().enumerate() {
+        for (idx, inst) in insts.insts.iter().enumerate() {
+            let (base, offset, ty) = match inst.op {
+                Op::Load { dst, base: Value::Reg(base), offset } => (base, offset, function.vregs[dst.0 as usize]),
+                Op::Store { base: Value::Reg(base), offset, value: Value::Reg(value) } => (base, offset, function.vregs[value.0 as usize]),
+                Op::Store { base: Value::Reg(base), offset, value: Value::Const(_) } => (base, offset, function.word),
+                _ => continue,
+            };
+            if let Some(frame) = frame.get(&base) {
+                accesses.push((block, idx, frame.wrapping_add(offset), ty));
+            }
+        }
+    }
+
+    // Type of the accesses at each frame offset, None if they disagree, and
+    // the end of the widest one
+    let bytes = |ty: Ty| if ty == Ty::I32 { 4 } else { 8 };
+    let mut slots: BTreeMap<i64, (Option<Ty>, i64)> = BTreeMap::new();
+    for &(_, _, offset, ty) in &accesses {
+        let slot = slots.entry(offset).or_insert((Some(ty), offset));
+        if slot.0 != Some(ty) {
+            slot.0 = None;
+        }
+        slot.1 = slot.1.max(offset + bytes(ty));
+    }
+    let overlaps = |offset: i64, end: i64| slots.iter().any(|(other, (_, other_end))| *other != offset && *other < end && offset < *other_end);
+    let promoted: Vec<(i64, Ty)> = slots
+        .iter()
+        .filter(|(offset, (_, end))| !overlaps(**offset, *end))
+        .filter_map(|(offset, (ty, _))| Some((*offset, (*ty)?)))
+        .collect();
+    if promoted.is_empty() {
+        return false;
+    }
+
+    let registers: HashMap<i64, VReg> = promoted.into_iter().map(|(offset, ty)| (offset, function.new_vreg(ty))).collect();
+    for (block, idx, offset, _) in accesses {
+        let Some(&register) = registers.get(&offset) else { continue };
+        let inst = &mut function.blocks[block].insts[idx];
+        inst.op = match inst.op {
+            Op::Load { dst, .. } => Op::Copy { dst, src: Value::Reg(register) },
+            Op::Store { value, .. } => Op::Copy { dst: register, src: value },
+            _ => unreachable!(),
+        };
+    }
+    true
+}
+
+// On-the-fly SSA construction after Braun et al., "Simple and Efficient
+// Construction of Static Single Assignment Form"
+struct SsaBuilder {
+    preds: Vec<Vec<usize>>,
+    // Current value of each original register per block
+    defs: HashMap<(VReg, usize), Value>,
+    sealed: HashSet<usize>,
+    incomplete: HashMap<usize, Vec<(VReg, VReg)>>,
+    // Phi register -> (block, arguments in predecessor order). Ordered, so
+    // phis are inserted in the same order on every run.
+    phis: BTreeMap<VReg, (usize, Vec<Value>)>,
+}
+
+impl SsaBuilder {
+    fn read(&mut self, function: &mut Function, var: VReg, block: usize) -> Value {
+        if let Some(value) = self.defs.get(&(var, block)) {
+            return *value;
+        }
+
+        let value = if !self.sealed.contains(&block) {
+            let phi = self.new_phi(function, var, block);
+            self.incomplete.entry(block).or_default().push((var, phi));
+            Value::Reg(phi)
+        } else if self.preds[block].is_empty() {
+            // The value the register holds on function entry
+            Value::Reg(var)
+        } else if self.preds[block].len() == 1 {
+            self.read(function, var, self.preds[block][0])
+        } else {
+            let phi = self.new_phi(function, var, block);
+            // Written before reading the predecessors to break cycles
+            self.defs.insert((var, block), Value::Reg(phi));
+            self.fill_phi(function, var, phi);
+            Value::Reg(phi)
+        };
+
+        self.defs.insert((var, block), value);
+        value
+    }
+
+    fn new_phi(&mut self, function: &mut Function, var: VReg, block: usize) -> VReg {
+        let phi = function.new_vreg(function.vregs[var.0 as usize]);
+        self.phis.insert(phi, (block, Vec::new()));
+        phi
+    }
+
+    fn fill_phi(&mut self, function: &mut Function, var: VReg, phi: VReg) {
+        let block = self.phis[&phi].0;
+        let args = self.preds[block]
+            .clone()
+            .into_iter()
+            .map(|pred| self.read(function, var, pred))
+            .collect();
+        self.phis.get_mut(&phi).unwrap().1 = args;
+    }
+
+    // Seals every block whose predecessors have all been filled
+    fn seal_ready(&mut self, function: &mut Function, filled: &HashSet<usize>) {
+        for block in 0..self.preds.len() {
+            if self.sealed.contains(&block) || !self.preds[block].iter().all(|pred| filled.contains(pred)) {
+                continue;
+            }
+            self.sealed.insert(block);
+            for (var, phi) in self.incomplete.remove(&block).unwrap_or_default() {
+                self.fill_phi(function, var, phi);
+            }
+        }
+    }
+}
+
+/// Renames every definition to a fresh virtual register and inserts phis at
+/// join points. Returns false (leaving the function unchanged) if the entry
+/// block is a loop header, which would need a phi for the entry values.
+pub fn build_ssa(function: &mut Function) -> bool {
+    let preds = function.predecessors();
+    if preds.first().is_none_or(|entry| !entry.is_empty()) {
+        return false;
+    }
+
+    let mut builder = SsaBuilder {
+        preds,
+        defs: HashMap::new(),
+        sealed: HashSet::new(),
+        incomplete: HashMap::new(),
+        phis: BTreeMap::new(),
+    };
+    let mut filled = HashSet::new();
+
+    for block in 0..function.blocks.len() {
+        builder.seal_ready(function, &filled);
+
+        let mut insts = std::mem::take(&mut function.blocks[block].insts);
+        for inst in &mut insts {
+            for value in inst.op.values_mut() {
+                if let Value::Reg(var) = *value {
+                    *value = builder.read(function, var, block);
+                }
+            }
+            if let Some(dst) = inst.op.def_mut() {
+                let var = *dst;
+                *dst = function.new_vreg(function.vregs[var.0 as usize]);
+                builder.defs.insert((var, block), Value::Reg(*dst));
+            }
+        }
+        function.blocks[block].insts = insts;
+
+        filled.insert(block);
+    }
+    builder.seal_ready(function, &filled);
+
+    for (dst, (block, args)) in builder.phis.into_iter().rev() {
+        let addr = function.blocks[block].start;
+        function.blocks[block].insts.insert(0, Inst { addr, op: Op::Phi { dst, args } });
+    }
+
+    true
+}
+
+/// Replaces uses of copies (and of phis whose arguments are all the same
+/// value) with their source, folding binary ops on constants along the way.
+/// Requires SSA form.
+pub fn propagate_copies(function: &mut Function) {
+    let word = function.word;
+    loop {
+        let mut replace: HashMap<VReg, Value> = HashMap::new();
+        for inst in function.insts() {
+            match &inst.op {
+                // A copy to an i32 register of 64-bit code truncates, see
+                // `infer_widths`
+                Op::Copy { dst, src: Value::Reg(src) }
+                    if matches!((function.vregs[dst.0 as usize], function.vregs[src.0 as usize]), (Ty::I32, Ty::I64) | (Ty::I64, Ty::I32)) => {}
+                Op::Copy { dst, src } => {
+                    replace.insert(*dst, *src);
+                }
+                Op::Phi { dst, args } => {
+                    let mut distinct = args.iter().filter(|arg| **arg != Value::Reg(*dst));
+                    if let Some(first) = distinct.next() {
+                        if distinct.all(|arg| arg == first) {
+                            replace.insert(*dst, *first);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Follows chains of copies. Phis of unreachable blocks can form
+        // cycles, those are left alone.
+        let resolve = |reg: VReg| {
+            let mut visited = HashSet::new();
+            let mut value = Value::Reg(reg);
+            while let Value::Reg(current) = value {
+                let Some(next) = replace.get(&current) else { break };
+                if !visited.insert(current) {
+                    return Value::Reg(reg);
+                }
+                value = *next;
+            }
+            value
+        };
+
+        let mut changed = false;
+        let vregs = &function.vregs;
+        for inst in function.blocks.iter_mut().flat_map(|block| block.insts.iter_mut()) {
+            for value in inst.op.values_mut() {
+                if let Value::Reg(reg) = *value {
+                    let resolved = resolve(reg);
+                    if resolved != *value {
+                        *value = resolved;
+                        changed = true;
+                    }
+                }
+            }
+
+            if let Op::Binary { op, dst, lhs: Value::Const(lhs), rhs: Value::Const(rhs) } = inst.op {
+                let ty = vregs[dst.0 as usize];
+                if let Some(value) = op.fold(ty, lhs, rhs) {
+                    inst.op = Op::Copy { dst, src: Value::Const(ty.wrap(word, value)) };
+                    changed = true;
+                }
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+}
+
+/// Removes side-effect free ops whose result is never read. Requires SSA
+/// form, where every register has a single definition.
+pub fn eliminate_dead_code(function: &mut Function) {
+    loop {
+        let used: HashSet<VReg> = function.insts().flat_map(|inst| inst.op.uses()).collect();
+        let mut removed = false;
+        for block in &mut function.blocks {
+            block.insts.retain(|inst| {
+                let dead = inst.op.def().is_some_and(|dst| !used.contains(&dst)) && !inst.op.has_side_effects();
+                removed |= dead;
+                !dead
+            });
+        }
+        if !removed {
+            break;
+        }
+    }
+}
+
+/// Removes side-effect free ops whose result is overwritten or never read
+/// on any path, such as comparisons no branch looks at. Works on plain
+/// copies with backward liveness, so it also covers functions SSA
+/// construction gives up on and the copies `destroy_ssa` leaves behind.
+pub fn eliminate_dead_stores(function: &mut Function) {
+    let succs = function.successors();
+    loop {
+        let live_in = live_in(function, &succs);
+        let mut removed = false;
+        for block in 0..function.blocks.len() {
+            let mut live = live_out(&live_in, &succs, block);
+            let insts = &mut function.blocks[block].insts;
+            let mut dead = vec![false; insts.len()];
+            for (idx, inst) in insts.iter().enumerate().rev() {
+                if let Some(dst) = inst.op.def() {
+                    if !live.remove(&dst) && !inst.op.has_side_effects() {
+                        dead[idx] = true;
+                        continue;
+                    }
+                }
+                live.extend(inst.op.uses());
+            }
+            removed |= dead.contains(&true);
+            let mut dead = dead.into_iter();
+            insts.retain(|_| !dead.next().unwrap());
+        }
+        if !removed {
+            break;
+        }
+    }
+}
+
+fn live_out(live_in: &[HashSet<VReg>], succs: &[Vec<usize>], block: usize) -> HashSet<VReg> {
+    succs[block].iter().flat_map(|succ| live_in[*succ].iter().copied()).collect()
+}
+
+/// Registers live on entry to each block, by backward dataflow until a fixed
+/// point
+fn live_in(function: &Function, succs: &[Vec<usize>]) -> Vec<HashSet<VReg>> {
+    let mut live_in: Vec<HashSet<VReg>> = vec![HashSet::new(); function.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in (0..function.blocks.len()).rev() {
+            let mut live = live_out(&live_in, succs, block);
+            for inst in function.blocks[block].insts.iter().rev() {
+                if let Some(dst) = inst.op.def() {
+                    live.remove(&dst);
+                }
+                live.extend(inst.op.uses());
+            }
+            if live != live_in[block] {
+                live_in[block] = live;
+                changed = true;
+            }
+        }
+    }
+    live_in
+}
+
+/// Replaces phis with copies at the end of each predecessor.
+pub fn destroy_ssa(function: &mut Function) {
+    let preds = function.predecessors();
+    let succs = function.successors();
+    let live_in = live_in(function, &succs);
+    let mut copies: Vec<Vec<(VReg, Value)>> = vec![Vec::new(); function.blocks.len()];
+
+    for (block, block_preds) in preds.iter().enumerate() {
+        let phis: Vec<(VReg, Vec<Value>)> = function.blocks[block].insts
+            .iter()
+            .filter_map(|inst| match &inst.op {
+                Op::Phi { dst, args } => Some((*dst, args.clone())),
+                _ => None,
+            })
+            .collect();
+        function.blocks[block].insts.retain(|inst| !matches!(inst.op, Op::Phi { .. }));
+
+        // The copies run on every edge leaving a predecessor. If the phi's
+        // register is still read on another one, e.g. after the exit of a
+        // loop once copy propagation replaced its uses, or by the
+        // terminator, they go to a fresh register copied over here instead.
+        let mut isolated = Vec::new();
+        for (dst, args) in phis {
+            let clobbers = block_preds.iter().any(|pred| {
+                succs[*pred].iter().any(|succ| *succ != block && live_in[*succ].contains(&dst))
+                    || function.blocks[*pred].insts.last().is_some_and(|inst| inst.op.is_terminator() && inst.op.uses().contains(&dst))
+            });
+            let target = match clobbers {
+                true => {
+                    let temp = function.new_vreg(function.vregs[dst.0 as usize]);
+                    isolated.push(Inst { addr: function.blocks[block].start, op: Op::Copy { dst, src: Value::Reg(temp) } });
+                    temp
+                }
+                false => dst,
+            };
+            for (pred, arg) in block_preds.iter().zip(args) {
+                copies[*pred].push((target, arg));
+            }
+        }
+        function.blocks[block].insts.splice(0..0, isolated);
+    }
+
+    for (block, block_copies) in copies.into_iter().enumerate() {
+        if block_copies.is_empty() {
+            continue;
+        }
+
+        // The copies happen in parallel; go through temporaries if one of
+        // them reads a register another one writes
+        let dsts: HashSet<VReg> = block_copies.iter().map(|(dst, _)| *dst).collect();
+        let conflicting = block_copies.iter().any(|(_, src)| matches!(src, Value::Reg(reg) if dsts.contains(reg)));
+
+        let mut ops = Vec::new();
+        if conflicting {
+            let mut temps = Vec::new();
+            for (dst, src) in block_copies {
+                let temp = function.new_vreg(function.vregs[dst.0 as usize]);
+                ops.push(Op::Copy { dst: temp, src });
+                temps.push((dst, temp));
+            }
+            for (dst, temp) in temps {
+                ops.push(Op::Copy { dst, src: Value::Reg(temp) });
+            }
+        } else {
+            ops.extend(block_copies.into_iter().map(|(dst, src)| Op::Copy { dst, src }));
+        }
+
+        // Before the terminator, attributed to the instruction preceding them
+        let target = &mut function.blocks[block];
+        let at = match target.insts.last() {
+            Some(inst) if inst.op.is_terminator() => target.insts.len() - 1,
+            _ => target.insts.len(),
+        };
+        let addr = match at {
+            0 => target.start,
+            at => target.insts[at - 1].addr,
+        };
+        let insts = &mut target.insts;
+        for (offset, op) in ops.into_iter().enumerate() {
+            insts.insert(at + offset, Inst { addr, op });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::Arch;
+
+    fn lift_bytes(code: &[u8]) -> (Function, FidelityReport) {
+        lift_arch(Arch::X86_64, code)
+    }
+
+    fn lift_arch(arch: Arch, code: &[u8]) -> (Function, FidelityReport) {
+        let decoded = FunctionIR::decode("test", arch, code, 0x1000);
+        let mut report = FidelityReport::default();
+        (lift(&decoded, &mut report), report)
+    }
+
+    fn ops(function: &Function) -> Vec<Op> {
+        function.insts().map(|inst| inst.op.clone()).collect()
+    }
+
+    #[test]
+    fn lifts_load_add_store() {
+        // mov eax, [rdi]; add eax, 1; mov [rdi], eax; ret
+        let (function, report) = lift_bytes(&[0x8b, 0x07, 0x83, 0xc0, 0x01, 0x89, 0x07, 0xc3]);
+        let (rdi, rax) = (VReg(0), VReg(1));
+
+        assert!(report.unsupported.is_empty());
+        assert_eq!(ops(&function), vec![
+            Op::Load { dst: rax, base: Value::Reg(rdi), offset: 0 },
+            Op::Binary { op: BinOp::Add, dst: rax, lhs: Value::Reg(rax), rhs: Value::Const(1) },
+            Op::Store { base: Value::Reg(rdi), offset: 0, value: Value::Reg(rax) },
+            // eax and rax share a virtual register
+            Op::Return { value: Value::Reg(rax) },
+        ]);
+    }
+
+    #[test]
+    fn recovers_stdcall_parameters_in_32_bit_mode() {
+        // mov eax, [esp+4]; add eax, [esp+8]; ret 12
+        let (function, report) = lift_arch(Arch::X86, &[
+            0x8b, 0x44, 0x24, 0x04,
+            0x03, 0x44, 0x24, 0x08,
+            0xc2, 0x0c, 0x00,
+        ]);
+        let eax = VReg(1);
+
+        assert!(report.unsupported.is_empty());
+        // The third parameter is never read, but popped by `ret 12`
+        assert_eq!(function.params, vec![VReg(0), VReg(2), VReg(3)]);
+        assert!(function.vregs.iter().all(|ty| *ty == Ty::I32));
+        assert_eq!(ops(&function), vec![
+            Op::Copy { dst: eax, src: Value::Reg(VReg(0)) },
+            Op::Binary { op: BinOp::Add, dst: eax, lhs: Value::Reg(eax), rhs: Value::Reg(VReg(2)) },
+            Op::Return { value: Value::Reg(eax) },
+        ]);
+    }
+
+    #[test]
+    fn wraps_32_bit_arithmetic() {
+        // mov eax, 0x7fffffff; add eax, 1; ret
+        let (mut function, _) = lift_arch(Arch::X86, &[0xb8, 0xff, 0xff, 0xff, 0x7f, 0x83, 0xc0, 0x01, 0xc3]);
+        optimize(&mut function);
+
+        assert_eq!(ops(&function), vec![Op::Return { value: Value::Const(i32::MIN as i64) }]);
+    }
+
+    #[test]
+    fn folds_constants_and_removes_dead_copies() {
+        // mov eax, 5; add eax, 3; ret
+        let (mut function, _) = lift_bytes(&[0xb8, 0x05, 0x00, 0x00, 0x00, 0x83, 0xc0, 0x03, 0xc3]);
+        optimize(&mut function);
+
+        assert_eq!(ops(&function), vec![Op::Return { value: Value::Const(8) }]);
+    }
+
+    #[test]
+    fn promotes_spilled_stack_slots_to_registers() {
+        // push rbp; mov rbp, rsp; mov [rbp-8], rdi; mov [rbp-16], rsi;
+        // mov rax, [rbp-8]; mov rcx, [rbp-16]; add rax, rcx; pop rbp; ret
+        let (mut function, _) = lift_bytes(&[
+            0x55, 0x48, 0x89, 0xe5, 0x48, 0x89, 0x7d, 0xf8, 0x48, 0x89, 0x75, 0xf0,
+            0x48, 0x8b, 0x45, 0xf8, 0x48, 0x8b, 0x4d, 0xf0, 0x48, 0x01, 0xc8, 0x5d, 0xc3,
+        ]);
+        let (rdi, rsi) = (function.int_args[0].unwrap(), function.int_args[1].unwrap());
+        optimize(&mut function);
+        assert!(matches!(ops(&function)[..], [
+            Op::Opaque { .. },
+            Op::Binary { op: BinOp::Add, dst, lhs: Value::Reg(lhs), rhs: Value::Reg(rhs) },
+            Op::Opaque { .. },
+            Op::Return { value: Value::Reg(ret) },
+        ] if lhs == rdi && rhs == rsi && dst == ret));
+
+        // Storing rbp lets the slots be reached through memory:
+        // push rbp; mov rbp, rsp; mov [rbp-8], rdi; mov [rdi], rbp;
+        // mov rax, [rbp-8]; pop rbp; ret
+        let (mut function, _) = lift_bytes(&[
+            0x55, 0x48, 0x89, 0xe5, 0x48, 0x89, 0x7d, 0xf8, 0x48, 0x89, 0x2f, 0x48, 0x8b, 0x45, 0xf8, 0x5d, 0xc3,
+        ]);
+        optimize(&mut function);
+        assert!(ops(&function).iter().any(|op| matches!(op, Op::Load { .. })));
+    }
+
+    #[test]
+    fn keeps_stores_and_flags_read_by_branches() {
+        // mov [rdi], 0; cmp eax, 1; je +0; ret
+        let (mut function, report) = lift_bytes(&[
+            0xc7, 0x07, 0x00, 0x00, 0x00, 0x00,
+            0x83, 0xf8, 0x01,
+            0x74, 0x00,
+            0xc3,
+        ]);
+        optimize(&mut function);
+
+        assert_eq!(report.approximated.len(), 1);
+        assert!(matches!(ops(&function)[..], [
+            Op::Store { value: Value::Const(0), .. },
+            Op::Binary { op: BinOp::Sub, .. },
+            Op::Branch { cond: Cond::Eq, .. },
+            Op::Return { .. },
+        ]));
+    }
+
+    #[test]
+    fn joins_values_with_phis_and_lowers_them_to_copies() {
+        // cmp edi, 0; je ret; mov eax, 1; ret: eax
+        let (mut function, _) = lift_bytes(&[
+            0x83, 0xff, 0x00,
+            0x74, 0x05,
+            0xb8, 0x01, 0x00, 0x00, 0x00,
+            0xc3,
+        ]);
+        let rax = function.insts()
+            .find_map(|inst| match inst.op {
+                Op::Copy { dst, .. } => Some(dst),
+                _ => None,
+            })
+            .unwrap();
+
+        assert!(build_ssa(&mut function));
+        propagate_copies(&mut function);
+        eliminate_dead_code(&mut function);
+        let phi = match &function.blocks[2].insts[0].op {
+            Op::Phi { dst, args } => {
+                assert_eq!(args, &vec![Value::Reg(rax), Value::Const(1)]);
+                *dst
+            }
+            op => panic!("expected a phi, got {:?}", op),
+        };
+
+        destroy_ssa(&mut function);
+        let ops = ops(&function);
+        assert!(matches!(ops[..], [
+            Op::Binary { op: BinOp::Sub, .. },
+            Op::Copy { dst: a, src: Value::Reg(entry) },
+            Op::Branch { .. },
+            Op::Copy { dst: b, src: Value::Const(1) },
+            Op::Return { value: Value::Reg(ret) },
+        ] if a == phi && b == phi && ret == phi && entry == rax));
+    }
+
+    #[test]
+    fn keeps_phis_read_after_the_loop_exit() {
+        // mov eax, 0; loop: mov ecx, eax; add eax, 1; cmp eax, edi; jne loop;
+        // mov eax, ecx; ret
+        let (mut function, _) = lift_bytes(&[
+            0xb8, 0x00, 0x00, 0x00, 0x00,
+            0x89, 0xc1,
+            0x83, 0xc0, 0x01,
+            0x39, 0xf8,
+            0x75, 0xf7,
+            0x89, 0xc8,
+            0xc3,
+        ]);
+        optimize(&mut function);
+
+        // ecx is the phi of eax, read after the exit edge, so the back edge
+        // copies into a fresh register, which the phi's takes at the top
+        let ops = ops(&function);
+        assert!(matches!(ops[..], [
+            Op::Copy { dst: entry, src: Value::Const(0) },
+            Op::Copy { dst: phi, src: Value::Reg(top) },
+            Op::Binary { op: BinOp::Add, dst: next, lhs: Value::Reg(add), .. },
+            Op::Binary { op: BinOp::Sub, .. },
+            Op::Copy { dst: back, src: Value::Reg(copied) },
+            Op::Branch { cond: Cond::Ne, .. },
+            Op::Return { value: Value::Reg(ret) },
+        ] if entry == top && back == top && add == phi && copied == next && ret == phi && top != phi), "{:?}", ops);
+    }
+
+    #[test]
+    fn turns_pointer_arguments_into_handle_parameters() {
+        use crate::backend::TranspilerBackend;
+        use crate::transpiler_real::{DirectBackend, StateAccess, TranspileOptions};
+
+        // mov eax, [rdi]; add eax, esi; mov [rdi], eax; ret
+        let code = [0x8b, 0x07, 0x01, 0xf0, 0x89, 0x07, 0xc3];
+        let (mut function, _) = lift_bytes(&code);
+        optimize(&mut function);
+        mark_handles(&mut function);
+        let (rdi, rsi) = (function.args[0], function.args[1]);
+
+        // rsi is added, so it stays a plain register after the handle
+        assert_eq!(function.params, vec![rdi]);
+        assert_eq!(function.vregs[rdi.0 as usize], Ty::Handle);
+        assert_eq!(function.vregs[rsi.0 as usize], Ty::I64);
+
+        let decoded = FunctionIR::decode("test", Arch::X86_64, &code, 0x1000);
+        let options = TranspileOptions { state_access: StateAccess::Handles, ..Default::default() };
+        let wasm = DirectBackend::new(options).transpile(&decoded).unwrap();
+        wasmparser::Validator::new().validate_all(&wasm).unwrap();
+        let wat = wasmprinter::print_bytes(&wasm).unwrap();
+        assert!(wat.contains(r#"(import "env" "get_i64_0" (func (;0;) (type 1)))"#), "{}", wat);
+        assert!(wat.contains(r#"(import "env" "set_i64_0" (func (;1;) (type 2)))"#), "{}", wat);
+        assert!(wat.contains("(param externref) (result i64)"), "{}", wat);
+    }
+
+    #[test]
+    fn emits_identical_modules_across_runs() {
+        use crate::backend::TranspilerBackend;
+        use crate::transpiler_real::{DirectBackend, StateAccess, TranspileOptions};
+
+        // cmp edi, 0; je join; mov eax, 1; mov ecx, 2; join: add eax, ecx; ret
+        // Two registers meet at the join, so it gets more than one phi
+        let code = [
+            0x83, 0xff, 0x00,
+            0x74, 0x0a,
+            0xb8, 0x01, 0x00, 0x00, 0x00,
+            0xb9, 0x02, 0x00, 0x00, 0x00,
+            0x01, 0xc8,
+            0xc3,
+        ];
+        for state_access in [StateAccess::Memory, StateAccess::Imports, StateAccess::Handles] {
+            let options = TranspileOptions { state_access, ..Default::default() };
+            // Every run uses freshly seeded hash maps
+            let runs: Vec<Vec<u8>> = (0..8)
+                .map(|_| {
+                    let decoded = FunctionIR::decode("test", Arch::X86_64, &code, 0x1000);
+                    DirectBackend::new(options.clone()).transpile(&decoded).unwrap()
+                })
+                .collect();
+            assert!(runs.windows(2).all(|pair| pair[0] == pair[1]), "{:?}", state_access);
+        }
+    }
+
+    #[test]
+    fn drops_overwritten_registers_and_unread_flags() {
+        // cmp edi, 0; mov eax, 1; mov eax, 2; ret
+        let (mut function, _) = lift_bytes(&[
+            0x83, 0xff, 0x00,
+            0xb8, 0x01, 0x00, 0x00, 0x00,
+            0xb8, 0x02, 0x00, 0x00, 0x00,
+            0xc3,
+        ]);
+        eliminate_dead_stores(&mut function);
+        assert!(matches!(ops(&function)[..], [
+            Op::Copy { dst, src: Value::Const(2) },
+            Op::Return { value: Value::Reg(ret) },
+        ] if dst == ret));
+
+        // cmp edi, 0; je ret; mov eax, 1; ret: eax
+        let (mut function, _) = lift_bytes(&[
+            0x83, 0xff, 0x00,
+            0x74, 0x05,
+            0xb8, 0x01, 0x00, 0x00, 0x00,
+            0xc3,
+        ]);
+        let before = ops(&function);
+        eliminate_dead_stores(&mut function);
+        assert_eq!(ops(&function), before);
+    }
+
+    #[test]
+    fn rejects_thread_local_accesses() {
+        // mov rax, fs:[0x28]; ret
+        let (function, report) = lift_bytes(&[0x64, 0x48, 0x8b, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00, 0xc3]);
+        assert!(!ops(&function).iter().any(|op| matches!(op, Op::Load { .. })));
+        assert_eq!(report.unsupported, vec![(0x1000, "mov (thread-local fs/gs access)".to_string())]);
+    }
+
+    #[test]
+    fn resolves_got_slots_and_applies_the_load_bias() {
+        // mov rax, [rip+0x100] (a GOT slot); mov eax, [rax]; mov ecx, [rip+0x10]; ret
+        let code = [0x48, 0x8b, 0x05, 0x00, 0x01, 0x00, 0x00, 0x8b, 0x00, 0x8b, 0x0d, 0x10, 0x00, 0x00, 0x00, 0xc3];
+        let mut decoded = FunctionIR::decode("test", Arch::X86_64, &code, 0x1000);
+        decoded.load_bias = 0x7f00_0000_0000;
+        decoded.relocations.insert(0x1107, 0x7f00_0000_4000);
+        let function = lift(&decoded, &mut FidelityReport::default());
+        let (rax, rcx) = (VReg(0), VReg(1));
+
+        assert_eq!(ops(&function)[..3], [
+            Op::Copy { dst: rax, src: Value::Const(0x7f00_0000_4000) },
+            Op::Load { dst: rax, base: Value::Reg(rax), offset: 0 },
+            Op::Load { dst: rcx, base: Value::Const(0), offset: 0x7f00_0000_101f },
+        ]);
+    }
+
+    #[test]
+    fn lifts_bit_tests() {
+        // mov eax, 0x10; bts eax, 35; btr eax, 4; btc eax, 0; bt eax, 3; jb +0; ret
+        let (mut function, report) = lift_bytes(&[
+            0xb8, 0x10, 0x00, 0x00, 0x00,
+            0x0f, 0xba, 0xe8, 0x23,
+            0x0f, 0xba, 0xf0, 0x04,
+            0x0f, 0xba, 0xf8, 0x00,
+            0x0f, 0xba, 0xe0, 0x03,
+            0x72, 0x00,
+            0xc3,
+        ]);
+        optimize(&mut function);
+        assert!(report.unsupported.is_empty(), "{:?}", report.unsupported);
+        // The index wraps at 32 bits; bit 3 is set, so the carry reads as a
+        // negative difference
+        assert_eq!(ops(&function), vec![
+            Op::Branch { cond: Cond::Below, flags: Value::Const(-1), target: 0x1017 },
+            Op::Return { value: Value::Const(9) },
+        ]);
+
+        // bts qword [rdi], rsi; ret: rsi >> 6 selects the qword
+        let (function, _) = lift_bytes(&[0x48, 0x0f, 0xab, 0x37, 0xc3]);
+        let (rsi, rdi) = (Value::Reg(VReg(0)), Value::Reg(VReg(2)));
+        let (words, address) = (VReg(3), Value::Reg(VReg(4)));
+        assert_eq!(ops(&function)[1..5], [
+            Op::Binary { op: BinOp::Sar, dst: words, lhs: rsi, rhs: Value::Const(6) },
+            Op::Binary { op: BinOp::Shl, dst: words, lhs: Value::Reg(words), rhs: Value::Const(3) },
+            Op::Binary { op: BinOp::Add, dst: VReg(4), lhs: rdi, rhs: Value::Reg(words) },
+            Op::Load { dst: VReg(5), base: address, offset: 0 },
+        ]);
+        assert!(matches!(ops(&function)[..], [.., Op::Store { base, offset: 0, .. }, Op::Return { .. }] if base == address));
+    }
+
+    #[test]
+    fn models_the_x87_register_stack() {
+        // fld qword [rdi]; fld qword [rdi+8]; faddp st(1), st; fld qword [rdi+8];
+        // fdivp st(1), st; fchs; fstp qword [rdi+16]; fld tbyte [rdi]; ret
+        let (function, report) = lift_bytes(&[
+            0xdd, 0x07,
+            0xdd, 0x47, 0x08,
+            0xde, 0xc1,
+            0xdd, 0x47, 0x08,
+            0xde, 0xf9,
+            0xd9, 0xe0,
+            0xdd, 0x5f, 0x10,
+            0xdb, 0x2f,
+            0xc3,
+        ]);
+        // The first push takes slot 7, which ends up holding the sum, the
+        // second one slot 6
+        let (rdi, sum, b) = (Value::Reg(VReg(0)), VReg(1), VReg(2));
+
+        assert_eq!(ops(&function)[2..7], [
+            Op::Binary { op: BinOp::Add, dst: sum, lhs: Value::Reg(sum), rhs: Value::Reg(b) },
+            Op::Load { dst: b, base: rdi, offset: 8 },
+            Op::Binary { op: BinOp::Div, dst: sum, lhs: Value::Reg(sum), rhs: Value::Reg(b) },
+            Op::Binary { op: BinOp::Mul, dst: sum, lhs: Value::Reg(sum), rhs: Value::Const((-1.0f64).to_bits() as i64) },
+            Op::Store { base: rdi, offset: 16, value: Value::Reg(sum) },
+        ]);
+        assert_eq!(report.unsupported, vec![(0x1011, "fld (80-bit extended precision)".to_string())]);
+        assert_eq!(report.approximated, vec![(0x1005, "faddp".to_string()), (0x100a, "fdivp".to_string())]);
+    }
+
+    #[test]
+    fn lowers_trap_instructions_to_unreachable() {
+        use crate::backend::TranspilerBackend;
+        use crate::transpiler_real::{DirectBackend, StateAccess, TranspileOptions};
+
+        // cmp edi, 0; je ok; ud2; ok: mov eax, [rdi]; ret
+        let code = [0x83, 0xff, 0x00, 0x74, 0x02, 0x0f, 0x0b, 0x8b, 0x07, 0xc3];
+        let (function, report) = lift_bytes(&code);
+        assert!(report.unsupported.is_empty(), "{:?}", report.unsupported);
+        // The trap ends its block, so only the branch reaches the load
+        assert_eq!(function.predecessors()[2], vec![0]);
+
+        let decoded = FunctionIR::decode("test", Arch::X86_64, &code, 0x1000);
+        let options = TranspileOptions { state_access: StateAccess::Imports, trap_reason: true, ..Default::default() };
+        let backend = DirectBackend::new(options);
+        let lowered = backend.lower(&decoded).unwrap();
+        let trap: Vec<String> = lowered.wasm(&lowered.lowerings[2]).iter().map(|instr| lowered.wat_text(instr)).collect();
+        assert_eq!(trap, vec!["i32.const 6", "call $env.trap_reason", "unreachable"]);
+
+        let wasm = backend.emit(lowered);
+        wasmparser::Validator::new().validate_all(&wasm).unwrap();
+        let wat = wasmprinter::print_bytes(&wasm).unwrap();
+        assert!(wat.contains(r#"(import "env" "trap_reason" (func (;2;) (type 3)))"#), "{}", wat);
+    }
+
+    #[test]
+    fn reports_trap_addresses_through_on_trap() {
+        use crate::backend::TranspilerBackend;
+        use crate::transpiler_real::{DirectBackend, TranspileOptions, TrapStrategy};
+
+        // hlt; ud2, above 4 GiB
+        let decoded = FunctionIR::decode("test", Arch::X86_64, &[0xf4, 0x0f, 0x0b], 0x1_0000_2000);
+        let options = TranspileOptions { traps: TrapStrategy::Unreachable, on_trap: true, ..Default::default() };
+        let backend = DirectBackend::new(options);
+        let lowered = backend.lower(&decoded).unwrap();
+        assert_eq!(lowered.report.unsupported.len(), 1, "{:?}", lowered.report.unsupported);
+        let wat = |idx: usize| -> Vec<String> { lowered.wasm(&lowered.lowerings[idx]).iter().map(|instr| lowered.wat_text(instr)).collect() };
+        // The untranslated instruction is reported like the CPU's #UD
+        assert_eq!(wat(0), vec!["i32.const 6", "i32.const 8192", "i32.const 1", "call $env.on_trap", "unreachable"]);
+        assert_eq!(wat(1), vec!["i32.const 6", "i32.const 8193", "i32.const 1", "call $env.on_trap", "unreachable"]);
+
+        let wasm = backend.emit(lowered);
+        wasmparser::Validator::new().validate_all(&wasm).unwrap();
+        let wat = wasmprinter::print_bytes(&wasm).unwrap();
+        assert!(wat.contains(r#"(import "env" "on_trap" (func (;0;) (type 1)))"#), "{}", wat);
+        assert!(wat.contains("(type (;1;) (func (param i32 i32 i32)))"), "{}", wat);
+    }
+}
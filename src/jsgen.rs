@@ -0,0 +1,361 @@
+// JavaScript fallback codegen
+// Lowers the optimized IR to plain JavaScript for browsers that cannot run the
+// WASM module (missing SIMD, threads, bulk memory, ...). i64 registers become
+// BigInt variables, i32 and f64 registers Numbers; memory accesses go through a
+// DataView over the heap, asm.js style, or through env.read_i64/write_i64 in
+// imports mode. There are no externrefs, so handles mode uses the heap too.
+// Syscalls always call the browser imports.
+// Unlike the WASM lowering, branches are kept: every basic block is a case of
+// a switch inside a loop.
+//
+// The generated source is an expression evaluating to a factory
+// `(env, heap) => callback`.
+
+use crate::backend::FunctionIR;
+use crate::cpuid::{self, CpuidLeaf};
+use crate::ir::{self, BinOp, Cond, Op, Ty, VReg, Value};
+use crate::syscalls::{Syscall, SYSCALL_TRAP_BASE};
+use crate::transpiler_real::{FidelityReport, StateAccess, TranspileOptions};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+pub fn generate(function: &FunctionIR, options: TranspileOptions) -> Result<String, String> {
+    crate::budget::check_instructions(function, &options)?;
+    let mut lifted = ir::lift(function, &mut FidelityReport::default());
+    if options.inline_threshold > 0 {
+        crate::inline::inline_calls(&mut lifted, function, options.inline_threshold);
+    }
+    ir::apply_float_abi(&mut lifted, options.float_result);
+    if let Some(signature) = &options.signature {
+        ir::apply_signature(&mut lifted, signature)?;
+    }
+    ir::optimize(&mut lifted);
+    Ok(Generator {
+        function: &lifted,
+        state_access: options.state_access,
+        trap_reason: options.trap_reason,
+        on_trap: options.on_trap,
+        tsc_mhz: options.tsc_mhz,
+        cpuid: &options.cpuid,
+    }.module(function))
+}
+
+struct Generator<'a> {
+    function: &'a ir::Function,
+    state_access: StateAccess,
+    trap_reason: bool,
+    on_trap: bool,
+    tsc_mhz: Option<u32>,
+    cpuid: &'a [CpuidLeaf],
+}
+
+impl Generator<'_> {
+    fn ty(&self, reg: VReg) -> Ty {
+        match self.function.vregs[reg.0 as usize] {
+            // Flags are as wide as the registers they were computed from
+            Ty::Flags => self.function.word,
+            ty => ty,
+        }
+    }
+
+    fn constant(ty: Ty, value: i64) -> String {
+        match ty {
+            Ty::I32 => (value as i32).to_string(),
+            // `{:?}` keeps the exponent and spells out NaN and inf as Rust
+            // does, so map those to their JS names
+            Ty::F64 => match f64::from_bits(value as u64) {
+                value if value.is_nan() => "NaN".to_string(),
+                value if value.is_infinite() => format!("{}Infinity", if value < 0.0 { "-" } else { "" }),
+                value => format!("{:?}", value),
+            },
+            _ => format!("{}n", value),
+        }
+    }
+
+    fn value(&self, value: Value, ty: Ty) -> String {
+        match value {
+            Value::Reg(reg) => format!("r{}", reg.0),
+            Value::Const(constant) => Self::constant(ty, constant),
+        }
+    }
+
+    fn value_ty(&self, value: Value) -> Ty {
+        match value {
+            Value::Reg(reg) => self.ty(reg),
+            Value::Const(_) => self.function.word,
+        }
+    }
+
+    fn binary(op: BinOp, ty: Ty, lhs: &str, rhs: &str) -> String {
+        match (ty, op) {
+            (Ty::I32, BinOp::Add) => format!("({} + {}) | 0", lhs, rhs),
+            (Ty::I32, BinOp::Sub) => format!("({} - {}) | 0", lhs, rhs),
+            (Ty::I32, BinOp::Mul) => format!("Math.imul({}, {})", lhs, rhs),
+            (Ty::I32, BinOp::Shr) => format!("({} >>> {}) | 0", lhs, rhs),
+            (Ty::I32, BinOp::Shl) => format!("{} << {}", lhs, rhs),
+            (Ty::F64, BinOp::Add) => format!("{} + {}", lhs, rhs),
+            (Ty::F64, BinOp::Sub) => format!("{} - {}", lhs, rhs),
+            (Ty::F64, BinOp::Mul) => format!("{} * {}", lhs, rhs),
+            (_, BinOp::Div) => format!("{} / {}", lhs, rhs),
+            (_, BinOp::And) => format!("{} & {}", lhs, rhs),
+            (_, BinOp::Or) => format!("{} | {}", lhs, rhs),
+            (_, BinOp::Xor) => format!("{} ^ {}", lhs, rhs),
+            (Ty::I32, BinOp::Sar) => format!("{} >> {}", lhs, rhs),
+            // BigInt shifts do not wrap the count
+            (_, BinOp::Sar) => format!("{} >> ({} & 63n)", lhs, rhs),
+            (_, BinOp::Shl) => format!("BigInt.asIntN(64, {} << ({} & 63n))", lhs, rhs),
+            (_, BinOp::Add) => format!("BigInt.asIntN(64, {} + {})", lhs, rhs),
+            (_, BinOp::Sub) => format!("BigInt.asIntN(64, {} - {})", lhs, rhs),
+            (_, BinOp::Mul) => format!("BigInt.asIntN(64, {} * {})", lhs, rhs),
+            (_, BinOp::Shr) => format!("BigInt.asIntN(64, BigInt.asUintN(64, {}) >> {})", lhs, rhs),
+        }
+    }
+
+    // Heap offset (a Number) in memory mode, state offset (a BigInt) in
+    // imports mode
+    fn address(&self, base: Value, offset: i64) -> String {
+        let word = self.function.word;
+        let base = self.value(base, word);
+        match (self.state_access, word) {
+            (StateAccess::Memory | StateAccess::Handles, Ty::I32) => format!("({} + {}) >>> 0", base, offset),
+            (StateAccess::Memory | StateAccess::Handles, _) => format!("Number(BigInt.asUintN(32, {} + {}n))", base, offset),
+            (StateAccess::Imports, Ty::I32) => format!("BigInt({} + {})", base, offset),
+            (StateAccess::Imports, _) => format!("{} + {}n", base, offset),
+        }
+    }
+
+    fn condition(cond: Cond, flags: &str, ty: Ty) -> String {
+        // Branches only see the difference of the compared values, so this
+        // ignores overflow and treats unsigned conditions as signed
+        let zero = Self::constant(ty, 0);
+        let op = match cond {
+            Cond::Eq => "===",
+            Cond::Ne => "!==",
+            Cond::Gt | Cond::Above => ">",
+            Cond::Lt | Cond::Below => "<",
+            Cond::Ge | Cond::AboveEq => ">=",
+            Cond::Le | Cond::BelowEq => "<=",
+        };
+        format!("{} {} {}", flags, op, zero)
+    }
+
+    // Same lookup as the WASM lowering: a constant, or a chain of
+    // conditionals over the table where the last match wins
+    fn cpuid(&self, leaf: Value, subleaf: Value, output: u8) -> String {
+        let word = self.function.word;
+        let constant = |value: Value| match value {
+            Value::Const(value) => Some(value as u32),
+            Value::Reg(_) => None,
+        };
+        if let Some(result) = constant(leaf).and_then(|leaf| cpuid::resolve(self.cpuid, leaf, constant(subleaf))) {
+            return Self::constant(word, result[output as usize] as i64);
+        }
+        let low = |value: Value| match word {
+            Ty::I32 => format!("({} >>> 0)", self.value(value, word)),
+            _ => format!("Number(BigInt.asUintN(32, {}))", self.value(value, word)),
+        };
+        let mut result = Self::constant(word, 0);
+        for entry in cpuid::entries(self.cpuid) {
+            let mut condition = format!("{} === {}", low(leaf), entry.leaf);
+            if let Some(expected) = entry.subleaf {
+                let _ = write!(condition, " && {} === {}", low(subleaf), expected);
+            }
+            result = format!("({} ? {} : {})", condition, Self::constant(word, entry.output(output) as i64), result);
+        }
+        result
+    }
+
+    fn jump(&self, blocks: &HashMap<u64, usize>, target: u64) -> String {
+        match blocks.get(&target) {
+            Some(block) => format!("block = {}; continue;", block),
+            None => format!("throw new Error('jump out of the function to {:#x}');", target),
+        }
+    }
+
+    // Call of the trap import with `code`, as in the WASM lowering
+    fn report_trap(&self, addr: u64, code: String) -> String {
+        match (self.on_trap, self.trap_reason) {
+            (true, _) => format!("env.on_trap({}, {}, {}); ", code, addr as u32, addr >> 32),
+            (false, true) => format!("env.trap_reason({}); ", code),
+            (false, false) => String::new(),
+        }
+    }
+
+    fn statement(&self, op: &Op, addr: u64, blocks: &HashMap<u64, usize>) -> String {
+        match *op {
+            Op::Copy { dst, src } => format!("r{} = {};", dst.0, self.value(src, self.ty(dst))),
+            Op::Binary { op, dst, lhs, rhs } => {
+                let ty = self.ty(dst);
+                let expr = Self::binary(op, ty, &self.value(lhs, ty), &self.value(rhs, ty));
+                format!("r{} = {};", dst.0, expr)
+            }
+            Op::Convert { dst, src, from } => {
+                let ty = self.value_ty(src);
+                let value = self.value(src, ty);
+                let converted = match (ty, from) {
+                    _ if self.ty(dst) == Ty::I32 => format!("Number(BigInt.asIntN(32, {}))", value),
+                    (Ty::I32, _) => value,
+                    (_, Ty::I32) => format!("Number(BigInt.asIntN(32, {}))", value),
+                    _ => format!("Number({})", value),
+                };
+                format!("r{} = {};", dst.0, converted)
+            }
+            Op::Load { dst, base, offset } => {
+                let address = self.address(base, offset);
+                let load = match (self.state_access, self.ty(dst)) {
+                    (StateAccess::Memory | StateAccess::Handles, Ty::I32) => format!("heap.getInt32({}, true)", address),
+                    (StateAccess::Memory | StateAccess::Handles, Ty::F64) => format!("heap.getFloat64({}, true)", address),
+                    (StateAccess::Memory | StateAccess::Handles, _) => format!("heap.getBigInt64({}, true)", address),
+                    (StateAccess::Imports, Ty::I32) => format!("Number(BigInt.asIntN(32, env.read_i64({})))", address),
+                    (StateAccess::Imports, Ty::F64) => format!("new Float64Array(new BigInt64Array([env.read_i64({})]).buffer)[0]", address),
+                    (StateAccess::Imports, _) => format!("env.read_i64({})", address),
+                };
+                format!("r{} = {};", dst.0, load)
+            }
+            Op::Store { base, offset, value } => {
+                let address = self.address(base, offset);
+                let ty = self.value_ty(value);
+                let value = self.value(value, ty);
+                match (self.state_access, ty) {
+                    (StateAccess::Memory | StateAccess::Handles, Ty::I32) => format!("heap.setInt32({}, {}, true);", address, value),
+                    (StateAccess::Memory | StateAccess::Handles, Ty::F64) => format!("heap.setFloat64({}, {}, true);", address, value),
+                    (StateAccess::Memory | StateAccess::Handles, _) => format!("heap.setBigInt64({}, {}, true);", address, value),
+                    (StateAccess::Imports, Ty::I32) => format!("env.write_i64({}, BigInt({}));", address, value),
+                    (StateAccess::Imports, Ty::F64) => format!("env.write_i64({}, new BigInt64Array(new Float64Array([{}]).buffer)[0]);", address, value),
+                    (StateAccess::Imports, _) => format!("env.write_i64({}, {});", address, value),
+                }
+            }
+            Op::Branch { cond, flags, target } => {
+                let ty = self.value_ty(flags);
+                format!("if ({}) {{ {} }}", Self::condition(cond, &self.value(flags, ty), ty), self.jump(blocks, target))
+            }
+            Op::Jump { target } => self.jump(blocks, target),
+            Op::IndirectJump => "throw new Error('indirect jump');".to_string(),
+            Op::Trap { kind } => format!("{}throw new Error('trap: {}');", self.report_trap(addr, kind.code().to_string()), kind),
+            Op::Syscall { dst, number, args } => {
+                let word = self.function.word;
+                let syscall = match number {
+                    Value::Const(number) => Syscall::from_number(self.function.arch, number),
+                    Value::Reg(_) => None,
+                };
+                match syscall {
+                    Some(syscall) => {
+                        let args: Vec<String> = args[..syscall.arg_count()].iter().map(|arg| self.value(*arg, word)).collect();
+                        format!("r{} = env.syscall_{}({});", dst.0, syscall.name(), args.join(", "))
+                    }
+                    None => {
+                        let number = match number {
+                            Value::Const(number) => number.to_string(),
+                            Value::Reg(reg) => format!("Number(r{})", reg.0),
+                        };
+                        format!(
+                            "{}throw new Error('unsupported syscall ' + {});",
+                            self.report_trap(addr, format!("{} + {}", SYSCALL_TRAP_BASE, number)),
+                            number,
+                        )
+                    }
+                }
+            }
+            Op::Timestamp { dst } => match self.tsc_mhz {
+                Some(mhz) => format!("r{} = env.now_ns() * {}n / 1000n;", dst.0, mhz),
+                None => format!("r{} = env.now_ns();", dst.0),
+            },
+            Op::Cpuid { dst, leaf, subleaf, output } => format!("r{} = {};", dst.0, self.cpuid(leaf, subleaf, output)),
+            Op::Return { value } => format!("return {};", self.value(value, self.function.result)),
+            Op::Opaque { ref mnemonic } => format!("/* {} */", mnemonic),
+            Op::Phi { .. } => unreachable!("phis are replaced by copies before lowering"),
+        }
+    }
+
+    fn module(&self, source: &FunctionIR) -> String {
+        let blocks: HashMap<u64, usize> = self.function.blocks
+            .iter()
+            .enumerate()
+            .map(|(idx, block)| (block.start, idx))
+            .collect();
+
+        let params: Vec<String> = self.function.params.iter().map(|param| format!("r{}", param.0)).collect();
+        let locals: Vec<String> = (0..self.function.vregs.len() as u32)
+            .map(VReg)
+            .filter(|reg| !self.function.params.contains(reg))
+            .map(|reg| format!("r{} = {}", reg.0, Self::constant(self.ty(reg), 0)))
+            .collect();
+
+        let mut js = String::new();
+        let _ = writeln!(js, "(function (env, heap) {{");
+        let _ = writeln!(js, "    // {}, translated from {} code at {:#x}", source.name, source.arch.name(), source.entry);
+        let _ = writeln!(js, "    return function callback({}) {{", params.join(", "));
+        if !locals.is_empty() {
+            let _ = writeln!(js, "        let {};", locals.join(", "));
+        }
+        let _ = writeln!(js, "        let block = 0;");
+        let _ = writeln!(js, "        for (;;) {{");
+        let _ = writeln!(js, "            switch (block) {{");
+        for (idx, block) in self.function.blocks.iter().enumerate() {
+            let _ = writeln!(js, "            case {}: // {:#x}", idx, block.start);
+            for inst in &block.insts {
+                let _ = writeln!(js, "                {}", self.statement(&inst.op, inst.addr, &blocks));
+            }
+            if !block.insts.last().is_some_and(|inst| matches!(inst.op, Op::Jump { .. } | Op::IndirectJump | Op::Return { .. } | Op::Trap { .. })) {
+                let fallthrough = match idx + 1 < self.function.blocks.len() {
+                    true => format!("block = {}; continue;", idx + 1),
+                    false => format!("return {};", Self::constant(self.function.result, 0)),
+                };
+                let _ = writeln!(js, "                {}", fallthrough);
+            }
+        }
+        let _ = writeln!(js, "            default:");
+        let _ = writeln!(js, "                throw new Error(`no block ${{block}}`);");
+        let _ = writeln!(js, "            }}");
+        let _ = writeln!(js, "        }}");
+        let _ = writeln!(js, "    }};");
+        let _ = write!(js, "}})");
+        js
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::Arch;
+
+    #[test]
+    fn keeps_branches_as_block_dispatch() {
+        // cmp edi, 0; je ret; mov eax, 1; ret: eax
+        let function = FunctionIR::decode("test", Arch::X86_64, &[
+            0x83, 0xff, 0x00,
+            0x74, 0x05,
+            0xb8, 0x01, 0x00, 0x00, 0x00,
+            0xc3,
+        ], 0x1000);
+        let js = generate(&function, TranspileOptions::default()).unwrap();
+
+        assert!(js.starts_with("(function (env, heap) {"));
+        assert!(js.contains("=== 0n) { block = 2; continue; }"), "{}", js);
+        assert!(js.contains("case 2: // 0x100a"), "{}", js);
+    }
+
+    #[test]
+    fn keeps_values_read_after_the_loop_exit() {
+        // mov eax, 0; loop: mov ecx, eax; add eax, 1; cmp eax, edi; jne loop;
+        // mov eax, ecx; ret
+        let function = FunctionIR::decode("test", Arch::X86_64, &[
+            0xb8, 0x00, 0x00, 0x00, 0x00,
+            0x89, 0xc1,
+            0x83, 0xc0, 0x01,
+            0x39, 0xf8,
+            0x75, 0xf7,
+            0x89, 0xc8,
+            0xc3,
+        ], 0x1000);
+        let signature = crate::signatures::DeclaredSignature { params: vec![crate::signatures::ParamType::I64], returns: Default::default() };
+        let js = generate(&function, TranspileOptions { signature: Some(signature), ..Default::default() }).unwrap();
+
+        // The result is ecx of the last iteration: its register is only set
+        // at the top of the loop, never by the copies on the back edge
+        let result = js.lines().find_map(|line| line.trim().strip_prefix("return ")?.strip_suffix(';')).unwrap();
+        let body: Vec<&str> = js.lines().map(str::trim).skip_while(|line| !line.starts_with("case 1:")).skip(1).take_while(|line| !line.starts_with("case 2:")).collect();
+        let assigned: Vec<usize> = body.iter().enumerate().filter(|(_, line)| line.starts_with(&format!("{} = ", result))).map(|(idx, _)| idx).collect();
+        assert_eq!(assigned, vec![0], "{}", js);
+    }
+}
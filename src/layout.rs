@@ -0,0 +1,140 @@
+// State layout manifest
+// Field names, byte offsets, sizes and types of the repr(C) structs shared
+// with translated code, derived with `#[derive(SelfServeState)]` (the
+// `self-serve-derive` crate). `GET /api/state/layout` serves the manifest of
+// the application state, and struct return descriptors carry the manifest of
+// the returned struct, so the page glue can read results out of linear
+// memory instead of hardcoding offsets.
+
+use actix_web::{HttpResponse, Responder};
+use schemars::JsonSchema;
+use serde::Serialize;
+
+pub use self_serve_derive::SelfServeState;
+
+/// Implemented by `#[derive(SelfServeState)]`
+pub trait SelfServeState {
+    fn layout() -> StructLayout;
+}
+
+/// Types a `SelfServeState` struct can have as fields
+pub trait FieldLayout {
+    fn field_type() -> FieldType;
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct StructLayout {
+    pub name: &'static str,
+    pub size: usize,
+    pub align: usize,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct Field {
+    pub name: &'static str,
+    pub offset: usize,
+    pub size: usize,
+    #[serde(rename = "type")]
+    pub ty: FieldType,
+}
+
+/// Little-endian scalars, fixed-size arrays and nested structs
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FieldType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    I64,
+    U64,
+    F32,
+    F64,
+    /// One byte, 0 or 1
+    Bool,
+    Array {
+        element: Box<FieldType>,
+        len: usize,
+        /// Bytes from one element to the next
+        stride: usize,
+    },
+    Struct(StructLayout),
+}
+
+macro_rules! scalar_layout {
+    ($($ty:ty => $kind:ident),* $(,)?) => {
+        $(impl FieldLayout for $ty {
+            fn field_type() -> FieldType {
+                FieldType::$kind
+            }
+        })*
+    };
+}
+
+scalar_layout! {
+    i8 => I8,
+    u8 => U8,
+    i16 => I16,
+    u16 => U16,
+    i32 => I32,
+    u32 => U32,
+    i64 => I64,
+    u64 => U64,
+    f32 => F32,
+    f64 => F64,
+    bool => Bool,
+}
+
+impl<T: FieldLayout, const N: usize> FieldLayout for [T; N] {
+    fn field_type() -> FieldType {
+        FieldType::Array {
+            element: Box::new(T::field_type()),
+            len: N,
+            stride: std::mem::size_of::<T>(),
+        }
+    }
+}
+
+pub async fn get_state_layout() -> impl Responder {
+    HttpResponse::Ok().json(crate::State::layout())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(C)]
+    #[derive(SelfServeState)]
+    struct Todo {
+        id: u32,
+        completed: bool,
+    }
+
+    #[repr(C)]
+    #[derive(SelfServeState)]
+    struct Board {
+        flags: u8,
+        total: f64,
+        todos: [Todo; 3],
+    }
+
+    #[test]
+    fn derives_offsets_and_nested_types() {
+        let layout = Board::layout();
+        assert_eq!((layout.name, layout.size, layout.align), ("Board", 40, 8));
+        let offsets: Vec<(&str, usize, usize)> = layout.fields.iter().map(|field| (field.name, field.offset, field.size)).collect();
+        assert_eq!(offsets, vec![("flags", 0, 1), ("total", 8, 8), ("todos", 16, 24)]);
+        let FieldType::Array { element, len: 3, stride: 8 } = &layout.fields[2].ty else {
+            panic!("{:?}", layout.fields[2].ty);
+        };
+        assert_eq!(**element, FieldType::Struct(Todo::layout()));
+
+        let json = serde_json::to_value(&layout).unwrap();
+        assert_eq!(json["fields"][2]["type"]["element"]["kind"], "struct");
+        assert_eq!(json["fields"][2]["type"]["element"]["fields"][1]["type"]["kind"], "bool");
+        assert_eq!(crate::State::layout().size, crate::State { counter: 0 }.as_bytes().len());
+    }
+}
@@ -0,0 +1,1349 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::{from_fn, Condition};
+use actix_web::{web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder, ResponseError};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+mod aarch64;
+mod a11y;
+mod abi;
+mod admin;
+mod alloc;
+mod analysis_cache;
+mod api;
+pub mod arch;
+mod audit;
+mod auth;
+pub mod backend;
+mod batch;
+mod bench;
+mod binaries;
+mod bundle;
+mod budget;
+pub mod callbacks;
+mod cluster;
+mod component;
+pub mod config;
+mod coverage;
+mod cors;
+mod cpuid;
+mod csp;
+mod csrf;
+mod debug;
+mod disasm;
+mod embed;
+pub mod encode;
+mod error;
+mod events;
+mod exposure;
+mod features;
+mod glue;
+mod hooks;
+mod hot_reload;
+mod i18n;
+mod inline;
+mod ir;
+mod jsgen;
+mod layout;
+mod logging;
+mod manifest;
+mod mapping;
+mod meta;
+mod multivalue;
+mod nats;
+mod openapi;
+mod pagination;
+mod preview;
+mod profile;
+mod progress;
+mod redis;
+mod render;
+mod runtime;
+mod scan;
+mod schedule;
+mod signatures;
+#[cfg(feature = "riscv")]
+mod riscv;
+mod specialize;
+mod store;
+mod symbols;
+mod sync;
+mod syscalls;
+mod telemetry;
+mod templates;
+pub mod transpiler;
+pub mod transpiler_real;
+mod uploads;
+mod validation;
+pub mod verify;
+mod dom;
+
+use a11y::Ids;
+use auth::{Auth, Permission};
+use binaries::BinaryRegistry;
+use callbacks::{CallbackEntry, CallbackError, CallbackRegistry, CallbackValue, ValueType};
+use config::Config;
+use error::ApiError;
+use csrf::Csrf;
+use events::Broadcaster;
+use hooks::{Hooks, Invocation, Trigger};
+use i18n::Catalogs;
+use profile::Profile;
+use progress::{Phase, Progress};
+use store::StateStore;
+use sync::SyncPolicy;
+use layout::SelfServeState;
+use render::RenderDom;
+use telemetry::Telemetry;
+use templates::Templates;
+use transpiler::Transpiler;
+use validation::Rule;
+use dom::{Dom, DomNode};
+
+// repr(C) so the byte offsets used by translated code match /api/state/bytes
+// and the manifest at /api/state/layout
+#[repr(C)]
+#[derive(Clone, Serialize, JsonSchema, SelfServeState, RenderDom)]
+pub struct State {
+    pub counter: i32,
+}
+
+impl State {
+    pub fn as_bytes(&self) -> &[u8] {
+        // SAFETY: State is repr(C) and consists of plain integer fields without padding
+        unsafe { std::slice::from_raw_parts(self as *const State as *const u8, std::mem::size_of::<State>()) }
+    }
+
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as for as_bytes, and any bytes are valid integer fields
+        unsafe { std::slice::from_raw_parts_mut(self as *mut State as *mut u8, std::mem::size_of::<State>()) }
+    }
+}
+
+#[derive(Clone)]
+struct ServerContext {
+    transpiler: Arc<Transpiler>,
+    // Binaries served under a namespace besides the callback binary
+    binaries: Arc<BinaryRegistry>,
+    callbacks: Arc<CallbackRegistry>,
+    auth: Arc<Auth>,
+    csrf: Arc<Csrf>,
+    events: Arc<Broadcaster>,
+    // Error reports from browsers, see `telemetry.rs`
+    telemetry: Arc<Telemetry>,
+    // The state, its version and where it is persisted, see `store.rs`
+    state: Arc<StateStore>,
+    // How POST /sync accepts state patches, see `sync.rs`
+    sync_policy: SyncPolicy,
+    // Run around every callback execution, see `hooks.rs`
+    hooks: Arc<Hooks>,
+    // Translation catalogs of the page, see `i18n.rs`
+    i18n: Arc<Catalogs>,
+    // Page markup, see `templates.rs`
+    templates: Arc<Templates>,
+    // Warns about rendered elements without accessible names, see `a11y.rs`
+    a11y: Arc<a11y::Checker>,
+    // Content-Security-Policy of the HTML pages, see `csp.rs`
+    csp: Arc<config::CspConfig>,
+    // Arguments streamed to files before an execution, see `uploads.rs`
+    uploads: Arc<uploads::Uploads>,
+    // Every callback execution, see `audit.rs`
+    audit: Arc<audit::AuditLog>,
+    // Reports of executions in the browser, see `debug.rs`
+    debug: Arc<debug::DebugChannel>,
+    // Which symbols are served, see `exposure.rs`
+    exposure: Arc<exposure::Exposure>,
+    // Warm-ups and reloads of the transpilers, see `progress.rs`
+    progress: Arc<Progress>,
+}
+
+impl ServerContext {
+    /// Opens the state, catalogs, templates and logs `config` names around
+    /// the transpilers of `callbacks`
+    fn new(
+        config: &Config,
+        callbacks: CallbackRegistry,
+        transpiler: Arc<Transpiler>,
+        binaries: Arc<BinaryRegistry>,
+        exposure: exposure::Exposure,
+        progress: Arc<Progress>,
+    ) -> std::io::Result<Self> {
+        let backend = store::open_backend(&config.state).map_err(std::io::Error::other)?;
+        let catalogs = Catalogs::load(&config.i18n).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        tracing::info!(locales = ?catalogs.locales().collect::<Vec<_>>(), "Translation catalogs loaded");
+        let templates = Templates::load(&config.templates).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        tracing::info!(partials = ?templates.names(), "Template partials loaded");
+        let events = Arc::new(Broadcaster::new());
+        let state = Arc::new(StateStore::open(State { counter: 0 }, backend, events.clone()).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?);
+
+        Ok(ServerContext {
+            transpiler,
+            binaries,
+            callbacks: Arc::new(callbacks),
+            auth: Arc::new(Auth::load(&config.auth, Arc::new(validate_caller))),
+            csrf: Arc::new(Csrf::load(&config.cluster)),
+            events,
+            telemetry: Arc::new(Telemetry::new()),
+            state,
+            sync_policy: config.sync.policy,
+            hooks: Arc::new(register_hooks()),
+            i18n: Arc::new(catalogs),
+            templates: Arc::new(templates),
+            a11y: Arc::new(a11y::Checker::new(a11y::check_enabled())),
+            csp: Arc::new(config.csp.clone()),
+            uploads: Arc::new(uploads::Uploads::new(&config.limits)?),
+            audit: Arc::new(audit::AuditLog::open(&config.audit).map_err(std::io::Error::other)?),
+            debug: Arc::new(debug::DebugChannel::new(&config.debug)),
+            exposure: Arc::new(exposure),
+            progress,
+        })
+    }
+
+    /// Runs a callback between its hooks on the state and commits the
+    /// change, for /execute and scheduled runs. Read-only callbacks run on a
+    /// copy under the shared lock and commit nothing, the version returned
+    /// is the one they read. `session` is recorded in the audit log with the
+    /// outcome.
+    fn execute(
+        &self,
+        callback: &CallbackEntry,
+        args: &[serde_json::Value],
+        trigger: Trigger,
+        session: Option<String>,
+    ) -> Result<(CallbackValue, u64), CallbackError> {
+        let invocation = Invocation { callback: &callback.name, args, trigger };
+        let executed = match callback.read_only {
+            true => {
+                let state = self.state.read();
+                let mut copy = state.clone();
+                let executed = self.hooks
+                    .run(&invocation, &mut copy, |state| callback.invoke(state, args))
+                    .map(|result| (result, self.state.version()));
+                if copy.as_bytes() != state.as_bytes() {
+                    tracing::warn!(callback = %callback.name, "Discarded the writes of a read-only callback");
+                }
+                executed
+            }
+            false => {
+                let mut state = self.state.lock();
+                self.hooks
+                    .run(&invocation, &mut state, |state| callback.invoke(state, args))
+                    .map(|result| (result, self.state.commit(&state)))
+            }
+        };
+        let (outcome, version) = match &executed {
+            // Read-only executions committed no version
+            Ok((result, version)) => (
+                Ok(serde_json::to_value(result).expect("results serialize to JSON")),
+                Some(*version).filter(|_| !callback.read_only),
+            ),
+            Err(e) => (Err(e.to_string()), None),
+        };
+        self.audit.record(audit::Execution { session, callback: &callback.name, trigger, args, outcome, version });
+        executed
+    }
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn increment_counter(state_ptr: *mut State) -> i32 {
+    unsafe {
+        if state_ptr.is_null() {
+            return 0;
+        }
+        let state = &mut *state_ptr;
+        state.counter += 1;
+        state.counter
+    }
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn decrement_counter(state_ptr: *mut State) -> i32 {
+    unsafe {
+        if state_ptr.is_null() {
+            return 0;
+        }
+        let state = &mut *state_ptr;
+        state.counter -= 1;
+        state.counter
+    }
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn reset_counter(state_ptr: *mut State) -> i32 {
+    unsafe {
+        if state_ptr.is_null() {
+            return 0;
+        }
+        let state = &mut *state_ptr;
+        state.counter = 0;
+        0
+    }
+}
+
+// As a double, so large counters do not overflow
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn counter_squared(state_ptr: *mut State) -> f64 {
+    unsafe {
+        if state_ptr.is_null() {
+            return 0.0;
+        }
+        let counter = (*state_ptr).counter as f64;
+        counter * counter
+    }
+}
+
+// Sets the counter to the decimal number in the UTF-8 text, keeping it if the
+// text is not one
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn set_counter(state_ptr: *mut State, text_ptr: *const u8, text_len: usize) -> i32 {
+    unsafe {
+        if state_ptr.is_null() || text_ptr.is_null() {
+            return 0;
+        }
+        let state = &mut *state_ptr;
+        let text = std::slice::from_raw_parts(text_ptr, text_len);
+        if let Some(counter) = std::str::from_utf8(text).ok().and_then(|text| text.trim().parse().ok()) {
+            state.counter = counter;
+        }
+        state.counter
+    }
+}
+
+// Adds every value to the counter, wrapping around on overflow
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn add_values(state_ptr: *mut State, values: *const i32, len: usize) -> i32 {
+    unsafe {
+        if state_ptr.is_null() {
+            return 0;
+        }
+        let state = &mut *state_ptr;
+        if !values.is_null() {
+            for value in std::slice::from_raw_parts(values, len) {
+                state.counter = state.counter.wrapping_add(*value);
+            }
+        }
+        state.counter
+    }
+}
+
+#[repr(C)]
+#[derive(Default, Serialize, JsonSchema, SelfServeState)]
+pub struct CounterStats {
+    value: i32,
+    magnitude: u32,
+    is_negative: bool,
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn counter_parity(state_ptr: *mut State, out_ptr: *mut *const u8, out_len: *mut usize) -> i32 {
+    unsafe {
+        if state_ptr.is_null() || out_ptr.is_null() || out_len.is_null() {
+            return 1;
+        }
+        let parity: &'static str = if (*state_ptr).counter % 2 == 0 { "even" } else { "odd" };
+        *out_ptr = parity.as_ptr();
+        *out_len = parity.len();
+        0
+    }
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn counter_stats(state_ptr: *mut State, out: *mut CounterStats) -> i32 {
+    unsafe {
+        if state_ptr.is_null() || out.is_null() {
+            return 1;
+        }
+        let counter = (*state_ptr).counter;
+        *out = CounterStats {
+            value: counter,
+            magnitude: counter.unsigned_abs(),
+            is_negative: counter < 0,
+        };
+        0
+    }
+}
+
+/// A button executing `callback` with `args` and the values of the inputs
+/// among `fields` named "arg". It submits a form posting to /execute, which
+/// works without JavaScript: the server redirects back to the page. With
+/// JavaScript the glue intercepts the submission and calls executeCallback.
+fn callback_form(callback: &str, args: &[&str], fields: Vec<DomNode>, label: DomNode) -> DomNode {
+    let action = format!("/execute/{}", callback);
+    let mut children: Vec<DomNode> = args
+        .iter()
+        .map(|arg| DomNode::element("input", vec![("type", "hidden"), ("name", "arg"), ("value", arg)], vec![]))
+        .collect();
+    children.extend(fields);
+    children.push(DomNode::element("button", vec![("type", "submit")], vec![label]));
+    DomNode::element("form", vec![("class", "callback"), ("method", "post"), ("action", &action), ("data-callback", callback)], children)
+}
+
+// Texts are translation keys, resolved in the session's locale when the
+// page is rendered. A button per catalog switches the locale.
+// Fills the slots of the `app` partial
+fn render_app(state: &State, locales: &[&str], templates: &Templates) -> Dom {
+    let counter = state.counter.to_string();
+    let controls = [
+        ("increment_counter", "increment"),
+        ("decrement_counter", "decrement"),
+        ("reset_counter", "reset"),
+        ("counter_parity", "parity"),
+        ("counter_squared", "squared"),
+        ("counter_stats", "stats"),
+    ]
+    .iter()
+    .map(|(callback, label)| callback_form(callback, &[], vec![], DomNode::tr(label, vec![])))
+    .collect();
+    let [label, input] = a11y::labelled(
+        &mut Ids::new("app"),
+        vec![DomNode::tr("counter-input-label", vec![])],
+        DomNode::element("input", vec![("type", "number"), ("name", "arg")], vec![]),
+    );
+    let setter = callback_form("set_counter", &[], vec![label, input], DomNode::tr("set", vec![]));
+    let locale_buttons = locales
+        .iter()
+        .map(|locale| callback_form(i18n::SET_LOCALE, &[locale], vec![], DomNode::text(&locale.to_uppercase())))
+        .collect();
+    let nodes = templates.render("app", vec![
+        ("counter", vec![DomNode::tr("counter", vec![("count", &counter)])]),
+        ("controls", controls),
+        ("setter", vec![setter]),
+        ("locales", locale_buttons),
+    ]);
+    Dom {
+        nodes: nodes.unwrap_or_else(|e| vec![DomNode::text(&e)]),
+    }
+}
+
+// Style of the page, also exported as app.css, see `bundle.rs`
+const APP_CSS: &str = "\
+body { font-family: Arial, sans-serif; max-width: 600px; margin: 50px auto; }
+.container { text-align: center; }
+.counter-display { font-size: 24px; margin: 20px 0; }
+form.callback { display: inline; }
+button { margin: 5px; padding: 10px 20px; font-size: 16px; cursor: pointer; }
+";
+
+async fn index(req: HttpRequest, ctx: web::Data<ServerContext>) -> impl Responder {
+    if api::accepts_json(&req) {
+        return api::state_response(&ctx);
+    }
+    
+    let session = ctx.csrf.session(&req);
+    let state = ctx.state.read();
+    let mut dom = render_app(&state, &ctx.i18n.locales().collect::<Vec<_>>(), &ctx.templates);
+    let locale = ctx.i18n.locale_for(&req);
+    ctx.a11y.check_rendered("/", &dom);
+    let mut policy = csp::Policy::new();
+    policy.apply(&mut dom);
+    let token = ctx.csrf.token_for(&session.id);
+    csrf::embed_token(&mut dom, &token);
+    
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html lang="{}">
+<head>
+    <meta charset="utf-8">
+    <meta name="csrf-token" content="{}">{}{}
+    <title>x64 to WASM Server</title>
+    <style nonce="{}">
+{}    </style>
+    <script nonce="{}">{}    </script>
+</head>
+<body>
+{}
+</body>
+</html>"#,
+        locale,
+        token,
+        match ctx.debug.enabled() {
+            true => "\n    <meta name=\"debug-channel\" content=\"/debug/ws\">",
+            false => "",
+        },
+        match ctx.sync_policy {
+            SyncPolicy::Paranoid => "\n    <meta name=\"sync-policy\" content=\"paranoid\">",
+            _ => "",
+        },
+        policy.nonce(),
+        APP_CSS,
+        policy.nonce(),
+        glue::script(&ctx.callbacks, csrf::CSRF_HEADER),
+        dom.to_html_localized(&|key, args| ctx.i18n.translate(locale, key, args))
+    );
+    
+    let mut response = HttpResponse::Ok();
+    response.content_type("text/html; charset=utf-8");
+    response.insert_header((header::VARY, "Accept, Accept-Language"));
+    if let Some(csp) = ctx.csp.header(&policy) {
+        response.insert_header(csp);
+    }
+    if session.is_new {
+        response.cookie(ctx.csrf.session_cookie(&session));
+    }
+    response.body(html)
+}
+
+#[derive(Deserialize)]
+struct WasmQuery {
+    /// "core" (default) or "component"
+    format: Option<String>,
+    /// `[transpiler] profile` if unset
+    profile: Option<Profile>,
+}
+
+async fn get_wasm(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<WasmQuery>,
+    ctx: web::Data<ServerContext>,
+) -> impl Responder {
+    let fn_name = path.into_inner();
+    
+    let profile = query.profile.unwrap_or_else(|| ctx.transpiler.default_profile());
+    let (Some(wasm_bytes), Some(callback)) = (ctx.transpiler.get_wasm_with_profile(&fn_name, profile), ctx.callbacks.get(&fn_name)) else {
+        return ApiError::not_found("Function not found").error_response();
+    };
+    let specialized = match profile {
+        Profile::Release => ctx.transpiler.get_specialized_wasm(&fn_name, &specialize::session_values(&req)),
+        Profile::Debug => None,
+    };
+    let wasm_bytes = match specialized {
+        Some(Ok(specialized)) => specialized,
+        Some(Err(e)) => return ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "specialization_failed", e).error_response(),
+        None => wasm_bytes,
+    };
+    
+    let wasm_bytes = match query.format.as_deref() {
+        None | Some("core") => wasm_bytes,
+        Some("component") => match component::wrap(&fn_name, &callback.signature, &wasm_bytes) {
+            Ok(component) => component,
+            Err(e) => return ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "unsupported_signature", e).error_response(),
+        },
+        Some(other) => return ApiError::bad_request(format!("Unknown format: {}", other)).error_response(),
+    };
+    
+    HttpResponse::Ok()
+        .content_type("application/wasm")
+        .insert_header((header::VARY, "Cookie"))
+        .body(wasm_bytes)
+}
+
+async fn get_wit(
+    path: web::Path<String>,
+    ctx: web::Data<ServerContext>,
+) -> impl Responder {
+    let fn_name = path.into_inner();
+    let Some(callback) = ctx.callbacks.get(&fn_name) else {
+        return ApiError::not_found("Function not found").error_response();
+    };
+    
+    let core = ctx.transpiler.get_wasm_for_function(&fn_name);
+    match component::wit(&fn_name, &callback.signature, core.as_deref()) {
+        Ok(wit) => HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .body(wit),
+        Err(e) => ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "unsupported_signature", e).error_response(),
+    }
+}
+
+async fn get_wat(
+    path: web::Path<String>,
+    ctx: web::Data<ServerContext>,
+) -> impl Responder {
+    let fn_name = path.into_inner();
+    
+    match ctx.transpiler.wat(&fn_name) {
+        Some(Ok(wat)) => HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .body(wat),
+        Some(Err(e)) => ApiError::internal(e).error_response(),
+        None => ApiError::not_found("Function not found").error_response(),
+    }
+}
+
+// Modules of a `[binaries.<namespace>]` binary, core format only: there is
+// no signature to build a component from
+async fn get_namespaced_wasm(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    query: web::Query<WasmQuery>,
+    ctx: web::Data<ServerContext>,
+) -> impl Responder {
+    let (namespace, fn_name) = path.into_inner();
+    
+    let binary = ctx.binaries.get(&namespace).filter(|_| ctx.exposure.exposes_in(&namespace, &fn_name));
+    let Some(binary) = binary else {
+        return ApiError::not_found("Function not found").error_response();
+    };
+    let profile = query.profile.unwrap_or_else(|| binary.default_profile());
+    let Some(wasm_bytes) = binary.get_wasm_with_profile(&fn_name, profile) else {
+        return ApiError::not_found("Function not found").error_response();
+    };
+    let specialized = match profile {
+        Profile::Release => binary.get_specialized_wasm(&fn_name, &specialize::session_values(&req)),
+        Profile::Debug => None,
+    };
+    let wasm_bytes = match specialized {
+        Some(Ok(specialized)) => specialized,
+        Some(Err(e)) => return ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "specialization_failed", e).error_response(),
+        None => wasm_bytes,
+    };
+    
+    HttpResponse::Ok()
+        .content_type("application/wasm")
+        .insert_header((header::VARY, "Cookie"))
+        .body(wasm_bytes)
+}
+
+// The shared runtime of `runtime.rs`, of the callback binary or of the
+// `[binaries.<namespace>]` binary the route names
+async fn get_runtime(
+    req: HttpRequest,
+    ctx: web::Data<ServerContext>,
+) -> impl Responder {
+    let transpiler = match req.match_info().get("namespace") {
+        Some(namespace) => ctx.binaries.get(namespace),
+        None => Some(&ctx.transpiler),
+    };
+    match transpiler.and_then(|transpiler| transpiler.runtime()) {
+        Some(Ok(wasm)) => HttpResponse::Ok()
+            .content_type("application/wasm")
+            .body(wasm),
+        Some(Err(e)) => ApiError::internal(e).error_response(),
+        None => ApiError::not_found("No shared runtime").error_response(),
+    }
+}
+
+async fn get_namespaced_wat(
+    path: web::Path<(String, String)>,
+    ctx: web::Data<ServerContext>,
+) -> impl Responder {
+    let (namespace, fn_name) = path.into_inner();
+    
+    let binary = ctx.binaries.get(&namespace).filter(|_| ctx.exposure.exposes_in(&namespace, &fn_name));
+    match binary.and_then(|binary| binary.wat(&fn_name)) {
+        Some(Ok(wat)) => HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .body(wat),
+        Some(Err(e)) => ApiError::internal(e).error_response(),
+        None => ApiError::not_found("Function not found").error_response(),
+    }
+}
+
+async fn get_report(
+    path: web::Path<String>,
+    ctx: web::Data<ServerContext>,
+) -> impl Responder {
+    let fn_name = path.into_inner();
+    if ctx.callbacks.get(&fn_name).is_none() {
+        return ApiError::not_found("Function not found").error_response();
+    }
+    
+    HttpResponse::Ok().json(ctx.transpiler.size_report(&fn_name))
+}
+
+#[derive(Deserialize)]
+struct JsQuery {
+    // An ES module exporting the factory instead of a bare expression
+    #[serde(default)]
+    module: bool,
+}
+
+async fn get_js(
+    path: web::Path<String>,
+    query: web::Query<JsQuery>,
+    ctx: web::Data<ServerContext>,
+) -> impl Responder {
+    let fn_name = path.into_inner();
+    if ctx.callbacks.get(&fn_name).is_none() {
+        return ApiError::not_found("Function not found").error_response();
+    }
+    
+    match ctx.transpiler.javascript(&fn_name) {
+        Ok(js) => HttpResponse::Ok()
+            .content_type("text/javascript; charset=utf-8")
+            .body(match query.module {
+                true => javascript_module(&js),
+                false => js,
+            }),
+        Err(e) => ApiError::internal(e).error_response(),
+    }
+}
+
+/// The JavaScript translation `js` as an ES module exporting its factory
+fn javascript_module(js: &str) -> String {
+    format!("export default {};\n", js.trim_end().trim_end_matches(';'))
+}
+
+async fn execute_callback(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: Result<web::Bytes, actix_web::Error>,
+    ctx: web::Data<ServerContext>,
+) -> impl Responder {
+    let fn_name = path.into_inner();
+    
+    // Registered callbacks and the built-in set_locale, which needs no
+    // permission as it only changes the session
+    let callback = ctx.callbacks.get(&fn_name);
+    if callback.is_none() && fn_name != i18n::SET_LOCALE {
+        return ApiError::not_found("Unknown callback").error_response();
+    }
+    
+    // A form of `callback_form` submitted without JavaScript carries its
+    // token in the body
+    let is_form = req.content_type() == "application/x-www-form-urlencoded";
+    if !is_form {
+        if let Err(e) = ctx.csrf.verify(&req) {
+            return e.error_response();
+        }
+    }
+    
+    if let Some(Err(e)) = callback.map(|callback| ctx.auth.authorize(&req, callback.permission)) {
+        return e.into_response(&ctx.auth);
+    }
+    
+    let body = match body {
+        Ok(body) => body,
+        Err(e) => return error::payload_error(e).error_response(),
+    };
+    // The client parameters of the signature as a JSON array, none if empty
+    let mut args: Vec<serde_json::Value> = match (is_form, body.is_empty()) {
+        (true, _) => {
+            let (token, args) = match form_submission(callback, &body) {
+                Ok(submission) => submission,
+                Err(e) => return e.error_response(),
+            };
+            if let Err(e) = ctx.csrf.verify_form(&req, token.as_deref()) {
+                return e.error_response();
+            }
+            args
+        }
+        (false, true) => Vec::new(),
+        (false, false) => match serde_json::from_slice(&body) {
+            Ok(args) => args,
+            Err(e) => return ApiError::bad_request(format!("Arguments must be a JSON array: {}", e)).error_response(),
+        },
+    };
+
+    if let Err(e) = ctx.uploads.resolve(&mut args) {
+        return ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "unknown_upload", e).error_response();
+    }
+
+    let response = match callback {
+        Some(callback) => execute_response(&req, &ctx, callback, &args),
+        None => i18n::set_locale(&ctx, &args),
+    };
+    if !is_form || !response.status().is_success() {
+        return response;
+    }
+    // Back to the page, which renders the new state
+    let mut redirect = HttpResponse::SeeOther();
+    redirect.insert_header((header::LOCATION, "/"));
+    for cookie in response.cookies() {
+        redirect.cookie(cookie);
+    }
+    redirect.finish()
+}
+
+/// The `_csrf` token and the "arg" fields of a submitted `callback_form`.
+/// String parameters take the fields as they are, others parse them as JSON.
+fn form_submission(callback: Option<&CallbackEntry>, body: &[u8]) -> Result<(Option<String>, Vec<serde_json::Value>), ApiError> {
+    let fields: Vec<(String, String)> = serde_urlencoded::from_bytes(body)
+        .map_err(|e| ApiError::bad_request(format!("Invalid form: {}", e)))?;
+    let mut token = None;
+    let mut args = Vec::new();
+    for (name, value) in fields {
+        match name.as_str() {
+            csrf::FORM_FIELD => token = Some(value),
+            "arg" => {
+                // set_locale, without a callback, takes a string
+                let param = callback.and_then(|callback| callback.signature.client_params().nth(args.len()));
+                args.push(match param {
+                    None | Some(ValueType::Str) => serde_json::Value::String(value),
+                    Some(_) => serde_json::from_str(&value).unwrap_or(serde_json::Value::String(value)),
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok((token, args))
+}
+
+fn execute_response(req: &HttpRequest, ctx: &ServerContext, callback: &CallbackEntry, args: &[serde_json::Value]) -> HttpResponse {
+    match ctx.execute(callback, args, Trigger::Execute, audit::session_digest(req)) {
+        Ok((result, version)) => {
+            tracing::info!(callback = %callback.name, ?result, "executed callback");
+            HttpResponse::Ok().json(serde_json::json!({ "version": version, "result": result }))
+        }
+        Err(e) => {
+            tracing::warn!(callback = %callback.name, error = %e, "callback failed");
+            ApiError::from(e).error_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ExecuteQuery {
+    /// The client parameters as a JSON array
+    args: Option<String>,
+}
+
+/// GET /execute/{fn_name}?args=[...] for read-only callbacks, which change
+/// nothing and so need no CSRF token and can be linked to or prefetched
+async fn query_callback(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<ExecuteQuery>,
+    ctx: web::Data<ServerContext>,
+) -> impl Responder {
+    let Some(callback) = ctx.callbacks.get(path.as_str()) else {
+        return ApiError::not_found("Unknown callback").error_response();
+    };
+    if !callback.read_only {
+        let mut response = ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "not_read_only", "Only read-only callbacks can be executed with GET")
+            .error_response();
+        response.headers_mut().insert(header::ALLOW, header::HeaderValue::from_static("POST"));
+        return response;
+    }
+    if let Err(e) = ctx.auth.authorize(&req, callback.permission) {
+        return e.into_response(&ctx.auth);
+    }
+    let args: Vec<serde_json::Value> = match query.args.as_deref() {
+        None => Vec::new(),
+        Some(args) => match serde_json::from_str(args) {
+            Ok(args) => args,
+            Err(e) => return ApiError::bad_request(format!("Arguments must be a JSON array: {}", e)).error_response(),
+        },
+    };
+    execute_response(&req, &ctx, callback, &args)
+}
+
+pub fn register_callbacks() -> CallbackRegistry {
+    let mut registry = CallbackRegistry::new();
+    registry
+        .register("increment_counter", increment_counter, Permission::User)
+        .register("decrement_counter", decrement_counter, Permission::User)
+        .register("reset_counter", reset_counter, Permission::Admin)
+        .register_str_arg("set_counter", set_counter, Permission::User)
+        .register_slice_arg("add_values", add_values, Permission::User)
+        .register_f64("counter_squared", counter_squared, Permission::Public)
+        .register_string("counter_parity", counter_parity, Permission::Public)
+        .register_struct("counter_stats", counter_stats, Permission::Public)
+        .read_only("counter_squared")
+        .read_only("counter_parity")
+        .read_only("counter_stats")
+        .validate("set_counter", 0, vec![Rule::max_length(11), Rule::pattern("-?[0-9]+")])
+        .validate("add_values", 0, vec![Rule::max_length(64), Rule::range(-1000.0, 1000.0)]);
+    registry
+}
+
+fn register_hooks() -> Hooks {
+    let mut hooks = Hooks::new();
+    hooks
+        // The native callbacks would overflow, which aborts a debug build
+        .before(|invocation, state| match (invocation.callback, state.counter) {
+            ("increment_counter", i32::MAX) | ("decrement_counter", i32::MIN) => Err("the counter is at its limit".to_string()),
+            _ => Ok(()),
+        })
+        .after(|invocation, old, new| {
+            if old.counter != new.counter {
+                tracing::debug!(callback = invocation.callback, args = ?invocation.args, trigger = ?invocation.trigger, from = old.counter, to = new.counter, "counter changed");
+            }
+        });
+    hooks
+}
+
+// Callers as an authenticating reverse proxy reports them, for `[auth]
+// validator = true`: X-Forwarded-User makes a user, "admin" among the
+// comma-separated X-Forwarded-Groups an admin. Only safe if the server can
+// be reached through the proxy alone.
+fn validate_caller(req: &HttpRequest) -> Option<Permission> {
+    let Some(user) = req.headers().get("X-Forwarded-User") else { return Some(Permission::Public) };
+    if user.to_str().map_or(true, str::is_empty) {
+        return None;
+    }
+    let groups = req.headers().get("X-Forwarded-Groups").and_then(|groups| groups.to_str().ok()).unwrap_or_default();
+    match groups.split(',').any(|group| group.trim() == "admin") {
+        true => Some(Permission::Admin),
+        false => Some(Permission::User),
+    }
+}
+
+// Transpiles the callbacks and the symbols of every `[binaries]` binary, as
+// one run of `progress.rs`
+fn warm_up(transpiler: &Transpiler, binaries: &BinaryRegistry, progress: &Progress) {
+    progress.begin(Phase::Warmup, 0);
+    transpiler.warm_up();
+    for (_, binary) in binaries.iter() {
+        binary.warm_up();
+    }
+    progress.end();
+}
+
+// Every route of the server, with the body limits of `limits`
+fn routes(cfg: &mut web::ServiceConfig, limits: &config::LimitsConfig) {
+    cfg
+        .app_data(error::json_config(web::JsonConfig::default()))
+        .app_data(web::QueryConfig::default().error_handler(error::query_error))
+        .route("/", web::get().to(index))
+        .route("/wasm/manifest.json", web::get().to(manifest::manifest))
+        .route(manifest::HASHED_ROUTE, web::get().to(manifest::hashed_wasm))
+        // Before the callback routes, which would match them too
+        .route("/wasm/runtime.wasm", web::get().to(get_runtime))
+        .route("/wasm/{namespace}/runtime.wasm", web::get().to(get_runtime))
+        .route("/wasm/{fn_name}", web::get().to(get_wasm))
+        .route("/wat/{fn_name}", web::get().to(get_wat))
+        // Before the namespaced routes, which would match it too
+        .route("/wasm/{fn_name}/requirements", web::get().to(features::get_requirements))
+        .route("/wasm/{namespace}/{fn_name}", web::get().to(get_namespaced_wasm))
+        .route("/wat/{namespace}/{fn_name}", web::get().to(get_namespaced_wat))
+        .route("/wit/{fn_name}", web::get().to(get_wit))
+        .route("/js/{fn_name}", web::get().to(get_js))
+        .route("/report/{fn_name}", web::get().to(get_report))
+        .route("/disasm/{fn_name}", web::get().to(disasm::disasm))
+        .route("/mapping/{fn_name}", web::get().to(mapping::mapping))
+        .service(web::resource("/execute/{fn_name}")
+            .app_data(web::PayloadConfig::new(limits.execute_bytes))
+            .route(web::post().to(execute_callback))
+            .route(web::get().to(query_callback)))
+        .service(web::resource("/preview/{fn_name}")
+            .app_data(web::PayloadConfig::new(limits.execute_bytes))
+            .route(web::post().to(preview::preview)))
+        .service(web::resource("/execute-batch")
+            .app_data(error::json_config(web::JsonConfig::default().limit(limits.batch_bytes)))
+            .route(web::post().to(batch::execute_batch)))
+        .service(web::resource("/sync")
+            .app_data(error::json_config(web::JsonConfig::default().limit(limits.sync_bytes)))
+            .route(web::post().to(sync::sync)))
+        .service(web::resource("/telemetry")
+            .app_data(error::json_config(web::JsonConfig::default().limit(limits.telemetry_bytes)))
+            .route(web::post().to(telemetry::report)))
+        .route("/session/globals", web::post().to(specialize::set_globals))
+        .route("/upload", web::post().to(uploads::upload))
+        .route("/events", web::get().to(events::subscribe))
+        .route("/debug/ws", web::get().to(debug::socket))
+        .route("/api/state", web::get().to(api::get_state))
+        .route("/api/state/bytes", web::get().to(api::get_state_bytes))
+        .route("/api/state/layout", web::get().to(layout::get_state_layout))
+        .route("/api/dom", web::get().to(api::get_dom))
+        .route("/openapi.json", web::get().to(openapi::openapi_json))
+        .route("/admin", web::get().to(admin::dashboard))
+        .route("/admin/retranspile/{fn_name}", web::post().to(admin::retranspile))
+        .route("/admin/errors", web::get().to(admin::errors))
+        .route("/admin/audit", web::get().to(admin::audit))
+        .route("/admin/verify", web::get().to(admin::verify))
+        .route("/admin/bench", web::get().to(admin::bench))
+        .route("/admin/export", web::get().to(admin::export))
+        .route("/admin/probe/{fn_name}", web::get().to(admin::probe))
+        .route("/admin/instructions", web::get().to(admin::instructions))
+        .route("/admin/transpile-progress", web::get().to(admin::transpile_progress))
+        .default_service(web::to(error::no_route));
+}
+
+/// Runs the server, or `scan` when that is the first argument
+pub async fn serve() -> std::io::Result<()> {
+    let port = std::env::var("RUN_AS_HTTP_SERVER")
+        .unwrap_or_else(|_| "8080".to_string())
+        .parse::<u16>()
+        .unwrap_or(8080);
+    
+    // Before logging, which shares stdout with the report
+    if std::env::args().nth(1).as_deref() == Some("scan") {
+        return scan::run(std::env::args().skip(2));
+    }
+    
+    logging::init();
+    
+    let config = Config::load().map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    
+    let exposure = exposure::Exposure::new(&config.exposure);
+    let mut callbacks = register_callbacks();
+    exposure.apply(&mut callbacks);
+    
+    tracing::info!(backend = ?config.transpiler.backend, "Analyzing binary...");
+    let transpiler = Arc::new(Transpiler::new(callbacks.iter(), &config.transpiler));
+    let binaries = Arc::new(
+        BinaryRegistry::new(&config, transpiler.symbols(), &exposure).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+    );
+    let progress = Arc::new(Progress::new());
+    transpiler.report_to(progress.clone());
+    for (_, binary) in binaries.iter() {
+        binary.report_to(progress.clone());
+    }
+    
+    // `x64_to_wasm_server export <directory|file.tar>` writes the static
+    // bundle instead of serving, see `bundle.rs`, and
+    // `x64_to_wasm_server instructions` prints the instruction profile of
+    // the binary, see `coverage::profile`
+    let mut args = std::env::args().skip(1);
+    let command = args.next();
+    if command.as_deref() == Some("instructions") {
+        let profile = transpiler.instruction_profile().map_err(std::io::Error::other)?;
+        print!("{}", profile);
+        return Ok(());
+    }
+    if command.as_deref() == Some("export") {
+        let target = args.next().map(std::path::PathBuf::from).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Usage: x64_to_wasm_server export <directory|file.tar>")
+        })?;
+        warm_up(&transpiler, &binaries, &progress);
+        bundle::build(&callbacks, &transpiler, &binaries).write_to(&target)?;
+        tracing::info!(path = %target.display(), "Bundle exported");
+        return Ok(());
+    }
+    
+    if config.transpiler.verify {
+        for verification in verify::verify_all(callbacks.iter(), &transpiler) {
+            match (&verification.skipped, verification.matches()) {
+                (Some(reason), _) => tracing::info!(callback = %verification.callback, %reason, "verification skipped"),
+                (None, true) => tracing::info!(callback = %verification.callback, "verification passed"),
+                (None, false) => tracing::warn!(
+                    callback = %verification.callback,
+                    mismatches = verification.cases.iter().filter(|case| !case.matches).count(),
+                    "WASM lowering differs from native execution"
+                ),
+            }
+        }
+    }
+    
+    let context = ServerContext::new(&config, callbacks, transpiler, binaries, exposure, progress)?;
+    
+    if let Err(e) = context.state.watch() {
+        tracing::warn!(error = %e, "Could not watch the state backend");
+    }
+    if let Err(e) = cluster::start(&config.cluster, context.events.clone(), context.state.clone()) {
+        tracing::warn!(error = %e, "Could not join the cluster, events stay on this instance");
+    }
+    tracing::info!(backend = ?config.state.backend, version = context.state.version(), "State loaded");
+    schedule::start(&config.schedule, &context);
+    
+    // Kept alive until the server shuts down, one per binary, one for the
+    // template partials and one for the config file
+    let _watchers: Vec<_> = if hot_reload::enabled() {
+        let transpilers = std::iter::once(context.transpiler.clone())
+            .chain(context.binaries.iter().map(|(_, transpiler)| transpiler.clone()))
+            .collect();
+        std::iter::once(&context.transpiler)
+            .chain(context.binaries.iter().map(|(_, transpiler)| transpiler))
+            .filter_map(|transpiler| {
+                hot_reload::watch(transpiler.clone(), context.events.clone(), context.state.clone())
+                    .map_err(|e| tracing::warn!(error = %e, "Could not start hot reload"))
+                    .ok()
+            })
+            .chain(
+                hot_reload::watch_templates(context.templates.clone(), context.events.clone(), context.state.clone())
+                    .map_err(|e| tracing::warn!(error = %e, "Could not watch the template partials"))
+                    .ok(),
+            )
+            .chain(Config::path().and_then(|path| {
+                hot_reload::watch_config(&path, transpilers, context.events.clone(), context.state.clone())
+                    .map_err(|e| tracing::warn!(error = %e, "Could not watch the config file"))
+                    .ok()
+            }))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    
+    // Modules are served as they are translated, while the dashboard follows
+    // the warm-up
+    let warming = context.clone();
+    std::thread::spawn(move || {
+        tracing::info!("Transpiling functions...");
+        warm_up(&warming.transpiler, &warming.binaries, &warming.progress);
+        tracing::info!(run = ?warming.progress.run(), "Transpiled functions");
+    });
+    
+    tracing::info!("Starting server on http://127.0.0.1:{}", port);
+    tracing::info!(auth = context.auth.describe(), "Authentication configured");
+    for callback in context.callbacks.iter() {
+        tracing::info!(callback = %callback.name, permission = ?callback.permission, "Available callback");
+    }
+    for (namespace, transpiler) in context.binaries.iter() {
+        for symbol in transpiler.symbols() {
+            tracing::info!(callback = %format!("{}/{}", namespace, symbol), "Available translated callback");
+        }
+    }
+    
+    let limits = config.limits.clone();
+    let cors_config = config.cors;
+    if !cors_config.allowed_origins.is_empty() {
+        tracing::info!(origins = ?cors_config.allowed_origins, "CORS enabled");
+    }
+    
+    HttpServer::new(move || {
+        App::new()
+            .wrap(Condition::new(cors_config.cross_origin_isolation, cors::isolation_headers()))
+            .wrap(Condition::new(!cors_config.allowed_origins.is_empty(), cors::middleware(&cors_config)))
+            .wrap(from_fn(logging::trace_request))
+            .app_data(web::Data::new(context.clone()))
+            .configure(|cfg| routes(cfg, &limits))
+    })
+    .bind(("127.0.0.1", port))?
+    .run()
+    .await
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use actix_web::dev::{Service, ServiceResponse};
+    use actix_web::test;
+    use serde_json::json;
+
+    // Served by the test server. Written in assembly, so a debug build has
+    // no overflow check calling the panic handler, which the lowering drops.
+    std::arch::global_asm!(
+        ".text",
+        ".globl served_increment",
+        ".type served_increment, @function",
+        "served_increment:",
+        "mov eax, [rdi]",
+        "add eax, 1",
+        "mov [rdi], eax",
+        "ret",
+        ".size served_increment, . - served_increment",
+    );
+    extern "C" {
+        fn served_increment(state: *mut State) -> i32;
+    }
+
+    // The routes on a context serving `served_increment`, translated, and
+    // the context
+    async fn server(config: &Config) -> (impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>, ServerContext) {
+        server_with_hooks(config, register_hooks()).await
+    }
+
+    // `server`, running `hooks` around executions
+    async fn server_with_hooks(config: &Config, hooks: Hooks) -> (impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>, ServerContext) {
+        let native: unsafe extern "C" fn(*mut State) -> i32 = served_increment;
+        // SAFETY: served_increment only reads and writes the counter of a valid state
+        let native = unsafe { std::mem::transmute::<unsafe extern "C" fn(*mut State) -> i32, callbacks::NativeCallback>(native) };
+        let mut callbacks = CallbackRegistry::new();
+        callbacks.register("served_increment", native, Permission::Public);
+        let transpiler = Arc::new(Transpiler::new(callbacks.iter(), &config.transpiler));
+        transpiler.retranspile("served_increment").unwrap();
+        let exposure = exposure::Exposure::new(&config.exposure);
+        let binaries = Arc::new(BinaryRegistry::new(config, transpiler.symbols(), &exposure).unwrap());
+        let mut context = ServerContext::new(config, callbacks, transpiler, binaries, exposure, Arc::new(Progress::new())).unwrap();
+        context.hooks = Arc::new(hooks);
+        let limits = config.limits.clone();
+        let app = test::init_service(App::new().app_data(web::Data::new(context.clone())).configure(move |cfg| routes(cfg, &limits))).await;
+        (app, context)
+    }
+
+    // GET /wasm/served_increment
+    async fn served(app: &impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>) -> Vec<u8> {
+        let response = test::call_service(app, test::TestRequest::get().uri("/wasm/served_increment").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        test::read_body(response).await.to_vec()
+    }
+
+    // The state and the whole linear memory after running a served module
+    // on `state`, as the glue does
+    fn run_served(wasm: &[u8], state: &State) -> (Vec<u8>, Vec<u8>) {
+        let compiled = verify::Compiled::new(wasm).unwrap();
+        let mut runner = compiled.instantiate(&verify::VerifyLimits::default()).unwrap();
+        let (outcome, _) = runner.run(state);
+        assert_eq!(outcome.trap, None);
+        (outcome.state, runner.memory_image().unwrap())
+    }
+
+    // POST /sync of a browser session, with its CSRF token
+    async fn post_sync(
+        app: &impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>,
+        context: &ServerContext,
+        body: serde_json::Value,
+    ) -> ServiceResponse {
+        post_json(app, context, "/sync", body).await
+    }
+
+    // POST of `body` to `uri` by a browser session, with its CSRF token
+    async fn post_json(
+        app: &impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>,
+        context: &ServerContext,
+        uri: &str,
+        body: serde_json::Value,
+    ) -> ServiceResponse {
+        let request = test::TestRequest::post()
+            .uri(uri)
+            .cookie(actix_web::cookie::Cookie::new(csrf::SESSION_COOKIE, "session"))
+            .insert_header((csrf::CSRF_HEADER, context.csrf.token_for("session")))
+            .set_json(body)
+            .to_request();
+        test::call_service(app, request).await
+    }
+
+    #[actix_web::test]
+    async fn serves_the_translation_of_callbacks() {
+        let (app, _) = server(&Config::default()).await;
+        let wasm = served(&app).await;
+
+        // The exports the glue runs it through
+        let mut exports = Vec::new();
+        for payload in wasmparser::Parser::new(0).parse_all(&wasm) {
+            if let wasmparser::Payload::ExportSection(reader) = payload.unwrap() {
+                exports.extend(reader.into_iter().map(|export| export.unwrap().name.to_string()));
+            }
+        }
+        assert!(exports.contains(&"callback".to_string()), "{:?}", exports);
+        assert!(exports.contains(&"memory".to_string()), "{:?}", exports);
+    }
+
+    #[actix_web::test]
+    async fn rebases_served_executions_onto_newer_state() {
+        let (app, context) = server(&Config::default()).await;
+        let wasm = served(&app).await;
+        let base = context.state.version();
+        let (executed, _) = run_served(&wasm, &context.state.read().clone());
+
+        // Another browser's execution is committed first
+        let callback = context.callbacks.get("served_increment").unwrap();
+        context.execute(callback, &[], Trigger::Execute, None).unwrap();
+        let stale = post_sync(&app, &context, json!({
+            "callback": "served_increment",
+            "version": base,
+            "state_patch": [{ "offset": 0, "bytes": executed }],
+        })).await;
+        assert_eq!(stale.status(), StatusCode::CONFLICT);
+        let conflict: serde_json::Value = test::read_body_json(stale).await;
+        assert_eq!(conflict["detail"]["reason"], "stale");
+
+        // Rebased onto the state of the conflict, the execution is accepted
+        let image: Vec<u8> = serde_json::from_value(conflict["detail"]["state"].clone()).unwrap();
+        let current = store::from_image(&State { counter: 0 }, &image).unwrap();
+        let (rebased, _) = run_served(&wasm, &current);
+        let accepted = post_sync(&app, &context, json!({
+            "callback": "served_increment",
+            "version": conflict["detail"]["version"],
+            "state_patch": [{ "offset": 0, "bytes": rebased }],
+        })).await;
+        assert_eq!(accepted.status(), StatusCode::OK);
+        assert_eq!(context.state.read().counter, 2);
+    }
+
+    #[actix_web::test]
+    async fn batches_commit_all_entries_or_none() {
+        // Doubles every increment, and counts the runs
+        static AFTER_RUNS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let mut hooks = Hooks::new();
+        hooks.after(|_, old, new| {
+            AFTER_RUNS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            new.counter += new.counter - old.counter;
+        });
+        let (app, context) = server_with_hooks(&Config::default(), hooks).await;
+        let entry = json!({ "callback": "served_increment" });
+
+        let committed = post_json(&app, &context, "/execute-batch", json!([entry, entry])).await;
+        assert_eq!(committed.status(), StatusCode::OK);
+        let committed: serde_json::Value = test::read_body_json(committed).await;
+        assert_eq!(committed["committed"], true);
+        assert_eq!(committed["results"][1], json!({ "status": "ok", "callback": "served_increment", "result": 3 }));
+        assert_eq!(context.state.read().counter, 4);
+        let version = context.state.version();
+        assert_eq!(committed["version"], version);
+
+        // The unknown entry fails the batch: the snapshot is restored,
+        // including what the after hooks of earlier entries changed
+        let runs = AFTER_RUNS.load(std::sync::atomic::Ordering::SeqCst);
+        let failed = post_json(&app, &context, "/execute-batch", json!([entry, entry, { "callback": "missing" }, entry])).await;
+        assert_eq!(failed.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let failed: serde_json::Value = test::read_body_json(failed).await;
+        assert_eq!(failed["code"], "batch_failed");
+        let statuses: Vec<&str> = failed["detail"]["results"].as_array().unwrap().iter().map(|result| result["status"].as_str().unwrap()).collect();
+        assert_eq!(statuses, ["rolled_back", "rolled_back", "error", "skipped"]);
+        assert_eq!(failed["detail"]["results"][1]["result"], 7);
+        assert_eq!((failed["detail"]["committed"].as_bool(), failed["detail"]["version"].as_u64()), (Some(false), Some(version)));
+        assert_eq!(AFTER_RUNS.load(std::sync::atomic::Ordering::SeqCst), runs + 2);
+        assert_eq!(context.state.read().counter, 4);
+        assert_eq!(context.state.version(), version);
+
+        // Upload references resolve as on /execute
+        let unknown = json!({ "callback": "served_increment", "args": [{ "upload": "0".repeat(32) }] });
+        let failed = post_json(&app, &context, "/execute-batch", json!([entry, unknown])).await;
+        assert_eq!(failed.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let failed: serde_json::Value = test::read_body_json(failed).await;
+        assert_eq!(failed["detail"]["results"][1]["error"], format!("No upload {}", "0".repeat(32)));
+        assert_eq!(context.state.read().counter, 4);
+    }
+
+    #[actix_web::test]
+    async fn streams_uploads_to_files() {
+        let mut config = Config::default();
+        config.limits.upload_dir = std::env::temp_dir().join(format!("self-serve-upload-route-{}", std::process::id()));
+        config.limits.upload_bytes = 128;
+        let (app, context) = server(&config).await;
+        let upload = |content_type: &str, body: &'static [u8]| test::TestRequest::post()
+            .uri("/upload")
+            .cookie(actix_web::cookie::Cookie::new(csrf::SESSION_COOKIE, "session"))
+            .insert_header((csrf::CSRF_HEADER, context.csrf.token_for("session")))
+            .insert_header((header::CONTENT_TYPE, content_type.to_string()))
+            .set_payload(body)
+            .to_request();
+        let received = |response: serde_json::Value| {
+            let mut args = vec![json!({ "upload": response["uploads"][0]["id"] })];
+            context.uploads.resolve(&mut args).unwrap();
+            std::fs::read(args[0].as_str().unwrap()).unwrap()
+        };
+
+        let raw = test::call_service(&app, upload("application/octet-stream", b"raw bytes")).await;
+        assert_eq!(raw.status(), StatusCode::OK);
+        assert_eq!(received(test::read_body_json(raw).await), b"raw bytes");
+
+        let body = b"--XyZ\r\nContent-Disposition: form-data; name=\"doc\"\r\n\r\npart\r\n--XyZ--\r\n";
+        let multipart = test::call_service(&app, upload("multipart/form-data; boundary=XyZ", body)).await;
+        assert_eq!(multipart.status(), StatusCode::OK);
+        let multipart: serde_json::Value = test::read_body_json(multipart).await;
+        assert_eq!(multipart["uploads"][0]["field"], "doc");
+        assert_eq!(received(multipart), b"part");
+
+        // A failed upload leaves no file behind
+        let large = test::call_service(&app, upload("application/octet-stream", &[0; 129])).await;
+        assert_eq!(large.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(std::fs::read_dir(&config.limits.upload_dir).unwrap().count(), 2);
+        std::fs::remove_dir_all(&config.limits.upload_dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn paranoid_syncs_compare_the_memory_of_served_modules() {
+        use base64::Engine;
+        let mut config = Config::default();
+        config.sync.policy = SyncPolicy::Paranoid;
+        let (app, context) = server(&config).await;
+        let wasm = served(&app).await;
+        let encode = |bytes: &[u8]| base64::engine::general_purpose::STANDARD.encode(bytes);
+        let sync = |version: u64, state: &[u8], image: Option<String>| json!({
+            "callback": "served_increment",
+            "version": version,
+            "state_patch": [{ "offset": 0, "bytes": state }],
+            "memory_image": image,
+        });
+
+        let (state, image) = run_served(&wasm, &context.state.read().clone());
+        let accepted = post_sync(&app, &context, sync(0, &state, Some(encode(&image)))).await;
+        assert_eq!(accepted.status(), StatusCode::OK);
+
+        // The patch agrees with native execution, the memory it came from
+        // does not
+        let (state, mut image) = run_served(&wasm, &context.state.read().clone());
+        image[1] ^= 0xff;
+        let mismatch = post_sync(&app, &context, sync(1, &state, Some(encode(&image)))).await;
+        assert_eq!(mismatch.status(), StatusCode::CONFLICT);
+        let conflict: serde_json::Value = test::read_body_json(mismatch).await;
+        assert_eq!(conflict["detail"]["reason"], "mismatch");
+
+        let missing = post_sync(&app, &context, sync(1, &state, None)).await;
+        assert_eq!(missing.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(context.state.read().counter, 1);
+    }
+}
@@ -0,0 +1,98 @@
+// Tracing setup and per-request spans
+//
+// Log verbosity is controlled with RUST_LOG (default: info), e.g.
+// RUST_LOG=x64_to_wasm_server=debug. Set SELF_SERVE_LOG_FORMAT=json for
+// newline-delimited JSON output in production.
+//
+// Every request gets an ID, the incoming X-Request-Id if it has a usable one.
+// It is a field of the request span, so everything logged while handling the
+// request carries it, and it is sent back in the X-Request-Id response
+// header, in error bodies and with client telemetry.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::Error;
+use std::time::Instant;
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+// Longer incoming IDs are replaced, so clients cannot flood the logs
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let json = std::env::var("SELF_SERVE_LOG_FORMAT").is_ok_and(|format| format == "json");
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    if json {
+        builder.json().init();
+    } else {
+        builder.init();
+    }
+}
+
+/// The incoming ID if it is short and printable, a random one otherwise
+fn request_id(incoming: Option<&HeaderValue>) -> String {
+    incoming
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(|| hex::encode(rand::random::<[u8; 8]>()))
+}
+
+/// Middleware that wraps every request in a `request` span and logs the
+/// response status and duration when it completes.
+pub async fn trace_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let request_id = request_id(req.headers().get(REQUEST_ID_HEADER));
+    let span = tracing::info_span!(
+        "request",
+        method = %req.method(),
+        path = %req.path(),
+        request_id = %request_id,
+    );
+    let start = Instant::now();
+    let header = HeaderValue::from_str(&request_id).ok();
+
+    let handled = async move {
+        let mut result = next.call(req).await;
+        if let (Ok(response), Some(header)) = (&mut result, header) {
+            response.headers_mut().insert(HeaderName::from_static("x-request-id"), header);
+        }
+        let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+        match &result {
+            Ok(response) => tracing::info!(
+                status = response.status().as_u16(),
+                duration_ms,
+                "request completed"
+            ),
+            Err(e) => tracing::warn!(error = %e, duration_ms, "request failed"),
+        }
+
+        result
+    }
+    .instrument(span);
+    crate::error::REQUEST_ID.scope(Some(request_id), handled).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn honors_usable_incoming_ids() {
+        assert_eq!(request_id(Some(&HeaderValue::from_static("lb-7f3a"))), "lb-7f3a");
+        let generated = request_id(None);
+        assert_eq!(generated.len(), 16);
+        assert_ne!(generated, request_id(None));
+        for unusable in ["", "has space", &"x".repeat(MAX_REQUEST_ID_LEN + 1)] {
+            assert_ne!(request_id(Some(&HeaderValue::from_str(unusable).unwrap())), unusable);
+        }
+    }
+}
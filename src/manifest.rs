@@ -0,0 +1,144 @@
+// Module manifest
+// `GET /wasm/manifest.json` maps every callback to a URL naming the content
+// hash of its module, `/wasm/<fn_name>.<hash>.wasm`. Those URLs are cached
+// by browsers for good, as their bytes never change; a re-transpiled module
+// gets a new hash and so a new URL. The manifest itself is revalidated on
+// every page load with its ETag, so the glue picks up new translations as
+// soon as they exist and keeps loading the unchanged ones from its cache.
+
+use crate::error::ApiError;
+use crate::profile::Profile;
+use crate::ServerContext;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse, Responder, ResponseError};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// Hex digits of the SHA-256 in hashed URLs
+pub const HASH_LEN: usize = 12;
+
+/// The route of the hashed URLs, registered before `/wasm/{fn_name}`
+pub const HASHED_ROUTE: &str = "/wasm/{fn_name}.{hash:[0-9a-f]{12}}.wasm";
+
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    pub profile: Profile,
+    /// Hashed URL of each callback's module
+    pub modules: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    /// Lists the release modules specialized for `session`, see
+    /// `specialize.rs`, and leaves out those whose specialization failed
+    pub fn build(ctx: &ServerContext, profile: Profile, session: &BTreeMap<String, i64>) -> Self {
+        let modules = ctx.callbacks
+            .iter()
+            .filter_map(|callback| {
+                let specialized = match profile {
+                    Profile::Release => ctx.transpiler.get_specialized_wasm(&callback.name, session),
+                    Profile::Debug => None,
+                };
+                let wasm = match specialized {
+                    Some(specialized) => specialized.ok()?,
+                    None => ctx.transpiler.get_wasm_with_profile(&callback.name, profile)?,
+                };
+                Some((callback.name.clone(), hashed_url(&callback.name, &wasm)))
+            })
+            .collect();
+        Self { profile, modules }
+    }
+}
+
+pub fn content_hash(wasm: &[u8]) -> String {
+    let mut hash = hex::encode(Sha256::digest(wasm));
+    hash.truncate(HASH_LEN);
+    hash
+}
+
+pub fn hashed_url(fn_name: &str, wasm: &[u8]) -> String {
+    format!("/wasm/{}.{}.wasm", fn_name, content_hash(wasm))
+}
+
+/// Whether the `If-None-Match` of `req` lists `etag`
+fn matches_etag(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').map(|tag| tag.trim().trim_start_matches("W/")).any(|tag| tag == etag || tag == "*"))
+}
+
+#[derive(Deserialize)]
+pub struct ManifestQuery {
+    /// `[transpiler] profile` if unset
+    profile: Option<Profile>,
+}
+
+pub async fn manifest(req: HttpRequest, query: web::Query<ManifestQuery>, ctx: web::Data<ServerContext>) -> impl Responder {
+    let profile = query.profile.unwrap_or_else(|| ctx.transpiler.default_profile());
+    let session = crate::specialize::session_values(&req);
+    let body = serde_json::to_vec(&Manifest::build(&ctx, profile, &session)).expect("manifest serializes to JSON");
+    let etag = format!("\"{}\"", content_hash(&body));
+
+    if matches_etag(&req, &etag) {
+        return HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .insert_header((header::CACHE_CONTROL, "no-cache"))
+            .insert_header((header::VARY, "Cookie"))
+            .finish();
+    }
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .insert_header((header::VARY, "Cookie"))
+        .body(body)
+}
+
+/// Serves whichever variant of the module has the hash in the URL, or a
+/// cached specialization of it. Other hashes are of modules since
+/// re-transpiled or evicted, which are not kept.
+pub async fn hashed_wasm(path: web::Path<(String, String)>, ctx: web::Data<ServerContext>) -> impl Responder {
+    let (fn_name, hash) = path.into_inner();
+    if ctx.callbacks.get(&fn_name).is_none() {
+        return ApiError::not_found("Function not found").error_response();
+    }
+
+    let wasm = [Profile::Release, Profile::Debug]
+        .into_iter()
+        .filter_map(|profile| ctx.transpiler.get_wasm_with_profile(&fn_name, profile))
+        .find(|wasm| content_hash(wasm) == hash)
+        .or_else(|| ctx.transpiler.find_specialized(&fn_name, &hash));
+    match wasm {
+        Some(wasm) => HttpResponse::Ok()
+            .content_type("application/wasm")
+            .insert_header((header::CACHE_CONTROL, IMMUTABLE))
+            .body(wasm),
+        None => ApiError::not_found("The module has been re-transpiled, see /wasm/manifest.json").error_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::dev::ResourceDef;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn names_modules_by_content() {
+        let url = hashed_url("increment_counter", b"\0asm\x01\0\0\0");
+        // printf '\0asm\1\0\0\0' | sha256sum
+        assert_eq!(url, "/wasm/increment_counter.93a44bbb96c7.wasm");
+        let path = ResourceDef::new(HASHED_ROUTE);
+        let mut matched = actix_web::dev::Path::new(url.as_str());
+        assert!(path.capture_match_info(&mut matched));
+        assert_eq!((&matched["fn_name"], &matched["hash"]), ("increment_counter", "93a44bbb96c7"));
+        assert!(!path.is_match("/wasm/increment_counter"));
+
+        let req = TestRequest::default().insert_header((header::IF_NONE_MATCH, r#"W/"abc", "def""#)).to_http_request();
+        assert!(matches_etag(&req, r#""def""#) && matches_etag(&req, r#""abc""#));
+        assert!(!matches_etag(&req, r#""ab""#));
+    }
+}
@@ -0,0 +1,147 @@
+// Instruction mapping
+// The lowering bookkeeping the disassembly viewer shows, as JSON for tools
+// post-processing translations: every source instruction with the WASM
+// instructions it was lowered to and their byte offsets in the module. The
+// same offsets place the source instructions as comments in the WAT text.
+
+use crate::transpiler_real::LoweredFunction;
+use crate::error::ApiError;
+use crate::ServerContext;
+use actix_web::{web, HttpResponse, Responder, ResponseError};
+use serde::Serialize;
+use std::collections::HashMap;
+use wasmparser::{Parser, Payload};
+
+#[derive(Debug, Serialize)]
+pub struct InstructionMapping {
+    pub x86_addr: u64,
+    pub x86_text: String,
+    /// Offsets of `wasm_ops` from the start of the module
+    pub wasm_offsets: Vec<usize>,
+    pub wasm_ops: Vec<String>,
+}
+
+pub async fn mapping(path: web::Path<String>, ctx: web::Data<ServerContext>) -> impl Responder {
+    let fn_name = path.into_inner();
+    if ctx.callbacks.get(&fn_name).is_none() {
+        return ApiError::not_found("Function not found").error_response();
+    }
+    match ctx.transpiler.mapping(&fn_name) {
+        Ok(mapping) => HttpResponse::Ok().json(mapping),
+        Err(e) => ApiError::internal(e).error_response(),
+    }
+}
+
+/// Maps the instructions of `lowered` to the module `emit` generates from
+/// it, which is returned along with the mapping, also if that fails
+pub fn map(
+    lowered: LoweredFunction,
+    emit: impl FnOnce(LoweredFunction) -> Vec<u8>,
+) -> (Result<Vec<InstructionMapping>, String>, Vec<u8>) {
+    let mut mapping: Vec<InstructionMapping> = lowered
+        .lowerings
+        .iter()
+        .map(|lowering| InstructionMapping {
+            x86_addr: lowering.instr.addr(),
+            x86_text: lowering.instr.text(),
+            wasm_offsets: Vec::new(),
+            wasm_ops: lowered.wasm(lowering).iter().map(|instr| lowered.wat_text(instr)).collect(),
+        })
+        .collect();
+    let wasm = emit(lowered);
+    let offsets = body_offsets(&wasm).and_then(|offsets| {
+        let mut offsets = offsets.into_iter();
+        for entry in &mut mapping {
+            entry.wasm_offsets = offsets.by_ref().take(entry.wasm_ops.len()).collect();
+            if entry.wasm_offsets.len() != entry.wasm_ops.len() {
+                return Err("Module has fewer instructions than the lowering".to_string());
+            }
+        }
+        Ok(())
+    });
+    (offsets.map(|()| mapping), wasm)
+}
+
+/// WAT text of `wasm` with a `;; 0x1143: add rax,1` comment before the
+/// instructions each source instruction was lowered to. Instructions that
+/// produced no WASM get no comment.
+pub fn annotated_wat(wasm: &[u8], mapping: &[InstructionMapping]) -> Result<String, String> {
+    let comments: HashMap<usize, &InstructionMapping> = mapping
+        .iter()
+        .filter_map(|entry| Some((*entry.wasm_offsets.first()?, entry)))
+        .collect();
+    let mut storage = String::new();
+    let lines = wasmprinter::Config::new()
+        .offsets_and_lines(wasm, &mut storage)
+        .map_err(|e| format!("Invalid module: {}", e))?;
+    let mut wat = String::new();
+    for (offset, line) in lines {
+        if let Some(entry) = offset.and_then(|offset| comments.get(&offset)) {
+            let indent = &line[..line.len() - line.trim_start().len()];
+            wat.push_str(&format!("{};; {:#x}: {}\n", indent, entry.x86_addr, entry.x86_text));
+        }
+        wat.push_str(line);
+    }
+    Ok(wat)
+}
+
+// Offsets of the instructions of the module's only function body, without
+// its closing `end`
+fn body_offsets(wasm: &[u8]) -> Result<Vec<usize>, String> {
+    for payload in Parser::new(0).parse_all(wasm) {
+        if let Payload::CodeSectionEntry(body) = payload.map_err(|e| e.to_string())? {
+            let mut offsets = body
+                .get_operators_reader()
+                .map_err(|e| e.to_string())?
+                .into_iter_with_offsets()
+                .map(|op| op.map(|(_, offset)| offset).map_err(|e| e.to_string()))
+                .collect::<Result<Vec<_>, _>>()?;
+            offsets.pop();
+            return Ok(offsets);
+        }
+    }
+    Err("Module has no function body".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::Arch;
+    use crate::backend::{FunctionIR, TranspilerBackend};
+    use crate::transpiler_real::{DirectBackend, OptLevel, TranspileOptions};
+
+    #[test]
+    fn maps_instructions_to_module_offsets() {
+        // mov rax, [rdi]; add rax, 5; mov [rdi], rax; ret
+        let code = [0x48, 0x8b, 0x07, 0x48, 0x83, 0xc0, 0x05, 0x48, 0x89, 0x07, 0xc3];
+        let function = FunctionIR::decode("test", Arch::X86_64, &code, 0x1000);
+        let backend = DirectBackend::new(TranspileOptions { optimization: OptLevel::None, ..Default::default() });
+        let (mapping, wasm) = map(backend.lower(&function).unwrap(), |lowered| backend.emit(lowered));
+        let mapping = mapping.unwrap();
+        assert_eq!(wasm, backend.transpile(&function).unwrap());
+
+        assert_eq!(mapping.iter().map(|entry| entry.x86_addr).collect::<Vec<_>>(), vec![0x1000, 0x1003, 0x1007, 0x100a]);
+        assert_eq!(mapping[1].x86_text, "add rax,5");
+        for entry in &mapping {
+            assert_eq!(entry.wasm_offsets.len(), entry.wasm_ops.len());
+            for (offset, op) in entry.wasm_offsets.iter().zip(&entry.wasm_ops) {
+                let opcode = match op.split_whitespace().next().unwrap() {
+                    "local.get" => 0x20,
+                    "local.set" => 0x21,
+                    "i64.load" => 0x29,
+                    "i64.store" => 0x37,
+                    "i64.const" => 0x42,
+                    "i64.add" => 0x7c,
+                    _ => continue,
+                };
+                assert_eq!(wasm[*offset], opcode, "{} at {}", op, offset);
+            }
+        }
+        assert!(mapping[1].wasm_ops.iter().any(|op| op == "i64.add"), "{:?}", mapping[1].wasm_ops);
+
+        let wat = annotated_wat(&wasm, &mapping).unwrap();
+        let add = wat.find("    ;; 0x1003: add rax,5\n").expect(&wat);
+        assert!(wat[add..].lines().nth(1).unwrap().trim_start().starts_with(&mapping[1].wasm_ops[0]), "{}", wat);
+        assert_eq!(wat.matches(";; 0x").count(), 4, "{}", wat);
+    }
+}
@@ -0,0 +1,106 @@
+// Module provenance
+// Every served module ends in a "selfserve.meta" custom section: JSON naming
+// the symbol and binary it was translated from, the transpiler version and
+// options, and how many instructions the translation of the machine code
+// dropped or approximated. A module found in a browser cache or attached to
+// a bug report can be traced back to what produced it. Engines ignore custom
+// sections, so the module behaves the same.
+
+use crate::transpiler_real::{FidelityReport, TranspileOptions};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use wasm_encoder::{CustomSection, Section};
+
+pub const SECTION: &str = "selfserve.meta";
+
+#[derive(Debug, Serialize)]
+pub struct ModuleMeta {
+    pub symbol: String,
+    /// `[binaries.<namespace>]` the symbol comes from, none for the main binary
+    pub namespace: Option<String>,
+    /// Hex SHA-256 of the binary, none if it could not be read
+    pub binary_sha256: Option<String>,
+    pub transpiler_version: &'static str,
+    pub options: TranspileOptions,
+    /// Counts of the fidelity report, none if the machine code could not be
+    /// translated
+    pub instructions: Option<usize>,
+    pub unsupported: Option<usize>,
+    pub approximated: Option<usize>,
+    /// Globals baked into a session-specialized module, see `specialize.rs`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub fixed: BTreeMap<String, i64>,
+}
+
+impl ModuleMeta {
+    pub fn new(symbol: &str, namespace: Option<&str>, binary_sha256: Option<&str>, options: TranspileOptions) -> Self {
+        Self {
+            symbol: symbol.to_string(),
+            namespace: namespace.map(str::to_string),
+            binary_sha256: binary_sha256.map(str::to_string),
+            transpiler_version: env!("CARGO_PKG_VERSION"),
+            options,
+            instructions: None,
+            unsupported: None,
+            approximated: None,
+            fixed: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_fidelity(self, fidelity: &FidelityReport) -> Self {
+        Self {
+            instructions: Some(fidelity.instructions),
+            unsupported: Some(fidelity.unsupported.len()),
+            approximated: Some(fidelity.approximated.len()),
+            ..self
+        }
+    }
+
+    /// Appends the metadata to `wasm` as the last section of the module
+    pub fn append_to(&self, wasm: &mut Vec<u8>) {
+        let data = serde_json::to_vec(self).expect("metadata serializes to JSON");
+        CustomSection { name: SECTION.into(), data: data.into() }.append_to(wasm);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::Arch;
+    use crate::backend::{FunctionIR, TranspilerBackend};
+    use crate::transpiler_real::DirectBackend;
+    use wasmparser::{Parser, Payload};
+
+    fn read(wasm: &[u8]) -> Option<serde_json::Value> {
+        Parser::new(0).parse_all(wasm).find_map(|payload| match payload.ok()? {
+            Payload::CustomSection(reader) if reader.name() == SECTION => serde_json::from_slice(reader.data()).ok(),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn appended_metadata_keeps_the_module_valid() {
+        // add rax, 1; rdtsc; ret
+        let code = [0x48, 0x83, 0xc0, 0x01, 0x0f, 0x31, 0xc3];
+        let function = FunctionIR::decode("test", Arch::X86_64, &code, 0x1000);
+        let backend = DirectBackend::default();
+        let lowered = backend.lower(&function).unwrap();
+        let fidelity = lowered.report.clone();
+        let mut wasm = backend.emit(lowered);
+        let translated = wasm.clone();
+        ModuleMeta::new("test", None, Some("00ff"), TranspileOptions::default())
+            .with_fidelity(&fidelity)
+            .append_to(&mut wasm);
+
+        assert!(wasm.starts_with(&translated));
+        wasmparser::Validator::new().validate_all(&wasm).unwrap();
+        let meta = read(&wasm).unwrap();
+        assert_eq!(meta["symbol"], "test");
+        assert_eq!(meta["binary_sha256"], "00ff");
+        assert_eq!(meta["transpiler_version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(meta["options"]["optimization"], "full");
+        assert_eq!(meta["instructions"], 3);
+        assert_eq!(meta["unsupported"], fidelity.unsupported.len());
+        assert!(read(&translated).is_none());
+    }
+}
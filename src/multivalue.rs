@@ -0,0 +1,107 @@
+// Multi-value results
+// Callbacks that return a status and write their values through
+// out-pointers, like the `(ptr, len)` of string callbacks, make the glue
+// allocate slots, pass their addresses and read them back afterwards. With
+// `multi_value = true` the module does this itself: the out-pointers become
+// parameters of the translated function, and the exported `callback` is a
+// wrapper that calls it with slots at the end of linear memory and returns
+// the status followed by the value of each slot. The glue destructures the
+// results. Modules without a linear memory keep the plain export.
+
+use crate::callbacks::ElementType;
+use crate::signatures::{DeclaredSignature, ParamType, ResultType};
+use crate::transpiler_real::TranspileOptions;
+use wasm_encoder::{Function, Instruction, MemArg, ValType};
+
+/// The parameters the translated function takes: the state pointer, which
+/// stays at address 0, then an out-pointer per result. None without
+/// multi-value results.
+pub fn out_signature(options: &TranspileOptions) -> Option<DeclaredSignature> {
+    if !options.multi_value || options.out_results.is_empty() {
+        return None;
+    }
+    let params = std::iter::once(ParamType::State).chain(options.out_results.iter().map(|_| ParamType::Opaque)).collect();
+    Some(DeclaredSignature { params, returns: ResultType::I32 })
+}
+
+/// WASM type of an out-parameter result
+pub fn result_type(ty: ElementType) -> ValType {
+    match ty {
+        ElementType::U8 | ElementType::I32 | ElementType::U32 => ValType::I32,
+        ElementType::I64 | ElementType::U64 => ValType::I64,
+        ElementType::F32 => ValType::F32,
+        ElementType::F64 => ValType::F64,
+    }
+}
+
+fn load(ty: ElementType) -> Instruction<'static> {
+    let memarg = |align| MemArg { offset: 0, align, memory_index: 0 };
+    match ty {
+        ElementType::U8 => Instruction::I32Load8U(memarg(0)),
+        ElementType::I32 | ElementType::U32 => Instruction::I32Load(memarg(2)),
+        ElementType::I64 | ElementType::U64 => Instruction::I64Load(memarg(3)),
+        ElementType::F32 => Instruction::F32Load(memarg(2)),
+        ElementType::F64 => Instruction::F64Load(memarg(3)),
+    }
+}
+
+// Pushes the address of the 8-byte slot `idx` from the end of memory
+fn slot(memory64: bool, idx: usize, function: &mut Function) {
+    let offset = 8 * (idx as i64 + 1);
+    let instrs = match memory64 {
+        true => [Instruction::MemorySize(0), Instruction::I64Const(16), Instruction::I64Shl, Instruction::I64Const(offset), Instruction::I64Sub],
+        false => [Instruction::MemorySize(0), Instruction::I32Const(16), Instruction::I32Shl, Instruction::I32Const(offset as i32), Instruction::I32Sub],
+    };
+    for instr in &instrs {
+        function.instruction(instr);
+    }
+}
+
+/// Body of the wrapper around function `callback`, which takes `leading`
+/// parameters and then the out-pointers
+pub fn wrapper(callback: u32, leading: u32, outs: &[ElementType], memory64: bool) -> Function {
+    let mut function = Function::new([]);
+    for param in 0..leading {
+        function.instruction(&Instruction::LocalGet(param));
+    }
+    for idx in 0..outs.len() {
+        slot(memory64, idx, &mut function);
+    }
+    function.instruction(&Instruction::Call(callback));
+    for (idx, ty) in outs.iter().enumerate() {
+        slot(memory64, idx, &mut function);
+        function.instruction(&load(*ty));
+    }
+    function.instruction(&Instruction::End);
+    function
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::Arch;
+    use crate::backend::{FunctionIR, TranspilerBackend};
+    use crate::transpiler_real::DirectBackend;
+    use wasmparser::{Validator, WasmFeatures};
+
+    #[test]
+    fn returns_out_parameters_as_results() {
+        // mov qword [rsi], 0x10; mov qword [rdx], 3; xor eax, eax; ret
+        let code = [0x48, 0xc7, 0x06, 0x10, 0x00, 0x00, 0x00, 0x48, 0xc7, 0x02, 0x03, 0x00, 0x00, 0x00, 0x31, 0xc0, 0xc3];
+        let function = FunctionIR::decode("test", Arch::X86_64, &code, 0x1000);
+        let options = TranspileOptions { multi_value: true, out_results: vec![ElementType::U64, ElementType::U64], ..Default::default() };
+        let wasm = DirectBackend::new(options).transpile(&function).unwrap();
+        Validator::new_with_features(WasmFeatures::all()).validate_all(&wasm).unwrap();
+        assert!(Validator::new_with_features(WasmFeatures::all() - WasmFeatures::MULTI_VALUE).validate_all(&wasm).is_err());
+
+        let wat = wasmprinter::print_bytes(&wasm).unwrap();
+        assert!(wat.contains("(type (;0;) (func (param i64 i64) (result i64)))"), "{}", wat);
+        assert!(wat.contains("(func (result i64 i64 i64)"), "{}", wat);
+        assert!(wat.contains(r#"(export "callback" (func 1))"#), "{}", wat);
+
+        // Without `multi_value` the translated function is the export
+        let options = TranspileOptions { out_results: vec![ElementType::U64, ElementType::U64], ..Default::default() };
+        let wat = wasmprinter::print_bytes(DirectBackend::new(options).transpile(&function).unwrap()).unwrap();
+        assert!(wat.contains(r#"(export "callback" (func 0))"#), "{}", wat);
+    }
+}
@@ -0,0 +1,154 @@
+// NATS client
+// The subset of the NATS text protocol the cluster relay needs: CONNECT,
+// PUB, SUB and answering the server's PINGs, over blocking TCP. URLs are
+// `nats://host[:port]`. The server drops connections that miss PINGs, so a
+// connection that only publishes calls `keep_alive` regularly.
+
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+pub struct Connection {
+    reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    pub fn open(url: &str) -> Result<Self, String> {
+        let address = url.strip_prefix("nats://").ok_or_else(|| format!("Not a nats:// URL: {}", url))?;
+        let address = match address.contains(':') {
+            true => address.to_string(),
+            false => format!("{}:4222", address),
+        };
+        let stream = TcpStream::connect(&address).map_err(|e| format!("Could not connect to NATS at {}: {}", address, e))?;
+        let mut connection = Self { reader: BufReader::new(stream) };
+        let info = connection.read_line()?;
+        if !info.starts_with("INFO") {
+            return Err(format!("Expected INFO from NATS, got {}", info));
+        }
+        connection.write(br#"CONNECT {"verbose":false,"pedantic":false}"#)?;
+        connection.write(b"\r\n")?;
+        Ok(connection)
+    }
+
+    pub fn publish(&mut self, subject: &str, payload: &[u8]) -> Result<(), String> {
+        self.write(format!("PUB {} {}\r\n", subject, payload.len()).as_bytes())?;
+        self.write(payload)?;
+        self.write(b"\r\n")
+    }
+
+    pub fn subscribe(&mut self, subject: &str) -> Result<(), String> {
+        self.write(format!("SUB {} 1\r\n", subject).as_bytes())
+    }
+
+    /// Payload of the next message on a subscribed subject
+    pub fn next_message(&mut self) -> Result<Vec<u8>, String> {
+        loop {
+            let line = self.read_line()?;
+            let mut parts = line.split_ascii_whitespace();
+            match parts.next() {
+                Some("MSG") => {
+                    // MSG <subject> <sid> [reply-to] <bytes>
+                    let len = parts.last().and_then(|len| len.parse::<usize>().ok());
+                    let len = len.ok_or_else(|| format!("Malformed NATS message: {}", line))?;
+                    let mut payload = vec![0; len + 2];
+                    self.reader.read_exact(&mut payload).map_err(|e| format!("NATS read failed: {}", e))?;
+                    payload.truncate(len);
+                    return Ok(payload);
+                }
+                Some("PING") => self.write(b"PONG\r\n")?,
+                Some("-ERR") => return Err(format!("NATS error: {}", line)),
+                // +OK, PONG and INFO updates
+                _ => {}
+            }
+        }
+    }
+
+    /// Answers the PINGs the server sent so far, without waiting for more
+    pub fn keep_alive(&mut self) -> Result<(), String> {
+        let blocking = |reader: &BufReader<TcpStream>, blocking: bool| reader.get_ref().set_nonblocking(!blocking).map_err(|e| e.to_string());
+        blocking(&self.reader, false)?;
+        let mut pings = 0;
+        let mut line = String::new();
+        let read = loop {
+            let read = match self.reader.read_line(&mut line) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock && line.is_empty() => break Ok(()),
+                // The rest of a line that arrived in parts
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    blocking(&self.reader, true)?;
+                    let rest = self.reader.read_line(&mut line);
+                    blocking(&self.reader, false)?;
+                    rest
+                }
+                read => read,
+            };
+            match read {
+                Ok(0) => break Err("NATS closed the connection".to_string()),
+                Ok(_) if line.starts_with("PING") => pings += 1,
+                Ok(_) if line.starts_with("-ERR") => break Err(format!("NATS error: {}", line.trim_end())),
+                // +OK, PONG and INFO updates
+                Ok(_) => {}
+                Err(e) => break Err(format!("NATS read failed: {}", e)),
+            }
+            line.clear();
+        };
+        blocking(&self.reader, true)?;
+        read?;
+        for _ in 0..pings {
+            self.write(b"PONG\r\n")?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.reader.get_mut().write_all(bytes).map_err(|e| format!("NATS write failed: {}", e))
+    }
+
+    fn read_line(&mut self) -> Result<String, String> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => Err("NATS closed the connection".to_string()),
+            Ok(_) => Ok(line.trim_end_matches("\r\n").to_string()),
+            Err(e) => Err(format!("NATS read failed: {}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn answers_pings_and_reads_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            reader.get_mut().write_all(b"INFO {}\r\n").unwrap();
+            let mut lines = Vec::new();
+            let mut read_line = |reader: &mut BufReader<TcpStream>| {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                lines.push(line);
+            };
+            read_line(&mut reader);
+            reader.get_mut().write_all(b"PING\r\n").unwrap();
+            // The PONG, once the publisher kept the connection alive
+            read_line(&mut reader);
+            read_line(&mut reader);
+            reader.get_mut().write_all(b"PING\r\nMSG events 1 5\r\nhello\r\n").unwrap();
+            read_line(&mut reader);
+            lines
+        });
+
+        let mut connection = Connection::open(&format!("nats://{}", address)).unwrap();
+        // Once the PING arrived
+        connection.reader.get_ref().peek(&mut [0]).unwrap();
+        connection.keep_alive().unwrap();
+        connection.subscribe("events").unwrap();
+        assert_eq!(connection.next_message().unwrap(), b"hello");
+        let lines = server.join().unwrap();
+        assert!(lines[0].starts_with("CONNECT"), "{:?}", lines);
+        assert_eq!(&lines[1..], ["PONG\r\n", "SUB events 1\r\n", "PONG\r\n"]);
+    }
+}
@@ -0,0 +1,364 @@
+// OpenAPI 3 description of the callback API, served at /openapi.json
+// Generated from the callback registry so it always matches the running server.
+
+use crate::callbacks::{CallbackEntry, ElementType, ReturnType, Signature, ValueType};
+use crate::validation::Rule;
+use crate::{ServerContext, State};
+use actix_web::{web, HttpResponse, Responder};
+use schemars::gen::SchemaSettings;
+use serde_json::{json, Map, Value};
+
+pub fn document(ctx: &ServerContext) -> Value {
+    let mut generator = SchemaSettings::openapi3().into_generator();
+    let state_schema = generator.root_schema_for::<State>();
+    // Derived from the types the descriptors are serialized from, their
+    // definitions go into the components
+    let signature = generator.subschema_for::<Signature>();
+
+    let mut paths = Map::new();
+    paths.insert("/api/state".to_string(), json!({
+        "get": {
+            "summary": "Current state and available callbacks",
+            "operationId": "getState",
+            "responses": {
+                "200": {
+                    "description": "State snapshot",
+                    "content": {
+                        "application/json": {
+                            "schema": { "$ref": "#/components/schemas/StateSnapshot" }
+                        }
+                    }
+                }
+            }
+        }
+    }));
+
+    paths.insert("/execute-batch".to_string(), json!({
+        "post": {
+            "summary": "Execute an ordered list of callbacks atomically",
+            "operationId": "executeBatch",
+            "security": [{ "bearerAuth": [] }, { "basicAuth": [], "csrfToken": [] }],
+            "requestBody": {
+                "content": {
+                    "application/json": {
+                        "schema": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "required": ["callback"],
+                                "properties": {
+                                    "callback": { "type": "string" },
+                                    "args": { "type": "array", "items": {} }
+                                }
+                            }
+                        }
+                    }
+                }
+            },
+            "responses": {
+                "200": { "description": "All entries executed and committed" },
+                "422": error_response("An entry failed, the state was rolled back")
+            }
+        }
+    }));
+
+    paths.insert("/wasm/manifest.json".to_string(), json!({
+        "get": {
+            "summary": "Content-hashed module URL of every callback",
+            "operationId": "wasmManifest",
+            "parameters": [{
+                "name": "profile",
+                "in": "query",
+                "schema": { "type": "string", "enum": ["debug", "release"] }
+            }],
+            "responses": {
+                "200": { "description": "`{\"profile\", \"modules\": {fn_name: url}}`, with an ETag" },
+                "304": { "description": "The manifest matches If-None-Match" }
+            }
+        }
+    }));
+
+    for callback in ctx.callbacks.iter() {
+        paths.insert(format!("/execute/{}", callback.name), execute_path(callback));
+        paths.insert(format!("/wasm/{}", callback.name), json!({
+            "get": {
+                "summary": format!("Transpiled WASM module of {}", callback.name),
+                "operationId": format!("wasm_{}", callback.name),
+                "parameters": [{
+                    "name": "format",
+                    "in": "query",
+                    "description": "`component` wraps the core module in a WASM component",
+                    "schema": { "type": "string", "enum": ["core", "component"], "default": "core" }
+                }, {
+                    "name": "profile",
+                    "in": "query",
+                    "description": "`debug` adds name and instruction mapping sections, `release` has none. Defaults to `[transpiler] profile`.",
+                    "schema": { "type": "string", "enum": ["debug", "release"] }
+                }],
+                "responses": {
+                    "200": {
+                        "description": "WASM module",
+                        "content": {
+                            "application/wasm": {
+                                "schema": { "type": "string", "format": "binary" }
+                            }
+                        }
+                    },
+                    "404": { "description": "Function not found" }
+                }
+            }
+        }));
+    }
+
+    let mut document = json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "x64 to WASM Server",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": {
+                "State": state_schema.schema,
+                "StateSnapshot": {
+                    "type": "object",
+                    "required": ["version", "state", "callbacks"],
+                    "properties": {
+                        "version": { "type": "integer", "format": "int64" },
+                        "state": { "$ref": "#/components/schemas/State" },
+                        "callbacks": {
+                            "type": "array",
+                            "items": { "$ref": "#/components/schemas/CallbackDescription" }
+                        }
+                    }
+                },
+                "CallbackDescription": {
+                    "type": "object",
+                    "required": ["name", "permission", "signature", "read_only", "wasm", "execute"],
+                    "properties": {
+                        "name": { "type": "string" },
+                        "permission": { "type": "string", "enum": ["public", "user", "admin"] },
+                        "signature": signature,
+                        "read_only": { "type": "boolean" },
+                        "wasm": { "type": "string" },
+                        "execute": { "type": "string" }
+                    }
+                },
+                "Error": {
+                    "type": "object",
+                    "required": ["code", "message", "detail", "request_id"],
+                    "properties": {
+                        "code": { "type": "string" },
+                        "message": { "type": "string" },
+                        "detail": {},
+                        "request_id": { "type": ["string", "null"] }
+                    }
+                }
+            },
+            "securitySchemes": {
+                "bearerAuth": { "type": "http", "scheme": "bearer" },
+                "basicAuth": { "type": "http", "scheme": "basic" },
+                "csrfToken": { "type": "apiKey", "in": "header", "name": crate::csrf::CSRF_HEADER }
+            }
+        }
+    });
+    let schemas = document["components"]["schemas"].as_object_mut().unwrap();
+    for (name, schema) in generator.take_definitions() {
+        schemas.insert(name, json!(schema));
+    }
+    document
+}
+
+fn execute_path(callback: &CallbackEntry) -> Value {
+    // The state pointer and out-pointers are supplied by the server, only the
+    // remaining parameters would have to be sent by the client
+    let params: Vec<Value> = callback.signature
+        .client_params()
+        .zip(&callback.rules)
+        .map(|(param, rules)| constrained(value_schema(*param), rules))
+        .collect();
+
+    let mut operation = json!({
+        "summary": format!("Execute {}", callback.name),
+        "operationId": callback.name,
+        "x-permission": callback.permission,
+        "x-signature": callback.signature,
+        "security": [{ "bearerAuth": [] }, { "basicAuth": [], "csrfToken": [] }],
+        "responses": {
+            "200": {
+                "description": "Callback executed",
+                "content": {
+                    "application/json": {
+                        "schema": {
+                            "type": "object",
+                            "required": ["version", "result"],
+                            "properties": {
+                                "version": { "type": "integer", "format": "int64" },
+                                "result": return_schema(&callback.signature.returns)
+                            }
+                        }
+                    }
+                }
+            },
+            "401": error_response("Authentication required"),
+            "403": error_response("Insufficient permissions or CSRF check failed"),
+            "404": error_response("Unknown callback"),
+            "422": error_response("The callback reported a nonzero status, or the arguments are invalid")
+        }
+    });
+
+    let args = json!({ "type": "array", "items": params, "minItems": params.len(), "maxItems": params.len() });
+    let mut path = json!({});
+    if callback.read_only {
+        // The same arguments as JSON in ?args=, no CSRF token needed
+        let mut query = operation.clone();
+        query["operationId"] = json!(format!("query_{}", callback.name));
+        query["security"] = json!([{ "bearerAuth": [] }, { "basicAuth": [] }]);
+        if !params.is_empty() {
+            query["parameters"] = json!([{
+                "name": "args",
+                "in": "query",
+                "required": true,
+                "content": { "application/json": { "schema": args } }
+            }]);
+        }
+        path["get"] = query;
+    }
+    if !params.is_empty() {
+        operation["requestBody"] = json!({
+            "content": {
+                "application/json": { "schema": args }
+            }
+        });
+    }
+    path["post"] = operation;
+    path
+}
+
+fn error_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": {
+            "application/json": {
+                "schema": { "$ref": "#/components/schemas/Error" }
+            }
+        }
+    })
+}
+
+fn value_schema(value: ValueType) -> Value {
+    match value {
+        ValueType::StatePtr | ValueType::OutPtr | ValueType::Out(_) => json!({ "type": "integer", "format": "int64" }),
+        ValueType::Str => json!({ "type": "string" }),
+        ValueType::Slice(element) => {
+            let items = match element {
+                ElementType::F32 => json!({ "type": "number", "format": "float" }),
+                ElementType::F64 => json!({ "type": "number", "format": "double" }),
+                ElementType::I64 => json!({ "type": "integer", "format": "int64" }),
+                ElementType::U64 => json!({ "type": "integer", "format": "uint64" }),
+                ElementType::U32 => json!({ "type": "integer", "format": "uint32" }),
+                ElementType::I32 => json!({ "type": "integer", "format": "int32" }),
+                ElementType::U8 => json!({ "type": "integer", "format": "uint8" }),
+            };
+            json!({ "type": "array", "items": items })
+        }
+    }
+}
+
+// The validation rules of a parameter as schema keywords
+fn constrained(mut schema: Value, rules: &[Rule]) -> Value {
+    let is_array = schema["type"] == "array";
+    for rule in rules {
+        match rule {
+            Rule::Range { min, max } => {
+                let target = if is_array { &mut schema["items"] } else { &mut schema };
+                if let Some(min) = min {
+                    target["minimum"] = json!(min);
+                }
+                if let Some(max) = max {
+                    target["maximum"] = json!(max);
+                }
+            }
+            Rule::MaxLength { max } if is_array => schema["maxItems"] = json!(max),
+            Rule::MaxLength { max } => schema["maxLength"] = json!(max),
+            Rule::Pattern { pattern } => schema["pattern"] = json!(pattern.as_str()),
+        }
+    }
+    schema
+}
+
+fn return_schema(returns: &ReturnType) -> Value {
+    match returns {
+        ReturnType::I32 => json!({ "type": "integer", "format": "int32" }),
+        ReturnType::F64 => json!({ "type": "number", "format": "double" }),
+        ReturnType::String => json!({ "type": "string" }),
+        ReturnType::Struct { schema, .. } => schema.clone(),
+    }
+}
+
+pub async fn openapi_json(ctx: web::Data<ServerContext>) -> impl Responder {
+    HttpResponse::Ok().json(document(&ctx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Permission;
+    use crate::binaries::BinaryRegistry;
+    use crate::callbacks::CallbackRegistry;
+    use crate::config::Config;
+    use crate::exposure::Exposure;
+    use crate::progress::Progress;
+    use crate::transpiler::Transpiler;
+    use std::sync::Arc;
+
+    extern "C" fn ratio(_: *mut State) -> f64 {
+        0.5
+    }
+
+    extern "C" fn rename(_: *mut State, _: *const u8, _: usize) -> i32 {
+        0
+    }
+
+    #[test]
+    fn describes_routes_and_callback_schemas() {
+        let config = Config::default();
+        let mut callbacks = CallbackRegistry::new();
+        callbacks.register_f64("ratio", ratio, Permission::Public).read_only("ratio");
+        callbacks.register_str_arg("rename", rename, Permission::User)
+            .validate("rename", 0, vec![Rule::MaxLength { max: 16 }]);
+        let transpiler = Arc::new(Transpiler::new(callbacks.iter(), &config.transpiler));
+        let exposure = Exposure::new(&config.exposure);
+        let binaries = Arc::new(BinaryRegistry::new(&config, transpiler.symbols(), &exposure).unwrap());
+        let ctx = ServerContext::new(&config, callbacks, transpiler, binaries, exposure, Arc::new(Progress::new())).unwrap();
+
+        let document = document(&ctx);
+        let paths = document["paths"].as_object().unwrap();
+        for path in ["/api/state", "/execute-batch", "/wasm/manifest.json", "/execute/ratio", "/wasm/ratio", "/execute/rename", "/wasm/rename"] {
+            assert!(paths.contains_key(path), "{} is missing", path);
+        }
+
+        // Read-only callbacks can be queried, without a request body if they
+        // take no arguments
+        let ratio = &paths["/execute/ratio"];
+        assert_eq!(ratio["get"]["operationId"], "query_ratio");
+        assert!(ratio["post"].get("requestBody").is_none());
+        let result = &ratio["post"]["responses"]["200"]["content"]["application/json"]["schema"]["properties"]["result"];
+        assert_eq!(result, &json!({ "type": "number", "format": "double" }));
+
+        // The state pointer is supplied by the server, the string is validated
+        let rename = &paths["/execute/rename"];
+        assert!(rename.get("get").is_none());
+        let args = &rename["post"]["requestBody"]["content"]["application/json"]["schema"];
+        assert_eq!(args["items"], json!([{ "type": "string", "maxLength": 16 }]));
+        assert_eq!((args["minItems"].as_u64(), args["maxItems"].as_u64()), (Some(1), Some(1)));
+        let result = &rename["post"]["responses"]["200"]["content"]["application/json"]["schema"]["properties"]["result"];
+        assert_eq!(result, &json!({ "type": "integer", "format": "int32" }));
+
+        // Referenced schemas are defined
+        let schemas = document["components"]["schemas"].as_object().unwrap();
+        for schema in ["State", "StateSnapshot", "CallbackDescription", "Error"] {
+            assert!(schemas.contains_key(schema), "{} is missing", schema);
+        }
+    }
+}
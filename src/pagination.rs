@@ -0,0 +1,171 @@
+// Pagination
+// A `PaginatedList` renders one window of a collection, so a page listing
+// thousands of items only carries the visible slice. The window comes from
+// the `offset` and `limit` query parameters, and the list links to the
+// neighbouring windows. Its `data-src` endpoint answers the same parameters
+// with JSON holding only the window's HTML, which `SCRIPT` swaps in place of
+// the list instead of reloading the page.
+
+use crate::a11y::{Aria, Role};
+use crate::dom::{Dom, DomNode};
+use actix_web::HttpResponse;
+use serde::Deserialize;
+
+pub const DEFAULT_LIMIT: usize = 20;
+// Caps what a single request can make the server render
+pub const MAX_LIMIT: usize = 200;
+
+/// Intercepts the navigation links of paginated lists and fetches the
+/// neighbouring window from the list's `data-src`
+pub const SCRIPT: &str = r#"
+        document.addEventListener('click', async (event) => {
+            const link = event.target.closest('.paginated nav a');
+            if (!link) {
+                return;
+            }
+            event.preventDefault();
+            const list = link.closest('.paginated');
+            const query = new URL(link.href).search;
+            const response = await fetch(list.dataset.src + query, { headers: { Accept: 'application/json' } });
+            if (!response.ok) {
+                // The link works without the script too
+                window.location.href = link.href;
+                return;
+            }
+            list.outerHTML = (await response.json()).html;
+            history.replaceState(null, '', query);
+        });
+"#;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct PageQuery {
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Page {
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl PageQuery {
+    /// The requested window, with the limit clamped to `1..=MAX_LIMIT`
+    pub fn page(&self) -> Page {
+        Page {
+            offset: self.offset.unwrap_or(0),
+            limit: self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+        }
+    }
+}
+
+pub struct PaginatedList<'a> {
+    id: &'a str,
+    // Answers `?offset=&limit=` with `window_response`
+    src: &'a str,
+    page: Page,
+    // Other parameters of the page, such as filters, kept by the links
+    query: String,
+}
+
+impl<'a> PaginatedList<'a> {
+    pub fn new(id: &'a str, src: &'a str, page: Page) -> Self {
+        Self { id, src, page, query: String::new() }
+    }
+
+    /// Keeps the parameters of `query` other than `offset` and `limit` in
+    /// the navigation links
+    pub fn with_query(mut self, query: &str) -> Self {
+        self.query = query
+            .split('&')
+            .filter(|param| !param.is_empty() && !param.starts_with("offset=") && !param.starts_with("limit="))
+            .map(|param| format!("{}&", param))
+            .collect();
+        self
+    }
+
+    // A window past the end shows the last one instead
+    fn offset(&self, total: usize) -> usize {
+        match total {
+            0 => 0,
+            _ => self.page.offset.min((total - 1) / self.page.limit * self.page.limit),
+        }
+    }
+
+    /// The items of the window, the only ones that need rendering
+    pub fn window<'b, T>(&self, items: &'b [T]) -> &'b [T] {
+        let offset = self.offset(items.len());
+        &items[offset..items.len().min(offset + self.page.limit)]
+    }
+
+    /// `content` holds the rendered window of a collection of `total` items.
+    /// It is wrapped with the list's data attributes and navigation.
+    pub fn render(&self, total: usize, content: DomNode) -> DomNode {
+        let Page { limit, .. } = self.page;
+        let offset = self.offset(total);
+        let end = total.min(offset + limit);
+        let link = |offset: usize, label: &str| {
+            let href = format!("?{}offset={}&limit={}", self.query, offset, limit);
+            DomNode::element("a", vec![("href", &href)], vec![DomNode::text(label)])
+        };
+        let mut nav = Vec::new();
+        if offset > 0 {
+            nav.push(link(offset.saturating_sub(limit), "Previous"));
+        }
+        let range = match total {
+            0 => "0 of 0".to_string(),
+            _ => format!("{}-{} of {}", offset + 1, end, total),
+        };
+        // Announced when a window is fetched in place
+        nav.push(DomNode::element("span", vec![], vec![DomNode::text(&range)]).with_role(Role::Status));
+        if end < total {
+            nav.push(link(end, "Next"));
+        }
+
+        let (total, offset, limit) = (total.to_string(), offset.to_string(), limit.to_string());
+        DomNode::element("div", vec![
+            ("id", self.id),
+            ("class", "paginated"),
+            ("data-src", self.src),
+            ("data-total", &total),
+            ("data-offset", &offset),
+            ("data-limit", &limit),
+        ], vec![
+            content,
+            DomNode::element("nav", vec![], nav).with_aria(Aria::Label("Pages".to_string())),
+        ])
+    }
+
+    /// The window as JSON for `data-src` requests
+    pub fn window_response(&self, total: usize, content: DomNode) -> HttpResponse {
+        let html = Dom { nodes: vec![self.render(total, content)] }.to_html();
+        HttpResponse::Ok().json(serde_json::json!({
+            "total": total,
+            "offset": self.offset(total),
+            "limit": self.page.limit,
+            "html": html,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_only_the_window() {
+        let items: Vec<usize> = (0..45).collect();
+        let list = PaginatedList::new("numbers", "/numbers", PageQuery { offset: Some(20), limit: Some(20) }.page());
+        assert_eq!(list.window(&items), &items[20..40]);
+        let html = Dom { nodes: vec![list.render(items.len(), DomNode::text("..."))] }.to_html();
+        assert!(html.contains(r#"data-total="45" data-offset="20" data-limit="20""#));
+        assert!(html.contains(r#"<a href="?offset=0&amp;limit=20">Previous</a><span role="status">21-40 of 45</span><a href="?offset=40&amp;limit=20">Next</a>"#));
+
+        // Past the end shows the last window, without a next link
+        let last = PaginatedList::new("numbers", "/numbers", PageQuery { offset: Some(100), limit: Some(20) }.page());
+        assert_eq!(last.window(&items), &items[40..]);
+        assert!(!Dom { nodes: vec![last.render(items.len(), DomNode::text(""))] }.to_html().contains("Next"));
+        assert_eq!(PageQuery { offset: None, limit: Some(100_000) }.page().limit, MAX_LIMIT);
+        assert!(list.window::<usize>(&[]).is_empty());
+    }
+}
@@ -0,0 +1,134 @@
+// Dry-run execution
+// POST /preview/{fn_name} takes the same arguments as /execute and runs the
+// native callback, between its hooks, on a copy of the state. Nothing is
+// committed, recorded or broadcast. The response holds the result, the
+// fields that would change and the changes to the rendered page, so a UI
+// can ask for confirmation before a destructive callback such as
+// reset_counter. Before hooks see `Trigger::Preview` and may veto as usual.
+
+use crate::dom::DomChange;
+use crate::error::ApiError;
+use crate::hooks::{Invocation, Trigger};
+use crate::ServerContext;
+use actix_web::{web, HttpRequest, HttpResponse, Responder, ResponseError};
+use serde::Serialize;
+use serde_json::Value;
+
+/// A field of the state, addressed by a JSON pointer into its JSON form,
+/// that the callback would change
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StateChange {
+    pub path: String,
+    pub before: Value,
+    pub after: Value,
+}
+
+/// Leaves of `before` and `after` that differ, arrays element by element
+pub fn state_changes(before: &Value, after: &Value) -> Vec<StateChange> {
+    fn walk(path: String, before: &Value, after: &Value, changes: &mut Vec<StateChange>) {
+        match (before, after) {
+            _ if before == after => {}
+            (Value::Object(old), Value::Object(new)) if old.len() == new.len() && old.keys().eq(new.keys()) => {
+                for (key, value) in old {
+                    walk(format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1")), value, &new[key], changes);
+                }
+            }
+            (Value::Array(old), Value::Array(new)) if old.len() == new.len() => {
+                for (idx, (old, new)) in old.iter().zip(new).enumerate() {
+                    walk(format!("{}/{}", path, idx), old, new, changes);
+                }
+            }
+            _ => changes.push(StateChange { path, before: before.clone(), after: after.clone() }),
+        }
+    }
+    let mut changes = Vec::new();
+    walk(String::new(), before, after, &mut changes);
+    changes
+}
+
+#[derive(Serialize)]
+pub struct Preview {
+    /// The version the preview ran on, which /execute would move past
+    pub version: u64,
+    pub result: crate::callbacks::CallbackValue,
+    pub state: Vec<StateChange>,
+    /// Changes to the page in the session's locale, see `Dom::diff`
+    pub dom: Vec<DomChange>,
+}
+
+pub async fn preview(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: Result<web::Bytes, actix_web::Error>,
+    ctx: web::Data<ServerContext>,
+) -> impl Responder {
+    let fn_name = path.into_inner();
+    let Some(callback) = ctx.callbacks.get(&fn_name) else {
+        return ApiError::not_found("Unknown callback").error_response();
+    };
+    if let Err(e) = ctx.csrf.verify(&req) {
+        return e.error_response();
+    }
+    if let Err(e) = ctx.auth.authorize(&req, callback.permission) {
+        return e.into_response(&ctx.auth);
+    }
+
+    let body = match body {
+        Ok(body) => body,
+        Err(e) => return crate::error::payload_error(e).error_response(),
+    };
+    let mut args: Vec<Value> = match body.is_empty() {
+        true => Vec::new(),
+        false => match serde_json::from_slice(&body) {
+            Ok(args) => args,
+            Err(e) => return ApiError::bad_request(format!("Arguments must be a JSON array: {}", e)).error_response(),
+        },
+    };
+    if let Err(e) = ctx.uploads.resolve(&mut args) {
+        return ApiError::new(actix_web::http::StatusCode::UNPROCESSABLE_ENTITY, "unknown_upload", e).error_response();
+    }
+
+    let (before, version) = {
+        let state = ctx.state.read();
+        (state.clone(), ctx.state.version())
+    };
+    let mut after = before.clone();
+    let invocation = Invocation { callback: &fn_name, args: &args, trigger: Trigger::Preview };
+    let result = match ctx.hooks.run(&invocation, &mut after, |state| callback.call(state, &args)) {
+        Ok(result) => result,
+        Err(e) => return ApiError::from(e).error_response(),
+    };
+
+    let json = |state| serde_json::to_value(state).expect("state serializes to JSON");
+    let locale = ctx.i18n.locale_for(&req);
+    let locales: Vec<&str> = ctx.i18n.locales().collect();
+    let page = |state| crate::render_app(state, &locales, &ctx.templates).translated(&|key, args| ctx.i18n.translate(locale, key, args));
+    HttpResponse::Ok().json(Preview {
+        version,
+        result,
+        state: state_changes(&json(&before), &json(&after)),
+        dom: page(&before).diff(&page(&after)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn lists_the_fields_that_change() {
+        let before = json!({ "counter": 3, "todos": [{ "done": false }, { "done": true }], "name/x": "a" });
+        let after = json!({ "counter": 0, "todos": [{ "done": false }, { "done": false }], "name/x": "b" });
+        let changes = state_changes(&before, &after);
+        assert_eq!(changes, vec![
+            StateChange { path: "/counter".to_string(), before: json!(3), after: json!(0) },
+            StateChange { path: "/name~1x".to_string(), before: json!("a"), after: json!("b") },
+            StateChange { path: "/todos/1/done".to_string(), before: json!(true), after: json!(false) },
+        ]);
+        // Arrays of another length change as a whole
+        let grown = json!({ "counter": 3, "todos": [], "name/x": "a" });
+        assert_eq!(state_changes(&before, &grown)[0].path, "/todos");
+        assert!(state_changes(&before, &before).is_empty());
+    }
+}
@@ -0,0 +1,150 @@
+// Module profiles
+// Every module is cached in two variants, picked with `?profile=` on
+// `/wasm` or `[transpiler] profile`. `release` carries no custom sections
+// besides the `meta.rs` provenance and the `abi.rs` manifest. `debug` adds a name section, so
+// engines and devtools show the symbol and the function names in stack
+// traces, and a "selfserve.mapping" section holding the instruction mapping
+// of `mapping.rs` when the module is the translation of the machine code.
+
+use crate::mapping::InstructionMapping;
+use serde::{Deserialize, Serialize};
+use wasm_encoder::{CustomSection, NameMap, NameSection, RawSection, Section};
+use wasmparser::{ExternalKind, Parser, Payload, TypeRef};
+
+pub const MAPPING_SECTION: &str = "selfserve.mapping";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    Debug,
+    Release,
+}
+
+impl Profile {
+    /// `debug` in debug builds, `release` otherwise
+    pub fn for_build() -> Self {
+        match cfg!(debug_assertions) {
+            true => Profile::Debug,
+            false => Profile::Release,
+        }
+    }
+}
+
+/// `wasm` without its custom sections, other than the module metadata and
+/// ABI manifest
+pub fn strip(wasm: &[u8]) -> Result<Vec<u8>, String> {
+    let mut stripped = wasm.get(..8).ok_or("Invalid module: too short")?.to_vec();
+    for payload in Parser::new(0).parse_all(wasm) {
+        let payload = payload.map_err(|e| format!("Invalid module: {}", e))?;
+        if let Payload::CustomSection(reader) = &payload {
+            if reader.name() != crate::meta::SECTION && reader.name() != crate::abi::SECTION {
+                continue;
+            }
+        }
+        if let Some((id, range)) = payload.as_section() {
+            RawSection { id, data: &wasm[range] }.append_to(&mut stripped);
+        }
+    }
+    Ok(stripped)
+}
+
+/// Appends the debug sections to `wasm`: the names of the module and its
+/// functions, taken from their imports and exports, and `mapping` if any
+pub fn append_debug_sections(wasm: &mut Vec<u8>, symbol: &str, mapping: Option<&[InstructionMapping]>) -> Result<(), String> {
+    let mut imported = Vec::new();
+    let mut exported = Vec::new();
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload.map_err(|e| format!("Invalid module: {}", e))? {
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import.map_err(|e| format!("Invalid module: {}", e))?;
+                    if let TypeRef::Func(_) = import.ty {
+                        imported.push(format!("{}.{}", import.module, import.name));
+                    }
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export.map_err(|e| format!("Invalid module: {}", e))?;
+                    if export.kind == ExternalKind::Func {
+                        exported.push((export.index, export.name.to_string()));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    // Imports keep their own name when re-exported
+    exported.retain(|(index, _)| *index as usize >= imported.len());
+    exported.sort();
+    exported.dedup_by_key(|(index, _)| *index);
+
+    let mut functions = NameMap::new();
+    for (index, name) in imported.iter().enumerate() {
+        functions.append(index as u32, name);
+    }
+    for (index, name) in &exported {
+        functions.append(*index, name);
+    }
+    let mut names = NameSection::new();
+    names.module(symbol);
+    names.functions(&functions);
+    names.append_to(wasm);
+
+    if let Some(mapping) = mapping {
+        let data = serde_json::to_vec(mapping).map_err(|e| e.to_string())?;
+        CustomSection { name: MAPPING_SECTION.into(), data: data.into() }.append_to(wasm);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_encoder::{CodeSection, EntityType, ExportKind, ExportSection, Function, FunctionSection, ImportSection, Instruction, Module, TypeSection, ValType};
+
+    #[test]
+    fn debug_sections_are_stripped_again() {
+        let mut module = Module::new();
+        let mut types = TypeSection::new();
+        types.ty().function([ValType::I32], []);
+        module.section(&types);
+        let mut imports = ImportSection::new();
+        imports.import("env", "trap_reason", EntityType::Function(0));
+        module.section(&imports);
+        let mut functions = FunctionSection::new();
+        functions.function(0);
+        module.section(&functions);
+        let mut exports = ExportSection::new();
+        exports.export("step", ExportKind::Func, 1);
+        module.section(&exports);
+        let mut code = CodeSection::new();
+        let mut body = Function::new([]);
+        body.instruction(&Instruction::End);
+        code.function(&body);
+        module.section(&code);
+        let release = module.finish();
+
+        let mut debug = release.clone();
+        let mapping = vec![InstructionMapping { x86_addr: 0x1000, x86_text: "ret".to_string(), wasm_offsets: vec![], wasm_ops: vec![] }];
+        append_debug_sections(&mut debug, "step", Some(&mapping)).unwrap();
+        wasmparser::validate(&debug).unwrap();
+        let printed = wasmprinter::print_bytes(&debug).unwrap();
+        assert!(printed.contains("(module $step"), "{}", printed);
+        assert!(printed.contains("$env.trap_reason") && printed.contains("(func $step"), "{}", printed);
+        let custom: Vec<String> = Parser::new(0)
+            .parse_all(&debug)
+            .filter_map(|payload| match payload.ok()? {
+                Payload::CustomSection(reader) => Some(reader.name().to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(custom, ["name", MAPPING_SECTION]);
+
+        crate::meta::ModuleMeta::new("step", None, None, Default::default()).append_to(&mut debug);
+        let stripped = strip(&debug).unwrap();
+        let mut expected = release;
+        crate::meta::ModuleMeta::new("step", None, None, Default::default()).append_to(&mut expected);
+        assert_eq!(stripped, expected);
+    }
+}
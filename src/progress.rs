@@ -0,0 +1,176 @@
+// Transpilation progress
+// Translating hundreds of symbols takes a while, at startup and after a
+// reload of the binary. Both run in the background while the server is up,
+// and every transpiler reports to one `Progress`, which admin dashboards
+// follow as server-sent events from /admin/transpile-progress: `start`,
+// then `finish` or `error` per symbol, each with the symbol, its namespace
+// (null for the callback binary) and the milliseconds it took, and `run`
+// with the counts of the current run whenever they change. A run lasts
+// while any transpiler is still working, so a warm-up of several binaries
+// is one run. Subscribers get the `run` of the current or last run first.
+
+use crate::events::Broadcaster;
+use actix_web::HttpResponse;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// Startup, every symbol
+    #[default]
+    Warmup,
+    /// After the binary changed, the symbols whose code changed
+    Reload,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Run {
+    pub phase: Phase,
+    /// Symbols to transpile, growing as transpilers join the run
+    pub total: usize,
+    pub finished: usize,
+    pub failed: usize,
+    /// Transpilers still working, the run is over at 0
+    pub running: usize,
+}
+
+#[derive(Serialize)]
+struct SymbolEvent<'a> {
+    symbol: &'a str,
+    namespace: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+pub struct Progress {
+    events: Broadcaster,
+    run: Mutex<Run>,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self { events: Broadcaster::new(), run: Mutex::new(Run::default()) }
+    }
+
+    /// The current run, or the last one if none is going
+    pub fn run(&self) -> Run {
+        self.run.lock().unwrap().clone()
+    }
+
+    fn send(&self, event: &str, data: &impl Serialize) {
+        let data = serde_json::to_string(data).expect("progress events serialize to JSON");
+        self.events.broadcast_local(event, &data);
+    }
+
+    /// A transpiler starts on `symbols` symbols, joining the current run if
+    /// there is one
+    pub fn begin(&self, phase: Phase, symbols: usize) {
+        let mut run = self.run.lock().unwrap();
+        if run.running == 0 {
+            *run = Run { phase, ..Run::default() };
+        }
+        run.running += 1;
+        run.total += symbols;
+        self.send("run", &*run);
+    }
+
+    pub fn started(&self, namespace: Option<&str>, symbol: &str) {
+        self.send("start", &SymbolEvent { symbol, namespace, duration_ms: None, error: None });
+    }
+
+    pub fn finished(&self, namespace: Option<&str>, symbol: &str, result: &Result<(), String>, duration: Duration) {
+        let mut run = self.run.lock().unwrap();
+        let duration_ms = Some(duration.as_millis() as u64);
+        match result {
+            Ok(()) => self.send("finish", &SymbolEvent { symbol, namespace, duration_ms, error: None }),
+            Err(error) => {
+                run.failed += 1;
+                self.send("error", &SymbolEvent { symbol, namespace, duration_ms, error: Some(error) });
+            }
+        }
+        run.finished += 1;
+        self.send("run", &*run);
+    }
+
+    /// A transpiler is done with the symbols it began
+    pub fn end(&self) {
+        let mut run = self.run.lock().unwrap();
+        run.running = run.running.saturating_sub(1);
+        self.send("run", &*run);
+    }
+
+    /// An event stream of the runs. Every subscriber is sent the current
+    /// run, the ones already connected too.
+    pub fn subscribe(&self) -> HttpResponse {
+        let response = self.events.subscribe();
+        self.send("run", &self.run());
+        response
+    }
+}
+
+/// Shows a progress bar in the dashboard's transpile progress element while
+/// a run is going, and reloads the dashboard after it for the new statuses.
+/// `error` events without data are the EventSource's connection errors.
+pub const SCRIPT: &str = r#"
+        document.addEventListener('DOMContentLoaded', () => {
+            const progress = document.getElementById('transpile-progress');
+            if (!progress) {
+                return;
+            }
+            const bar = progress.querySelector('progress');
+            const label = progress.querySelector('span');
+            const source = new EventSource(progress.dataset.src);
+            let current = '';
+            let watched = false;
+            source.addEventListener('start', (event) => {
+                const { symbol, namespace } = JSON.parse(event.data);
+                current = namespace ? `${namespace}/${symbol}` : symbol;
+            });
+            source.addEventListener('error', (event) => {
+                if (event.data) {
+                    const { symbol, namespace, error } = JSON.parse(event.data);
+                    console.warn(`Transpiling ${namespace ? `${namespace}/${symbol}` : symbol} failed: ${error}`);
+                }
+            });
+            source.addEventListener('run', (event) => {
+                const run = JSON.parse(event.data);
+                progress.hidden = run.running === 0;
+                bar.max = Math.max(run.total, 1);
+                bar.value = run.finished;
+                const failed = run.failed ? `, ${run.failed} failed` : '';
+                label.textContent = ` ${run.phase === 'reload' ? 'Reload' : 'Warm-up'}: ${run.finished}/${run.total}${failed} ${current}`;
+                if (run.running > 0) {
+                    watched = true;
+                } else if (watched) {
+                    window.location.reload();
+                }
+            });
+        });
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_span_every_transpiler_until_the_last_ends() {
+        let progress = Progress::new();
+        progress.begin(Phase::Warmup, 2);
+        progress.begin(Phase::Warmup, 1);
+        progress.finished(None, "increment_counter", &Ok(()), Duration::from_millis(3));
+        progress.finished(Some("plugin"), "blend", &Err("Function not found".to_string()), Duration::ZERO);
+        progress.end();
+        assert_eq!(progress.run(), Run { phase: Phase::Warmup, total: 3, finished: 2, failed: 1, running: 1 });
+        progress.finished(None, "decrement_counter", &Ok(()), Duration::ZERO);
+        progress.end();
+        assert_eq!(progress.run().running, 0);
+
+        // The next run starts over
+        progress.begin(Phase::Reload, 1);
+        assert_eq!(progress.run(), Run { phase: Phase::Reload, total: 1, finished: 0, failed: 0, running: 1 });
+    }
+}
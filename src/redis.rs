@@ -0,0 +1,118 @@
+// Redis client
+// The subset of RESP2 the Redis state backend needs: commands as arrays of
+// bulk strings over a blocking TCP connection, and replies parsed back into
+// `Reply`. URLs are `redis://[:password@]host[:port][/db]`.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Status(String),
+    Integer(i64),
+    /// None for the null bulk string of a missing key
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+pub struct Connection {
+    reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    pub fn open(url: &str) -> Result<Self, String> {
+        let rest = url.strip_prefix("redis://").ok_or_else(|| format!("Not a redis:// URL: {}", url))?;
+        let (password, rest) = match rest.rsplit_once('@') {
+            Some((auth, rest)) => (Some(auth.trim_start_matches(':')), rest),
+            None => (None, rest),
+        };
+        let (address, db) = match rest.split_once('/') {
+            Some((address, db)) if !db.is_empty() => (address, Some(db)),
+            Some((address, _)) => (address, None),
+            None => (rest, None),
+        };
+        let address = match address.contains(':') {
+            true => address.to_string(),
+            false => format!("{}:6379", address),
+        };
+        let stream = TcpStream::connect(&address).map_err(|e| format!("Could not connect to Redis at {}: {}", address, e))?;
+        let mut connection = Self { reader: BufReader::new(stream) };
+        if let Some(password) = password {
+            connection.command(&[b"AUTH", password.as_bytes()])?;
+        }
+        if let Some(db) = db {
+            connection.command(&[b"SELECT", db.as_bytes()])?;
+        }
+        Ok(connection)
+    }
+
+    /// Sends a command and waits for its reply; error replies become `Err`
+    pub fn command(&mut self, args: &[&[u8]]) -> Result<Reply, String> {
+        let mut request = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            request.extend_from_slice(arg);
+            request.extend_from_slice(b"\r\n");
+        }
+        self.reader.get_mut().write_all(&request).map_err(|e| format!("Redis write failed: {}", e))?;
+        self.read_reply()
+    }
+
+    /// The next reply, such as a message pushed to a subscribed connection
+    pub fn read_reply(&mut self) -> Result<Reply, String> {
+        let line = self.read_line()?;
+        let (kind, value) = line.split_at(1);
+        let length = || value.parse::<i64>().map_err(|_| format!("Malformed Redis reply: {}", line));
+        match kind {
+            "+" => Ok(Reply::Status(value.to_string())),
+            "-" => Err(format!("Redis error: {}", value)),
+            ":" => Ok(Reply::Integer(length()?)),
+            "$" => match length()? {
+                len if len < 0 => Ok(Reply::Bulk(None)),
+                len => {
+                    let mut bytes = vec![0; len as usize + 2];
+                    self.reader.read_exact(&mut bytes).map_err(|e| format!("Redis read failed: {}", e))?;
+                    bytes.truncate(len as usize);
+                    Ok(Reply::Bulk(Some(bytes)))
+                }
+            },
+            "*" => (0..length()?.max(0)).map(|_| self.read_reply()).collect::<Result<_, _>>().map(Reply::Array),
+            _ => Err(format!("Malformed Redis reply: {}", line)),
+        }
+    }
+
+    fn read_line(&mut self) -> Result<String, String> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => Err("Redis closed the connection".to_string()),
+            Ok(_) if line.len() > 2 => Ok(line.trim_end_matches("\r\n").to_string()),
+            Ok(_) => Err("Malformed Redis reply: empty line".to_string()),
+            Err(e) => Err(format!("Redis read failed: {}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn speaks_resp_to_a_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![0; 256];
+            let len = stream.read(&mut request).unwrap();
+            request.truncate(len);
+            stream.write_all(b"$3\r\nabc\r\n").unwrap();
+            request
+        });
+
+        let mut connection = Connection::open(&format!("redis://{}", address)).unwrap();
+        assert_eq!(connection.command(&[b"GET", b"key"]).unwrap(), Reply::Bulk(Some(b"abc".to_vec())));
+        assert_eq!(server.join().unwrap(), b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n");
+        assert!(Connection::open("http://localhost").is_err());
+    }
+}
@@ -0,0 +1,119 @@
+// Automatic rendering
+// `#[derive(RenderDom)]` (the `self-serve-derive` crate) renders a struct as
+// a definition list, a `dt` with the name of each field and a `dd` with its
+// value, for admin and debug views without handwritten markup. Field types
+// implement `RenderDom`: scalars and strings render as text, nested structs
+// derive it and render as nested lists, `Vec`, slices and arrays as an `ol`
+// numbered from 0 and `Option` as its value or "None". Fields marked
+// `#[render(skip)]` are left out. The admin dashboard shows the state this
+// way.
+
+use crate::dom::DomNode;
+
+pub use self_serve_derive::RenderDom;
+
+pub trait RenderDom {
+    fn render_dom(&self) -> DomNode;
+}
+
+macro_rules! text_render {
+    ($($ty:ty),* $(,)?) => {
+        $(impl RenderDom for $ty {
+            fn render_dom(&self) -> DomNode {
+                DomNode::text(&self.to_string())
+            }
+        })*
+    };
+}
+
+text_render! {
+    i8, u8, i16, u16, i32, u32, i64, u64, i128, u128, isize, usize,
+    f32, f64, bool, char, str, String,
+}
+
+impl<T: RenderDom + ?Sized> RenderDom for &T {
+    fn render_dom(&self) -> DomNode {
+        (**self).render_dom()
+    }
+}
+
+impl<T: RenderDom + ?Sized> RenderDom for Box<T> {
+    fn render_dom(&self) -> DomNode {
+        (**self).render_dom()
+    }
+}
+
+impl<T: RenderDom> RenderDom for Option<T> {
+    fn render_dom(&self) -> DomNode {
+        match self {
+            Some(value) => value.render_dom(),
+            None => DomNode::element("em", vec![], vec![DomNode::text("None")]),
+        }
+    }
+}
+
+impl<T: RenderDom> RenderDom for [T] {
+    fn render_dom(&self) -> DomNode {
+        let items = self.iter().map(|item| DomNode::element("li", vec![], vec![item.render_dom()])).collect();
+        DomNode::element("ol", vec![("start", "0")], items)
+    }
+}
+
+impl<T: RenderDom, const N: usize> RenderDom for [T; N] {
+    fn render_dom(&self) -> DomNode {
+        self.as_slice().render_dom()
+    }
+}
+
+impl<T: RenderDom> RenderDom for Vec<T> {
+    fn render_dom(&self) -> DomNode {
+        self.as_slice().render_dom()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dom::Dom;
+
+    #[derive(RenderDom)]
+    struct Position {
+        x: i32,
+        y: f64,
+    }
+
+    #[derive(RenderDom)]
+    struct Player {
+        name: String,
+        position: Position,
+        scores: Vec<u32>,
+        team: Option<String>,
+        #[render(skip)]
+        #[allow(dead_code)]
+        secret: u64,
+    }
+
+    #[derive(RenderDom)]
+    struct Pair<T>(T, T);
+
+    #[test]
+    fn renders_structs_as_definition_lists() {
+        let player = Player {
+            name: "Ada <3".to_string(),
+            position: Position { x: -2, y: 0.5 },
+            scores: vec![10, 20],
+            team: None,
+            secret: 7,
+        };
+        let html = Dom { nodes: vec![player.render_dom()] }.to_html();
+        assert_eq!(
+            html,
+            "<dl><dt>name</dt><dd>Ada &lt;3</dd>\
+             <dt>position</dt><dd><dl><dt>x</dt><dd>-2</dd><dt>y</dt><dd>0.5</dd></dl></dd>\
+             <dt>scores</dt><dd><ol start=\"0\"><li>10</li><li>20</li></ol></dd>\
+             <dt>team</dt><dd><em>None</em></dd></dl>"
+        );
+        let pair = Dom { nodes: vec![Pair(Some(1u8), None).render_dom()] }.to_html();
+        assert_eq!(pair, "<dl><dt>0</dt><dd>1</dd><dt>1</dt><dd><em>None</em></dd></dl>");
+    }
+}
@@ -0,0 +1,381 @@
+// RISC-V (RV64) front-end, behind the `riscv` cargo feature
+// Decodes RV64IMC code, compressed instructions included, and lifts it to the
+// shared IR. x0 reads as the constant 0 and writes to it are dropped. The
+// 32-bit `*w` operations are lifted as their 64-bit counterparts, which only
+// differs when the result overflows 32 bits.
+
+use crate::arch::MachineInstr;
+use crate::ir::{BinOp, Cond, Lifter, Op, RegKey, TrapKind, Value};
+use riscv_decode::types::{BType, IType, RType, SType, UType};
+use riscv_decode::{DecodingError, Instruction};
+
+const ZERO: u32 = 0;
+const RA: u32 = 1;
+const A0: u32 = 10;
+
+const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+pub fn decode(code: &[u8], entry: u64) -> Vec<MachineInstr> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset + 2 <= code.len() {
+        let low = u16::from_le_bytes([code[offset], code[offset + 1]]);
+        let len = riscv_decode::instruction_length(low);
+        if offset + len > code.len() {
+            break;
+        }
+        let mut word = [0u8; 4];
+        word[..len.min(4)].copy_from_slice(&code[offset..offset + len.min(4)]);
+        let word = match len {
+            2 => expand_compressed(low).unwrap_or(low as u32),
+            _ => u32::from_le_bytes(word),
+        };
+        instructions.push(MachineInstr::RiscV {
+            addr: entry + offset as u64,
+            len: len as u8,
+            instr: riscv_decode::decode(word),
+        });
+        offset += len;
+    }
+    instructions
+}
+
+fn sign_extend(value: u32, bits: u32) -> i64 {
+    ((value << (32 - bits)) as i32 >> (32 - bits)) as i64
+}
+
+// Encoders for the 32-bit instruction formats
+fn encode_i(imm: i64, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    ((imm as u32 & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn encode_r(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn encode_b(imm: i64, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    ((imm >> 12 & 1) << 31) | ((imm >> 5 & 0x3f) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12)
+        | ((imm >> 1 & 0xf) << 8) | ((imm >> 11 & 1) << 7) | 0b1100011
+}
+
+fn encode_j(imm: i64, rd: u32) -> u32 {
+    let imm = imm as u32;
+    ((imm >> 20 & 1) << 31) | ((imm >> 1 & 0x3ff) << 21) | ((imm >> 11 & 1) << 20) | (imm & 0xff000)
+        | (rd << 7) | 0b1101111
+}
+
+/// Expands the RV64C instructions riscv-decode does not handle to their
+/// 32-bit equivalents. Returns None for the ones it does (compressed loads
+/// and stores) and for reserved encodings.
+fn expand_compressed(c: u16) -> Option<u32> {
+    let c = c as u32;
+    let bit = |n: u32| c >> n & 1;
+    let bits = |hi: u32, lo: u32| c >> lo & ((1 << (hi - lo + 1)) - 1);
+    // Full and compressed (x8-x15) register fields
+    let rd = bits(11, 7);
+    let rs2 = bits(6, 2);
+    let rd_c = bits(4, 2) + 8;
+    let rs1_c = bits(9, 7) + 8;
+    let imm6 = sign_extend(bit(12) << 5 | bits(6, 2), 6);
+
+    const OP_IMM: u32 = 0b0010011;
+    const OP_IMM_32: u32 = 0b0011011;
+    const OP: u32 = 0b0110011;
+    const OP_32: u32 = 0b0111011;
+    const LOAD: u32 = 0b0000011;
+
+    Some(match (c & 0b11, c >> 13) {
+        // c.addi4spn
+        (0b00, 0b000) if c != 0 => {
+            let imm = bits(12, 11) << 4 | bits(10, 7) << 6 | bit(6) << 2 | bit(5) << 3;
+            encode_i(imm as i64, 2, 0, rd_c, OP_IMM)
+        }
+        // c.addi, c.addiw, c.li
+        (0b01, 0b000) => encode_i(imm6, rd, 0, rd, OP_IMM),
+        (0b01, 0b001) => encode_i(imm6, rd, 0, rd, OP_IMM_32),
+        (0b01, 0b010) => encode_i(imm6, 0, 0, rd, OP_IMM),
+        // c.addi16sp
+        (0b01, 0b011) if rd == 2 => {
+            let imm = bit(12) << 9 | bit(6) << 4 | bit(5) << 6 | bits(4, 3) << 7 | bit(2) << 5;
+            encode_i(sign_extend(imm, 10), 2, 0, 2, OP_IMM)
+        }
+        // c.lui
+        (0b01, 0b011) => ((sign_extend(bit(12) << 17 | bits(6, 2) << 12, 18) as u32) & 0xfffff000) | (rd << 7) | 0b0110111,
+        (0b01, 0b100) => match (bits(11, 10), bit(12), bits(6, 5)) {
+            (0b00, _, _) => encode_i((bit(12) << 5 | rs2) as i64, rs1_c, 0b101, rs1_c, OP_IMM),
+            (0b01, _, _) => encode_i((0x400 | bit(12) << 5 | rs2) as i64, rs1_c, 0b101, rs1_c, OP_IMM),
+            (0b10, _, _) => encode_i(imm6, rs1_c, 0b111, rs1_c, OP_IMM),
+            (0b11, 0, 0b00) => encode_r(0b0100000, rd_c, rs1_c, 0b000, rs1_c, OP),
+            (0b11, 0, 0b01) => encode_r(0, rd_c, rs1_c, 0b100, rs1_c, OP),
+            (0b11, 0, 0b10) => encode_r(0, rd_c, rs1_c, 0b110, rs1_c, OP),
+            (0b11, 0, 0b11) => encode_r(0, rd_c, rs1_c, 0b111, rs1_c, OP),
+            (0b11, 1, 0b00) => encode_r(0b0100000, rd_c, rs1_c, 0b000, rs1_c, OP_32),
+            (0b11, 1, 0b01) => encode_r(0, rd_c, rs1_c, 0b000, rs1_c, OP_32),
+            _ => return None,
+        },
+        // c.j
+        (0b01, 0b101) => {
+            let imm = bit(12) << 11 | bit(11) << 4 | bits(10, 9) << 8 | bit(8) << 10 | bit(7) << 6
+                | bit(6) << 7 | bits(5, 3) << 1 | bit(2) << 5;
+            encode_j(sign_extend(imm, 12), 0)
+        }
+        // c.beqz, c.bnez
+        (0b01, funct3 @ (0b110 | 0b111)) => {
+            let imm = bit(12) << 8 | bits(11, 10) << 3 | bits(6, 5) << 6 | bits(4, 3) << 1 | bit(2) << 5;
+            encode_b(sign_extend(imm, 9), 0, rs1_c, funct3 & 1)
+        }
+        // c.slli
+        (0b10, 0b000) => encode_i((bit(12) << 5 | rs2) as i64, rd, 0b001, rd, OP_IMM),
+        // c.lwsp, c.ldsp
+        (0b10, 0b010) => encode_i((bit(12) << 5 | bits(6, 4) << 2 | bits(3, 2) << 6) as i64, 2, 0b010, rd, LOAD),
+        (0b10, 0b011) => encode_i((bit(12) << 5 | bits(6, 5) << 3 | bits(4, 2) << 6) as i64, 2, 0b011, rd, LOAD),
+        (0b10, 0b100) => match (bit(12), rd, rs2) {
+            // c.jr, c.mv
+            (0, rs1, 0) if rs1 != 0 => encode_i(0, rs1, 0, 0, 0b1100111),
+            (0, _, _) => encode_r(0, rs2, 0, 0, rd, OP),
+            // c.ebreak, c.jalr, c.add
+            (1, 0, 0) => 0x00100073,
+            (1, rs1, 0) => encode_i(0, rs1, 0, 1, 0b1100111),
+            (1, _, _) => encode_r(0, rs2, rd, 0, rd, OP),
+            _ => return None,
+        },
+        _ => return None,
+    })
+}
+
+fn branch(instr: &Instruction) -> Option<(Cond, BType)> {
+    Some(match *instr {
+        Instruction::Beq(b) => (Cond::Eq, b),
+        Instruction::Bne(b) => (Cond::Ne, b),
+        Instruction::Blt(b) => (Cond::Lt, b),
+        Instruction::Bge(b) => (Cond::Ge, b),
+        Instruction::Bltu(b) => (Cond::Below, b),
+        Instruction::Bgeu(b) => (Cond::AboveEq, b),
+        _ => return None,
+    })
+}
+
+pub fn branch_target(addr: u64, instr: &Instruction) -> Option<u64> {
+    match instr {
+        Instruction::Jal(j) => Some(addr.wrapping_add(sign_extend(j.imm(), 21) as u64)),
+        _ => branch(instr).map(|(_, b)| addr.wrapping_add(sign_extend(b.imm(), 13) as u64)),
+    }
+}
+
+/// jal linking a return address
+pub fn call_target(addr: u64, instr: &Instruction) -> Option<u64> {
+    match instr {
+        Instruction::Jal(j) if j.rd() != ZERO => branch_target(addr, instr),
+        _ => None,
+    }
+}
+
+pub fn ends_block(instr: &Instruction) -> bool {
+    matches!(instr, Instruction::Jal(_) | Instruction::Jalr(_) | Instruction::Ebreak) || branch(instr).is_some()
+}
+
+/// jal and jalr only continue when they link (calls); ebreak traps
+pub fn falls_through(instr: &Instruction) -> bool {
+    match instr {
+        Instruction::Jal(j) => j.rd() != 0,
+        Instruction::Jalr(i) => i.rd() != 0,
+        Instruction::Ebreak => false,
+        _ => true,
+    }
+}
+
+pub fn mnemonic(instr: &Result<Instruction, DecodingError>) -> String {
+    match instr {
+        Ok(instr) => {
+            let debug = format!("{:?}", instr);
+            debug.split('(').next().unwrap_or_default().to_lowercase()
+        }
+        Err(_) => "unknown".to_string(),
+    }
+}
+
+pub fn text(addr: u64, instr: &Result<Instruction, DecodingError>) -> String {
+    let Ok(instr) = instr else { return "unknown".to_string() };
+    let reg = |n: u32| ABI_NAMES[n as usize];
+    let r = |r: &RType| format!("{}, {}, {}", reg(r.rd()), reg(r.rs1()), reg(r.rs2()));
+    let i = |i: &IType| format!("{}, {}, {}", reg(i.rd()), reg(i.rs1()), sign_extend(i.imm(), 12));
+    let load = |i: &IType| format!("{}, {}({})", reg(i.rd()), sign_extend(i.imm(), 12), reg(i.rs1()));
+    let store = |s: &SType| format!("{}, {}({})", reg(s.rs2()), sign_extend(s.imm(), 12), reg(s.rs1()));
+    let u = |u: &UType| format!("{}, {:#x}", reg(u.rd()), u.imm() >> 12);
+
+    let operands = match instr {
+        Instruction::Lui(x) | Instruction::Auipc(x) => u(x),
+        Instruction::Jal(x) => format!("{}, {:#x}", reg(x.rd()), branch_target(addr, instr).unwrap_or_default()),
+        Instruction::Jalr(x) => load(x),
+        Instruction::Lb(x) | Instruction::Lh(x) | Instruction::Lw(x) | Instruction::Lbu(x)
+        | Instruction::Lhu(x) | Instruction::Lwu(x) | Instruction::Ld(x) => load(x),
+        Instruction::Sb(x) | Instruction::Sh(x) | Instruction::Sw(x) | Instruction::Sd(x) => store(x),
+        Instruction::Addi(x) | Instruction::Slti(x) | Instruction::Sltiu(x) | Instruction::Xori(x)
+        | Instruction::Ori(x) | Instruction::Andi(x) | Instruction::Addiw(x) => i(x),
+        Instruction::Add(x) | Instruction::Sub(x) | Instruction::And(x) | Instruction::Or(x)
+        | Instruction::Xor(x) | Instruction::Mul(x) | Instruction::Addw(x) | Instruction::Subw(x)
+        | Instruction::Mulw(x) => r(x),
+        _ => match branch(instr) {
+            Some((_, b)) => format!(
+                "{}, {}, {:#x}",
+                reg(b.rs1()),
+                reg(b.rs2()),
+                branch_target(addr, instr).unwrap_or_default(),
+            ),
+            None => return mnemonic(&Ok(*instr)),
+        },
+    };
+    format!("{} {}", mnemonic(&Ok(*instr)), operands)
+}
+
+impl Lifter {
+    fn riscv_reg(&mut self, number: u32) -> Value {
+        match number {
+            ZERO => Value::Const(0),
+            n => Value::Reg(self.key(RegKey::RiscV(n))),
+        }
+    }
+
+    // Writes to x0 are dropped
+    fn riscv_def(&mut self, rd: u32, op: impl FnOnce(crate::ir::VReg) -> Op) -> Vec<Op> {
+        match rd {
+            ZERO => vec![],
+            n => vec![op(self.key(RegKey::RiscV(n)))],
+        }
+    }
+
+    fn riscv_binary(&mut self, op: BinOp, rd: u32, rs1: u32, rhs: Value) -> Vec<Op> {
+        let lhs = self.riscv_reg(rs1);
+        self.riscv_def(rd, |dst| Op::Binary { op, dst, lhs, rhs })
+    }
+
+    pub fn lift_riscv(&mut self, instr: &Result<Instruction, DecodingError>, addr: u64) -> Option<Vec<Op>> {
+        let instr = instr.as_ref().ok()?;
+
+        if let Some((cond, b)) = branch(instr) {
+            // Compare-and-branch: the comparison goes through the flags register
+            let lhs = self.riscv_reg(b.rs1());
+            let rhs = self.riscv_reg(b.rs2());
+            let flags = self.flags();
+            return Some(vec![
+                Op::Binary { op: BinOp::Sub, dst: flags, lhs, rhs },
+                Op::Branch { cond, flags: Value::Reg(flags), target: branch_target(addr, instr)? },
+            ]);
+        }
+
+        let ops = match *instr {
+            Instruction::Lui(u) => {
+                let value = u.imm() as i32 as i64;
+                self.riscv_def(u.rd(), |dst| Op::Copy { dst, src: Value::Const(value) })
+            }
+            Instruction::Auipc(u) => {
+                let value = addr.wrapping_add(u.imm() as i32 as i64 as u64) as i64;
+                self.riscv_def(u.rd(), |dst| Op::Copy { dst, src: Value::Const(value) })
+            }
+
+            Instruction::Addi(i) | Instruction::Addiw(i) => {
+                self.riscv_binary(BinOp::Add, i.rd(), i.rs1(), Value::Const(sign_extend(i.imm(), 12)))
+            }
+            Instruction::Andi(i) => self.riscv_binary(BinOp::And, i.rd(), i.rs1(), Value::Const(sign_extend(i.imm(), 12))),
+            Instruction::Add(r) | Instruction::Addw(r) | Instruction::Sub(r) | Instruction::Subw(r)
+            | Instruction::And(r) | Instruction::Mul(r) | Instruction::Mulw(r) => {
+                let op = match instr {
+                    Instruction::Add(_) | Instruction::Addw(_) => BinOp::Add,
+                    Instruction::Sub(_) | Instruction::Subw(_) => BinOp::Sub,
+                    Instruction::And(_) => BinOp::And,
+                    _ => BinOp::Mul,
+                };
+                let rhs = self.riscv_reg(r.rs2());
+                self.riscv_binary(op, r.rd(), r.rs1(), rhs)
+            }
+
+            Instruction::Lw(i) | Instruction::Lwu(i) | Instruction::Ld(i) => {
+                let base = self.riscv_reg(i.rs1());
+                let offset = sign_extend(i.imm(), 12);
+                self.riscv_def(i.rd(), |dst| Op::Load { dst, base, offset })
+            }
+            Instruction::Sw(s) | Instruction::Sd(s) => vec![Op::Store {
+                base: self.riscv_reg(s.rs1()),
+                offset: sign_extend(s.imm(), 12),
+                value: self.riscv_reg(s.rs2()),
+            }],
+
+            Instruction::Jal(j) if j.rd() == ZERO => vec![Op::Jump { target: branch_target(addr, instr)? }],
+            // `ret` is `jalr x0, 0(ra)`, the return value is in a0
+            Instruction::Jalr(i) if i.rd() == ZERO && i.rs1() == RA && i.imm() == 0 => {
+                vec![Op::Return { value: self.riscv_reg(A0) }]
+            }
+            Instruction::Jalr(i) if i.rd() == ZERO => vec![Op::IndirectJump],
+            Instruction::Jal(_) | Instruction::Jalr(_) => {
+                vec![Op::Opaque { mnemonic: mnemonic(&Ok(*instr)) }]
+            }
+
+            Instruction::Ebreak => vec![Op::Trap { kind: TrapKind::Breakpoint }],
+
+            // Number in a7, arguments in a0-a5, result in a0
+            Instruction::Ecall => {
+                let args = [A0, A0 + 1, A0 + 2, A0 + 3, A0 + 4, A0 + 5].map(|n| self.riscv_reg(n));
+                let number = self.riscv_reg(A0 + 7);
+                vec![Op::Syscall { dst: self.key(RegKey::RiscV(A0)), number, args }]
+            }
+
+            Instruction::Fence(_) | Instruction::FenceI => vec![],
+
+            _ => return None,
+        };
+        Some(ops)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::expand_compressed;
+    use crate::arch::Arch;
+    use crate::backend::FunctionIR;
+    use crate::ir::{self, BinOp, Op, Value, VReg};
+    use crate::transpiler_real::FidelityReport;
+
+    #[test]
+    fn lifts_compressed_and_full_width_instructions() {
+        // lw a5, 0(a0); addiw a5, a5, 1; sw a5, 0(a0); c.mv a0, a5; c.ret
+        let code = [
+            0x83, 0x27, 0x05, 0x00,
+            0x9b, 0x87, 0x17, 0x00,
+            0x23, 0x20, 0xf5, 0x00,
+            0x3e, 0x85,
+            0x82, 0x80,
+        ];
+        let decoded = FunctionIR::decode("test", Arch::RiscV64, &code, 0x1000);
+        let mut report = FidelityReport::default();
+        let function = ir::lift(&decoded, &mut report);
+        let (a0, a5) = (VReg(0), VReg(1));
+
+        assert!(report.unsupported.is_empty(), "{:?}", report.unsupported);
+        assert_eq!(decoded.instructions.len(), 5);
+        assert_eq!(function.insts().map(|inst| inst.op.clone()).collect::<Vec<_>>(), vec![
+            Op::Load { dst: a5, base: Value::Reg(a0), offset: 0 },
+            Op::Binary { op: BinOp::Add, dst: a5, lhs: Value::Reg(a5), rhs: Value::Const(1) },
+            Op::Store { base: Value::Reg(a0), offset: 0, value: Value::Reg(a5) },
+            Op::Binary { op: BinOp::Add, dst: a0, lhs: Value::Const(0), rhs: Value::Reg(a5) },
+            Op::Return { value: Value::Reg(a0) },
+        ]);
+    }
+
+    #[test]
+    fn expands_compressed_instructions() {
+        // c.li a0, 5
+        assert_eq!(expand_compressed(0x4515), Some(0x00500513));
+        // c.addi a0, 1
+        assert_eq!(expand_compressed(0x0505), Some(0x00150513));
+        // c.mv a0, a5
+        assert_eq!(expand_compressed(0x853e), Some(0x00f00533));
+        // c.ret
+        assert_eq!(expand_compressed(0x8082), Some(0x00008067));
+        // c.beqz a0, +8
+        assert_eq!(expand_compressed(0xc501), Some(0x00050463));
+    }
+}
@@ -0,0 +1,193 @@
+// Shared runtime module
+// Every callback module with a linear memory carries its own memory and
+// allocator, so a page calling many callbacks instantiates as many copies of
+// them. With `[transpiler] shared_runtime = true` they live in one runtime
+// module instead, served at /wasm/runtime.wasm (/wasm/<namespace>/runtime.wasm
+// for `[binaries]`), which exports the memory and `alloc.rs`'s functions.
+// Callback modules import them from "runtime" and re-export them, so callers
+// see the same exports as before. Their data segments and the state image
+// at address 0 have to stay below the heap: the "runtime" field of the
+// `abi.rs` manifest is the heap base a module needs, which the glue passes
+// to the runtime's `reserve(end)` before instantiating it.
+
+use crate::alloc;
+use crate::transpiler_real::LinearMemory;
+use wasm_encoder::{
+    BlockType, CodeSection, ExportKind, ExportSection, Function, FunctionSection, GlobalSection, Instruction,
+    MemorySection, MemoryType, Module, TypeSection, ValType,
+};
+use wasmparser::{DataKind, Operator, Parser, Payload, TypeRef};
+
+/// Module name callback modules import the runtime under
+pub const MODULE: &str = "runtime";
+
+// Global indices of the heap pointer and of the lowest address it is reset to
+const TOP: u32 = 0;
+const BASE: u32 = 1;
+
+/// Type of the shared memory, which imports have to match
+pub fn memory_type(memory64: bool, minimum: u64) -> MemoryType {
+    MemoryType { minimum, maximum: None, memory64, shared: false, page_size_log2: None }
+}
+
+/// The runtime module for binaries with 64-bit (`memory64`) or 32-bit
+/// addresses
+pub fn module(memory64: bool) -> Vec<u8> {
+    let mut module = Module::new();
+
+    // alloc, free and reset as in `alloc.rs`, then reserve(end) -> i32
+    let mut types = TypeSection::new();
+    for (params, results) in alloc::types() {
+        types.ty().function(params, results);
+    }
+    types.ty().function(vec![ValType::I32], vec![ValType::I32]);
+    module.section(&types);
+
+    let mut functions = FunctionSection::new();
+    for idx in 0..alloc::EXPORTS.len() as u32 + 1 {
+        functions.function(idx);
+    }
+    module.section(&functions);
+
+    let mut memories = MemorySection::new();
+    memories.memory(memory_type(memory64, 1));
+    module.section(&memories);
+
+    let mut globals = GlobalSection::new();
+    // Both start at the end of the state image
+    let (ty, init) = alloc::global(&LinearMemory { memory64, segments: Vec::new(), allocator: true });
+    globals.global(ty, &init);
+    globals.global(ty, &init);
+    module.section(&globals);
+
+    let mut exports = ExportSection::new();
+    exports.export("memory", ExportKind::Memory, 0);
+    for (idx, name) in alloc::EXPORTS.iter().chain(["reserve"].iter()).enumerate() {
+        exports.export(name, ExportKind::Func, idx as u32);
+    }
+    module.section(&exports);
+
+    let mut codes = CodeSection::new();
+    codes.function(&alloc::alloc(memory64, TOP));
+    codes.function(&alloc::free(TOP));
+    codes.function(&reset());
+    codes.function(&reserve());
+    module.section(&codes);
+
+    module.finish()
+}
+
+fn reset() -> Function {
+    let mut function = Function::new([]);
+    for instr in [Instruction::GlobalGet(BASE), Instruction::GlobalSet(TOP), Instruction::End] {
+        function.instruction(&instr);
+    }
+    function
+}
+
+// reserve(end): raises the heap base to end, rounded up to 16, and the heap
+// pointer with it, growing the memory to cover it. Returns the heap pointer,
+// or 0 if memory cannot grow.
+fn reserve() -> Function {
+    let mut function = Function::new([]);
+    for instr in [
+        // base = max(base, (end + 15) & -16)
+        Instruction::LocalGet(0),
+        Instruction::I32Const(15),
+        Instruction::I32Add,
+        Instruction::I32Const(-16),
+        Instruction::I32And,
+        Instruction::LocalTee(0),
+        Instruction::GlobalGet(BASE),
+        Instruction::I32GtU,
+        Instruction::If(BlockType::Empty),
+        Instruction::LocalGet(0),
+        Instruction::GlobalSet(BASE),
+        Instruction::End,
+        // top = max(top, base)
+        Instruction::GlobalGet(BASE),
+        Instruction::GlobalGet(TOP),
+        Instruction::I32GtU,
+        Instruction::If(BlockType::Empty),
+        Instruction::GlobalGet(BASE),
+        Instruction::GlobalSet(TOP),
+        Instruction::End,
+        // An empty allocation grows the memory up to the heap pointer
+        Instruction::I32Const(0),
+        Instruction::I32Const(1),
+        Instruction::Call(0),
+        Instruction::End,
+    ] {
+        function.instruction(&instr);
+    }
+    function
+}
+
+/// The heap base a module that imports the runtime needs reserved: the end
+/// of the state image and of its data segments. None for other modules.
+pub fn heap_base(wasm: &[u8]) -> Option<u32> {
+    let mut imports = false;
+    let mut memory = LinearMemory { memory64: false, segments: Vec::new(), allocator: true };
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload {
+            Ok(Payload::ImportSection(reader)) => {
+                for import in reader.into_iter().flatten() {
+                    if let (MODULE, TypeRef::Memory(ty)) = (import.module, import.ty) {
+                        imports = true;
+                        memory.memory64 = ty.memory64;
+                    }
+                }
+            }
+            Ok(Payload::DataSection(reader)) => {
+                for data in reader.into_iter().flatten() {
+                    let DataKind::Active { offset_expr, .. } = data.kind else { continue };
+                    let addr = match offset_expr.get_operators_reader().read() {
+                        Ok(Operator::I32Const { value }) => value as u32 as u64,
+                        Ok(Operator::I64Const { value }) => value as u64,
+                        _ => continue,
+                    };
+                    memory.segments.push((addr, data.data.to_vec()));
+                }
+            }
+            _ => {}
+        }
+    }
+    imports.then(|| alloc::heap_base(&memory))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::Arch;
+    use crate::backend::{FunctionIR, TranspilerBackend};
+    use crate::transpiler_real::{DirectBackend, TranspileOptions};
+    use wasmparser::{Validator, WasmFeatures};
+
+    #[test]
+    fn callback_modules_import_the_shared_runtime() {
+        let runtime = module(true);
+        Validator::new_with_features(WasmFeatures::all()).validate_all(&runtime).unwrap();
+        let wat = wasmprinter::print_bytes(&runtime).unwrap();
+        for export in ["memory", "alloc", "free", "reset", "reserve"] {
+            assert!(wat.contains(&format!("(export \"{}\"", export)), "{}", wat);
+        }
+
+        // mov rax, [rdi]; ret
+        let function = FunctionIR::decode("test", Arch::X86_64, &[0x48, 0x8b, 0x07, 0xc3], 0x1000);
+        let options = TranspileOptions { allocator: true, shared_runtime: true, ..Default::default() };
+        let wasm = DirectBackend::new(options).transpile(&function).unwrap();
+        Validator::new_with_features(WasmFeatures::all()).validate_all(&wasm).unwrap();
+        let wat = wasmprinter::print_bytes(&wasm).unwrap();
+        assert!(wat.contains(r#"(import "runtime" "alloc" (func"#), "{}", wat);
+        assert!(wat.contains(r#"(import "runtime" "memory" (memory"#), "{}", wat);
+        assert!(!wat.contains("(global"), "{}", wat);
+        assert!(wat.contains(r#"(export "callback" (func 3))"#), "{}", wat);
+        assert!(wat.contains(r#"(export "alloc" (func 0))"#), "{}", wat);
+        let state = std::mem::size_of::<crate::State>() as u32;
+        assert_eq!(heap_base(&wasm), Some(state.max(1).next_multiple_of(16)));
+
+        // Without the option the module keeps its own memory and allocator
+        let wasm = DirectBackend::new(TranspileOptions { allocator: true, ..Default::default() }).transpile(&function).unwrap();
+        assert_eq!(heap_base(&wasm), None);
+    }
+}
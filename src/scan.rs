@@ -0,0 +1,216 @@
+// Transpilability scan
+// `x64_to_wasm_server scan <binary>` decodes and lifts every function of a
+// binary, without emitting modules, and reports for each whether it
+// translates: "ok" if every instruction lifts exactly, "partial" if some
+// are approximated (branches, calls, stack operations), "blocked" with the
+// reason if it cannot be decoded or has instructions the front-end does not
+// lift. Each row also has the code size and the call-graph fan-in, the
+// number of scanned functions with a direct call to it, so the functions
+// worth unblocking first stand out. The report is JSON, or CSV with
+// `--format csv`.
+
+use crate::backend::FunctionIR;
+use crate::transpiler_real::{FidelityReport, X64ToWasmTranspiler};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    Partial,
+    Blocked,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Partial => "partial",
+            Status::Blocked => "blocked",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanEntry {
+    pub function: String,
+    /// Entry address, none if the function could not be decoded
+    pub address: Option<u64>,
+    /// Bytes of machine code
+    pub size: usize,
+    pub instructions: usize,
+    pub status: Status,
+    /// Approximated or unsupported mnemonics, or the decoding error
+    pub reason: Option<String>,
+    pub fan_in: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Summary {
+    pub ok: usize,
+    pub partial: usize,
+    pub blocked: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Scan {
+    pub summary: Summary,
+    /// In symbol order
+    pub functions: Vec<ScanEntry>,
+}
+
+// Distinct mnemonics of a fidelity list, sorted
+fn mnemonics(instructions: &[(u64, String)]) -> String {
+    let distinct: BTreeSet<&str> = instructions.iter().map(|(_, mnemonic)| mnemonic.as_str()).collect();
+    distinct.into_iter().collect::<Vec<_>>().join(", ")
+}
+
+/// Lifts each decoded function and counts its direct callers
+pub fn scan(functions: impl IntoIterator<Item = (String, Result<FunctionIR, String>)>) -> Scan {
+    let mut entries = Vec::new();
+    let mut callers: HashMap<u64, usize> = HashMap::new();
+    for (name, function) in functions {
+        let function = match function {
+            Ok(function) => function,
+            Err(error) => {
+                entries.push(ScanEntry {
+                    function: name,
+                    address: None,
+                    size: 0,
+                    instructions: 0,
+                    status: Status::Blocked,
+                    reason: Some(error),
+                    fan_in: 0,
+                });
+                continue;
+            }
+        };
+
+        let callees: HashSet<u64> = function.instructions.iter().filter_map(|info| info.instr.call_target()).collect();
+        for callee in callees {
+            *callers.entry(callee).or_default() += 1;
+        }
+
+        let mut report = FidelityReport::default();
+        crate::ir::lift(&function, &mut report);
+        let (status, reason) = match () {
+            _ if !report.unsupported.is_empty() => (Status::Blocked, Some(format!("unsupported: {}", mnemonics(&report.unsupported)))),
+            _ if !report.approximated.is_empty() => (Status::Partial, Some(format!("approximated: {}", mnemonics(&report.approximated)))),
+            _ => (Status::Ok, None),
+        };
+        entries.push(ScanEntry {
+            function: name,
+            address: Some(function.entry),
+            size: function.code.len(),
+            instructions: function.instructions.len(),
+            status,
+            reason,
+            fan_in: 0,
+        });
+    }
+
+    let mut summary = Summary::default();
+    for entry in &mut entries {
+        entry.fan_in = entry.address.and_then(|address| callers.get(&address).copied()).unwrap_or(0);
+        match entry.status {
+            Status::Ok => summary.ok += 1,
+            Status::Partial => summary.partial += 1,
+            Status::Blocked => summary.blocked += 1,
+        }
+    }
+    Scan { summary, functions: entries }
+}
+
+// Quoted if it holds a separator, quote or line break
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+impl Scan {
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("function,address,size,instructions,status,reason,fan_in\n");
+        for entry in &self.functions {
+            let address = entry.address.map(|address| format!("{:#x}", address)).unwrap_or_default();
+            csv.push_str(&format!(
+                "{},{},{},{},{},{},{}\n",
+                csv_field(&entry.function),
+                address,
+                entry.size,
+                entry.instructions,
+                entry.status.name(),
+                csv_field(entry.reason.as_deref().unwrap_or_default()),
+                entry.fan_in
+            ));
+        }
+        csv
+    }
+}
+
+const USAGE: &str = "Usage: x64_to_wasm_server scan <binary> [--format json|csv]";
+
+/// `x64_to_wasm_server scan`, with the arguments after the command; prints
+/// the report
+pub fn run(mut args: impl Iterator<Item = String>) -> std::io::Result<()> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+    let mut binary = None;
+    let mut csv = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => match args.next().as_deref() {
+                Some("csv") => csv = true,
+                Some("json") => csv = false,
+                _ => return Err(invalid(USAGE.to_string())),
+            },
+            _ if binary.is_none() => binary = Some(arg),
+            _ => return Err(invalid(USAGE.to_string())),
+        }
+    }
+    let binary = binary.ok_or_else(|| invalid(USAGE.to_string()))?;
+    let transpiler = X64ToWasmTranspiler::new(&binary).map_err(|e| invalid(format!("{}: {}", binary, e)))?;
+    let scan = scan(transpiler.decode_functions().map_err(|e| invalid(format!("{}: {}", binary, e)))?);
+    let report = match csv {
+        true => scan.to_csv(),
+        false => serde_json::to_string_pretty(&scan).map_err(std::io::Error::other)? + "\n",
+    };
+    std::io::stdout().lock().write_all(report.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::Arch;
+
+    #[test]
+    fn classifies_functions_and_counts_callers() {
+        let decode = |name: &str, code: &[u8], entry: u64| (name.to_string(), Ok(FunctionIR::decode(name, Arch::X86_64, code, entry)));
+        let scan = scan([
+            // mov eax, [rdi]; ret
+            decode("load", &[0x8b, 0x07, 0xc3], 0x1000),
+            // call 0x1000; ret
+            decode("caller", &[0xe8, 0xf6, 0xff, 0xff, 0xff, 0xc3], 0x1005),
+            // movd xmm0, eax; call 0x1000; ret
+            decode("vector", &[0x66, 0x0f, 0x6e, 0xc0, 0xe8, 0xed, 0xff, 0xff, 0xff, 0xc3], 0x100a),
+            ("missing".to_string(), Err("Function not found".to_string())),
+        ]);
+
+        let rows: Vec<(&str, Status, usize)> = scan.functions.iter().map(|entry| (entry.function.as_str(), entry.status, entry.fan_in)).collect();
+        assert_eq!(rows, vec![
+            ("load", Status::Ok, 2),
+            ("caller", Status::Partial, 0),
+            ("vector", Status::Blocked, 0),
+            ("missing", Status::Blocked, 0),
+        ]);
+        assert_eq!(scan.functions[1].reason.as_deref(), Some("approximated: call"));
+        assert_eq!(scan.functions[2].reason.as_deref(), Some("unsupported: movd"));
+        assert_eq!((scan.summary.ok, scan.summary.partial, scan.summary.blocked), (1, 1, 2));
+
+        let csv = scan.to_csv();
+        assert!(csv.starts_with("function,address,size,instructions,status,reason,fan_in\nload,0x1000,3,2,ok,,2\n"));
+        assert!(csv.ends_with("missing,,0,0,blocked,Function not found,0\n"));
+    }
+}
@@ -0,0 +1,211 @@
+// Scheduled callbacks
+// `[[schedule]]` tables run registered callbacks without a client: on a
+// five-field cron expression in UTC (`minute hour day-of-month month
+// day-of-week`, with `*`, lists, ranges and `/` steps) or every
+// `every_secs` seconds. Each run takes the same path as POST /execute, so it
+// is committed, logged and pushed to the browsers like any other mutation.
+// Every instance runs its schedule, so in a cluster only one should have it.
+
+use crate::hooks::Trigger;
+use crate::ServerContext;
+use serde::Deserialize;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    pub callback: String,
+    /// e.g. `"0 3 * * *"` for 03:00 UTC every night
+    pub cron: Option<String>,
+    pub every_secs: Option<u64>,
+    /// The client arguments of the callback's signature
+    #[serde(default)]
+    pub args: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+    Cron(Cron),
+    Every(Duration),
+}
+
+impl ScheduleConfig {
+    pub fn schedule(&self) -> Result<Schedule, String> {
+        match (&self.cron, self.every_secs) {
+            (Some(cron), None) => Cron::parse(cron).map(Schedule::Cron),
+            (None, Some(0)) => Err("every_secs must be positive".to_string()),
+            (None, Some(secs)) => Ok(Schedule::Every(Duration::from_secs(secs))),
+            _ => Err("A schedule needs exactly one of cron and every_secs".to_string()),
+        }
+    }
+}
+
+/// The matching values of each field, as bit sets
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Cron matches either day field when both are restricted
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!("Cron expression needs 5 fields: {}", expression));
+        };
+        Ok(Self {
+            minutes: field(minute, 0, 59)?,
+            hours: field(hour, 0, 23)?,
+            days: field(day, 1, 31)?,
+            months: field(month, 1, 12)?,
+            // 0 and 7 are both Sunday
+            weekdays: field(weekday, 0, 7).map(|bits| (bits | bits >> 7) & 0x7f)?,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+
+    /// The first matching minute after `secs`, in seconds since the epoch
+    pub fn next_after(&self, secs: u64) -> Option<u64> {
+        let first = secs / 60 + 1;
+        // Every combination of fields recurs within 28 years
+        (first..first + 28 * 366 * 24 * 60).find(|minute| self.matches(*minute)).map(|minute| minute * 60)
+    }
+
+    fn matches(&self, minute: u64) -> bool {
+        let days_since_epoch = minute / (24 * 60);
+        let (_, month, day) = civil_from_days(days_since_epoch as i64);
+        // 1970-01-01 was a Thursday
+        let weekday = (days_since_epoch + 4) % 7;
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => bit(self.days, day) || bit(self.weekdays, weekday),
+            _ => bit(self.days, day) && bit(self.weekdays, weekday),
+        };
+        bit(self.minutes, minute % 60) && bit(self.hours, minute / 60 % 24) && bit(self.months, month) && day_matches
+    }
+}
+
+fn bit(set: u64, value: u64) -> bool {
+    set & (1 << value) != 0
+}
+
+// One field: a comma-separated list of `*`, `n` or `a-b`, each with an
+// optional `/step`
+fn field(text: &str, min: u64, max: u64) -> Result<u64, String> {
+    let number = |n: &str| match n.parse::<u64>() {
+        Ok(n) if (min..=max).contains(&n) => Ok(n),
+        _ => Err(format!("{} is not between {} and {}", n, min, max)),
+    };
+    let mut set = 0;
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u64>().ok().filter(|step| *step > 0).ok_or_else(|| format!("Invalid step in {}", part))?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (number(start)?, number(end)?),
+                // A single value with a step runs to the end, like `*`
+                None if step > 1 => (number(range)?, max),
+                None => (number(range)?, number(range)?),
+            },
+        };
+        if start > end {
+            return Err(format!("Empty range {}", range));
+        }
+        set |= (start..=end).step_by(step as usize).fold(0, |set, value| set | 1 << value);
+    }
+    Ok(set)
+}
+
+// (year, month, day) of a day count since 1970-01-01, from Howard Hinnant's
+// days_from_civil inverse
+fn civil_from_days(days: i64) -> (i64, u64, u64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u64;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u64;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+/// Spawns a task per valid schedule entry; invalid ones are logged and skipped
+pub fn start(schedules: &[ScheduleConfig], ctx: &ServerContext) {
+    for config in schedules {
+        let schedule = match config.schedule() {
+            Ok(schedule) if ctx.callbacks.get(&config.callback).is_some() => schedule,
+            Ok(_) => {
+                tracing::warn!(callback = %config.callback, "Not scheduling an unknown callback");
+                continue;
+            }
+            Err(e) => {
+                tracing::warn!(callback = %config.callback, error = %e, "Invalid schedule");
+                continue;
+            }
+        };
+        let (ctx, config) = (ctx.clone(), config.clone());
+        tracing::info!(callback = %config.callback, cron = ?config.cron, every_secs = ?config.every_secs, "Scheduled callback");
+        actix_rt::spawn(async move {
+            loop {
+                let delay = match &schedule {
+                    Schedule::Every(interval) => *interval,
+                    Schedule::Cron(cron) => {
+                        let now = now_secs();
+                        let Some(next) = cron.next_after(now) else {
+                            return tracing::warn!(callback = %config.callback, "Cron expression never matches");
+                        };
+                        Duration::from_secs(next - now)
+                    }
+                };
+                actix_rt::time::sleep(delay).await;
+                let callback = ctx.callbacks.get(&config.callback).expect("checked above");
+                match ctx.execute(callback, &config.args, Trigger::Schedule, None) {
+                    Ok((result, version)) => tracing::info!(callback = %config.callback, ?result, version, trigger = "schedule", "executed callback"),
+                    Err(e) => tracing::warn!(callback = %config.callback, error = %e, trigger = "schedule", "callback failed"),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_next_cron_match() {
+        // 2024-02-28 23:59:30 UTC, a Wednesday
+        let now = 1709164770;
+        let nightly = Cron::parse("0 3 * * *").unwrap();
+        // 2024-02-29 03:00, the leap day
+        assert_eq!(nightly.next_after(now), Some(1709175600));
+        let quarter_hours = Cron::parse("*/15 * * * *").unwrap();
+        assert_eq!(quarter_hours.next_after(now), Some(1709164800));
+        // Mondays at 09:30, 2024-03-04
+        let mondays = Cron::parse("30 9 * * 1").unwrap();
+        assert_eq!(mondays.next_after(now), Some(1709544600));
+        // 7 is Sunday too, 2024-03-03
+        assert_eq!(Cron::parse("0 0 * * 7").unwrap().next_after(now), Some(1709424000));
+        assert_eq!(Cron::parse("0 0 31 2 *").unwrap().next_after(now), None);
+
+        assert!(Cron::parse("60 * * * *").is_err());
+        assert!(Cron::parse("* * *").is_err());
+        let both = ScheduleConfig { callback: "reset_counter".to_string(), cron: Some("* * * * *".to_string()), every_secs: Some(5), args: Vec::new() };
+        assert!(both.schedule().is_err());
+    }
+}
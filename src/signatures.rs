@@ -0,0 +1,130 @@
+// Declared signatures
+// Binaries without debug info do not say what their functions take or
+// return, and the symbols of `[binaries]` tables have no registered
+// callback signature either, so they are translated with the parameters the
+// lifter recovers. `[binaries.<namespace>] signatures = "signatures.toml"`
+// declares them instead, one table per symbol:
+//
+//     [blend]
+//     params = ["state", "in", { out = 16 }, "f64"]
+//     returns = "f64"
+//
+// Parameters are assigned to the argument registers of the calling
+// convention in order, integers and pointers to the integer ones, doubles
+// to the float ones, and become the parameters of the WASM function whether
+// the code reads them or not. Pointers are annotated with what they point
+// to, which decides how the glue's `callSymbol` marshals them:
+// - `"state"` the application state. It takes an argument register but is
+//   no WASM parameter, the register keeps address 0, where the glue copies
+//   the state image before the call.
+// - `"in"` an input buffer, passed as a `(ptr, len)` pair in two registers.
+//   The client's string or TypedArray is copied into linear memory.
+// - `{ out = <bytes> }` an output buffer the glue allocates in linear memory
+//   and returns a copy of after the call.
+// - `"opaque"` an address the client passes through unchanged.
+// Integers are passed at the register width of the binary. The glue reads
+// the signature from the module's `meta.rs` section.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParamType {
+    /// Pointer to the state, fixed to address 0
+    State,
+    /// Input buffer supplied by the client, as `(ptr, len)`
+    In,
+    /// Output buffer of the given size, allocated by the glue
+    Out(usize),
+    /// Pointer the client supplies as a number
+    Opaque,
+    I32,
+    I64,
+    F64,
+}
+
+impl ParamType {
+    /// Integer argument registers the parameter takes
+    pub fn int_args(self) -> usize {
+        match self {
+            ParamType::In => 2,
+            ParamType::F64 => 0,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResultType {
+    I32,
+    #[default]
+    I64,
+    F64,
+    Void,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeclaredSignature {
+    pub params: Vec<ParamType>,
+    pub returns: ResultType,
+}
+
+/// The signatures of one binary, by symbol
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Signatures(BTreeMap<String, DeclaredSignature>);
+
+impl Signatures {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        toml::from_str(&contents).map_err(|e| format!("Invalid signatures {}: {}", path.display(), e))
+    }
+
+    pub fn get(&self, symbol: &str) -> Option<&DeclaredSignature> {
+        self.0.get(symbol)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::Arch;
+    use crate::backend::{FunctionIR, TranspilerBackend};
+    use crate::transpiler_real::{DirectBackend, TranspileOptions};
+    use wasm_encoder::ValType;
+
+    #[test]
+    fn declared_parameters_become_the_wasm_signature() {
+        let signatures: Signatures = toml::from_str(r#"
+            [scale]
+            params = ["state", "in", "f64", { out = 8 }]
+            returns = "f64"
+        "#).unwrap();
+        let signature = signatures.get("scale").unwrap().clone();
+        assert_eq!(signature.params, vec![ParamType::State, ParamType::In, ParamType::F64, ParamType::Out(8)]);
+        assert!(signatures.get("blend").is_none());
+
+        // cvtsi2sd xmm1, rsi; mulsd xmm0, xmm1; ret, which scales by the
+        // length of the input buffer and never reads rdx or rcx
+        let code = [0xf2, 0x48, 0x0f, 0x2a, 0xce, 0xf2, 0x0f, 0x59, 0xc1, 0xc3];
+        let function = FunctionIR::decode("scale", Arch::X86_64, &code, 0x1000);
+        let lower = |options: TranspileOptions| {
+            let backend = DirectBackend::new(options);
+            let lowered = backend.lower(&function).unwrap();
+            (lowered.params, lowered.result)
+        };
+        assert_eq!(lower(TranspileOptions::default()).0.len(), 1);
+        let declared = TranspileOptions { float_result: true, signature: Some(signature.clone()), ..Default::default() };
+        assert_eq!(lower(declared), (vec![ValType::I64, ValType::I64, ValType::F64, ValType::I64], ValType::F64));
+        let json = serde_json::to_value(&signature).unwrap();
+        assert_eq!(json["params"], serde_json::json!(["state", "in", "f64", { "out": 8 }]));
+
+        let too_many = DeclaredSignature { params: vec![ParamType::F64; 9], returns: ResultType::Void };
+        let options = TranspileOptions { signature: Some(too_many), ..Default::default() };
+        assert!(DirectBackend::new(options).lower(&function).is_err());
+    }
+}
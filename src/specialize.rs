@@ -0,0 +1,182 @@
+// Session-specialized modules
+// A callback whose behavior depends on configuration kept in globals can
+// list them as `session_globals` in `[transpiler.functions.<name>]`. A
+// session fixes their values with `POST /session/globals`, kept in the
+// `self_serve_globals` cookie, and from then on `/wasm` and the manifest
+// serve it a module translated with those values baked in: loads of a fixed
+// global become constants, which constant propagation folds further.
+// Specialized modules are translations of the machine code, also for the
+// callbacks whose generic module is not. They are cached per symbol and
+// values, up to `[transpiler] max_specialized_modules`, dropping the least
+// recently used, and replace the release variant only: debug modules keep
+// the generic code their instruction mapping describes.
+
+use crate::error::ApiError;
+use crate::ServerContext;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Responder, ResponseError};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+
+pub const GLOBALS_COOKIE: &str = "self_serve_globals";
+
+pub const DEFAULT_MAX_MODULES: usize = 256;
+
+/// The values the session of `req` fixed, none if its cookie is missing or
+/// malformed
+pub fn session_values(req: &HttpRequest) -> BTreeMap<String, i64> {
+    req.cookie(GLOBALS_COOKIE)
+        .and_then(|cookie| URL_SAFE_NO_PAD.decode(cookie.value()).ok())
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .unwrap_or_default()
+}
+
+/// Identifies a set of values in the cache
+pub fn key(values: &BTreeMap<String, i64>) -> String {
+    let json = serde_json::to_vec(values).expect("values serialize to JSON");
+    hex::encode(Sha256::digest(json))
+}
+
+pub struct SpecializedModules {
+    max: usize,
+    tick: u64,
+    // By symbol and `key` of the values, with the tick of the last use
+    modules: HashMap<(String, String), (Vec<u8>, u64)>,
+}
+
+impl SpecializedModules {
+    pub fn new(max: usize) -> Self {
+        Self { max, tick: 0, modules: HashMap::new() }
+    }
+
+    pub fn get(&mut self, fn_name: &str, key: &str) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let (wasm, used) = self.modules.get_mut(&(fn_name.to_string(), key.to_string()))?;
+        *used = self.tick;
+        Some(wasm.clone())
+    }
+
+    pub fn insert(&mut self, fn_name: &str, key: &str, wasm: Vec<u8>) {
+        self.tick += 1;
+        self.modules.insert((fn_name.to_string(), key.to_string()), (wasm, self.tick));
+        while self.modules.len() > self.max {
+            let Some(oldest) = self.modules.iter().min_by_key(|(_, (_, used))| *used).map(|(id, _)| id.clone()) else { break };
+            self.modules.remove(&oldest);
+        }
+    }
+
+    /// Drops the modules of `fn_name`, once its generic module is replaced
+    pub fn remove_function(&mut self, fn_name: &str) {
+        self.modules.retain(|(symbol, _), _| symbol != fn_name);
+    }
+
+    /// A cached module of `fn_name` that `matches`
+    pub fn find(&self, fn_name: &str, matches: impl Fn(&[u8]) -> bool) -> Option<Vec<u8>> {
+        self.modules
+            .iter()
+            .find(|((symbol, _), (wasm, _))| symbol == fn_name && matches(wasm))
+            .map(|(_, (wasm, _))| wasm.clone())
+    }
+}
+
+/// Fixes the globals of the session to the values of the JSON object in
+/// the body. An empty object clears them, so the generic modules are
+/// served again.
+pub async fn set_globals(
+    req: HttpRequest,
+    values: web::Json<BTreeMap<String, i64>>,
+    ctx: web::Data<ServerContext>,
+) -> impl Responder {
+    if let Err(e) = ctx.csrf.verify(&req) {
+        return e.error_response();
+    }
+    let values = values.into_inner();
+    let unknown: Vec<&String> = values
+        .keys()
+        .filter(|name| {
+            !std::iter::once(&ctx.transpiler)
+                .chain(ctx.binaries.iter().map(|(_, binary)| binary))
+                .any(|transpiler| transpiler.is_session_global(name))
+        })
+        .collect();
+    if !unknown.is_empty() {
+        return ApiError::new(StatusCode::BAD_REQUEST, "unknown_global", "Only the session_globals of [transpiler.functions] can be fixed")
+            .with_detail(serde_json::json!({ "globals": unknown }))
+            .error_response();
+    }
+
+    let json = serde_json::to_vec(&values).expect("values serialize to JSON");
+    let mut cookie = Cookie::build(GLOBALS_COOKIE, URL_SAFE_NO_PAD.encode(json))
+        .path("/")
+        .same_site(SameSite::Lax)
+        .finish();
+    if values.is_empty() {
+        cookie.make_removal();
+    }
+    HttpResponse::Ok()
+        .cookie(cookie)
+        .json(serde_json::json!({ "globals": values }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BinaryConfig, TranspileOverrides, TranspilerConfig};
+    use crate::signatures::Signatures;
+    use crate::transpiler::Transpiler;
+    use actix_web::test::TestRequest;
+
+    #[no_mangle]
+    static mut SPECIALIZE_RATE: i64 = 3;
+
+    #[no_mangle]
+    extern "C" fn specialize_probe(x: i64) -> i64 {
+        // A direct load even in debug builds, which read globals through the GOT
+        let rate: i64;
+        // SAFETY: only reads the global, which is never written
+        unsafe { std::arch::asm!("mov {}, qword ptr [rip + {}]", out(reg) rate, sym SPECIALIZE_RATE) };
+        x.wrapping_mul(rate)
+    }
+
+    #[test]
+    fn bakes_session_globals_into_modules() {
+        // Keeps the probe from being stripped out of the test binary
+        assert_eq!(std::hint::black_box(specialize_probe as extern "C" fn(i64) -> i64)(2), 6);
+        let binary = BinaryConfig {
+            path: std::env::current_exe().unwrap(),
+            callbacks: vec!["specialize_probe".to_string()],
+            load_bias: None,
+            signatures: None,
+        };
+        let mut config = TranspilerConfig { max_specialized_modules: Some(1), ..Default::default() };
+        config.functions.insert(
+            "specialize_probe".to_string(),
+            TranspileOverrides { session_globals: vec!["SPECIALIZE_RATE".to_string()], ..Default::default() },
+        );
+        let transpiler = Transpiler::for_namespace("test", &binary, &Signatures::default(), &config);
+        transpiler.warm_up();
+        assert!(transpiler.is_session_global("SPECIALIZE_RATE") && !transpiler.is_session_global("OTHER"));
+
+        let cookie = URL_SAFE_NO_PAD.encode(r#"{"SPECIALIZE_RATE":7,"OTHER":1}"#);
+        let req = TestRequest::default().cookie(Cookie::new(GLOBALS_COOKIE, cookie)).to_http_request();
+        let session = session_values(&req);
+        let wasm = transpiler.get_specialized_wasm("specialize_probe", &session).unwrap().unwrap();
+        let wat = wasmprinter::print_bytes(&wasm).unwrap();
+        let generic = wasmprinter::print_bytes(transpiler.get_wasm_for_function("specialize_probe").unwrap()).unwrap();
+        // The load of the global became the constant
+        assert!(wat.contains("i64.const 7") && !generic.contains("i64.const 7"), "{}", wat);
+        assert_eq!(wat.matches("i64.load").count() + 1, generic.matches("i64.load").count(), "{}", wat);
+        assert_eq!(transpiler.get_specialized_wasm("specialize_probe", &session).unwrap().unwrap(), wasm);
+        assert!(transpiler.get_specialized_wasm("specialize_probe", &BTreeMap::new()).is_none());
+
+        let hash = crate::manifest::content_hash(&wasm);
+        assert!(transpiler.find_specialized("specialize_probe", &hash).is_some());
+        let other = BTreeMap::from([("SPECIALIZE_RATE".to_string(), 8)]);
+        transpiler.get_specialized_wasm("specialize_probe", &other).unwrap().unwrap();
+        // Only one module is kept
+        assert!(transpiler.find_specialized("specialize_probe", &hash).is_none());
+    }
+}
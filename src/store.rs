@@ -0,0 +1,475 @@
+// State store
+// The application state and its version. Every mutation is committed to a
+// `StateBackend`, chosen with `[state] backend`: "memory" (the default)
+// keeps nothing, "file" writes the image to a file, "sqlite" to a row of a
+// database and "redis" to a key, which several server instances share. On
+// startup the store loads the stored image, and the backend watches for
+// images other instances stored, which replace the state when they are
+// later. Every commit and every state taken over is pushed to the browsers
+// as a `state` event. Each instance counts versions on its own, so two of
+// them can commit the same version; snapshots are ordered by version, then
+// by the id of the committing instance, and the backends keep the later
+// one, so every instance settles on the same state. Readers, such as
+// read-only callbacks and the JSON API, share the lock; mutations hold it
+// exclusively.
+
+use crate::events::Broadcaster;
+use crate::redis::{Connection, Reply};
+use crate::State;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use rusqlite::OptionalExtension;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StateBackendKind {
+    #[default]
+    Memory,
+    File,
+    Sqlite,
+    Redis,
+}
+
+/// A committed state image
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub version: u64,
+    /// Id of the instance that committed it
+    pub instance: u64,
+    pub image: Vec<u8>,
+}
+
+impl Snapshot {
+    /// Snapshots are ordered by version, then by instance, so instances
+    /// agree on which of two commits of the same version wins
+    pub fn order(&self) -> (u64, u64) {
+        (self.version, self.instance)
+    }
+}
+
+/// Called with a state another instance stored
+pub type OnChange = Box<dyn Fn(Snapshot) + Send + 'static>;
+
+pub trait StateBackend: Send + Sync {
+    /// The stored snapshot, none if nothing is stored yet
+    fn load(&self) -> Result<Option<Snapshot>, String>;
+    /// Stores `snapshot`, unless a later one is stored already
+    fn store(&self, snapshot: &Snapshot) -> Result<(), String>;
+    /// Starts calling `on_change` for stores of other instances
+    fn watch(&self, on_change: OnChange) -> Result<(), String>;
+}
+
+// Stored as the version and the instance, 8 bytes little-endian each,
+// followed by the image
+fn encode(snapshot: &Snapshot) -> Vec<u8> {
+    let mut bytes = snapshot.version.to_le_bytes().to_vec();
+    bytes.extend_from_slice(&snapshot.instance.to_le_bytes());
+    bytes.extend_from_slice(&snapshot.image);
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Result<Snapshot, String> {
+    let Some((version, rest)) = bytes.split_first_chunk::<8>() else {
+        return Err(format!("Stored state of {} bytes has no version", bytes.len()));
+    };
+    let Some((instance, image)) = rest.split_first_chunk::<8>() else {
+        return Err(format!("Stored state of {} bytes has no instance", bytes.len()));
+    };
+    Ok(Snapshot { version: u64::from_le_bytes(*version), instance: u64::from_le_bytes(*instance), image: image.to_vec() })
+}
+
+// Whether `snapshot` is later than the stored one, if any
+fn supersedes(snapshot: &Snapshot, stored: Option<&Snapshot>) -> bool {
+    stored.is_none_or(|stored| snapshot.order() > stored.order())
+}
+
+/// Keeps the state in the process only
+pub struct MemoryBackend;
+
+impl StateBackend for MemoryBackend {
+    fn load(&self) -> Result<Option<Snapshot>, String> {
+        Ok(None)
+    }
+
+    fn store(&self, _snapshot: &Snapshot) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn watch(&self, _on_change: OnChange) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+pub struct FileBackend {
+    path: PathBuf,
+    // Kept alive while watching
+    watcher: Mutex<Option<RecommendedWatcher>>,
+}
+
+impl FileBackend {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, watcher: Mutex::new(None) }
+    }
+}
+
+impl StateBackend for FileBackend {
+    fn load(&self) -> Result<Option<Snapshot>, String> {
+        match std::fs::read(&self.path) {
+            Ok(bytes) => decode(&bytes).map(Some),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("Could not read {}: {}", self.path.display(), e)),
+        }
+    }
+
+    fn store(&self, snapshot: &Snapshot) -> Result<(), String> {
+        // Not atomic with the write: processes storing at the same moment
+        // can still overwrite a later snapshot
+        if !supersedes(snapshot, self.load().ok().flatten().as_ref()) {
+            return Ok(());
+        }
+        // Written next to the file and renamed over it, so readers never
+        // see half an image
+        let temporary = self.path.with_extension("tmp");
+        std::fs::write(&temporary, encode(snapshot))
+            .and_then(|_| std::fs::rename(&temporary, &self.path))
+            .map_err(|e| format!("Could not write {}: {}", self.path.display(), e))
+    }
+
+    fn watch(&self, on_change: OnChange) -> Result<(), String> {
+        let path = self.path.clone();
+        let file_name = path.file_name().map(PathBuf::from).unwrap_or_default();
+        let mut watcher = notify::recommended_watcher(move |result: notify::Result<notify::Event>| {
+            let Ok(event) = result else { return };
+            let affects_file = event.paths.iter().any(|changed| changed.file_name() == Some(file_name.as_os_str()));
+            if affects_file && (event.kind.is_create() || event.kind.is_modify()) {
+                if let Ok(snapshot) = std::fs::read(&path).map_err(|e| e.to_string()).and_then(|bytes| decode(&bytes)) {
+                    on_change(snapshot);
+                }
+            }
+        })
+        .map_err(|e| e.to_string())?;
+        // The directory, as the file is replaced on every store
+        let directory = self.path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        watcher.watch(directory, RecursiveMode::NonRecursive).map_err(|e| e.to_string())?;
+        *self.watcher.lock().unwrap() = Some(watcher);
+        Ok(())
+    }
+}
+
+/// Stores the state in the single row of the `state` table of a database,
+/// which instances on one host or a shared volume open together
+pub struct SqliteBackend {
+    path: PathBuf,
+    connection: Mutex<rusqlite::Connection>,
+}
+
+// How often a watching instance looks for snapshots of the others, as
+// SQLite does not notify other connections
+const SQLITE_POLL: Duration = Duration::from_millis(250);
+
+impl SqliteBackend {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let connection = Self::connect(path)?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS state (
+                    id INTEGER PRIMARY KEY CHECK (id = 0),
+                    version INTEGER NOT NULL,
+                    instance INTEGER NOT NULL,
+                    image BLOB NOT NULL
+                )",
+            )
+            .map_err(|e| format!("Could not create the state table in {}: {}", path.display(), e))?;
+        Ok(Self { path: path.to_path_buf(), connection: Mutex::new(connection) })
+    }
+
+    fn connect(path: &Path) -> Result<rusqlite::Connection, String> {
+        let connection = rusqlite::Connection::open(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+        // Waits for the write lock of another instance instead of failing
+        connection.busy_timeout(Duration::from_secs(5)).map_err(|e| e.to_string())?;
+        Ok(connection)
+    }
+
+    fn select(connection: &rusqlite::Connection) -> Result<Option<Snapshot>, String> {
+        connection
+            .query_row("SELECT version, instance, image FROM state WHERE id = 0", [], |row| {
+                Ok(Snapshot { version: row.get::<_, i64>(0)? as u64, instance: row.get::<_, i64>(1)? as u64, image: row.get(2)? })
+            })
+            .optional()
+            .map_err(|e| format!("Could not load the state: {}", e))
+    }
+}
+
+impl StateBackend for SqliteBackend {
+    fn load(&self) -> Result<Option<Snapshot>, String> {
+        Self::select(&self.connection.lock().unwrap())
+    }
+
+    fn store(&self, snapshot: &Snapshot) -> Result<(), String> {
+        // Versions and instance ids stay below 2^63, so they compare the
+        // same as SQLite integers
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO state (id, version, instance, image) VALUES (0, ?1, ?2, ?3)
+                 ON CONFLICT (id) DO UPDATE SET version = excluded.version, instance = excluded.instance, image = excluded.image
+                 WHERE (excluded.version, excluded.instance) > (state.version, state.instance)",
+                rusqlite::params![snapshot.version as i64, snapshot.instance as i64, snapshot.image],
+            )
+            .map(|_| ())
+            .map_err(|e| format!("Could not store the state: {}", e))
+    }
+
+    fn watch(&self, on_change: OnChange) -> Result<(), String> {
+        let connection = Self::connect(&self.path)?;
+        let mut seen = Self::select(&connection)?.map(|snapshot| snapshot.order());
+        std::thread::spawn(move || loop {
+            std::thread::sleep(SQLITE_POLL);
+            match Self::select(&connection) {
+                Ok(Some(snapshot)) if Some(snapshot.order()) != seen => {
+                    seen = Some(snapshot.order());
+                    on_change(snapshot);
+                }
+                Ok(_) => {}
+                Err(e) => tracing::warn!(error = %e, "Could not poll the SQLite state"),
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Stores the state under a key and publishes its version on the channel
+/// `<key>:version`
+pub struct RedisBackend {
+    url: String,
+    key: String,
+    connection: Mutex<Connection>,
+}
+
+impl RedisBackend {
+    pub fn open(url: &str, key: &str) -> Result<Self, String> {
+        let connection = Connection::open(url)?;
+        Ok(Self { url: url.to_string(), key: key.to_string(), connection: Mutex::new(connection) })
+    }
+
+    fn channel(&self) -> String {
+        format!("{}:version", self.key)
+    }
+}
+
+impl StateBackend for RedisBackend {
+    fn load(&self) -> Result<Option<Snapshot>, String> {
+        match self.connection.lock().unwrap().command(&[b"GET", self.key.as_bytes()])? {
+            Reply::Bulk(Some(bytes)) => decode(&bytes).map(Some),
+            Reply::Bulk(None) => Ok(None),
+            reply => Err(format!("Unexpected reply to GET: {:?}", reply)),
+        }
+    }
+
+    fn store(&self, snapshot: &Snapshot) -> Result<(), String> {
+        let mut connection = self.connection.lock().unwrap();
+        // The key is watched while comparing, so EXEC does nothing, and the
+        // store is retried, when another instance sets it in between
+        loop {
+            connection.command(&[b"WATCH", self.key.as_bytes()])?;
+            let stored = match connection.command(&[b"GET", self.key.as_bytes()])? {
+                Reply::Bulk(Some(bytes)) => decode(&bytes).ok(),
+                _ => None,
+            };
+            if !supersedes(snapshot, stored.as_ref()) {
+                connection.command(&[b"UNWATCH"])?;
+                return Ok(());
+            }
+            connection.command(&[b"MULTI"])?;
+            connection.command(&[b"SET", self.key.as_bytes(), &encode(snapshot)])?;
+            connection.command(&[b"PUBLISH", self.channel().as_bytes(), snapshot.version.to_string().as_bytes()])?;
+            // The null reply of an aborted transaction reads as no replies
+            if connection.command(&[b"EXEC"])? != Reply::Array(Vec::new()) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn watch(&self, on_change: OnChange) -> Result<(), String> {
+        // A subscribed connection takes no other commands, so it gets its own
+        let mut subscriber = Connection::open(&self.url)?;
+        subscriber.command(&[b"SUBSCRIBE", self.channel().as_bytes()])?;
+        let mut reader = Connection::open(&self.url)?;
+        let key = self.key.clone();
+        std::thread::spawn(move || loop {
+            match subscriber.read_reply() {
+                Ok(Reply::Array(message)) if message.first() == Some(&Reply::Bulk(Some(b"message".to_vec()))) => {
+                    if let Ok(Reply::Bulk(Some(bytes))) = reader.command(&[b"GET", key.as_bytes()]) {
+                        if let Ok(snapshot) = decode(&bytes) {
+                            on_change(snapshot);
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::warn!(error = %e, "Redis state subscription ended");
+                    return;
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+pub fn open_backend(config: &crate::config::StateConfig) -> Result<Box<dyn StateBackend>, String> {
+    Ok(match config.backend {
+        StateBackendKind::Memory => Box::new(MemoryBackend),
+        StateBackendKind::File => Box::new(FileBackend::new(config.path.clone())),
+        StateBackendKind::Sqlite => Box::new(SqliteBackend::open(&config.path)?),
+        StateBackendKind::Redis => Box::new(RedisBackend::open(&config.url, &config.key)?),
+    })
+}
+
+pub struct StateStore {
+    state: RwLock<State>,
+    // Bumped on every state mutation and on hot reload
+    version: AtomicU64,
+    // The instance that committed the state, see `Snapshot::order`
+    committer: AtomicU64,
+    // Id of this instance, random and below 2^63
+    instance: u64,
+    backend: Box<dyn StateBackend>,
+    events: Arc<Broadcaster>,
+}
+
+impl StateStore {
+    /// The stored state, or `initial` if the backend has none
+    pub fn open(initial: State, backend: Box<dyn StateBackend>, events: Arc<Broadcaster>) -> Result<Self, String> {
+        let (version, committer, state) = match backend.load()? {
+            Some(snapshot) => (snapshot.version, snapshot.instance, from_image(&initial, &snapshot.image)?),
+            None => (0, 0, initial),
+        };
+        Ok(Self {
+            state: RwLock::new(state),
+            version: AtomicU64::new(version),
+            committer: AtomicU64::new(committer),
+            instance: rand::random::<u64>() >> 1,
+            backend,
+            events,
+        })
+    }
+
+    /// Id of this instance in the snapshots it commits
+    pub fn instance(&self) -> u64 {
+        self.instance
+    }
+
+    /// The state, for mutating it
+    pub fn lock(&self) -> RwLockWriteGuard<'_, State> {
+        self.state.write().unwrap()
+    }
+
+    /// The state, shared with other readers
+    pub fn read(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read().unwrap()
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    /// Bumps the version after `state`, the locked state, was mutated,
+    /// stores and announces it. A failing backend is logged, the change
+    /// stays in memory.
+    pub fn commit(&self, state: &State) -> u64 {
+        let version = self.version.fetch_add(1, Ordering::SeqCst) + 1;
+        self.committer.store(self.instance, Ordering::SeqCst);
+        let snapshot = Snapshot { version, instance: self.instance, image: state.as_bytes().to_vec() };
+        if let Err(e) = self.backend.store(&snapshot) {
+            tracing::warn!(error = %e, version, "Could not store the state");
+        }
+        self.events.broadcast_state(version, state.as_bytes());
+        version
+    }
+
+    /// Replaces the state with the snapshot of another instance if it is
+    /// later, and announces it to this instance's browsers
+    pub fn take_over(&self, snapshot: &Snapshot) {
+        let mut state = self.lock();
+        if snapshot.order() <= (self.version(), self.committer.load(Ordering::SeqCst)) {
+            return;
+        }
+        match from_image(&state, &snapshot.image) {
+            Ok(stored) => *state = stored,
+            Err(e) => return tracing::warn!(error = %e, "Ignoring stored state"),
+        }
+        self.version.store(snapshot.version, Ordering::SeqCst);
+        self.committer.store(snapshot.instance, Ordering::SeqCst);
+        drop(state);
+        self.events.broadcast_local("state", &snapshot.version.to_string());
+    }
+
+    /// Takes over later states other instances store
+    pub fn watch(self: &Arc<Self>) -> Result<(), String> {
+        let store = Arc::downgrade(self);
+        self.backend.watch(Box::new(move |snapshot| {
+            if let Some(store) = store.upgrade() {
+                store.take_over(&snapshot);
+            }
+        }))
+    }
+}
+
+pub fn from_image(template: &State, image: &[u8]) -> Result<State, String> {
+    let mut state = template.clone();
+    let bytes = state.as_bytes_mut();
+    if image.len() != bytes.len() {
+        return Err(format!("Stored state has {} bytes, the state {}", image.len(), bytes.len()));
+    }
+    bytes.copy_from_slice(image);
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_backend_persists_commits() {
+        let events = Arc::new(Broadcaster::new());
+        let path = std::env::temp_dir().join(format!("self-serve-state-{}.bin", std::process::id()));
+        let store = StateStore::open(State { counter: 0 }, Box::new(FileBackend::new(path.clone())), events.clone()).unwrap();
+        store.lock().counter = 41;
+        assert_eq!(store.commit(&store.lock()), 1);
+
+        let reopened = StateStore::open(State { counter: 0 }, Box::new(FileBackend::new(path.clone())), events.clone()).unwrap();
+        assert_eq!((reopened.version(), reopened.lock().counter), (1, 41));
+        std::fs::write(&path, [2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 9]).unwrap();
+        assert!(StateStore::open(State { counter: 0 }, Box::new(FileBackend::new(path.clone())), events.clone()).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn instances_settle_on_one_of_two_commits_of_a_version() {
+        let events = Arc::new(Broadcaster::new());
+        let path = std::env::temp_dir().join(format!("self-serve-state-{}.db", std::process::id()));
+        let open = || StateStore::open(State { counter: 0 }, Box::new(SqliteBackend::open(&path).unwrap()), events.clone()).unwrap();
+        let (a, b) = (open(), open());
+
+        // Both commit version 1, and each hears of the other's commit
+        a.lock().counter = 1;
+        assert_eq!(a.commit(&a.lock()), 1);
+        b.lock().counter = 2;
+        assert_eq!(b.commit(&b.lock()), 1);
+        let snapshot = |store: &StateStore| Snapshot { version: 1, instance: store.instance(), image: store.read().as_bytes().to_vec() };
+        let (from_a, from_b) = (snapshot(&a), snapshot(&b));
+        a.take_over(&from_b);
+        b.take_over(&from_a);
+
+        let winner = if a.instance() > b.instance() { 1 } else { 2 };
+        assert_eq!((a.read().counter, b.read().counter), (winner, winner));
+        // The database kept the same snapshot, whichever stored last
+        assert_eq!(open().read().counter, winner);
+        std::fs::remove_file(path).unwrap();
+    }
+}
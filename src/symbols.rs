@@ -0,0 +1,202 @@
+// Symbol index
+// Several symbols can name the same code: identical code folding at link
+// time gives functions with equal bodies one address, and weak aliases share
+// the address of their target. One name can also be defined more than once,
+// as a weak default with a strong override or as static functions of several
+// files. The index keys function symbols by (name, address). A name resolves
+// to its strong definition over weak and local ones, and the other names of
+// a body find the module translated under one of them. The index can be
+// persisted between boots, see `analysis_cache.rs`.
+
+use object::{BinaryFormat, Object, ObjectSymbol, SymbolKind};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+
+/// Binding of a definition, in order of preference
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Binding {
+    Global,
+    Weak,
+    Local,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+struct Definition {
+    size: u64,
+    binding: Binding,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SymbolIndex {
+    // Entries of the static and dynamic symbol tables and of the exports
+    // are merged per (name, address)
+    #[serde(with = "entries")]
+    symbols: BTreeMap<(String, u64), Definition>,
+    // Mach-O prefixes C symbols with an underscore
+    underscored: bool,
+}
+
+// `SymbolIndex::symbols` as a list of (name, address, definition), as
+// JSON object keys cannot be pairs
+mod entries {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(symbols: &BTreeMap<(String, u64), Definition>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(symbols.iter().map(|((name, addr), definition)| (name, addr, definition)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<(String, u64), Definition>, D::Error> {
+        let entries: Vec<(String, u64, Definition)> = Vec::deserialize(deserializer)?;
+        Ok(entries.into_iter().map(|(name, addr, definition)| ((name, addr), definition)).collect())
+    }
+}
+
+impl SymbolIndex {
+    /// Function symbols defined in `obj`, and the exports of DLLs (which
+    /// have no sizes)
+    pub fn new(obj: &object::File) -> Self {
+        let mut index = Self { underscored: obj.format() == BinaryFormat::MachO, ..Self::default() };
+        for symbol in obj.symbols().chain(obj.dynamic_symbols()) {
+            if symbol.kind() != SymbolKind::Text || !symbol.is_definition() {
+                continue;
+            }
+            let Ok(name) = symbol.name() else { continue };
+            let binding = match () {
+                _ if symbol.is_weak() => Binding::Weak,
+                _ if symbol.is_global() => Binding::Global,
+                _ => Binding::Local,
+            };
+            index.insert(name, symbol.address(), symbol.size(), binding);
+        }
+        for export in obj.exports().unwrap_or_default() {
+            if let Ok(name) = std::str::from_utf8(export.name()) {
+                index.insert(name, export.address(), 0, Binding::Global);
+            }
+        }
+        index
+    }
+
+    fn insert(&mut self, name: &str, addr: u64, size: u64, binding: Binding) {
+        let definition = self.symbols.entry((name.to_string(), addr)).or_insert(Definition { size, binding });
+        definition.size = definition.size.max(size);
+        definition.binding = definition.binding.min(binding);
+    }
+
+    // `fn_name` as the symbol table spells it
+    fn spellings(&self, fn_name: &str) -> Vec<String> {
+        match self.underscored {
+            true => vec![fn_name.to_string(), format!("_{}", fn_name)],
+            false => vec![fn_name.to_string()],
+        }
+    }
+
+    // `name` as callbacks are registered
+    fn unmangled<'a>(&self, name: &'a str) -> &'a str {
+        match self.underscored {
+            true => name.strip_prefix('_').unwrap_or(name),
+            false => name,
+        }
+    }
+
+    /// Address and size of the definition `fn_name` resolves to. A symbol
+    /// without a size takes the size of another name of its address; the
+    /// size is 0 if none has one. Equally preferred definitions at different
+    /// addresses are ambiguous.
+    pub fn resolve(&self, fn_name: &str) -> Result<(u64, u64), String> {
+        let mut candidates: Vec<(u64, Definition)> = self
+            .spellings(fn_name)
+            .into_iter()
+            .flat_map(|name| self.symbols.range((name.clone(), 0)..=(name, u64::MAX)))
+            .map(|((_, addr), definition)| (*addr, *definition))
+            .collect();
+        candidates.sort_by_key(|(addr, definition)| (definition.binding, *addr));
+        let (addr, definition) = *candidates.first().ok_or("Function not found")?;
+        if let Some((other, _)) = candidates.iter().find(|(other, candidate)| candidate.binding == definition.binding && *other != addr) {
+            return Err(format!("{} is defined at both {:#x} and {:#x}", fn_name, addr, other));
+        }
+        let size = match definition.size {
+            0 => self.symbols.iter().filter(|((_, other), _)| *other == addr).map(|(_, other)| other.size).max().unwrap_or(0),
+            size => size,
+        };
+        Ok((addr, size))
+    }
+
+    /// One name per function body that resolves to it, the strong name
+    /// where there is a choice, by address
+    pub fn functions(&self) -> Vec<String> {
+        let mut bodies: BTreeMap<u64, (Binding, &str)> = BTreeMap::new();
+        for ((name, addr), definition) in &self.symbols {
+            let name = self.unmangled(name);
+            if !self.resolve(name).is_ok_and(|(other, _)| other == *addr) {
+                continue;
+            }
+            let body = bodies.entry(*addr).or_insert((definition.binding, name));
+            *body = (*body).min((definition.binding, name));
+        }
+        bodies.into_values().map(|(_, name)| name.to_string()).collect()
+    }
+
+    /// The name that resolves to the body at `addr`, the strong one where
+    /// there is a choice
+    pub fn at(&self, addr: u64) -> Option<String> {
+        self.symbols
+            .iter()
+            .filter(|((_, other), _)| *other == addr)
+            .map(|((name, _), definition)| (definition.binding, self.unmangled(name)))
+            .filter(|(_, name)| self.resolve(name).is_ok_and(|(other, _)| other == addr))
+            .min()
+            .map(|(_, name)| name.to_string())
+    }
+
+    /// The other names that resolve to the body of `fn_name`, strong ones
+    /// first
+    pub fn aliases(&self, fn_name: &str) -> Vec<String> {
+        let Ok((addr, _)) = self.resolve(fn_name) else { return Vec::new() };
+        let mut aliases: Vec<(Binding, &str)> = self
+            .symbols
+            .iter()
+            .filter(|((name, other), _)| *other == addr && self.unmangled(name) != fn_name)
+            .map(|((name, _), definition)| (definition.binding, self.unmangled(name)))
+            .filter(|(_, name)| self.resolve(name).is_ok_and(|(other, _)| other == addr))
+            .collect();
+        aliases.sort();
+        let mut names: Vec<String> = Vec::new();
+        for (_, name) in aliases {
+            if !names.iter().any(|other| other == name) {
+                names.push(name.to_string());
+            }
+        }
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_aliases_and_prefers_strong_definitions() {
+        let mut index = SymbolIndex::default();
+        // Folded by ICF
+        index.insert("add_one", 0x1000, 8, Binding::Global);
+        index.insert("increment", 0x1000, 8, Binding::Global);
+        // A weak alias without a size
+        index.insert("inc", 0x1000, 0, Binding::Weak);
+        // A weak default overridden by a strong definition
+        index.insert("hook", 0x2000, 4, Binding::Weak);
+        index.insert("hook", 0x3000, 4, Binding::Global);
+        // Static functions of two files
+        index.insert("helper", 0x4000, 4, Binding::Local);
+        index.insert("helper", 0x5000, 4, Binding::Local);
+
+        assert_eq!(index.resolve("inc"), Ok((0x1000, 8)));
+        assert_eq!(index.aliases("inc"), vec!["add_one", "increment"]);
+        assert_eq!(index.aliases("add_one"), vec!["increment", "inc"]);
+        assert_eq!(index.resolve("hook"), Ok((0x3000, 4)));
+        assert!(index.aliases("hook").is_empty());
+        assert_eq!(index.resolve("helper"), Err("helper is defined at both 0x4000 and 0x5000".to_string()));
+        assert_eq!(index.resolve("missing"), Err("Function not found".to_string()));
+        assert_eq!(index.functions(), vec!["add_one", "hook"]);
+    }
+}
@@ -0,0 +1,273 @@
+// State sync
+// POST /sync closes the optimistic execution loop: a browser that ran a
+// callback's module on its own copy of the state posts the callback, its
+// arguments, the state version it started from and the byte ranges the call
+// changed. With `[sync] policy = "verify"` (the default) the server runs the
+// native callback on its state and accepts the patch only if it produces
+// the same bytes; with "trust" it applies the patch without re-executing.
+// "paranoid" also has the client send its whole linear memory after the
+// call: the state region at address 0 is byte-compared with the native
+// result, and a mismatch is logged with a hex diff and listed with the
+// client errors of the admin dashboard.
+// Accepted patches are committed like any mutation, which bumps the state
+// version and announces it to the other browsers as a `state` event. A rejected patch gets the authoritative state
+// and version back, so the glue can rebase its pending invocations on top.
+
+use crate::audit::Execution;
+use crate::callbacks::CallbackValue;
+use crate::error::ApiError;
+use crate::hooks::{Invocation, Trigger};
+use crate::{ServerContext, State};
+use actix_web::http::StatusCode;
+use base64::Engine;
+use actix_web::{web, HttpRequest, HttpResponse, Responder, ResponseError};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncPolicy {
+    /// Re-execute the native callback and compare
+    #[default]
+    Verify,
+    /// Apply the patch of any client allowed to invoke the callback
+    Trust,
+    /// Verify, and compare the client's memory image with native execution
+    Paranoid,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyncRequest {
+    pub callback: String,
+    #[serde(default)]
+    pub args: Vec<serde_json::Value>,
+    /// State version the client executed against
+    pub version: u64,
+    pub state_patch: Vec<PatchRange>,
+    /// Base64 of the client's linear memory after the call, required by
+    /// the paranoid policy
+    #[serde(default)]
+    pub memory_image: Option<String>,
+}
+
+/// Bytes of the state image starting at `offset`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PatchRange {
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Serialize)]
+pub struct SyncResponse {
+    pub version: u64,
+    /// Result of the native callback, none when the patch was trusted
+    pub result: Option<CallbackValue>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictReason {
+    /// The patch is based on an older version
+    Stale,
+    /// Native execution produced different bytes
+    Mismatch,
+}
+
+/// Detail of a 409, carrying what the client rebases onto
+#[derive(Serialize)]
+pub struct SyncConflict {
+    pub reason: ConflictReason,
+    pub version: u64,
+    /// The current state image
+    pub state: Vec<u8>,
+}
+
+impl SyncConflict {
+    pub fn new(reason: ConflictReason, version: u64, state: &State) -> Self {
+        Self { reason, version, state: state.as_bytes().to_vec() }
+    }
+}
+
+impl From<SyncConflict> for ApiError {
+    fn from(conflict: SyncConflict) -> Self {
+        let message = match conflict.reason {
+            ConflictReason::Stale => format!("The patch is not based on the current version {}", conflict.version),
+            ConflictReason::Mismatch => "The patch differs from the result of the native callback".to_string(),
+        };
+        ApiError::new(StatusCode::CONFLICT, "conflict", message).with_detail(conflict)
+    }
+}
+
+/// The runs of bytes where `client` differs from `native`, as hex
+pub fn hex_diff(native: &[u8], client: &[u8]) -> String {
+    let mut runs = Vec::new();
+    let mut offset = 0;
+    while offset < native.len() {
+        if native[offset] == client[offset] {
+            offset += 1;
+            continue;
+        }
+        let end = (offset..native.len()).find(|&idx| native[idx] == client[idx]).unwrap_or(native.len());
+        runs.push(format!("+{}: native {} client {}", offset, hex::encode(&native[offset..end]), hex::encode(&client[offset..end])));
+        offset = end;
+    }
+    runs.join(", ")
+}
+
+/// The state region of a paranoid sync's memory image, if it differs from
+/// `native`, with the hex diff
+fn image_mismatch(native: &State, image: Option<&str>) -> Result<Option<String>, ApiError> {
+    let image = image.ok_or_else(|| {
+        ApiError::new(StatusCode::BAD_REQUEST, "image_required", "The paranoid sync policy needs the memory_image of the call")
+    })?;
+    let image = base64::engine::general_purpose::STANDARD
+        .decode(image)
+        .map_err(|e| ApiError::bad_request(format!("Invalid memory_image: {}", e)))?;
+    let native = native.as_bytes();
+    let Some(region) = image.get(..native.len()) else {
+        return Err(ApiError::bad_request(format!("The memory_image has {} bytes, the state {}", image.len(), native.len())));
+    };
+    Ok((region != native).then(|| hex_diff(native, region)))
+}
+
+/// `state` with the patch applied
+pub fn patched(state: &State, patch: &[PatchRange]) -> Result<State, String> {
+    let mut patched = state.clone();
+    let bytes = patched.as_bytes_mut();
+    for range in patch {
+        let end = range.offset.checked_add(range.bytes.len()).filter(|end| *end <= bytes.len());
+        let Some(end) = end else {
+            return Err(format!("Patch at {} runs past the {}-byte state", range.offset, bytes.len()));
+        };
+        bytes[range.offset..end].copy_from_slice(&range.bytes);
+    }
+    Ok(patched)
+}
+
+pub async fn sync(req: HttpRequest, body: web::Json<SyncRequest>, ctx: web::Data<ServerContext>) -> impl Responder {
+    if let Err(e) = ctx.csrf.verify(&req) {
+        return e.error_response();
+    }
+    let Some(callback) = ctx.callbacks.get(&body.callback) else {
+        return ApiError::not_found("Unknown callback").error_response();
+    };
+    if let Err(e) = ctx.auth.authorize(&req, callback.permission) {
+        return e.into_response(&ctx.auth);
+    }
+
+    let mut state = ctx.state.lock();
+    let current = ctx.state.version();
+    if body.version != current {
+        return ApiError::from(SyncConflict::new(ConflictReason::Stale, current, &state)).error_response();
+    }
+    let mut patched = match patched(&state, &body.state_patch) {
+        Ok(patched) => patched,
+        Err(e) => return ApiError::bad_request(e).error_response(),
+    };
+    let invocation = Invocation { callback: &body.callback, args: &body.args, trigger: Trigger::Sync };
+    let session = crate::audit::session_digest(&req);
+    let audit = |outcome, version| {
+        ctx.audit.record(Execution { session: session.clone(), callback: &body.callback, trigger: Trigger::Sync, args: &body.args, outcome, version });
+    };
+    if let Err(e) = ctx.hooks.check(&invocation, &state) {
+        audit(Err(e.to_string()), None);
+        return ApiError::from(e).error_response();
+    }
+
+    let result = match ctx.sync_policy {
+        SyncPolicy::Verify | SyncPolicy::Paranoid => {
+            let mut expected = state.clone();
+            let result = match callback.call(&mut expected, &body.args) {
+                Ok(result) => result,
+                Err(e) => {
+                    audit(Err(e.to_string()), None);
+                    return ApiError::from(e).error_response();
+                }
+            };
+            if expected.as_bytes() != patched.as_bytes() {
+                tracing::warn!(callback = %body.callback, "client state patch differs from native execution");
+                audit(Err("The state patch differs from native execution".to_string()), None);
+                return ApiError::from(SyncConflict::new(ConflictReason::Mismatch, current, &state)).error_response();
+            }
+            if ctx.sync_policy == SyncPolicy::Paranoid {
+                match image_mismatch(&expected, body.memory_image.as_deref()) {
+                    Ok(None) => {}
+                    Ok(Some(diff)) => {
+                        tracing::error!(callback = %body.callback, %diff, "client memory image differs from native execution");
+                        let message = format!("State image differs from native execution: {}", diff);
+                        ctx.telemetry.record(crate::telemetry::ClientReport {
+                            callback: body.callback.clone(),
+                            module: None,
+                            trap: None,
+                            message: Some(message.clone()),
+                            user_agent: req.headers().get(actix_web::http::header::USER_AGENT).and_then(|agent| agent.to_str().ok()).map(str::to_string),
+                            request_id: crate::error::request_id(),
+                        });
+                        audit(Err(message), None);
+                        return ApiError::from(SyncConflict::new(ConflictReason::Mismatch, current, &state)).error_response();
+                    }
+                    Err(e) => {
+                        audit(Err(e.to_string()), None);
+                        return e.error_response();
+                    }
+                }
+            }
+            Some(result)
+        }
+        SyncPolicy::Trust => None,
+    };
+
+    // After the comparison, which is against the bare native callback
+    ctx.hooks.finish(&invocation, &state, &mut patched);
+    *state = patched;
+    callback.record_execution();
+    let version = ctx.state.commit(&state);
+    audit(Ok(serde_json::to_value(&result).expect("results serialize to JSON")), Some(version));
+    drop(state);
+    tracing::info!(callback = %body.callback, version, policy = ?ctx.sync_policy, "synced client execution");
+    HttpResponse::Ok().json(SyncResponse { version, result })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_patches_within_the_state() {
+        let state = State { counter: 0x01020304 };
+        let patch = [PatchRange { offset: 1, bytes: vec![0xff, 0xee] }];
+        assert_eq!(patched(&state, &patch).unwrap().counter, 0x01eeff04);
+        assert_eq!(patched(&state, &[]).unwrap().counter, 0x01020304);
+
+        let past_end = [PatchRange { offset: 3, bytes: vec![0, 0] }];
+        assert_eq!(patched(&state, &past_end).err().unwrap(), "Patch at 3 runs past the 4-byte state");
+        let overflow = [PatchRange { offset: usize::MAX, bytes: vec![0] }];
+        assert!(patched(&state, &overflow).is_err());
+    }
+
+    #[test]
+    fn conflicts_carry_the_authoritative_state() {
+        let conflict = ApiError::from(SyncConflict::new(ConflictReason::Stale, 7, &State { counter: 0x0102 }));
+        assert_eq!(conflict.status_code(), StatusCode::CONFLICT);
+        let json = serde_json::to_value(&conflict).unwrap();
+        assert_eq!(json["code"], "conflict");
+        assert_eq!(json["detail"]["reason"], "stale");
+        assert_eq!(json["detail"]["version"], 7);
+        assert_eq!(json["detail"]["state"], serde_json::json!([2, 1, 0, 0]));
+    }
+
+    #[test]
+    fn compares_memory_images_with_the_native_state() {
+        let native = State { counter: 0x01020304 };
+        let encode = |bytes: &[u8]| base64::engine::general_purpose::STANDARD.encode(bytes);
+        // Memory past the state is the module's own
+        assert_eq!(image_mismatch(&native, Some(&encode(&[4, 3, 2, 1, 9, 9]))).unwrap(), None);
+        assert_eq!(image_mismatch(&native, Some(&encode(&[4, 0, 0, 1, 9]))).unwrap().unwrap(), "+1: native 0302 client 0000");
+        assert_eq!(hex_diff(&[1, 2, 3, 4], &[0, 2, 3, 5]), "+0: native 01 client 00, +3: native 04 client 05");
+
+        assert_eq!(image_mismatch(&native, None).err().unwrap().status_code(), StatusCode::BAD_REQUEST);
+        assert!(image_mismatch(&native, Some(&encode(&[4, 3]))).is_err());
+        assert!(image_mismatch(&native, Some("not base64!")).is_err());
+    }
+}
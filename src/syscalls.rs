@@ -0,0 +1,168 @@
+// Syscall policy
+// Linux syscalls a callback makes (inlined getpid, clock reads) are mapped to
+// host functions. In browser mode every recognized syscall becomes an
+// `env.syscall_<name>` import taking the raw arguments and returning the raw
+// result, a negative errno on failure. In WASI mode the ones with a WASI
+// preview 1 equivalent call it instead, with pointers into linear memory.
+// Anything else traps, reporting `SYSCALL_TRAP_BASE + number` through
+// `env.trap_reason` when that is enabled.
+
+use crate::arch::Arch;
+use serde::{Deserialize, Serialize};
+use wasm_encoder::ValType;
+
+/// Trap reason codes of unsupported syscalls start here, above the x86
+/// exception vectors, see `ir::TrapKind::code`
+pub const SYSCALL_TRAP_BASE: i32 = 0x100;
+
+pub const WASI_MODULE: &str = "wasi_snapshot_preview1";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyscallMode {
+    // env.syscall_<name> imports, implemented by the page glue
+    #[default]
+    Browser,
+    // wasi_snapshot_preview1 imports, for WASI hosts
+    Wasi,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syscall {
+    SchedYield,
+    Getpid,
+    Gettid,
+    ClockGettime,
+    Getrandom,
+    Exit,
+    ExitGroup,
+}
+
+impl Syscall {
+    /// x86-64 has its own numbering, AArch64 and RISC-V share the generic one
+    pub fn from_number(arch: Arch, number: i64) -> Option<Self> {
+        let syscall = match (arch, number) {
+            (Arch::X86_64, 24) | (Arch::AArch64, 124) => Syscall::SchedYield,
+            (Arch::X86_64, 39) | (Arch::AArch64, 172) => Syscall::Getpid,
+            (Arch::X86_64, 186) | (Arch::AArch64, 178) => Syscall::Gettid,
+            (Arch::X86_64, 228) | (Arch::AArch64, 113) => Syscall::ClockGettime,
+            (Arch::X86_64, 318) | (Arch::AArch64, 278) => Syscall::Getrandom,
+            (Arch::X86_64, 60) | (Arch::AArch64, 93) => Syscall::Exit,
+            (Arch::X86_64, 231) | (Arch::AArch64, 94) => Syscall::ExitGroup,
+            #[cfg(feature = "riscv")]
+            (Arch::RiscV64, number) => return Self::from_number(Arch::AArch64, number),
+            _ => return None,
+        };
+        Some(syscall)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Syscall::SchedYield => "sched_yield",
+            Syscall::Getpid => "getpid",
+            Syscall::Gettid => "gettid",
+            Syscall::ClockGettime => "clock_gettime",
+            Syscall::Getrandom => "getrandom",
+            Syscall::Exit => "exit",
+            Syscall::ExitGroup => "exit_group",
+        }
+    }
+
+    /// Arguments passed to the browser import
+    pub fn arg_count(self) -> usize {
+        match self {
+            Syscall::SchedYield | Syscall::Getpid | Syscall::Gettid => 0,
+            Syscall::Exit | Syscall::ExitGroup => 1,
+            Syscall::ClockGettime => 2,
+            Syscall::Getrandom => 3,
+        }
+    }
+
+    /// Name and core type of the WASI function implementing the syscall.
+    /// getpid and gettid have none.
+    pub fn wasi(self) -> Option<(&'static str, Vec<ValType>, Vec<ValType>)> {
+        let (name, params, results) = match self {
+            Syscall::SchedYield => ("sched_yield", vec![], vec![ValType::I32]),
+            // (id, precision, time_ptr) -> errno, writes nanoseconds
+            Syscall::ClockGettime => ("clock_time_get", vec![ValType::I32, ValType::I64, ValType::I32], vec![ValType::I32]),
+            // (buf, len) -> errno
+            Syscall::Getrandom => ("random_get", vec![ValType::I32, ValType::I32], vec![ValType::I32]),
+            Syscall::Exit | Syscall::ExitGroup => ("proc_exit", vec![ValType::I32], vec![]),
+            Syscall::Getpid | Syscall::Gettid => return None,
+        };
+        Some((name, params, results))
+    }
+}
+
+/// A host function imported for syscalls, numbered in order of first use
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyscallImport {
+    pub module: &'static str,
+    pub name: String,
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
+}
+
+impl SyscallImport {
+    /// The import implementing `syscall` in `mode`, None if it has to trap
+    pub fn new(syscall: Syscall, mode: SyscallMode, word: ValType) -> Option<Self> {
+        match mode {
+            SyscallMode::Browser => Some(Self {
+                module: "env",
+                name: format!("syscall_{}", syscall.name()),
+                params: vec![word; syscall.arg_count()],
+                results: vec![word],
+            }),
+            SyscallMode::Wasi => syscall.wasi().map(|(name, params, results)| Self {
+                module: WASI_MODULE,
+                name: name.to_string(),
+                params,
+                results,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arch::Arch;
+    use crate::backend::{FunctionIR, TranspilerBackend};
+    use crate::syscalls::SyscallMode;
+    use crate::transpiler_real::{DirectBackend, TranspileOptions};
+
+    fn lower(code: &[u8], options: TranspileOptions) -> (Vec<String>, String) {
+        let backend = DirectBackend::new(options);
+        let lowered = backend.lower(&FunctionIR::decode("test", Arch::X86_64, code, 0x1000)).unwrap();
+        let syscall = lowered.wasm(&lowered.lowerings[1]).iter().map(|instr| lowered.wat_text(instr)).collect();
+        let wasm = backend.emit(lowered);
+        wasmparser::Validator::new().validate_all(&wasm).unwrap();
+        (syscall, wasmprinter::print_bytes(&wasm).unwrap())
+    }
+
+    #[test]
+    fn maps_syscalls_to_host_functions() {
+        // mov eax, 39 (getpid); syscall; ret
+        let (syscall, wat) = lower(&[0xb8, 0x27, 0x00, 0x00, 0x00, 0x0f, 0x05, 0xc3], TranspileOptions::default());
+        assert_eq!(syscall[0], "call $env.syscall_getpid");
+        assert!(wat.contains(r#"(import "env" "syscall_getpid" (func (;0;) (type 1)))"#), "{}", wat);
+
+        // mov eax, 318 (getrandom); syscall; ret
+        let code = [0xb8, 0x3e, 0x01, 0x00, 0x00, 0x0f, 0x05, 0xc3];
+        let (_, wat) = lower(&code, TranspileOptions { syscalls: SyscallMode::Wasi, ..Default::default() });
+        assert!(wat.contains(r#"(import "wasi_snapshot_preview1" "random_get" (func (;0;) (type 1)))"#), "{}", wat);
+        assert!(wat.contains("select"), "{}", wat);
+
+        // mov eax, 39; syscall in WASI mode, which has no getpid
+        let code = [0xb8, 0x27, 0x00, 0x00, 0x00, 0x0f, 0x05, 0xc3];
+        let options = TranspileOptions { syscalls: SyscallMode::Wasi, trap_reason: true, ..Default::default() };
+        let (syscall, _) = lower(&code, options);
+        assert_eq!(syscall, vec!["i32.const 295", "call $env.trap_reason", "unreachable"]);
+
+        // mov edi, 3; mov eax, 60 (exit); syscall, whose result nothing reads
+        let code = [0xbf, 0x03, 0x00, 0x00, 0x00, 0xb8, 0x3c, 0x00, 0x00, 0x00, 0x0f, 0x05];
+        let backend = DirectBackend::new(TranspileOptions::default());
+        let lowered = backend.lower(&FunctionIR::decode("test", Arch::X86_64, &code, 0x1000)).unwrap();
+        let exit: Vec<String> = lowered.wasm(&lowered.lowerings[2]).iter().map(|instr| lowered.wat_text(instr)).collect();
+        assert_eq!(exit[..2], ["i64.const 3", "call $env.syscall_exit"]);
+    }
+}
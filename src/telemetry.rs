@@ -0,0 +1,151 @@
+// Client telemetry
+// Error reports the page glue posts from the browser: traps of translated
+// modules and errors loading or running callbacks. With `on_trap = true`,
+// traps carry the address of the machine instruction the module trapped at.
+// The latest reports are kept in a ring buffer and listed on the admin
+// dashboard, so broken translations in the wild become visible.
+
+use crate::ServerContext;
+use actix_web::{http::header, web, HttpRequest, HttpResponse, Responder, ResponseError};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+// Reports kept, older ones are dropped
+const CAPACITY: usize = 100;
+// Longer strings are cut, so the buffer stays small whatever clients send
+const MAX_FIELD_CHARS: usize = 512;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientReport {
+    pub callback: String,
+    /// URL the failing code was loaded from, `/wasm/{fn_name}` or the
+    /// JavaScript fallback at `/js/{fn_name}`
+    #[serde(default)]
+    pub module: Option<String>,
+    #[serde(default)]
+    pub trap: Option<TrapInfo>,
+    /// Errors other than traps, such as a module failing to instantiate
+    #[serde(default)]
+    pub message: Option<String>,
+    /// The request's User-Agent header if absent
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    /// ID of the failed request, from its `X-Request-Id` header or error
+    /// body, to find it in the server logs. The ID of the report's own
+    /// request if absent.
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrapInfo {
+    /// Code passed to env.on_trap, see `ir::TrapKind::code`
+    pub code: i32,
+    /// Address of the source instruction as hex, which can exceed the
+    /// integers JSON numbers hold exactly
+    pub address: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredReport {
+    pub received_at: SystemTime,
+    pub report: ClientReport,
+}
+
+#[derive(Default)]
+pub struct Telemetry {
+    reports: Mutex<VecDeque<StoredReport>>,
+}
+
+impl Telemetry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, mut report: ClientReport) {
+        let cut = |field: &mut String| {
+            if let Some((end, _)) = field.char_indices().nth(MAX_FIELD_CHARS) {
+                field.truncate(end);
+            }
+        };
+        cut(&mut report.callback);
+        for field in [&mut report.module, &mut report.message, &mut report.user_agent, &mut report.request_id].into_iter().flatten() {
+            cut(field);
+        }
+        if let Some(trap) = &mut report.trap {
+            cut(&mut trap.address);
+            cut(&mut trap.reason);
+        }
+        let mut reports = self.reports.lock().unwrap();
+        if reports.len() == CAPACITY {
+            reports.pop_front();
+        }
+        reports.push_back(StoredReport { received_at: SystemTime::now(), report });
+    }
+
+    /// The kept reports, newest first
+    pub fn recent(&self) -> Vec<StoredReport> {
+        self.reports.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+pub async fn report(req: HttpRequest, report: web::Json<ClientReport>, ctx: web::Data<ServerContext>) -> impl Responder {
+    if let Err(e) = ctx.csrf.verify(&req) {
+        return e.error_response();
+    }
+    let mut report = report.into_inner();
+    if report.user_agent.is_none() {
+        report.user_agent = req.headers().get(header::USER_AGENT).and_then(|agent| agent.to_str().ok()).map(str::to_string);
+    }
+    if report.request_id.is_none() {
+        report.request_id = crate::error::request_id();
+    }
+    match &report.trap {
+        Some(trap) => tracing::warn!(
+            callback = %report.callback,
+            code = trap.code,
+            address = %trap.address,
+            reason = %trap.reason,
+            failed_request = ?report.request_id,
+            "callback trapped in the browser"
+        ),
+        None => tracing::warn!(
+            callback = %report.callback,
+            message = ?report.message,
+            failed_request = ?report.request_id,
+            "client error"
+        ),
+    }
+    ctx.telemetry.record(report);
+    HttpResponse::NoContent().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_latest_reports() {
+        let telemetry = Telemetry::new();
+        for idx in 0..CAPACITY + 5 {
+            telemetry.record(ClientReport {
+                callback: format!("callback_{}", idx),
+                module: None,
+                trap: None,
+                message: Some("x".repeat(2 * MAX_FIELD_CHARS)),
+                user_agent: None,
+                request_id: None,
+            });
+        }
+        let recent = telemetry.recent();
+        assert_eq!(recent.len(), CAPACITY);
+        assert_eq!(recent[0].report.callback, format!("callback_{}", CAPACITY + 4));
+        assert_eq!(recent[CAPACITY - 1].report.callback, "callback_5");
+        assert_eq!(recent[0].report.message.as_ref().unwrap().len(), MAX_FIELD_CHARS);
+    }
+}
@@ -0,0 +1,123 @@
+// Template partials
+// HTML fragments in the `[templates] directory`, one `<name>.html` file per
+// partial, parsed into DOM nodes with `dom::parse` at startup. A partial
+// marks the places filled by the server with `<slot name="...">` elements;
+// a slot the renderer leaves unfilled shows its own children instead. Files
+// override the built-in partials of the same name. With hot reload enabled
+// the directory is watched and the partials are re-read on every change.
+
+use crate::dom::{self, DomNode};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+const BUILTIN_PARTIALS: &[(&str, &str)] = &[("app", include_str!("../templates/app.html"))];
+
+pub struct Templates {
+    directory: PathBuf,
+    partials: RwLock<BTreeMap<String, Vec<DomNode>>>,
+}
+
+impl Templates {
+    pub fn load(config: &crate::config::TemplatesConfig) -> Result<Self, String> {
+        let partials = read_partials(&config.directory)?;
+        Ok(Self { directory: config.directory.clone(), partials: RwLock::new(partials) })
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.partials.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Re-reads the directory. On an error the previous partials are kept.
+    pub fn reload(&self) -> Result<(), String> {
+        let partials = read_partials(&self.directory)?;
+        *self.partials.write().unwrap() = partials;
+        Ok(())
+    }
+
+    /// The partial with its slots replaced by the given nodes
+    pub fn render(&self, name: &str, slots: Vec<(&str, Vec<DomNode>)>) -> Result<Vec<DomNode>, String> {
+        let partials = self.partials.read().unwrap();
+        let partial = partials.get(name).ok_or_else(|| format!("No template partial named {}", name))?;
+        let slots: BTreeMap<&str, Vec<DomNode>> = slots.into_iter().collect();
+        Ok(fill(partial, &slots))
+    }
+}
+
+// The built-in partials, overridden by the `.html` files in `directory`
+fn read_partials(directory: &Path) -> Result<BTreeMap<String, Vec<DomNode>>, String> {
+    let mut partials = BTreeMap::new();
+    for (name, html) in BUILTIN_PARTIALS {
+        partials.insert(name.to_string(), dom::parse(html).map_err(|e| format!("Built-in partial {}: {}", name, e))?);
+    }
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return Ok(partials);
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(name) = partial_name(&path) else { continue };
+        let html = std::fs::read_to_string(&path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        let nodes = dom::parse(&html).map_err(|e| format!("{}: {}", path.display(), e))?;
+        partials.insert(name, nodes);
+    }
+    Ok(partials)
+}
+
+fn partial_name(path: &Path) -> Option<String> {
+    match path.extension()?.to_str()? {
+        "html" => Some(path.file_stem()?.to_str()?.to_string()),
+        _ => None,
+    }
+}
+
+fn fill(nodes: &[DomNode], slots: &BTreeMap<&str, Vec<DomNode>>) -> Vec<DomNode> {
+    nodes
+        .iter()
+        .flat_map(|node| match node {
+            DomNode::Element { tag, attrs, children } if tag == "slot" => {
+                let name = attrs.iter().find(|(key, _)| key == "name").map(|(_, name)| name.as_str());
+                match name.and_then(|name| slots.get(name)) {
+                    Some(filling) => filling.clone(),
+                    None => fill(children, slots),
+                }
+            }
+            DomNode::Element { tag, attrs, children } => vec![DomNode::Element {
+                tag: tag.clone(),
+                attrs: attrs.clone(),
+                children: fill(children, slots),
+            }],
+            other => vec![other.clone()],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dom::Dom;
+
+    #[test]
+    fn fills_slots_of_parsed_partials() {
+        let html = r#"<!-- card -->
+<div class="card" data-tr="title" hidden></div>
+<p>Tom &amp; Jerry: <slot name="body"><em>empty</em></slot> <b>x</b></p>
+<input value='a "b"'/><slot name="footer">fallback</slot>"#;
+        let partial = dom::parse(html).unwrap();
+        let mut partials = BTreeMap::new();
+        partials.insert("card".to_string(), partial);
+        let templates = Templates { directory: PathBuf::new(), partials: RwLock::new(partials) };
+
+        let nodes = templates.render("card", vec![("body", vec![DomNode::text("<3")])]).unwrap();
+        assert_eq!(
+            Dom { nodes }.to_html(),
+            r#"<div class="card" hidden="">title</div><p>Tom &amp; Jerry: &lt;3 <b>x</b></p><input value="a &quot;b&quot;">fallback"#
+        );
+        assert!(templates.render("missing", vec![]).is_err());
+        assert!(dom::parse("<p><b>unclosed</p>").is_err());
+        assert!(dom::parse("</p>").is_err());
+    }
+}
@@ -1,151 +1,579 @@
-use std::collections::HashMap;
-use wasm_encoder::{
-    CodeSection, ExportKind, ExportSection, Function, FunctionSection, Instruction, 
-    Module, TypeSection, ValType,
-};
+use crate::abi::{AbiManifest, CallSignature};
+use crate::budget::{LoweringSize, SizeBreakdown, SizeReport};
+use crate::callbacks::{CallbackEntry, ReturnType, Signature};
+use crate::config::{BinaryConfig, TranspilerConfig};
+use crate::mapping::InstructionMapping;
+use crate::meta::ModuleMeta;
+use crate::profile::Profile;
+use crate::progress::{Phase, Progress};
+use crate::signatures::{ResultType, Signatures};
+use crate::specialize::SpecializedModules;
+use crate::verify::VerifyLimits;
+use crate::transpiler_real::{FidelityReport, LoweredFunction, TranspileOptions, X64ToWasmTranspiler};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Instant, SystemTime};
 
 pub struct Transpiler {
-    wasm_cache: HashMap<String, Vec<u8>>,
+    // The binary containing the callbacks (the server's own executable by
+    // default), used to analyze the real x86-64 code
+    binary_path: PathBuf,
+    binary: RwLock<Option<X64ToWasmTranspiler>>,
+    // Namespace of a binary from the `[binaries]` config, None for the
+    // callback binary
+    namespace: Option<String>,
+    // Replaced when the config file changes, see `reconfigure`
+    settings: RwLock<Settings>,
+    source: Source,
+    // Registry signatures of the callbacks, for the `abi.rs` manifest
+    signatures: HashMap<String, Signature>,
+    symbols: Vec<String>,
+    wasm_cache: RwLock<HashMap<String, TranspiledModule>>,
+    failures: RwLock<HashMap<String, String>>,
+    last_reload: RwLock<Option<ReloadDelta>>,
+    // Modules with the globals of a session baked in, see `specialize.rs`
+    specialized: Mutex<SpecializedModules>,
+    // Where warm-ups and reloads report, see `progress.rs`
+    progress: OnceLock<Arc<Progress>>,
+}
+
+// The `[transpiler]` config and what it implies for every symbol
+struct Settings {
+    config: TranspilerConfig,
+    // Options of every callback and overridden symbol: the config merged
+    // with what the callback signature implies (a float result)
+    options: HashMap<String, TranspileOptions>,
+}
+
+// What the options of a symbol derive from besides the config
+enum Source {
+    // The registered callbacks, by their registry signature
+    Callbacks,
+    // A `[binaries]` binary, by the declared signatures
+    Namespace { load_bias: Option<u64>, signatures: Signatures },
+}
+
+#[derive(Clone)]
+pub struct TranspiledModule {
+    /// The `profile::Profile::Release` variant, which budgets apply to
+    pub wasm: Vec<u8>,
+    /// `wasm` with the debug sections of `profile.rs`
+    pub debug: Vec<u8>,
+    // Result of translating the actual machine code of the symbol
+    pub fidelity: Result<FidelityReport, String>,
+    pub transpiled_at: SystemTime,
+    /// `X64ToWasmTranspiler::code_hash` of the code it was translated from,
+    /// none if the binary could not be read
+    pub code_hash: Option<String>,
+}
+
+/// What a reload of the binary re-transpiled
+#[derive(Debug, Clone)]
+pub struct ReloadDelta {
+    pub at: SystemTime,
+    /// Symbols whose code changed, or that had no module
+    pub retranspiled: Vec<String>,
+    /// Symbols whose module was kept
+    pub unchanged: Vec<String>,
+}
+
+pub enum TranspileStatus {
+    Ready(TranspiledModule),
+    Failed(String),
+    Missing,
 }
 
 impl Transpiler {
-    pub fn new() -> Self {
-        let mut transpiler = Transpiler {
-            wasm_cache: HashMap::new(),
-        };
-        
-        transpiler.analyze_binary();
-        transpiler
+    /// The binary is read from `SELF_SERVE_BINARY`, or the current executable.
+    /// Nothing is transpiled before `warm_up`.
+    pub fn new<'a>(callbacks: impl IntoIterator<Item = &'a CallbackEntry>, config: &TranspilerConfig) -> Self {
+        let binary_path = std::env::var_os("SELF_SERVE_BINARY")
+            .map(PathBuf::from)
+            .or_else(|| std::env::current_exe().ok())
+            .unwrap_or_default();
+        let mut signatures = HashMap::new();
+        let symbols = callbacks
+            .into_iter()
+            .map(|callback| {
+                signatures.insert(callback.name.clone(), callback.signature.clone());
+                callback.name.clone()
+            })
+            .collect();
+        Self::with_binary(binary_path, None, Source::Callbacks, symbols, signatures, config)
     }
     
-    fn analyze_binary(&mut self) {
-        let callbacks = vec![
-            "increment_counter",
-            "decrement_counter", 
-            "reset_counter",
-        ];
-        
-        for callback in callbacks {
-            if let Some(wasm) = self.transpile_function(callback) {
-                self.wasm_cache.insert(callback.to_string(), wasm);
+    /// Translates the callbacks of a `[binaries.<namespace>]` binary, with
+    /// the declared signatures of `signatures`
+    pub fn for_namespace(namespace: &str, binary: &BinaryConfig, signatures: &Signatures, config: &TranspilerConfig) -> Self {
+        let source = Source::Namespace { load_bias: binary.load_bias, signatures: signatures.clone() };
+        Self::with_binary(binary.path.clone(), Some(namespace.to_string()), source, binary.callbacks.clone(), HashMap::new(), config)
+    }
+    
+    // `symbols` are the callbacks, `signatures` their registry signatures
+    // if they have one
+    fn with_binary(
+        binary_path: PathBuf,
+        namespace: Option<String>,
+        source: Source,
+        symbols: Vec<String>,
+        signatures: HashMap<String, Signature>,
+        config: &TranspilerConfig,
+    ) -> Self {
+        let settings = Self::settings(&source, &symbols, &signatures, config);
+        Transpiler {
+            binary: RwLock::new(Self::load_binary(&binary_path, &settings.config, &settings.options)),
+            binary_path,
+            namespace,
+            settings: RwLock::new(settings),
+            source,
+            signatures,
+            symbols,
+            wasm_cache: RwLock::new(HashMap::new()),
+            failures: RwLock::new(HashMap::new()),
+            last_reload: RwLock::new(None),
+            specialized: Mutex::new(SpecializedModules::new(
+                config.max_specialized_modules.unwrap_or(crate::specialize::DEFAULT_MAX_MODULES),
+            )),
+            progress: OnceLock::new(),
+        }
+    }
+    
+    fn load_binary(
+        path: &Path,
+        config: &TranspilerConfig,
+        options: &HashMap<String, TranspileOptions>,
+    ) -> Option<X64ToWasmTranspiler> {
+        match X64ToWasmTranspiler::open(&path.to_string_lossy(), config.analysis_cache.as_deref()) {
+            Ok(binary) => {
+                let mut binary = binary
+                    .with_backend(crate::backend::create(config.backend, config.defaults()))
+                    .with_load_bias(config.load_bias);
+                for (fn_name, options) in options.iter().filter(|(_, options)| **options != config.defaults()) {
+                    binary = binary.with_backend_for(fn_name, crate::backend::create(config.backend, options.clone()));
+                }
+                Some(binary)
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, path = %path.display(), "Could not read binary, skipping x86-64 analysis");
+                None
             }
         }
     }
     
-    fn transpile_function(&self, fn_name: &str) -> Option<Vec<u8>> {
-        println!("Transpiling function: {}", fn_name);
-        
-        // For this PoC, we're generating simple WASM modules that demonstrate
-        // the concept. In a real implementation, this would:
-        // 1. Use dladdr/dlsym to locate the function in the binary
-        // 2. Read the x86-64 assembly using object/iced-x86
-        // 3. Convert x86-64 instructions to WASM instructions
-        
-        let wasm_module = match fn_name {
-            "increment_counter" => self.generate_increment_wasm(),
-            "decrement_counter" => self.generate_decrement_wasm(),
-            "reset_counter" => self.generate_reset_wasm(),
-            _ => return None,
+    // Merges `config` with what the signatures of `symbols` imply
+    fn settings(
+        source: &Source,
+        symbols: &[String],
+        signatures: &HashMap<String, Signature>,
+        config: &TranspilerConfig,
+    ) -> Settings {
+        let config = match source {
+            Source::Callbacks => config.clone(),
+            Source::Namespace { load_bias, .. } => {
+                TranspilerConfig { load_bias: load_bias.unwrap_or(config.load_bias), ..config.clone() }
+            }
         };
-        
-        Some(wasm_module)
+        let mut options: HashMap<String, TranspileOptions> = config.functions
+            .keys()
+            .map(|fn_name| (fn_name.clone(), config.options(fn_name)))
+            .collect();
+        for name in symbols {
+            let symbol_options = match source {
+                Source::Callbacks => {
+                    let options = config.options(name);
+                    let signature = signatures.get(name);
+                    let float_result = signature.is_some_and(|signature| matches!(signature.returns, ReturnType::F64));
+                    let out_results = match (options.multi_value, signature) {
+                        (true, Some(signature)) => signature.outs(),
+                        _ => Vec::new(),
+                    };
+                    TranspileOptions { float_result, out_results, ..options }
+                }
+                Source::Namespace { signatures, .. } => {
+                    let signature = signatures.get(name).cloned();
+                    let float_result = signature.as_ref().is_some_and(|signature| signature.returns == ResultType::F64);
+                    TranspileOptions { float_result, signature, ..config.options(name) }
+                }
+            };
+            options.insert(name.clone(), symbol_options);
+        }
+        Settings { config, options }
     }
     
-    fn generate_increment_wasm(&self) -> Vec<u8> {
-        // Generate a WASM module that increments a counter
-        // This is a simplified version - real implementation would transpile actual x86-64
-        
-        let mut module = Module::new();
-        
-        // Type section: (i32) -> i32
-        let mut types = TypeSection::new();
-        types.function(vec![ValType::I32], vec![ValType::I32]);
-        module.section(&types);
-        
-        // Function section
-        let mut functions = FunctionSection::new();
-        functions.function(0); // Use type 0
-        module.section(&functions);
-        
-        // Export section
-        let mut exports = ExportSection::new();
-        exports.export("increment", ExportKind::Func, 0);
-        module.section(&exports);
-        
-        // Code section
-        let mut codes = CodeSection::new();
-        let mut func = Function::new(vec![]);
-        
-        // WASM code: (local.get 0) (i32.const 1) (i32.add)
-        func.instruction(&Instruction::LocalGet(0));
-        func.instruction(&Instruction::I32Const(1));
-        func.instruction(&Instruction::I32Add);
-        func.instruction(&Instruction::End);
-        
-        codes.function(&func);
-        module.section(&codes);
-        
-        module.finish()
+    fn options(&self, fn_name: &str) -> TranspileOptions {
+        let settings = self.settings.read().unwrap();
+        settings.options.get(fn_name).cloned().unwrap_or_else(|| settings.config.defaults())
     }
     
-    fn generate_decrement_wasm(&self) -> Vec<u8> {
-        let mut module = Module::new();
-        
-        let mut types = TypeSection::new();
-        types.function(vec![ValType::I32], vec![ValType::I32]);
-        module.section(&types);
-        
-        let mut functions = FunctionSection::new();
-        functions.function(0);
-        module.section(&functions);
-        
-        let mut exports = ExportSection::new();
-        exports.export("decrement", ExportKind::Func, 0);
-        module.section(&exports);
-        
-        let mut codes = CodeSection::new();
-        let mut func = Function::new(vec![]);
-        
-        func.instruction(&Instruction::LocalGet(0));
-        func.instruction(&Instruction::I32Const(1));
-        func.instruction(&Instruction::I32Sub);
-        func.instruction(&Instruction::End);
-        
-        codes.function(&func);
-        module.section(&codes);
+    // The `abi.rs` manifest of `wasm`, the module of `fn_name`
+    fn abi(&self, fn_name: &str, options: &TranspileOptions, wasm: &[u8]) -> AbiManifest {
+        let signature = match self.signatures.get(fn_name) {
+            Some(signature) => Some(CallSignature::Registered(signature.clone())),
+            None => options.signature.clone().map(CallSignature::Declared),
+        };
+        AbiManifest::new(signature, options, wasm)
+    }
+    
+    /// The variant `/wasm` serves when the request names none
+    pub fn default_profile(&self) -> Profile {
+        self.settings.read().unwrap().config.profile.unwrap_or_else(Profile::for_build)
+    }
+    
+    pub fn binary_path(&self) -> &Path {
+        &self.binary_path
+    }
+    
+    pub fn verify_limits(&self) -> VerifyLimits {
+        self.settings.read().unwrap().config.verify_limits.clone()
+    }
+    
+    /// The callbacks of the binary
+    pub fn symbols(&self) -> &[String] {
+        &self.symbols
+    }
+    
+    /// Reports every later warm-up and reload to `progress`
+    pub fn report_to(&self, progress: Arc<Progress>) {
+        let _ = self.progress.set(progress);
+    }
+    
+    /// Transpiles every symbol. Modules are missing until this ran, the
+    /// server runs it in the background.
+    pub fn warm_up(&self) {
+        let symbols: Vec<&String> = self.symbols.iter().collect();
+        self.transpile_all(Phase::Warmup, &symbols);
+    }
+    
+    // Transpiles `symbols` from scratch, reporting the progress
+    fn transpile_all(&self, phase: Phase, symbols: &[&String]) {
+        let progress = self.progress.get();
+        if let Some(progress) = progress {
+            progress.begin(phase, symbols.len());
+        }
+        for symbol in symbols {
+            let start = Instant::now();
+            if let Some(progress) = progress {
+                progress.started(self.namespace.as_deref(), symbol);
+            }
+            // The last good module keeps being served until its replacement
+            // is in; failures are recorded and logged by retranspile
+            let result = self.retranspile(symbol);
+            if let Some(progress) = progress {
+                progress.finished(self.namespace.as_deref(), symbol, &result, start.elapsed());
+            }
+        }
+        if let Some(progress) = progress {
+            progress.end();
+        }
+    }
+    
+    /// Re-reads the binary from disk and transpiles the symbols whose code
+    /// changed. The others keep their modules, including the binary hash in
+    /// their metadata, as the code they were translated from is the same.
+    pub fn reload_binary(&self) -> ReloadDelta {
+        tracing::info!(path = %self.binary_path.display(), "Binary changed, re-transpiling");
+        let settings = self.settings.read().unwrap();
+        *self.binary.write().unwrap() = Self::load_binary(&self.binary_path, &settings.config, &settings.options);
+        drop(settings);
         
-        module.finish()
+        let mut delta = ReloadDelta { at: SystemTime::now(), retranspiled: Vec::new(), unchanged: Vec::new() };
+        for symbol in &self.symbols {
+            let hash = self.binary.read().unwrap().as_ref().and_then(|binary| binary.code_hash(symbol).ok());
+            let cached = self.wasm_cache.read().unwrap().get(symbol).and_then(|module| module.code_hash.clone());
+            if hash.is_some() && hash == cached {
+                delta.unchanged.push(symbol.clone());
+                continue;
+            }
+            delta.retranspiled.push(symbol.clone());
+        }
+        let retranspiled: Vec<&String> = delta.retranspiled.iter().collect();
+        self.transpile_all(Phase::Reload, &retranspiled);
+        tracing::info!(retranspiled = ?delta.retranspiled, unchanged = delta.unchanged.len(), "Reloaded binary");
+        *self.last_reload.write().unwrap() = Some(delta.clone());
+        delta
+    }
+    
+    /// Applies a changed `[transpiler]` config and transpiles every symbol
+    /// again. Each keeps its module until the new one is in.
+    pub fn reconfigure(&self, config: &TranspilerConfig) {
+        tracing::info!(path = %self.binary_path.display(), "Config changed, re-transpiling");
+        let settings = Self::settings(&self.source, &self.symbols, &self.signatures, config);
+        *self.binary.write().unwrap() = Self::load_binary(&self.binary_path, &settings.config, &settings.options);
+        *self.settings.write().unwrap() = settings;
+        let symbols: Vec<&String> = self.symbols.iter().collect();
+        self.transpile_all(Phase::Reload, &symbols);
+    }
+    
+    /// What the last reload of the binary re-transpiled, none before the
+    /// first one
+    pub fn last_reload(&self) -> Option<ReloadDelta> {
+        self.last_reload.read().unwrap().clone()
     }
     
-    fn generate_reset_wasm(&self) -> Vec<u8> {
-        let mut module = Module::new();
+    /// Transpiles `fn_name` again and replaces the cached module.
+    pub fn retranspile(&self, fn_name: &str) -> Result<(), String> {
+        let backend = self.binary.read().unwrap().as_ref().map(|binary| binary.backend_name(fn_name));
+        let span = tracing::info_span!("transpile", symbol = fn_name, backend);
+        let _guard = span.enter();
+        let start = Instant::now();
         
-        let mut types = TypeSection::new();
-        types.function(vec![ValType::I32], vec![ValType::I32]);
-        module.section(&types);
+        // Lowered once: the module, the fidelity report and the instruction
+        // mapping all come from the same lowering
+        let options = self.options(fn_name);
+        let translated = match &*self.binary.read().unwrap() {
+            Some(binary) => binary.lower_function(fn_name).map_err(|e| e.to_string()).map(|lowered| {
+                let report = lowered.report.clone();
+                let (mapping, translated) = crate::mapping::map(lowered, |lowered| binary.emit_function(fn_name, lowered));
+                (translated, report, mapping, binary.code_hash(fn_name).ok())
+            }),
+            None => Err("Binary not available".to_string()),
+        };
+        let translated = translated.and_then(|(translated, report, mapping, code_hash)| {
+            let wasm = crate::profile::strip(&translated)?;
+            crate::budget::check(&wasm, &options)?;
+            // Served modules end in the `abi.rs` and `meta.rs` sections
+            let mapping = mapping.ok().filter(|_| wasm.starts_with(&translated));
+            Ok((wasm, report, mapping, code_hash))
+        });
+        let (mut wasm, report, mapping, code_hash) = match translated {
+            Ok(translated) => translated,
+            Err(error) => {
+                tracing::warn!(%error, "transpilation failed");
+                self.failures.write().unwrap().insert(fn_name.to_string(), error.clone());
+                return Err(error);
+            }
+        };
+        let mut debug = wasm.clone();
+        if let Err(error) = crate::profile::append_debug_sections(&mut debug, fn_name, mapping.as_deref()) {
+            tracing::warn!(%error, "could not add debug sections");
+        }
         
-        let mut functions = FunctionSection::new();
-        functions.function(0);
-        module.section(&functions);
+        let abi = self.abi(fn_name, &options, &wasm);
+        abi.append_to(&mut wasm);
+        abi.append_to(&mut debug);
+        let binary = self.binary.read().unwrap();
+        let meta = ModuleMeta::new(fn_name, self.namespace.as_deref(), binary.as_ref().map(|binary| binary.binary_sha256()), options)
+            .with_fidelity(&report);
+        drop(binary);
+        meta.append_to(&mut wasm);
+        meta.append_to(&mut debug);
         
-        let mut exports = ExportSection::new();
-        exports.export("reset", ExportKind::Func, 0);
-        module.section(&exports);
+        tracing::info!(
+            module_bytes = wasm.len(),
+            instructions = report.instructions,
+            ir_bytes_before = report.unoptimized.bytes,
+            ir_bytes_after = report.optimized.bytes,
+            duration_us = start.elapsed().as_micros() as u64,
+            "transpiled"
+        );
         
-        let mut codes = CodeSection::new();
-        let mut func = Function::new(vec![]);
+        self.failures.write().unwrap().remove(fn_name);
+        self.specialized.lock().unwrap().remove_function(fn_name);
+        self.wasm_cache.write().unwrap().insert(fn_name.to_string(), TranspiledModule {
+            wasm,
+            debug,
+            fidelity: Ok(report),
+            transpiled_at: SystemTime::now(),
+            code_hash,
+        });
         
-        func.instruction(&Instruction::I32Const(0));
-        func.instruction(&Instruction::End);
+        Ok(())
+    }
+    
+    /// Whether a session can fix the global `name`
+    pub fn is_session_global(&self, name: &str) -> bool {
+        self.settings.read().unwrap().config.functions.values().any(|overrides| overrides.session_globals.iter().any(|global| global == name))
+    }
+    
+    /// The release module of `fn_name` with the `session` values of its
+    /// `session_globals` baked in, none if the session fixed none of them
+    pub fn get_specialized_wasm(&self, fn_name: &str, session: &BTreeMap<String, i64>) -> Option<Result<Vec<u8>, String>> {
+        let globals = self.settings.read().unwrap().config.functions.get(fn_name)?.session_globals.clone();
+        let values: BTreeMap<String, i64> = session
+            .iter()
+            .filter(|(name, _)| globals.contains(name))
+            .map(|(name, value)| (name.clone(), *value))
+            .collect();
+        if values.is_empty() {
+            return None;
+        }
+        let key = crate::specialize::key(&values);
+        if let Some(wasm) = self.specialized.lock().unwrap().get(fn_name, &key) {
+            return Some(Ok(wasm));
+        }
         
-        codes.function(&func);
-        module.section(&codes);
+        let options = self.options(fn_name);
+        let binary = self.binary.read().unwrap();
+        let wasm = match &*binary {
+            Some(binary) => binary.transpile_specialized(fn_name, &values).map_err(|e| e.to_string()),
+            None => Err("Binary not available".to_string()),
+        };
+        let mut wasm = match wasm
+            .and_then(|wasm| crate::profile::strip(&wasm))
+            .and_then(|wasm| crate::budget::check(&wasm, &options).map(|_| wasm))
+        {
+            Ok(wasm) => wasm,
+            Err(error) => {
+                tracing::warn!(symbol = fn_name, %error, "specialization failed");
+                return Some(Err(error));
+            }
+        };
+        self.abi(fn_name, &options, &wasm).append_to(&mut wasm);
+        let meta = ModuleMeta::new(fn_name, self.namespace.as_deref(), binary.as_ref().map(|binary| binary.binary_sha256()), options);
+        ModuleMeta { fixed: values, ..meta }.append_to(&mut wasm);
+        drop(binary);
+        self.specialized.lock().unwrap().insert(fn_name, &key, wasm.clone());
+        Some(Ok(wasm))
+    }
+    
+    /// The cached specialization of `fn_name` with the content hash `hash`
+    pub fn find_specialized(&self, fn_name: &str, hash: &str) -> Option<Vec<u8>> {
+        self.specialized.lock().unwrap().find(fn_name, |wasm| crate::manifest::content_hash(wasm) == hash)
+    }
+    
+    /// Translates the real machine code of `fn_name`, keeping the
+    /// per-instruction mapping for the debugging views.
+    pub fn lower(&self, fn_name: &str) -> Result<LoweredFunction, String> {
+        let binary = self.binary.read().unwrap();
+        let binary = binary.as_ref().ok_or("Binary not available")?;
+        binary.lower_function(fn_name).map_err(|e| e.to_string())
+    }
+    
+    /// The module emitted from `lower`, without the sections served modules
+    /// end in
+    pub fn lowered_module(&self, fn_name: &str) -> Result<Vec<u8>, String> {
+        let binary = self.binary.read().unwrap();
+        let binary = binary.as_ref().ok_or("Binary not available")?;
+        let lowered = binary.lower_function(fn_name).map_err(|e| e.to_string())?;
+        Ok(binary.emit_function(fn_name, lowered))
+    }
+    
+    /// The per-instruction mapping of `lower`, with the offsets of the WASM
+    /// instructions in the module emitted from it.
+    pub fn mapping(&self, fn_name: &str) -> Result<Vec<InstructionMapping>, String> {
+        self.mapped_module(fn_name).map(|(mapping, _)| mapping)
+    }
+    
+    fn mapped_module(&self, fn_name: &str) -> Result<(Vec<InstructionMapping>, Vec<u8>), String> {
+        let binary = self.binary.read().unwrap();
+        let binary = binary.as_ref().ok_or("Binary not available")?;
+        let lowered = binary.lower_function(fn_name).map_err(|e| e.to_string())?;
+        let (mapping, wasm) = crate::mapping::map(lowered, |lowered| binary.emit_function(fn_name, lowered));
+        Ok((mapping?, wasm))
+    }
+    
+    /// WAT text of the served module of `fn_name`. When that module is the
+    /// translation of the machine code, each instruction group is preceded
+    /// by a comment naming the source instruction it was lowered from.
+    pub fn wat(&self, fn_name: &str) -> Option<Result<String, String>> {
+        let wasm = self.get_wasm_for_function(fn_name)?;
+        Some(match self.mapped_module(fn_name) {
+            // Served modules end in the `abi.rs` and `meta.rs` sections
+            Ok((mapping, translated)) if wasm.starts_with(&translated) => crate::mapping::annotated_wat(&wasm, &mapping),
+            _ => wasmprinter::print_bytes(&wasm).map_err(|e| format!("Invalid module: {}", e)),
+        })
+    }
+    
+    /// The shared runtime module the modules of the binary import their
+    /// memory and allocator from, none if no symbol has `shared_runtime`
+    pub fn runtime(&self) -> Option<Result<Vec<u8>, String>> {
+        let settings = self.settings.read().unwrap();
+        if !settings.config.shared_runtime && !settings.options.values().any(|options| options.shared_runtime) {
+            return None;
+        }
+        drop(settings);
+        let binary = self.binary.read().unwrap();
+        let Some(binary) = binary.as_ref() else { return Some(Err("Binary not available".to_string())) };
+        Some(binary.arch().map(|arch| crate::runtime::module(arch.word() != crate::ir::Ty::I32)).map_err(|e| e.to_string()))
+    }
+    
+    /// Translates the real machine code of `fn_name` to the JavaScript
+    /// fallback for browsers without the required WASM features.
+    pub fn javascript(&self, fn_name: &str) -> Result<String, String> {
+        let binary = self.binary.read().unwrap();
+        let binary = binary.as_ref().ok_or("Binary not available")?;
+        let function = binary.decode_function(fn_name).map_err(|e| e.to_string())?;
+        crate::jsgen::generate(&function, self.options(fn_name))
+    }
+    
+    /// Decodes `fn_name` without lifting it and reports which mnemonics and
+    /// operand forms it needs, and whether the front-end supports them.
+    /// Works for any symbol in the binary, not only registered callbacks.
+    pub fn probe(&self, fn_name: &str) -> Result<crate::coverage::Probe, String> {
+        let binary = self.binary.read().unwrap();
+        let binary = binary.as_ref().ok_or("Binary not available")?;
+        let function = binary.decode_function(fn_name).map_err(|e| e.to_string())?;
+        Ok(crate::coverage::probe(&function))
+    }
+    
+    /// Probes every function of the binary, see `coverage::profile`
+    pub fn instruction_profile(&self) -> Result<crate::coverage::InstructionProfile, String> {
+        let binary = self.binary.read().unwrap();
+        let binary = binary.as_ref().ok_or("Binary not available")?;
+        let functions = binary.decode_functions().map_err(|e| e.to_string())?;
+        Ok(crate::coverage::profile(functions))
+    }
+    
+    /// Size breakdown of the served module, before its `abi.rs` and
+    /// `meta.rs` sections, and of the lowering of the real machine code,
+    /// regardless of whether they are within budget.
+    pub fn size_report(&self, fn_name: &str) -> SizeReport {
+        let binary = self.binary.read().unwrap();
+        let translated = match &*binary {
+            Some(binary) => binary.transpile_function_with_report(fn_name).map_err(|e| e.to_string()),
+            None => Err("Binary not available".to_string()),
+        };
+        // Both from the same lowering
+        let module = translated.as_ref()
+            .map_err(Clone::clone)
+            .and_then(|(wasm, _)| crate::profile::strip(wasm))
+            .and_then(|wasm| SizeBreakdown::of(&wasm));
+        let lowering = translated.and_then(|(wasm, report)| Ok(LoweringSize {
+            module: SizeBreakdown::of(&wasm)?,
+            unoptimized: report.unoptimized,
+            optimized: report.optimized,
+        }));
         
-        module.finish()
+        SizeReport {
+            callback: fn_name.to_string(),
+            max_module_bytes: self.options(fn_name).max_module_bytes,
+            module: module.into(),
+            lowering: lowering.into(),
+        }
+    }
+    
+    pub fn status(&self, fn_name: &str) -> TranspileStatus {
+        if let Some(module) = self.wasm_cache.read().unwrap().get(fn_name) {
+            return TranspileStatus::Ready(module.clone());
+        }
+        match self.failures.read().unwrap().get(fn_name) {
+            Some(error) => TranspileStatus::Failed(error.clone()),
+            None => TranspileStatus::Missing,
+        }
     }
     
+    /// The release module of `fn_name`, or of another symbol of the same
+    /// code (an alias, or a function folded with it by the linker)
     pub fn get_wasm_for_function(&self, fn_name: &str) -> Option<Vec<u8>> {
-        self.wasm_cache.get(fn_name).cloned()
+        self.get_wasm_with_profile(fn_name, Profile::Release)
+    }
+    
+    /// The `profile` variant of the module of `fn_name`
+    pub fn get_wasm_with_profile(&self, fn_name: &str, profile: Profile) -> Option<Vec<u8>> {
+        let cache = self.wasm_cache.read().unwrap();
+        let cached = cache
+            .get(fn_name)
+            .or_else(|| {
+                let aliases = self.binary.read().unwrap().as_ref().map(|binary| binary.aliases(fn_name))?;
+                aliases.iter().find_map(|alias| cache.get(alias))
+            })
+            .map(|module| match profile {
+                Profile::Debug => module.debug.clone(),
+                Profile::Release => module.wasm.clone(),
+            });
+        tracing::debug!(symbol = fn_name, cache_hit = cached.is_some(), "wasm lookup");
+        cached
     }
 }
 
@@ -196,3 +624,69 @@ mod x64_analysis {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[no_mangle]
+    extern "C" fn reload_probe(x: i64) -> i64 {
+        x * 3 + 1
+    }
+
+    #[test]
+    fn reload_keeps_functions_whose_code_is_unchanged() {
+        // Keeps the probe from being stripped out of the test binary
+        assert_eq!(std::hint::black_box(reload_probe as extern "C" fn(i64) -> i64)(2), 7);
+        let binary = BinaryConfig {
+            path: std::env::current_exe().unwrap(),
+            callbacks: vec!["reload_probe".to_string(), "reload_missing".to_string()],
+            load_bias: None,
+            signatures: None,
+        };
+        let transpiler = Transpiler::for_namespace("test", &binary, &Signatures::default(), &TranspilerConfig::default());
+        let progress = Arc::new(Progress::new());
+        transpiler.report_to(progress.clone());
+        transpiler.warm_up();
+        let TranspileStatus::Ready(before) = transpiler.status("reload_probe") else { panic!("reload_probe was not transpiled") };
+        assert!(before.code_hash.is_some());
+        let mapping = crate::profile::MAPPING_SECTION.as_bytes();
+        assert!(before.debug.windows(mapping.len()).any(|window| window == mapping));
+        assert!(transpiler.last_reload().is_none());
+
+        let delta = transpiler.reload_binary();
+        assert_eq!(delta.unchanged, vec!["reload_probe"]);
+        assert_eq!(delta.retranspiled, vec!["reload_missing"]);
+        let TranspileStatus::Ready(after) = transpiler.status("reload_probe") else { panic!("reload_probe was dropped") };
+        assert_eq!(after.transpiled_at, before.transpiled_at);
+        // Only the missing symbol was transpiled again
+        let run = progress.run();
+        assert_eq!((run.phase, run.total, run.finished, run.failed, run.running), (Phase::Reload, 1, 1, 1, 0));
+        assert_eq!(transpiler.last_reload().unwrap().unchanged, delta.unchanged);
+    }
+
+    #[test]
+    fn failed_reconfigure_keeps_the_last_module() {
+        assert_eq!(std::hint::black_box(reload_probe as extern "C" fn(i64) -> i64)(2), 7);
+        let binary = BinaryConfig {
+            path: std::env::current_exe().unwrap(),
+            callbacks: vec!["reload_probe".to_string()],
+            load_bias: None,
+            signatures: None,
+        };
+        let transpiler = Transpiler::for_namespace("test", &binary, &Signatures::default(), &TranspilerConfig::default());
+        transpiler.warm_up();
+        let TranspileStatus::Ready(before) = transpiler.status("reload_probe") else { panic!("reload_probe was not transpiled") };
+
+        // No module fits in a byte
+        transpiler.reconfigure(&TranspilerConfig { max_module_bytes: Some(1), ..TranspilerConfig::default() });
+        let TranspileStatus::Ready(after) = transpiler.status("reload_probe") else { panic!("reload_probe was dropped") };
+        assert_eq!(after.transpiled_at, before.transpiled_at);
+        assert!(transpiler.failures.read().unwrap().contains_key("reload_probe"));
+
+        transpiler.reconfigure(&TranspilerConfig::default());
+        let TranspileStatus::Ready(after) = transpiler.status("reload_probe") else { panic!("reload_probe was dropped") };
+        assert!(after.transpiled_at > before.transpiled_at);
+        assert!(transpiler.failures.read().unwrap().is_empty());
+    }
+}
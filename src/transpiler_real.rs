@@ -1,451 +1,1671 @@
 // Practical x86-64 to WASM Transpiler
-// Handles simple C callbacks with jumps and function calls
+// Handles simple C callbacks with jumps and function calls. AArch64 objects
+// go through the same pipeline, see `arch.rs`.
 
-use iced_x86::{Decoder, DecoderOptions, Instruction, Mnemonic, OpKind, Register, Code};
-use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+use crate::alloc;
+use crate::analysis_cache::{self, AnalysisIndex, FunctionEntry};
+use crate::callbacks::ElementType;
+use crate::arch::{Arch, MachineInstr};
+use crate::cpuid::{self, CpuidLeaf};
+use crate::embed;
+use crate::inline;
+use crate::multivalue;
+use crate::runtime;
+use crate::encode;
+use crate::symbols;
+use object::read::archive::ArchiveFile;
+use object::{
+    elf, BinaryFormat, Object, ObjectKind, ObjectSection, ObjectSymbol, ObjectSymbolTable, RelocationFlags, RelocationKind,
+    RelocationTarget, SectionKind, SymbolIndex, SymbolKind,
+};
+use crate::backend::{DataBlock, FunctionIR, TranspilerBackend};
+use crate::ir::{self, BinOp, Op, Ty, VReg, Value};
+use crate::signatures::DeclaredSignature;
+use crate::syscalls::{Syscall, SyscallImport, SyscallMode, SYSCALL_TRAP_BASE};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use wasm_encoder::{
-    BlockType, CodeSection, ConstExpr, ExportKind, ExportSection, Function, 
-    FunctionSection, Instruction as WasmInstr, MemArg, Module, TypeSection, ValType,
+    CodeSection, ConstExpr, DataSection, EntityType, ExportKind, ExportSection, FunctionSection,
+    GlobalSection, ImportSection, Instruction as WasmInstr, MemArg, MemorySection, Module, TypeSection, ValType,
 };
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Range;
+use std::path::Path;
+
+/// Each function symbol with its decoding, see `decode_functions`
+pub type DecodedFunctions<'a> = Box<dyn Iterator<Item = (String, Result<FunctionIR, String>)> + 'a>;
 
 pub struct X64ToWasmTranspiler {
     binary_data: Vec<u8>,
+    // Hex SHA-256 of `binary_data`, recorded in the module metadata
+    binary_sha256: String,
+    // Function symbols of a linked binary, see `symbols.rs`. Empty for
+    // archives and object files, which linking has not folded.
+    symbols: symbols::SymbolIndex,
+    backend: Box<dyn TranspilerBackend>,
+    // Backends of symbols with their own TranspileOptions
+    overrides: HashMap<String, Box<dyn TranspilerBackend>>,
+    // Address the binary is loaded at, see `FunctionIR::load_bias`
+    load_bias: u64,
+    // Code hashes of the function bodies by address, empty without an
+    // analysis cache, see `analysis_cache.rs`
+    code_hashes: HashMap<u64, String>,
+}
+
+// How translated loads and stores reach the application state
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StateAccess {
+    // Plain i64.load/i64.store, the state image lives in linear memory
+    #[default]
+    Memory,
+    // Calls to env.read_i64(offset) / env.write_i64(offset, value), backed by
+    // the JS glue. Pointers are treated as offsets into the state.
+    Imports,
+    // Pointer arguments that are only used as load/store bases become
+    // externref parameters, their field accesses calls to generated
+    // env.get_<ty>_<offset>(handle) / env.set_<ty>_<offset>(handle, value)
+    // imports. Other accesses (stack spills) stay in linear memory.
+    Handles,
+}
+
+// Whether the IR optimization passes run before lowering
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OptLevel {
+    // Lower the lifted IR as is, one WASM sequence per machine instruction
+    None,
+    // SSA construction, copy/constant propagation and dead code elimination
+    #[default]
+    Full,
+}
+
+// What becomes of instructions the front-end cannot lift
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrapStrategy {
+    // Drop them; the fidelity report lists them
+    #[default]
+    Skip,
+    // Emit `unreachable` in their place, so reaching one traps instead of
+    // computing a wrong result
+    Unreachable,
+    // Fail the translation
+    Reject,
+}
+
+// Per-function lowering options, see `config::TranspilerConfig::options`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TranspileOptions {
+    pub state_access: StateAccess,
+    pub optimization: OptLevel,
+    pub traps: TrapStrategy,
+    // Size limit of the generated module, see `budget.rs`
+    pub max_module_bytes: Option<usize>,
+    // Functions with more instructions are not translated, see `budget.rs`
+    pub max_instructions: Option<usize>,
+    // The function returns a double in xmm0, taken from the callback
+    // signature rather than the config, see `ir::apply_float_abi`
+    pub float_result: bool,
+    // Parameters and result declared in a signatures file, see
+    // `ir::apply_signature`
+    pub signature: Option<DeclaredSignature>,
+    // Call an imported env.trap_reason(code) before the `unreachable` of a
+    // trap instruction, see `ir::TrapKind::code`
+    pub trap_reason: bool,
+    // Call an imported env.on_trap(code, addr_low, addr_high) with the
+    // address of the source instruction instead of env.trap_reason, also
+    // before the traps of TrapStrategy::Unreachable
+    pub on_trap: bool,
+    // Host functions recognized syscalls are mapped to, see `syscalls.rs`
+    pub syscalls: SyscallMode,
+    // Timestamp counter frequency rdtsc ticks are scaled to from the
+    // nanoseconds of env.now_ns, one tick per nanosecond if unset
+    pub tsc_mhz: Option<u32>,
+    // cpuid results over the built-in table, see `cpuid.rs`
+    pub cpuid: Vec<CpuidLeaf>,
+    // Fail the translation when an embedded constant would read back a
+    // different value than in the binary, see `embed::audit`
+    pub audit_data: bool,
+    // Link a bump allocator into modules with a linear memory, see
+    // `alloc.rs`
+    pub allocator: bool,
+    // Export a wrapper returning `out_results` as extra results, see
+    // `multivalue.rs`
+    pub multi_value: bool,
+    // Scalar out-parameters of the callback, after its other parameters,
+    // taken from the callback signature rather than the config
+    pub out_results: Vec<ElementType>,
+    // Keep registers only 32-bit instructions write in i32 locals, see
+    // `ir::infer_widths`
+    pub infer_widths: bool,
+    // Inline direct callees that lift to at most this many ops, none if 0,
+    // see `inline.rs`
+    pub inline_threshold: usize,
+    // Import the memory and allocator from the shared runtime module
+    // instead of defining them, see `runtime.rs`
+    pub shared_runtime: bool,
+}
+
+const WASM_PAGE: u64 = 64 * 1024;
+
+// Function indices of the state accessors in StateAccess::Imports mode
+const READ_STATE_FN: u32 = 0;
+const WRITE_STATE_FN: u32 = 1;
+
+// A field read or write through a handle, imported in StateAccess::Handles
+// mode. Imports are numbered in order of first use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Accessor {
+    pub write: bool,
+    pub offset: i64,
+    pub ty: ValType,
+}
+
+impl Accessor {
+    /// Import name, e.g. `get_i32_0` or `set_i64_8`
+    pub fn name(&self) -> String {
+        let ty = match self.ty {
+            ValType::I32 => "i32",
+            ValType::F64 => "f64",
+            _ => "i64",
+        };
+        format!("{}_{}_{}", if self.write { "set" } else { "get" }, ty, self.offset)
+    }
+}
+
+// How faithfully a function was translated
+#[derive(Debug, Clone, Default)]
+pub struct FidelityReport {
+    pub instructions: usize,
+    // Instructions that were dropped because no translation exists
+    pub unsupported: Vec<(u64, String)>,
+    // Instructions that were translated in a simplified way (calls, stack ops, jumps)
+    pub approximated: Vec<(u64, String)>,
+    // Emitted code before and after the IR optimization passes
+    pub unoptimized: ModuleSize,
+    pub optimized: ModuleSize,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ModuleSize {
+    pub wasm_instructions: usize,
+    pub bytes: usize,
+}
+
+impl FidelityReport {
+    pub fn translated(&self) -> usize {
+        self.instructions - self.unsupported.len() - self.approximated.len()
+    }
+    
+    pub fn is_exact(&self) -> bool {
+        self.unsupported.is_empty() && self.approximated.is_empty()
+    }
+}
+
+// The WASM instructions emitted for a single machine instruction, as
+// ranges into the buffers of its function, see `LoweredFunction::wasm`
+pub struct InstructionLowering {
+    pub instr: MachineInstr,
+    pub bytes: Range<usize>,
+    pub wasm: Range<usize>,
+}
+
+// A function after translation, before module generation. The lowerings
+// index two buffers instead of owning their bytes and instructions, which
+// saves two allocations per machine instruction.
+pub struct LoweredFunction {
+    pub arch: Arch,
+    pub entry: u64,
+    pub lowerings: Vec<InstructionLowering>,
+    /// Machine code of the function
+    pub code: Vec<u8>,
+    /// The function body, the instructions of every lowering in order
+    pub body: Vec<WasmInstr<'static>>,
+    pub report: FidelityReport,
+    pub params: Vec<ValType>,
+    pub result: ValType,
+    pub locals: Vec<(u32, ValType)>,
+    /// Field accessors imported in StateAccess::Handles mode
+    pub accessors: Vec<Accessor>,
+    /// Function index of the env.trap_reason import, if there is one
+    pub trap_reason: Option<u32>,
+    /// The trap import is env.on_trap, which also takes the address
+    pub on_trap: bool,
+    /// Syscall imports with their function indices, after env.trap_reason
+    pub syscalls: Vec<(u32, SyscallImport)>,
+    /// Function index of the env.now_ns import read by rdtsc, the last one
+    pub now_ns: Option<u32>,
+    /// The module defines a linear memory, see `LinearMemory`
+    pub memory: Option<LinearMemory>,
+}
+
+// Memory of a module that loads or stores outside of imports and handles,
+// with the constants it reads embedded as data segments, see `embed.rs`
+#[derive(Debug, Clone)]
+pub struct LinearMemory {
+    /// Addresses are i64, as the registers of 64-bit architectures
+    pub memory64: bool,
+    pub segments: Vec<(u64, Vec<u8>)>,
+    /// The module exports the allocator of `alloc.rs`
+    pub allocator: bool,
+}
+
+impl LoweredFunction {
+    pub fn bytes(&self, lowering: &InstructionLowering) -> &[u8] {
+        &self.code[lowering.bytes.clone()]
+    }
+
+    pub fn wasm(&self, lowering: &InstructionLowering) -> &[WasmInstr<'static>] {
+        &self.body[lowering.wasm.clone()]
+    }
+
+    /// WAT text of an instruction of this function, naming accessor calls
+    pub fn wat_text(&self, instr: &WasmInstr) -> String {
+        match instr {
+            WasmInstr::Call(idx) if Some(*idx) == self.trap_reason => match self.on_trap {
+                true => "call $env.on_trap".to_string(),
+                false => "call $env.trap_reason".to_string(),
+            },
+            WasmInstr::Call(idx) if Some(*idx) == self.now_ns => "call $env.now_ns".to_string(),
+            WasmInstr::Call(idx) if self.syscalls.iter().any(|(index, _)| index == idx) => {
+                let (_, import) = self.syscalls.iter().find(|(index, _)| index == idx).unwrap();
+                format!("call ${}.{}", import.module, import.name)
+            }
+            WasmInstr::Call(idx) if !self.accessors.is_empty() => match self.accessors.get(*idx as usize) {
+                Some(accessor) => format!("call $env.{}", accessor.name()),
+                None => format!("call {}", idx),
+            },
+            other => wat_text(other),
+        }
+    }
 }
 
 impl X64ToWasmTranspiler {
     pub fn new(binary_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::open(binary_path, None)
+    }
+    
+    /// Reads the symbol index and code hashes from `analysis_cache` if it
+    /// holds them for this binary, and stores them there otherwise
+    pub fn open(binary_path: &str, analysis_cache: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
         let binary_data = std::fs::read(binary_path)?;
-        Ok(Self { binary_data })
+        let binary_sha256: String = Sha256::digest(&binary_data).iter().map(|byte| format!("{:02x}", byte)).collect();
+        let cached = analysis_cache.and_then(|directory| analysis_cache::load(directory, &binary_sha256));
+        let symbols = match (&cached, object::File::parse(&*binary_data)) {
+            (Some(_), _) => symbols::SymbolIndex::default(),
+            (None, Ok(obj)) if obj.kind() != ObjectKind::Relocatable => symbols::SymbolIndex::new(&obj),
+            _ => symbols::SymbolIndex::default(),
+        };
+        let mut transpiler = Self {
+            symbols,
+            binary_sha256,
+            binary_data,
+            backend: Box::new(DirectBackend::default()),
+            overrides: HashMap::new(),
+            load_bias: 0,
+            code_hashes: HashMap::new(),
+        };
+        let Some(directory) = analysis_cache else { return Ok(transpiler) };
+        let index = match cached {
+            Some(index) => index,
+            None => {
+                let index = transpiler.analysis_index();
+                if let Err(e) = analysis_cache::store(directory, &transpiler.binary_sha256, &index) {
+                    tracing::warn!(error = %e, directory = %directory.display(), "Could not store the analysis cache");
+                }
+                index
+            }
+        };
+        transpiler.code_hashes = index.functions.into_values().map(|function| (function.addr, function.code_hash)).collect();
+        transpiler.symbols = index.symbols;
+        Ok(transpiler)
+    }
+    
+    // The symbol index, taken out of the transpiler, with the address, size
+    // and code hash of every function body. Bodies of archives and object
+    // files are not indexed, as they are linked on decoding.
+    fn analysis_index(&mut self) -> AnalysisIndex {
+        let mut functions = BTreeMap::new();
+        if let Ok(obj) = object::File::parse(&*self.binary_data) {
+            for name in self.symbols.functions() {
+                let Ok((_, code, entry)) = self.extract_function_code(&obj, &self.symbols, &name) else { continue };
+                let function = FunctionEntry { addr: entry, size: code.len() as u64, code_hash: Self::hash_code(entry, code) };
+                functions.insert(name, function);
+            }
+        }
+        AnalysisIndex { symbols: std::mem::take(&mut self.symbols), functions }
+    }
+    
+    /// Architecture of the binary, or of the first object of an archive
+    pub fn arch(&self) -> Result<Arch, Box<dyn std::error::Error>> {
+        if !self.binary_data.starts_with(&object::archive::MAGIC) {
+            return Ok(Arch::from_object(object::File::parse(&*self.binary_data)?.architecture())?);
+        }
+        let archive = ArchiveFile::parse(&*self.binary_data)?;
+        for member in archive.members() {
+            let Ok(obj) = object::File::parse(member?.data(&*self.binary_data)?) else { continue };
+            return Ok(Arch::from_object(obj.architecture())?);
+        }
+        Err("No object in the archive".into())
+    }
+    
+    pub fn binary_sha256(&self) -> &str {
+        &self.binary_sha256
+    }
+    
+    /// Hex SHA-256 of the machine code of `fn_name` and the address it is
+    /// linked at, which its translation depends on apart from the data it
+    /// reads
+    pub fn code_hash(&self, fn_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let cached = self.symbols.resolve(fn_name).ok().and_then(|(addr, _)| self.code_hashes.get(&addr));
+        if let Some(hash) = cached {
+            return Ok(hash.clone());
+        }
+        let function = self.decode_function(fn_name)?;
+        Ok(Self::hash_code(function.entry, &function.code))
+    }
+    
+    fn hash_code(entry: u64, code: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(entry.to_le_bytes());
+        hasher.update(code);
+        hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+    
+    /// Other symbols of the binary naming the code of `fn_name`
+    pub fn aliases(&self, fn_name: &str) -> Vec<String> {
+        self.symbols.aliases(fn_name)
+    }
+    
+    pub fn with_load_bias(mut self, load_bias: u64) -> Self {
+        self.load_bias = load_bias;
+        self
+    }
+    
+    pub fn with_backend(mut self, backend: Box<dyn TranspilerBackend>) -> Self {
+        self.backend = backend;
+        self
+    }
+    
+    /// Uses `backend` instead of the default one for `fn_name`
+    pub fn with_backend_for(mut self, fn_name: &str, backend: Box<dyn TranspilerBackend>) -> Self {
+        self.overrides.insert(fn_name.to_string(), backend);
+        self
+    }
+    
+    fn backend(&self, fn_name: &str) -> &dyn TranspilerBackend {
+        self.overrides.get(fn_name).unwrap_or(&self.backend).as_ref()
+    }
+    
+    /// The name of the backend translating `fn_name`
+    pub fn backend_name(&self, fn_name: &str) -> &'static str {
+        self.backend(fn_name).name()
     }
     
     pub fn transpile_function(&self, fn_name: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        // Step 1: Find function in binary
-        let (code, entry_addr) = self.extract_function_code(fn_name)?;
-        
-        // Step 2: Disassemble x86-64
-        let instructions = self.disassemble(code, entry_addr)?;
-        
-        // Step 3: Build control flow graph
-        let cfg = ControlFlowGraph::from_instructions(&instructions, entry_addr);
-        
-        // Step 4: Allocate registers to WASM locals
-        let mut allocator = RegisterAllocator::new();
-        
-        // Step 5: Translate to WASM
-        let wasm_body = self.translate_to_wasm(&instructions, &cfg, &mut allocator)?;
-        
-        // Step 6: Generate WASM module
-        Ok(self.generate_wasm_module(wasm_body, allocator))
+        self.backend(fn_name).transpile(&self.decode_function(fn_name)?)
+    }
+    
+    pub fn transpile_function_with_report(
+        &self,
+        fn_name: &str,
+    ) -> Result<(Vec<u8>, FidelityReport), Box<dyn std::error::Error>> {
+        let lowered = self.lower_function(fn_name)?;
+        let report = lowered.report.clone();
+        Ok((self.backend(fn_name).emit(lowered), report))
     }
     
-    fn extract_function_code(&self, fn_name: &str) -> Result<(&[u8], u64), Box<dyn std::error::Error>> {
+    /// Translates `fn_name` with the globals named in `values` holding the
+    /// given integers instead of their initial values, see `specialize.rs`
+    pub fn transpile_specialized(&self, fn_name: &str, values: &BTreeMap<String, i64>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut function = self.decode_function(fn_name)?;
+        for (name, value) in values {
+            let (addr, size) = self.data_symbol(name)?;
+            function.fix(addr.wrapping_add(self.load_bias), size, *value).map_err(|e| format!("{}: {}", name, e))?;
+        }
+        self.backend(fn_name).transpile(&function)
+    }
+    
+    /// Link-time address and size of the global `name`
+    fn data_symbol(&self, name: &str) -> Result<(u64, u64), Box<dyn std::error::Error>> {
         let obj = object::File::parse(&*self.binary_data)?;
-        
-        // Find symbol
-        let mut target_addr = None;
-        let mut target_size = None;
-        
-        for symbol in obj.symbols() {
-            if symbol.kind() == SymbolKind::Text && symbol.name().ok() == Some(fn_name) {
-                target_addr = Some(symbol.address());
-                target_size = Some(symbol.size());
-                break;
+        if obj.kind() == ObjectKind::Relocatable {
+            return Err("Globals can only be fixed in linked binaries".into());
+        }
+        obj.symbols()
+            .find(|symbol| {
+                symbol.kind() == SymbolKind::Data && symbol.is_definition() && symbol.name().is_ok_and(|symbol| Self::is_named(&obj, symbol, name))
+            })
+            .map(|symbol| (symbol.address(), symbol.size()))
+            .ok_or_else(|| format!("No global named {}", name).into())
+    }
+    
+    /// Generates the module of a function lowered by `lower_function`
+    pub fn emit_function(&self, fn_name: &str, lowered: LoweredFunction) -> Vec<u8> {
+        self.backend(fn_name).emit(lowered)
+    }
+    
+    /// Runs the pipeline up to module generation, keeping the WASM
+    /// instructions of every source instruction separate.
+    pub fn lower_function(&self, fn_name: &str) -> Result<LoweredFunction, Box<dyn std::error::Error>> {
+        self.backend(fn_name).lower(&self.decode_function(fn_name)?)
+    }
+    
+    /// Decodes every function symbol, one per body of a linked binary, for
+    /// analyses of the whole text section. Linked binaries are parsed once;
+    /// relocatable objects and archives go through `decode_function`.
+    pub fn decode_functions(&self) -> Result<DecodedFunctions<'_>, Box<dyn std::error::Error>> {
+        let defined = |obj: &object::File| -> Vec<String> {
+            obj.symbols()
+                .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.is_definition())
+                .filter_map(|symbol| symbol.name().ok())
+                .map(|name| match obj.format() {
+                    BinaryFormat::MachO => name.strip_prefix('_').unwrap_or(name).to_string(),
+                    _ => name.to_string(),
+                })
+                .collect()
+        };
+        let names = if !self.binary_data.starts_with(&object::archive::MAGIC) {
+            let obj = object::File::parse(&*self.binary_data)?;
+            if obj.kind() != ObjectKind::Relocatable {
+                return Ok(Box::new(self.symbols.functions().into_iter().map(move |name| {
+                    let function = self.extract_function_code(&obj, &self.symbols, &name).and_then(|(arch, code, entry)| {
+                        let function = FunctionIR::decode(&name, arch, code, entry);
+                        function.validate()?;
+                        Ok(function)
+                    });
+                    let function = function.map_err(|e| e.to_string());
+                    (name, function)
+                })));
+            }
+            defined(&obj)
+        } else {
+            let archive = ArchiveFile::parse(&*self.binary_data)?;
+            let mut names = Vec::new();
+            for member in archive.members() {
+                let Ok(obj) = object::File::parse(member?.data(&*self.binary_data)?) else { continue };
+                for name in defined(&obj) {
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
             }
+            names
+        };
+        Ok(Box::new(names.into_iter().map(|name| {
+            let function = self.decode_function(&name).map_err(|e| e.to_string());
+            (name, function)
+        })))
+    }
+    
+    /// Locates, disassembles and splits `fn_name` into basic blocks, the
+    /// backend-independent part of the pipeline.
+    /// Static archives are searched member by member.
+    pub fn decode_function(&self, fn_name: &str) -> Result<FunctionIR, Box<dyn std::error::Error>> {
+        if !self.binary_data.starts_with(&object::archive::MAGIC) {
+            return self.decode_from(&object::File::parse(&*self.binary_data)?, fn_name);
         }
+        let archive = ArchiveFile::parse(&*self.binary_data)?;
+        for member in archive.members() {
+            // Members that are not objects (symbol tables, sources) are skipped
+            let Ok(obj) = object::File::parse(member?.data(&*self.binary_data)?) else { continue };
+            if obj.symbols().any(|symbol| symbol.is_definition() && symbol.name().is_ok_and(|name| Self::is_named(&obj, name, fn_name))) {
+                return self.decode_from(&obj, fn_name);
+            }
+        }
+        Err("Function not found in any archive member".into())
+    }
+    
+    fn decode_from(&self, obj: &object::File, fn_name: &str) -> Result<FunctionIR, Box<dyn std::error::Error>> {
+        let (linked, symbols) = if obj.kind() == ObjectKind::Relocatable {
+            (self.link_function(obj, fn_name)?, None)
+        } else {
+            // The index of the binary, unless `obj` is a member of an archive
+            let symbols = match self.binary_data.starts_with(&object::archive::MAGIC) {
+                false => Cow::Borrowed(&self.symbols),
+                true => Cow::Owned(symbols::SymbolIndex::new(obj)),
+            };
+            let (arch, code, entry) = self.extract_function_code(obj, &symbols, fn_name)?;
+            let data = Self::data_blocks(obj, |section| Some(section.address()));
+            (LinkedCode { arch, code: Cow::Borrowed(code), entry, relocations: self.relocations(obj), data }, Some(symbols))
+        };
+        let mut function = FunctionIR::decode(fn_name, linked.arch, &linked.code, linked.entry);
+        function.validate()?;
+        function.load_bias = self.load_bias;
+        function.relocations = linked.relocations;
+        function.data = linked.data
+            .into_iter()
+            .map(|block| DataBlock { addr: block.addr.wrapping_add(self.load_bias), ..block })
+            .collect();
+        if let Some(symbols) = &symbols {
+            function.callees = self.callees(obj, symbols, &function);
+        }
+        Ok(function)
+    }
+    
+    // The functions `function` calls directly that are small enough to be
+    // inlined, see `inline.rs`
+    fn callees(&self, obj: &object::File, symbols: &symbols::SymbolIndex, function: &FunctionIR) -> HashMap<u64, FunctionIR> {
+        let targets: BTreeSet<u64> = function.instructions.iter().filter_map(|info| info.instr.call_target()).collect();
+        targets
+            .into_iter()
+            .filter_map(|target| {
+                let name = symbols.at(target)?;
+                let (arch, code, entry) = self.extract_function_code(obj, symbols, &name).ok()?;
+                let mut callee = FunctionIR::decode(&name, arch, code, entry);
+                // Same binary, so RIP-relative operands resolve the same way
+                callee.load_bias = function.load_bias;
+                callee.relocations = function.relocations.clone();
+                (code.len() <= inline::MAX_CALLEE_BYTES && callee.validate().is_ok()).then_some((entry, callee))
+            })
+            .collect()
+    }
+
+    // Initialized data sections, at the address `address_of` lays them out at
+    fn data_blocks(obj: &object::File, address_of: impl Fn(&object::Section) -> Option<u64>) -> Vec<DataBlock> {
+        obj.sections()
+            .filter(|section| {
+                matches!(
+                    section.kind(),
+                    SectionKind::Data | SectionKind::ReadOnlyData | SectionKind::ReadOnlyDataWithRel | SectionKind::ReadOnlyString
+                )
+            })
+            .filter_map(|section| {
+                Some(DataBlock { addr: address_of(&section)?, bytes: section.data().ok()?.to_vec(), big_endian: !obj.is_little_endian() })
+            })
+            .collect()
+    }
+    
+    // Mach-O prefixes C symbols with an underscore
+    fn is_named(obj: &object::File, name: &str, fn_name: &str) -> bool {
+        name == fn_name || (obj.format() == BinaryFormat::MachO && name.strip_prefix('_') == Some(fn_name))
+    }
+    
+    fn extract_function_code<'data>(
+        &self,
+        obj: &object::File<'data>,
+        symbols: &symbols::SymbolIndex,
+        fn_name: &str,
+    ) -> Result<(Arch, &'data [u8], u64), Box<dyn std::error::Error>> {
+        let arch = Arch::from_object(obj.architecture())?;
+        // Stripped shared libraries only have the dynamic symbol table, DLLs
+        // only their exports
+        let (addr, size) = symbols.resolve(fn_name)?;
         
-        let addr = target_addr.ok_or("Function not found")?;
-        let size = target_size.ok_or("Function size unknown")?;
-        
-        // Extract code from .text section
+        // Extract code from the text section (.text, __text)
         for section in obj.sections() {
-            if section.name() == Ok(".text") {
+            if section.kind() == SectionKind::Text {
                 let section_addr = section.address();
-                let section_data = section.data().ok_or("No section data")?;
+                let section_data = section.data()?;
+                let section_end = section_addr + section_data.len() as u64;
+                if addr < section_addr || addr >= section_end {
+                    continue;
+                }
+                let offset = (addr - section_addr) as usize;
                 
-                if addr >= section_addr && addr + size <= section_addr + section_data.len() as u64 {
-                    let offset = (addr - section_addr) as usize;
-                    return Ok((&section_data[offset..offset + size as usize], addr));
+                let size = match size {
+                    0 => {
+                        let symbols = obj.symbols().chain(obj.dynamic_symbols()).map(|symbol| symbol.address());
+                        Self::infer_size(arch, section_data, section_addr, addr, symbols)
+                    }
+                    size => size,
+                };
+                if addr + size <= section_end {
+                    return Ok((arch, &section_data[offset..offset + size as usize], addr));
                 }
             }
         }
         
-        Err("Function code not found in .text section".into())
+        Err("Function code not found in a text section".into())
     }
     
-    fn disassemble(&self, code: &[u8], rip: u64) -> Result<Vec<InstructionInfo>, Box<dyn std::error::Error>> {
-        let mut decoder = Decoder::with_ip(64, code, rip, DecoderOptions::NONE);
-        let mut instructions = Vec::new();
-        
-        while decoder.can_decode() {
-            let instr = decoder.decode();
-            instructions.push(InstructionInfo {
-                addr: instr.ip(),
-                instr,
-            });
-        }
-        
-        Ok(instructions)
+    // Symbols without a size (hand-written assembly, some toolchains) end
+    // at the next symbol, or earlier where every path from the entry has
+    // returned or jumped away
+    fn infer_size(arch: Arch, section_data: &[u8], section_addr: u64, addr: u64, symbols: impl Iterator<Item = u64>) -> u64 {
+        let section_end = section_addr + section_data.len() as u64;
+        let next = symbols.filter(|&other| other > addr && other < section_end).min().unwrap_or(section_end);
+        let code = &section_data[(addr - section_addr) as usize..(next - section_addr) as usize];
+        arch.reachable_len(code, addr) as u64
     }
     
-    fn translate_to_wasm(
+    // Object files, alone or as archive members, are not linked yet: all
+    // their sections start at 0 and references between them are
+    // relocations. The sections are laid out one after the other from
+    // 0x1000, the way a linker would, and the relocations inside the
+    // function are applied to a copy of its code. GOT-relative references
+    // get slots in a GOT after the sections, resolved like the GOT of a
+    // shared library. References to undefined symbols stay unresolved.
+    fn link_function<'data>(
         &self,
-        instructions: &[InstructionInfo],
-        cfg: &ControlFlowGraph,
-        allocator: &mut RegisterAllocator,
-    ) -> Result<Vec<WasmInstr>, Box<dyn std::error::Error>> {
-        let mut wasm = Vec::new();
-        let mut label_map = HashMap::new();
+        obj: &object::File<'data>,
+        fn_name: &str,
+    ) -> Result<LinkedCode<'data>, Box<dyn std::error::Error>> {
+        let arch = Arch::from_object(obj.architecture())?;
+        let symbol = obj.symbols()
+            .find(|symbol| {
+                symbol.kind() == SymbolKind::Text
+                    && symbol.is_definition()
+                    && symbol.name().is_ok_and(|name| Self::is_named(obj, name, fn_name))
+            })
+            .ok_or("Function not found")?;
+        let section_index = symbol.section_index().ok_or("Function not found in a section")?;
         
-        // First pass: create label mapping
-        for (idx, info) in instructions.iter().enumerate() {
-            label_map.insert(info.addr, idx);
+        let mut layout = HashMap::new();
+        let mut end: u64 = 0x1000;
+        for section in obj.sections() {
+            // Only sections that are loaded take up space
+            let loaded = matches!(
+                section.kind(),
+                SectionKind::Text | SectionKind::Data | SectionKind::ReadOnlyData | SectionKind::ReadOnlyDataWithRel
+                    | SectionKind::ReadOnlyString | SectionKind::UninitializedData
+            );
+            if !loaded {
+                continue;
+            }
+            end = end.next_multiple_of(section.align().max(1));
+            layout.insert(section.index(), end);
+            end += section.size();
         }
+        let address_of = |index: SymbolIndex| {
+            let symbol = obj.symbol_by_index(index).ok()?;
+            Some(layout.get(&symbol.section_index()?)? + symbol.address())
+        };
         
-        // Second pass: translate instructions
-        let blocks = cfg.structure_control_flow(&label_map);
+        let section = obj.section_by_index(section_index)?;
+        let section_data = section.data()?;
+        let start = symbol.address();
+        let size = match symbol.size() {
+            0 => {
+                let symbols = obj.symbols()
+                    .filter(|other| other.section_index() == Some(section_index))
+                    .map(|other| other.address());
+                Self::infer_size(arch, section_data, 0, start, symbols)
+            }
+            size => size,
+        };
+        let mut code = section_data
+            .get(start as usize..(start + size) as usize)
+            .ok_or("Function code not found in its section")?
+            .to_vec();
+        let base = layout[&section_index];
         
-        for block in blocks {
-            wasm.extend(self.translate_block(&block, instructions, allocator, &label_map)?);
+        let mut got: HashMap<u64, u64> = HashMap::new();
+        let got_start = end.next_multiple_of(8);
+        for (offset, relocation) in section.relocations() {
+            let Some(at) = offset.checked_sub(start).filter(|&at| at < size).map(|at| at as usize) else { continue };
+            let gotpcrelx = matches!(
+                relocation.flags(),
+                RelocationFlags::Elf { r_type: elf::R_X86_64_GOTPCRELX | elf::R_X86_64_REX_GOTPCRELX }
+            );
+            let (kind, bytes) = match relocation.kind() {
+                _ if gotpcrelx => (RelocationKind::GotRelative, 4),
+                kind => (kind, relocation.size() as usize / 8),
+            };
+            let Some(place) = code.get_mut(at..at + bytes).filter(|place| matches!(place.len(), 4 | 8)) else { continue };
+            let target = match relocation.target() {
+                RelocationTarget::Symbol(index) => address_of(index),
+                RelocationTarget::Section(index) => layout.get(&index).copied(),
+                _ => None,
+            };
+            let Some(target) = target else { continue };
+            // Mach-O and REL formats keep the addend in the patched bytes
+            let addend = match (relocation.has_implicit_addend(), bytes) {
+                (false, _) => relocation.addend(),
+                (true, 4) => i32::from_le_bytes(place[..4].try_into().unwrap()) as i64,
+                (true, _) => i64::from_le_bytes(place[..8].try_into().unwrap()),
+            };
+            let pc = base + offset;
+            let value = match kind {
+                RelocationKind::Absolute => target.wrapping_add(addend as u64).wrapping_add(self.load_bias),
+                RelocationKind::Relative | RelocationKind::PltRelative => target.wrapping_add(addend as u64).wrapping_sub(pc),
+                RelocationKind::GotRelative => {
+                    let slots = got.len() as u64;
+                    let slot = *got.entry(target).or_insert(got_start + slots * 8);
+                    slot.wrapping_add(addend as u64).wrapping_sub(pc)
+                }
+                _ => continue,
+            };
+            place.copy_from_slice(&value.to_le_bytes()[..bytes]);
+        }
+        let relocations = got.into_iter().map(|(target, slot)| (slot, target.wrapping_add(self.load_bias))).collect();
+        let data = Self::data_blocks(obj, |section| layout.get(&section.index()).copied());
+        Ok(LinkedCode { arch, code: Cow::Owned(code), entry: base + start, relocations, data })
+    }
+    
+    // Pointers the ELF dynamic loader writes, by slot address: GOT entries
+    // of symbols defined in the binary itself, and load-relative pointers.
+    // Slots of undefined symbols are resolved at runtime by other objects
+    // and stay unknown.
+    fn relocations(&self, obj: &object::File) -> HashMap<u64, u64> {
+        let Some(relocations) = obj.dynamic_relocations() else { return HashMap::new() };
+        let symbols = obj.dynamic_symbol_table();
+        relocations
+            .filter_map(|(slot, relocation)| {
+                let RelocationFlags::Elf { r_type } = relocation.flags() else { return None };
+                let base = match (r_type, relocation.target()) {
+                    (elf::R_X86_64_RELATIVE | elf::R_AARCH64_RELATIVE, _) => 0,
+                    (
+                        elf::R_X86_64_GLOB_DAT | elf::R_X86_64_JUMP_SLOT | elf::R_X86_64_64
+                        | elf::R_AARCH64_GLOB_DAT | elf::R_AARCH64_JUMP_SLOT | elf::R_AARCH64_ABS64,
+                        RelocationTarget::Symbol(index),
+                    ) => {
+                        let symbol = symbols.as_ref()?.symbol_by_index(index).ok()?;
+                        if symbol.is_undefined() {
+                            return None;
+                        }
+                        symbol.address()
+                    }
+                    _ => return None,
+                };
+                let target = base.wrapping_add(relocation.addend() as u64).wrapping_add(self.load_bias);
+                Some((slot, target))
+            })
+            .collect()
+    }
+}
+
+// Code of a function at the address it runs at
+struct LinkedCode<'data> {
+    arch: Arch,
+    code: Cow<'data, [u8]>,
+    entry: u64,
+    // See `FunctionIR::relocations`
+    relocations: HashMap<u64, u64>,
+    // See `FunctionIR::data`, before the load bias
+    data: Vec<DataBlock>,
+}
+
+// Imports of a generated module besides the state accessors
+struct ModuleImports<'a> {
+    accessors: &'a [Accessor],
+    trap_reason: bool,
+    syscalls: &'a [(u32, SyscallImport)],
+    now_ns: bool,
+}
+
+// Lifts to the IR, optimizes it and lowers every op to WASM instructions
+// operating on one local per virtual register, typed after the register
+#[derive(Default)]
+pub struct DirectBackend {
+    options: TranspileOptions,
+}
+
+impl DirectBackend {
+    pub fn new(options: TranspileOptions) -> Self {
+        Self { options }
+    }
+    
+    fn val_type(function: &ir::Function, ty: Ty) -> ValType {
+        match ty {
+            Ty::I64 => ValType::I64,
+            Ty::I32 => ValType::I32,
+            // Flags are kept in locals of the register width
+            Ty::Flags => Self::val_type(function, function.word),
+            Ty::Handle => ValType::EXTERNREF,
+            Ty::F64 => ValType::F64,
         }
-        
-        Ok(wasm)
     }
     
-    fn translate_block(
+    fn is_handle(function: &ir::Function, value: Value) -> bool {
+        matches!(value, Value::Reg(reg) if function.vregs[reg.0 as usize] == Ty::Handle)
+    }
+    
+    // Field accessors of the handle parameters, in order of first use
+    fn accessors(function: &ir::Function) -> Vec<Accessor> {
+        let mut accessors = Vec::new();
+        for inst in function.insts() {
+            let accessor = match inst.op {
+                Op::Load { dst, base, offset } if Self::is_handle(function, base) => Accessor {
+                    write: false,
+                    offset,
+                    ty: Self::value_type(function, Value::Reg(dst)),
+                },
+                Op::Store { base, offset, value } if Self::is_handle(function, base) => Accessor {
+                    write: true,
+                    offset,
+                    ty: Self::value_type(function, value),
+                },
+                _ => continue,
+            };
+            if !accessors.contains(&accessor) {
+                accessors.push(accessor);
+            }
+        }
+        accessors
+    }
+    
+    // Number of state and field accessor imports, which come first
+    fn accessor_imports(&self, function: &ir::Function) -> u32 {
+        let state_imports = if self.options.state_access == StateAccess::Imports { 2 } else { 0 };
+        state_imports + Self::accessors(function).len() as u32
+    }
+    
+    // Imported after the state or field accessors, if enabled and needed by
+    // a trap instruction or a syscall without a host function. env.on_trap
+    // takes the place of env.trap_reason, and is also needed by the traps
+    // of TrapStrategy::Unreachable.
+    fn trap_reason_index(&self, function: &ir::Function) -> Option<u32> {
+        let traps = function.insts().any(|inst| match inst.op {
+            Op::Trap { .. } => true,
+            Op::Syscall { number, .. } => self.syscall_import(function, number).is_none(),
+            _ => false,
+        });
+        let unsupported = self.options.on_trap && self.options.traps == TrapStrategy::Unreachable && !function.unsupported.is_empty();
+        ((self.options.trap_reason || self.options.on_trap) && (traps || unsupported)).then(|| self.accessor_imports(function))
+    }
+    
+    // Calls the trap import with the code on the stack, after pushing the
+    // halves of the source address for env.on_trap
+    fn call_trap_import(&self, index: u32, addr: u64, wasm: &mut Vec<WasmInstr<'static>>) {
+        if self.options.on_trap {
+            wasm.push(WasmInstr::I32Const(addr as u32 as i32));
+            wasm.push(WasmInstr::I32Const((addr >> 32) as u32 as i32));
+        }
+        wasm.push(WasmInstr::Call(index));
+    }
+    
+    // The syscall and the host function implementing it. The number is only
+    // known once constant propagation put it into the op.
+    fn syscall_import(&self, function: &ir::Function, number: Value) -> Option<(Syscall, SyscallImport)> {
+        let Value::Const(number) = number else { return None };
+        let syscall = Syscall::from_number(function.arch, number)?;
+        let import = SyscallImport::new(syscall, self.options.syscalls, Self::val_type(function, function.word))?;
+        Some((syscall, import))
+    }
+    
+    // Syscall imports in order of first use, after env.trap_reason
+    fn syscall_imports(&self, function: &ir::Function) -> Vec<(u32, SyscallImport)> {
+        let first = self.accessor_imports(function) + self.trap_reason_index(function).is_some() as u32;
+        let mut imports: Vec<SyscallImport> = Vec::new();
+        for inst in function.insts() {
+            if let Op::Syscall { number, .. } = inst.op {
+                if let Some((_, import)) = self.syscall_import(function, number) {
+                    if !imports.contains(&import) {
+                        imports.push(import);
+                    }
+                }
+            }
+        }
+        imports.into_iter().enumerate().map(|(idx, import)| (first + idx as u32, import)).collect()
+    }
+    
+    // Imported after everything else if the function reads the timestamp
+    // counter
+    fn now_ns_index(&self, function: &ir::Function) -> Option<u32> {
+        function.insts().any(|inst| matches!(inst.op, Op::Timestamp { .. })).then(|| {
+            self.accessor_imports(function)
+                + self.trap_reason_index(function).is_some() as u32
+                + self.syscall_imports(function).len() as u32
+        })
+    }
+    
+    // A memory for loads and stores that go neither through the state
+    // imports nor through handles, with the data segments they read
+    fn memory(&self, lifted: &ir::Function, function: &FunctionIR) -> Result<Option<LinearMemory>, String> {
+        let accesses_memory = lifted.insts().any(|inst| match inst.op {
+            Op::Load { base, .. } | Op::Store { base, .. } => !Self::is_handle(lifted, base),
+            _ => false,
+        });
+        // The allocator needs a memory even if the code itself has none
+        if self.options.state_access == StateAccess::Imports || !(accesses_memory || self.options.allocator) {
+            return Ok(None);
+        }
+        let accesses = embed::accesses(lifted, function);
+        let segments = embed::segments(&accesses, &function.data);
+        if self.options.audit_data {
+            let problems = embed::audit(&accesses, &function.data, &segments);
+            if !problems.is_empty() {
+                return Err(format!("Embedded data does not match the binary: {}", problems.join(", ")));
+            }
+        }
+        Ok(Some(LinearMemory { memory64: lifted.word != Ty::I32, segments, allocator: self.options.allocator }))
+    }
+    
+    fn accessor_index(function: &ir::Function, accessor: Accessor) -> u32 {
+        Self::accessors(function).iter().position(|a| *a == accessor).unwrap_or_default() as u32
+    }
+    
+    fn value_type(function: &ir::Function, value: Value) -> ValType {
+        match value {
+            Value::Reg(reg) => Self::val_type(function, function.vregs[reg.0 as usize]),
+            Value::Const(_) => Self::val_type(function, function.word),
+        }
+    }
+    
+    // WASM parameters come first in the local index space, the locals of
+    // the remaining virtual registers follow
+    fn local(function: &ir::Function, reg: VReg) -> u32 {
+        match function.params.iter().position(|param| *param == reg) {
+            Some(idx) => idx as u32,
+            None => function.params.len() as u32 + reg.0,
+        }
+    }
+    
+    fn params(function: &ir::Function) -> Vec<ValType> {
+        function.params.iter().map(|param| Self::value_type(function, Value::Reg(*param))).collect()
+    }
+    
+    fn locals(function: &ir::Function) -> Vec<(u32, ValType)> {
+        // One entry per run of equally typed registers
+        let mut locals: Vec<(u32, ValType)> = Vec::new();
+        for ty in &function.vregs {
+            let ty = Self::val_type(function, *ty);
+            match locals.last_mut() {
+                Some((count, last)) if *last == ty => *count += 1,
+                _ => locals.push((1, ty)),
+            }
+        }
+        locals
+    }
+    
+    fn module_size(&self, function: &ir::Function) -> ModuleSize {
+        let mut body = Vec::new();
+        for inst in function.insts() {
+            self.lower_op(function, inst.addr, &inst.op, &mut body);
+        }
+        ModuleSize {
+            wasm_instructions: body.len(),
+            bytes: self.generate_wasm_module(
+                body,
+                Self::params(function),
+                Self::val_type(function, function.result),
+                Self::locals(function),
+                ModuleImports {
+                    accessors: &Self::accessors(function),
+                    trap_reason: self.trap_reason_index(function).is_some(),
+                    syscalls: &self.syscall_imports(function),
+                    now_ns: self.now_ns_index(function).is_some(),
+                },
+                None,
+            ).len(),
+        }
+    }
+    
+    // Registers of the other integer width are converted, see
+    // `ir::infer_widths`
+    fn value(&self, function: &ir::Function, value: Value, ty: ValType, wasm: &mut Vec<WasmInstr<'static>>) {
+        wasm.push(match value {
+            Value::Reg(reg) => WasmInstr::LocalGet(Self::local(function, reg)),
+            Value::Const(constant) if ty == ValType::I32 => WasmInstr::I32Const(constant as i32),
+            Value::Const(constant) if ty == ValType::F64 => WasmInstr::F64Const(f64::from_bits(constant as u64)),
+            Value::Const(constant) => WasmInstr::I64Const(constant),
+        });
+        match (Self::value_type(function, value), ty) {
+            (ValType::I32, ValType::I64) if matches!(value, Value::Reg(_)) => wasm.push(WasmInstr::I64ExtendI32U),
+            (ValType::I64, ValType::I32) if matches!(value, Value::Reg(_)) => wasm.push(WasmInstr::I32WrapI64),
+            _ => {}
+        }
+    }
+    
+    // Pushes the effective address of an access of type `ty`; non-negative
+    // offsets are folded into the memory argument in Memory mode. The state
+    // accessors of Imports mode always take an i64 offset.
+    fn address(
         &self,
-        block: &BasicBlock,
-        instructions: &[InstructionInfo],
-        allocator: &mut RegisterAllocator,
-        label_map: &HashMap<u64, usize>,
-    ) -> Result<Vec<WasmInstr>, Box<dyn std::error::Error>> {
-        let mut wasm = Vec::new();
-        
-        for &instr_idx in &block.instruction_indices {
-            let info = &instructions[instr_idx];
-            wasm.extend(self.translate_instruction(&info.instr, allocator, label_map)?);
+        function: &ir::Function,
+        base: Value,
+        offset: i64,
+        ty: ValType,
+        wasm: &mut Vec<WasmInstr<'static>>,
+    ) -> MemArg {
+        let word = Self::val_type(function, function.word);
+        let align = if ty == ValType::I32 { 2 } else { 3 };
+        self.value(function, base, word, wasm);
+        if offset < 0 || self.options.state_access == StateAccess::Imports {
+            self.value(function, Value::Const(offset), word, wasm);
+            wasm.push(if word == ValType::I32 { WasmInstr::I32Add } else { WasmInstr::I64Add });
+            if self.options.state_access == StateAccess::Imports && word == ValType::I32 {
+                wasm.push(WasmInstr::I64ExtendI32U);
+            }
+            return MemArg { offset: 0, align, memory_index: 0 };
+        }
+        MemArg {
+            offset: offset as u64,
+            align,
+            memory_index: 0,
+        }
+    }
+    
+    // Pushes a word truncated to i32, as linear memory addresses and 32-bit
+    // inputs are
+    fn wrapped(&self, function: &ir::Function, value: Value, wasm: &mut Vec<WasmInstr<'static>>) {
+        let word = Self::val_type(function, function.word);
+        self.value(function, value, word, wasm);
+        if word == ValType::I64 {
+            wasm.push(WasmInstr::I32WrapI64);
         }
-        
-        Ok(wasm)
     }
     
-    fn translate_instruction(
+    // Turns the i32 errno on the stack into the negated word a Linux
+    // syscall returns, given a zero word pushed before the call
+    fn negate_errno(function: &ir::Function, wasm: &mut Vec<WasmInstr<'static>>) {
+        match Self::val_type(function, function.word) {
+            ValType::I32 => wasm.push(WasmInstr::I32Sub),
+            _ => wasm.extend([WasmInstr::I64ExtendI32S, WasmInstr::I64Sub]),
+        }
+    }
+    
+    // Calls the host function of a recognized syscall. Unrecognized ones and
+    // ones without a WASI equivalent trap, reporting the syscall number.
+    fn lower_syscall(
         &self,
-        instr: &Instruction,
-        allocator: &mut RegisterAllocator,
-        label_map: &HashMap<u64, usize>,
-    ) -> Result<Vec<WasmInstr>, Box<dyn std::error::Error>> {
-        let mut wasm = Vec::new();
-        
-        match instr.mnemonic() {
-            // MOV instructions
-            Mnemonic::Mov => {
-                match (instr.op0_kind(), instr.op1_kind()) {
-                    (OpKind::Register, OpKind::Register) => {
-                        let dst = allocator.get_or_allocate(instr.op0_register());
-                        let src = allocator.get_or_allocate(instr.op1_register());
-                        wasm.push(WasmInstr::LocalGet(src));
-                        wasm.push(WasmInstr::LocalSet(dst));
+        function: &ir::Function,
+        addr: u64,
+        dst: VReg,
+        number: Value,
+        args: &[Value; 6],
+        wasm: &mut Vec<WasmInstr<'static>>,
+    ) {
+        let word = Self::val_type(function, function.word);
+        let Some((syscall, import)) = self.syscall_import(function, number) else {
+            if let Some(trap_reason) = self.trap_reason_index(function) {
+                match number {
+                    Value::Const(number) => wasm.push(WasmInstr::I32Const(SYSCALL_TRAP_BASE + number as i32)),
+                    Value::Reg(_) => {
+                        wasm.push(WasmInstr::I32Const(SYSCALL_TRAP_BASE));
+                        self.wrapped(function, number, wasm);
+                        wasm.push(WasmInstr::I32Add);
                     }
-                    (OpKind::Register, OpKind::Immediate32) => {
-                        let dst = allocator.get_or_allocate(instr.op0_register());
-                        wasm.push(WasmInstr::I64Const(instr.immediate32() as i64));
-                        wasm.push(WasmInstr::LocalSet(dst));
-                    }
-                    (OpKind::Register, OpKind::Memory) => {
-                        // Load from memory
-                        let dst = allocator.get_or_allocate(instr.op0_register());
-                        let base = allocator.get_or_allocate(instr.memory_base());
-                        
-                        wasm.push(WasmInstr::LocalGet(base));
-                        wasm.push(WasmInstr::I64Load(MemArg {
-                            offset: instr.memory_displacement() as u64,
-                            align: 3, // 8-byte alignment for i64
-                            memory_index: 0,
-                        }));
-                        wasm.push(WasmInstr::LocalSet(dst));
-                    }
-                    (OpKind::Memory, OpKind::Register) => {
-                        // Store to memory
-                        let src = allocator.get_or_allocate(instr.op1_register());
-                        let base = allocator.get_or_allocate(instr.memory_base());
-                        
-                        wasm.push(WasmInstr::LocalGet(base));
-                        wasm.push(WasmInstr::LocalGet(src));
-                        wasm.push(WasmInstr::I64Store(MemArg {
-                            offset: instr.memory_displacement() as u64,
-                            align: 3,
-                            memory_index: 0,
-                        }));
-                    }
-                    _ => {}
                 }
+                self.call_trap_import(trap_reason, addr, wasm);
             }
-            
-            // Arithmetic
-            Mnemonic::Add => {
-                let dst = allocator.get_or_allocate(instr.op0_register());
-                
-                match instr.op1_kind() {
-                    OpKind::Register => {
-                        let src = allocator.get_or_allocate(instr.op1_register());
-                        wasm.push(WasmInstr::LocalGet(dst));
-                        wasm.push(WasmInstr::LocalGet(src));
-                        wasm.push(WasmInstr::I64Add);
-                        wasm.push(WasmInstr::LocalSet(dst));
-                    }
-                    OpKind::Immediate32 => {
-                        wasm.push(WasmInstr::LocalGet(dst));
-                        wasm.push(WasmInstr::I64Const(instr.immediate32() as i64));
-                        wasm.push(WasmInstr::I64Add);
-                        wasm.push(WasmInstr::LocalSet(dst));
-                    }
-                    _ => {}
+            wasm.push(WasmInstr::Unreachable);
+            return;
+        };
+        let call = WasmInstr::Call(self.syscall_imports(function)
+            .into_iter()
+            .find(|(_, imported)| *imported == import)
+            .map(|(idx, _)| idx)
+            .unwrap_or_default());
+        let zero = |wasm: &mut Vec<WasmInstr<'static>>| self.value(function, Value::Const(0), word, wasm);
+        let result = WasmInstr::LocalSet(Self::local(function, dst));
+        
+        match (self.options.syscalls, syscall) {
+            (SyscallMode::Browser, _) => {
+                for arg in &args[..syscall.arg_count()] {
+                    self.value(function, *arg, word, wasm);
                 }
+                wasm.extend([call, result]);
             }
-            
-            Mnemonic::Sub => {
-                let dst = allocator.get_or_allocate(instr.op0_register());
-                
-                match instr.op1_kind() {
-                    OpKind::Register => {
-                        let src = allocator.get_or_allocate(instr.op1_register());
-                        wasm.push(WasmInstr::LocalGet(dst));
-                        wasm.push(WasmInstr::LocalGet(src));
-                        wasm.push(WasmInstr::I64Sub);
-                        wasm.push(WasmInstr::LocalSet(dst));
-                    }
-                    OpKind::Immediate32 => {
-                        wasm.push(WasmInstr::LocalGet(dst));
-                        wasm.push(WasmInstr::I64Const(instr.immediate32() as i64));
-                        wasm.push(WasmInstr::I64Sub);
-                        wasm.push(WasmInstr::LocalSet(dst));
-                    }
-                    _ => {}
+            (SyscallMode::Wasi, Syscall::Exit | Syscall::ExitGroup) => {
+                self.wrapped(function, args[0], wasm);
+                wasm.push(call);
+            }
+            // random_get(buf, len) -> errno; getrandom returns len on success
+            (SyscallMode::Wasi, Syscall::Getrandom) => {
+                zero(wasm);
+                self.wrapped(function, args[0], wasm);
+                self.wrapped(function, args[1], wasm);
+                wasm.push(call);
+                Self::negate_errno(function, wasm);
+                wasm.push(result.clone());
+                self.value(function, args[1], word, wasm);
+                self.value(function, Value::Reg(dst), word, wasm);
+                self.value(function, Value::Reg(dst), word, wasm);
+                wasm.push(if word == ValType::I32 { WasmInstr::I32Eqz } else { WasmInstr::I64Eqz });
+                wasm.extend([WasmInstr::Select, result]);
+            }
+            // clock_time_get(id, precision, time) -> errno writes the
+            // nanoseconds at tp, which are then split into the tv_sec and
+            // tv_nsec of a timespec. Linux and WASI number the realtime and
+            // monotonic clocks the same.
+            (SyscallMode::Wasi, Syscall::ClockGettime) => {
+                zero(wasm);
+                self.wrapped(function, args[0], wasm);
+                wasm.push(WasmInstr::I64Const(1));
+                self.wrapped(function, args[1], wasm);
+                wasm.push(call);
+                Self::negate_errno(function, wasm);
+                wasm.push(result);
+                let memarg = |offset| MemArg { offset, align: 3, memory_index: 0 };
+                for (offset, op) in [(8, WasmInstr::I64RemU), (0, WasmInstr::I64DivU)] {
+                    self.wrapped(function, args[1], wasm);
+                    self.wrapped(function, args[1], wasm);
+                    wasm.extend([WasmInstr::I64Load(memarg(0)), WasmInstr::I64Const(1_000_000_000), op, WasmInstr::I64Store(memarg(offset))]);
                 }
             }
-            
-            Mnemonic::Imul => {
-                let dst = allocator.get_or_allocate(instr.op0_register());
-                let src = allocator.get_or_allocate(instr.op1_register());
-                wasm.push(WasmInstr::LocalGet(dst));
-                wasm.push(WasmInstr::LocalGet(src));
-                wasm.push(WasmInstr::I64Mul);
-                wasm.push(WasmInstr::LocalSet(dst));
+            // sched_yield() -> errno
+            (SyscallMode::Wasi, _) => {
+                zero(wasm);
+                wasm.push(call);
+                Self::negate_errno(function, wasm);
+                wasm.push(result);
             }
-            
-            // Comparisons (set flags for conditional jumps)
-            Mnemonic::Cmp | Mnemonic::Test => {
-                // Store comparison result in a virtual flag register
-                let flag_reg = allocator.get_or_allocate_flag();
-                
-                match instr.mnemonic() {
-                    Mnemonic::Cmp => {
-                        let op0 = allocator.get_or_allocate(instr.op0_register());
-                        
-                        match instr.op1_kind() {
-                            OpKind::Register => {
-                                let op1 = allocator.get_or_allocate(instr.op1_register());
-                                wasm.push(WasmInstr::LocalGet(op0));
-                                wasm.push(WasmInstr::LocalGet(op1));
-                                wasm.push(WasmInstr::I64Sub);
-                            }
-                            OpKind::Immediate32 => {
-                                wasm.push(WasmInstr::LocalGet(op0));
-                                wasm.push(WasmInstr::I64Const(instr.immediate32() as i64));
-                                wasm.push(WasmInstr::I64Sub);
-                            }
-                            _ => {}
-                        }
-                        
-                        wasm.push(WasmInstr::LocalSet(flag_reg));
-                    }
-                    Mnemonic::Test => {
-                        let op0 = allocator.get_or_allocate(instr.op0_register());
-                        let op1 = allocator.get_or_allocate(instr.op1_register());
-                        wasm.push(WasmInstr::LocalGet(op0));
-                        wasm.push(WasmInstr::LocalGet(op1));
-                        wasm.push(WasmInstr::I64And);
-                        wasm.push(WasmInstr::LocalSet(flag_reg));
-                    }
-                    _ => {}
+        }
+        if matches!(syscall, Syscall::Exit | Syscall::ExitGroup) {
+            wasm.push(WasmInstr::Unreachable);
+        }
+    }
+    
+    // Pushes a cpuid output register. Unless constant propagation made the
+    // leaf (and subleaf, where it matters) constants, every entry of the
+    // table is checked at runtime, the last match winning.
+    fn lower_cpuid(&self, function: &ir::Function, leaf: Value, subleaf: Value, output: u8, wasm: &mut Vec<WasmInstr<'static>>) {
+        let word = Self::val_type(function, function.word);
+        let constant = |value: Value| match value {
+            Value::Const(value) => Some(value as u32),
+            Value::Reg(_) => None,
+        };
+        if let Some(result) = constant(leaf).and_then(|leaf| cpuid::resolve(&self.options.cpuid, leaf, constant(subleaf))) {
+            self.value(function, Value::Const(result[output as usize] as i64), word, wasm);
+            return;
+        }
+        
+        self.value(function, Value::Const(0), word, wasm);
+        for entry in cpuid::entries(&self.options.cpuid) {
+            // Keeps the result so far unless the entry matches
+            self.value(function, Value::Const(entry.output(output) as i64), word, wasm);
+            self.wrapped(function, leaf, wasm);
+            wasm.extend([WasmInstr::I32Const(entry.leaf as i32), WasmInstr::I32Ne]);
+            if let Some(expected) = entry.subleaf {
+                self.wrapped(function, subleaf, wasm);
+                wasm.extend([WasmInstr::I32Const(expected as i32), WasmInstr::I32Ne, WasmInstr::I32Or]);
+            }
+            wasm.push(WasmInstr::Select);
+        }
+    }
+    
+    // Appends the instructions of `op` to `wasm`
+    fn lower_op(&self, function: &ir::Function, addr: u64, op: &Op, wasm: &mut Vec<WasmInstr<'static>>) {
+        match *op {
+            Op::Copy { dst, src } => {
+                self.value(function, src, Self::value_type(function, Value::Reg(dst)), wasm);
+                wasm.push(WasmInstr::LocalSet(Self::local(function, dst)));
+            }
+            Op::Binary { op, dst, lhs, rhs } => {
+                let ty = Self::value_type(function, Value::Reg(dst));
+                self.value(function, lhs, ty, wasm);
+                self.value(function, rhs, ty, wasm);
+                wasm.push(match (op, ty) {
+                    (BinOp::Add, ValType::I32) => WasmInstr::I32Add,
+                    (BinOp::Sub, ValType::I32) => WasmInstr::I32Sub,
+                    (BinOp::Mul, ValType::I32) => WasmInstr::I32Mul,
+                    (BinOp::And, ValType::I32) => WasmInstr::I32And,
+                    (BinOp::Shr, ValType::I32) => WasmInstr::I32ShrU,
+                    (BinOp::Sar, ValType::I32) => WasmInstr::I32ShrS,
+                    (BinOp::Shl, ValType::I32) => WasmInstr::I32Shl,
+                    (BinOp::Or, ValType::I32) => WasmInstr::I32Or,
+                    (BinOp::Xor, ValType::I32) => WasmInstr::I32Xor,
+                    (BinOp::Add, ValType::F64) => WasmInstr::F64Add,
+                    (BinOp::Sub, ValType::F64) => WasmInstr::F64Sub,
+                    (BinOp::Mul, ValType::F64) => WasmInstr::F64Mul,
+                    (BinOp::Div, ValType::F64) => WasmInstr::F64Div,
+                    (BinOp::Div, _) | (BinOp::And | BinOp::Shr | BinOp::Sar | BinOp::Shl | BinOp::Or | BinOp::Xor, ValType::F64) => unreachable!("only divisions of doubles are lifted"),
+                    (BinOp::Add, _) => WasmInstr::I64Add,
+                    (BinOp::Sub, _) => WasmInstr::I64Sub,
+                    (BinOp::Mul, _) => WasmInstr::I64Mul,
+                    (BinOp::And, _) => WasmInstr::I64And,
+                    (BinOp::Shr, _) => WasmInstr::I64ShrU,
+                    (BinOp::Sar, _) => WasmInstr::I64ShrS,
+                    (BinOp::Shl, _) => WasmInstr::I64Shl,
+                    (BinOp::Or, _) => WasmInstr::I64Or,
+                    (BinOp::Xor, _) => WasmInstr::I64Xor,
+                });
+                wasm.push(WasmInstr::LocalSet(Self::local(function, dst)));
+            }
+            Op::Convert { dst, src, from } => {
+                // An i32 register read as a 64-bit one is zero-extended
+                let ty = match (Self::value_type(function, src), from) {
+                    (ValType::I32, Ty::I64) => ValType::I64,
+                    (ty, _) => ty,
+                };
+                self.value(function, src, ty, wasm);
+                match (ty, from) {
+                    _ if Self::value_type(function, Value::Reg(dst)) == ValType::I32 => wasm.push(WasmInstr::I32WrapI64),
+                    (ValType::I64, Ty::I64) => wasm.push(WasmInstr::F64ConvertI64S),
+                    (ValType::I64, _) => wasm.extend([WasmInstr::I32WrapI64, WasmInstr::F64ConvertI32S]),
+                    _ => wasm.push(WasmInstr::F64ConvertI32S),
                 }
+                wasm.push(WasmInstr::LocalSet(Self::local(function, dst)));
             }
-            
-            // Conditional jumps - these need special handling
-            Mnemonic::Je | Mnemonic::Jne | Mnemonic::Jg | Mnemonic::Jl | 
-            Mnemonic::Jge | Mnemonic::Jle | Mnemonic::Ja | Mnemonic::Jb => {
-                // These are handled by control flow structuring
-                // Just note: WASM uses structured control flow (if/block/loop)
-                // not goto-style jumps
+            Op::Load { dst, base, offset } if Self::is_handle(function, base) => {
+                let ty = Self::value_type(function, Value::Reg(dst));
+                self.value(function, base, ValType::EXTERNREF, wasm);
+                wasm.push(WasmInstr::Call(Self::accessor_index(function, Accessor { write: false, offset, ty })));
+                wasm.push(WasmInstr::LocalSet(Self::local(function, dst)));
             }
-            
-            // Unconditional jump
-            Mnemonic::Jmp => {
-                // Handled by control flow structuring
+            Op::Store { base, offset, value } if Self::is_handle(function, base) => {
+                let ty = Self::value_type(function, value);
+                self.value(function, base, ValType::EXTERNREF, wasm);
+                self.value(function, value, ty, wasm);
+                wasm.push(WasmInstr::Call(Self::accessor_index(function, Accessor { write: true, offset, ty })));
             }
-            
-            // Function calls
-            Mnemonic::Call => {
-                // For now, we'll ignore external calls
-                // In a real implementation, you'd need to:
-                // 1. Resolve the target function
-                // 2. Recursively transpile it
-                // 3. Add to imports or internal functions
+            Op::Load { dst, base, offset } => {
+                let ty = Self::value_type(function, Value::Reg(dst));
+                let memarg = self.address(function, base, offset, ty, wasm);
+                match self.options.state_access {
+                    StateAccess::Memory | StateAccess::Handles if ty == ValType::I32 => wasm.push(WasmInstr::I32Load(memarg)),
+                    StateAccess::Memory | StateAccess::Handles if ty == ValType::F64 => wasm.push(WasmInstr::F64Load(memarg)),
+                    StateAccess::Memory | StateAccess::Handles => wasm.push(WasmInstr::I64Load(memarg)),
+                    StateAccess::Imports => {
+                        wasm.push(WasmInstr::Call(READ_STATE_FN));
+                        if ty == ValType::I32 {
+                            wasm.push(WasmInstr::I32WrapI64);
+                        } else if ty == ValType::F64 {
+                            wasm.push(WasmInstr::F64ReinterpretI64);
+                        }
+                    }
+                }
+                wasm.push(WasmInstr::LocalSet(Self::local(function, dst)));
             }
-            
-            // Return
-            Mnemonic::Ret => {
-                // Return value is in RAX/EAX
-                let rax = allocator.get_or_allocate(Register::RAX);
-                wasm.push(WasmInstr::LocalGet(rax));
+            Op::Store { base, offset, value } => {
+                let ty = Self::value_type(function, value);
+                let memarg = self.address(function, base, offset, ty, wasm);
+                self.value(function, value, ty, wasm);
+                match self.options.state_access {
+                    StateAccess::Memory | StateAccess::Handles if ty == ValType::I32 => wasm.push(WasmInstr::I32Store(memarg)),
+                    StateAccess::Memory | StateAccess::Handles if ty == ValType::F64 => wasm.push(WasmInstr::F64Store(memarg)),
+                    StateAccess::Memory | StateAccess::Handles => wasm.push(WasmInstr::I64Store(memarg)),
+                    StateAccess::Imports => {
+                        if ty == ValType::I32 {
+                            wasm.push(WasmInstr::I64ExtendI32S);
+                        } else if ty == ValType::F64 {
+                            wasm.push(WasmInstr::I64ReinterpretF64);
+                        }
+                        wasm.push(WasmInstr::Call(WRITE_STATE_FN));
+                    }
+                }
+            }
+            Op::Return { value } => {
+                self.value(function, value, Self::val_type(function, function.result), wasm);
                 wasm.push(WasmInstr::Return);
             }
-            
-            // Push/Pop (need stack simulation)
-            Mnemonic::Push => {
-                // Simplified: ignore for now
-                // Real implementation needs to maintain a WASM-side stack
+            Op::Trap { kind } => {
+                if let Some(trap_reason) = self.trap_reason_index(function) {
+                    wasm.push(WasmInstr::I32Const(kind.code()));
+                    self.call_trap_import(trap_reason, addr, wasm);
+                }
+                wasm.push(WasmInstr::Unreachable);
             }
-            
-            Mnemonic::Pop => {
-                // Simplified: ignore for now
+            Op::Syscall { dst, number, ref args } => self.lower_syscall(function, addr, dst, number, args, wasm),
+            Op::Timestamp { dst } => {
+                wasm.push(WasmInstr::Call(self.now_ns_index(function).unwrap_or_default()));
+                if let Some(mhz) = self.options.tsc_mhz {
+                    wasm.extend([WasmInstr::I64Const(mhz as i64), WasmInstr::I64Mul, WasmInstr::I64Const(1000), WasmInstr::I64DivU]);
+                }
+                wasm.push(WasmInstr::LocalSet(Self::local(function, dst)));
             }
-            
-            _ => {
-                // Unsupported instruction - could log or panic
-                println!("Warning: Unsupported instruction: {:?}", instr.mnemonic());
+            Op::Cpuid { dst, leaf, subleaf, output } => {
+                self.lower_cpuid(function, leaf, subleaf, output, wasm);
+                wasm.push(WasmInstr::LocalSet(Self::local(function, dst)));
             }
+            // WASM uses structured control flow (if/block/loop), not goto-style
+            // jumps; until the CFG is structured these are dropped, as are
+            // calls and stack operations
+            Op::Branch { .. } | Op::Jump { .. } | Op::IndirectJump | Op::Opaque { .. } => {}
+            Op::Phi { .. } => unreachable!("phis are replaced by copies before lowering"),
         }
-        
-        Ok(wasm)
     }
     
-    fn generate_wasm_module(&self, body: Vec<WasmInstr>, allocator: RegisterAllocator) -> Vec<u8> {
+    fn generate_wasm_module(
+        &self,
+        body: Vec<WasmInstr<'static>>,
+        params: Vec<ValType>,
+        result: ValType,
+        locals: Vec<(u32, ValType)>,
+        imports: ModuleImports,
+        memory: Option<&LinearMemory>,
+    ) -> Vec<u8> {
+        let ModuleImports { accessors, trap_reason, syscalls, now_ns } = imports;
         let mut module = Module::new();
+        // Out-parameters returned by a wrapper, which needs memory for their
+        // slots, see `multivalue.rs`
+        let outs = match memory {
+            Some(_) if self.options.multi_value && params.len() >= self.options.out_results.len() => &self.options.out_results[..],
+            _ => &[],
+        };
+        let leading = params.len() - outs.len();
+        let wrapper_params = params[..leading].to_vec();
         
-        // Type section: () -> i64 (simple callback signature)
+        // Type section: recovered parameters -> register-sized or f64 result
         let mut types = TypeSection::new();
-        types.function(vec![], vec![ValType::I64]);
+        types.ty().function(params, vec![result]);
+        if self.options.state_access == StateAccess::Imports {
+            types.ty().function(vec![ValType::I64], vec![ValType::I64]);
+            types.ty().function(vec![ValType::I64, ValType::I64], vec![]);
+        }
+        for accessor in accessors {
+            match accessor.write {
+                false => types.ty().function(vec![ValType::EXTERNREF], vec![accessor.ty]),
+                true => types.ty().function(vec![ValType::EXTERNREF, accessor.ty], vec![]),
+            };
+        }
+        if trap_reason && self.options.on_trap {
+            types.ty().function(vec![ValType::I32; 3], vec![]);
+        } else if trap_reason {
+            types.ty().function(vec![ValType::I32], vec![]);
+        }
+        let trap_reason_type = types.len() - 1;
+        for (_, import) in syscalls {
+            types.ty().function(import.params.clone(), import.results.clone());
+        }
+        if now_ns {
+            types.ty().function(vec![], vec![ValType::I64]);
+        }
+        let now_ns_type = types.len() - 1;
+        let allocator = memory.filter(|memory| memory.allocator);
+        // The memory and allocator imported from the runtime, see `runtime.rs`
+        let shared = memory.is_some() && self.options.shared_runtime;
+        let memory_type = memory.map(|memory| {
+            let end = memory.segments.iter().map(|(addr, bytes)| addr + bytes.len() as u64).max().unwrap_or(0);
+            runtime::memory_type(memory.memory64, end.div_ceil(WASM_PAGE).max(1))
+        });
+        let alloc_type = types.len();
+        if allocator.is_some() {
+            for (params, results) in alloc::types() {
+                types.ty().function(params, results);
+            }
+        }
+        let wrapper_type = types.len();
+        if !outs.is_empty() {
+            let results: Vec<ValType> = std::iter::once(result).chain(outs.iter().map(|ty| multivalue::result_type(*ty))).collect();
+            types.ty().function(wrapper_params, results);
+        }
         module.section(&types);
         
-        // Function section
+        // Import section: state accessors take function indices 0 and 1,
+        // field accessors 0..n, then env.trap_reason, the syscall imports
+        // and env.now_ns. Type indices are the function index plus one. The
+        // runtime's allocator and memory come last.
+        let mut imports = ImportSection::new();
+        if self.options.state_access == StateAccess::Imports {
+            imports.import("env", "read_i64", EntityType::Function(1));
+            imports.import("env", "write_i64", EntityType::Function(2));
+        }
+        for (idx, accessor) in accessors.iter().enumerate() {
+            imports.import("env", &accessor.name(), EntityType::Function(idx as u32 + 1));
+        }
+        if trap_reason {
+            let name = if self.options.on_trap { "on_trap" } else { "trap_reason" };
+            imports.import("env", name, EntityType::Function(trap_reason_type));
+        }
+        for (idx, import) in syscalls {
+            imports.import(import.module, &import.name, EntityType::Function(idx + 1));
+        }
+        if now_ns {
+            imports.import("env", "now_ns", EntityType::Function(now_ns_type));
+        }
+        if shared && allocator.is_some() {
+            for (idx, name) in alloc::EXPORTS.iter().enumerate() {
+                imports.import(runtime::MODULE, name, EntityType::Function(alloc_type + idx as u32));
+            }
+        }
+        // Function indices only count the imported functions
+        let callback_index = imports.len();
+        if let Some(memory_type) = memory_type.filter(|_| shared) {
+            imports.import(runtime::MODULE, "memory", EntityType::Memory(memory_type));
+        }
+        let alloc_index = match shared {
+            true => callback_index - alloc::EXPORTS.len() as u32,
+            false => callback_index + 1,
+        };
+        let allocator = allocator.filter(|_| !shared);
+        if !imports.is_empty() {
+            module.section(&imports);
+        }
+        
+        // Function section: the callback, the allocator functions, then the
+        // multi-value wrapper
         let mut functions = FunctionSection::new();
         functions.function(0);
+        if allocator.is_some() {
+            for idx in 0..alloc::EXPORTS.len() as u32 {
+                functions.function(alloc_type + idx);
+            }
+        }
+        let wrapper_index = callback_index + functions.len();
+        if !outs.is_empty() {
+            functions.function(wrapper_type);
+        }
         module.section(&functions);
         
+        // Memory section: large enough for the data segments
+        if let Some(memory_type) = memory_type.filter(|_| !shared) {
+            let mut memories = MemorySection::new();
+            memories.memory(memory_type);
+            module.section(&memories);
+        }
+        
+        // Global section: the heap pointer of the allocator
+        if let Some(memory) = allocator {
+            let mut globals = GlobalSection::new();
+            let (ty, init) = alloc::global(memory);
+            globals.global(ty, &init);
+            module.section(&globals);
+        }
+        
         // Export section
         let mut exports = ExportSection::new();
-        exports.export("callback", ExportKind::Func, 0);
+        let exported = if outs.is_empty() { callback_index } else { wrapper_index };
+        exports.export("callback", ExportKind::Func, exported);
+        if memory.is_some() {
+            exports.export("memory", ExportKind::Memory, 0);
+        }
+        if memory.is_some_and(|memory| memory.allocator) {
+            for (idx, name) in alloc::EXPORTS.iter().enumerate() {
+                exports.export(name, ExportKind::Func, alloc_index + idx as u32);
+            }
+        }
         module.section(&exports);
         
         // Code section
         let mut codes = CodeSection::new();
-        let mut func = Function::new(allocator.get_locals_types());
-        
-        for instr in body {
-            func.instruction(&instr);
+        codes.raw(&encode::function(locals, &body));
+        if let Some(memory) = allocator {
+            for function in alloc::functions(memory, 0) {
+                codes.function(&function);
+            }
+        }
+        if let Some(memory) = memory.filter(|_| !outs.is_empty()) {
+            codes.function(&multivalue::wrapper(callback_index, leading as u32, outs, memory.memory64));
         }
-        
-        // Ensure function ends properly
-        func.instruction(&WasmInstr::End);
-        
-        codes.function(&func);
         module.section(&codes);
         
+        // Data section: constants at their addresses in the binary
+        if let Some(memory) = memory.filter(|memory| !memory.segments.is_empty()) {
+            let mut data = DataSection::new();
+            for (addr, bytes) in &memory.segments {
+                let offset = match memory.memory64 {
+                    true => ConstExpr::i64_const(*addr as i64),
+                    false => ConstExpr::i32_const(*addr as i32),
+                };
+                data.active(0, &offset, bytes.iter().copied());
+            }
+            module.section(&data);
+        }
+        
         module.finish()
     }
 }
 
-// Register allocator - maps x86-64 registers to WASM locals
-struct RegisterAllocator {
-    reg_map: HashMap<Register, u32>,
-    next_local: u32,
-    flag_reg: Option<u32>,
-}
-
-impl RegisterAllocator {
-    fn new() -> Self {
-        Self {
-            reg_map: HashMap::new(),
-            next_local: 0,
-            flag_reg: None,
-        }
-    }
-    
-    fn get_or_allocate(&mut self, reg: Register) -> u32 {
-        *self.reg_map.entry(reg).or_insert_with(|| {
-            let idx = self.next_local;
-            self.next_local += 1;
-            idx
-        })
+impl TranspilerBackend for DirectBackend {
+    fn name(&self) -> &'static str {
+        "direct"
     }
     
-    fn get_or_allocate_flag(&mut self) -> u32 {
-        if let Some(idx) = self.flag_reg {
-            idx
-        } else {
-            let idx = self.next_local;
-            self.next_local += 1;
-            self.flag_reg = Some(idx);
-            idx
+    fn lower(&self, function: &FunctionIR) -> Result<LoweredFunction, Box<dyn std::error::Error>> {
+        crate::budget::check_instructions(function, &self.options)?;
+        // Step 4: Lift to the IR and optimize
+        let mut report = FidelityReport {
+            instructions: function.instructions.len(),
+            ..Default::default()
+        };
+        let mut lifted = ir::lift(function, &mut report);
+        if self.options.inline_threshold > 0 {
+            let inlined = inline::inline_calls(&mut lifted, function, self.options.inline_threshold);
+            report.approximated.retain(|(addr, _)| !inlined.contains(addr));
+        }
+        // Before folding, which reads 4 bytes for an i32 load
+        if self.options.infer_widths {
+            ir::infer_widths(&mut lifted);
+        }
+        embed::fold_fixed(&mut lifted, function);
+        ir::apply_float_abi(&mut lifted, self.options.float_result);
+        if let Some(signature) = &self.options.signature {
+            ir::apply_signature(&mut lifted, signature)?;
+        } else if let Some(signature) = multivalue::out_signature(&self.options) {
+            // The wrapper passes the out-pointers
+            ir::apply_signature(&mut lifted, &signature)?;
+        }
+        report.unoptimized = self.module_size(&lifted);
+        if self.options.optimization == OptLevel::Full {
+            ir::optimize(&mut lifted);
+        }
+        // After copy propagation, which turns reloaded pointers back into
+        // the argument register
+        if self.options.state_access == StateAccess::Handles {
+            ir::mark_handles(&mut lifted);
+        }
+        report.optimized = self.module_size(&lifted);
+        
+        // Step 5: Translate to WASM, one local per virtual register. Every
+        // op appends to one buffer, with the address and range it took.
+        let mut wasm = Vec::with_capacity(function.instructions.len() * 3);
+        let mut spans: Vec<(u64, Range<usize>)> = Vec::with_capacity(function.instructions.len());
+        for inst in lifted.insts() {
+            let start = wasm.len();
+            self.lower_op(&lifted, inst.addr, &inst.op, &mut wasm);
+            spans.push((inst.addr, start..wasm.len()));
+        }
+        match self.options.traps {
+            TrapStrategy::Skip => {}
+            TrapStrategy::Unreachable => {
+                for (addr, _) in &report.unsupported {
+                    let start = wasm.len();
+                    // Reported as the CPU would an instruction it does not know
+                    if let Some(on_trap) = self.trap_reason_index(&lifted).filter(|_| self.options.on_trap) {
+                        wasm.push(WasmInstr::I32Const(ir::TrapKind::Invalid.code()));
+                        self.call_trap_import(on_trap, *addr, &mut wasm);
+                    }
+                    wasm.push(WasmInstr::Unreachable);
+                    spans.push((*addr, start..wasm.len()));
+                }
+            }
+            TrapStrategy::Reject if !report.unsupported.is_empty() => {
+                let unsupported: Vec<String> = report.unsupported
+                    .iter()
+                    .map(|(addr, mnemonic)| format!("{:#x}: {}", addr, mnemonic))
+                    .collect();
+                return Err(format!("Unsupported instructions: {}", unsupported.join(", ")).into());
+            }
+            TrapStrategy::Reject => {}
+        }
+        
+        let memory = self.memory(&lifted, function)?;
+        // The body follows the machine code, but blocks are lifted in
+        // structured order and traps come last. Only then is it rebuilt;
+        // the sort is stable, so the ops of an instruction keep their order.
+        if !spans.is_sorted_by_key(|(addr, _)| *addr) {
+            spans.sort_by_key(|(addr, _)| *addr);
+            let mut sorted = Vec::with_capacity(wasm.len());
+            for (_, range) in &mut spans {
+                let start = sorted.len();
+                sorted.extend_from_slice(&wasm[range.clone()]);
+                *range = start..sorted.len();
+            }
+            wasm = sorted;
         }
+        let mut spans = spans.into_iter().peekable();
+        let code_start = function.entry;
+        let lowerings = function.instructions
+            .iter()
+            .map(|info| {
+                while spans.next_if(|(addr, _)| *addr < info.addr).is_some() {}
+                let start = spans.peek().map_or(wasm.len(), |(_, span)| span.start);
+                let mut end = start;
+                while let Some((_, span)) = spans.next_if(|(addr, _)| *addr == info.addr) {
+                    end = span.end;
+                }
+                let offset = (info.addr - code_start) as usize;
+                InstructionLowering {
+                    instr: info.instr.clone(),
+                    bytes: offset..offset + info.instr.len(),
+                    wasm: start..end,
+                }
+            })
+            .collect();
+        
+        Ok(LoweredFunction {
+            arch: function.arch,
+            entry: function.entry,
+            lowerings,
+            code: function.code.clone(),
+            body: wasm,
+            report,
+            params: Self::params(&lifted),
+            result: Self::val_type(&lifted, lifted.result),
+            locals: Self::locals(&lifted),
+            accessors: Self::accessors(&lifted),
+            trap_reason: self.trap_reason_index(&lifted),
+            on_trap: self.options.on_trap,
+            syscalls: self.syscall_imports(&lifted),
+            now_ns: self.now_ns_index(&lifted),
+            memory,
+        })
     }
     
-    fn get_locals_types(&self) -> Vec<(u32, ValType)> {
-        // All locals are i64 for simplicity
-        if self.next_local > 0 {
-            vec![(self.next_local, ValType::I64)]
-        } else {
-            vec![]
-        }
+    fn emit(&self, function: LoweredFunction) -> Vec<u8> {
+        // Step 6: Generate WASM module
+        self.generate_wasm_module(
+            function.body,
+            function.params,
+            function.result,
+            function.locals,
+            ModuleImports {
+                accessors: &function.accessors,
+                trap_reason: function.trap_reason.is_some(),
+                syscalls: &function.syscalls,
+                now_ns: function.now_ns.is_some(),
+            },
+            function.memory.as_ref(),
+        )
     }
 }
 
 // Control flow graph structures
 #[derive(Debug, Clone)]
-struct InstructionInfo {
-    addr: u64,
-    instr: Instruction,
+pub struct InstructionInfo {
+    pub addr: u64,
+    pub instr: MachineInstr,
 }
 
-struct ControlFlowGraph {
+pub struct ControlFlowGraph {
     blocks: Vec<BasicBlock>,
+    #[allow(dead_code)]
     edges: HashMap<usize, Vec<usize>>,
 }
 
 #[derive(Debug, Clone)]
-struct BasicBlock {
-    start_addr: u64,
+#[allow(dead_code)]
+pub struct BasicBlock {
+    pub start_addr: u64,
     end_addr: u64,
-    instruction_indices: Vec<usize>,
+    pub instruction_indices: Vec<usize>,
 }
 
 impl ControlFlowGraph {
-    fn from_instructions(instructions: &[InstructionInfo], entry: u64) -> Self {
+    pub fn from_instructions(instructions: &[InstructionInfo], entry: u64) -> Self {
         let mut blocks = Vec::new();
-        let mut edges = HashMap::new();
+        let edges = HashMap::new();
         let mut leaders = HashSet::new();
         
         // Identify basic block leaders
         leaders.insert(entry);
         
         for (idx, info) in instructions.iter().enumerate() {
-            match info.instr.mnemonic() {
-                Mnemonic::Jmp | Mnemonic::Je | Mnemonic::Jne | Mnemonic::Jg | 
-                Mnemonic::Jl | Mnemonic::Jge | Mnemonic::Jle | Mnemonic::Ja | 
-                Mnemonic::Jb | Mnemonic::Call | Mnemonic::Ret => {
-                    // Target of jump is a leader
-                    if info.instr.is_jmp_short_or_near() {
-                        leaders.insert(info.instr.near_branch_target());
-                    }
-                    
-                    // Instruction after jump/call is a leader
-                    if idx + 1 < instructions.len() {
-                        leaders.insert(instructions[idx + 1].addr);
-                    }
+            if info.instr.ends_block() {
+                // Target of jump is a leader
+                if let Some(target) = info.instr.branch_target() {
+                    leaders.insert(target);
+                }
+                
+                // Instruction after jump/call is a leader
+                if idx + 1 < instructions.len() {
+                    leaders.insert(instructions[idx + 1].addr);
                 }
-                _ => {}
             }
         }
         
@@ -481,10 +1701,33 @@ impl ControlFlowGraph {
         Self { blocks, edges }
     }
     
-    fn structure_control_flow(&self, label_map: &HashMap<u64, usize>) -> Vec<BasicBlock> {
+    pub fn structure_control_flow(&self) -> Vec<BasicBlock> {
         // For simple callbacks, just return blocks in order
         // A real implementation would use Relooper or similar algorithm
         // to convert to structured control flow (if/loop/block)
         self.blocks.clone()
     }
 }
+
+// Renders a WASM instruction in WAT syntax, for the debugging views
+pub fn wat_text(instr: &WasmInstr) -> String {
+    match instr {
+        WasmInstr::LocalGet(idx) => format!("local.get {}", idx),
+        WasmInstr::LocalSet(idx) => format!("local.set {}", idx),
+        WasmInstr::LocalTee(idx) => format!("local.tee {}", idx),
+        WasmInstr::I32Const(value) => format!("i32.const {}", value),
+        WasmInstr::I64Const(value) => format!("i64.const {}", value),
+        WasmInstr::I64Add => "i64.add".to_string(),
+        WasmInstr::I64Sub => "i64.sub".to_string(),
+        WasmInstr::I64Mul => "i64.mul".to_string(),
+        WasmInstr::I64And => "i64.and".to_string(),
+        WasmInstr::I64Load(mem) => format!("i64.load offset={}", mem.offset),
+        WasmInstr::I64Store(mem) => format!("i64.store offset={}", mem.offset),
+        WasmInstr::Call(READ_STATE_FN) => "call $env.read_i64".to_string(),
+        WasmInstr::Call(WRITE_STATE_FN) => "call $env.write_i64".to_string(),
+        WasmInstr::Return => "return".to_string(),
+        WasmInstr::Unreachable => "unreachable".to_string(),
+        WasmInstr::End => "end".to_string(),
+        other => format!("{:?}", other),
+    }
+}
@@ -0,0 +1,411 @@
+// Uploads
+// Large arguments are not sent inline to /execute, whose body is capped by
+// `[limits] execute_bytes`. They are posted to /upload first, as a raw body
+// or as multipart/form-data with any number of parts. Each is streamed into
+// a file in `upload_dir` as it arrives and is never buffered whole, up to
+// `upload_bytes` per request. The response names every upload by an id. An
+// `{"upload": "<id>"}` argument of a later /execute or batch entry is
+// replaced by the path of its file, so a string parameter receives the path.
+// Uploads are removed `upload_ttl_secs` after they were received. The files
+// are written, swept and removed on the blocking pool.
+
+use crate::auth::Permission;
+use crate::error::ApiError;
+use crate::ServerContext;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse, Responder, ResponseError};
+use futures_util::StreamExt;
+use serde::Serialize;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Part headers longer than this are rejected
+const MAX_HEADER_BYTES: usize = 8 * 1024;
+
+pub struct Uploads {
+    directory: PathBuf,
+    max_bytes: usize,
+    ttl: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Upload {
+    pub id: String,
+    /// Form field of a multipart part
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    pub len: usize,
+}
+
+impl Uploads {
+    pub fn new(config: &crate::config::LimitsConfig) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&config.upload_dir)?;
+        let uploads = Self {
+            directory: config.upload_dir.clone(),
+            max_bytes: config.upload_bytes,
+            ttl: Duration::from_secs(config.upload_ttl_secs),
+        };
+        uploads.sweep();
+        Ok(uploads)
+    }
+
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        // Ids are ours, 32 hex digits, so they never leave the directory
+        let valid = id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit());
+        let path = self.directory.join(id);
+        (valid && path.is_file()).then_some(path)
+    }
+
+    /// Replaces `{"upload": "<id>"}` arguments with the path of the upload
+    pub fn resolve(&self, args: &mut [serde_json::Value]) -> Result<(), String> {
+        for arg in args {
+            let Some(id) = arg.as_object().filter(|object| object.len() == 1).and_then(|object| object.get("upload")) else {
+                continue;
+            };
+            let id = id.as_str().ok_or("An upload id must be a string")?;
+            let path = self.path(id).ok_or_else(|| format!("No upload {}", id))?;
+            *arg = serde_json::Value::String(path.to_string_lossy().into_owned());
+        }
+        Ok(())
+    }
+
+    /// Removes the uploads older than the TTL
+    pub fn sweep(&self) {
+        let Ok(entries) = std::fs::read_dir(&self.directory) else { return };
+        let expired = |entry: &std::fs::DirEntry| {
+            let received = entry.metadata().and_then(|metadata| metadata.modified());
+            received.is_ok_and(|received| received.elapsed().is_ok_and(|age| age > self.ttl))
+        };
+        for entry in entries.flatten().filter(expired) {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+
+    fn create(&self) -> std::io::Result<(String, File)> {
+        let id = hex::encode(rand::random::<[u8; 16]>());
+        let file = File::create(self.directory.join(&id))?;
+        Ok((id, file))
+    }
+
+    fn remove(&self, uploads: &[Upload]) {
+        for upload in uploads {
+            let _ = std::fs::remove_file(self.directory.join(&upload.id));
+        }
+    }
+}
+
+#[derive(Debug)]
+enum UploadError {
+    TooLarge(usize),
+    Malformed(String),
+    Io(std::io::Error),
+}
+
+impl UploadError {
+    fn response(&self) -> HttpResponse {
+        match self {
+            UploadError::TooLarge(max) => ApiError::payload_too_large(format!("Uploads are limited to {} bytes", max)).error_response(),
+            UploadError::Malformed(e) => ApiError::bad_request(format!("Malformed upload: {}", e)).error_response(),
+            UploadError::Io(e) => ApiError::internal(format!("Could not store the upload: {}", e)).error_response(),
+        }
+    }
+}
+
+impl From<std::io::Error> for UploadError {
+    fn from(e: std::io::Error) -> Self {
+        UploadError::Io(e)
+    }
+}
+
+// Where a multipart body is between two parts, or in one
+enum Part {
+    Preamble,
+    // After a delimiter, before `--` (the end) or a part's headers
+    Delimiter,
+    Headers,
+    Body(Upload, File),
+    Done,
+}
+
+/// A multipart/form-data body fed in chunks, whose parts are written to
+/// upload files as they arrive
+struct Multipart {
+    uploads: Arc<Uploads>,
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    part: Part,
+    done: Vec<Upload>,
+}
+
+impl Multipart {
+    fn new(uploads: Arc<Uploads>, boundary: &str) -> Self {
+        Self {
+            uploads,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // The first delimiter is not preceded by a line break
+            buffer: b"\r\n".to_vec(),
+            part: Part::Preamble,
+            done: Vec::new(),
+        }
+    }
+
+    fn feed(&mut self, chunk: &[u8]) -> Result<(), UploadError> {
+        self.buffer.extend_from_slice(chunk);
+        loop {
+            let part = std::mem::replace(&mut self.part, Part::Done);
+            let (part, progressed) = self.step(part)?;
+            self.part = part;
+            if !progressed {
+                return Ok(());
+            }
+        }
+    }
+
+    // Advances by at most one state, false once it needs more input
+    fn step(&mut self, part: Part) -> Result<(Part, bool), UploadError> {
+        match part {
+            Part::Preamble => match find(&self.buffer, &self.delimiter) {
+                Some(idx) => {
+                    self.buffer.drain(..idx + self.delimiter.len());
+                    Ok((Part::Delimiter, true))
+                }
+                None => {
+                    let keep = self.buffer.len().saturating_sub(self.delimiter.len());
+                    self.buffer.drain(..keep);
+                    Ok((Part::Preamble, false))
+                }
+            },
+            Part::Delimiter if self.buffer.len() < 2 => Ok((Part::Delimiter, false)),
+            Part::Delimiter => match &self.buffer[..2] {
+                b"--" => Ok((Part::Done, false)),
+                b"\r\n" => {
+                    self.buffer.drain(..2);
+                    Ok((Part::Headers, true))
+                }
+                _ => Err(UploadError::Malformed("expected a line break after the boundary".to_string())),
+            },
+            // The end of the headers and the start of the body. A part
+            // without headers starts with the blank line.
+            Part::Headers => match self.buffer.starts_with(b"\r\n").then_some((0, 2)).or_else(|| find(&self.buffer, b"\r\n\r\n").map(|end| (end, end + 4))) {
+                Some((end, body)) => {
+                    let headers = String::from_utf8_lossy(&self.buffer[..end]).into_owned();
+                    self.buffer.drain(..body);
+                    let (field, filename) = disposition(&headers);
+                    let (id, file) = self.uploads.create()?;
+                    Ok((Part::Body(Upload { id, field, filename, len: 0 }, file), true))
+                }
+                None if self.buffer.len() > MAX_HEADER_BYTES => Err(UploadError::Malformed("part headers too long".to_string())),
+                None => Ok((Part::Headers, false)),
+            },
+            Part::Body(mut upload, mut file) => match find(&self.buffer, &self.delimiter) {
+                Some(idx) => {
+                    file.write_all(&self.buffer[..idx])?;
+                    upload.len += idx;
+                    self.buffer.drain(..idx + self.delimiter.len());
+                    self.done.push(upload);
+                    Ok((Part::Delimiter, true))
+                }
+                None => {
+                    // The end may hold the start of the delimiter
+                    let safe = self.buffer.len().saturating_sub(self.delimiter.len());
+                    file.write_all(&self.buffer[..safe])?;
+                    upload.len += safe;
+                    self.buffer.drain(..safe);
+                    Ok((Part::Body(upload, file), false))
+                }
+            },
+            Part::Done => Ok((Part::Done, false)),
+        }
+    }
+
+    fn finish(self) -> Result<Vec<Upload>, (UploadError, Vec<Upload>)> {
+        let mut uploads = self.done;
+        match self.part {
+            Part::Done => Ok(uploads),
+            Part::Body(upload, _) => {
+                uploads.push(upload);
+                Err((UploadError::Malformed("the body ends inside a part".to_string()), uploads))
+            }
+            _ => Err((UploadError::Malformed("the body ends before the closing boundary".to_string()), uploads)),
+        }
+    }
+
+    // Every file written so far, to remove on an error
+    fn written(&self) -> Vec<Upload> {
+        let mut uploads = self.done.clone();
+        if let Part::Body(upload, _) = &self.part {
+            uploads.push(upload.clone());
+        }
+        uploads
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// The field name and filename of a part's Content-Disposition header
+fn disposition(headers: &str) -> (Option<String>, Option<String>) {
+    let Some(value) = headers
+        .lines()
+        .find_map(|line| line.split_once(':').filter(|(name, _)| name.trim().eq_ignore_ascii_case("content-disposition")))
+        .map(|(_, value)| value)
+    else {
+        return (None, None);
+    };
+    let param = |key: &str| {
+        value.split(';').find_map(|param| {
+            let (name, value) = param.trim().split_once('=')?;
+            (name.trim() == key).then(|| value.trim().trim_matches('"').to_string())
+        })
+    };
+    (param("name"), param("filename"))
+}
+
+fn boundary(content_type: &str) -> Option<&str> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params.split(';').find_map(|param| {
+        let (name, value) = param.trim().split_once('=')?;
+        name.trim().eq_ignore_ascii_case("boundary").then(|| value.trim().trim_matches('"'))
+    })
+}
+
+// Runs the file I/O of `f` on the blocking pool
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> Result<T, UploadError> {
+    web::block(f).await.map_err(|e| UploadError::Io(std::io::Error::other(e.to_string())))
+}
+
+// Streams a multipart body into a file per part. The parser and its open
+// file move to the blocking pool for every chunk; if the pool fails, the
+// files written so far are left to the sweep.
+async fn receive_multipart(uploads: Arc<Uploads>, boundary: &str, payload: &mut web::Payload) -> Result<Vec<Upload>, (UploadError, Vec<Upload>)> {
+    let max_bytes = uploads.max_bytes;
+    let mut multipart = Multipart::new(uploads, boundary);
+    let mut received = 0;
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) if received + chunk.len() > max_bytes => return Err((UploadError::TooLarge(max_bytes), multipart.written())),
+            Ok(chunk) => chunk,
+            Err(e) => return Err((UploadError::Malformed(e.to_string()), multipart.written())),
+        };
+        received += chunk.len();
+        let (fed, result) = blocking(move || {
+            let result = multipart.feed(&chunk);
+            (multipart, result)
+        })
+        .await
+        .map_err(|e| (e, Vec::new()))?;
+        multipart = fed;
+        if let Err(e) = result {
+            return Err((e, multipart.written()));
+        }
+    }
+    multipart.finish()
+}
+
+// Streams a raw body into a single upload
+async fn receive_raw(uploads: Arc<Uploads>, payload: &mut web::Payload) -> Result<Vec<Upload>, (UploadError, Vec<Upload>)> {
+    let max_bytes = uploads.max_bytes;
+    let (id, mut file) = blocking(move || uploads.create())
+        .await
+        .and_then(|created| created.map_err(UploadError::Io))
+        .map_err(|e| (e, Vec::new()))?;
+    let mut upload = Upload { id, field: None, filename: None, len: 0 };
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) if upload.len + chunk.len() > max_bytes => return Err((UploadError::TooLarge(max_bytes), vec![upload])),
+            Ok(chunk) => chunk,
+            Err(e) => return Err((UploadError::Malformed(e.to_string()), vec![upload])),
+        };
+        let len = chunk.len();
+        let written = blocking(move || {
+            let result = file.write_all(&chunk);
+            (file, result)
+        })
+        .await;
+        match written {
+            Ok((written, Ok(()))) => file = written,
+            Ok((_, Err(e))) => return Err((UploadError::Io(e), vec![upload])),
+            Err(e) => return Err((e, vec![upload])),
+        }
+        upload.len += len;
+    }
+    Ok(vec![upload])
+}
+
+pub async fn upload(req: HttpRequest, mut payload: web::Payload, ctx: web::Data<ServerContext>) -> impl Responder {
+    if let Err(e) = ctx.csrf.verify(&req) {
+        return e.error_response();
+    }
+    if let Err(e) = ctx.auth.authorize(&req, Permission::User) {
+        return e.into_response(&ctx.auth);
+    }
+    let uploads = ctx.uploads.clone();
+    let sweeping = uploads.clone();
+    if let Err(e) = blocking(move || sweeping.sweep()).await {
+        tracing::warn!(error = ?e, "could not sweep uploads");
+    }
+
+    let content_type = req.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    let result = match boundary(content_type) {
+        Some(boundary) => receive_multipart(uploads.clone(), boundary, &mut payload).await,
+        None => receive_raw(uploads.clone(), &mut payload).await,
+    };
+
+    match result {
+        Ok(received) => {
+            tracing::info!(count = received.len(), bytes = received.iter().map(|upload| upload.len).sum::<usize>(), "received uploads");
+            HttpResponse::Ok().json(serde_json::json!({ "uploads": received }))
+        }
+        Err((e, written)) => {
+            if let Err(e) = blocking(move || uploads.remove(&written)).await {
+                tracing::warn!(error = ?e, "could not remove the failed uploads");
+            }
+            tracing::warn!(error = ?e, "upload failed");
+            e.response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams_multipart_parts_to_files() {
+        let directory = std::env::temp_dir().join(format!("self-serve-uploads-{}", std::process::id()));
+        let config = crate::config::LimitsConfig { upload_dir: directory.clone(), ..Default::default() };
+        let uploads = Arc::new(Uploads::new(&config).unwrap());
+
+        let body = b"preamble\r\n--XyZ\r\nContent-Disposition: form-data; name=\"doc\"; filename=\"a.txt\"\r\nContent-Type: text/plain\r\n\r\nline one\r\n--XY not the end\r\n--XyZ\r\nContent-Disposition: form-data; name=\"note\"\r\n\r\nhi\r\n--XyZ--\r\n";
+        let mut multipart = Multipart::new(uploads.clone(), boundary("multipart/form-data; boundary=\"XyZ\"").unwrap());
+        // In chunks that split the delimiters
+        for chunk in body.chunks(5) {
+            multipart.feed(chunk).unwrap();
+        }
+        let received = multipart.finish().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!((received[0].field.as_deref(), received[0].filename.as_deref()), (Some("doc"), Some("a.txt")));
+        assert_eq!(received[1].filename, None);
+
+        let mut args = vec![serde_json::json!({ "upload": received[0].id }), serde_json::json!(3)];
+        uploads.resolve(&mut args).unwrap();
+        let path = args[0].as_str().unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"line one\r\n--XY not the end");
+        assert!(uploads.resolve(&mut [serde_json::json!({ "upload": "../etc/passwd" })]).is_err());
+
+        let mut truncated = Multipart::new(uploads.clone(), "XyZ");
+        truncated.feed(b"--XyZ\r\n\r\nno end").unwrap();
+        let (_, written) = truncated.finish().unwrap_err();
+        assert_eq!(written[0].len, 0);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
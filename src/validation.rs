@@ -0,0 +1,131 @@
+// Argument validation
+// Rules registered per client parameter of a callback with
+// `CallbackRegistry::validate`. They are checked before the native callback
+// runs, and a failing argument is answered with a 422 listing every
+// violation. The glue gets the same rules to check arguments before sending
+// them. Numbers are checked with `Range`, strings with `MaxLength` (in
+// characters) and `Pattern`, arrays with `MaxLength` (in elements) and with
+// `Range` for every element.
+
+use regex::Regex;
+use serde::{Serialize, Serializer};
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum Rule {
+    Range { min: Option<f64>, max: Option<f64> },
+    MaxLength { max: usize },
+    /// A regex the whole string must match. Patterns are also compiled as
+    /// JavaScript `RegExp`s, so they should stay within the common syntax.
+    Pattern {
+        #[serde(serialize_with = "regex_source")]
+        pattern: Regex,
+    },
+}
+
+fn regex_source<S: Serializer>(regex: &Regex, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(regex.as_str())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Violation {
+    /// Index of the client argument
+    pub param: usize,
+    pub rule: &'static str,
+    pub message: String,
+}
+
+impl Rule {
+    pub fn range(min: f64, max: f64) -> Self {
+        Rule::Range { min: Some(min), max: Some(max) }
+    }
+
+    pub fn max_length(max: usize) -> Self {
+        Rule::MaxLength { max }
+    }
+
+    /// Anchored at both ends. Panics on an invalid pattern, as rules are
+    /// registered at startup.
+    pub fn pattern(pattern: &str) -> Self {
+        let anchored = format!("^(?:{})$", pattern);
+        Rule::Pattern { pattern: Regex::new(&anchored).expect("valid validation pattern") }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Rule::Range { .. } => "range",
+            Rule::MaxLength { .. } => "max_length",
+            Rule::Pattern { .. } => "pattern",
+        }
+    }
+
+    /// Why `value` breaks the rule, none if it holds or does not apply
+    fn check(&self, value: &serde_json::Value) -> Option<String> {
+        match (self, value) {
+            (Rule::Range { min, max }, serde_json::Value::Number(number)) => {
+                let number = number.as_f64()?;
+                let below = min.is_some_and(|min| number < min);
+                let above = max.is_some_and(|max| number > max);
+                (below || above).then(|| format!("{} is not within {}", number, describe_range(*min, *max)))
+            }
+            (Rule::Range { .. }, serde_json::Value::Array(values)) => values.iter().find_map(|value| self.check(value)),
+            (Rule::MaxLength { max }, serde_json::Value::String(text)) => {
+                let len = text.chars().count();
+                (len > *max).then(|| format!("{} characters, at most {} are allowed", len, max))
+            }
+            (Rule::MaxLength { max }, serde_json::Value::Array(values)) => {
+                (values.len() > *max).then(|| format!("{} elements, at most {} are allowed", values.len(), max))
+            }
+            (Rule::Pattern { pattern }, serde_json::Value::String(text)) => {
+                (!pattern.is_match(text)).then(|| format!("does not match {}", pattern.as_str()))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn describe_range(min: Option<f64>, max: Option<f64>) -> String {
+    match (min, max) {
+        (Some(min), Some(max)) => format!("[{}, {}]", min, max),
+        (Some(min), None) => format!("[{}, ...)", min),
+        (None, Some(max)) => format!("(..., {}]", max),
+        (None, None) => "any range".to_string(),
+    }
+}
+
+/// Violations of `rules`, one list per client parameter, by `args`
+pub fn check(rules: &[Vec<Rule>], args: &[serde_json::Value]) -> Vec<Violation> {
+    rules
+        .iter()
+        .zip(args)
+        .enumerate()
+        .flat_map(|(param, (rules, arg))| {
+            rules.iter().filter_map(move |rule| {
+                rule.check(arg).map(|message| Violation { param, rule: rule.name(), message })
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reports_every_violation() {
+        let rules = vec![
+            vec![Rule::max_length(3), Rule::pattern("-?[0-9]+")],
+            vec![Rule::max_length(2), Rule::range(0.0, 10.0)],
+        ];
+        assert!(check(&rules, &[json!("-12"), json!([0, 10])]).is_empty());
+
+        let violations = check(&rules, &[json!("12a4"), json!([1, 11])]);
+        let found: Vec<(usize, &str)> = violations.iter().map(|violation| (violation.param, violation.rule)).collect();
+        assert_eq!(found, vec![(0, "max_length"), (0, "pattern"), (1, "range")]);
+        assert_eq!(violations[2].message, "11 is not within [0, 10]");
+
+        let json = serde_json::to_value(&rules[0]).unwrap();
+        assert_eq!(json, json!([{ "rule": "max_length", "max": 3 }, { "rule": "pattern", "pattern": "^(?:-?[0-9]+)$" }]));
+    }
+}
@@ -0,0 +1,564 @@
+// Differential verification
+// Runs each callback natively, in-process, on a copy of a sample state and
+// compares the result and the resulting state bytes with a run of its WASM
+// lowering. The lowering is compiled by Cranelift and run by wasmtime, with
+// the state image at address 0 of its linear memory, or behind
+// env.read_i64/env.write_i64 when it has none, so it traps where a browser
+// would. Only callbacks returning a plain i32 or f64 are compared;
+// out-pointer results cannot be observed this way. Every run has a fuel,
+// memory and time budget (`VerifyLimits`), so a mistranslated loop cannot
+// hang the worker serving /admin/verify.
+
+use crate::callbacks::{CallbackEntry, CallbackValue, ReturnType};
+use crate::runtime;
+use crate::transpiler::Transpiler;
+use crate::State;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use std::time::Duration;
+use wasmtime::{
+    Caller, Config, Engine, Extern, ExternType, Func, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+    Trap, Val,
+};
+
+// Counter values every callback is run on. They stay clear of the overflow
+// edges: an overflow panic in a debug build of an extern "C" callback aborts
+// the process instead of failing the case
+pub const SAMPLE_COUNTERS: [i32; 5] = [0, 1, -1, 41, 1 << 20];
+
+const WORD: usize = 8;
+
+// Period of the epoch that `VerifyLimits::timeout_ms` is counted in
+const EPOCH: Duration = Duration::from_millis(10);
+
+/// Budgets of a single run of a lowering. Native runs are not limited.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VerifyLimits {
+    /// wasmtime fuel a run may consume, about one unit per WASM instruction
+    pub fuel: u64,
+    /// Largest linear memory, or state image behind env.read_i64 and
+    /// env.write_i64, a run may have
+    pub memory_bytes: usize,
+    /// Wall-clock time a run may take, rounded up to the epoch
+    pub timeout_ms: u64,
+}
+
+impl Default for VerifyLimits {
+    fn default() -> Self {
+        Self {
+            fuel: 1_000_000,
+            memory_bytes: 16 << 20,
+            timeout_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Verification {
+    pub callback: String,
+    /// Why the callback could not be compared, if it was skipped
+    pub skipped: Option<String>,
+    pub cases: Vec<Case>,
+}
+
+impl Verification {
+    pub fn matches(&self) -> bool {
+        self.cases.iter().all(|case| case.matches)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Case {
+    pub input: Vec<u8>,
+    pub native: Outcome,
+    pub wasm: Outcome,
+    pub matches: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Outcome {
+    pub result: Option<CallbackValue>,
+    pub state: Vec<u8>,
+    /// Set when execution trapped instead of returning
+    pub trap: Option<String>,
+}
+
+pub fn verify_all<'a>(
+    callbacks: impl IntoIterator<Item = &'a CallbackEntry>,
+    transpiler: &Transpiler,
+) -> Vec<Verification> {
+    callbacks
+        .into_iter()
+        .map(|callback| verify(callback, transpiler))
+        .collect()
+}
+
+pub fn verify(callback: &CallbackEntry, transpiler: &Transpiler) -> Verification {
+    let skipped = |reason: String| Verification {
+        callback: callback.name.clone(),
+        skipped: Some(reason),
+        cases: Vec::new(),
+    };
+
+    let compiled = match comparable(callback, transpiler) {
+        Ok(compiled) => compiled,
+        Err(reason) => return skipped(reason),
+    };
+
+    let cases = SAMPLE_COUNTERS
+        .iter()
+        .map(|&counter| {
+            let input = State { counter };
+            let native = run_native(callback, input.clone());
+            let wasm = execute(&compiled, &input, &transpiler.verify_limits()).0;
+            Case {
+                input: input.as_bytes().to_vec(),
+                matches: native == wasm,
+                native,
+                wasm,
+            }
+        })
+        .collect();
+
+    Verification {
+        callback: callback.name.clone(),
+        skipped: None,
+        cases,
+    }
+}
+
+/// The lowering of `callback` compiled, if its result can be observed and
+/// its imports emulated
+pub fn comparable(callback: &CallbackEntry, transpiler: &Transpiler) -> Result<Compiled, String> {
+    if !matches!(callback.signature.returns, ReturnType::I32 | ReturnType::F64) {
+        return Err("only i32 and f64 results are compared".to_string());
+    }
+    if callback.signature.client_params().next().is_some() {
+        return Err("callbacks taking arguments are not compared".to_string());
+    }
+    Compiled::new(&transpiler.lowered_module(&callback.name)?)
+}
+
+fn run_native(callback: &CallbackEntry, mut state: State) -> Outcome {
+    let result = callback.call(&mut state, &[]);
+    Outcome {
+        result: result.ok(),
+        state: state.as_bytes().to_vec(),
+        trap: None,
+    }
+}
+
+/// Runs `compiled` on a copy of `input` in a fresh instance, also returning
+/// the number of WASM instructions executed
+pub fn execute(compiled: &Compiled, input: &State, limits: &VerifyLimits) -> (Outcome, u64) {
+    match compiled.instantiate(limits) {
+        Ok(mut runner) => runner.run(input),
+        Err(trap) => (Outcome { result: None, state: input.as_bytes().to_vec(), trap: Some(trap) }, 0),
+    }
+}
+
+// The engine of every run. Fuel counts instructions, and a thread advances
+// the epoch that deadlines are set in.
+fn engine() -> &'static Engine {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    ENGINE.get_or_init(|| {
+        let mut config = Config::new();
+        config.consume_fuel(true).epoch_interruption(true).wasm_memory64(true);
+        let engine = Engine::new(&config).expect("the verification engine is configured");
+        let clock = engine.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(EPOCH);
+            clock.increment_epoch();
+        });
+        engine
+    })
+}
+
+/// A lowering compiled by Cranelift
+pub struct Compiled {
+    module: Module,
+    // The shared runtime it imports its memory from, and the heap base to
+    // reserve in it, see `runtime.rs`
+    runtime: Option<(Module, u32)>,
+}
+
+impl Compiled {
+    /// Compiles a lowering, or a module as served to the browser
+    pub(crate) fn new(wasm: &[u8]) -> Result<Self, String> {
+        let module = Module::new(engine(), wasm).map_err(|e| e.to_string())?;
+        let mut runtime = None;
+        for import in module.imports() {
+            match import.ty() {
+                ExternType::Func(ty) if ty.params().any(|param| param.is_ref()) => {
+                    return Err("field accessors of handles are not emulated".to_string());
+                }
+                ExternType::Memory(ty) if import.module() == runtime::MODULE => {
+                    let shared = Module::new(engine(), runtime::module(ty.is_64())).map_err(|e| e.to_string())?;
+                    runtime = Some((shared, runtime::heap_base(wasm).unwrap_or(0)));
+                }
+                _ => {}
+            }
+        }
+        Ok(Self { module, runtime })
+    }
+
+    /// An instance to run the lowering in, within `limits`
+    pub fn instantiate(&self, limits: &VerifyLimits) -> Result<Runner, String> {
+        let host = Host {
+            state: Vec::new(),
+            memory_bytes: limits.memory_bytes,
+            reason: None,
+            limits: StoreLimitsBuilder::new().memory_size(limits.memory_bytes).build(),
+        };
+        let mut store = Store::new(engine(), host);
+        store.limiter(|host| &mut host.limits);
+        // Instantiating the shared runtime runs its start code
+        store.set_fuel(limits.fuel).unwrap();
+        let ticks = limits.timeout_ms.div_ceil(EPOCH.as_millis() as u64);
+        store.set_epoch_deadline(ticks);
+
+        let mut linker = Linker::new(engine());
+        if let Some((shared, heap_base)) = &self.runtime {
+            let instance = linker.instantiate(&mut store, shared).map_err(|e| e.to_string())?;
+            let reserve = instance.get_typed_func::<i32, i32>(&mut store, "reserve").map_err(|e| e.to_string())?;
+            reserve.call(&mut store, *heap_base as i32).map_err(|e| e.to_string())?;
+            linker.instance(&mut store, runtime::MODULE, instance).map_err(|e| e.to_string())?;
+        }
+        for import in self.module.imports() {
+            let (module, name) = (import.module(), import.name());
+            let ExternType::Func(ty) = import.ty() else { continue };
+            let linked = match (module, name) {
+                (runtime::MODULE, _) => continue,
+                ("env", "read_i64") => linker.func_wrap(module, name, |mut caller: Caller<'_, Host>, addr: i64| {
+                    Ok(i64::from_le_bytes(word(&mut caller, addr)?.try_into().unwrap()))
+                }),
+                ("env", "write_i64") => linker.func_wrap(module, name, |mut caller: Caller<'_, Host>, addr: i64, value: i64| {
+                    word(&mut caller, addr)?.copy_from_slice(&value.to_le_bytes());
+                    wasmtime::Result::<()>::Ok(())
+                }),
+                ("env", "trap_reason") => linker.func_wrap(module, name, |mut caller: Caller<'_, Host>, code: i32| {
+                    caller.data_mut().reason = Some(code);
+                }),
+                // env.on_trap also takes the halves of the source address
+                ("env", "on_trap") => linker.func_wrap(module, name, |mut caller: Caller<'_, Host>, code: i32, _: i32, _: i32| {
+                    caller.data_mut().reason = Some(code);
+                }),
+                // Syscalls and env.now_ns only exist in the browser
+                _ => {
+                    let message = format!("{}.{} is not emulated", module, name);
+                    linker.func_new(module, name, ty, move |_, _, _| Err(wasmtime::Error::msg(message.clone())))
+                }
+            };
+            linked.map_err(|e| e.to_string())?;
+        }
+        let instance = linker.instantiate(&mut store, &self.module).map_err(|e| e.to_string())?;
+        let callback = instance.get_func(&mut store, "callback").ok_or("the module exports no callback")?;
+        let memory = instance.get_memory(&mut store, "memory");
+        // Parameters (the state pointer) are 0, the address of the state image
+        let args = callback.ty(&store).params().map(|ty| Val::default_for_ty(&ty).unwrap_or(Val::I64(0))).collect();
+        Ok(Runner { store, callback, memory, args, fuel: limits.fuel, ticks })
+    }
+}
+
+// Store data of a run
+struct Host {
+    // The state image of modules without a memory, behind env.read_i64 and
+    // env.write_i64. It grows by the words written past its end.
+    state: Vec<u8>,
+    memory_bytes: usize,
+    // Code passed to env.trap_reason, reported with the trap
+    reason: Option<i32>,
+    limits: StoreLimits,
+}
+
+// The word at `addr` of the state image: the module's memory, where it has
+// one, or the host's copy
+fn word<'a>(caller: &'a mut Caller<'_, Host>, addr: i64) -> wasmtime::Result<&'a mut [u8]> {
+    let out_of_bounds = || wasmtime::Error::msg(format!("out of bounds state access at {:#x}", addr));
+    let start = usize::try_from(addr).map_err(|_| out_of_bounds())?;
+    let end = start.checked_add(WORD).ok_or_else(out_of_bounds)?;
+    let image = match caller.get_export("memory") {
+        Some(Extern::Memory(memory)) => memory.data_mut(caller),
+        _ => {
+            let host = caller.data_mut();
+            if end > host.memory_bytes.max(host.state.len()) {
+                return Err(out_of_bounds());
+            }
+            if end > host.state.len() {
+                host.state.resize(end, 0);
+            }
+            &mut host.state[..]
+        }
+    };
+    image.get_mut(start..end).ok_or_else(out_of_bounds)
+}
+
+/// An instance of a lowering, called once per run
+pub struct Runner {
+    store: Store<Host>,
+    callback: Func,
+    memory: Option<Memory>,
+    args: Vec<Val>,
+    fuel: u64,
+    ticks: u64,
+}
+
+impl Runner {
+    /// Sets the leading parameters, f64 ones as their bits
+    #[cfg(test)]
+    fn with_args(mut self, args: &[i64]) -> Self {
+        for (slot, arg) in self.args.iter_mut().zip(args) {
+            *slot = match slot {
+                Val::I32(_) => Val::I32(*arg as i32),
+                Val::F64(_) => Val::F64(*arg as u64),
+                _ => Val::I64(*arg),
+            };
+        }
+        self
+    }
+
+    /// The linear memory after the last run, as the glue sends it under the
+    /// paranoid sync policy
+    #[cfg(test)]
+    pub(crate) fn memory_image(&self) -> Option<Vec<u8>> {
+        self.memory.map(|memory| memory.data(&self.store).to_vec())
+    }
+
+    /// Runs the lowering on a copy of `input`, also returning the number of
+    /// WASM instructions executed
+    pub fn run(&mut self, input: &State) -> (Outcome, u64) {
+        let input = input.as_bytes();
+        let (result, executed) = self.call(input);
+        let state = match self.memory {
+            Some(memory) => memory.data(&self.store).get(..input.len()).unwrap_or(input).to_vec(),
+            None => self.store.data().state[..input.len()].to_vec(),
+        };
+        let outcome = Outcome {
+            result: result.as_ref().ok().map(|value| match value {
+                Val::F64(bits) => CallbackValue::F64(f64::from_bits(*bits)),
+                Val::I32(value) => CallbackValue::I32(*value),
+                other => CallbackValue::I32(other.unwrap_i64() as i32),
+            }),
+            state,
+            trap: result.err(),
+        };
+        (outcome, executed)
+    }
+
+    // Calls the callback with the state image set to `input`, returning its
+    // first result and the number of instructions executed
+    fn call(&mut self, input: &[u8]) -> (Result<Val, String>, u64) {
+        match self.memory {
+            Some(memory) => {
+                if let Err(e) = memory.write(&mut self.store, 0, input) {
+                    return (Err(e.to_string()), 0);
+                }
+            }
+            None => self.store.data_mut().state = input.to_vec(),
+        }
+        self.store.data_mut().reason = None;
+        // Fuel is only unavailable on engines that do not consume it
+        self.store.set_fuel(self.fuel).unwrap();
+        self.store.set_epoch_deadline(self.ticks);
+
+        let mut results = vec![Val::I64(0); self.callback.ty(&self.store).results().len()];
+        let result = self.callback.call(&mut self.store, &self.args, &mut results);
+        let executed = self.fuel - self.store.get_fuel().unwrap();
+        let result = match result {
+            Ok(()) => results.into_iter().next().ok_or_else(|| "function ended without a result".to_string()),
+            Err(error) => Err(match error.downcast_ref::<Trap>() {
+                Some(Trap::OutOfFuel) => format!("out of fuel after {} instructions", executed),
+                Some(Trap::Interrupt) => format!("timed out after {} instructions", executed),
+                Some(Trap::UnreachableCodeReached) => match self.store.data().reason {
+                    Some(code) => format!("unreachable (trap reason {})", code),
+                    None => "unreachable".to_string(),
+                },
+                Some(trap) => trap.to_string(),
+                None => error.root_cause().to_string(),
+            }),
+        };
+        (result, executed)
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::arch::Arch;
+    use crate::backend::{DataBlock, FunctionIR, TranspilerBackend};
+    use crate::signatures::{DeclaredSignature, ParamType, ResultType};
+    use crate::transpiler_real::{DirectBackend, OptLevel, TranspileOptions, X64ToWasmTranspiler};
+    use wasm_encoder::{
+        BlockType, CodeSection, ExportKind, ExportSection, Function, FunctionSection, Instruction as WasmInstr,
+        MemorySection, MemoryType, Module, TypeSection, ValType,
+    };
+
+    // Float parameters and results only, translated from this test binary
+    #[no_mangle]
+    extern "C" fn verify_lerp(a: f64, b: f64, t: f64) -> f64 {
+        a + (b - a) * t
+    }
+
+    #[no_mangle]
+    extern "C" fn verify_ratio(x: f64, y: f64) -> f64 {
+        (x * x - y) / y
+    }
+
+    #[test]
+    fn float_callbacks_match_native_results() {
+        let binary = X64ToWasmTranspiler::new(&std::env::current_exe().unwrap().to_string_lossy())
+            .unwrap()
+            .with_backend(Box::new(DirectBackend::new(TranspileOptions { float_result: true, ..Default::default() })));
+        let inputs: [[f64; 3]; 4] = [[0.0, 1.0, 0.5], [-3.25, 8.0, 2.0], [1e300, -1e-300, 0.125], [7.0, 7.0, -1.0]];
+
+        let check = |name: &str, arity: usize, native: &dyn Fn(&[f64]) -> f64| {
+            let lowered = binary.lower_function(name).unwrap();
+            assert_eq!(lowered.params, vec![ValType::F64; arity], "{}", name);
+            assert_eq!(lowered.result, ValType::F64, "{}", name);
+            assert!(lowered.report.unsupported.is_empty(), "{}: {:?}", name, lowered.report.unsupported);
+
+            let compiled = Compiled::new(&binary.emit_function(name, lowered)).unwrap();
+            for input in &inputs {
+                let args: Vec<i64> = input[..arity].iter().map(|arg| arg.to_bits() as i64).collect();
+                let mut runner = compiled.instantiate(&VerifyLimits::default()).unwrap().with_args(&args);
+                let (outcome, _) = runner.run(&State { counter: 0 });
+                assert_eq!(outcome.result, Some(CallbackValue::F64(native(input))), "{}{:?}", name, input);
+            }
+        };
+        check("verify_lerp", 3, &|args| verify_lerp(args[0], args[1], args[2]));
+        check("verify_ratio", 2, &|args| verify_ratio(args[0], args[1]));
+    }
+
+    #[test]
+    fn typed_globals_round_trip_through_data_segments() {
+        // i16, i32, i64 and f64 arrays of two elements at 0x2000, 0x2008,
+        // 0x2010 and 0x2020
+        let mut data = Vec::new();
+        data.extend([-2i16, 0x1234].iter().flat_map(|value| value.to_le_bytes()));
+        data.extend([0u8; 4]);
+        data.extend([-3i32, 0x1234_5678].iter().flat_map(|value| value.to_le_bytes()));
+        data.extend([-4i64, 0x1234_5678_9abc_def0].iter().flat_map(|value| value.to_le_bytes()));
+        data.extend([-0.5f64, 1e300].iter().flat_map(|value| value.to_le_bytes()));
+
+        // Loads the second element: movsx rax, word / movsxd rax, dword /
+        // mov rax, qword [rip+disp] or movsd xmm0, [rip+disp]; ret
+        let run = |opcode: &[u8], addr: u64, float_result: bool| {
+            let disp = (addr - (0x1000 + opcode.len() as u64 + 4)) as u32;
+            let code: Vec<u8> = opcode.iter().copied().chain(disp.to_le_bytes()).chain([0xc3]).collect();
+            let mut function = FunctionIR::decode("test", Arch::X86_64, &code, 0x1000);
+            function.data = vec![DataBlock { addr: 0x2000, bytes: data.clone(), big_endian: false }];
+            let options = TranspileOptions { audit_data: true, float_result, ..Default::default() };
+            let backend = DirectBackend::new(options);
+            let wasm = backend.emit(backend.lower(&function).unwrap());
+            wasmparser::Validator::new().validate_all(&wasm).unwrap();
+            let mut runner = Compiled::new(&wasm).unwrap().instantiate(&VerifyLimits::default()).unwrap();
+            match runner.call(&[]).0.unwrap() {
+                Val::F64(bits) => bits as i64,
+                value => value.unwrap_i64(),
+            }
+        };
+        assert_eq!(run(&[0x48, 0x0f, 0xbf, 0x05], 0x2002, false), 0x1234);
+        assert_eq!(run(&[0x48, 0x0f, 0xbf, 0x05], 0x2000, false), -2);
+        assert_eq!(run(&[0x48, 0x63, 0x05], 0x2008, false), -3);
+        assert_eq!(run(&[0x48, 0x63, 0x05], 0x200c, false), 0x1234_5678);
+        assert_eq!(run(&[0x48, 0x8b, 0x05], 0x2018, false), 0x1234_5678_9abc_def0);
+        assert_eq!(f64::from_bits(run(&[0xf2, 0x0f, 0x10, 0x05], 0x2028, true) as u64), 1e300);
+    }
+
+    #[test]
+    fn inferred_widths_compute_the_same_results() {
+        // mov eax, esi; imul eax, edx; add eax, 0x10; mov ecx, eax;
+        // sub ecx, esi; mov [rdi+4], ecx; mov eax, [rdi]; add eax, ecx; ret
+        let code = [
+            0x89, 0xf0, 0x0f, 0xaf, 0xc2, 0x83, 0xc0, 0x10, 0x89, 0xc1, 0x29, 0xf1, 0x89, 0x4f, 0x04, 0x8b, 0x07, 0x01,
+            0xc8, 0xc3,
+        ];
+        let function = FunctionIR::decode("test", Arch::X86_64, &code, 0x1000);
+        let signature = DeclaredSignature { params: vec![ParamType::State, ParamType::I32, ParamType::I32], returns: ResultType::I32 };
+        let lower = |infer_widths: bool, optimization: OptLevel| {
+            let options = TranspileOptions { signature: Some(signature.clone()), infer_widths, optimization, ..Default::default() };
+            let backend = DirectBackend::new(options);
+            let wasm = backend.emit(backend.lower(&function).unwrap());
+            wasmparser::Validator::new_with_features(wasmparser::WasmFeatures::all()).validate_all(&wasm).unwrap();
+            (backend.lower(&function).unwrap(), Compiled::new(&wasm).unwrap())
+        };
+
+        let inputs: [(i32, i32, i32); 4] = [(0, 0, 0), (5, 7, -3), (i32::MIN, -1, 1 << 30), (0x1234_5678, 0x7fff, i32::MAX)];
+        for optimization in [OptLevel::Full, OptLevel::None] {
+            let ((wide, wide_module), (narrow, narrow_module)) = (lower(false, optimization), lower(true, optimization));
+            assert!(!wide.locals.iter().any(|(_, ty)| *ty == ValType::I32));
+            assert!(narrow.locals.iter().any(|(_, ty)| *ty == ValType::I32));
+            // ecx is stored as the 4 bytes mov [rdi+4], ecx writes
+            assert!(narrow.body.iter().any(|instr| matches!(instr, WasmInstr::I32Store(_))));
+            for (a, b, counter) in inputs {
+                // The upper halves of 32-bit arguments are undefined
+                let args = [a as u32 as i64 | 0x5a5a << 40, b as i64];
+                let state = State { counter };
+                let run = |compiled: &Compiled| {
+                    let mut runner = compiled.instantiate(&VerifyLimits::default()).unwrap().with_args(&args);
+                    runner.run(&state).0.result
+                };
+                let expected = counter.wrapping_add(a.wrapping_mul(b).wrapping_add(0x10).wrapping_sub(a));
+                assert_eq!(run(&wide_module), Some(CallbackValue::I32(expected)), "{:?}", (a, b, counter));
+                assert_eq!(run(&narrow_module), Some(CallbackValue::I32(expected)), "{:?}", (a, b, counter));
+            }
+        }
+    }
+
+    #[test]
+    fn stops_runs_that_exceed_their_budget() {
+        // add rax, 1; add rax, 1; add rax, 1; ret
+        let code = [0x48, 0x83, 0xc0, 0x01, 0x48, 0x83, 0xc0, 0x01, 0x48, 0x83, 0xc0, 0x01, 0xc3];
+        let function = FunctionIR::decode("test", Arch::X86_64, &code, 0x1000);
+        let options = TranspileOptions { optimization: OptLevel::None, ..Default::default() };
+        let backend = DirectBackend::new(options);
+        let compiled = Compiled::new(&backend.emit(backend.lower(&function).unwrap())).unwrap();
+        let run = |limits: VerifyLimits| compiled.instantiate(&limits).unwrap().call(&[]).0.map(|value| value.unwrap_i64());
+
+        assert_eq!(run(VerifyLimits::default()), Ok(3));
+        // Fuel is checked on entry and at loop headers, not per instruction
+        assert_eq!(run(VerifyLimits { fuel: 0, ..Default::default() }), Err("out of fuel after 0 instructions".to_string()));
+        assert_eq!(run(VerifyLimits { timeout_ms: 0, ..Default::default() }), Err("timed out after 0 instructions".to_string()));
+    }
+
+    #[test]
+    fn stops_loops_and_large_memories() {
+        // (memory 2) (func (result i32) (loop (br 0)) (i32.const 0))
+        let mut module = Module::new();
+        let mut types = TypeSection::new();
+        types.ty().function(vec![], vec![ValType::I32]);
+        module.section(&types);
+        let mut functions = FunctionSection::new();
+        functions.function(0);
+        module.section(&functions);
+        let mut memories = MemorySection::new();
+        memories.memory(MemoryType { minimum: 2, maximum: None, memory64: false, shared: false, page_size_log2: None });
+        module.section(&memories);
+        let mut exports = ExportSection::new();
+        exports.export("callback", ExportKind::Func, 0);
+        exports.export("memory", ExportKind::Memory, 0);
+        module.section(&exports);
+        let mut body = Function::new([]);
+        for instr in [WasmInstr::Loop(BlockType::Empty), WasmInstr::Br(0), WasmInstr::End, WasmInstr::I32Const(0), WasmInstr::End] {
+            body.instruction(&instr);
+        }
+        let mut code = CodeSection::new();
+        code.function(&body);
+        module.section(&code);
+        let compiled = Compiled::new(&module.finish()).unwrap();
+
+        let (outcome, executed) = execute(&compiled, &State { counter: 7 }, &VerifyLimits { fuel: 10_000, ..Default::default() });
+        assert_eq!(outcome.trap, Some(format!("out of fuel after {} instructions", executed)));
+        assert!(executed >= 10_000);
+        assert_eq!(outcome.state, State { counter: 7 }.as_bytes());
+
+        let limits = VerifyLimits { fuel: u64::MAX, timeout_ms: 50, ..Default::default() };
+        let (outcome, _) = execute(&compiled, &State { counter: 7 }, &limits);
+        assert!(outcome.trap.unwrap().starts_with("timed out after "));
+
+        // Two pages do not fit
+        let (outcome, _) = execute(&compiled, &State { counter: 7 }, &VerifyLimits { memory_bytes: 65536, ..Default::default() });
+        assert!(outcome.trap.unwrap().contains("memory"));
+    }
+}